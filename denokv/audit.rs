@@ -0,0 +1,301 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! Per-token request auditing, to notice leaked tokens early.
+//!
+//! The auditor counts what every token does — reads, writes, watches,
+//! entries and bytes moved, errors — and at the end of each interval stores
+//! one JSON summary per active token under
+//! `["__audit", <interval start in ms>, <token name>]`. Summaries expire
+//! after `AUDIT_RETENTION`. Only tokens that see the whole keyspace can
+//! read them.
+//!
+//! Two patterns raise alerts: a token that starts reading its entire
+//! keyspace, and a token that writes far more keys in an interval than it
+//! usually does.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use denokv_proto::encode_key;
+use denokv_proto::AtomicWrite;
+use denokv_proto::Key;
+use denokv_proto::KeyPart;
+use denokv_proto::KvValue;
+use denokv_proto::Mutation;
+use denokv_proto::MutationKind;
+use denokv_proto::ReadRange;
+use log::warn;
+use serde::Serialize;
+
+use crate::tenants::Tenant;
+use crate::DatabaseBackend;
+
+/// How long audit summaries are kept.
+const AUDIT_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Intervals with fewer written keys than this never count as a spike, so
+/// that mostly idle tokens do not alert on every small burst.
+const MIN_SPIKE_WRITES: u64 = 100;
+
+/// Weight of the latest interval in a token's usual number of written keys.
+const BASELINE_WEIGHT: f64 = 0.2;
+
+/// What one token did during one audit interval.
+#[derive(Serialize, Default, Clone, Debug)]
+pub struct AuditSummary {
+  pub reads: u64,
+  pub writes: u64,
+  pub watches: u64,
+  /// Requests that were answered with an error status.
+  pub errors: u64,
+  /// Reads of a range covering the token's entire keyspace.
+  pub full_scans: u64,
+  pub entries_read: u64,
+  pub bytes_read: u64,
+  pub mutations: u64,
+  pub enqueues: u64,
+  pub bytes_written: u64,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+  FullScan,
+  WriteSpike,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Alert {
+  /// Name of the token in the token registry.
+  pub token: String,
+  pub kind: AlertKind,
+  pub message: String,
+}
+
+type AlertCallback = Box<dyn Fn(&Alert) + Send + Sync>;
+
+pub struct Auditor {
+  interval: Duration,
+  write_spike_factor: f64,
+  state: Mutex<AuditState>,
+  on_alert: AlertCallback,
+}
+
+#[derive(Default)]
+struct AuditState {
+  current: HashMap<String, AuditSummary>,
+  /// Tokens that scanned their entire keyspace in the previous interval.
+  /// They only alert again after an interval without full scans.
+  scanned_before: HashSet<String>,
+}
+
+impl Auditor {
+  pub fn new(
+    interval: Duration,
+    write_spike_factor: f64,
+    on_alert: impl Fn(&Alert) + Send + Sync + 'static,
+  ) -> Self {
+    Self {
+      interval,
+      write_spike_factor,
+      state: Mutex::default(),
+      on_alert: Box::new(on_alert),
+    }
+  }
+
+  fn update(&self, tenant: &Tenant, f: impl FnOnce(&mut AuditSummary)) {
+    let mut state = self.state.lock().unwrap();
+    f(state.current.entry(tenant.name.clone()).or_default());
+  }
+
+  /// Count a finished request to the datapath endpoint `path`.
+  pub fn record_request(&self, tenant: &Tenant, path: &str, success: bool) {
+    self.update(tenant, |summary| {
+      match path {
+        "/snapshot_read" => summary.reads += 1,
+        "/atomic_write" => summary.writes += 1,
+        "/watch" | "/watch_ws" => summary.watches += 1,
+        _ => {}
+      }
+      if !success {
+        summary.errors += 1;
+      }
+    });
+  }
+
+  /// Count the ranges of a read, before they are moved into the tenant's
+  /// namespace, and the size of its response.
+  pub fn record_read(
+    &self,
+    tenant: &Tenant,
+    requests: &[ReadRange],
+    entries: usize,
+    bytes: usize,
+  ) {
+    let full_scans = requests.iter().filter(|r| is_full_scan(r)).count();
+    let alert = {
+      let mut state = self.state.lock().unwrap();
+      let first_scan = !state.scanned_before.contains(&tenant.name);
+      let summary = state.current.entry(tenant.name.clone()).or_default();
+      let alert = first_scan && full_scans > 0 && summary.full_scans == 0;
+      summary.full_scans += full_scans as u64;
+      summary.entries_read += entries as u64;
+      summary.bytes_read += bytes as u64;
+      alert
+    };
+    if alert {
+      (self.on_alert)(&Alert {
+        token: tenant.name.clone(),
+        kind: AlertKind::FullScan,
+        message: format!("Token '{}' read its entire keyspace", tenant.name),
+      });
+    }
+  }
+
+  pub fn record_write(
+    &self,
+    tenant: &Tenant,
+    write: &AtomicWrite,
+    bytes: usize,
+  ) {
+    self.update(tenant, |summary| {
+      summary.mutations += write.mutations.len() as u64;
+      summary.enqueues += write.enqueues.len() as u64;
+      summary.bytes_written += bytes as u64;
+    });
+  }
+
+  /// Store a summary and check for write spikes at the end of every
+  /// interval, until the server stops.
+  pub async fn run(self: Arc<Self>, database: DatabaseBackend) {
+    let mut baselines: HashMap<String, f64> = HashMap::new();
+    let mut timer = tokio::time::interval(self.interval);
+    timer.tick().await;
+    let mut started = denokv_proto::time::utc_now();
+    loop {
+      timer.tick().await;
+      let summaries = {
+        let mut state = self.state.lock().unwrap();
+        let summaries = std::mem::take(&mut state.current);
+        state.scanned_before = summaries
+          .iter()
+          .filter(|(_, summary)| summary.full_scans > 0)
+          .map(|(name, _)| name.clone())
+          .collect();
+        summaries
+      };
+
+      for alert in self.check_write_spikes(&mut baselines, &summaries) {
+        (self.on_alert)(&alert);
+      }
+      if let Err(e) = store_summaries(&database, started, summaries).await {
+        warn!("Failed to store the audit summary: {e}");
+      }
+      started = denokv_proto::time::utc_now();
+    }
+  }
+
+  /// Compare the keys each token wrote with its usual number and update
+  /// the latter. Tokens are not checked in the first interval they are
+  /// active in, when there is nothing to compare with.
+  fn check_write_spikes(
+    &self,
+    baselines: &mut HashMap<String, f64>,
+    summaries: &HashMap<String, AuditSummary>,
+  ) -> Vec<Alert> {
+    let mut alerts = Vec::new();
+    for (name, baseline) in baselines.iter_mut() {
+      let written = summaries
+        .get(name)
+        .map_or(0, |summary| summary.mutations + summary.enqueues);
+      if written >= MIN_SPIKE_WRITES
+        && written as f64 > baseline.max(1.0) * self.write_spike_factor
+      {
+        alerts.push(Alert {
+          token: name.clone(),
+          kind: AlertKind::WriteSpike,
+          message: format!(
+            "Token '{name}' wrote {written} keys, usually about {:.0}",
+            baseline
+          ),
+        });
+      }
+      *baseline += (written as f64 - *baseline) * BASELINE_WEIGHT;
+    }
+    for (name, summary) in summaries {
+      baselines
+        .entry(name.clone())
+        .or_insert((summary.mutations + summary.enqueues) as f64);
+    }
+    alerts
+  }
+}
+
+/// Whether `request` starts at the beginning of the keyspace and has no
+/// upper bound.
+fn is_full_scan(request: &ReadRange) -> bool {
+  request.start.iter().all(|b| *b == 0)
+    && request.start.len() <= 1
+    && request.is_open_ended()
+}
+
+async fn store_summaries(
+  database: &DatabaseBackend,
+  started: chrono::DateTime<chrono::Utc>,
+  summaries: HashMap<String, AuditSummary>,
+) -> anyhow::Result<()> {
+  if summaries.is_empty() {
+    return Ok(());
+  }
+  let expire_at = denokv_proto::time::utc_now()
+    + chrono::Duration::from_std(AUDIT_RETENTION).unwrap();
+  let mut mutations = Vec::with_capacity(summaries.len());
+  for (name, summary) in summaries {
+    let key = encode_key(&Key(vec![
+      KeyPart::String("__audit".to_string()),
+      KeyPart::Float(started.timestamp_millis() as f64),
+      KeyPart::String(name),
+    ]))?;
+    mutations.push(Mutation {
+      key,
+      kind: MutationKind::Set(KvValue::Bytes(serde_json::to_vec(&summary)?)),
+      expire_at: Some(expire_at),
+    });
+  }
+  database
+    .atomic_write(AtomicWrite {
+      checks: vec![],
+      mutations,
+      enqueues: vec![],
+    })
+    .await?;
+  Ok(())
+}
+
+/// A callback that POSTs every `T` it is called with as JSON to `url`, such
+/// as audit alerts. `what` names them in log lines.
+pub fn webhook<T: Serialize>(
+  url: hyper::Uri,
+  what: &'static str,
+) -> impl Fn(&T) + Send + Sync {
+  let client = hyper::Client::new();
+  move |event| {
+    let request = hyper::Request::post(url.clone())
+      .header("content-type", "application/json")
+      .body(hyper::Body::from(serde_json::to_vec(event).unwrap()))
+      .unwrap();
+    let response = client.request(request);
+    tokio::spawn(async move {
+      match response.await {
+        Ok(response) if response.status().is_success() => {}
+        Ok(response) => {
+          warn!("The {what} webhook responded {}", response.status())
+        }
+        Err(e) => warn!("Failed to deliver {what}: {e}"),
+      }
+    });
+  }
+}
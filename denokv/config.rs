@@ -1,6 +1,8 @@
 use std::net::SocketAddr;
+use std::path::PathBuf;
 
 use clap::Parser;
+use clap::ValueEnum;
 
 #[derive(Parser)]
 pub struct Config {
@@ -16,10 +18,34 @@ pub struct Config {
   #[clap(long, env = "DENO_KV_DATABASE_TYPE", default_value = "sqlite")]
   pub database_type: String,
 
+  /// A TOML file with further options. See the `config_file` module for
+  /// the format and which settings are reloaded while the server runs.
+  #[clap(long, env = "DENO_KV_CONFIG", global = true)]
+  pub config: Option<PathBuf>,
+
+  /// How to write log lines: `text` for people, or `json` with one object
+  /// per line for log collectors.
+  #[clap(
+    long,
+    env = "DENO_KV_LOG_FORMAT",
+    value_enum,
+    default_value = "text",
+    global = true
+  )]
+  pub log_format: LogFormat,
+
   #[command(subcommand)]
   pub subcommand: SubCmd,
 }
 
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LogFormat {
+  Text,
+  Json,
+}
+
+// Parsed once at startup, so the size of the serve options doesn't matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(Parser)]
 pub enum SubCmd {
   /// Starts the Deno KV HTTP server.
@@ -27,6 +53,44 @@ pub enum SubCmd {
 
   /// Point-in-time recovery tools.
   Pitr(PitrOptions),
+
+  /// Scan the PostgreSQL database for rows that fail checksum verification
+  /// or cannot be decoded. Exits with an error if any are found.
+  Verify,
+
+  /// Check the PostgreSQL server's settings for ones that commonly cause
+  /// slow or unreliable operation, and print what to change.
+  Doctor(DoctorOptions),
+
+  /// Fix detectable inconsistencies in the PostgreSQL database's internal
+  /// state and report what was changed.
+  Repair(RepairOptions),
+
+  /// Replay a workload recorded with `serve --record-workload` against the
+  /// configured database and report latencies.
+  ReplayWorkload(ReplayWorkloadOptions),
+
+  /// Print a sample of the keys under a prefix in the PostgreSQL database,
+  /// with estimates of how many keys and bytes the prefix holds.
+  SampleKeys(SampleKeysOptions),
+
+  /// Copy keys from the PostgreSQL database into another PostgreSQL or
+  /// SQLite database, such as a staging environment, optionally removing or
+  /// hashing fields of the values on the way.
+  Copy(CopyOptions),
+
+  /// Load a fixture file of entries and queue messages into the database,
+  /// each namespace in one atomic write. See the `seed` module for the
+  /// format.
+  Seed(SeedOptions),
+
+  /// Print the OpenAPI document of the server's JSON endpoints, for
+  /// generating clients.
+  Openapi,
+
+  /// Publish changes to the PostgreSQL database to Kafka topics.
+  #[cfg(feature = "kafka")]
+  ConnectKafka(KafkaOptions),
 }
 
 #[derive(Parser)]
@@ -35,16 +99,56 @@ pub struct ServeOptions {
   #[clap(long, env = "DENO_KV_ACCESS_TOKEN")]
   pub access_token: String,
 
-  /// The address to bind the Deno KV HTTP endpoint to.
-  #[clap(long = "addr", default_value = "0.0.0.0:4512")]
+  /// A JSON file of further access tokens, each confined to a namespace
+  /// and a set of permissions. See the `tenants` module for the format.
+  #[clap(long, env = "DENO_KV_TOKEN_REGISTRY")]
+  pub token_registry: Option<PathBuf>,
+
+  /// A JSON file of encryption keys by namespace. Values in these
+  /// namespaces are encrypted at rest with their own key. See the
+  /// `encryption` module for the format.
+  #[clap(long, env = "DENO_KV_ENCRYPTION_KEYS")]
+  pub encryption_keys: Option<PathBuf>,
+
+  /// The address to bind the Deno KV HTTP endpoint to. Ignored when the
+  /// server is passed a socket by systemd socket activation.
+  #[clap(long = "addr", env = "DENO_KV_ADDR", default_value = "0.0.0.0:4512")]
   pub addr: SocketAddr,
 
+  /// Bind `--addr` with SO_REUSEPORT, so that a new server can start
+  /// listening before the one it replaces has drained.
+  #[cfg(unix)]
+  #[clap(long, env = "DENO_KV_REUSE_PORT")]
+  pub reuse_port: bool,
+
+  /// Seconds to wait for requests in flight after SIGTERM or Ctrl-C before
+  /// exiting. Watches end right away, with a hint to reconnect.
+  #[clap(long, env = "DENO_KV_DRAIN_TIMEOUT", default_value = "30")]
+  pub drain_timeout: u64,
+
+  /// Serve unauthenticated `/healthz` and `/readyz` checks on this address,
+  /// for liveness and readiness probes.
+  #[clap(long, env = "DENO_KV_HEALTH_LISTEN")]
+  pub health_listen: Option<SocketAddr>,
+
+  /// Listen on a Unix domain socket at this path instead of `--addr`. A
+  /// stale socket left at the path is replaced.
+  #[cfg(unix)]
+  #[clap(long, env = "DENO_KV_UNIX_SOCKET")]
+  pub unix_socket: Option<PathBuf>,
+
+  /// Only accept plaintext HTTP/2 (h2c) connections with prior knowledge,
+  /// for proxies that terminate TLS and forward HTTP/2. Without this flag
+  /// both HTTP/1.1 and h2c are accepted. WebSocket watches need HTTP/1.1.
+  #[clap(long, env = "DENO_KV_HTTP2_ONLY")]
+  pub http2_only: bool,
+
   /// Open in read-only mode.
-  #[clap(long)]
+  #[clap(long, env = "DENO_KV_READ_ONLY")]
   pub read_only: bool,
 
   /// Sync changes from S3 continuously.
-  #[clap(long, conflicts_with = "read_only")]
+  #[clap(long, env = "DENO_KV_SYNC_FROM_S3", conflicts_with = "read_only")]
   pub sync_from_s3: bool,
 
   /// Atomic write batch timeout. Batching is disabled if this is not set.
@@ -55,10 +159,450 @@ pub struct ServeOptions {
   #[clap(long, env = "DENO_KV_NUM_WORKERS", default_value = "1")]
   pub num_workers: usize,
 
+  /// Maximum number of entries returned by one snapshot read, across all of
+  /// its ranges. Ranges cut short are returned with a continuation key.
+  #[clap(long, env = "DENO_KV_MAX_READ_ENTRIES")]
+  pub max_read_entries: Option<usize>,
+
+  /// Maximum number of key and value bytes returned by one snapshot read,
+  /// across all of its ranges. Ranges cut short are returned with a
+  /// continuation key.
+  #[clap(long, env = "DENO_KV_MAX_READ_BYTES")]
+  pub max_read_bytes: Option<usize>,
+
+  /// Record an anonymized trace of every read and write to this file, for
+  /// replaying with the `replay-workload` command.
+  #[clap(long, env = "DENO_KV_RECORD_WORKLOAD")]
+  pub record_workload: Option<PathBuf>,
+
+  /// Store a summary of each token's requests — operation counts, bytes
+  /// and errors — under the key `["__audit", <time>, <token name>]` every
+  /// `--audit-interval` seconds, and alert on full-keyspace scans and
+  /// write spikes.
+  #[clap(
+    long,
+    env = "DENO_KV_AUDIT",
+    conflicts_with_all = ["read_only", "sync_from_s3"]
+  )]
+  pub audit: bool,
+
+  /// Seconds covered by one audit summary.
+  #[clap(long, env = "DENO_KV_AUDIT_INTERVAL", default_value = "60")]
+  pub audit_interval: u64,
+
+  /// How many times its usual number of written keys a token has to write
+  /// in one audit interval to raise a write spike alert.
+  #[clap(long, env = "DENO_KV_AUDIT_WRITE_SPIKE_FACTOR", default_value = "10")]
+  pub audit_write_spike_factor: f64,
+
+  /// POST audit alerts as JSON to this http:// URL. Alerts are always
+  /// logged as warnings.
+  #[clap(long, env = "DENO_KV_AUDIT_ALERT_WEBHOOK", requires = "audit")]
+  pub audit_alert_webhook: Option<hyper::Uri>,
+
+  /// A JSON file of key and byte quotas by namespace. Namespaces at a
+  /// quota can only delete. See the `quotas` module for the format.
+  #[clap(long, env = "DENO_KV_NAMESPACE_QUOTAS")]
+  pub namespace_quotas: Option<PathBuf>,
+
+  /// Seconds between counts of the usage of namespaces with a quota.
+  #[clap(long, env = "DENO_KV_QUOTA_CHECK_INTERVAL", default_value = "60")]
+  pub quota_check_interval: u64,
+
+  /// Fractions of a quota at which a namespace raises a usage warning.
+  #[clap(
+    long,
+    env = "DENO_KV_QUOTA_WARNING_THRESHOLDS",
+    value_delimiter = ',',
+    default_value = "0.8,0.9"
+  )]
+  pub quota_warning_thresholds: Vec<f64>,
+
+  /// POST usage warnings as JSON to this http:// URL. Warnings are always
+  /// logged.
+  #[clap(
+    long,
+    env = "DENO_KV_QUOTA_WARNING_WEBHOOK",
+    requires = "namespace_quotas"
+  )]
+  pub quota_warning_webhook: Option<hyper::Uri>,
+
+  /// Verify per-row checksums on every read (PostgreSQL only).
+  #[clap(long, env = "DENO_KV_POSTGRES_VERIFY_CHECKSUMS")]
+  pub postgres_verify_checksums: bool,
+
+  /// Acknowledge writes without waiting for PostgreSQL to flush them to
+  /// disk (`synchronous_commit = off`). Much lower write latency, but a
+  /// crash of the server can lose the last few hundred milliseconds of
+  /// acknowledged writes.
+  #[clap(long, env = "DENO_KV_POSTGRES_ASYNC_COMMIT")]
+  pub postgres_async_commit: bool,
+
+  /// Seconds a dequeued PostgreSQL queue message may go without a
+  /// keepalive from its worker before it is considered failed, the worker
+  /// presumably gone, and delivered again. At most a week.
+  #[clap(
+    long,
+    env = "DENO_KV_POSTGRES_QUEUE_VISIBILITY_TIMEOUT",
+    default_value = "5"
+  )]
+  pub postgres_queue_visibility_timeout: u64,
+
+  /// Move queue messages that failed this many delivery attempts to the
+  /// PostgreSQL `queue_dead_letter` table instead of retrying them. By
+  /// default messages are retried for as long as their backoff schedule
+  /// lasts.
+  #[clap(long, env = "DENO_KV_POSTGRES_MAX_DELIVERY_ATTEMPTS")]
+  pub postgres_max_delivery_attempts: Option<u32>,
+
+  /// Fraction of PostgreSQL statements (0.0 to 1.0) to log with parameter
+  /// sizes, duration and row count. Visible with
+  /// `RUST_LOG=denokv_postgres::statements=trace`.
+  #[clap(
+    long,
+    env = "DENO_KV_POSTGRES_STATEMENT_LOG_SAMPLE_RATE",
+    default_value = "0"
+  )]
+  pub postgres_statement_log_sample_rate: f64,
+
+  /// Record the shapes of PostgreSQL range scans, so that the server can
+  /// suggest indexes for frequently scanned prefixes at `/index_advice`.
+  #[clap(long, env = "DENO_KV_POSTGRES_RECORD_RANGE_SCANS")]
+  pub postgres_record_range_scans: bool,
+
+  /// PostgreSQL read replica URLs. Eventually consistent reads are routed
+  /// to the fastest healthy replica.
+  #[clap(
+    long = "postgres-read-replica",
+    env = "DENO_KV_POSTGRES_READ_REPLICAS",
+    value_delimiter = ','
+  )]
+  pub postgres_read_replicas: Vec<String>,
+
+  /// Seconds of replication lag beyond which a read replica serves no
+  /// eventually consistent reads until it catches up. Unset, lag is only
+  /// reported in the metrics.
+  #[clap(long, env = "DENO_KV_POSTGRES_MAX_REPLICA_LAG")]
+  pub postgres_max_replica_lag: Option<f64>,
+
+  /// Seconds for which this server remembers the versions of keys it wrote
+  /// or read from the primary. Replica reads that return older versions of
+  /// those keys are repeated on the primary. 0 disables read repair.
+  #[clap(
+    long,
+    env = "DENO_KV_POSTGRES_READ_REPAIR_WINDOW",
+    default_value = "0"
+  )]
+  pub postgres_read_repair_window: u64,
+
+  /// Seconds between sweeps deleting expired keys from PostgreSQL. 0
+  /// disables the sweeper; expired keys are never read either way.
+  #[clap(
+    long,
+    env = "DENO_KV_POSTGRES_EXPIRY_SWEEP_INTERVAL",
+    default_value = "60"
+  )]
+  pub postgres_expiry_sweep_interval: u64,
+
+  /// Expired keys deleted per statement during a sweep.
+  #[clap(
+    long,
+    env = "DENO_KV_POSTGRES_EXPIRY_SWEEP_BATCH_SIZE",
+    default_value = "1000"
+  )]
+  pub postgres_expiry_sweep_batch_size: usize,
+
+  /// Seconds a watch may go unread before the server drops its
+  /// subscriptions, so that watches of clients that vanished without
+  /// closing them are cleaned up. Streamed watches are pinged often enough
+  /// to stay alive. 0 keeps them until the connection closes.
+  #[clap(long, env = "DENO_KV_POSTGRES_WATCH_LEASE", default_value = "60")]
+  pub postgres_watch_lease: u64,
+
+  /// Rows of a PostgreSQL table changed since it was last analyzed after
+  /// which the server analyzes it, to keep query plans and estimates
+  /// accurate. 0 leaves it to autovacuum.
+  #[clap(
+    long,
+    env = "DENO_KV_POSTGRES_ANALYZE_THRESHOLD",
+    default_value = "100000"
+  )]
+  pub postgres_analyze_threshold: u64,
+
+  /// PostgreSQL schema to keep the tables in, created if it does not exist.
+  /// Defaults to the connection's search path.
+  #[clap(long, env = "DENO_KV_POSTGRES_SCHEMA")]
+  pub postgres_schema: Option<String>,
+
+  /// Prefix for the names of the PostgreSQL tables, so that several
+  /// databases can share one schema.
+  #[clap(long, env = "DENO_KV_POSTGRES_TABLE_PREFIX", default_value = "")]
+  pub postgres_table_prefix: String,
+
+  /// Store keys whose first part is the string PREFIX in another PostgreSQL
+  /// cluster, given as `PREFIX=URL`. For example `eu=postgresql://eu-db/kv`
+  /// stores every key starting with `["eu"]` in that cluster.
+  #[clap(
+    long = "postgres-partition",
+    env = "DENO_KV_POSTGRES_PARTITIONS",
+    value_delimiter = ','
+  )]
+  pub postgres_partitions: Vec<String>,
+
+  /// Create the PostgreSQL kv_store table hash partitioned into this many
+  /// partitions by key. Only applies when the table does not exist yet.
+  #[clap(
+    long,
+    env = "DENO_KV_POSTGRES_HASH_PARTITIONS",
+    conflicts_with = "postgres_range_splits"
+  )]
+  pub postgres_hash_partitions: Option<u32>,
+
+  /// Create the PostgreSQL kv_store table range partitioned by key, with a
+  /// partition starting at the keys whose first part is each of these
+  /// strings. For example `m,t` splits the keyspace before `["m"]` and
+  /// `["t"]`. Only applies when the table does not exist yet.
+  #[clap(
+    long = "postgres-range-split",
+    env = "DENO_KV_POSTGRES_RANGE_SPLITS",
+    value_delimiter = ','
+  )]
+  pub postgres_range_splits: Vec<String>,
+
+  /// Split a partition of the partitioned PostgreSQL kv_store table in two
+  /// once it is estimated to hold more than this many rows. Writes wait
+  /// while half of its rows move. 0 never splits.
+  #[clap(
+    long,
+    env = "DENO_KV_POSTGRES_PARTITION_SPLIT_ROWS",
+    default_value = "0"
+  )]
+  pub postgres_partition_split_rows: u64,
+
+  /// Archive the range partitions of the PostgreSQL kv_store table that no
+  /// key was written to for this many seconds, leaving their keys to read
+  /// as expired. Archived partitions are kept as tables of their own until
+  /// they are uploaded with `--postgres-partition-archive-bucket`. 0 never
+  /// archives.
+  #[clap(
+    long,
+    env = "DENO_KV_POSTGRES_PARTITION_ARCHIVE_AFTER",
+    default_value = "0"
+  )]
+  pub postgres_partition_archive_after: u64,
+
+  /// Upload archived PostgreSQL partitions to this S3 bucket, then drop
+  /// them from the database.
+  #[clap(long, env = "DENO_KV_POSTGRES_PARTITION_ARCHIVE_BUCKET")]
+  pub postgres_partition_archive_bucket: Option<String>,
+
+  /// Prefix of the archived partitions in the S3 bucket, ending with a
+  /// slash.
+  #[clap(
+    long,
+    env = "DENO_KV_POSTGRES_PARTITION_ARCHIVE_PREFIX",
+    default_value = ""
+  )]
+  pub postgres_partition_archive_prefix: String,
+
+  /// S3 endpoint URL of the archive bucket like
+  /// `https://storage.googleapis.com`. Only needed for S3-compatible
+  /// services other than Amazon S3.
+  #[clap(long, env = "DENO_KV_POSTGRES_PARTITION_ARCHIVE_ENDPOINT")]
+  pub postgres_partition_archive_endpoint: Option<String>,
+
+  /// Keep a SQLite replica of the PostgreSQL database at this path and serve
+  /// eventually consistent reads and watches from it. Strong reads, writes
+  /// and the queue still go to PostgreSQL.
+  #[clap(long, env = "DENO_KV_POSTGRES_LOCAL_REPLICA")]
+  pub postgres_local_replica: Option<PathBuf>,
+
+  /// Maximum sustained PostgreSQL write operations per second.
+  #[clap(long, env = "DENO_KV_POSTGRES_WRITE_OPS_PER_SEC")]
+  pub postgres_write_ops_per_sec: Option<f64>,
+
+  /// Maximum sustained bytes written to PostgreSQL per second.
+  #[clap(long, env = "DENO_KV_POSTGRES_WRITE_BYTES_PER_SEC")]
+  pub postgres_write_bytes_per_sec: Option<f64>,
+
+  /// Number of throttled writes that may wait before further writes are
+  /// rejected and clients are asked to try again.
+  #[clap(
+    long,
+    env = "DENO_KV_POSTGRES_WRITE_QUEUE_LIMIT",
+    default_value = "1000"
+  )]
+  pub postgres_write_queue_limit: usize,
+
+  /// Consecutive PostgreSQL connection failures after which reads, writes
+  /// or queue operations fail fast. 0 disables the circuit breaker.
+  #[clap(
+    long,
+    env = "DENO_KV_POSTGRES_CIRCUIT_BREAKER_THRESHOLD",
+    default_value = "5"
+  )]
+  pub postgres_circuit_breaker_threshold: u32,
+
+  /// Seconds an open PostgreSQL circuit breaker fails fast.
+  #[clap(
+    long,
+    env = "DENO_KV_POSTGRES_CIRCUIT_BREAKER_COOLDOWN",
+    default_value = "30"
+  )]
+  pub postgres_circuit_breaker_cooldown: u64,
+
+  /// Seconds a request waits for a PostgreSQL connection before it is
+  /// rejected with 503 and a Retry-After header. 0 waits indefinitely.
+  #[clap(
+    long,
+    env = "DENO_KV_POSTGRES_POOL_WAIT_TIMEOUT",
+    default_value = "5"
+  )]
+  pub postgres_pool_wait_timeout: u64,
+
+  /// Lanes PostgreSQL keys are hashed to for connection affinity. Atomic
+  /// writes whose keys all fall in one lane share a connection kept for the
+  /// lane, which cuts tail latency for hot keys. Each lane holds one of the
+  /// pool's connections, of which there are `--num-workers` but at least
+  /// 10. 0 disables affinity.
+  #[clap(long, env = "DENO_KV_POSTGRES_AFFINITY_LANES", default_value = "0")]
+  pub postgres_affinity_lanes: usize,
+
+  /// Statements each PostgreSQL connection keeps prepared, so that the hot
+  /// reads and writes skip parsing and planning. 0 prepares statements
+  /// every time.
+  #[clap(
+    long,
+    env = "DENO_KV_POSTGRES_STATEMENT_CACHE_SIZE",
+    default_value = "64"
+  )]
+  pub postgres_statement_cache_size: usize,
+
+  /// Average number of retries of failed PostgreSQL operations allowed per
+  /// operation. Once spent, requests are rejected with 503 and a
+  /// Retry-After header instead of retried. 0 allows every retry.
+  #[clap(long, env = "DENO_KV_POSTGRES_RETRY_BUDGET", default_value = "0.2")]
+  pub postgres_retry_budget: f64,
+
   #[command(flatten)]
   pub replica: ReplicaOptions,
 }
 
+#[derive(Parser)]
+pub struct RepairOptions {
+  /// Report what would be repaired without changing anything.
+  #[clap(long)]
+  pub dry_run: bool,
+
+  /// Also rebuild the secondary indexes of the KV and queue tables.
+  #[clap(long)]
+  pub reindex: bool,
+}
+
+#[derive(Parser)]
+pub struct ReplayWorkloadOptions {
+  /// The trace file to replay.
+  #[clap(long)]
+  pub trace: PathBuf,
+
+  /// How many times faster than recorded to replay the workload.
+  #[clap(long, default_value = "1.0")]
+  pub speed: f64,
+}
+
+#[derive(Parser)]
+pub struct DoctorOptions {
+  /// The number of connections each server instance may open, to check
+  /// against the server's connection limit.
+  #[clap(long, default_value = "10")]
+  pub pool_size: usize,
+}
+
+#[derive(Parser)]
+pub struct SampleKeysOptions {
+  /// Only sample keys under this prefix: either a key like
+  /// `["users", 42]`, or a string for the first part of the key. Samples
+  /// the whole keyspace if not set.
+  #[clap(long)]
+  pub prefix: Option<String>,
+
+  /// Number of keys to sample.
+  #[clap(long, default_value = "20")]
+  pub count: usize,
+}
+
+#[derive(Parser)]
+#[clap(group(clap::ArgGroup::new("destination").required(true)))]
+pub struct CopyOptions {
+  /// Copy into the PostgreSQL database at this URL.
+  #[clap(long, group = "destination")]
+  pub to_postgres: Option<String>,
+
+  /// Copy into the SQLite database at this path, creating it if needed.
+  #[clap(long, group = "destination")]
+  pub to_sqlite: Option<PathBuf>,
+
+  /// Only copy keys under this prefix: either a key like `["users"]`, or a
+  /// string for the first part of the key. Copies everything if not set.
+  #[clap(long)]
+  pub prefix: Option<String>,
+
+  /// Remove the property FIELD from the values of keys under PREFIX, given
+  /// as `PREFIX=FIELD`, at any depth. PREFIX is a key or a string as for
+  /// `--prefix`. May be given several times.
+  #[clap(long = "drop-field")]
+  pub drop_fields: Vec<String>,
+
+  /// Replace the string property FIELD of the values of keys under PREFIX
+  /// with a hash of it, given as `PREFIX=FIELD`. Equal strings hash alike.
+  /// May be given several times.
+  #[clap(long = "hash-field")]
+  pub hash_fields: Vec<String>,
+
+  /// Leave the keys under this prefix out of the copy. May be given several
+  /// times.
+  #[clap(long = "skip")]
+  pub skip: Vec<String>,
+}
+
+#[derive(Parser)]
+pub struct SeedOptions {
+  /// The fixture file.
+  pub fixture: PathBuf,
+
+  /// Seed every namespace at most once per fixture name, skipping those
+  /// that were seeded before, rather than writing them again.
+  #[clap(long)]
+  pub idempotent: bool,
+
+  /// A JSON file of encryption keys by namespace, as given to `serve`.
+  /// Values in these namespaces are sealed with their key.
+  #[clap(long, env = "DENO_KV_ENCRYPTION_KEYS")]
+  pub encryption_keys: Option<PathBuf>,
+}
+
+#[cfg(feature = "kafka")]
+#[derive(Parser)]
+pub struct KafkaOptions {
+  /// Comma-separated list of Kafka bootstrap servers.
+  #[clap(long, env = "DENO_KV_KAFKA_BROKERS")]
+  pub kafka_brokers: String,
+
+  /// Publish changes to keys whose first part is the string PREFIX to
+  /// TOPIC, given as `PREFIX=TOPIC`. An empty PREFIX publishes every change.
+  #[clap(
+    long = "kafka-topic",
+    env = "DENO_KV_KAFKA_TOPICS",
+    value_delimiter = ','
+  )]
+  pub kafka_topics: Vec<String>,
+
+  /// Name under which the connector's progress is stored in the database.
+  /// Connectors sharing a name share their progress.
+  #[clap(long, env = "DENO_KV_KAFKA_SUBSCRIPTION", default_value = "kafka")]
+  pub kafka_subscription: String,
+}
+
 #[derive(Parser)]
 pub struct ReplicaOptions {
   /// The name of the S3 bucket to sync changes from.
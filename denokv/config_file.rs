@@ -0,0 +1,298 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! The TOML config file of the server, given with `--config`.
+//!
+//! Every option can be set in the file under its name with underscores:
+//! global options at the top level and options of `serve` in a `[serve]`
+//! table. Options given on the command line or in the environment take
+//! precedence over the file.
+//!
+//! ```toml
+//! database_type = "postgres"
+//! postgres_url = "postgresql://localhost/kv"
+//! log_level = "info,denokv_postgres=debug"
+//!
+//! [serve]
+//! addr = "0.0.0.0:4512"
+//! access_token = "..."
+//! max_read_entries = 1000
+//! postgres_write_ops_per_sec = 500
+//!
+//! [[serve.tokens]]
+//! name = "shop"
+//! token = "..."
+//! namespace = "shop"
+//! permissions = ["read", "write"]
+//! ```
+//!
+//! `log_level` is a filter in `RUST_LOG` syntax, and `serve.tokens` lists
+//! tokens in the format of the token registry. The server reloads the file
+//! on SIGHUP and when it changes, and applies the new `log_level`, tokens
+//! and PostgreSQL write rate limits. The token registry file is read again
+//! too. Other options only change on restart.
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Context;
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use futures::stream::unfold;
+use futures::Stream;
+use notify::RecursiveMode;
+use notify::Watcher;
+
+use crate::tenants::TokenEntry;
+
+/// How long to wait for further changes before reloading, as editors
+/// often write a file in several steps.
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Settings that are not command line options.
+const LOG_LEVEL: &str = "log_level";
+const SERVE: &str = "serve";
+const TOKENS: &str = "tokens";
+
+pub struct ConfigFile {
+  path: PathBuf,
+  settings: toml::Table,
+  /// Options of `serve` that were taken from the file, rather than from
+  /// the command line or the environment.
+  applied: HashSet<String>,
+}
+
+impl ConfigFile {
+  /// The file given with `--config` or `DENO_KV_CONFIG`, read before the
+  /// command line is parsed.
+  pub fn from_args() -> anyhow::Result<Option<Self>> {
+    let mut args = std::env::args_os().skip(1);
+    let mut path = None;
+    while let Some(arg) = args.next() {
+      if arg == "--config" {
+        path = args.next();
+      } else if let Some(value) =
+        arg.to_str().and_then(|arg| arg.strip_prefix("--config="))
+      {
+        path = Some(OsString::from(value));
+      }
+    }
+    match path.or_else(|| std::env::var_os("DENO_KV_CONFIG")) {
+      Some(path) => Self::read(PathBuf::from(path)).map(Some),
+      None => Ok(None),
+    }
+  }
+
+  fn read(path: PathBuf) -> anyhow::Result<Self> {
+    let file = std::fs::read_to_string(&path).with_context(|| {
+      format!("Failed to read the config file {}", path.display())
+    })?;
+    let settings: toml::Table = toml::from_str(&file)
+      .with_context(|| format!("Invalid config file {}", path.display()))?;
+    if settings.get(LOG_LEVEL).is_some_and(|level| !level.is_str()) {
+      anyhow::bail!("`{LOG_LEVEL}` must be a string in {}", path.display());
+    }
+    Ok(Self {
+      path,
+      settings,
+      applied: HashSet::new(),
+    })
+  }
+
+  /// Read the file again, keeping track of which options came from it.
+  pub fn reread(&self) -> anyhow::Result<Self> {
+    let mut file = Self::read(self.path.clone())?;
+    file.applied.clone_from(&self.applied);
+    Ok(file)
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  fn serve_settings(&self) -> Option<&toml::Table> {
+    self.settings.get(SERVE).and_then(|serve| serve.as_table())
+  }
+
+  /// Provide the options of `command` set in the file through their
+  /// environment variables, unless those are set already.
+  pub fn apply(&mut self, command: &clap::Command) -> anyhow::Result<()> {
+    for (key, value) in &self.settings {
+      match key.as_str() {
+        LOG_LEVEL => {}
+        SERVE => {
+          let table = value.as_table().with_context(|| {
+            format!("`{SERVE}` must be a table in {}", self.path.display())
+          })?;
+          let serve = command
+            .find_subcommand(SERVE)
+            .expect("the serve command exists");
+          for (key, value) in table {
+            if key == TOKENS {
+              continue;
+            }
+            if set_env(serve, key, value, &self.path)? {
+              self.applied.insert(key.clone());
+            }
+          }
+        }
+        _ => {
+          set_env(command, key, value, &self.path)?;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Forget the options that were given on the command line after all.
+  pub fn retain_applied(&mut self, matches: &ArgMatches) {
+    let Some(serve) = matches.subcommand_matches(SERVE) else {
+      self.applied.clear();
+      return;
+    };
+    self
+      .applied
+      .retain(|key| serve.value_source(key) == Some(ValueSource::EnvVariable));
+  }
+
+  pub fn log_level(&self) -> Option<&str> {
+    self
+      .settings
+      .get(LOG_LEVEL)
+      .and_then(|level| level.as_str())
+  }
+
+  /// The tokens listed in `serve.tokens`.
+  pub fn tokens(&self) -> anyhow::Result<Vec<TokenEntry>> {
+    match self.serve_settings().and_then(|serve| serve.get(TOKENS)) {
+      Some(tokens) => tokens.clone().try_into().with_context(|| {
+        format!("Invalid `{SERVE}.{TOKENS}` in {}", self.path.display())
+      }),
+      None => Ok(Vec::new()),
+    }
+  }
+
+  /// The value of the numeric `serve` option `key` if it was taken from the
+  /// file, else `current`.
+  pub fn serve_f64(
+    &self,
+    key: &str,
+    current: Option<f64>,
+  ) -> anyhow::Result<Option<f64>> {
+    if !self.applied.contains(key) {
+      return Ok(current);
+    }
+    match self.serve_settings().and_then(|serve| serve.get(key)) {
+      None => Ok(None),
+      Some(toml::Value::Float(value)) => Ok(Some(*value)),
+      Some(toml::Value::Integer(value)) => Ok(Some(*value as f64)),
+      Some(_) => anyhow::bail!(
+        "`{SERVE}.{key}` must be a number in {}",
+        self.path.display()
+      ),
+    }
+  }
+}
+
+/// Set the environment variable of the option `key` of `command` to
+/// `value`, unless it is set already. Returns whether it was set.
+fn set_env(
+  command: &clap::Command,
+  key: &str,
+  value: &toml::Value,
+  path: &Path,
+) -> anyhow::Result<bool> {
+  let arg = command
+    .get_arguments()
+    .find(|arg| arg.get_id() == key)
+    .with_context(|| {
+      format!(
+        "Unknown option `{key}` in the config file {}",
+        path.display()
+      )
+    })?;
+  let env = arg.get_env().with_context(|| {
+    format!("The option `{key}` can not be set in the config file")
+  })?;
+  if std::env::var_os(env).is_some() {
+    return Ok(false);
+  }
+  let value = env_value(value).with_context(|| {
+    format!("Invalid value for `{key}` in {}", path.display())
+  })?;
+  std::env::set_var(env, value);
+  Ok(true)
+}
+
+/// `value` as an option value. Arrays are joined with commas, as options
+/// taking several values are split at commas.
+fn env_value(value: &toml::Value) -> Option<String> {
+  match value {
+    toml::Value::String(value) => Some(value.clone()),
+    toml::Value::Integer(value) => Some(value.to_string()),
+    toml::Value::Float(value) => Some(value.to_string()),
+    toml::Value::Boolean(value) => Some(value.to_string()),
+    toml::Value::Array(values) => values
+      .iter()
+      .map(env_value)
+      .collect::<Option<Vec<_>>>()
+      .map(|values| values.join(",")),
+    toml::Value::Datetime(_) | toml::Value::Table(_) => None,
+  }
+}
+
+/// Yields whenever the file at `path` should be reloaded: on SIGHUP, and
+/// when it is written or replaced.
+pub fn changes(path: &Path) -> anyhow::Result<impl Stream<Item = ()>> {
+  let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+
+  let file_name = path.file_name().map(ToOwned::to_owned);
+  let changed = tx.clone();
+  let mut watcher = notify::recommended_watcher(
+    move |event: notify::Result<notify::Event>| {
+      let Ok(event) = event else {
+        return;
+      };
+      if (event.kind.is_create() || event.kind.is_modify())
+        && event
+          .paths
+          .iter()
+          .any(|path| path.file_name() == file_name.as_deref())
+      {
+        let _ = changed.send(());
+      }
+    },
+  )?;
+  // Editors often replace the file, so watch the directory it is in.
+  let dir = match path.parent() {
+    Some(dir) if !dir.as_os_str().is_empty() => dir,
+    _ => Path::new("."),
+  };
+  watcher
+    .watch(dir, RecursiveMode::NonRecursive)
+    .with_context(|| format!("Failed to watch {}", dir.display()))?;
+
+  #[cfg(unix)]
+  {
+    use tokio::signal::unix::signal;
+    use tokio::signal::unix::SignalKind;
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+      while hangups.recv().await.is_some() {
+        if tx.send(()).is_err() {
+          break;
+        }
+      }
+    });
+  }
+
+  Ok(unfold((watcher, rx), |(watcher, mut rx)| async move {
+    rx.recv().await?;
+    tokio::time::sleep(RELOAD_DEBOUNCE).await;
+    while rx.try_recv().is_ok() {}
+    Some(((), (watcher, rx)))
+  }))
+}
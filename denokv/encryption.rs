@@ -0,0 +1,361 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! At-rest encryption of the values in a namespace, with a key of its own.
+//!
+//! The keys are listed in a JSON file given with `--encryption-keys`, by
+//! namespace. A key is either given directly, as 32 random bytes in base64,
+//! or as a data key wrapped by AWS KMS, which is unwrapped once at startup:
+//!
+//! ```json
+//! {
+//!   "shop": { "key": "..." },
+//!   "billing": { "kms_key_id": "arn:aws:kms:...", "encrypted_key": "..." }
+//! }
+//! ```
+//!
+//! A wrapped data key is made with `aws kms generate-data-key --key-spec
+//! AES_256`, keeping its `CiphertextBlob`.
+//!
+//! Either kind of entry may list `plaintext_prefixes`, key prefixes within
+//! the namespace made of string parts, whose values are stored in plain so
+//! that the database can still work with them, for example to sum them or
+//! to serve materialized views:
+//!
+//! ```json
+//! { "shop": { "key": "...", "plaintext_prefixes": [["counters"], ["public", "catalog"]] } }
+//! ```
+//!
+//! Values written through a token of the namespace are sealed with
+//! AES-256-GCM before they reach the database, and opened again when read
+//! or watched, so the database, its backups and admin tokens without the
+//! namespace only ever see ciphertext. Keys and versionstamps are not
+//! encrypted, but every value is bound to its key, so a value copied to
+//! another key fails to open. Values of versionstamped keys are bound to
+//! the key without its versionstamp, and the payloads of queue messages,
+//! which are also stored as the value of their undelivered keys, to no key.
+//! Sum, min and max mutations need the plain value in the database and are
+//! rejected. The key must be configured before the namespace stores
+//! anything, as values written without it can not be read with it. Values
+//! under plaintext prefixes that were sealed before the prefix was listed
+//! are still opened when read.
+//!
+//! Since sealed values can not be compared, fields that must be looked up
+//! by equality can be indexed by their blind index instead: a keyed hash of
+//! the value that is the same for equal values but reveals nothing else
+//! without the namespace's key. Clients get it from `POST /blind_index` and
+//! store it in the key of an index entry, such as
+//! `["users_by_email", <blind index>]`.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use aes_gcm::aead::Aead;
+use aes_gcm::aead::Payload;
+use aes_gcm::Aes256Gcm;
+use aes_gcm::KeyInit;
+use aes_gcm::Nonce;
+use anyhow::Context;
+use aws_sdk_kms::primitives::Blob;
+use aws_smithy_async::rt::sleep::TokioSleep;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use denokv_proto::encode_key;
+use denokv_proto::Key;
+use denokv_proto::KeyPart;
+use denokv_proto::KvValue;
+use hmac::Hmac;
+use hmac::Mac;
+use serde::Deserialize;
+use sha2::Sha256;
+
+/// The first byte of every sealed value, so the format can change later.
+const FORMAT_VERSION: u8 = 1;
+const NONCE_LEN: usize = 12;
+
+/// How the plain value was encoded, stored next to the ciphertext.
+const ENCODING_V8: u8 = 1;
+const ENCODING_BYTES: u8 = 2;
+const ENCODING_U64: u8 = 3;
+
+/// What a value is bound to, stored next to the ciphertext, so that a
+/// sealed value can not be passed off as something it was not sealed for.
+const BOUND_TO_KEY: u8 = 1;
+const BOUND_TO_KEY_PREFIX: u8 = 2;
+const QUEUE_PAYLOAD: u8 = 3;
+
+/// The length of the string key part that versionstamped keys end in.
+const VERSIONSTAMP_SUFFIX_LEN: usize = 22;
+
+/// Derives the key of blind indexes from the key of the namespace, so that
+/// one never reveals anything about the other.
+const BLIND_INDEX_KEY_CONTEXT: &[u8] = b"denokv blind index key";
+
+#[derive(Deserialize)]
+struct KeyEntry {
+  #[serde(flatten)]
+  source: KeySource,
+  #[serde(default)]
+  plaintext_prefixes: Vec<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KeySource {
+  Plain {
+    key: String,
+  },
+  Kms {
+    kms_key_id: String,
+    encrypted_key: String,
+  },
+}
+
+/// The encryption keys of namespaces, by namespace.
+#[derive(Default)]
+pub struct NamespaceKeys {
+  ciphers: HashMap<String, Arc<ValueCipher>>,
+}
+
+impl NamespaceKeys {
+  /// Read the key file at `path`, unwrapping keys with AWS KMS as needed.
+  pub async fn load(path: &Path) -> anyhow::Result<Self> {
+    let file = std::fs::read(path).with_context(|| {
+      format!("Failed to read the encryption keys {}", path.display())
+    })?;
+    let entries: HashMap<String, KeyEntry> = serde_json::from_slice(&file)
+      .with_context(|| {
+        format!("Invalid encryption keys file {}", path.display())
+      })?;
+
+    let mut kms = None;
+    let mut ciphers = HashMap::new();
+    for (namespace, entry) in entries {
+      let key = match entry.source {
+        KeySource::Plain { key } => BASE64.decode(key).with_context(|| {
+          format!("The key of namespace '{namespace}' is not valid base64")
+        })?,
+        KeySource::Kms {
+          kms_key_id,
+          encrypted_key,
+        } => {
+          let encrypted_key =
+            BASE64.decode(encrypted_key).with_context(|| {
+              format!(
+                "The wrapped key of namespace '{namespace}' is not base64"
+              )
+            })?;
+          if kms.is_none() {
+            kms = Some(kms_client().await);
+          }
+          let output = kms
+            .as_ref()
+            .unwrap()
+            .decrypt()
+            .key_id(kms_key_id)
+            .ciphertext_blob(Blob::new(encrypted_key))
+            .send()
+            .await
+            .with_context(|| {
+              format!("Failed to unwrap the key of namespace '{namespace}'")
+            })?;
+          output
+            .plaintext()
+            .map(|key| key.as_ref().to_vec())
+            .with_context(|| {
+              format!("AWS KMS returned no key for namespace '{namespace}'")
+            })?
+        }
+      };
+      let plaintext_prefixes = entry
+        .plaintext_prefixes
+        .into_iter()
+        .map(|parts| {
+          let parts = parts.into_iter().map(KeyPart::String).collect();
+          encode_key(&Key(parts))
+        })
+        .collect::<Result<_, _>>()?;
+      let cipher = ValueCipher::new(&namespace, &key, plaintext_prefixes)?;
+      ciphers.insert(namespace, Arc::new(cipher));
+    }
+    Ok(Self { ciphers })
+  }
+
+  pub fn get(&self, namespace: &str) -> Option<Arc<ValueCipher>> {
+    self.ciphers.get(namespace).cloned()
+  }
+}
+
+async fn kms_client() -> aws_sdk_kms::Client {
+  let config = aws_config::from_env()
+    .sleep_impl(Arc::new(TokioSleep::new()))
+    .load()
+    .await;
+  aws_sdk_kms::Client::new(&config)
+}
+
+/// Seals and opens the values of one namespace.
+pub struct ValueCipher {
+  aead: Aes256Gcm,
+  /// The encoded namespace prefix, authenticated with every value so that
+  /// a value can not be moved to another namespace with the same key.
+  prefix: Vec<u8>,
+  /// Encoded prefixes, within the namespace, of the keys whose values are
+  /// not sealed.
+  plaintext_prefixes: Vec<Vec<u8>>,
+  blind_index_key: Vec<u8>,
+}
+
+impl ValueCipher {
+  fn new(
+    namespace: &str,
+    key: &[u8],
+    plaintext_prefixes: Vec<Vec<u8>>,
+  ) -> anyhow::Result<Self> {
+    let aead = Aes256Gcm::new_from_slice(key).map_err(|_| {
+      anyhow::anyhow!("The key of namespace '{namespace}' must be 32 bytes")
+    })?;
+    let prefix =
+      encode_key(&Key(vec![KeyPart::String(namespace.to_string())]))?;
+    let blind_index_key = hmac(key, &[BLIND_INDEX_KEY_CONTEXT]).to_vec();
+    Ok(Self {
+      aead,
+      prefix,
+      plaintext_prefixes,
+      blind_index_key,
+    })
+  }
+
+  /// Whether the value of `key`, within the namespace, is stored in plain.
+  pub fn is_plaintext(&self, key: &[u8]) -> bool {
+    self
+      .plaintext_prefixes
+      .iter()
+      .any(|prefix| key.starts_with(prefix))
+  }
+
+  /// The blind index of `value` in `field`: the same for equal values of
+  /// the field in this namespace, and unrelated across fields and
+  /// namespaces.
+  pub fn blind_index(&self, field: &str, value: &[u8]) -> [u8; 32] {
+    let field_len = (field.len() as u32).to_be_bytes();
+    hmac(
+      &self.blind_index_key,
+      &[&self.prefix, &field_len, field.as_bytes(), value],
+    )
+  }
+
+  /// The data authenticated along with a value: the namespace, the header
+  /// of the sealed value, and the key it is bound to, if any. The encoded
+  /// namespace ends in a terminator and the header is of fixed length, so
+  /// the parts can not run into each other.
+  fn associated_data(&self, header: [u8; 3], key: &[u8]) -> Vec<u8> {
+    [&self.prefix[..], &header, key].concat()
+  }
+
+  fn seal_bytes(
+    &self,
+    binding: u8,
+    key: &[u8],
+    encoding: u8,
+    plaintext: &[u8],
+  ) -> Vec<u8> {
+    let header = [FORMAT_VERSION, binding, encoding];
+    let nonce: [u8; NONCE_LEN] = rand::random();
+    let ciphertext = self
+      .aead
+      .encrypt(
+        Nonce::from_slice(&nonce),
+        Payload {
+          msg: plaintext,
+          aad: &self.associated_data(header, key),
+        },
+      )
+      .expect("encrypting in memory does not fail");
+    [&header[..], &nonce, &ciphertext].concat()
+  }
+
+  fn seal_value(&self, binding: u8, key: &[u8], value: &KvValue) -> KvValue {
+    let sealed = match value {
+      KvValue::V8(data) => self.seal_bytes(binding, key, ENCODING_V8, data),
+      KvValue::Bytes(data) => {
+        self.seal_bytes(binding, key, ENCODING_BYTES, data)
+      }
+      KvValue::U64(n) => {
+        self.seal_bytes(binding, key, ENCODING_U64, &n.to_le_bytes())
+      }
+    };
+    KvValue::Bytes(sealed)
+  }
+
+  /// `value` sealed for `key`, within the namespace, as bytes.
+  pub fn seal(&self, key: &[u8], value: &KvValue) -> KvValue {
+    self.seal_value(BOUND_TO_KEY, key, value)
+  }
+
+  /// `value` sealed for the keys `key` becomes once a versionstamp is
+  /// appended to it, as bytes.
+  pub fn seal_versionstamped(&self, key: &[u8], value: &KvValue) -> KvValue {
+    self.seal_value(BOUND_TO_KEY_PREFIX, key, value)
+  }
+
+  /// A queue message payload sealed. Undelivered messages are stored as
+  /// V8 values holding the sealed payload, which [`ValueCipher::open`]
+  /// opens at any key.
+  pub fn seal_payload(&self, payload: &[u8]) -> Vec<u8> {
+    self.seal_bytes(QUEUE_PAYLOAD, &[], ENCODING_V8, payload)
+  }
+
+  /// The plain value of a sealed `value` stored at `key`, within the
+  /// namespace, or `None` if it was not sealed with this key or for
+  /// another key.
+  pub fn open(&self, key: &[u8], value: &KvValue) -> Option<KvValue> {
+    let (KvValue::V8(sealed) | KvValue::Bytes(sealed)) = value else {
+      return None;
+    };
+    let [FORMAT_VERSION, binding, encoding, rest @ ..] = sealed.as_slice()
+    else {
+      return None;
+    };
+    let bound_key = match *binding {
+      BOUND_TO_KEY => key,
+      BOUND_TO_KEY_PREFIX => {
+        &key[..key.len().checked_sub(VERSIONSTAMP_SUFFIX_LEN)?]
+      }
+      QUEUE_PAYLOAD => &[],
+      _ => return None,
+    };
+    if rest.len() < NONCE_LEN {
+      return None;
+    }
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let header = [FORMAT_VERSION, *binding, *encoding];
+    let plaintext = self
+      .aead
+      .decrypt(
+        Nonce::from_slice(nonce),
+        Payload {
+          msg: ciphertext,
+          aad: &self.associated_data(header, bound_key),
+        },
+      )
+      .ok()?;
+    match *encoding {
+      ENCODING_V8 => Some(KvValue::V8(plaintext)),
+      ENCODING_BYTES => Some(KvValue::Bytes(plaintext)),
+      ENCODING_U64 => {
+        Some(KvValue::U64(u64::from_le_bytes(plaintext.try_into().ok()?)))
+      }
+      _ => None,
+    }
+  }
+}
+
+fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+  let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key)
+    .expect("HMAC accepts keys of any length");
+  for part in parts {
+    mac.update(part);
+  }
+  mac.finalize().into_bytes().into()
+}
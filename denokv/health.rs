@@ -0,0 +1,113 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! Health checks for process supervisors.
+//!
+//! With `--health-listen`, a separate unauthenticated HTTP server answers
+//! `GET /healthz` as long as the process runs, and `GET /readyz` with
+//! 200 OK while the database is reachable and the server is not draining,
+//! and 503 Service Unavailable otherwise. Both are meant for liveness and
+//! readiness probes. `GET /metrics` serves the Prometheus metrics there
+//! as well.
+//!
+//! Under systemd, the server reports readiness once it is listening,
+//! pings the watchdog if `WatchdogSec` is set, and reports when it starts
+//! draining.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::Context;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Json;
+use axum::Router;
+use log::info;
+use serde_json::json;
+use tokio_util::sync::CancellationToken;
+
+use crate::metrics::Metrics;
+use crate::DatabaseBackend;
+
+#[derive(Clone)]
+struct HealthState {
+  database: DatabaseBackend,
+  metrics: Arc<Metrics>,
+  draining: CancellationToken,
+}
+
+/// Serve the health checks on `addr` until the process exits.
+pub fn serve(
+  addr: SocketAddr,
+  database: DatabaseBackend,
+  metrics: Arc<Metrics>,
+  draining: CancellationToken,
+) -> anyhow::Result<()> {
+  let listener = std::net::TcpListener::bind(addr)
+    .context("Failed to start the health check server")?;
+  info!("Serving health checks on http://{}", listener.local_addr()?);
+  let app = Router::new()
+    .route("/healthz", get(|| async { "ok" }))
+    .route("/readyz", get(readiness))
+    .route(
+      "/metrics",
+      get(|State(state): State<HealthState>| async move {
+        state.metrics.render(state.database.metrics())
+      }),
+    )
+    .with_state(HealthState {
+      database,
+      metrics,
+      draining,
+    });
+  let server = axum::Server::from_tcp(listener)?.serve(app.into_make_service());
+  tokio::spawn(server);
+  Ok(())
+}
+
+async fn readiness(State(state): State<HealthState>) -> impl IntoResponse {
+  if state.draining.is_cancelled() {
+    let body = json!({ "ready": false, "draining": true });
+    return (StatusCode::SERVICE_UNAVAILABLE, Json(body));
+  }
+  let (healthy, database) = state.database.health().await;
+  let status = if healthy {
+    StatusCode::OK
+  } else {
+    StatusCode::SERVICE_UNAVAILABLE
+  };
+  (
+    status,
+    Json(json!({ "ready": healthy, "database": database })),
+  )
+}
+
+/// Tell systemd that the server is ready, keep its watchdog fed, and tell
+/// it when the server starts draining. Does nothing outside of systemd.
+#[cfg(unix)]
+pub fn notify_systemd(draining: CancellationToken) {
+  use sd_notify::NotifyState;
+
+  if let Err(e) = sd_notify::notify(false, &[NotifyState::Ready]) {
+    log::warn!("Failed to notify systemd of readiness: {e}");
+  }
+  let mut watchdog_usec = 0;
+  if sd_notify::watchdog_enabled(false, &mut watchdog_usec) {
+    let period = std::time::Duration::from_micros(watchdog_usec / 2);
+    tokio::spawn(async move {
+      let mut timer = tokio::time::interval(period);
+      loop {
+        timer.tick().await;
+        let _ = sd_notify::notify(false, &[NotifyState::Watchdog]);
+      }
+    });
+  }
+  tokio::spawn(async move {
+    draining.cancelled().await;
+    let _ = sd_notify::notify(false, &[NotifyState::Stopping]);
+  });
+}
+
+#[cfg(not(unix))]
+pub fn notify_systemd(_draining: CancellationToken) {}
@@ -0,0 +1,119 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! Publishes KV changes to Kafka topics.
+//!
+//! Every `PREFIX=TOPIC` mapping follows its own durable subscription on the
+//! PostgreSQL database, named `<subscription>/<TOPIC>`. An entry is only
+//! acknowledged to the subscription once Kafka has confirmed the record, so
+//! delivery is at-least-once and a restarted connector continues from the
+//! last confirmed record.
+
+use denokv_postgres::Postgres;
+use denokv_proto::encode_key;
+use denokv_proto::Key;
+use denokv_proto::KeyPart;
+use denokv_proto::KvEntry;
+use denokv_proto::KvValue;
+use futures::TryStreamExt;
+use log::info;
+use rdkafka::producer::FutureProducer;
+use rdkafka::producer::FutureRecord;
+use rdkafka::util::Timeout;
+use rdkafka::ClientConfig;
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::config::KafkaOptions;
+use crate::open_postgres_maintenance;
+
+pub async fn run_connect_kafka(
+  config: &'static Config,
+  options: &'static KafkaOptions,
+) -> anyhow::Result<()> {
+  let postgres = open_postgres_maintenance(config, "connect-kafka").await?;
+  let producer: FutureProducer = ClientConfig::new()
+    .set("bootstrap.servers", &options.kafka_brokers)
+    .set("enable.idempotence", "true")
+    .create()?;
+
+  let mut publishers = Vec::new();
+  for mapping in &options.kafka_topics {
+    let (prefix, topic) = parse_topic_mapping(mapping)?;
+    let name = format!("{}/{}", options.kafka_subscription, topic);
+    publishers.push(publish(
+      postgres.clone(),
+      producer.clone(),
+      name,
+      prefix,
+      topic,
+    ));
+  }
+  if publishers.is_empty() {
+    anyhow::bail!("At least one --kafka-topic mapping is required");
+  }
+  futures::future::try_join_all(publishers).await?;
+  Ok(())
+}
+
+/// Parse a `PREFIX=TOPIC` mapping into the encoded key prefix `[PREFIX]` and
+/// the topic. An empty PREFIX maps the whole keyspace.
+fn parse_topic_mapping(mapping: &str) -> anyhow::Result<(Vec<u8>, String)> {
+  let Some((prefix, topic)) = mapping.split_once('=') else {
+    anyhow::bail!("Invalid topic mapping '{}'. Expected PREFIX=TOPIC", mapping);
+  };
+  let prefix = if prefix.is_empty() {
+    Vec::new()
+  } else {
+    encode_key(&Key(vec![KeyPart::String(prefix.to_string())]))?
+  };
+  Ok((prefix, topic.to_string()))
+}
+
+async fn publish(
+  postgres: Postgres,
+  producer: FutureProducer,
+  name: String,
+  prefix: Vec<u8>,
+  topic: String,
+) -> anyhow::Result<()> {
+  info!("Publishing subscription {name} to topic {topic}");
+  let entries = postgres.subscription_stream(name, prefix);
+  futures::pin_mut!(entries);
+  while let Some(entry) = entries.try_next().await? {
+    let payload = serde_json::to_vec(&ChangeRecord::from(&entry))?;
+    let record = FutureRecord::to(&topic).key(&entry.key).payload(&payload);
+    // Not asking for the next entry until the record is confirmed is what
+    // keeps the subscription from acknowledging unpublished changes.
+    producer
+      .send(record, Timeout::Never)
+      .await
+      .map_err(|(err, _)| err)?;
+  }
+  Ok(())
+}
+
+/// The JSON payload of a published change. Keys, versionstamps and binary
+/// values are hex encoded.
+#[derive(Serialize)]
+struct ChangeRecord {
+  key: String,
+  versionstamp: String,
+  encoding: &'static str,
+  value: String,
+}
+
+impl From<&KvEntry> for ChangeRecord {
+  fn from(entry: &KvEntry) -> Self {
+    let (encoding, value) = match &entry.value {
+      KvValue::V8(value) => ("v8", hex::encode(value)),
+      KvValue::Bytes(value) => ("bytes", hex::encode(value)),
+      KvValue::U64(value) => ("u64", value.to_string()),
+    };
+    Self {
+      key: hex::encode(&entry.key),
+      versionstamp: hex::encode(entry.versionstamp),
+      encoding,
+      value,
+    }
+  }
+}
@@ -0,0 +1,219 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! Listening sockets and draining, so that the server can be restarted
+//! behind a load balancer without clients noticing.
+//!
+//! The listening socket is taken from systemd socket activation if the
+//! server was started with one (`LISTEN_FDS`), and bound otherwise. With
+//! `--reuse-port`, a new server can bind the address of one that is still
+//! running. On SIGTERM or Ctrl-C the server stops accepting connections,
+//! ends watches with a hint to reconnect, and exits once the requests in
+//! flight have finished or the drain timeout has passed.
+
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Context;
+use axum::Router;
+use futures::future::BoxFuture;
+use futures::stream::unfold;
+use futures::FutureExt;
+use log::info;
+use log::warn;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+use crate::config::ServeOptions;
+
+/// Pending connections the kernel queues for a socket bound here.
+const LISTEN_BACKLOG: u32 = 1024;
+
+pub enum Listener {
+  Tcp(std::net::TcpListener),
+  #[cfg(unix)]
+  Unix(tokio::net::UnixListener),
+}
+
+impl Listener {
+  /// The socket passed by socket activation, or else the one configured
+  /// in `options`.
+  pub fn open(options: &ServeOptions) -> anyhow::Result<Self> {
+    #[cfg(unix)]
+    if let Some(listener) = activated_listener()? {
+      return Ok(listener);
+    }
+    #[cfg(unix)]
+    if let Some(path) = &options.unix_socket {
+      return bind_unix(path);
+    }
+    #[cfg(unix)]
+    let reuse_port = options.reuse_port;
+    #[cfg(not(unix))]
+    let reuse_port = false;
+    bind_tcp(options.addr, reuse_port)
+  }
+
+  /// Serve `app` until `draining` is cancelled and every connection has
+  /// closed.
+  pub fn serve(
+    self,
+    app: Router,
+    http2_only: bool,
+    draining: CancellationToken,
+  ) -> anyhow::Result<BoxFuture<'static, hyper::Result<()>>> {
+    let app = app.into_make_service();
+    let shutdown = draining.cancelled_owned();
+    Ok(match self {
+      Listener::Tcp(listener) => {
+        info!("Listening on http://{}", listener.local_addr()?);
+        axum::Server::from_tcp(listener)?
+          .http2_only(http2_only)
+          .serve(app)
+          .with_graceful_shutdown(shutdown)
+          .boxed()
+      }
+      #[cfg(unix)]
+      Listener::Unix(listener) => {
+        let path = listener.local_addr()?;
+        let path = path.as_pathname().unwrap_or(Path::new("<unnamed>"));
+        info!("Listening on unix:{}", path.display());
+        let incoming = unfold(listener, |listener| async move {
+          let stream = listener.accept().await.map(|(stream, _)| stream);
+          Some((stream, listener))
+        });
+        axum::Server::builder(hyper::server::accept::from_stream(incoming))
+          .http2_only(http2_only)
+          .serve(app)
+          .with_graceful_shutdown(shutdown)
+          .boxed()
+      }
+    })
+  }
+}
+
+fn bind_tcp(addr: SocketAddr, reuse_port: bool) -> anyhow::Result<Listener> {
+  let socket = if addr.is_ipv4() {
+    tokio::net::TcpSocket::new_v4()
+  } else {
+    tokio::net::TcpSocket::new_v6()
+  }?;
+  #[cfg(unix)]
+  {
+    socket.set_reuseaddr(true)?;
+    socket.set_reuseport(reuse_port)?;
+  }
+  #[cfg(not(unix))]
+  let _ = reuse_port;
+  socket.bind(addr).context("Failed to start server")?;
+  let listener = socket.listen(LISTEN_BACKLOG)?;
+  Ok(Listener::Tcp(listener.into_std()?))
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> anyhow::Result<Listener> {
+  use std::os::unix::fs::FileTypeExt;
+
+  if let Ok(metadata) = std::fs::symlink_metadata(path) {
+    if !metadata.file_type().is_socket() {
+      anyhow::bail!("{} exists and is not a socket", path.display());
+    }
+    std::fs::remove_file(path).context("Failed to remove the stale socket")?;
+  }
+  let listener =
+    tokio::net::UnixListener::bind(path).context("Failed to start server")?;
+  Ok(Listener::Unix(listener))
+}
+
+/// The listening socket systemd passed to this process, if any. Only a
+/// single TCP or Unix socket is supported.
+#[cfg(unix)]
+fn activated_listener() -> anyhow::Result<Option<Listener>> {
+  use std::os::fd::FromRawFd;
+  use std::os::fd::OwnedFd;
+
+  /// The first file descriptor passed by socket activation.
+  const LISTEN_FDS_START: i32 = 3;
+
+  let Ok(pid) = std::env::var("LISTEN_PID") else {
+    return Ok(None);
+  };
+  if pid.parse::<u32>().ok() != Some(std::process::id()) {
+    return Ok(None);
+  }
+  let fds = std::env::var("LISTEN_FDS").unwrap_or_default();
+  if fds != "1" {
+    anyhow::bail!("Expected one socket from socket activation, got {fds:?}");
+  }
+  // Sockets are not inherited further, so neither is the environment.
+  std::env::remove_var("LISTEN_PID");
+  std::env::remove_var("LISTEN_FDS");
+  std::env::remove_var("LISTEN_FDNAMES");
+
+  // SAFETY: LISTEN_PID names this process, so the service manager passed
+  // it LISTEN_FDS_START and nothing else owns it.
+  let fd = unsafe { OwnedFd::from_raw_fd(LISTEN_FDS_START) };
+  let listener = std::net::TcpListener::from(fd);
+  if listener.local_addr().is_ok() {
+    info!("Using the socket passed by socket activation");
+    return Ok(Some(Listener::Tcp(listener)));
+  }
+  let listener =
+    std::os::unix::net::UnixListener::from(OwnedFd::from(listener));
+  listener
+    .local_addr()
+    .context("The socket passed by socket activation is not listening")?;
+  listener.set_nonblocking(true)?;
+  info!("Using the socket passed by socket activation");
+  Ok(Some(Listener::Unix(tokio::net::UnixListener::from_std(
+    listener,
+  )?)))
+}
+
+/// Cancel `draining` on the first SIGTERM or Ctrl-C.
+pub fn drain_on_signal(draining: CancellationToken) -> anyhow::Result<()> {
+  #[cfg(unix)]
+  let mut sigterm =
+    tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+  tokio::spawn(async move {
+    #[cfg(unix)]
+    tokio::select! {
+      _ = sigterm.recv() => {}
+      _ = tokio::signal::ctrl_c() => {}
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+    info!("Draining connections");
+    draining.cancel();
+  });
+  Ok(())
+}
+
+/// Run `server` until it has drained, then wait for the connections it
+/// handed off to `upgraded`, giving up on both after `timeout`.
+pub async fn serve_until_drained(
+  server: BoxFuture<'static, hyper::Result<()>>,
+  draining: &CancellationToken,
+  upgraded: &TaskTracker,
+  timeout: Duration,
+) -> anyhow::Result<()> {
+  let mut server = server;
+  tokio::select! {
+    result = &mut server => return Ok(result?),
+    _ = draining.cancelled() => {}
+  }
+  upgraded.close();
+  let drained = async {
+    server.await?;
+    upgraded.wait().await;
+    Ok::<_, hyper::Error>(())
+  };
+  match tokio::time::timeout(timeout, drained).await {
+    Ok(result) => result?,
+    Err(_) => warn!(
+      "Connections were still open after draining for {}s",
+      timeout.as_secs()
+    ),
+  }
+  Ok(())
+}
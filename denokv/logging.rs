@@ -0,0 +1,81 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! An `env_logger` whose filter can be changed while the server runs, and
+//! that can write JSON lines.
+
+use std::io::Write;
+use std::sync::OnceLock;
+use std::sync::RwLock;
+
+use chrono::SecondsFormat;
+use env_logger::Env;
+use log::Log;
+use serde_json::json;
+
+use crate::config::LogFormat;
+
+/// The filter used when neither `RUST_LOG` nor the config file set one.
+const DEFAULT_FILTER: &str = "info";
+
+static LOGGER: OnceLock<ReloadableLogger> = OnceLock::new();
+
+struct ReloadableLogger {
+  format: LogFormat,
+  inner: RwLock<env_logger::Logger>,
+}
+
+impl Log for ReloadableLogger {
+  fn enabled(&self, metadata: &log::Metadata) -> bool {
+    self.inner.read().unwrap().enabled(metadata)
+  }
+
+  fn log(&self, record: &log::Record) {
+    self.inner.read().unwrap().log(record)
+  }
+
+  fn flush(&self) {
+    self.inner.read().unwrap().flush()
+  }
+}
+
+/// Filtered by `RUST_LOG` if set, else `filter`, else the default.
+fn build(format: LogFormat, filter: Option<&str>) -> env_logger::Logger {
+  let mut builder = env_logger::Builder::from_env(
+    Env::default().default_filter_or(filter.unwrap_or(DEFAULT_FILTER)),
+  );
+  if format == LogFormat::Json {
+    builder.format(|buf, record| {
+      let line = json!({
+        "time": denokv_proto::time::utc_now()
+          .to_rfc3339_opts(SecondsFormat::Millis, true),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+      });
+      writeln!(buf, "{line}")
+    });
+  }
+  builder.build()
+}
+
+/// Install the logger with `filter`, in `RUST_LOG` syntax.
+pub fn init(format: LogFormat, filter: Option<&str>) {
+  let logger = build(format, filter);
+  log::set_max_level(logger.filter());
+  let logger = LOGGER.get_or_init(|| ReloadableLogger {
+    format,
+    inner: RwLock::new(logger),
+  });
+  log::set_logger(logger).expect("the logger is only installed once");
+}
+
+/// Replace the filter of the installed logger. `RUST_LOG` still takes
+/// precedence.
+pub fn set_filter(filter: Option<&str>) {
+  let Some(installed) = LOGGER.get() else {
+    return;
+  };
+  let logger = build(installed.format, filter);
+  log::set_max_level(logger.filter());
+  *installed.inner.write().unwrap() = logger;
+}
@@ -2,6 +2,7 @@ use std::borrow::Cow;
 use std::io::Write;
 use std::path::Path;
 use std::sync::Arc;
+use std::sync::RwLock;
 
 use anyhow::Context;
 use aws_smithy_async::rt::sleep::TokioSleep;
@@ -11,8 +12,14 @@ use axum::body::Body;
 use axum::body::Bytes;
 use axum::body::StreamBody;
 use axum::debug_handler;
+use axum::extract::ws::CloseFrame;
+use axum::extract::ws::Message as WsMessage;
+use axum::extract::ws::WebSocket;
+use axum::extract::ws::WebSocketUpgrade;
 use axum::extract::FromRequest;
+use axum::extract::Query;
 use axum::extract::State;
+use axum::http::header::RETRY_AFTER;
 use axum::http::HeaderMap;
 use axum::http::Request;
 use axum::http::StatusCode;
@@ -20,21 +27,43 @@ use axum::middleware;
 use axum::middleware::Next;
 use axum::response::IntoResponse;
 use axum::response::Response;
+use axum::routing::get;
 use axum::routing::post;
+use axum::Extension;
 use axum::Json;
 use axum::Router;
 use chrono::DateTime;
 use chrono::Duration;
 use chrono::SecondsFormat;
 use chrono::Utc;
-use clap::Parser;
+use clap::CommandFactory;
+use clap::FromArgMatches;
 use config::Config;
+use config::CopyOptions;
+use config::DoctorOptions;
 use config::PitrOptions;
+use config::RepairOptions;
+use config::ReplayWorkloadOptions;
 use config::ReplicaOptions;
+use config::SampleKeysOptions;
+use config::SeedOptions;
 use config::ServeOptions;
 use config::SubCmd;
-use constant_time_eq::constant_time_eq;
+use denokv_postgres::Durability;
+use denokv_postgres::LocalReplica;
+use denokv_postgres::PartitionedPostgres;
+use denokv_postgres::Postgres;
+use denokv_postgres::PostgresConfig;
+use denokv_postgres::PostgresError;
+use denokv_postgres::PrefixUsage;
+use denokv_postgres::Pressure;
+use denokv_postgres::TablePartitioning;
+use denokv_postgres::Transforms;
 use denokv_proto::datapath as pb;
+use denokv_proto::encode_key;
+use denokv_proto::encode_value;
+use denokv_proto::format_key;
+use denokv_proto::parse_key;
 use denokv_proto::time::utc_now;
 use denokv_proto::AtomicWrite;
 use denokv_proto::Consistency;
@@ -42,16 +71,21 @@ use denokv_proto::ConvertError;
 use denokv_proto::Database;
 use denokv_proto::DatabaseMetadata;
 use denokv_proto::EndpointInfo;
+use denokv_proto::Feature;
+use denokv_proto::Key;
+use denokv_proto::KeyPart;
 use denokv_proto::MetadataExchangeRequest;
+use denokv_proto::RateLimit;
+use denokv_proto::ReadBudget;
 use denokv_proto::ReadRange;
 use denokv_proto::SnapshotReadOptions;
+use denokv_proto::WatchOutputEncoder;
+use denokv_proto::FEATURES_HEADER;
 use denokv_sqlite::Connection;
 use denokv_sqlite::Sqlite;
 use denokv_sqlite::SqliteBackendError;
 use denokv_sqlite::SqliteConfig;
 use denokv_sqlite::SqliteNotifier;
-use denokv_postgres::Postgres;
-use denokv_postgres::PostgresConfig;
 use denokv_timemachine::backup_source_s3::DatabaseBackupSourceS3;
 use denokv_timemachine::backup_source_s3::DatabaseBackupSourceS3Config;
 use denokv_timemachine::time_travel::TimeTravelControl;
@@ -63,30 +97,75 @@ use hyper::client::HttpConnector;
 use hyper_proxy::Intercept;
 use hyper_proxy::Proxy;
 use hyper_proxy::ProxyConnector;
-use log::error;
 use log::info;
+use log::warn;
 use prost::DecodeError;
 use prost::Message;
 use rand::Rng;
 use rand::SeedableRng;
 use rusqlite::OpenFlags;
+use serde::Deserialize;
+use serde::Serialize;
 use std::env;
 use thiserror::Error;
 use tokio::sync::oneshot;
 use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 use uuid::Uuid;
 
+use crate::audit::Auditor;
 use crate::config::PitrSubCmd;
-
+use crate::config_file::ConfigFile;
+use crate::encryption::NamespaceKeys;
+use crate::listen::Listener;
+use crate::metrics::Metrics;
+use crate::namespace_modes::NamespaceMode;
+use crate::namespace_modes::NamespaceModes;
+use crate::partition_archive::PartitionArchive;
+use crate::quotas::QuotaMonitor;
+use crate::tenants::Permission;
+use crate::tenants::Tenant;
+use crate::tenants::TokenRegistry;
+use crate::workload::WorkloadRecorder;
+
+mod audit;
 mod config;
+mod config_file;
+mod encryption;
+mod health;
+#[cfg(feature = "kafka")]
+mod kafka;
+mod listen;
+mod logging;
+mod metrics;
+mod namespace_modes;
+mod openapi;
+mod partition_archive;
+mod quotas;
+mod seed;
+mod tenants;
+mod workload;
 
 const SYNC_INTERVAL_BASE_MS: u64 = 10000;
 const SYNC_INTERVAL_JITTER_MS: u64 = 5000;
 
+/// How often idle watch streams are pinged to keep proxies from closing
+/// them.
+const WATCH_PING_INTERVAL: std::time::Duration =
+  std::time::Duration::from_secs(5);
+
+/// The routes of the watch endpoints, which count the bytes they stream in
+/// the metrics themselves.
+const WATCH_ENDPOINT: &str = "/v2/watch";
+const WATCH_WEBSOCKET_ENDPOINT: &str = "/v2/watch_ws";
+
 #[derive(Clone)]
 enum DatabaseBackend {
   Sqlite(Sqlite),
   Postgres(Postgres),
+  PartitionedPostgres(PartitionedPostgres),
+  LocalReplica(LocalReplica),
 }
 
 impl DatabaseBackend {
@@ -94,37 +173,176 @@ impl DatabaseBackend {
     &self,
     requests: Vec<ReadRange>,
     options: SnapshotReadOptions,
-  ) -> Result<Vec<denokv_proto::ReadRangeOutput>, deno_error::JsErrorBox> {
+  ) -> Result<Vec<denokv_proto::ReadRangeOutput>, ApiError> {
     match self {
-      DatabaseBackend::Sqlite(sqlite) => sqlite.snapshot_read(requests, options).await.map_err(deno_error::JsErrorBox::from_err),
-      DatabaseBackend::Postgres(postgres) => postgres.snapshot_read(requests, options).await,
+      DatabaseBackend::Sqlite(sqlite) => {
+        Ok(sqlite.snapshot_read(requests, options).await?)
+      }
+      DatabaseBackend::Postgres(postgres) => {
+        Ok(postgres.snapshot_read(requests, options).await?)
+      }
+      DatabaseBackend::PartitionedPostgres(postgres) => {
+        Ok(postgres.snapshot_read(requests, options).await?)
+      }
+      DatabaseBackend::LocalReplica(replica) => {
+        Ok(replica.snapshot_read(requests, options).await?)
+      }
     }
   }
 
   async fn atomic_write(
     &self,
     write: AtomicWrite,
-  ) -> Result<Option<denokv_proto::CommitResult>, deno_error::JsErrorBox> {
+  ) -> Result<Option<denokv_proto::CommitResult>, ApiError> {
     match self {
-      DatabaseBackend::Sqlite(sqlite) => sqlite.atomic_write(write).await.map_err(deno_error::JsErrorBox::from_err),
-      DatabaseBackend::Postgres(postgres) => postgres.atomic_write(write).await,
+      DatabaseBackend::Sqlite(sqlite) => Ok(sqlite.atomic_write(write).await?),
+      DatabaseBackend::Postgres(postgres) => {
+        Ok(postgres.atomic_write(write).await?)
+      }
+      DatabaseBackend::PartitionedPostgres(postgres) => {
+        Ok(postgres.atomic_write(write).await?)
+      }
+      DatabaseBackend::LocalReplica(replica) => {
+        Ok(replica.atomic_write(write).await?)
+      }
     }
   }
 
   fn watch(
     &self,
     keys: Vec<Vec<u8>>,
-  ) -> std::pin::Pin<Box<dyn futures::Stream<Item = Result<Vec<denokv_proto::WatchKeyOutput>, deno_error::JsErrorBox>> + Send>> {
+  ) -> std::pin::Pin<
+    Box<
+      dyn futures::Stream<
+          Item = Result<
+            Vec<denokv_proto::WatchKeyOutput>,
+            deno_error::JsErrorBox,
+          >,
+        > + Send,
+    >,
+  > {
     match self {
       DatabaseBackend::Sqlite(sqlite) => sqlite.watch(keys),
       DatabaseBackend::Postgres(postgres) => postgres.watch(keys),
+      DatabaseBackend::PartitionedPostgres(postgres) => postgres.watch(keys),
+      DatabaseBackend::LocalReplica(replica) => replica.watch(keys),
+    }
+  }
+
+  /// Prometheus metrics of the database, if it keeps any.
+  fn metrics(&self) -> Vec<prometheus::proto::MetricFamily> {
+    match self {
+      DatabaseBackend::Sqlite(_) => Vec::new(),
+      DatabaseBackend::Postgres(postgres) => postgres.metrics(),
+      DatabaseBackend::PartitionedPostgres(postgres) => postgres.metrics(),
+      DatabaseBackend::LocalReplica(replica) => replica.metrics(),
+    }
+  }
+
+  /// Suggested indexes for the range scans recorded so far, by partition
+  /// if the database is partitioned.
+  fn index_advice(&self, min_scans: u64) -> serde_json::Value {
+    match self {
+      DatabaseBackend::Sqlite(_) => serde_json::json!([]),
+      DatabaseBackend::Postgres(postgres) => {
+        serde_json::json!(postgres.index_advice(min_scans))
+      }
+      DatabaseBackend::PartitionedPostgres(postgres) => {
+        serde_json::json!({ "partitions": postgres.index_advice(min_scans) })
+      }
+      DatabaseBackend::LocalReplica(replica) => {
+        serde_json::json!(replica.index_advice(min_scans))
+      }
     }
   }
 
-  fn close(&self) {
+  /// Whether the database is reachable, and details for readiness checks.
+  async fn health(&self) -> (bool, serde_json::Value) {
     match self {
-      DatabaseBackend::Sqlite(sqlite) => sqlite.close(),
-      DatabaseBackend::Postgres(postgres) => postgres.close(),
+      DatabaseBackend::Sqlite(_) => {
+        (true, serde_json::json!({ "healthy": true }))
+      }
+      DatabaseBackend::Postgres(postgres) => {
+        let health = postgres.health().await;
+        (health.healthy, serde_json::json!(health))
+      }
+      DatabaseBackend::PartitionedPostgres(postgres) => {
+        let partitions = postgres.health().await;
+        let healthy = partitions.iter().all(|health| health.healthy);
+        (healthy, serde_json::json!({ "partitions": partitions }))
+      }
+      DatabaseBackend::LocalReplica(replica) => {
+        let health = replica.health().await;
+        (health.healthy, serde_json::json!(health))
+      }
+    }
+  }
+
+  /// The keys stored under `prefix` and their bytes.
+  async fn prefix_usage(&self, prefix: &[u8]) -> anyhow::Result<PrefixUsage> {
+    match self {
+      DatabaseBackend::Sqlite(sqlite) => {
+        // SQLite keeps no statistics to ask, so read every key.
+        let mut usage = PrefixUsage::default();
+        let mut start = prefix.to_vec();
+        let end = tenants::prefix_upper_bound(prefix);
+        loop {
+          let request = ReadRange {
+            start,
+            end: end.clone(),
+            limit: std::num::NonZeroU32::new(1000).unwrap(),
+            reverse: false,
+          };
+          let options = SnapshotReadOptions {
+            consistency: Consistency::Strong,
+          };
+          let mut outputs =
+            sqlite.snapshot_read(vec![request], options).await?;
+          let entries =
+            outputs.pop().map_or(Vec::new(), |output| output.entries);
+          for entry in &entries {
+            let value = encode_value(&entry.value).0;
+            usage.keys += 1;
+            usage.bytes += (entry.key.len() + value.len()) as u64;
+          }
+          match entries.last() {
+            Some(last) if entries.len() == 1000 => {
+              start = [&last.key[..], &[0]].concat();
+            }
+            _ => return Ok(usage),
+          }
+        }
+      }
+      DatabaseBackend::Postgres(postgres) => {
+        Ok(postgres.prefix_usage(prefix).await?)
+      }
+      DatabaseBackend::PartitionedPostgres(postgres) => {
+        Ok(postgres.prefix_usage(prefix).await?)
+      }
+      DatabaseBackend::LocalReplica(replica) => {
+        Ok(replica.prefix_usage(prefix).await?)
+      }
+    }
+  }
+
+  /// Change the PostgreSQL write rate limits. Returns `false` if writes
+  /// are not throttled.
+  fn set_write_rates(
+    &self,
+    ops_per_sec: Option<f64>,
+    bytes_per_sec: Option<f64>,
+  ) -> bool {
+    match self {
+      DatabaseBackend::Sqlite(_) => false,
+      DatabaseBackend::Postgres(postgres) => {
+        postgres.set_write_rates(ops_per_sec, bytes_per_sec)
+      }
+      DatabaseBackend::PartitionedPostgres(postgres) => {
+        postgres.set_write_rates(ops_per_sec, bytes_per_sec)
+      }
+      DatabaseBackend::LocalReplica(replica) => {
+        replica.set_write_rates(ops_per_sec, bytes_per_sec)
+      }
     }
   }
 }
@@ -132,16 +350,37 @@ impl DatabaseBackend {
 #[derive(Clone)]
 struct AppState {
   database: DatabaseBackend,
-  access_token: &'static str,
+  /// Replaced when the config file is reloaded.
+  tokens: Arc<RwLock<TokenRegistry>>,
+  read_budget: ReadBudget,
+  recorder: Option<Arc<WorkloadRecorder>>,
+  auditor: Option<Arc<Auditor>>,
+  quotas: Option<Arc<QuotaMonitor>>,
+  namespace_modes: Arc<NamespaceModes>,
+  /// Cancelled when the server starts draining, to end watches.
+  draining: CancellationToken,
+  /// Connections upgraded to WebSockets, which draining waits for.
+  upgraded: TaskTracker,
+  metrics: Arc<Metrics>,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-  let config: &'static Config = Box::leak(Box::new(Config::parse()));
-  if std::env::var("RUST_LOG").is_err() {
-    std::env::set_var("RUST_LOG", "info");
+  let mut config_file = ConfigFile::from_args()?;
+  let command = Config::command();
+  if let Some(config_file) = &mut config_file {
+    config_file.apply(&command)?;
+  }
+  let matches = command.get_matches();
+  let config = Config::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+  let config: &'static Config = Box::leak(Box::new(config));
+  if let Some(config_file) = &mut config_file {
+    config_file.retain_applied(&matches);
   }
-  env_logger::init();
+  logging::init(
+    config.log_format,
+    config_file.as_ref().and_then(ConfigFile::log_level),
+  );
 
   match &config.subcommand {
     SubCmd::Serve(options) => {
@@ -159,17 +398,47 @@ async fn main() -> Result<(), anyhow::Error> {
       };
       let serve_fut = async move {
         drop(initial_sync_ok_rx.await);
-        run_serve(config, options).await
+        run_serve(config, options, config_file).await
       };
 
-      let sync_fut = std::pin::pin!(sync_fut);
-      let serve_fut = std::pin::pin!(serve_fut);
-
-      futures::future::try_join(sync_fut, serve_fut).await?;
+      // Continuous syncing only stops on errors, and the server once it has
+      // drained.
+      tokio::select! {
+        result = sync_fut => result?,
+        result = serve_fut => result?,
+      }
     }
     SubCmd::Pitr(options) => {
       run_pitr(config, options).await?;
     }
+    SubCmd::Verify => {
+      run_verify(config).await?;
+    }
+    SubCmd::Doctor(options) => {
+      run_doctor(config, options).await?;
+    }
+    SubCmd::Repair(options) => {
+      run_repair(config, options).await?;
+    }
+    SubCmd::ReplayWorkload(options) => {
+      run_replay_workload(config, options).await?;
+    }
+    SubCmd::SampleKeys(options) => {
+      run_sample_keys(config, options).await?;
+    }
+    SubCmd::Copy(options) => {
+      run_copy(config, options).await?;
+    }
+    SubCmd::Seed(options) => {
+      run_seed(config, options).await?;
+    }
+    SubCmd::Openapi => {
+      println!("{}", openapi::document());
+    }
+    #[cfg(feature = "kafka")]
+    SubCmd::ConnectKafka(options) => {
+      kafka::run_connect_kafka(config, options).await?;
+    }
   }
 
   Ok(())
@@ -184,8 +453,9 @@ async fn run_pitr(
       run_sync(config, &options.replica, false, None).await?;
     }
     PitrSubCmd::List(options) => {
-      let sqlite_path = config.sqlite_path.as_ref()
-        .ok_or_else(|| anyhow::anyhow!("SQLite path is required for PITR operations"))?;
+      let sqlite_path = config.sqlite_path.as_ref().ok_or_else(|| {
+        anyhow::anyhow!("SQLite path is required for PITR operations")
+      })?;
       let db = rusqlite::Connection::open(sqlite_path)?;
       let mut ttc = TimeTravelControl::open(db)?;
 
@@ -225,8 +495,9 @@ async fn run_pitr(
       }
     }
     PitrSubCmd::Info => {
-      let sqlite_path = config.sqlite_path.as_ref()
-        .ok_or_else(|| anyhow::anyhow!("SQLite path is required for PITR operations"))?;
+      let sqlite_path = config.sqlite_path.as_ref().ok_or_else(|| {
+        anyhow::anyhow!("SQLite path is required for PITR operations")
+      })?;
       let db = rusqlite::Connection::open(sqlite_path)?;
       let mut ttc = TimeTravelControl::open(db)?;
 
@@ -237,8 +508,9 @@ async fn run_pitr(
       );
     }
     PitrSubCmd::Checkout(options) => {
-      let sqlite_path = config.sqlite_path.as_ref()
-        .ok_or_else(|| anyhow::anyhow!("SQLite path is required for PITR operations"))?;
+      let sqlite_path = config.sqlite_path.as_ref().ok_or_else(|| {
+        anyhow::anyhow!("SQLite path is required for PITR operations")
+      })?;
       let db = rusqlite::Connection::open(sqlite_path)?;
       let mut ttc = TimeTravelControl::open(db)?;
       let versionstamp = hex::decode(&options.versionstamp)
@@ -258,9 +530,294 @@ async fn run_pitr(
   Ok(())
 }
 
+/// Open the PostgreSQL database for a maintenance subcommand.
+async fn open_postgres_maintenance(
+  config: &Config,
+  command: &str,
+) -> anyhow::Result<Postgres> {
+  let postgres_config = postgres_maintenance_config(config, command)?;
+  Ok(Postgres::new(postgres_config).await?)
+}
+
+/// The configuration maintenance commands connect with. Server settings
+/// are not checked at startup, since these commands are short-lived.
+fn postgres_maintenance_config(
+  config: &Config,
+  command: &str,
+) -> anyhow::Result<PostgresConfig> {
+  if config.database_type != "postgres" {
+    anyhow::bail!(
+      "The {command} command is only supported for the postgres database type"
+    );
+  }
+  let postgres_url = config.postgres_url.as_ref().ok_or_else(|| {
+    anyhow::anyhow!(
+      "PostgreSQL URL is required when using postgres database type"
+    )
+  })?;
+  Ok(
+    PostgresConfig::new(postgres_url.clone()).with_check_server_settings(false),
+  )
+}
+
+/// Parse a `PREFIX=URL` partition rule into the encoded key prefix `[PREFIX]`
+/// and the cluster URL.
+fn parse_postgres_partition(rule: &str) -> anyhow::Result<(Vec<u8>, String)> {
+  let Some((prefix, url)) = rule.split_once('=') else {
+    anyhow::bail!("Invalid partition rule '{}'. Expected PREFIX=URL", rule);
+  };
+  let prefix = encode_key(&Key(vec![KeyPart::String(prefix.to_string())]))?;
+  Ok((prefix, url.to_string()))
+}
+
+async fn run_verify(config: &'static Config) -> anyhow::Result<()> {
+  let postgres = open_postgres_maintenance(config, "verify").await?;
+
+  let report = postgres.verify().await?;
+  for (key, kind) in &report.corrupt {
+    println!("{}\t{}", format_key(key), kind);
+  }
+  info!(
+    "Scanned {} row(s), {} without checksum, {} corrupt",
+    report.rows_scanned,
+    report.rows_without_checksum,
+    report.corrupt.len(),
+  );
+  if !report.corrupt.is_empty() {
+    anyhow::bail!("Found {} corrupt row(s)", report.corrupt.len());
+  }
+  Ok(())
+}
+
+async fn run_doctor(
+  config: &'static Config,
+  options: &'static DoctorOptions,
+) -> anyhow::Result<()> {
+  let postgres_config = postgres_maintenance_config(config, "doctor")?
+    .with_max_connections(options.pool_size);
+  let postgres = Postgres::new(postgres_config).await?;
+
+  let info = postgres.server_info().await?;
+  info!(
+    "PostgreSQL {}{}",
+    info.server_version,
+    if info.in_recovery { ", a standby" } else { "" },
+  );
+  let warnings = postgres.check_server_settings().await?;
+  for warning in &warnings {
+    println!("{warning}");
+  }
+  info!("{} setting(s) to look at", warnings.len());
+  Ok(())
+}
+
+async fn run_repair(
+  config: &'static Config,
+  options: &'static RepairOptions,
+) -> anyhow::Result<()> {
+  let postgres = open_postgres_maintenance(config, "repair").await?;
+
+  let report = postgres.repair(options.dry_run, options.reindex).await?;
+  let verb = if options.dry_run { "Would" } else { "Did" };
+  if report.version_row_recreated {
+    println!("{verb} recreate the missing data_version row");
+  }
+  if let Some((from, to)) = report.version_advanced {
+    println!("{verb} advance the version counter from {from} to {to}");
+  }
+  if report.orphaned_running_removed > 0 {
+    println!(
+      "{verb} remove {} queue_running row(s) without a message",
+      report.orphaned_running_removed
+    );
+  }
+  if report.reindexed {
+    println!("Rebuilt secondary indexes");
+  }
+  Ok(())
+}
+
+/// The configured SQLite or PostgreSQL database, for commands that work
+/// with either.
+async fn open_database_maintenance(
+  config: &Config,
+  command: &str,
+) -> anyhow::Result<DatabaseBackend> {
+  Ok(match config.database_type.as_str() {
+    "sqlite" => {
+      let sqlite_path = config.sqlite_path.as_ref().ok_or_else(|| {
+        anyhow::anyhow!(
+          "SQLite path is required when using sqlite database type"
+        )
+      })?;
+      let sqlite_config = SqliteConfig {
+        batch_timeout: None,
+        num_workers: 1,
+      };
+      DatabaseBackend::Sqlite(open_sqlite(
+        Path::new(sqlite_path),
+        false,
+        sqlite_config,
+      )?)
+    }
+    "postgres" => DatabaseBackend::Postgres(
+      open_postgres_maintenance(config, command).await?,
+    ),
+    _ => anyhow::bail!(
+      "Invalid database type: {}. Must be 'sqlite' or 'postgres'",
+      config.database_type
+    ),
+  })
+}
+
+async fn run_replay_workload(
+  config: &'static Config,
+  options: &'static ReplayWorkloadOptions,
+) -> anyhow::Result<()> {
+  let database = open_database_maintenance(config, "replay-workload").await?;
+
+  let report =
+    workload::replay(database, &options.trace, options.speed).await?;
+  for (kind, latencies) in &report.latencies {
+    let mut latencies = latencies.clone();
+    latencies.sort();
+    let percentile =
+      |p: f64| latencies[((latencies.len() - 1) as f64 * p) as usize];
+    println!(
+      "{kind}\tcount={}\tp50={:?}\tp99={:?}\tmax={:?}",
+      latencies.len(),
+      percentile(0.5),
+      percentile(0.99),
+      latencies[latencies.len() - 1],
+    );
+  }
+  if report.errors > 0 {
+    anyhow::bail!("{} replayed operation(s) failed", report.errors);
+  }
+  Ok(())
+}
+
+async fn run_sample_keys(
+  config: &'static Config,
+  options: &'static SampleKeysOptions,
+) -> anyhow::Result<()> {
+  let postgres = open_postgres_maintenance(config, "sample-keys").await?;
+
+  let prefix = match &options.prefix {
+    Some(prefix) => parse_prefix(prefix)?,
+    None => Vec::new(),
+  };
+  let report = postgres.sample_keys(&prefix, options.count).await?;
+  for sample in &report.samples {
+    println!("{}\t{}", format_key(&sample.key), sample.size);
+  }
+  if report.fraction_scanned >= 1.0 {
+    info!(
+      "{} key(s) and {} byte(s) under the prefix",
+      report.estimated_keys, report.estimated_bytes,
+    );
+  } else {
+    info!(
+      "Sampled {:.2}% of the table: about {} key(s) and {} byte(s) under the prefix",
+      report.fraction_scanned * 100.0,
+      report.estimated_keys,
+      report.estimated_bytes,
+    );
+  }
+  Ok(())
+}
+
+/// Parse a prefix given on the command line: either a key like
+/// `["users", 42]`, or a string for the first part of the key.
+fn parse_prefix(prefix: &str) -> anyhow::Result<Vec<u8>> {
+  if prefix.starts_with('[') {
+    Ok(parse_key(prefix)?)
+  } else {
+    Ok(encode_key(&Key(vec![KeyPart::String(prefix.to_string())]))?)
+  }
+}
+
+async fn run_copy(
+  config: &'static Config,
+  options: &'static CopyOptions,
+) -> anyhow::Result<()> {
+  let postgres = open_postgres_maintenance(config, "copy").await?;
+
+  let field_rule = |rule: &str| -> anyhow::Result<(Vec<u8>, String)> {
+    let Some((prefix, field)) = rule.rsplit_once('=') else {
+      anyhow::bail!("Invalid field rule '{}'. Expected PREFIX=FIELD", rule);
+    };
+    Ok((parse_prefix(prefix)?, field.to_string()))
+  };
+  let mut transforms = Transforms::new();
+  for rule in &options.drop_fields {
+    let (prefix, field) = field_rule(rule)?;
+    transforms = transforms.drop_fields(prefix, vec![field]);
+  }
+  for rule in &options.hash_fields {
+    let (prefix, field) = field_rule(rule)?;
+    transforms = transforms.hash_fields(prefix, vec![field]);
+  }
+  for prefix in &options.skip {
+    transforms = transforms.skip(parse_prefix(prefix)?);
+  }
+
+  let prefix = match &options.prefix {
+    Some(prefix) => parse_prefix(prefix)?,
+    None => Vec::new(),
+  };
+  let report = match (&options.to_postgres, &options.to_sqlite) {
+    (Some(url), _) => {
+      let dest = Postgres::new(PostgresConfig::new(url.clone())).await?;
+      postgres.copy_to(&prefix, &dest, &transforms).await?
+    }
+    (None, Some(path)) => {
+      let sqlite_config = SqliteConfig {
+        batch_timeout: None,
+        num_workers: 1,
+      };
+      let dest = open_sqlite(path, false, sqlite_config)?;
+      postgres.copy_to(&prefix, &dest, &transforms).await?
+    }
+    (None, None) => unreachable!("clap requires a destination"),
+  };
+  info!(
+    "Copied {} key(s), skipped {} and deleted {}",
+    report.entries_copied, report.entries_skipped, report.keys_deleted,
+  );
+  Ok(())
+}
+
+async fn run_seed(
+  config: &'static Config,
+  options: &'static SeedOptions,
+) -> anyhow::Result<()> {
+  let fixture = seed::Fixture::load(&options.fixture)?;
+  let keys = match &options.encryption_keys {
+    Some(path) => NamespaceKeys::load(path).await?,
+    None => NamespaceKeys::default(),
+  };
+  let database = open_database_maintenance(config, "seed").await?;
+  let seeded =
+    seed::seed(&database, &fixture, &keys, options.idempotent).await?;
+  for namespace in seeded {
+    let name = seed::namespace_name(namespace.namespace.as_deref());
+    if namespace.skipped {
+      println!("Skipped {name}, which was seeded before");
+    } else {
+      println!(
+        "Seeded {name} with {} entry(s) and {} message(s)",
+        namespace.entries, namespace.messages
+      );
+    }
+  }
+  Ok(())
+}
+
 async fn run_serve(
   config: &'static Config,
   options: &'static ServeOptions,
+  config_file: Option<ConfigFile>,
 ) -> anyhow::Result<()> {
   if options.access_token.len() < 12 {
     anyhow::bail!("Access token must be at minimum 12 chars long.");
@@ -268,8 +825,11 @@ async fn run_serve(
 
   let database = match config.database_type.as_str() {
     "sqlite" => {
-      let sqlite_path = config.sqlite_path.as_ref()
-        .ok_or_else(|| anyhow::anyhow!("SQLite path is required when using sqlite database type"))?;
+      let sqlite_path = config.sqlite_path.as_ref().ok_or_else(|| {
+        anyhow::anyhow!(
+          "SQLite path is required when using sqlite database type"
+        )
+      })?;
       let path = Path::new(sqlite_path);
       let read_only = options.read_only || options.sync_from_s3;
       let sqlite_config = SqliteConfig {
@@ -288,28 +848,248 @@ async fn run_serve(
       DatabaseBackend::Sqlite(sqlite)
     }
     "postgres" => {
-      let postgres_url = config.postgres_url.as_ref()
-        .ok_or_else(|| anyhow::anyhow!("PostgreSQL URL is required when using postgres database type"))?;
-      let postgres_config = PostgresConfig::new(postgres_url.clone())
-        .with_max_connections(options.num_workers.max(10));
-      let postgres = Postgres::new(postgres_config).await?;
-      info!("Opened PostgreSQL database at {}", postgres_url);
-      DatabaseBackend::Postgres(postgres)
+      let postgres_url = config.postgres_url.as_ref().ok_or_else(|| {
+        anyhow::anyhow!(
+          "PostgreSQL URL is required when using postgres database type"
+        )
+      })?;
+      let mut postgres_config = PostgresConfig::new(postgres_url.clone())
+        .with_max_connections(options.num_workers.max(10))
+        .with_verify_checksums(options.postgres_verify_checksums)
+        .with_statement_log_sample_rate(
+          options.postgres_statement_log_sample_rate,
+        )
+        .with_record_range_scans(options.postgres_record_range_scans)
+        .with_write_throttle(
+          options.postgres_write_ops_per_sec,
+          options.postgres_write_bytes_per_sec,
+        )
+        .with_write_queue_limit(options.postgres_write_queue_limit)
+        .with_circuit_breaker(
+          options.postgres_circuit_breaker_threshold,
+          options.postgres_circuit_breaker_cooldown,
+        )
+        .with_pool_wait_timeout(options.postgres_pool_wait_timeout)
+        .with_affinity_lanes(options.postgres_affinity_lanes)
+        .with_statement_cache_size(options.postgres_statement_cache_size)
+        .with_retry_budget(options.postgres_retry_budget)
+        .with_read_repair_window(options.postgres_read_repair_window)
+        .with_max_replica_lag(options.postgres_max_replica_lag)
+        .with_analyze_threshold(options.postgres_analyze_threshold)
+        .with_expiry_sweep(
+          options.postgres_expiry_sweep_interval,
+          options.postgres_expiry_sweep_batch_size,
+        )
+        .with_watch_lease(options.postgres_watch_lease)
+        .with_table_prefix(options.postgres_table_prefix.clone())
+        .with_max_delivery_attempts(options.postgres_max_delivery_attempts)
+        .with_queue_visibility_timeout(
+          options.postgres_queue_visibility_timeout,
+        )
+        .with_partition_split_rows(options.postgres_partition_split_rows)
+        .with_partition_archive_after(options.postgres_partition_archive_after);
+      if options.postgres_async_commit {
+        postgres_config =
+          postgres_config.with_durability(Durability::Asynchronous);
+      }
+      if let Some(schema) = &options.postgres_schema {
+        postgres_config = postgres_config.with_schema(schema.clone());
+      }
+      for url in &options.postgres_read_replicas {
+        postgres_config = postgres_config.with_read_replica(url.clone());
+      }
+      for partition in &options.postgres_partitions {
+        let (prefix, url) = parse_postgres_partition(partition)?;
+        postgres_config = postgres_config.with_partition(prefix, url);
+      }
+      if let Some(partitions) = options.postgres_hash_partitions {
+        postgres_config = postgres_config
+          .with_table_partitioning(TablePartitioning::Hash { partitions });
+      } else if !options.postgres_range_splits.is_empty() {
+        let mut split_points = options
+          .postgres_range_splits
+          .iter()
+          .map(|split| {
+            encode_key(&Key(vec![KeyPart::String(split.to_string())]))
+          })
+          .collect::<Result<Vec<_>, _>>()?;
+        split_points.sort();
+        split_points.dedup();
+        postgres_config = postgres_config
+          .with_table_partitioning(TablePartitioning::Range { split_points });
+      }
+      if postgres_config.partitions.is_empty() {
+        let postgres = Postgres::new(postgres_config).await?;
+        info!("Opened PostgreSQL database at {}", postgres_url);
+        if let Some(bucket) = &options.postgres_partition_archive_bucket {
+          let client =
+            s3_client(options.postgres_partition_archive_endpoint.as_deref())
+              .await;
+          let archive = PartitionArchive::new(
+            client,
+            bucket.clone(),
+            options.postgres_partition_archive_prefix.clone(),
+          )?;
+          let leadership = postgres.elect_leader("partition-archive")?;
+          tokio::spawn(archive.run(postgres.clone(), leadership));
+          info!("Uploading archived partitions to s3://{bucket}");
+        }
+        match &options.postgres_local_replica {
+          Some(path) => {
+            let sqlite_config = SqliteConfig {
+              batch_timeout: None,
+              num_workers: options.num_workers,
+            };
+            let cache = open_sqlite(path, false, sqlite_config)?;
+            info!("Replicating to SQLite database at {}", path.display());
+            DatabaseBackend::LocalReplica(postgres.local_replica(cache))
+          }
+          None => DatabaseBackend::Postgres(postgres),
+        }
+      } else {
+        if options.postgres_local_replica.is_some() {
+          anyhow::bail!(
+            "--postgres-local-replica can not be combined with --postgres-partition"
+          );
+        }
+        if options.postgres_partition_archive_bucket.is_some() {
+          anyhow::bail!(
+            "--postgres-partition-archive-bucket can not be combined with --postgres-partition"
+          );
+        }
+        let partitions = postgres_config.partitions.len();
+        let postgres = PartitionedPostgres::new(postgres_config).await?;
+        info!(
+          "Opened PostgreSQL database at {} with {} partition(s)",
+          postgres_url, partitions
+        );
+        DatabaseBackend::PartitionedPostgres(postgres)
+      }
     }
-    _ => anyhow::bail!("Invalid database type: {}. Must be 'sqlite' or 'postgres'", config.database_type),
+    _ => anyhow::bail!(
+      "Invalid database type: {}. Must be 'sqlite' or 'postgres'",
+      config.database_type
+    ),
   };
 
-  let access_token = options.access_token.as_str();
+  let keys = match &options.encryption_keys {
+    Some(path) => {
+      let keys = NamespaceKeys::load(path).await?;
+      info!("Loaded the encryption keys from {}", path.display());
+      keys
+    }
+    None => NamespaceKeys::default(),
+  };
+  let keys = Arc::new(keys);
+  let tokens = load_tokens(options, &keys, config_file.as_ref())?;
+  if let Some(path) = &options.token_registry {
+    info!("Loaded the token registry from {}", path.display());
+  }
 
+  let recorder = match &options.record_workload {
+    Some(path) => {
+      info!("Recording the workload to {}", path.display());
+      Some(Arc::new(WorkloadRecorder::open(path)?))
+    }
+    None => None,
+  };
+
+  let auditor = if options.audit {
+    let webhook = options
+      .audit_alert_webhook
+      .clone()
+      .map(|url| audit::webhook(url, "audit alert"));
+    let auditor = Arc::new(Auditor::new(
+      std::time::Duration::from_secs(options.audit_interval),
+      options.audit_write_spike_factor,
+      move |alert| {
+        warn!("Audit alert: {}", alert.message);
+        if let Some(webhook) = &webhook {
+          webhook(alert);
+        }
+      },
+    ));
+    tokio::spawn(auditor.clone().run(database.clone()));
+    info!("Auditing requests every {}s", options.audit_interval);
+    Some(auditor)
+  } else {
+    None
+  };
+
+  let draining = CancellationToken::new();
+  let upgraded = TaskTracker::new();
+  let metrics = Arc::new(Metrics::new());
+
+  let namespace_modes = Arc::new(NamespaceModes::default());
+  if let Err(e) = namespace_modes.refresh(&database).await {
+    warn!("Failed to read the namespace modes: {e}");
+  }
+  tokio::spawn(namespace_modes.clone().run(database.clone()));
+
+  let quotas = match &options.namespace_quotas {
+    Some(path) => {
+      let webhook = options
+        .quota_warning_webhook
+        .clone()
+        .map(|url| audit::webhook(url, "quota warning"));
+      let quotas = Arc::new(QuotaMonitor::load(
+        path,
+        options.quota_warning_thresholds.clone(),
+        std::time::Duration::from_secs(options.quota_check_interval),
+        metrics.clone(),
+        move |warning| {
+          warn!("Quota warning: {}", warning.message);
+          if let Some(webhook) = &webhook {
+            webhook(warning);
+          }
+        },
+      )?);
+      tokio::spawn(quotas.clone().run(database.clone()));
+      info!("Loaded the namespace quotas from {}", path.display());
+      Some(quotas)
+    }
+    None => None,
+  };
+  if let Some(addr) = options.health_listen {
+    health::serve(addr, database.clone(), metrics.clone(), draining.clone())?;
+  }
   let state = AppState {
     database,
-    access_token,
+    tokens: Arc::new(RwLock::new(tokens)),
+    read_budget: ReadBudget {
+      max_entries: options.max_read_entries,
+      max_bytes: options.max_read_bytes,
+    },
+    recorder,
+    auditor,
+    quotas,
+    namespace_modes,
+    draining: draining.clone(),
+    upgraded: upgraded.clone(),
+    metrics,
   };
 
+  if let Some(config_file) = config_file {
+    let changes = config_file::changes(config_file.path())?;
+    let state = state.clone();
+    tokio::spawn(async move {
+      let mut changes = std::pin::pin!(changes);
+      while changes.next().await.is_some() {
+        match reload_config(&config_file, options, &keys, &state) {
+          Ok(()) => {
+            info!("Reloaded the config file {}", config_file.path().display())
+          }
+          Err(e) => warn!("Failed to reload the config file: {e:#}"),
+        }
+      }
+    });
+  }
+
   let v1 = Router::new()
     .route("/snapshot_read", post(snapshot_read_endpoint))
     .route("/atomic_write", post(atomic_write_endpoint))
     .route("/watch", post(watch_endpoint))
+    .route("/watch_ws", get(watch_websocket_endpoint))
     .route_layer(middleware::from_fn_with_state(
       state.clone(),
       authentication_middleware,
@@ -317,18 +1097,84 @@ async fn run_serve(
 
   let app = Router::new()
     .route("/", post(metadata_endpoint))
+    .route("/metrics", get(metrics_endpoint))
+    .route("/index_advice", get(index_advice_endpoint))
+    .route("/rate_limit", post(rate_limit_endpoint))
+    .route(
+      "/namespace_modes",
+      get(list_namespace_modes_endpoint).post(set_namespace_mode_endpoint),
+    )
+    .route("/blind_index", post(blind_index_endpoint))
+    .route("/openapi.json", get(openapi_endpoint))
     .nest("/v2", v1)
     .fallback(fallback_handler)
+    .layer(middleware::from_fn_with_state(
+      state.clone(),
+      metrics::record_request,
+    ))
     .with_state(state);
 
-  let listener = std::net::TcpListener::bind(options.addr)
-    .context("Failed to start server")?;
-  info!("Listening on http://{}", listener.local_addr().unwrap());
+  let listener = Listener::open(options)?;
+  let server = listener.serve(app, options.http2_only, draining.clone())?;
+  health::notify_systemd(draining.clone());
+  listen::drain_on_signal(draining.clone())?;
+  listen::serve_until_drained(
+    server,
+    &draining,
+    &upgraded,
+    std::time::Duration::from_secs(options.drain_timeout),
+  )
+  .await
+}
 
-  axum::Server::from_tcp(listener)?
-    .serve(app.into_make_service())
-    .await?;
+/// The OpenAPI document of the JSON endpoints, without authentication.
+async fn openapi_endpoint() -> Response {
+  ([("content-type", "application/json")], openapi::document()).into_response()
+}
 
+/// The server's access token and the tokens of the token registry and the
+/// config file.
+fn load_tokens(
+  options: &ServeOptions,
+  keys: &Arc<NamespaceKeys>,
+  config_file: Option<&ConfigFile>,
+) -> anyhow::Result<TokenRegistry> {
+  let mut tokens = TokenRegistry::new(&options.access_token, keys.clone());
+  if let Some(path) = &options.token_registry {
+    tokens.load(path)?;
+  }
+  if let Some(config_file) = config_file {
+    tokens.add(config_file.tokens()?)?;
+  }
+  Ok(tokens)
+}
+
+/// Read the config file again and apply the settings that can change while
+/// the server runs. Nothing is changed if the file is invalid.
+fn reload_config(
+  config_file: &ConfigFile,
+  options: &ServeOptions,
+  keys: &Arc<NamespaceKeys>,
+  state: &AppState,
+) -> anyhow::Result<()> {
+  let config_file = config_file.reread()?;
+  let tokens = load_tokens(options, keys, Some(&config_file))?;
+  let ops_per_sec = config_file.serve_f64(
+    "postgres_write_ops_per_sec",
+    options.postgres_write_ops_per_sec,
+  )?;
+  let bytes_per_sec = config_file.serve_f64(
+    "postgres_write_bytes_per_sec",
+    options.postgres_write_bytes_per_sec,
+  )?;
+
+  logging::set_filter(config_file.log_level());
+  *state.tokens.write().unwrap() = tokens;
+  if !state.database.set_write_rates(ops_per_sec, bytes_per_sec)
+    && (ops_per_sec.is_some() || bytes_per_sec.is_some())
+  {
+    warn!("Write rate limits that were not set at startup need a restart");
+  }
   Ok(())
 }
 
@@ -338,35 +1184,11 @@ async fn run_sync(
   continuous: bool,
   initial_sync_ok_tx: Option<oneshot::Sender<()>>,
 ) -> anyhow::Result<()> {
-  let mut s3_config = aws_config::from_env()
-    .sleep_impl(Arc::new(TokioSleep::new()))
-    .retry_config(RetryConfig::standard().with_max_attempts(u32::MAX));
-
-  if let Some(endpoint) = &options.s3_endpoint {
-    s3_config = s3_config.endpoint_url(endpoint);
-  }
-
-  let https_proxy = env::var("https_proxy")
-    .or_else(|_| env::var("HTTPS_PROXY"))
-    .ok();
-  if let Some(https_proxy) = https_proxy {
-    let proxy = {
-      let proxy_uri = https_proxy.parse().unwrap();
-      let proxy = Proxy::new(Intercept::All, proxy_uri);
-      let connector = HttpConnector::new();
-      ProxyConnector::from_proxy_unsecured(connector, proxy)
-    };
-    let hyper_client =
-      aws_smithy_client::hyper_ext::Adapter::builder().build(proxy);
-
-    s3_config = s3_config.http_connector(hyper_client);
-  }
+  let s3_client = s3_client(options.s3_endpoint.as_deref()).await;
 
-  let s3_config = s3_config.load().await;
-  let s3_client = aws_sdk_s3::Client::new(&s3_config);
-
-  let sqlite_path = config.sqlite_path.as_ref()
-    .ok_or_else(|| anyhow::anyhow!("SQLite path is required for sync operations"))?;
+  let sqlite_path = config.sqlite_path.as_ref().ok_or_else(|| {
+    anyhow::anyhow!("SQLite path is required for sync operations")
+  })?;
   let db = rusqlite::Connection::open(sqlite_path)?;
   let mut ttc = TimeTravelControl::open(db)?;
   let s3_config = DatabaseBackupSourceS3Config {
@@ -406,6 +1228,37 @@ async fn run_sync(
   }
 }
 
+/// An S3 client configured from the environment, through the proxy in
+/// `https_proxy` if set, retrying indefinitely.
+async fn s3_client(endpoint: Option<&str>) -> aws_sdk_s3::Client {
+  let mut s3_config = aws_config::from_env()
+    .sleep_impl(Arc::new(TokioSleep::new()))
+    .retry_config(RetryConfig::standard().with_max_attempts(u32::MAX));
+
+  if let Some(endpoint) = endpoint {
+    s3_config = s3_config.endpoint_url(endpoint);
+  }
+
+  let https_proxy = env::var("https_proxy")
+    .or_else(|_| env::var("HTTPS_PROXY"))
+    .ok();
+  if let Some(https_proxy) = https_proxy {
+    let proxy = {
+      let proxy_uri = https_proxy.parse().unwrap();
+      let proxy = Proxy::new(Intercept::All, proxy_uri);
+      let connector = HttpConnector::new();
+      ProxyConnector::from_proxy_unsecured(connector, proxy)
+    };
+    let hyper_client =
+      aws_smithy_client::hyper_ext::Adapter::builder().build(proxy);
+
+    s3_config = s3_config.http_connector(hyper_client);
+  }
+
+  let s3_config = s3_config.load().await;
+  aws_sdk_s3::Client::new(&s3_config)
+}
+
 fn open_sqlite(
   path: &Path,
   read_only: bool,
@@ -433,6 +1286,18 @@ fn open_sqlite(
   Ok(sqlite)
 }
 
+#[utoipa::path(
+  post,
+  path = "/",
+  operation_id = "exchange_metadata",
+  tag = "kv_connect",
+  request_body = MetadataExchangeRequest,
+  responses(
+    (status = 200, description = "How to reach the data path.", body = DatabaseMetadata),
+    (status = 400, description = "No supported protocol version was requested.", body = String),
+    (status = 401, description = "The access token is missing or invalid.", body = String),
+  )
+)]
 #[axum::debug_handler]
 async fn metadata_endpoint(
   State(state): State<AppState>,
@@ -458,10 +1323,20 @@ async fn metadata_endpoint(
     return Err(ApiError::MalformedAuthorizationHeader);
   };
   if bearer.to_lowercase() != "bearer"
-    || !constant_time_eq(token.as_bytes(), state.access_token.as_bytes())
+    || state.tokens.read().unwrap().authenticate(token).is_none()
   {
     return Err(ApiError::InvalidAccessToken);
   }
+  // Extensions build on the data path of version 3.
+  let features = if version >= 3 {
+    req
+      .features
+      .into_iter()
+      .filter(|name| Feature::from_name(name).is_some())
+      .collect()
+  } else {
+    Vec::new()
+  };
   let expires_at = utc_now() + Duration::days(1);
   Ok(Json(DatabaseMetadata {
     version,
@@ -470,15 +1345,297 @@ async fn metadata_endpoint(
       url: Cow::Borrowed("/v2"),
       consistency: Cow::Borrowed("strong"),
     }],
-    token: Cow::Borrowed(state.access_token),
+    token: Cow::Owned(token.to_string()),
     expires_at,
+    features,
   }))
 }
 
+/// The metrics of the server and the database, for admin tokens only.
+#[utoipa::path(
+  get,
+  path = "/metrics",
+  operation_id = "get_metrics",
+  tag = "admin",
+  responses(
+    (status = 200, description = "Metrics in the Prometheus text format.", body = String),
+    (status = 401, description = "The access token is missing or invalid.", body = String),
+    (status = 403, description = "The token is not an admin token.", body = String),
+  )
+)]
+async fn metrics_endpoint(
+  State(state): State<AppState>,
+  headers: HeaderMap,
+) -> Result<Response, ApiError> {
+  let tenant = authenticate_bearer(&state, &headers)?;
+  if !tenant.allows(Permission::Admin) {
+    return Err(ApiError::permission_denied(&tenant, "read metrics"));
+  }
+  Ok(state.metrics.render(state.database.metrics()))
+}
+
+#[derive(Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct IndexAdviceQuery {
+  /// Only advise on prefixes scanned at least this many times.
+  #[serde(default = "default_min_scans")]
+  min_scans: u64,
+}
+
+fn default_min_scans() -> u64 {
+  100
+}
+
+/// Indexes that would speed up the range scans recorded with
+/// `--postgres-record-range-scans`, as JSON.
+#[utoipa::path(
+  get,
+  path = "/index_advice",
+  operation_id = "get_index_advice",
+  tag = "admin",
+  params(IndexAdviceQuery),
+  responses(
+    (status = 200, description = "The suggested indexes, none unless the database is PostgreSQL.", body = [denokv_postgres::IndexSuggestion]),
+    (status = 401, description = "The access token is missing or invalid.", body = String),
+    (status = 403, description = "The token is not an admin token.", body = String),
+  )
+)]
+async fn index_advice_endpoint(
+  State(state): State<AppState>,
+  headers: HeaderMap,
+  Query(query): Query<IndexAdviceQuery>,
+) -> Result<Response, ApiError> {
+  let tenant = authenticate_bearer(&state, &headers)?;
+  if !tenant.allows(Permission::Admin) {
+    return Err(ApiError::permission_denied(&tenant, "read index advice"));
+  }
+  Ok(Json(state.database.index_advice(query.min_scans)).into_response())
+}
+
+/// The namespaces that are read-only or frozen, as JSON.
+#[utoipa::path(
+  get,
+  path = "/namespace_modes",
+  operation_id = "list_namespace_modes",
+  tag = "admin",
+  responses(
+    (status = 200, description = "The namespaces that are not active.", body = [NamespaceMode]),
+    (status = 401, description = "The access token is missing or invalid.", body = String),
+    (status = 403, description = "The token is not an admin token.", body = String),
+  )
+)]
+async fn list_namespace_modes_endpoint(
+  State(state): State<AppState>,
+  headers: HeaderMap,
+) -> Result<Response, ApiError> {
+  let tenant = authenticate_bearer(&state, &headers)?;
+  if !tenant.allows(Permission::Admin) {
+    return Err(ApiError::permission_denied(&tenant, "read namespace modes"));
+  }
+  Ok(Json(state.namespace_modes.list()).into_response())
+}
+
+/// Make a namespace active, read-only or frozen.
+#[utoipa::path(
+  post,
+  path = "/namespace_modes",
+  operation_id = "set_namespace_mode",
+  tag = "admin",
+  request_body = NamespaceMode,
+  responses(
+    (status = 200, description = "The mode was stored and applies right away.", body = NamespaceMode),
+    (status = 400, description = "The namespace is empty.", body = String),
+    (status = 401, description = "The access token is missing or invalid.", body = String),
+    (status = 403, description = "The token is not an admin token.", body = String),
+  )
+)]
+async fn set_namespace_mode_endpoint(
+  State(state): State<AppState>,
+  headers: HeaderMap,
+  Json(mode): Json<NamespaceMode>,
+) -> Result<Response, ApiError> {
+  let tenant = authenticate_bearer(&state, &headers)?;
+  if !tenant.allows(Permission::Admin) {
+    return Err(ApiError::permission_denied(&tenant, "set namespace modes"));
+  }
+  info!(
+    "Token '{}' set namespace '{}' to {:?}",
+    tenant.name, mode.namespace, mode.mode
+  );
+  state
+    .namespace_modes
+    .set(&state.database, mode.clone())
+    .await?;
+  Ok(Json(mode).into_response())
+}
+
+#[derive(Deserialize, utoipa::ToSchema)]
+struct BlindIndexRequest {
+  field: String,
+  value: String,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+struct BlindIndexResponse {
+  /// Hex encoded.
+  index: String,
+}
+
+/// The blind index of a field's value in the encrypted namespace of the
+/// token, for keys of entries that look the value up by equality.
+#[utoipa::path(
+  post,
+  path = "/blind_index",
+  operation_id = "blind_index",
+  tag = "namespaces",
+  request_body = BlindIndexRequest,
+  responses(
+    (status = 200, description = "The blind index of the value.", body = BlindIndexResponse),
+    (status = 400, description = "The namespace of the token is not encrypted.", body = String),
+    (status = 401, description = "The access token is missing or invalid.", body = String),
+  )
+)]
+async fn blind_index_endpoint(
+  State(state): State<AppState>,
+  headers: HeaderMap,
+  Json(request): Json<BlindIndexRequest>,
+) -> Result<Response, ApiError> {
+  let tenant = authenticate_bearer(&state, &headers)?;
+  let index = tenant
+    .blind_index(&request.field, request.value.as_bytes())
+    .ok_or(ApiError::NamespaceNotEncrypted)?;
+  Ok(
+    Json(BlindIndexResponse {
+      index: hex::encode(index),
+    })
+    .into_response(),
+  )
+}
+
+/// The tenant of the bearer token in the `authorization` header, for
+/// endpoints outside the data path.
+fn authenticate_bearer(
+  state: &AppState,
+  headers: &HeaderMap,
+) -> Result<Arc<Tenant>, ApiError> {
+  let Some(authorization) =
+    headers.get("authorization").and_then(|v| v.to_str().ok())
+  else {
+    return Err(ApiError::MalformedAuthorizationHeader);
+  };
+  let Some((bearer, token)) = authorization.split_once(' ') else {
+    return Err(ApiError::MalformedAuthorizationHeader);
+  };
+  if bearer.to_lowercase() != "bearer" {
+    return Err(ApiError::InvalidAccessToken);
+  }
+  let tenant = state.tokens.read().unwrap().authenticate(token);
+  tenant.ok_or(ApiError::InvalidAccessToken)
+}
+
+#[derive(Deserialize, utoipa::ToSchema)]
+struct RateLimitRequest {
+  /// The limited key, written as `format_key` writes keys.
+  key: String,
+  limit: u64,
+  window_ms: u64,
+  #[serde(default = "default_rate_limit_cost")]
+  cost: u64,
+}
+
+fn default_rate_limit_cost() -> u64 {
+  1
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+struct RateLimitResponse {
+  allowed: bool,
+  remaining: u64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  retry_after_ms: Option<u64>,
+}
+
+/// Count a request against a sliding window rate limit on a key, in the
+/// namespace of the token. Denied requests get a 429 with a Retry-After
+/// header, unless they can never be allowed.
+#[utoipa::path(
+  post,
+  path = "/rate_limit",
+  operation_id = "rate_limit",
+  tag = "namespaces",
+  request_body = RateLimitRequest,
+  responses(
+    (status = 200, description = "The request is allowed and was counted.", body = RateLimitResponse),
+    (status = 400, description = "The key or window is invalid.", body = String),
+    (status = 401, description = "The access token is missing or invalid.", body = String),
+    (status = 403, description = "The token can not read and write, or the namespace is read-only.", body = String),
+    (status = 429, description = "The request is denied. Retry-After says when to try again.", body = RateLimitResponse),
+  )
+)]
+async fn rate_limit_endpoint(
+  State(state): State<AppState>,
+  headers: HeaderMap,
+  Json(req): Json<RateLimitRequest>,
+) -> Result<Response, ApiError> {
+  let tenant = authenticate_bearer(&state, &headers)?;
+  if !tenant.allows(Permission::Read) || !tenant.allows(Permission::Write) {
+    return Err(ApiError::permission_denied(&tenant, "rate limit"));
+  }
+  state.namespace_modes.check(&tenant, true)?;
+  let key = parse_key(&req.key)
+    .map_err(|e| ApiError::InvalidRateLimit(e.to_string()))?;
+  if req.window_ms == 0 {
+    return Err(ApiError::InvalidRateLimit(
+      "the window must not be empty".to_string(),
+    ));
+  }
+  let limit = RateLimit {
+    limit: req.limit,
+    window: std::time::Duration::from_millis(req.window_ms),
+  };
+
+  let outcome = loop {
+    let check = limit.start(&key, utc_now());
+    let mut reads = check.reads();
+    tenant.scope_reads(&mut reads);
+    let options = SnapshotReadOptions {
+      consistency: Consistency::Strong,
+    };
+    let mut outputs = state.database.snapshot_read(reads, options).await?;
+    tenant.unscope_reads(&mut outputs)?;
+    let (outcome, write) = check
+      .finish(&outputs, req.cost)
+      .map_err(|e| ApiError::TypeMismatch(e.to_string()))?;
+    let Some(mut write) = write else {
+      break outcome;
+    };
+    tenant.scope_write(&mut write)?;
+    if state.database.atomic_write(write).await?.is_some() {
+      break outcome;
+    }
+  };
+
+  let body = Json(RateLimitResponse {
+    allowed: outcome.allowed,
+    remaining: outcome.remaining,
+    retry_after_ms: outcome.retry_after.map(|after| after.as_millis() as u64),
+  });
+  if outcome.allowed {
+    return Ok(body.into_response());
+  }
+  let mut res = (StatusCode::TOO_MANY_REQUESTS, body).into_response();
+  if let Some(retry_after) = outcome.retry_after {
+    res
+      .headers_mut()
+      .insert(RETRY_AFTER, retry_after_secs(retry_after).into());
+  }
+  Ok(res)
+}
+
 // #[axum::debug_handler]
 async fn authentication_middleware(
   State(state): State<AppState>,
-  req: Request<Body>,
+  mut req: Request<Body>,
   next: Next<Body>,
 ) -> Result<Response, ApiError> {
   let Some(protocol_version) = req
@@ -501,9 +1658,12 @@ async fn authentication_middleware(
   let Some((bearer, token)) = authorization.split_once(' ') else {
     return Err(ApiError::MalformedAuthorizationHeader);
   };
-  if bearer.to_lowercase() != "bearer" || token != state.access_token {
+  if bearer.to_lowercase() != "bearer" {
     return Err(ApiError::InvalidAccessToken);
   }
+  let Some(tenant) = state.tokens.read().unwrap().authenticate(token) else {
+    return Err(ApiError::InvalidAccessToken);
+  };
   let Some(td_id) = req
     .headers()
     .get("x-denokv-database-id")
@@ -519,56 +1679,142 @@ async fn authentication_middleware(
     return Err(ApiError::InvalidDatabaseId);
   }
 
-  Ok(next.run(req).await)
+  let features = match req.headers().get(FEATURES_HEADER) {
+    Some(value) => Features::parse(value)?,
+    None => Features::default(),
+  };
+
+  let path = req.uri().path().to_string();
+  state
+    .namespace_modes
+    .check(&tenant, path == "/atomic_write")?;
+  req.extensions_mut().insert(tenant.clone());
+  req.extensions_mut().insert(features);
+  let res = next.run(req).await;
+  if let Some(auditor) = &state.auditor {
+    let failed =
+      res.status().is_client_error() || res.status().is_server_error();
+    auditor.record_request(&tenant, &path, !failed);
+  }
+  Ok(res)
 }
 
 #[axum::debug_handler]
 async fn snapshot_read_endpoint(
   State(state): State<AppState>,
+  Extension(tenant): Extension<Arc<Tenant>>,
+  Extension(features): Extension<Features>,
   Protobuf(snapshot_read): Protobuf<pb::SnapshotRead>,
 ) -> Result<Protobuf<pb::SnapshotReadOutput>, ApiError> {
+  if !tenant.allows(Permission::Read) {
+    return Err(ApiError::permission_denied(&tenant, "read"));
+  }
   let requests: Vec<ReadRange> = snapshot_read.try_into()?;
 
   let options = SnapshotReadOptions {
     consistency: Consistency::Strong,
   };
 
-  let result_ranges = state.database.snapshot_read(requests, options).await?;
+  let started = std::time::Instant::now();
+  let mut planned = state.read_budget.plan(&requests);
+  let recorded = state.recorder.as_ref().map(|_| planned.clone());
+  tenant.scope_reads(&mut planned);
+  let mut result_ranges = if planned.is_empty() {
+    Vec::new()
+  } else {
+    state.database.snapshot_read(planned, options).await?
+  };
+  tenant.unscope_reads(&mut result_ranges)?;
+  if let (Some(recorder), Some(planned)) = (&state.recorder, recorded) {
+    recorder.record_read(started, &planned, &result_ranges);
+  }
 
-  let res = result_ranges.into();
+  let res = state.read_budget.finish(&requests, result_ranges);
+  // Clients that do not follow continuations would take a read that was cut
+  // short for the whole range.
+  if !features.has(Feature::ReadContinuations)
+    && res.ranges.iter().any(|range| range.continuation.is_some())
+  {
+    return Err(ApiError::ReadBudgetExceeded);
+  }
+  if let Some(auditor) = &state.auditor {
+    let entries = res.ranges.iter().flat_map(|range| &range.values);
+    let (count, bytes) = entries.fold((0, 0), |(count, bytes), entry| {
+      (count + 1, bytes + entry.key.len() + entry.value.len())
+    });
+    auditor.record_read(&tenant, &requests, count, bytes);
+  }
   Ok(Protobuf(res))
 }
 
 #[debug_handler]
 async fn atomic_write_endpoint(
   State(state): State<AppState>,
+  Extension(tenant): Extension<Arc<Tenant>>,
   Protobuf(atomic_write): Protobuf<pb::AtomicWrite>,
 ) -> Result<Protobuf<pb::AtomicWriteOutput>, ApiError> {
-  let atomic_write: AtomicWrite = atomic_write.try_into()?;
+  let size = atomic_write.encoded_len();
+  let mut atomic_write: AtomicWrite = atomic_write.try_into()?;
+  if !tenant.allows_write(&atomic_write) {
+    return Err(ApiError::permission_denied(&tenant, "write"));
+  }
+  if let Some(quotas) = &state.quotas {
+    quotas.check_write(&tenant, &atomic_write)?;
+  }
+  if let Some(auditor) = &state.auditor {
+    auditor.record_write(&tenant, &atomic_write, size);
+  }
 
-  let res = state.database.atomic_write(atomic_write).await
+  let started = std::time::Instant::now();
+  let trace = state
+    .recorder
+    .as_ref()
+    .map(|recorder| recorder.trace_write(&atomic_write));
+  tenant.scope_write(&mut atomic_write)?;
+  let res = state
+    .database
+    .atomic_write(atomic_write)
+    .await
     .map_err(|e| {
       log::error!("atomic_write failed: {}", e);
       e
     })?;
+  if let (Some(recorder), Some(trace)) = (&state.recorder, trace) {
+    recorder.record(started, trace);
+  }
 
   Ok(Protobuf(res.into()))
 }
 
 async fn watch_endpoint(
   State(state): State<AppState>,
+  Extension(tenant): Extension<Arc<Tenant>>,
+  Extension(features): Extension<Features>,
   Protobuf(watch): Protobuf<pb::Watch>,
 ) -> Result<impl IntoResponse, ApiError> {
-  let keys = watch.try_into()?;
+  if !tenant.allows(Permission::Read) {
+    return Err(ApiError::permission_denied(&tenant, "read"));
+  }
+  let mut encoder = WatchOutputEncoder::new(
+    watch.accept_value_deltas && features.has(Feature::ValueDeltas),
+  );
+  let mut keys: Vec<Vec<u8>> = watch.try_into()?;
+  tenant.scope_keys(&mut keys);
 
   let watcher = state.database.watch(keys);
 
-  let data_stream = watcher.map_ok(|outs| {
-    let output = pb::WatchOutput::from(outs);
-    output.encode_to_vec()
+  let active = state.metrics.watch_started("http");
+  let data_stream = watcher.map(move |outs| {
+    let _active = &active;
+    let mut outs = outs?;
+    tenant
+      .unscope_watch(&mut outs)
+      .map_err(|e| deno_error::JsErrorBox::generic(e.to_string()))?;
+    let output = encoder.encode(outs);
+    Ok(output.encode_to_vec())
   });
 
-  let mut timer = tokio::time::interval(std::time::Duration::from_secs(5));
+  let mut timer = tokio::time::interval(WATCH_PING_INTERVAL);
   timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
   let ping_stream = unfold(timer, |mut timer| async move {
     timer.tick().await;
@@ -576,9 +1822,14 @@ async fn watch_endpoint(
   })
   .boxed();
 
-  let body_stream = stream_select!(data_stream, ping_stream).map_ok(|data| {
-    Bytes::from([&(data.len() as u32).to_le_bytes()[..], &data[..]].concat())
-  });
+  // Draining ends the stream cleanly, which tells clients to reconnect
+  // right away rather than after a backoff.
+  let body_stream = stream_select!(data_stream, ping_stream)
+    .take_until(state.draining.cancelled_owned())
+    .map_ok(move |data| {
+      state.metrics.record_sent(WATCH_ENDPOINT, data.len() + 4);
+      Bytes::from([&(data.len() as u32).to_le_bytes()[..], &data[..]].concat())
+    });
 
   let mut res = StreamBody::new(body_stream).into_response();
   res
@@ -587,6 +1838,135 @@ async fn watch_endpoint(
   Ok(res)
 }
 
+/// A watch over a WebSocket instead of a streamed response. The client sends
+/// the `Watch` request as the first binary message and receives every
+/// `WatchOutput` as a binary message. The server pings every
+/// `WATCH_PING_INTERVAL` and closes connections that stop answering.
+async fn watch_websocket_endpoint(
+  State(state): State<AppState>,
+  Extension(tenant): Extension<Arc<Tenant>>,
+  Extension(features): Extension<Features>,
+  ws: WebSocketUpgrade,
+) -> Result<Response, ApiError> {
+  if !tenant.allows(Permission::Read) {
+    return Err(ApiError::permission_denied(&tenant, "read"));
+  }
+  let upgraded = state.upgraded.clone();
+  Ok(ws.on_upgrade(move |mut socket| {
+    upgraded.track_future(async move {
+      if let Err(e) =
+        serve_watch_websocket(state, tenant, features, &mut socket).await
+      {
+        let close = CloseFrame {
+          code: e.close_code(),
+          reason: Cow::Owned(e.to_string()),
+        };
+        let _ = socket.send(WsMessage::Close(Some(close))).await;
+      }
+    })
+  }))
+}
+
+async fn serve_watch_websocket(
+  state: AppState,
+  tenant: Arc<Tenant>,
+  features: Features,
+  socket: &mut WebSocket,
+) -> Result<(), ApiError> {
+  let watch = loop {
+    match socket.recv().await {
+      Some(Ok(WsMessage::Binary(data))) => {
+        break pb::Watch::decode(&data[..])
+          .map_err(ApiError::InvalidRequestProto)?
+      }
+      Some(Ok(WsMessage::Text(_))) => {
+        return Err(ApiError::InvalidRequestProto(DecodeError::new(
+          "expected a binary message",
+        )))
+      }
+      Some(Ok(_)) => continue,
+      Some(Err(_)) | None => return Ok(()),
+    }
+  };
+  let mut encoder = WatchOutputEncoder::new(
+    watch.accept_value_deltas && features.has(Feature::ValueDeltas),
+  );
+  let mut keys: Vec<Vec<u8>> = watch.try_into()?;
+  tenant.scope_keys(&mut keys);
+  let mut watcher = state.database.watch(keys);
+  let _active = state.metrics.watch_started("websocket");
+
+  let mut ping = tokio::time::interval_at(
+    tokio::time::Instant::now() + WATCH_PING_INTERVAL,
+    WATCH_PING_INTERVAL,
+  );
+  ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+  let mut last_seen = std::time::Instant::now();
+  loop {
+    tokio::select! {
+      outputs = watcher.next() => {
+        let Some(outputs) = outputs else {
+          return Ok(());
+        };
+        let mut outputs = outputs?;
+        tenant.unscope_watch(&mut outputs)?;
+        let output = encoder.encode(outputs).encode_to_vec();
+        state.metrics.record_sent(WATCH_WEBSOCKET_ENDPOINT, output.len());
+        if socket.send(WsMessage::Binary(output)).await.is_err() {
+          return Ok(());
+        }
+      }
+      message = socket.recv() => match message {
+        Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => return Ok(()),
+        Some(Ok(_)) => last_seen = std::time::Instant::now(),
+      },
+      _ = state.draining.cancelled() => {
+        let close = CloseFrame {
+          code: axum::extract::ws::close_code::AWAY,
+          reason: Cow::Borrowed("server restarting"),
+        };
+        let _ = socket.send(WsMessage::Close(Some(close))).await;
+        return Ok(());
+      }
+      _ = ping.tick() => {
+        if last_seen.elapsed() > WATCH_PING_INTERVAL * 3 {
+          return Ok(());
+        }
+        if socket.send(WsMessage::Ping(vec![])).await.is_err() {
+          return Ok(());
+        }
+      }
+    }
+  }
+}
+
+/// The protocol extensions a data path request uses, from the
+/// `x-denokv-features` header.
+#[derive(Clone, Default)]
+struct Features(Vec<Feature>);
+
+impl Features {
+  fn parse(header: &axum::http::HeaderValue) -> Result<Self, ApiError> {
+    let header = header
+      .to_str()
+      .map_err(|_| ApiError::UnknownFeature(String::new()))?;
+    header
+      .split(',')
+      .map(str::trim)
+      .filter(|name| !name.is_empty())
+      .map(|name| {
+        Feature::from_name(name)
+          .ok_or_else(|| ApiError::UnknownFeature(name.to_string()))
+      })
+      .collect::<Result<_, _>>()
+      .map(Features)
+  }
+
+  fn has(&self, feature: Feature) -> bool {
+    self.0.contains(&feature)
+  }
+}
+
 #[debug_handler]
 async fn fallback_handler() -> ApiError {
   ApiError::NotFound
@@ -600,8 +1980,12 @@ enum ApiError {
   MalformedAuthorizationHeader,
   #[error("Invalid access token.")]
   InvalidAccessToken,
+  #[error("The access token does not permit this request.")]
+  PermissionDenied,
   #[error("Invalid database id.")]
   InvalidDatabaseId,
+  #[error("Unknown protocol feature '{0}'.")]
+  UnknownFeature(String),
   #[error("Expected protocol version 2.")]
   InvalidProtocolVersion,
   #[error("Request protobuf is invalid: {}.", .0)]
@@ -612,6 +1996,8 @@ enum ApiError {
   ValueTooLong,
   #[error("The total number of entries requested across read ranges in the read request is too large.")]
   ReadRangeTooLarge,
+  #[error("The read exceeds the server's response size limit. Read fewer entries at once.")]
+  ReadBudgetExceeded,
   #[error("The total size of the atomic write is too large.")]
   AtomicWriteTooLarge,
   #[error("Too many read ranges requested in one read request.")]
@@ -646,6 +2032,11 @@ enum ApiError {
   NoMatchingProtocolVersion,
   #[error("The server is temporarially unavailable, try again later.")]
   TryAgain,
+  #[error("The server is overloaded ({pressure}), try again in {}s.", retry_after_secs(*.retry_after))]
+  Overloaded {
+    pressure: Pressure,
+    retry_after: std::time::Duration,
+  },
   #[error("This database is read only.")]
   // TODO: this should not be used (write_disabled should be used instead)
   ReadOnly,
@@ -653,20 +2044,65 @@ enum ApiError {
   UnknownValueEncoding(i64),
   #[error("{0}")]
   TypeMismatch(String),
+  #[error(
+    "Sum, min and max mutations are not supported in encrypted databases."
+  )]
+  UnsupportedInEncryptedDatabase,
+  #[error("A value could not be decrypted.")]
+  DecryptionFailed,
+  #[error("Invalid rate limit: {0}.")]
+  InvalidRateLimit(String),
+  #[error(
+    "The namespace is over its storage quota. Delete keys to make room."
+  )]
+  QuotaExceeded,
+  #[error("The namespace is read only{}.", reason_suffix(.0))]
+  NamespaceReadOnly(Option<String>),
+  #[error("The namespace is frozen{}.", reason_suffix(.0))]
+  NamespaceFrozen(Option<String>),
+  #[error("Invalid namespace mode: {0}.")]
+  InvalidNamespaceMode(String),
+  #[error("The namespace of the token has no encryption key.")]
+  NamespaceNotEncrypted,
+}
+
+/// `reason` as the end of an error message.
+fn reason_suffix(reason: &Option<String>) -> String {
+  reason
+    .as_ref()
+    .map_or(String::new(), |reason| format!(": {reason}"))
 }
 
 impl ApiError {
+  fn permission_denied(tenant: &Tenant, operation: &str) -> Self {
+    warn!("Token '{}' is not permitted to {}", tenant.name, operation);
+    ApiError::PermissionDenied
+  }
+
+  /// The WebSocket close code for an error ending a watch.
+  fn close_code(&self) -> u16 {
+    match self.status() {
+      // Internal error
+      status if status.is_server_error() => 1011,
+      // Policy violation
+      _ => 1008,
+    }
+  }
+
   fn status(&self) -> StatusCode {
     match self {
       ApiError::NotFound => StatusCode::NOT_FOUND,
       ApiError::MalformedAuthorizationHeader => StatusCode::UNAUTHORIZED,
       ApiError::InvalidAccessToken => StatusCode::UNAUTHORIZED,
+      ApiError::PermissionDenied => StatusCode::FORBIDDEN,
       ApiError::InvalidDatabaseId => StatusCode::BAD_REQUEST,
       ApiError::InvalidProtocolVersion => StatusCode::BAD_REQUEST,
+      ApiError::UnknownFeature(..) => StatusCode::BAD_REQUEST,
       ApiError::InvalidRequestProto(..) => StatusCode::BAD_REQUEST,
       ApiError::KeyTooLong => StatusCode::BAD_REQUEST,
       ApiError::ValueTooLong => StatusCode::BAD_REQUEST,
       ApiError::ReadRangeTooLarge => StatusCode::BAD_REQUEST,
+      ApiError::ReadBudgetExceeded => StatusCode::BAD_REQUEST,
       ApiError::AtomicWriteTooLarge => StatusCode::BAD_REQUEST,
       ApiError::TooManyReadRanges => StatusCode::BAD_REQUEST,
       ApiError::TooManyWatchedKeys => StatusCode::BAD_REQUEST,
@@ -684,9 +2120,19 @@ impl ApiError {
       ApiError::MinumumProtocolVersion => StatusCode::BAD_REQUEST,
       ApiError::NoMatchingProtocolVersion => StatusCode::BAD_REQUEST,
       ApiError::TryAgain => StatusCode::SERVICE_UNAVAILABLE,
+      ApiError::Overloaded { .. } => StatusCode::SERVICE_UNAVAILABLE,
       ApiError::ReadOnly => StatusCode::BAD_REQUEST,
       ApiError::UnknownValueEncoding(_) => StatusCode::BAD_REQUEST,
       ApiError::TypeMismatch(_) => StatusCode::BAD_REQUEST,
+      ApiError::UnsupportedInEncryptedDatabase => StatusCode::BAD_REQUEST,
+      ApiError::DecryptionFailed => StatusCode::INTERNAL_SERVER_ERROR,
+      ApiError::InvalidRateLimit(_) => StatusCode::BAD_REQUEST,
+      // Clients retry server errors, which would not help.
+      ApiError::QuotaExceeded => StatusCode::FORBIDDEN,
+      ApiError::NamespaceReadOnly(_) => StatusCode::FORBIDDEN,
+      ApiError::NamespaceFrozen(_) => StatusCode::FORBIDDEN,
+      ApiError::InvalidNamespaceMode(_) => StatusCode::BAD_REQUEST,
+      ApiError::NamespaceNotEncrypted => StatusCode::BAD_REQUEST,
     }
   }
 }
@@ -717,10 +2163,22 @@ impl From<SqliteBackendError> for ApiError {
 
 impl IntoResponse for ApiError {
   fn into_response(self) -> Response {
-    (self.status(), format!("{self}")).into_response()
+    let mut res = (self.status(), format!("{self}")).into_response();
+    if let ApiError::Overloaded { retry_after, .. } = self {
+      res
+        .headers_mut()
+        .insert(RETRY_AFTER, retry_after_secs(retry_after).into());
+    }
+    res
   }
 }
 
+/// `retry_after` in whole seconds, rounded up so clients do not retry
+/// early, as the Retry-After header has no fractions.
+fn retry_after_secs(retry_after: std::time::Duration) -> u64 {
+  retry_after.as_millis().div_ceil(1000) as u64
+}
+
 impl From<ConvertError> for ApiError {
   fn from(err: ConvertError) -> ApiError {
     match err {
@@ -757,6 +2215,31 @@ impl From<ConvertError> for ApiError {
 
 impl From<deno_error::JsErrorBox> for ApiError {
   fn from(err: deno_error::JsErrorBox) -> ApiError {
+    let postgres_error = err
+      .get_inner_ref()
+      .and_then(|inner| inner.downcast_ref::<PostgresError>());
+    match postgres_error {
+      Some(PostgresError::Overloaded {
+        pressure,
+        retry_after,
+      }) => {
+        log::debug!("Shedding a request: {pressure}");
+        return ApiError::Overloaded {
+          pressure: *pressure,
+          retry_after: *retry_after,
+        };
+      }
+      Some(PostgresError::Backpressure | PostgresError::CircuitOpen(_)) => {
+        return ApiError::TryAgain;
+      }
+      Some(PostgresError::TypeMismatch(msg)) => {
+        return ApiError::TypeMismatch(msg.clone());
+      }
+      Some(x @ PostgresError::SumOutOfRange) => {
+        return ApiError::TypeMismatch(x.to_string());
+      }
+      _ => {}
+    }
     log::error!("Database error: {}", err);
     ApiError::InternalServerError
   }
@@ -0,0 +1,277 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! Prometheus metrics of the HTTP server, served on `/metrics` together
+//! with the metrics of the database backend.
+//!
+//! `/metrics` on the main address needs an admin token, like any other
+//! request that is not confined to a namespace. With `--health-listen` it
+//! is also served without a token on the health check address.
+
+use std::time::Instant;
+
+use axum::body::Body;
+use axum::body::HttpBody;
+use axum::extract::MatchedPath;
+use axum::extract::State;
+use axum::http::header::CONTENT_LENGTH;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::Request;
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use prometheus::proto::MetricFamily;
+use prometheus::Encoder;
+use prometheus::HistogramOpts;
+use prometheus::HistogramVec;
+use prometheus::IntCounter;
+use prometheus::IntCounterVec;
+use prometheus::IntGaugeVec;
+use prometheus::Opts;
+use prometheus::Registry;
+use prometheus::TextEncoder;
+
+use crate::AppState;
+
+/// The `endpoint` label of requests that matched no route.
+const UNMATCHED: &str = "unmatched";
+
+pub struct Metrics {
+  registry: Registry,
+  requests: IntCounterVec,
+  request_duration: HistogramVec,
+  received_bytes: IntCounterVec,
+  sent_bytes: IntCounterVec,
+  auth_failures: IntCounter,
+  watches: IntCounterVec,
+  active_watches: IntGaugeVec,
+  namespace_usage: IntGaugeVec,
+  namespace_quota: IntGaugeVec,
+  quota_warnings: IntCounterVec,
+}
+
+impl Metrics {
+  pub fn new() -> Self {
+    let requests = IntCounterVec::new(
+      Opts::new(
+        "denokv_http_requests_total",
+        "HTTP requests, by endpoint and response status.",
+      ),
+      &["endpoint", "status"],
+    )
+    .unwrap();
+    let request_duration = HistogramVec::new(
+      HistogramOpts::new(
+        "denokv_http_request_duration_seconds",
+        "Time until the response head was sent, by endpoint.",
+      ),
+      &["endpoint"],
+    )
+    .unwrap();
+    let received_bytes = IntCounterVec::new(
+      Opts::new(
+        "denokv_http_received_bytes_total",
+        "Bytes of request bodies, by endpoint.",
+      ),
+      &["endpoint"],
+    )
+    .unwrap();
+    let sent_bytes = IntCounterVec::new(
+      Opts::new(
+        "denokv_http_sent_bytes_total",
+        "Bytes of response bodies and watch streams, by endpoint.",
+      ),
+      &["endpoint"],
+    )
+    .unwrap();
+    let auth_failures = IntCounter::new(
+      "denokv_auth_failures_total",
+      "Requests rejected for a missing or invalid access token.",
+    )
+    .unwrap();
+    let watches = IntCounterVec::new(
+      Opts::new(
+        "denokv_watches_total",
+        "Watch streams opened, by transport.",
+      ),
+      &["transport"],
+    )
+    .unwrap();
+    let active_watches = IntGaugeVec::new(
+      Opts::new(
+        "denokv_watches_active",
+        "Watch streams currently open, by transport.",
+      ),
+      &["transport"],
+    )
+    .unwrap();
+    let namespace_usage = IntGaugeVec::new(
+      Opts::new(
+        "denokv_namespace_usage",
+        "Keys or key and value bytes stored in namespaces with a quota, as last counted.",
+      ),
+      &["namespace", "resource"],
+    )
+    .unwrap();
+    let namespace_quota = IntGaugeVec::new(
+      Opts::new(
+        "denokv_namespace_quota",
+        "Quotas of namespaces, by resource.",
+      ),
+      &["namespace", "resource"],
+    )
+    .unwrap();
+    let quota_warnings = IntCounterVec::new(
+      Opts::new(
+        "denokv_quota_warnings_total",
+        "Namespaces crossing a quota warning threshold, by resource.",
+      ),
+      &["namespace", "resource"],
+    )
+    .unwrap();
+
+    let registry = Registry::new();
+    registry.register(Box::new(requests.clone())).unwrap();
+    registry
+      .register(Box::new(request_duration.clone()))
+      .unwrap();
+    registry.register(Box::new(received_bytes.clone())).unwrap();
+    registry.register(Box::new(sent_bytes.clone())).unwrap();
+    registry.register(Box::new(auth_failures.clone())).unwrap();
+    registry.register(Box::new(watches.clone())).unwrap();
+    registry.register(Box::new(active_watches.clone())).unwrap();
+    registry
+      .register(Box::new(namespace_usage.clone()))
+      .unwrap();
+    registry
+      .register(Box::new(namespace_quota.clone()))
+      .unwrap();
+    registry.register(Box::new(quota_warnings.clone())).unwrap();
+    Self {
+      registry,
+      requests,
+      request_duration,
+      received_bytes,
+      sent_bytes,
+      auth_failures,
+      watches,
+      active_watches,
+      namespace_usage,
+      namespace_quota,
+      quota_warnings,
+    }
+  }
+
+  /// Count `bytes` sent on a stream of `endpoint`.
+  pub fn record_sent(&self, endpoint: &str, bytes: usize) {
+    self
+      .sent_bytes
+      .with_label_values(&[endpoint])
+      .inc_by(bytes as u64);
+  }
+
+  /// Count a watch opened over `transport`, which stays active until the
+  /// returned guard is dropped.
+  pub fn watch_started(&self, transport: &str) -> ActiveWatch {
+    self.watches.with_label_values(&[transport]).inc();
+    let active = self.active_watches.with_label_values(&[transport]);
+    active.inc();
+    ActiveWatch(active)
+  }
+
+  /// Record how much of `resource` a namespace uses, and its quota.
+  pub fn record_namespace_usage(
+    &self,
+    namespace: &str,
+    resource: &str,
+    used: u64,
+    quota: Option<u64>,
+  ) {
+    self
+      .namespace_usage
+      .with_label_values(&[namespace, resource])
+      .set(used as i64);
+    if let Some(quota) = quota {
+      self
+        .namespace_quota
+        .with_label_values(&[namespace, resource])
+        .set(quota as i64);
+    }
+  }
+
+  pub fn record_quota_warning(&self, namespace: &str, resource: &str) {
+    self
+      .quota_warnings
+      .with_label_values(&[namespace, resource])
+      .inc();
+  }
+
+  /// The metrics of the server and `backend` in the Prometheus text
+  /// format.
+  pub fn render(&self, backend: Vec<MetricFamily>) -> Response {
+    let mut families = self.registry.gather();
+    families.extend(backend);
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    if let Err(e) = encoder.encode(&families, &mut body) {
+      log::error!("Failed to encode metrics: {e}");
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    ([(CONTENT_TYPE, encoder.format_type().to_string())], body).into_response()
+  }
+}
+
+/// Keeps a watch counted as active.
+pub struct ActiveWatch(prometheus::IntGauge);
+
+impl Drop for ActiveWatch {
+  fn drop(&mut self) {
+    self.0.dec();
+  }
+}
+
+/// Record every request in the metrics of the server.
+pub async fn record_request(
+  State(state): State<AppState>,
+  req: Request<Body>,
+  next: Next<Body>,
+) -> Response {
+  let endpoint = req
+    .extensions()
+    .get::<MatchedPath>()
+    .map_or(UNMATCHED, MatchedPath::as_str)
+    .to_string();
+  let received = req
+    .headers()
+    .get(CONTENT_LENGTH)
+    .and_then(|length| length.to_str().ok()?.parse().ok())
+    .or_else(|| req.body().size_hint().exact())
+    .unwrap_or(0);
+
+  let started = Instant::now();
+  let res = next.run(req).await;
+  let metrics = &state.metrics;
+  metrics
+    .request_duration
+    .with_label_values(&[&endpoint])
+    .observe(started.elapsed().as_secs_f64());
+  metrics
+    .requests
+    .with_label_values(&[&endpoint, res.status().as_str()])
+    .inc();
+  metrics
+    .received_bytes
+    .with_label_values(&[&endpoint])
+    .inc_by(received);
+  // Streamed bodies are counted as they are sent.
+  if let Some(sent) = res.body().size_hint().exact() {
+    metrics
+      .sent_bytes
+      .with_label_values(&[&endpoint])
+      .inc_by(sent);
+  }
+  if res.status() == StatusCode::UNAUTHORIZED {
+    metrics.auth_failures.inc();
+  }
+  res
+}
@@ -0,0 +1,229 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! Switches that make a namespace read-only or freeze it entirely while the
+//! server runs, for incident response, suspended accounts and migrating
+//! one tenant at a time.
+//!
+//! In a read-only namespace, atomic writes and rate limits fail. In a
+//! frozen one, reads and new watches fail too; watches that are already
+//! open are not ended. Both fail with 403 and a message saying why, so
+//! that clients do not retry them. Admin tokens that see the whole
+//! keyspace are never affected.
+//!
+//! The mode of a namespace is stored under the reserved key
+//! `["__namespace_mode", <namespace>]`, as JSON bytes like
+//! `{ "mode": "frozen", "reason": "billing suspended" }`, so that it
+//! survives restarts and applies to every server sharing the database. It
+//! is set with `POST /namespace_modes` and listed with
+//! `GET /namespace_modes`, both with an admin token, or by writing the key
+//! directly. Servers pick up changes made elsewhere within
+//! `REFRESH_INTERVAL`.
+
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use denokv_proto::decode_key;
+use denokv_proto::encode_key;
+use denokv_proto::AtomicWrite;
+use denokv_proto::Consistency;
+use denokv_proto::Key;
+use denokv_proto::KeyPart;
+use denokv_proto::KvValue;
+use denokv_proto::Mutation;
+use denokv_proto::MutationKind;
+use denokv_proto::ReadRange;
+use denokv_proto::SnapshotReadOptions;
+use log::warn;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::tenants::prefix_upper_bound;
+use crate::tenants::Tenant;
+use crate::ApiError;
+use crate::DatabaseBackend;
+
+/// The first part of the keys the modes are stored under.
+const MODE_KEY: &str = "__namespace_mode";
+
+/// How often the modes are read again, to pick up changes made by other
+/// servers or by writing the keys directly.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Modes read per snapshot read.
+const PAGE_SIZE: u32 = 1000;
+
+#[derive(
+  Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, utoipa::ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+  Active,
+  ReadOnly,
+  Frozen,
+}
+
+/// The mode of a namespace, as stored and as taken and returned by the
+/// `/namespace_modes` endpoint.
+#[derive(Serialize, Deserialize, Clone, Debug, utoipa::ToSchema)]
+pub struct NamespaceMode {
+  #[serde(default)]
+  pub namespace: String,
+  pub mode: Mode,
+  /// Shown to clients whose requests fail because of the mode.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub reason: Option<String>,
+}
+
+/// The modes of the namespaces that are not active, by key prefix.
+#[derive(Default)]
+pub struct NamespaceModes {
+  modes: RwLock<HashMap<Vec<u8>, NamespaceMode>>,
+}
+
+impl NamespaceModes {
+  /// Fail the request if the namespace of `tenant` is frozen, or read-only
+  /// and `writes` is set.
+  pub fn check(&self, tenant: &Tenant, writes: bool) -> Result<(), ApiError> {
+    let modes = self.modes.read().unwrap();
+    let Some(mode) = modes.get(tenant.prefix()) else {
+      return Ok(());
+    };
+    let reason = mode.reason.clone();
+    match mode.mode {
+      Mode::Frozen => Err(ApiError::NamespaceFrozen(reason)),
+      Mode::ReadOnly if writes => Err(ApiError::NamespaceReadOnly(reason)),
+      Mode::ReadOnly | Mode::Active => Ok(()),
+    }
+  }
+
+  /// The namespaces that are not active, by name.
+  pub fn list(&self) -> Vec<NamespaceMode> {
+    let mut modes: Vec<_> =
+      self.modes.read().unwrap().values().cloned().collect();
+    modes.sort_by(|a, b| a.namespace.cmp(&b.namespace));
+    modes
+  }
+
+  /// Store the mode of a namespace and apply it right away.
+  pub async fn set(
+    &self,
+    database: &DatabaseBackend,
+    mode: NamespaceMode,
+  ) -> Result<(), ApiError> {
+    if mode.namespace.is_empty() {
+      return Err(ApiError::InvalidNamespaceMode(
+        "the namespace must not be empty".to_string(),
+      ));
+    }
+    let key = encode_key(&mode_key(&mode.namespace))
+      .map_err(|e| ApiError::InvalidNamespaceMode(e.to_string()))?;
+    let kind = match mode.mode {
+      Mode::Active => MutationKind::Delete,
+      Mode::ReadOnly | Mode::Frozen => {
+        MutationKind::Set(KvValue::Bytes(serde_json::to_vec(&mode).unwrap()))
+      }
+    };
+    database
+      .atomic_write(AtomicWrite {
+        checks: vec![],
+        mutations: vec![Mutation {
+          key,
+          kind,
+          expire_at: None,
+        }],
+        enqueues: vec![],
+      })
+      .await?;
+    let prefix = namespace_prefix(&mode.namespace);
+    let mut modes = self.modes.write().unwrap();
+    match mode.mode {
+      Mode::Active => modes.remove(&prefix),
+      Mode::ReadOnly | Mode::Frozen => modes.insert(prefix, mode),
+    };
+    Ok(())
+  }
+
+  /// Read every stored mode, replacing the ones known so far.
+  pub async fn refresh(
+    &self,
+    database: &DatabaseBackend,
+  ) -> anyhow::Result<()> {
+    let start = encode_key(&Key(vec![KeyPart::String(MODE_KEY.to_string())]))?;
+    let end = prefix_upper_bound(&start);
+    let mut modes = HashMap::new();
+    let mut next = start;
+    loop {
+      let request = ReadRange {
+        start: next,
+        end: end.clone(),
+        limit: NonZeroU32::new(PAGE_SIZE).unwrap(),
+        reverse: false,
+      };
+      let options = SnapshotReadOptions {
+        consistency: Consistency::Strong,
+      };
+      let mut outputs = database.snapshot_read(vec![request], options).await?;
+      let entries = outputs.pop().map_or(Vec::new(), |output| output.entries);
+      for entry in &entries {
+        match parse_mode(&entry.key, &entry.value) {
+          Some(mode) if mode.mode == Mode::Active => {}
+          Some(mode) => {
+            modes.insert(namespace_prefix(&mode.namespace), mode);
+          }
+          None => warn!(
+            "Ignoring the invalid namespace mode at {}",
+            denokv_proto::format_key(&entry.key)
+          ),
+        }
+      }
+      match entries.last() {
+        Some(last) if entries.len() == PAGE_SIZE as usize => {
+          next = [&last.key[..], &[0]].concat();
+        }
+        _ => break,
+      }
+    }
+    *self.modes.write().unwrap() = modes;
+    Ok(())
+  }
+
+  /// Refresh the modes every `REFRESH_INTERVAL`, until the server stops.
+  pub async fn run(self: std::sync::Arc<Self>, database: DatabaseBackend) {
+    let mut timer = tokio::time::interval(REFRESH_INTERVAL);
+    timer.tick().await;
+    loop {
+      timer.tick().await;
+      if let Err(e) = self.refresh(&database).await {
+        warn!("Failed to read the namespace modes: {e}");
+      }
+    }
+  }
+}
+
+fn mode_key(namespace: &str) -> Key {
+  Key(vec![
+    KeyPart::String(MODE_KEY.to_string()),
+    KeyPart::String(namespace.to_string()),
+  ])
+}
+
+fn namespace_prefix(namespace: &str) -> Vec<u8> {
+  encode_key(&Key(vec![KeyPart::String(namespace.to_string())]))
+    .expect("a single string key part always encodes")
+}
+
+/// The mode stored at `key`, named after the namespace in the key.
+fn parse_mode(key: &[u8], value: &KvValue) -> Option<NamespaceMode> {
+  let Key(parts) = decode_key(key).ok()?;
+  let [_, KeyPart::String(namespace)] = parts.as_slice() else {
+    return None;
+  };
+  let KvValue::Bytes(value) = value else {
+    return None;
+  };
+  let mut mode: NamespaceMode = serde_json::from_slice(value).ok()?;
+  mode.namespace.clone_from(namespace);
+  Some(mode)
+}
@@ -0,0 +1,78 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! The OpenAPI document of the server's JSON endpoints: the KV Connect
+//! metadata exchange and the admin endpoints next to it. It is generated
+//! from the request and response types, so that it stays in sync with the
+//! handlers, and served at `GET /openapi.json` without authentication, or
+//! printed with `denokv openapi`, for generating Python or TypeScript
+//! clients and for dashboards.
+//!
+//! The data path under `/v2` takes and returns protobuf messages, which
+//! the schemas in `proto/schema` describe, and is left out.
+
+use utoipa::openapi::security::HttpAuthScheme;
+use utoipa::openapi::security::HttpBuilder;
+use utoipa::openapi::security::SecurityScheme;
+use utoipa::Modify;
+use utoipa::OpenApi;
+
+#[derive(OpenApi)]
+#[openapi(
+  info(
+    title = "Deno KV",
+    description = "The JSON endpoints of a denokv server. Every endpoint \
+      takes the access token of the server or of the token registry as a \
+      bearer token. Errors are returned as plain text."
+  ),
+  paths(
+    crate::metadata_endpoint,
+    crate::metrics_endpoint,
+    crate::index_advice_endpoint,
+    crate::rate_limit_endpoint,
+    crate::list_namespace_modes_endpoint,
+    crate::set_namespace_mode_endpoint,
+    crate::blind_index_endpoint,
+  ),
+  components(schemas(
+    denokv_proto::MetadataExchangeRequest,
+    denokv_proto::DatabaseMetadata,
+    denokv_proto::EndpointInfo,
+    denokv_postgres::IndexSuggestion,
+    crate::RateLimitRequest,
+    crate::RateLimitResponse,
+    crate::namespace_modes::NamespaceMode,
+    crate::namespace_modes::Mode,
+    crate::BlindIndexRequest,
+    crate::BlindIndexResponse,
+  )),
+  modifiers(&BearerAuth),
+  security(("bearer" = [])),
+  tags(
+    (name = "kv_connect", description = "The KV Connect protocol."),
+    (name = "admin", description = "Endpoints for admin tokens only."),
+    (name = "namespaces", description = "Endpoints in the namespace of \
+      the token."),
+  )
+)]
+pub struct ApiDoc;
+
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+  fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+    let components = openapi.components.get_or_insert_with(Default::default);
+    components.add_security_scheme(
+      "bearer",
+      SecurityScheme::Http(
+        HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build(),
+      ),
+    );
+  }
+}
+
+/// The document as pretty printed JSON.
+pub fn document() -> String {
+  ApiDoc::openapi()
+    .to_pretty_json()
+    .expect("the OpenAPI document serializes")
+}
@@ -0,0 +1,130 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! Moving the PostgreSQL partitions archived as cold into an S3 bucket.
+//!
+//! With `--postgres-partition-archive-after`, the partition manager detaches
+//! the range partitions of kv_store that no key was written to for that
+//! long into tables of their own. With `--postgres-partition-archive-bucket`,
+//! one of the servers sharing the database looks for such tables every
+//! [`UPLOAD_INTERVAL`], uploads each to `<prefix><table>.pgcopy` in the
+//! bucket, in the binary format of PostgreSQL's `COPY`, and drops it once
+//! the upload completed. A table whose upload failed stays in the database
+//! and is tried again on the next round.
+
+use std::path::Path;
+use std::time::Duration;
+
+use aws_sdk_s3::primitives::ByteStream;
+use denokv_postgres::ArchivedPartition;
+use denokv_postgres::Leadership;
+use denokv_postgres::Postgres;
+use futures::TryStreamExt;
+use log::info;
+use log::warn;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+/// How often archived partitions are looked for.
+const UPLOAD_INTERVAL: Duration = Duration::from_secs(300);
+
+pub struct PartitionArchive {
+  client: aws_sdk_s3::Client,
+  bucket: String,
+  prefix: String,
+}
+
+impl PartitionArchive {
+  pub fn new(
+    client: aws_sdk_s3::Client,
+    bucket: String,
+    prefix: String,
+  ) -> anyhow::Result<Self> {
+    if !prefix.is_empty() && !prefix.ends_with('/') {
+      anyhow::bail!("--postgres-partition-archive-prefix must end with a slash")
+    }
+    Ok(Self {
+      client,
+      bucket,
+      prefix,
+    })
+  }
+
+  /// Upload the archived partitions of `postgres` whenever this server
+  /// leads among those sharing the database.
+  pub async fn run(self, postgres: Postgres, leadership: Leadership) {
+    let mut interval = tokio::time::interval(UPLOAD_INTERVAL);
+    loop {
+      interval.tick().await;
+      if !leadership.is_leader() {
+        continue;
+      }
+      if let Err(e) = self.upload_all(&postgres).await {
+        warn!("Failed to upload the archived partitions: {e:#}");
+      }
+    }
+  }
+
+  async fn upload_all(&self, postgres: &Postgres) -> anyhow::Result<()> {
+    for partition in postgres.archived_partitions().await? {
+      let key = format!("{}{}.pgcopy", self.prefix, partition.table);
+      self.upload(postgres, &partition, &key).await?;
+      postgres.drop_archived_partition(&partition).await?;
+      info!(
+        "Moved the archived partition {} to s3://{}/{}",
+        partition.table, self.bucket, key
+      );
+    }
+    Ok(())
+  }
+
+  /// Upload `partition` to `key`. The export is spooled to a temporary
+  /// file first, as S3 needs to know the length of an upload up front.
+  async fn upload(
+    &self,
+    postgres: &Postgres,
+    partition: &ArchivedPartition,
+    key: &str,
+  ) -> anyhow::Result<()> {
+    let path = std::env::temp_dir().join(format!(
+      "{}-{}.pgcopy",
+      partition.table,
+      Uuid::new_v4()
+    ));
+    let result = async {
+      spool(postgres, partition, &path).await?;
+      let body = ByteStream::from_path(&path).await?;
+      self
+        .client
+        .put_object()
+        .bucket(&self.bucket)
+        .key(key)
+        .body(body)
+        .send()
+        .await?;
+      Ok(())
+    }
+    .await;
+    match tokio::fs::remove_file(&path).await {
+      Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+        warn!("Failed to remove {}: {e}", path.display());
+      }
+      _ => {}
+    }
+    result
+  }
+}
+
+async fn spool(
+  postgres: &Postgres,
+  partition: &ArchivedPartition,
+  path: &Path,
+) -> anyhow::Result<()> {
+  let mut file = tokio::fs::File::create(path).await?;
+  let mut chunks =
+    std::pin::pin!(postgres.export_archived_partition(partition));
+  while let Some(chunk) = chunks.try_next().await? {
+    file.write_all(&chunk).await?;
+  }
+  file.flush().await?;
+  Ok(())
+}
@@ -0,0 +1,253 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! Storage quotas of namespaces, with warnings as namespaces approach them.
+//!
+//! The quotas are listed in a JSON file given with `--namespace-quotas`, by
+//! namespace. Either limit may be left out:
+//!
+//! ```json
+//! {
+//!   "shop": { "max_keys": 1000000, "max_bytes": 1073741824 },
+//!   "reports": { "max_bytes": 104857600 }
+//! }
+//! ```
+//!
+//! Every `--quota-check-interval` seconds the server counts the keys under
+//! each of these namespaces and their key and value bytes. A namespace at
+//! or over one of its quotas can only delete until a later check finds it
+//! under again: writes through its tokens that set values or enqueue
+//! messages fail with 403. As usage is only counted at intervals, a
+//! namespace can overshoot its quota by what it writes in one interval.
+//!
+//! Crossing one of the `--quota-warning-thresholds`, fractions of a quota,
+//! raises a usage warning, so that tenants can be contacted before their
+//! writes start failing. Warnings are logged, counted in the
+//! `denokv_quota_warnings_total` metric and posted as JSON to
+//! `--quota-warning-webhook`. A namespace warns about a threshold again
+//! only after falling back below it, or after the server restarts. The
+//! counted usage and the quotas are exported as the `denokv_namespace_usage`
+//! and `denokv_namespace_quota` metrics.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use anyhow::Context;
+use denokv_postgres::PrefixUsage;
+use denokv_proto::encode_key;
+use denokv_proto::AtomicWrite;
+use denokv_proto::Key;
+use denokv_proto::KeyPart;
+use denokv_proto::MutationKind;
+use log::info;
+use log::warn;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::metrics::Metrics;
+use crate::tenants::Tenant;
+use crate::ApiError;
+use crate::DatabaseBackend;
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Quota {
+  #[serde(default)]
+  max_keys: Option<u64>,
+  #[serde(default)]
+  max_bytes: Option<u64>,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Resource {
+  Keys,
+  Bytes,
+}
+
+impl Resource {
+  pub fn as_str(self) -> &'static str {
+    match self {
+      Resource::Keys => "keys",
+      Resource::Bytes => "bytes",
+    }
+  }
+}
+
+#[derive(Serialize, Debug)]
+pub struct UsageWarning {
+  pub namespace: String,
+  pub resource: Resource,
+  pub used: u64,
+  pub limit: u64,
+  /// The threshold that was crossed, as a fraction of the limit.
+  pub threshold: f64,
+  pub message: String,
+}
+
+type WarningCallback = Box<dyn Fn(&UsageWarning) + Send + Sync>;
+
+struct NamespaceQuota {
+  namespace: String,
+  prefix: Vec<u8>,
+  quota: Quota,
+}
+
+pub struct QuotaMonitor {
+  quotas: Vec<NamespaceQuota>,
+  /// Ascending fractions of a quota that raise a warning when crossed.
+  thresholds: Vec<f64>,
+  interval: Duration,
+  /// Prefixes of the namespaces that were at or over a quota when last
+  /// counted.
+  exhausted: RwLock<HashSet<Vec<u8>>>,
+  metrics: Arc<Metrics>,
+  on_warning: WarningCallback,
+}
+
+impl QuotaMonitor {
+  /// Read the quota file at `path`.
+  pub fn load(
+    path: &Path,
+    mut thresholds: Vec<f64>,
+    interval: Duration,
+    metrics: Arc<Metrics>,
+    on_warning: impl Fn(&UsageWarning) + Send + Sync + 'static,
+  ) -> anyhow::Result<Self> {
+    let file = std::fs::read(path).with_context(|| {
+      format!("Failed to read the namespace quotas {}", path.display())
+    })?;
+    let entries: HashMap<String, Quota> = serde_json::from_slice(&file)
+      .with_context(|| {
+        format!("Invalid namespace quotas {}", path.display())
+      })?;
+    if let Some(threshold) =
+      thresholds.iter().find(|t| !(**t > 0.0 && **t < 1.0))
+    {
+      anyhow::bail!(
+        "Quota warning thresholds must be between 0 and 1, got {threshold}"
+      );
+    }
+    thresholds.sort_by(f64::total_cmp);
+    thresholds.dedup();
+
+    let mut quotas = Vec::with_capacity(entries.len());
+    for (namespace, quota) in entries {
+      let prefix = encode_key(&Key(vec![KeyPart::String(namespace.clone())]))?;
+      quotas.push(NamespaceQuota {
+        namespace,
+        prefix,
+        quota,
+      });
+    }
+    Ok(Self {
+      quotas,
+      thresholds,
+      interval,
+      exhausted: RwLock::default(),
+      metrics,
+      on_warning: Box::new(on_warning),
+    })
+  }
+
+  /// Reject `write` if it would store more in the namespace of `tenant`
+  /// while the namespace is at its quota. Deletes and checks always pass.
+  pub fn check_write(
+    &self,
+    tenant: &Tenant,
+    write: &AtomicWrite,
+  ) -> Result<(), ApiError> {
+    let stores = !write.enqueues.is_empty()
+      || write
+        .mutations
+        .iter()
+        .any(|mutation| !matches!(mutation.kind, MutationKind::Delete));
+    if stores && self.exhausted.read().unwrap().contains(tenant.prefix()) {
+      warn!(
+        "Rejected a write with token '{}' over its namespace's quota",
+        tenant.name
+      );
+      return Err(ApiError::QuotaExceeded);
+    }
+    Ok(())
+  }
+
+  /// Count the usage of every namespace with a quota at the end of every
+  /// interval, until the server stops.
+  pub async fn run(self: Arc<Self>, database: DatabaseBackend) {
+    // How many thresholds each namespace and resource was over.
+    let mut levels: HashMap<(String, Resource), usize> = HashMap::new();
+    let mut timer = tokio::time::interval(self.interval);
+    loop {
+      timer.tick().await;
+      for quota in &self.quotas {
+        match database.prefix_usage(&quota.prefix).await {
+          Ok(usage) => self.update(quota, usage, &mut levels),
+          Err(e) => warn!(
+            "Failed to count the usage of namespace '{}': {e}",
+            quota.namespace
+          ),
+        }
+      }
+    }
+  }
+
+  fn update(
+    &self,
+    quota: &NamespaceQuota,
+    usage: PrefixUsage,
+    levels: &mut HashMap<(String, Resource), usize>,
+  ) {
+    let namespace = &quota.namespace;
+    let mut exhausted = false;
+    for (resource, used, limit) in [
+      (Resource::Keys, usage.keys, quota.quota.max_keys),
+      (Resource::Bytes, usage.bytes, quota.quota.max_bytes),
+    ] {
+      self.metrics.record_namespace_usage(
+        namespace,
+        resource.as_str(),
+        used,
+        limit,
+      );
+      let Some(limit) = limit else {
+        continue;
+      };
+      exhausted |= used >= limit;
+
+      let fraction = used as f64 / limit.max(1) as f64;
+      let level = self.thresholds.iter().filter(|t| fraction >= **t).count();
+      let previous = levels.insert((namespace.clone(), resource), level);
+      if level > previous.unwrap_or(0) {
+        let threshold = self.thresholds[level - 1];
+        self
+          .metrics
+          .record_quota_warning(namespace, resource.as_str());
+        (self.on_warning)(&UsageWarning {
+          namespace: namespace.clone(),
+          resource,
+          used,
+          limit,
+          threshold,
+          message: format!(
+            "Namespace '{namespace}' uses {used} of its {limit} {}, over {:.0}%",
+            resource.as_str(),
+            threshold * 100.0
+          ),
+        });
+      }
+    }
+
+    let mut exhausted_namespaces = self.exhausted.write().unwrap();
+    if exhausted {
+      if exhausted_namespaces.insert(quota.prefix.clone()) {
+        warn!("Namespace '{namespace}' reached its quota; writes to it fail");
+      }
+    } else if exhausted_namespaces.remove(&quota.prefix) {
+      info!("Namespace '{namespace}' is back under its quota");
+    }
+  }
+}
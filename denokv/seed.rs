@@ -0,0 +1,320 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! Seeding a database from a fixture file, so that development and staging
+//! environments start from the same data every time.
+//!
+//! A fixture is a TOML file listing entries and queue messages by
+//! namespace. Keys are written as `format_key` writes them, and values are
+//! stored as V8 values unless they are given as `u64` or `bytes`:
+//!
+//! ```toml
+//! name = "demo"
+//!
+//! [[namespaces]]
+//! namespace = "shop"
+//!
+//! [[namespaces.entries]]
+//! key = '["users", 1.0]'
+//! value = { name = "Alice", joined = 2024-01-01T00:00:00Z }
+//!
+//! [[namespaces.entries]]
+//! key = '["visits"]'
+//! u64 = 0
+//!
+//! [[namespaces.entries]]
+//! key = '["sessions", "abc"]'
+//! bytes = "0a0b0c"
+//! expire_in_ms = 3600000
+//!
+//! [[namespaces.messages]]
+//! value = { type = "welcome", user = 1 }
+//! delay_ms = 1000
+//! keys_if_undelivered = ['["failed", 1.0]']
+//! ```
+//!
+//! A `value` is a JavaScript value: tables are objects, arrays are arrays,
+//! datetimes with an offset are `Date`s, and integers are numbers, which
+//! must be at most 2^53 - 1. Larger integers are given as
+//! `bigint = "<decimal>"`. Messages take a `value` and optionally
+//! `backoff_schedule`, a list of milliseconds.
+//!
+//! Each namespace is written in one atomic write, so it is seeded entirely
+//! or not at all. A namespace without a name is the whole keyspace. Values
+//! in namespaces with an encryption key are sealed as the server seals them.
+//!
+//! Seeding again overwrites the entries and enqueues the messages again. In
+//! the idempotent mode, every namespace is seeded at most once per fixture
+//! name instead: the write also stores the key `["__seed", <name>,
+//! <namespace>]`, outside of every namespace, and only applies if that key
+//! does not exist yet. The name defaults to the file name without its
+//! extension.
+
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::path::Path;
+
+use anyhow::Context;
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+use denokv_proto::encode_key;
+use denokv_proto::parse_key;
+use denokv_proto::time::utc_now;
+use denokv_proto::v8;
+use denokv_proto::AtomicWrite;
+use denokv_proto::Check;
+use denokv_proto::Enqueue;
+use denokv_proto::Key;
+use denokv_proto::KeyPart;
+use denokv_proto::KvValue;
+use denokv_proto::Mutation;
+use denokv_proto::MutationKind;
+use serde::ser::Error as _;
+use serde::Deserialize;
+use serde::Serialize;
+use serde::Serializer;
+
+use crate::encryption::NamespaceKeys;
+use crate::tenants::Tenant;
+use crate::DatabaseBackend;
+
+/// The first part of the keys that record which namespaces were seeded.
+const SEED_KEY: &str = "__seed";
+
+#[derive(Deserialize)]
+pub struct Fixture {
+  /// Identifies the fixture in the idempotent mode.
+  #[serde(default)]
+  pub name: Option<String>,
+  #[serde(default)]
+  pub namespaces: Vec<FixtureNamespace>,
+}
+
+#[derive(Deserialize)]
+pub struct FixtureNamespace {
+  #[serde(default)]
+  pub namespace: Option<String>,
+  #[serde(default)]
+  pub entries: Vec<FixtureEntry>,
+  #[serde(default)]
+  pub messages: Vec<FixtureMessage>,
+}
+
+#[derive(Deserialize)]
+pub struct FixtureEntry {
+  pub key: String,
+  #[serde(flatten)]
+  pub value: FixtureValue,
+  #[serde(default)]
+  pub expire_in_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FixtureValue {
+  /// A JavaScript value.
+  Value(toml::Value),
+  /// A JavaScript bigint, in decimal.
+  Bigint(String),
+  U64(u64),
+  /// A byte array, in hex.
+  Bytes(String),
+}
+
+#[derive(Deserialize)]
+pub struct FixtureMessage {
+  pub value: toml::Value,
+  #[serde(default)]
+  pub delay_ms: u64,
+  #[serde(default)]
+  pub keys_if_undelivered: Vec<String>,
+  #[serde(default)]
+  pub backoff_schedule: Option<Vec<u32>>,
+}
+
+/// What seeding did to a namespace.
+pub struct SeededNamespace {
+  /// `None` for the whole keyspace.
+  pub namespace: Option<String>,
+  pub entries: usize,
+  pub messages: usize,
+  /// Whether the namespace was seeded with the fixture before, in the
+  /// idempotent mode, and was left alone.
+  pub skipped: bool,
+}
+
+impl Fixture {
+  /// Read the fixture at `path`, naming it after the file if it has no
+  /// name.
+  pub fn load(path: &Path) -> anyhow::Result<Self> {
+    let file = std::fs::read_to_string(path).with_context(|| {
+      format!("Failed to read the fixture {}", path.display())
+    })?;
+    let mut fixture: Fixture = toml::from_str(&file)
+      .with_context(|| format!("Invalid fixture {}", path.display()))?;
+    if fixture.name.is_none() {
+      fixture.name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned());
+    }
+    let mut namespaces = HashSet::new();
+    for namespace in &fixture.namespaces {
+      if !namespaces.insert(&namespace.namespace) {
+        anyhow::bail!(
+          "The namespace {} is listed twice in the fixture {}",
+          namespace_name(namespace.namespace.as_deref()),
+          path.display()
+        );
+      }
+    }
+    Ok(fixture)
+  }
+}
+
+/// Write every namespace of `fixture` in an atomic write of its own, in
+/// the order they are listed. Namespaces seeded before a failure stay
+/// seeded.
+pub async fn seed(
+  database: &DatabaseBackend,
+  fixture: &Fixture,
+  keys: &NamespaceKeys,
+  idempotent: bool,
+) -> anyhow::Result<Vec<SeededNamespace>> {
+  let mut seeded = Vec::with_capacity(fixture.namespaces.len());
+  for namespace in &fixture.namespaces {
+    let name = namespace.namespace.as_deref();
+    let mut write = namespace_write(namespace)
+      .with_context(|| format!("Invalid namespace {}", namespace_name(name)))?;
+    Tenant::of_namespace(name, keys)?.scope_write(&mut write)?;
+    if idempotent {
+      let Some(fixture_name) = &fixture.name else {
+        anyhow::bail!("The idempotent mode needs a fixture with a name");
+      };
+      let key = encode_key(&seed_key(fixture_name, name))?;
+      write.checks.push(Check {
+        key: key.clone(),
+        versionstamp: None,
+      });
+      let seeded_at = v8::to_vec(&v8::Date::from(utc_now()))?;
+      write.mutations.push(Mutation {
+        key,
+        kind: MutationKind::Set(KvValue::V8(seeded_at)),
+        expire_at: None,
+      });
+    }
+    let committed = database.atomic_write(write).await?.is_some();
+    seeded.push(SeededNamespace {
+      namespace: namespace.namespace.clone(),
+      entries: namespace.entries.len(),
+      messages: namespace.messages.len(),
+      skipped: !committed,
+    });
+  }
+  Ok(seeded)
+}
+
+/// `'<namespace>'`, or `the whole keyspace`, for messages.
+pub fn namespace_name(namespace: Option<&str>) -> String {
+  match namespace {
+    Some(namespace) => format!("'{namespace}'"),
+    None => "the whole keyspace".to_string(),
+  }
+}
+
+/// The entries and messages of `namespace`, with keys relative to it.
+fn namespace_write(
+  namespace: &FixtureNamespace,
+) -> anyhow::Result<AtomicWrite> {
+  let now = utc_now();
+  let after = |ms: u64| -> anyhow::Result<DateTime<Utc>> {
+    i64::try_from(ms)
+      .ok()
+      .and_then(|ms| now.checked_add_signed(Duration::milliseconds(ms)))
+      .with_context(|| format!("{ms} ms is too far in the future"))
+  };
+
+  let mut mutations = Vec::with_capacity(namespace.entries.len());
+  for entry in &namespace.entries {
+    let key = parse_key(&entry.key)?;
+    let value = entry_value(&entry.value)
+      .with_context(|| format!("Invalid value of {}", entry.key))?;
+    mutations.push(Mutation {
+      key,
+      kind: MutationKind::Set(value),
+      expire_at: entry.expire_in_ms.map(after).transpose()?,
+    });
+  }
+
+  let mut enqueues = Vec::with_capacity(namespace.messages.len());
+  for message in &namespace.messages {
+    enqueues.push(Enqueue {
+      payload: v8::to_vec(&JsValue(&message.value))?,
+      deadline: after(message.delay_ms)?,
+      keys_if_undelivered: message
+        .keys_if_undelivered
+        .iter()
+        .map(|key| parse_key(key))
+        .collect::<Result<_, _>>()?,
+      backoff_schedule: message.backoff_schedule.clone(),
+    });
+  }
+
+  Ok(AtomicWrite {
+    checks: vec![],
+    mutations,
+    enqueues,
+  })
+}
+
+fn entry_value(value: &FixtureValue) -> anyhow::Result<KvValue> {
+  Ok(match value {
+    FixtureValue::Value(value) => KvValue::V8(v8::to_vec(&JsValue(value))?),
+    FixtureValue::Bigint(digits) => {
+      let bigint: i128 = digits
+        .parse()
+        .with_context(|| format!("'{digits}' is not a 128-bit integer"))?;
+      KvValue::V8(v8::to_vec(&bigint)?)
+    }
+    FixtureValue::U64(value) => KvValue::U64(*value),
+    FixtureValue::Bytes(value) => KvValue::Bytes(hex::decode(value)?),
+  })
+}
+
+fn seed_key(fixture: &str, namespace: Option<&str>) -> Key {
+  let mut parts = vec![
+    KeyPart::String(SEED_KEY.to_string()),
+    KeyPart::String(fixture.to_string()),
+  ];
+  parts
+    .extend(namespace.map(|namespace| KeyPart::String(namespace.to_string())));
+  Key(parts)
+}
+
+/// A TOML value as the JavaScript value it stands for.
+struct JsValue<'a>(&'a toml::Value);
+
+impl Serialize for JsValue<'_> {
+  fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+    match self.0 {
+      toml::Value::String(value) => s.serialize_str(value),
+      toml::Value::Integer(value) => s.serialize_i64(*value),
+      toml::Value::Float(value) => s.serialize_f64(*value),
+      toml::Value::Boolean(value) => s.serialize_bool(*value),
+      toml::Value::Datetime(value) => {
+        let date =
+          DateTime::parse_from_rfc3339(&value.to_string()).map_err(|_| {
+            S::Error::custom(format!("{value} is not a date with an offset"))
+          })?;
+        v8::Date::from(date.with_timezone(&Utc)).serialize(s)
+      }
+      toml::Value::Array(items) => s.collect_seq(items.iter().map(JsValue)),
+      toml::Value::Table(table) => {
+        let properties: BTreeMap<_, _> = table
+          .iter()
+          .map(|(name, value)| (name, JsValue(value)))
+          .collect();
+        v8::Object(properties).serialize(s)
+      }
+    }
+  }
+}
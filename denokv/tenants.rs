@@ -0,0 +1,335 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! Access tokens scoped to namespaces, so that one server can be shared by
+//! several applications.
+//!
+//! The token registry is a JSON file listing tokens, each with a name, the
+//! namespace it is confined to and what it may do:
+//!
+//! ```json
+//! [
+//!   { "name": "shop", "token": "...", "namespace": "shop", "permissions": ["read", "write", "queue"] },
+//!   { "name": "reports", "token": "...", "namespace": "shop", "permissions": ["read"] },
+//!   { "name": "ops", "token": "...", "permissions": ["admin"] }
+//! ]
+//! ```
+//!
+//! A namespace is the key prefix `[namespace]`. Requests made with a token
+//! that has a namespace see only the keys under it, with the prefix
+//! stripped, so every application has what looks like a database of its
+//! own. Only admin tokens may omit the namespace and see the whole keyspace.
+//! The `--access-token` of the server is always an admin token.
+//!
+//! Namespaces with an encryption key (see the `encryption` module) have
+//! their values sealed and opened here as well.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use constant_time_eq::constant_time_eq;
+use denokv_proto::encode_key;
+use denokv_proto::AtomicWrite;
+use denokv_proto::Key;
+use denokv_proto::KeyPart;
+use denokv_proto::KvEntry;
+use denokv_proto::MutationKind;
+use denokv_proto::ReadRange;
+use denokv_proto::ReadRangeOutput;
+use denokv_proto::WatchKeyOutput;
+use serde::Deserialize;
+
+use crate::encryption::NamespaceKeys;
+use crate::encryption::ValueCipher;
+use crate::ApiError;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+  /// Snapshot reads and watches.
+  Read,
+  /// Atomic writes with checks and mutations.
+  Write,
+  /// Atomic writes that enqueue messages.
+  Queue,
+  /// Everything, optionally across all namespaces.
+  Admin,
+}
+
+/// A token as listed in the token registry.
+#[derive(Deserialize)]
+pub struct TokenEntry {
+  name: String,
+  token: String,
+  #[serde(default)]
+  namespace: Option<String>,
+  permissions: Vec<Permission>,
+}
+
+/// The identity and rights a request was authenticated with.
+pub struct Tenant {
+  /// Identifies the token in logs without revealing it.
+  pub name: String,
+  /// Encoded key prefix of the namespace, empty for the whole keyspace.
+  prefix: Vec<u8>,
+  permissions: Vec<Permission>,
+  /// The key of the namespace, if it has one.
+  cipher: Option<Arc<ValueCipher>>,
+}
+
+impl Tenant {
+  fn admin(name: &str) -> Self {
+    Self {
+      name: name.to_string(),
+      prefix: Vec::new(),
+      permissions: vec![Permission::Admin],
+      cipher: None,
+    }
+  }
+
+  /// The tenant of `namespace`, or of the whole keyspace if `None`, for
+  /// commands that write to a namespace without a token.
+  pub fn of_namespace(
+    namespace: Option<&str>,
+    keys: &NamespaceKeys,
+  ) -> anyhow::Result<Self> {
+    let Some(namespace) = namespace else {
+      return Ok(Self::admin("admin"));
+    };
+    Ok(Self {
+      name: namespace.to_string(),
+      prefix: encode_key(&Key(vec![KeyPart::String(namespace.to_string())]))?,
+      permissions: vec![Permission::Admin],
+      cipher: keys.get(namespace),
+    })
+  }
+
+  pub fn allows(&self, permission: Permission) -> bool {
+    self.permissions.contains(&permission)
+      || self.permissions.contains(&Permission::Admin)
+  }
+
+  /// The permissions `write` needs.
+  pub fn allows_write(&self, write: &AtomicWrite) -> bool {
+    (write.checks.is_empty() || self.allows(Permission::Read))
+      && (write.mutations.is_empty() || self.allows(Permission::Write))
+      && (write.enqueues.is_empty() || self.allows(Permission::Queue))
+  }
+
+  /// Encoded key prefix of the namespace, empty for the whole keyspace.
+  pub fn prefix(&self) -> &[u8] {
+    &self.prefix
+  }
+
+  fn scoped(&self, key: &[u8]) -> Vec<u8> {
+    [self.prefix.as_slice(), key].concat()
+  }
+
+  /// The blind index of `value` in `field`, or `None` if the namespace is
+  /// not encrypted.
+  pub fn blind_index(&self, field: &str, value: &[u8]) -> Option<[u8; 32]> {
+    Some(self.cipher.as_ref()?.blind_index(field, value))
+  }
+
+  /// Strip the namespace from `entry` and open its value.
+  fn unscope(&self, entry: &mut KvEntry) -> Result<(), ApiError> {
+    if let Some(cipher) = &self.cipher {
+      let key = &entry.key[self.prefix.len()..];
+      match cipher.open(key, &entry.value) {
+        Some(value) => entry.value = value,
+        // Unless it was sealed before its prefix was listed as plaintext.
+        None if cipher.is_plaintext(key) => {}
+        None => {
+          log::error!(
+            "Failed to decrypt a value read with token '{}'",
+            self.name
+          );
+          return Err(ApiError::DecryptionFailed);
+        }
+      }
+    }
+    entry.key.drain(..self.prefix.len());
+    Ok(())
+  }
+
+  /// Move `requests` into the namespace.
+  pub fn scope_reads(&self, requests: &mut [ReadRange]) {
+    if self.prefix.is_empty() {
+      return;
+    }
+    for request in requests {
+      request.end = if request.is_open_ended() {
+        prefix_upper_bound(&self.prefix)
+      } else {
+        self.scoped(&request.end)
+      };
+      request.start = self.scoped(&request.start);
+    }
+  }
+
+  /// Strip the namespace from the entries read by scoped requests.
+  pub fn unscope_reads(
+    &self,
+    outputs: &mut [ReadRangeOutput],
+  ) -> Result<(), ApiError> {
+    if self.prefix.is_empty() {
+      return Ok(());
+    }
+    for output in outputs {
+      for entry in &mut output.entries {
+        self.unscope(entry)?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Move every key `write` touches into the namespace, and seal the
+  /// values it writes.
+  pub fn scope_write(&self, write: &mut AtomicWrite) -> Result<(), ApiError> {
+    if let Some(cipher) = &self.cipher {
+      for mutation in &mut write.mutations {
+        if cipher.is_plaintext(&mutation.key) {
+          continue;
+        }
+        match &mut mutation.kind {
+          MutationKind::Set(value) => {
+            *value = cipher.seal(&mutation.key, value);
+          }
+          MutationKind::SetSuffixVersionstampedKey(value) => {
+            *value = cipher.seal_versionstamped(&mutation.key, value);
+          }
+          MutationKind::Delete => {}
+          MutationKind::Sum { .. }
+          | MutationKind::Min(_)
+          | MutationKind::Max(_) => {
+            return Err(ApiError::UnsupportedInEncryptedDatabase)
+          }
+        }
+      }
+      for enqueue in &mut write.enqueues {
+        enqueue.payload = cipher.seal_payload(&enqueue.payload);
+      }
+    }
+    if self.prefix.is_empty() {
+      return Ok(());
+    }
+    for check in &mut write.checks {
+      check.key = self.scoped(&check.key);
+    }
+    for mutation in &mut write.mutations {
+      mutation.key = self.scoped(&mutation.key);
+    }
+    for enqueue in &mut write.enqueues {
+      for key in &mut enqueue.keys_if_undelivered {
+        *key = self.scoped(key);
+      }
+    }
+    Ok(())
+  }
+
+  pub fn scope_keys(&self, keys: &mut [Vec<u8>]) {
+    for key in keys {
+      *key = self.scoped(key);
+    }
+  }
+
+  pub fn unscope_watch(
+    &self,
+    outputs: &mut [WatchKeyOutput],
+  ) -> Result<(), ApiError> {
+    for output in outputs {
+      if let WatchKeyOutput::Changed { entry: Some(entry) } = output {
+        self.unscope(entry)?;
+      }
+    }
+    Ok(())
+  }
+}
+
+/// The smallest key after every key starting with `prefix`. Namespace
+/// prefixes end in the 0x00 terminator of their string part, so there
+/// always is one.
+pub fn prefix_upper_bound(prefix: &[u8]) -> Vec<u8> {
+  let mut end = prefix.to_vec();
+  *end.last_mut().expect("namespace prefixes are not empty") += 1;
+  end
+}
+
+/// Maps bearer tokens to the tenant they authenticate.
+pub struct TokenRegistry {
+  tokens: Vec<(String, Arc<Tenant>)>,
+  keys: Arc<NamespaceKeys>,
+}
+
+impl TokenRegistry {
+  /// A registry with only the server's own access token. Tokens added
+  /// later use the encryption key of their namespace in `keys`.
+  pub fn new(access_token: &str, keys: Arc<NamespaceKeys>) -> Self {
+    Self {
+      tokens: vec![(
+        access_token.to_string(),
+        Arc::new(Tenant::admin("admin")),
+      )],
+      keys,
+    }
+  }
+
+  /// Add the tokens listed in the registry file at `path`.
+  pub fn load(&mut self, path: &Path) -> anyhow::Result<()> {
+    let file = std::fs::read(path).with_context(|| {
+      format!("Failed to read the token registry {}", path.display())
+    })?;
+    let entries: Vec<TokenEntry> = serde_json::from_slice(&file)
+      .with_context(|| format!("Invalid token registry {}", path.display()))?;
+    self.add(entries)
+  }
+
+  /// Add the tokens `entries`.
+  pub fn add(&mut self, entries: Vec<TokenEntry>) -> anyhow::Result<()> {
+    for entry in entries {
+      if entry.token.len() < 12 {
+        anyhow::bail!(
+          "The token of '{}' must be at minimum 12 chars long.",
+          entry.name
+        );
+      }
+      let prefix = match &entry.namespace {
+        Some(namespace) => {
+          encode_key(&Key(vec![KeyPart::String(namespace.clone())]))?
+        }
+        None if entry.permissions.contains(&Permission::Admin) => Vec::new(),
+        None => anyhow::bail!(
+          "The token of '{}' needs a namespace or the admin permission.",
+          entry.name
+        ),
+      };
+      let cipher = entry
+        .namespace
+        .as_deref()
+        .and_then(|namespace| self.keys.get(namespace));
+      let tenant = Tenant {
+        name: entry.name,
+        prefix,
+        permissions: entry.permissions,
+        cipher,
+      };
+      if self.tokens.iter().any(|(token, _)| *token == entry.token) {
+        anyhow::bail!("A token is listed twice in the token registry.");
+      }
+      self.tokens.push((entry.token, Arc::new(tenant)));
+    }
+    Ok(())
+  }
+
+  /// The tenant `token` belongs to. Every token is compared in constant
+  /// time, so response times do not reveal how close a guess was.
+  pub fn authenticate(&self, token: &str) -> Option<Arc<Tenant>> {
+    let mut found = None;
+    for (candidate, tenant) in &self.tokens {
+      if constant_time_eq(candidate.as_bytes(), token.as_bytes()) {
+        found = Some(tenant.clone());
+      }
+    }
+    found
+  }
+}
@@ -14,6 +14,7 @@ use denokv_proto::WatchKeyOutput;
 use denokv_remote::RemotePermissions;
 use denokv_remote::RemoteResponse;
 use denokv_remote::RemoteTransport;
+use futures::SinkExt;
 use futures::Stream;
 use futures::StreamExt;
 use futures::TryStreamExt;
@@ -97,13 +98,32 @@ fn denokv_exe() -> PathBuf {
 }
 
 async fn start_server() -> (tokio::process::Child, SocketAddr) {
+  start_server_with_args(&[]).await
+}
+
+async fn start_server_with_args(
+  serve_args: &[&str],
+) -> (tokio::process::Child, SocketAddr) {
+  let (child, listening) = spawn_server(serve_args).await;
+  let addr = listening
+    .strip_prefix("http://")
+    .expect("server listens on TCP")
+    .parse()
+    .unwrap();
+  println!("Server started and listening on {addr}");
+  (child, addr)
+}
+
+/// Start the server and return where it says it is listening.
+async fn spawn_server(serve_args: &[&str]) -> (tokio::process::Child, String) {
   let tmp_file = tempfile::NamedTempFile::new().unwrap().keep().unwrap().1;
-  let mut child = tokio::process::Command::new(denokv_exe())
-    .arg("--sqlite-path")
-    .arg(tmp_file)
-    .arg("serve")
-    .arg("--addr")
-    .arg("127.0.0.1:0")
+  let mut command = tokio::process::Command::new(denokv_exe());
+  command.arg("--sqlite-path").arg(tmp_file).arg("serve");
+  if !serve_args.contains(&"--addr") {
+    command.arg("--addr").arg("127.0.0.1:0");
+  }
+  let mut child = command
+    .args(serve_args)
     .env("DENO_KV_ACCESS_TOKEN", ACCESS_TOKEN)
     .stdout(Stdio::piped())
     .stderr(Stdio::piped())
@@ -126,15 +146,17 @@ async fn start_server() -> (tokio::process::Child, SocketAddr) {
   let stderr_buf = BufReader::new(stderr);
   let mut stderr_lines = stderr_buf.lines();
 
-  let addr = loop {
+  let listening = loop {
     let line = stderr_lines
       .next_line()
       .await
       .expect("server died")
       .expect("server died");
     eprintln!("{line}");
-    if let Some((_, addr)) = line.split_once("Listening on http://") {
-      break addr.parse().unwrap();
+    if let Some((_, listening)) = line.split_once("Listening on ") {
+      // In JSON log lines, the message ends at a quote.
+      let listening = listening.split('"').next().unwrap();
+      break listening.to_string();
     }
   };
 
@@ -145,9 +167,7 @@ async fn start_server() -> (tokio::process::Child, SocketAddr) {
     }
   });
 
-  println!("Server started and listening on {addr}");
-
-  (child, addr)
+  (child, listening)
 }
 
 #[derive(Clone)]
@@ -220,17 +240,1588 @@ async fn basics() {
     )
     .await
     .unwrap();
-  assert_eq!(ranges.len(), 1);
-  let range = &ranges[0];
-  assert_eq!(range.entries.len(), 1);
-  assert_eq!(range.entries[0].key, vec![1]);
+  assert_eq!(ranges.len(), 1);
+  let range = &ranges[0];
+  assert_eq!(range.entries.len(), 1);
+  assert_eq!(range.entries[0].key, vec![1]);
+  assert!(matches!(
+    range.entries[0].value,
+    denokv_proto::KvValue::U64(1)
+  ));
+  assert_eq!(range.entries[0].versionstamp, commit_result.versionstamp);
+
+  println!("remote");
+}
+
+#[tokio::test]
+async fn versionstamped_keys() {
+  let (_child, addr) = start_server().await;
+  let client = ReqwestClient(reqwest::Client::new());
+  let url = format!("http://localhost:{}", addr.port()).parse().unwrap();
+
+  let metadata_endpoint = denokv_remote::MetadataEndpoint {
+    url,
+    access_token: ACCESS_TOKEN.to_string(),
+  };
+
+  let remote =
+    denokv_remote::Remote::new(client, DummyPermissions, metadata_endpoint);
+
+  let prefix = denokv_proto::encode_key(&denokv_proto::Key(vec![
+    denokv_proto::KeyPart::String("log".to_string()),
+  ]))
+  .unwrap();
+  let mutation = |kind| denokv_proto::Mutation {
+    key: prefix.clone(),
+    kind,
+    expire_at: None,
+  };
+  let commit_result = remote
+    .atomic_write(AtomicWrite {
+      checks: vec![],
+      mutations: vec![
+        mutation(denokv_proto::MutationKind::SetSuffixVersionstampedKey(
+          KvValue::U64(1),
+        )),
+        mutation(denokv_proto::MutationKind::Set(KvValue::U64(0))),
+      ],
+      enqueues: vec![],
+    })
+    .await
+    .unwrap()
+    .expect("commit success");
+  assert_eq!(commit_result.versionstamped_keys.len(), 1);
+  let key = &commit_result.versionstamped_keys[0];
+  assert_eq!(
+    denokv_proto::decode_key(key).unwrap(),
+    denokv_proto::Key(vec![
+      denokv_proto::KeyPart::String("log".to_string()),
+      denokv_proto::KeyPart::String(hex::encode(commit_result.versionstamp)),
+    ])
+  );
+
+  // The key the client derived is the key the server wrote.
+  let ranges = remote
+    .snapshot_read(
+      vec![ReadRange {
+        start: key.clone(),
+        end: [&key[..], &[0]].concat(),
+        limit: NonZeroU32::try_from(1).unwrap(),
+        reverse: false,
+      }],
+      denokv_proto::SnapshotReadOptions {
+        consistency: denokv_proto::Consistency::Strong,
+      },
+    )
+    .await
+    .unwrap();
+  assert!(matches!(ranges[0].entries[0].value, KvValue::U64(1)));
+}
+
+#[tokio::test]
+async fn open_ended_ranges() {
+  let (_child, addr) = start_server().await;
+  let client = ReqwestClient(reqwest::Client::new());
+  let url = format!("http://localhost:{}", addr.port()).parse().unwrap();
+
+  let metadata_endpoint = denokv_remote::MetadataEndpoint {
+    url,
+    access_token: ACCESS_TOKEN.to_string(),
+  };
+
+  let remote =
+    denokv_remote::Remote::new(client, DummyPermissions, metadata_endpoint);
+
+  remote
+    .atomic_write(AtomicWrite {
+      checks: vec![],
+      mutations: [vec![1], vec![2, 0], vec![2, 0xfe]]
+        .into_iter()
+        .map(|key| denokv_proto::Mutation {
+          key,
+          kind: denokv_proto::MutationKind::Set(denokv_proto::KvValue::U64(1)),
+          expire_at: None,
+        })
+        .collect(),
+      enqueues: vec![],
+    })
+    .await
+    .unwrap()
+    .expect("commit success");
+
+  for end in [vec![], vec![0xff], vec![0xff, 0xff]] {
+    let ranges = remote
+      .snapshot_read(
+        vec![ReadRange {
+          start: vec![2],
+          end,
+          limit: NonZeroU32::try_from(10).unwrap(),
+          reverse: false,
+        }],
+        denokv_proto::SnapshotReadOptions {
+          consistency: denokv_proto::Consistency::Strong,
+        },
+      )
+      .await
+      .unwrap();
+    let keys: Vec<_> =
+      ranges[0].entries.iter().map(|e| e.key.clone()).collect();
+    assert_eq!(keys, vec![vec![2, 0], vec![2, 0xfe]]);
+  }
+}
+
+#[tokio::test]
+async fn read_budget_continuations() {
+  let (_child, addr) = start_server_with_args(&[
+    "--max-read-entries",
+    "3",
+    "--max-read-bytes",
+    "64",
+  ])
+  .await;
+  let client = ReqwestClient(reqwest::Client::new());
+  let url = format!("http://localhost:{}", addr.port()).parse().unwrap();
+
+  let metadata_endpoint = denokv_remote::MetadataEndpoint {
+    url,
+    access_token: ACCESS_TOKEN.to_string(),
+  };
+
+  let remote =
+    denokv_remote::Remote::new(client, DummyPermissions, metadata_endpoint);
+
+  remote
+    .atomic_write(AtomicWrite {
+      checks: vec![],
+      mutations: (0u8..8)
+        .map(|i| denokv_proto::Mutation {
+          key: vec![1, i],
+          kind: denokv_proto::MutationKind::Set(denokv_proto::KvValue::Bytes(
+            vec![i; 20],
+          )),
+          expire_at: None,
+        })
+        .collect(),
+      enqueues: vec![],
+    })
+    .await
+    .unwrap()
+    .expect("commit success");
+
+  // The server returns at most 3 entries and 64 bytes per response; the
+  // client follows the continuations to read the ranges in full.
+  let ranges = remote
+    .snapshot_read(
+      vec![
+        ReadRange {
+          start: vec![1],
+          end: vec![2],
+          limit: NonZeroU32::try_from(6).unwrap(),
+          reverse: false,
+        },
+        ReadRange {
+          start: vec![1],
+          end: vec![2],
+          limit: NonZeroU32::try_from(5).unwrap(),
+          reverse: true,
+        },
+      ],
+      denokv_proto::SnapshotReadOptions {
+        consistency: denokv_proto::Consistency::Strong,
+      },
+    )
+    .await
+    .unwrap();
+  let keys = |i: usize| -> Vec<u8> {
+    ranges[i].entries.iter().map(|e| e.key[1]).collect()
+  };
+  assert_eq!(keys(0), vec![0, 1, 2, 3, 4, 5]);
+  assert_eq!(keys(1), vec![7, 6, 5, 4, 3]);
+}
+
+#[tokio::test]
+async fn feature_negotiation() {
+  use prost::Message;
+
+  let (_child, addr) =
+    start_server_with_args(&["--max-read-entries", "1"]).await;
+  let client = reqwest::Client::new();
+  let base = format!("http://localhost:{}", addr.port());
+  let metadata = |body: serde_json::Value| {
+    client
+      .post(&base)
+      .bearer_auth(ACCESS_TOKEN)
+      .json(&body)
+      .send()
+  };
+
+  // Standard clients get the standard response.
+  let res: serde_json::Value = metadata(serde_json::json!({
+    "supportedVersions": [2, 3],
+  }))
+  .await
+  .unwrap()
+  .json()
+  .await
+  .unwrap();
+  assert!(res.get("features").is_none(), "{res}");
+
+  let res: serde_json::Value = metadata(serde_json::json!({
+    "supportedVersions": [3],
+    "features": ["read_continuations", "time_travel"],
+  }))
+  .await
+  .unwrap()
+  .json()
+  .await
+  .unwrap();
+  assert_eq!(res["features"], serde_json::json!(["read_continuations"]));
+
+  let remote = denokv_remote::Remote::new(
+    ReqwestClient(client.clone()),
+    DummyPermissions,
+    denokv_remote::MetadataEndpoint {
+      url: base.parse().unwrap(),
+      access_token: ACCESS_TOKEN.to_string(),
+    },
+  );
+  remote
+    .atomic_write(AtomicWrite {
+      checks: vec![],
+      mutations: [vec![1], vec![2]]
+        .into_iter()
+        .map(|key| denokv_proto::Mutation {
+          key,
+          kind: denokv_proto::MutationKind::Set(KvValue::U64(1)),
+          expire_at: None,
+        })
+        .collect(),
+      enqueues: vec![],
+    })
+    .await
+    .unwrap()
+    .expect("commit success");
+
+  // A read cut short by the budget fails for clients that do not follow
+  // continuations, rather than looking complete.
+  let read = |features: Option<&'static str>| {
+    let mut req = client
+      .post(format!("{base}/v2/snapshot_read"))
+      .bearer_auth(ACCESS_TOKEN)
+      .header("x-denokv-version", "3")
+      .header("x-denokv-database-id", uuid::Uuid::nil().to_string())
+      .body(
+        denokv_proto::datapath::SnapshotRead {
+          ranges: vec![denokv_proto::datapath::ReadRange {
+            start: vec![],
+            end: vec![0xff],
+            limit: 10,
+            reverse: false,
+          }],
+        }
+        .encode_to_vec(),
+      );
+    if let Some(features) = features {
+      req = req.header("x-denokv-features", features);
+    }
+    req.send()
+  };
+  assert_eq!(read(None).await.unwrap().status(), 400);
+  assert_eq!(read(Some("time_travel")).await.unwrap().status(), 400);
+  let res = read(Some("value_deltas, read_continuations"))
+    .await
+    .unwrap();
+  assert_eq!(res.status(), 200);
+  let output = denokv_proto::datapath::SnapshotReadOutput::decode(
+    res.bytes().await.unwrap(),
+  )
+  .unwrap();
+  assert_eq!(output.ranges[0].values.len(), 1);
+  assert_eq!(output.ranges[0].continuation, Some(vec![1, 0]));
+}
+
+#[tokio::test]
+async fn record_and_replay_workload() {
+  let trace = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+  let trace_path = trace.to_str().unwrap().to_string();
+  let (child, addr) =
+    start_server_with_args(&["--record-workload", &trace_path]).await;
+  let client = ReqwestClient(reqwest::Client::new());
+  let url = format!("http://localhost:{}", addr.port()).parse().unwrap();
+
+  let metadata_endpoint = denokv_remote::MetadataEndpoint {
+    url,
+    access_token: ACCESS_TOKEN.to_string(),
+  };
+
+  let remote =
+    denokv_remote::Remote::new(client, DummyPermissions, metadata_endpoint);
+
+  remote
+    .atomic_write(AtomicWrite {
+      checks: vec![],
+      mutations: vec![denokv_proto::Mutation {
+        key: b"secret-key".to_vec(),
+        kind: denokv_proto::MutationKind::Set(denokv_proto::KvValue::Bytes(
+          b"secret-value".to_vec(),
+        )),
+        expire_at: None,
+      }],
+      enqueues: vec![],
+    })
+    .await
+    .unwrap()
+    .expect("commit success");
+  remote
+    .snapshot_read(
+      vec![ReadRange {
+        start: vec![],
+        end: vec![0xff],
+        limit: NonZeroU32::try_from(10).unwrap(),
+        reverse: false,
+      }],
+      denokv_proto::SnapshotReadOptions {
+        consistency: denokv_proto::Consistency::Strong,
+      },
+    )
+    .await
+    .unwrap();
+
+  // Records are written in the background.
+  let mut recorded = String::new();
+  for _ in 0..50 {
+    recorded = std::fs::read_to_string(&trace).unwrap();
+    if recorded.lines().count() >= 2 {
+      break;
+    }
+    tokio::time::sleep(Duration::from_millis(20)).await;
+  }
+  drop(child);
+  assert_eq!(recorded.lines().count(), 2, "{recorded}");
+  assert!(!recorded.contains("secret"));
+
+  let target = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+  let output = tokio::process::Command::new(denokv_exe())
+    .arg("--sqlite-path")
+    .arg(target.to_str().unwrap())
+    .arg("replay-workload")
+    .arg("--trace")
+    .arg(&trace_path)
+    .arg("--speed")
+    .arg("100")
+    .output()
+    .await
+    .unwrap();
+  assert!(output.status.success(), "{output:?}");
+  let stdout = String::from_utf8(output.stdout).unwrap();
+  assert!(stdout.contains("read\tcount=1"), "{stdout}");
+  assert!(stdout.contains("write\tcount=1"), "{stdout}");
+}
+
+#[tokio::test]
+async fn namespaced_tokens() {
+  let registry = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+  std::fs::write(
+    &registry,
+    r#"[
+      { "name": "app", "token": "app-token-0001", "namespace": "app", "permissions": ["read", "write"] },
+      { "name": "viewer", "token": "viewer-token-01", "namespace": "app", "permissions": ["read"] }
+    ]"#,
+  )
+  .unwrap();
+  let (_child, addr) =
+    start_server_with_args(&["--token-registry", registry.to_str().unwrap()])
+      .await;
+  let url: Url = format!("http://localhost:{}", addr.port()).parse().unwrap();
+  let remote = |access_token: &str| {
+    let metadata_endpoint = denokv_remote::MetadataEndpoint {
+      url: url.clone(),
+      access_token: access_token.to_string(),
+    };
+    denokv_remote::Remote::new(
+      ReqwestClient(reqwest::Client::new()),
+      DummyPermissions,
+      metadata_endpoint,
+    )
+  };
+  let set_key_1 = || AtomicWrite {
+    checks: vec![],
+    mutations: vec![denokv_proto::Mutation {
+      key: vec![1],
+      kind: denokv_proto::MutationKind::Set(denokv_proto::KvValue::U64(1)),
+      expire_at: None,
+    }],
+    enqueues: vec![],
+  };
+  let app = remote("app-token-0001");
+  app
+    .atomic_write(set_key_1())
+    .await
+    .unwrap()
+    .expect("commit success");
+  assert_eq!(read_all_keys(&app).await, vec![vec![1]]);
+  assert_eq!(read_key_1(&app).await.key, vec![1]);
+
+  let viewer = remote("viewer-token-01");
+  assert_eq!(read_all_keys(&viewer).await, vec![vec![1]]);
+  assert!(viewer.atomic_write(set_key_1()).await.is_err());
+
+  // The server's own token sees every namespace.
+  let admin = remote(ACCESS_TOKEN);
+  assert_eq!(
+    read_all_keys(&admin).await,
+    vec![b"\x02app\x00\x01".to_vec()]
+  );
+}
+
+#[tokio::test]
+async fn encrypted_namespace() {
+  use prost::Message;
+
+  let registry = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+  std::fs::write(
+    &registry,
+    r#"[
+      { "name": "app", "token": "app-token-0001", "namespace": "app", "permissions": ["read", "write"] }
+    ]"#,
+  )
+  .unwrap();
+  let keys = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+  std::fs::write(
+    &keys,
+    r#"{ "app": { "key": "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=", "plaintext_prefixes": [["counters"]] } }"#,
+  )
+  .unwrap();
+  let (_child, addr) = start_server_with_args(&[
+    "--token-registry",
+    registry.to_str().unwrap(),
+    "--encryption-keys",
+    keys.to_str().unwrap(),
+  ])
+  .await;
+  let url: Url = format!("http://localhost:{}", addr.port()).parse().unwrap();
+  let remote = |access_token: &str| {
+    let metadata_endpoint = denokv_remote::MetadataEndpoint {
+      url: url.clone(),
+      access_token: access_token.to_string(),
+    };
+    denokv_remote::Remote::new(
+      ReqwestClient(reqwest::Client::new()),
+      DummyPermissions,
+      metadata_endpoint,
+    )
+  };
+  let write = |kind| AtomicWrite {
+    checks: vec![],
+    mutations: vec![denokv_proto::Mutation {
+      key: vec![1],
+      kind,
+      expire_at: None,
+    }],
+    enqueues: vec![],
+  };
+
+  let app = remote("app-token-0001");
+  app
+    .atomic_write(write(denokv_proto::MutationKind::Set(KvValue::Bytes(
+      b"secret".to_vec(),
+    ))))
+    .await
+    .unwrap()
+    .expect("commit success");
+  let KvValue::Bytes(value) = read_key_1(&app).await.value else {
+    panic!("expected bytes");
+  };
+  assert_eq!(value, b"secret");
+
+  // Values of versionstamped keys open at the key they end up at.
+  let log = b"\x02log\x00".to_vec();
+  let commit = app
+    .atomic_write(AtomicWrite {
+      checks: vec![],
+      mutations: vec![denokv_proto::Mutation {
+        key: log.clone(),
+        kind: denokv_proto::MutationKind::SetSuffixVersionstampedKey(
+          KvValue::Bytes(b"entry".to_vec()),
+        ),
+        expire_at: None,
+      }],
+      enqueues: vec![],
+    })
+    .await
+    .unwrap()
+    .expect("commit success");
+  let key = denokv_proto::versionstamped_key(&log, &commit.versionstamp);
+  let entry = read_key(&app, &key).await;
+  assert!(matches!(entry.value, KvValue::Bytes(value) if value == b"entry"));
+
+  // Sums need the plain value in the database.
+  assert!(app
+    .atomic_write(write(denokv_proto::MutationKind::Sum {
+      value: KvValue::U64(1),
+      min_v8: vec![],
+      max_v8: vec![],
+      clamp: false,
+    }))
+    .await
+    .is_err());
+
+  // Without the namespace, the value is only ciphertext.
+  let admin = remote(ACCESS_TOKEN);
+  let key = b"\x02app\x00\x01".to_vec();
+  let ranges = admin
+    .snapshot_read(
+      vec![ReadRange {
+        start: key.clone(),
+        end: [&key[..], &[0]].concat(),
+        limit: NonZeroU32::try_from(1).unwrap(),
+        reverse: false,
+      }],
+      denokv_proto::SnapshotReadOptions {
+        consistency: denokv_proto::Consistency::Strong,
+      },
+    )
+    .await
+    .unwrap();
+  let KvValue::Bytes(stored) = &ranges[0].entries[0].value else {
+    panic!("expected bytes");
+  };
+  assert!(!stored.windows(6).any(|window| window == b"secret"));
+
+  // A value copied to another key does not open there.
+  admin
+    .atomic_write(AtomicWrite {
+      checks: vec![],
+      mutations: vec![denokv_proto::Mutation {
+        key: b"\x02app\x00\x02".to_vec(),
+        kind: denokv_proto::MutationKind::Set(KvValue::Bytes(stored.clone())),
+        expire_at: None,
+      }],
+      enqueues: vec![],
+    })
+    .await
+    .unwrap()
+    .expect("commit success");
+  let res = reqwest::Client::new()
+    .post(format!("http://localhost:{}/v2/snapshot_read", addr.port()))
+    .bearer_auth("app-token-0001")
+    .header("x-denokv-version", "3")
+    .header("x-denokv-database-id", uuid::Uuid::nil().to_string())
+    .body(
+      denokv_proto::datapath::SnapshotRead {
+        ranges: vec![denokv_proto::datapath::ReadRange {
+          start: vec![2],
+          end: vec![3],
+          limit: 1,
+          reverse: false,
+        }],
+      }
+      .encode_to_vec(),
+    )
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(res.status(), 500);
+  assert_eq!(res.text().await.unwrap(), "A value could not be decrypted.");
+
+  // Values under plaintext prefixes are stored as they are, so they can be
+  // summed.
+  let counter = b"\x02counters\x00\x02hits\x00".to_vec();
+  let sum = AtomicWrite {
+    checks: vec![],
+    mutations: vec![denokv_proto::Mutation {
+      key: counter.clone(),
+      kind: denokv_proto::MutationKind::Sum {
+        value: KvValue::U64(5),
+        min_v8: vec![],
+        max_v8: vec![],
+        clamp: false,
+      },
+      expire_at: None,
+    }],
+    enqueues: vec![],
+  };
+  app
+    .atomic_write(sum)
+    .await
+    .unwrap()
+    .expect("commit success");
+  let entry = read_key(&app, &counter).await;
+  assert!(matches!(entry.value, KvValue::U64(5)));
+  let stored_counter = [&b"\x02app\x00"[..], &counter].concat();
+  let entry = read_key(&admin, &stored_counter).await;
+  assert!(matches!(entry.value, KvValue::U64(5)));
+
+  // Blind indexes are equal for equal values of a field only.
+  let client = reqwest::Client::new();
+  let blind_index = |token: &'static str, field: &str, value: &str| {
+    let request = client
+      .post(format!("http://localhost:{}/blind_index", addr.port()))
+      .bearer_auth(token)
+      .json(&serde_json::json!({ "field": field, "value": value }));
+    async move {
+      let response = request.send().await.unwrap();
+      let status = response.status();
+      let body = response.text().await.unwrap();
+      let index = serde_json::from_str::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|body| body["index"].as_str().map(str::to_string));
+      (status, index)
+    }
+  };
+  let (status, email) = blind_index("app-token-0001", "email", "a@b.c").await;
+  assert_eq!(status, 200);
+  let email = email.unwrap();
+  assert_eq!(email.len(), 64);
+  let (_, again) = blind_index("app-token-0001", "email", "a@b.c").await;
+  assert_eq!(again.unwrap(), email);
+  let (_, other_field) = blind_index("app-token-0001", "name", "a@b.c").await;
+  assert_ne!(other_field.unwrap(), email);
+  let (status, _) = blind_index(ACCESS_TOKEN, "email", "a@b.c").await;
+  assert_eq!(status, 400, "the admin token has no namespace key");
+}
+
+#[tokio::test]
+async fn audit_alerts() {
+  // Receives the alerts the server posts to its webhook.
+  let (alerts_tx, mut alerts) = tokio::sync::mpsc::unbounded_channel();
+  let webhook = axum::Router::new().route(
+    "/",
+    axum::routing::post(
+      move |axum::Json(alert): axum::Json<serde_json::Value>| {
+        let _ = alerts_tx.send(alert);
+        async {}
+      },
+    ),
+  );
+  let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+  let webhook_url = format!("http://{}/", listener.local_addr().unwrap());
+  tokio::spawn(
+    axum::Server::from_tcp(listener)
+      .unwrap()
+      .serve(webhook.into_make_service()),
+  );
+
+  let (_child, addr) = start_server_with_args(&[
+    "--audit",
+    "--audit-interval",
+    "1",
+    "--audit-alert-webhook",
+    &webhook_url,
+  ])
+  .await;
+  let metadata_endpoint = denokv_remote::MetadataEndpoint {
+    url: format!("http://localhost:{}", addr.port()).parse().unwrap(),
+    access_token: ACCESS_TOKEN.to_string(),
+  };
+  let remote = denokv_remote::Remote::new(
+    ReqwestClient(reqwest::Client::new()),
+    DummyPermissions,
+    metadata_endpoint,
+  );
+
+  remote
+    .atomic_write(AtomicWrite {
+      checks: vec![],
+      mutations: vec![denokv_proto::Mutation {
+        key: vec![1],
+        kind: denokv_proto::MutationKind::Set(KvValue::U64(1)),
+        expire_at: None,
+      }],
+      enqueues: vec![],
+    })
+    .await
+    .unwrap()
+    .expect("commit success");
+  read_all_keys(&remote).await;
+
+  let alert = tokio::time::timeout(Duration::from_secs(5), alerts.recv())
+    .await
+    .expect("no alert received")
+    .unwrap();
+  assert_eq!(alert["token"], "admin");
+  assert_eq!(alert["kind"], "full_scan");
+
+  // The write and the scan may land in different summaries.
+  let (mut writes, mut full_scans) = (0, 0);
+  for _ in 0..50 {
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let ranges = remote
+      .snapshot_read(
+        vec![ReadRange {
+          start: b"\x02__audit\x00".to_vec(),
+          end: b"\x02__audit\x00\xff".to_vec(),
+          limit: NonZeroU32::try_from(100).unwrap(),
+          reverse: false,
+        }],
+        denokv_proto::SnapshotReadOptions {
+          consistency: denokv_proto::Consistency::Strong,
+        },
+      )
+      .await
+      .unwrap();
+    (writes, full_scans) = (0, 0);
+    for entry in &ranges[0].entries {
+      assert!(entry.key.ends_with(b"\x02admin\x00"));
+      let KvValue::Bytes(summary) = &entry.value else {
+        panic!("audit summaries are JSON bytes");
+      };
+      let summary: serde_json::Value = serde_json::from_slice(summary).unwrap();
+      writes += summary["writes"].as_u64().unwrap();
+      full_scans += summary["full_scans"].as_u64().unwrap();
+    }
+    if writes >= 1 && full_scans >= 1 {
+      break;
+    }
+  }
+  assert_eq!((writes, full_scans), (1, 1));
+}
+
+#[tokio::test]
+async fn namespace_quotas() {
+  // Receives the warnings the server posts to its webhook.
+  let (warnings_tx, mut warnings) = tokio::sync::mpsc::unbounded_channel();
+  let webhook = axum::Router::new().route(
+    "/",
+    axum::routing::post(
+      move |axum::Json(warning): axum::Json<serde_json::Value>| {
+        let _ = warnings_tx.send(warning);
+        async {}
+      },
+    ),
+  );
+  let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+  let webhook_url = format!("http://{}/", listener.local_addr().unwrap());
+  tokio::spawn(
+    axum::Server::from_tcp(listener)
+      .unwrap()
+      .serve(webhook.into_make_service()),
+  );
+
+  let registry = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+  std::fs::write(
+    &registry,
+    r#"[
+      { "name": "app", "token": "app-token-0001", "namespace": "app", "permissions": ["read", "write"] }
+    ]"#,
+  )
+  .unwrap();
+  let quotas = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+  std::fs::write(&quotas, r#"{ "app": { "max_keys": 4 } }"#).unwrap();
+  let (_child, addr) = start_server_with_args(&[
+    "--token-registry",
+    registry.to_str().unwrap(),
+    "--namespace-quotas",
+    quotas.to_str().unwrap(),
+    "--quota-check-interval",
+    "1",
+    "--quota-warning-thresholds",
+    "0.5,0.75",
+    "--quota-warning-webhook",
+    &webhook_url,
+  ])
+  .await;
+  let metadata_endpoint = denokv_remote::MetadataEndpoint {
+    url: format!("http://localhost:{}", addr.port()).parse().unwrap(),
+    access_token: "app-token-0001".to_string(),
+  };
+  let app = denokv_remote::Remote::new(
+    ReqwestClient(reqwest::Client::new()),
+    DummyPermissions,
+    metadata_endpoint,
+  );
+  let write = |key: u8, kind| AtomicWrite {
+    checks: vec![],
+    mutations: vec![denokv_proto::Mutation {
+      key: vec![key],
+      kind,
+      expire_at: None,
+    }],
+    enqueues: vec![],
+  };
+  let set = |key| write(key, denokv_proto::MutationKind::Set(KvValue::U64(1)));
+  for key in 0..3 {
+    app
+      .atomic_write(set(key))
+      .await
+      .unwrap()
+      .expect("commit success");
+  }
+  // Crossing both thresholds at once warns about the higher one.
+  let warning = tokio::time::timeout(Duration::from_secs(5), warnings.recv())
+    .await
+    .expect("no warning received")
+    .unwrap();
+  assert_eq!(warning["namespace"], "app");
+  assert_eq!(warning["resource"], "keys");
+  assert_eq!(warning["used"], 3);
+  assert_eq!(warning["limit"], 4);
+  assert_eq!(warning["threshold"], 0.75);
+
+  app
+    .atomic_write(set(3))
+    .await
+    .unwrap()
+    .expect("commit success");
+  // Writes fail once a check finds the namespace at its quota, but deletes
+  // still go through.
+  let mut rejected = false;
+  for _ in 0..50 {
+    if app.atomic_write(set(4)).await.is_err() {
+      rejected = true;
+      break;
+    }
+    app
+      .atomic_write(write(4, denokv_proto::MutationKind::Delete))
+      .await
+      .unwrap()
+      .expect("commit success");
+    tokio::time::sleep(Duration::from_millis(100)).await;
+  }
+  assert!(rejected);
+  for key in 0..3 {
+    app
+      .atomic_write(write(key, denokv_proto::MutationKind::Delete))
+      .await
+      .unwrap()
+      .expect("commit success");
+  }
+  let mut accepted = false;
+  for _ in 0..50 {
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    if app.atomic_write(set(0)).await.is_ok() {
+      accepted = true;
+      break;
+    }
+  }
+  assert!(accepted);
+  assert!(warnings.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn namespace_modes() {
+  let registry = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+  std::fs::write(
+    &registry,
+    r#"[
+      { "name": "app", "token": "app-token-0001", "namespace": "app", "permissions": ["read", "write"] }
+    ]"#,
+  )
+  .unwrap();
+  let (_child, addr) =
+    start_server_with_args(&["--token-registry", registry.to_str().unwrap()])
+      .await;
+  let metadata_endpoint = denokv_remote::MetadataEndpoint {
+    url: format!("http://localhost:{}", addr.port()).parse().unwrap(),
+    access_token: "app-token-0001".to_string(),
+  };
+  let app = denokv_remote::Remote::new(
+    ReqwestClient(reqwest::Client::new()),
+    DummyPermissions,
+    metadata_endpoint,
+  );
+  let set_key_1 = || AtomicWrite {
+    checks: vec![],
+    mutations: vec![denokv_proto::Mutation {
+      key: vec![1],
+      kind: denokv_proto::MutationKind::Set(KvValue::U64(1)),
+      expire_at: None,
+    }],
+    enqueues: vec![],
+  };
+  let client = reqwest::Client::new();
+  let set_mode = |token: &str, mode: serde_json::Value| {
+    client
+      .post(format!("http://localhost:{}/namespace_modes", addr.port()))
+      .bearer_auth(token)
+      .json(&mode)
+      .send()
+  };
+
+  app
+    .atomic_write(set_key_1())
+    .await
+    .unwrap()
+    .expect("commit success");
+
+  // Only admins may change modes.
+  let res = set_mode(
+    "app-token-0001",
+    serde_json::json!({ "namespace": "app", "mode": "frozen" }),
+  )
+  .await
+  .unwrap();
+  assert_eq!(res.status(), 403);
+
+  let res = set_mode(
+    ACCESS_TOKEN,
+    serde_json::json!({ "namespace": "app", "mode": "read_only", "reason": "migrating" }),
+  )
+  .await
+  .unwrap();
+  assert_eq!(res.status(), 200);
+  assert_eq!(read_key_1(&app).await.key, vec![1]);
+  let err = app.atomic_write(set_key_1()).await.unwrap_err();
+  assert!(err.to_string().contains("read only: migrating"), "{err}");
+
+  set_mode(
+    ACCESS_TOKEN,
+    serde_json::json!({ "namespace": "app", "mode": "frozen" }),
+  )
+  .await
+  .unwrap();
+  let read = app
+    .snapshot_read(
+      vec![ReadRange {
+        start: vec![],
+        end: vec![0xff],
+        limit: NonZeroU32::try_from(1).unwrap(),
+        reverse: false,
+      }],
+      denokv_proto::SnapshotReadOptions {
+        consistency: denokv_proto::Consistency::Strong,
+      },
+    )
+    .await;
+  assert!(read.is_err());
+
+  // The mode is stored under a reserved key, visible to admins.
+  let modes: serde_json::Value = client
+    .get(format!("http://localhost:{}/namespace_modes", addr.port()))
+    .bearer_auth(ACCESS_TOKEN)
+    .send()
+    .await
+    .unwrap()
+    .json()
+    .await
+    .unwrap();
+  assert_eq!(
+    modes,
+    serde_json::json!([{ "namespace": "app", "mode": "frozen" }])
+  );
+
+  set_mode(
+    ACCESS_TOKEN,
+    serde_json::json!({ "namespace": "app", "mode": "active" }),
+  )
+  .await
+  .unwrap();
+  app
+    .atomic_write(set_key_1())
+    .await
+    .unwrap()
+    .expect("commit success");
+}
+
+#[tokio::test]
+async fn typed_client() {
+  use denokv_proto::Key;
+  use denokv_proto::KeyPart;
+
+  let (_child, addr) =
+    start_server_with_args(&["--max-read-entries", "2"]).await;
+  let client = denokv_remote::KvClient::connect(
+    format!("http://localhost:{}", addr.port()).parse().unwrap(),
+    ACCESS_TOKEN.to_string(),
+  );
+  let user = |name: &str| {
+    Key(vec![
+      KeyPart::String("users".to_string()),
+      KeyPart::String(name.to_string()),
+    ])
+  };
+  let users = Key(vec![KeyPart::String("users".to_string())]);
+
+  for name in ["a", "b", "c", "d", "e"] {
+    client
+      .set(&user(name), KvValue::Bytes(name.as_bytes().to_vec()))
+      .await
+      .unwrap();
+  }
+  client.delete(&user("e")).await.unwrap();
+
+  let entry = client.get(&user("a")).await.unwrap().unwrap();
+  assert_eq!(entry.key, user("a"));
+  assert!(matches!(entry.value, KvValue::Bytes(ref v) if v == b"a"));
+  let entries = client.get_many(&[user("e"), user("b")]).await.unwrap();
+  assert!(entries[0].is_none());
+  assert_eq!(entries[1].as_ref().unwrap().key, user("b"));
+
+  // Pages larger than the server's read budget are filled by following
+  // continuations.
+  let options = denokv_remote::ListOptions {
+    limit: NonZeroU32::new(3).unwrap(),
+    ..Default::default()
+  };
+  let page = client.list(&users, None, &options).await.unwrap();
+  assert_eq!(page.entries.len(), 3);
+  let cursor: denokv_remote::Cursor =
+    page.cursor.unwrap().to_string().parse().unwrap();
+  let page = client.list(&users, Some(&cursor), &options).await.unwrap();
+  let keys: Vec<_> = page.entries.into_iter().map(|e| e.key).collect();
+  assert_eq!(keys, vec![user("d")]);
+  assert!(page.cursor.is_none());
+
+  assert_eq!(client.count(&users).await.unwrap(), 4);
+  let reversed = denokv_remote::ListOptions {
+    limit: NonZeroU32::new(1).unwrap(),
+    reverse: true,
+    ..Default::default()
+  };
+  let keys: Vec<_> = client
+    .list_all(users.clone(), reversed)
+    .map_ok(|entry| entry.key)
+    .try_collect()
+    .await
+    .unwrap();
+  assert_eq!(keys, vec![user("d"), user("c"), user("b"), user("a")]);
+
+  let mut watch = Box::pin(client.watch(&[user("a"), user("e")]).unwrap());
+  let current = watch.next().await.unwrap().unwrap();
+  assert!(current[0].is_some() && current[1].is_none());
+  client.set(&user("e"), KvValue::U64(5)).await.unwrap();
+  let current = watch.next().await.unwrap().unwrap();
+  assert!(current[0].is_some());
+  assert!(matches!(
+    current[1].as_ref().unwrap().value,
+    KvValue::U64(5)
+  ));
+}
+
+#[tokio::test]
+async fn typed_entities() {
+  use denokv_proto::Key;
+  use denokv_proto::KeyPart;
+  use denokv_remote::KvEntity;
+
+  #[derive(
+    Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize, KvEntity,
+  )]
+  #[kv(key = ["users", id, "profile"])]
+  struct Profile {
+    id: u64,
+    name: String,
+  }
+
+  #[derive(serde::Serialize, serde::Deserialize, KvEntity)]
+  #[kv(key = ["settings", 1, user], codec = "json")]
+  struct Settings {
+    user: String,
+    dark: bool,
+  }
+
+  let (_child, addr) = start_server().await;
+  let client = denokv_remote::KvClient::connect(
+    format!("http://localhost:{}", addr.port()).parse().unwrap(),
+    ACCESS_TOKEN.to_string(),
+  );
+
+  let key = Profile::key_of(&7);
+  assert_eq!(
+    key,
+    Key(vec![
+      KeyPart::String("users".to_string()),
+      KeyPart::Int(7.into()),
+      KeyPart::String("profile".to_string()),
+    ])
+  );
+  assert_eq!(
+    Profile::prefix(),
+    Key(vec![KeyPart::String("users".to_string())])
+  );
+  assert!(client.load::<Profile>(&key).await.unwrap().is_none());
+
+  let profile = Profile {
+    id: 7,
+    name: "Ada".to_string(),
+  };
+  let versionstamp = client.create(&profile).await.unwrap().unwrap();
+  assert!(client.create(&profile).await.unwrap().is_none());
+
+  let mut loaded = client.load::<Profile>(&key).await.unwrap().unwrap();
+  assert_eq!(loaded.value, profile);
+  assert_eq!(loaded.versionstamp, versionstamp);
+  // Stored in the V8 format, for JavaScript clients.
+  let entry = client.get(&key).await.unwrap().unwrap();
+  let KvValue::V8(bytes) = entry.value else {
+    panic!("expected a V8 value");
+  };
+  ValueDeserializer::default().read(&bytes).unwrap();
+
+  // A concurrent change makes the loaded versionstamp stale.
+  let mut stale = loaded.clone();
+  loaded.value.name = "Ada L.".to_string();
+  let versionstamp = client.update_if_unchanged(&loaded).await.unwrap();
+  assert!(versionstamp.is_some());
+  stale.value.name = "Grace".to_string();
+  assert!(client.update_if_unchanged(&stale).await.unwrap().is_none());
+  let loaded = client.load::<Profile>(&key).await.unwrap().unwrap();
+  assert_eq!(loaded.value.name, "Ada L.");
+  assert_eq!(Some(loaded.versionstamp), versionstamp);
+
+  let settings = Settings {
+    user: "ada".to_string(),
+    dark: true,
+  };
+  client.save(&settings).await.unwrap();
+  let entry = client.get(&settings.key()).await.unwrap().unwrap();
+  assert!(matches!(entry.value, KvValue::Bytes(ref v) if v.starts_with(b"{")));
+  let loaded = client
+    .load::<Settings>(&Settings::key_of(&"ada".to_string()))
+    .await
+    .unwrap()
+    .unwrap();
+  assert!(loaded.value.dark);
+  assert!(matches!(
+    client.load::<Profile>(&settings.key()).await,
+    Err(denokv_remote::ClientError::InvalidValue(_))
+  ));
+}
+
+#[tokio::test]
+async fn counters() {
+  use denokv_proto::Key;
+  use denokv_proto::KeyPart;
+
+  let (_child, addr) = start_server().await;
+  let client = denokv_remote::KvClient::connect(
+    format!("http://localhost:{}", addr.port()).parse().unwrap(),
+    ACCESS_TOKEN.to_string(),
+  );
+  let key = |name: &str| Key(vec![KeyPart::String(name.to_string())]);
+
+  let visits = client.counter(key("visits"));
+  assert_eq!(visits.read().await.unwrap(), 0);
+  visits.increment(5).await.unwrap();
+  visits.decrement(2).await.unwrap();
+  assert_eq!(visits.read().await.unwrap(), 3);
+  let entry = client.get(&key("visits")).await.unwrap().unwrap();
+  assert!(matches!(entry.value, KvValue::U64(3)));
+
+  // Increments land on random shards and reads add them all up.
+  let shards = NonZeroU32::new(4).unwrap();
+  let likes = client.sharded_counter(key("likes"), shards);
+  let increments = (0..40).map(|_| likes.increment(1));
+  futures::future::try_join_all(increments).await.unwrap();
+  likes.decrement(10).await.unwrap();
+  assert_eq!(likes.read().await.unwrap(), 30);
+  assert!(client.count(&key("likes")).await.unwrap() > 1);
+
+  client
+    .set(&key("name"), KvValue::Bytes(b"ada".to_vec()))
+    .await
+    .unwrap();
+  assert!(matches!(
+    client.counter(key("name")).read().await,
+    Err(denokv_remote::ClientError::InvalidValue(_))
+  ));
+}
+
+#[tokio::test]
+async fn sorted_sets() {
+  use denokv_proto::Key;
+  use denokv_proto::KeyPart;
+
+  let (_child, addr) = start_server().await;
+  let client = denokv_remote::KvClient::connect(
+    format!("http://localhost:{}", addr.port()).parse().unwrap(),
+    ACCESS_TOKEN.to_string(),
+  );
+  let scores = client.sorted_set(Key(vec![
+    KeyPart::String("games".to_string()),
+    KeyPart::String("scores".to_string()),
+  ]));
+
+  scores.add("ada", 30.0).await.unwrap();
+  scores.add("bob", -2.5).await.unwrap();
+  scores.add("cy", 100.0).await.unwrap();
+  scores.add("dee", 30.0).await.unwrap();
+  scores.add("eve", 0.0).await.unwrap();
+  // Moving a member drops its old position.
+  scores.add("cy", 10.0).await.unwrap();
+  assert_eq!(scores.increment("bob", 50.0).await.unwrap(), 47.5);
+  assert_eq!(scores.increment("fay", -1.0).await.unwrap(), -1.0);
+  assert!(scores.remove("eve").await.unwrap());
+  assert!(!scores.remove("eve").await.unwrap());
+
+  let named = |entries: &[(&str, f64)]| {
+    entries
+      .iter()
+      .map(|(member, score)| (member.to_string(), *score))
+      .collect::<Vec<_>>()
+  };
+  assert_eq!(
+    scores.top(3).await.unwrap(),
+    named(&[("bob", 47.5), ("dee", 30.0), ("ada", 30.0)])
+  );
+  assert_eq!(
+    scores.bottom(10).await.unwrap(),
+    named(&[
+      ("fay", -1.0),
+      ("cy", 10.0),
+      ("ada", 30.0),
+      ("dee", 30.0),
+      ("bob", 47.5)
+    ])
+  );
+  assert_eq!(scores.score("cy").await.unwrap(), Some(10.0));
+  assert_eq!(scores.score("eve").await.unwrap(), None);
+  assert_eq!(scores.rank("bob").await.unwrap(), Some(0));
+  assert_eq!(scores.rank("ada").await.unwrap(), Some(2));
+  assert_eq!(scores.rank("fay").await.unwrap(), Some(4));
+  assert_eq!(scores.rank("eve").await.unwrap(), None);
+  assert_eq!(scores.count().await.unwrap(), 5);
+
+  // Concurrent increments of one member are all applied.
+  let increments = (0..10).map(|_| scores.increment("ada", 1.0));
+  futures::future::try_join_all(increments).await.unwrap();
+  assert_eq!(scores.score("ada").await.unwrap(), Some(40.0));
+  assert_eq!(scores.count().await.unwrap(), 5);
+}
+
+#[tokio::test]
+async fn rate_limits() {
+  let registry = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+  std::fs::write(
+    &registry,
+    r#"[
+      { "name": "app", "token": "app-token-0001", "namespace": "app", "permissions": ["read", "write"] },
+      { "name": "viewer", "token": "viewer-token-01", "namespace": "app", "permissions": ["read"] }
+    ]"#,
+  )
+  .unwrap();
+  let (_child, addr) =
+    start_server_with_args(&["--token-registry", registry.to_str().unwrap()])
+      .await;
+  let url = format!("http://localhost:{}/rate_limit", addr.port());
+  let http = reqwest::Client::new();
+  let check = |token: &'static str, cost: u64| {
+    http
+      .post(&url)
+      .bearer_auth(token)
+      .json(&serde_json::json!({
+        "key": r#"["login", "1.2.3.4"]"#,
+        "limit": 3,
+        "window_ms": 60_000,
+        "cost": cost,
+      }))
+      .send()
+  };
+
+  for remaining in [2, 1, 0] {
+    let response = check("app-token-0001", 1).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+      body,
+      serde_json::json!({ "allowed": true, "remaining": remaining })
+    );
+  }
+  let response = check("app-token-0001", 1).await.unwrap();
+  assert_eq!(response.status(), 429);
+  let retry_after: u64 = response.headers()["retry-after"]
+    .to_str()
+    .unwrap()
+    .parse()
+    .unwrap();
+  assert!((1..=120).contains(&retry_after));
+  let body: serde_json::Value = response.json().await.unwrap();
+  assert_eq!(body["allowed"], false);
+  // More than the limit is never allowed.
+  let response = check("app-token-0001", 4).await.unwrap();
+  assert_eq!(response.status(), 429);
+  assert!(response.headers().get("retry-after").is_none());
+
+  assert_eq!(check("viewer-token-01", 1).await.unwrap().status(), 403);
+  let response = http
+    .post(&url)
+    .bearer_auth("app-token-0001")
+    .json(&serde_json::json!({ "key": "login", "limit": 3, "window_ms": 1 }))
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(response.status(), 400);
+
+  // The counts live in the namespace of the token, where Rust embedders
+  // can share them.
+  let app = denokv_remote::Remote::new(
+    ReqwestClient(reqwest::Client::new()),
+    DummyPermissions,
+    denokv_remote::MetadataEndpoint {
+      url: format!("http://localhost:{}", addr.port()).parse().unwrap(),
+      access_token: "app-token-0001".to_string(),
+    },
+  );
+  let limiter = denokv_proto::RateLimiter::new(
+    app,
+    denokv_proto::RateLimit {
+      limit: 3,
+      window: Duration::from_secs(60),
+    },
+  );
+  let key = denokv_proto::parse_key(r#"["login", "1.2.3.4"]"#).unwrap();
+  assert!(!limiter.check(&key, 1).await.unwrap().allowed);
+  let key = denokv_proto::parse_key(r#"["login", "5.6.7.8"]"#).unwrap();
+  let outcome = limiter.check(&key, 2).await.unwrap();
+  assert!(outcome.allowed);
+  assert_eq!(outcome.remaining, 1);
+}
+
+#[tokio::test]
+async fn sessions() {
+  use denokv_proto::Key;
+  use denokv_proto::KeyPart;
+  use denokv_remote::Session;
+  use tower::Layer;
+  use tower::ServiceExt;
+
+  #[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+  struct User {
+    name: String,
+  }
+
+  let (_child, addr) = start_server().await;
+  let client = denokv_remote::KvClient::connect(
+    format!("http://localhost:{}", addr.port()).parse().unwrap(),
+    ACCESS_TOKEN.to_string(),
+  );
+  let prefix = Key(vec![KeyPart::String("sessions".to_string())]);
+  let store = client.session_store(prefix.clone(), Duration::from_secs(3600));
+
+  let ada = User {
+    name: "ada".to_string(),
+  };
+  let mut session = store.create(ada.clone()).await.unwrap();
+  assert_eq!(session.id.len(), 64);
+  let other = store.create(ada.clone()).await.unwrap();
+  assert_ne!(session.id, other.id);
+  let loaded = store.get::<User>(&session.id).await.unwrap().unwrap();
+  assert_eq!(loaded.data, ada);
+  assert!(store.get::<User>("not-an-id").await.unwrap().is_none());
+  let entry = client
+    .get(&Key(vec![
+      KeyPart::String("sessions".to_string()),
+      KeyPart::String(session.id.clone()),
+    ]))
+    .await
+    .unwrap()
+    .unwrap();
+  assert!(matches!(entry.value, KvValue::Bytes(_)));
+
+  // Refreshing makes copies read before it stale.
+  let refreshed = store.refresh::<User>(&session.id).await.unwrap().unwrap();
+  assert!(refreshed.expires_at >= session.expires_at);
+  session.data.name = "ada l.".to_string();
+  assert!(!store.save(&mut session).await.unwrap());
+  let mut session = refreshed;
+  session.data.name = "ada l.".to_string();
+  assert!(store.save(&mut session).await.unwrap());
+  let loaded = store.get::<User>(&session.id).await.unwrap().unwrap();
+  assert_eq!(loaded.data.name, "ada l.");
+
+  // The middleware hands live sessions to handlers and renews the cookie.
+  let handler = tower::service_fn(|req: http::Request<()>| async move {
+    let name = req
+      .extensions()
+      .get::<Session<User>>()
+      .map(|session| session.data.name.clone());
+    Ok::<_, std::convert::Infallible>(http::Response::new(name))
+  });
+  let service = denokv_remote::SessionLayer::<_, _, User>::new(store.clone())
+    .cookie_name("sid")
+    .layer(handler);
+  let request = |cookie: &str| {
+    http::Request::builder()
+      .header("cookie", cookie)
+      .body(())
+      .unwrap()
+  };
+  let res = service
+    .clone()
+    .oneshot(request(&format!("theme=dark; sid={}", session.id)))
+    .await
+    .unwrap();
+  assert_eq!(res.body().as_deref(), Some("ada l."));
+  let cookie = res.headers()["set-cookie"].to_str().unwrap();
+  assert!(
+    cookie.starts_with(&format!("sid={};", session.id)),
+    "{cookie}"
+  );
+  assert!(cookie.contains("HttpOnly"));
+
+  store.destroy(&session.id).await.unwrap();
+  assert!(store.get::<User>(&session.id).await.unwrap().is_none());
+  let res = service
+    .oneshot(request(&format!("sid={}", session.id)))
+    .await
+    .unwrap();
+  assert_eq!(res.body(), &None);
+  assert!(res.headers().get("set-cookie").is_none());
+  assert!(denokv_remote::expired_session_cookie("sid").contains("Max-Age=0"));
+
+  // Sessions are gone once their time to live is over.
+  let short = client.session_store(prefix, Duration::from_millis(500));
+  let session = short.create(ada).await.unwrap();
+  tokio::time::sleep(Duration::from_millis(600)).await;
+  assert!(short.get::<User>(&session.id).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn compute_cache() {
+  use std::sync::atomic::AtomicUsize;
+  use std::sync::atomic::Ordering;
+
+  use denokv_proto::Key;
+  use denokv_proto::KeyPart;
+
+  let (_child, addr) = start_server().await;
+  let client = denokv_remote::KvClient::connect(
+    format!("http://localhost:{}", addr.port()).parse().unwrap(),
+    ACCESS_TOKEN.to_string(),
+  );
+  let cache = client.compute_cache(Some(Duration::from_secs(60)));
+  let key = |name: &str| {
+    Key(vec![
+      KeyPart::String("report".to_string()),
+      KeyPart::String(name.to_string()),
+    ])
+  };
+  let computed = AtomicUsize::new(0);
+  let slow_report = || async {
+    computed.fetch_add(1, Ordering::SeqCst);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    Ok::<_, String>(KvValue::U64(42))
+  };
+
+  // Concurrent misses share one computation.
+  let daily = key("daily");
+  let callers = (0..10).map(|_| cache.get_or_compute(&daily, slow_report));
+  let values = futures::future::try_join_all(callers).await.unwrap();
+  assert!(values.iter().all(|value| matches!(value, KvValue::U64(42))));
+  assert_eq!(computed.load(Ordering::SeqCst), 1);
+  let entry = client.get(&key("daily")).await.unwrap().unwrap();
+  assert!(matches!(entry.value, KvValue::U64(42)));
+  cache
+    .get_or_compute(&key("daily"), slow_report)
+    .await
+    .unwrap();
+  assert_eq!(computed.load(Ordering::SeqCst), 1);
+
+  // Only the missing keys of a batch are computed, in one call.
+  let keys = [key("daily"), key("weekly"), key("monthly"), key("weekly")];
+  let values = cache
+    .get_or_compute_many(&keys, |missing| {
+      assert_eq!(missing, vec![key("weekly"), key("monthly")]);
+      async move {
+        let values = missing.iter().map(|key| match &key.0[1] {
+          KeyPart::String(name) => KvValue::Bytes(name.as_bytes().to_vec()),
+          _ => unreachable!(),
+        });
+        Ok::<_, String>(values.collect())
+      }
+    })
+    .await
+    .unwrap();
+  assert!(matches!(values[0], KvValue::U64(42)));
+  assert!(matches!(&values[1], KvValue::Bytes(name) if name == b"weekly"));
+  assert!(matches!(&values[2], KvValue::Bytes(name) if name == b"monthly"));
+  assert!(matches!(&values[3], KvValue::Bytes(name) if name == b"weekly"));
+
+  // Failures are not cached.
+  let failed = cache
+    .get_or_compute(&key("yearly"), || async {
+      Err::<KvValue, _>("upstream timed out")
+    })
+    .await;
   assert!(matches!(
-    range.entries[0].value,
-    denokv_proto::KvValue::U64(1)
+    failed,
+    Err(denokv_remote::ClientError::Compute(message)) if message == "upstream timed out"
   ));
-  assert_eq!(range.entries[0].versionstamp, commit_result.versionstamp);
+  assert!(client.get(&key("yearly")).await.unwrap().is_none());
+}
 
-  println!("remote");
+#[tokio::test]
+async fn feature_flags() {
+  use denokv_remote::Flag;
+
+  #[derive(Debug, PartialEq, serde::Deserialize)]
+  struct Checkout {
+    layout: String,
+  }
+
+  let (_child, addr) = start_server().await;
+  let connect = || {
+    denokv_remote::KvClient::connect(
+      format!("http://localhost:{}", addr.port()).parse().unwrap(),
+      ACCESS_TOKEN.to_string(),
+    )
+  };
+  let admin = connect().flags().await.unwrap();
+  let service = connect().flags().await.unwrap();
+  assert!(!service.is_enabled("beta", Some("ada")));
+
+  let beta = Flag {
+    subjects: vec!["ada".to_string()],
+    rollout: 0,
+    ..Flag::on()
+  };
+  admin.set("beta", beta.clone()).await.unwrap();
+  assert!(admin.is_enabled("beta", Some("ada")));
+  // Other processes pick changes up through the watch.
+  let mut reloaded = false;
+  for _ in 0..100 {
+    reloaded = service.get("beta").is_some();
+    if reloaded {
+      break;
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+  }
+  assert!(reloaded);
+  assert_eq!(service.get("beta"), Some(beta));
+  assert!(service.is_enabled("beta", Some("ada")));
+  assert!(!service.is_enabled("beta", Some("bob")));
+
+  // Rollouts pick a stable share of subjects.
+  let checkout = Flag {
+    rollout: 25,
+    value: Some(serde_json::json!({ "layout": "compact" })),
+    ..Flag::on()
+  };
+  admin.set("checkout", checkout).await.unwrap();
+  let users: Vec<String> = (0..2000).map(|i| format!("user-{i}")).collect();
+  let on: Vec<&String> = users
+    .iter()
+    .filter(|user| admin.is_enabled("checkout", Some(user)))
+    .collect();
+  assert!((400..600).contains(&on.len()), "{} of 2000", on.len());
+  service.reload().await.unwrap();
+  assert!(on
+    .iter()
+    .all(|user| service.is_enabled("checkout", Some(user))));
+  assert!(!service.is_enabled("checkout", None));
+  assert_eq!(
+    service.value::<Checkout>("checkout", Some(on[0])).unwrap(),
+    Some(Checkout {
+      layout: "compact".to_string()
+    })
+  );
+  let off = users
+    .iter()
+    .find(|user| !service.is_enabled("checkout", Some(user)))
+    .unwrap();
+  assert_eq!(
+    service.value::<Checkout>("checkout", Some(off)).unwrap(),
+    None
+  );
+
+  admin.delete("beta").await.unwrap();
+  for _ in 0..100 {
+    if service.get("beta").is_none() {
+      break;
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+  }
+  assert!(!service.is_enabled("beta", Some("ada")));
 }
 
 #[tokio::test]
@@ -291,6 +1882,416 @@ async fn watch() {
   assert_eq!(entry.key, vec![1]);
 }
 
+type WatchSocket = tokio_tungstenite::WebSocketStream<
+  tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+>;
+
+/// Open a WebSocket watch of `key`.
+async fn open_watch_websocket(addr: SocketAddr, key: Vec<u8>) -> WatchSocket {
+  use prost::Message as _;
+  use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+  use tokio_tungstenite::tungstenite::Message;
+
+  let mut request = format!("ws://localhost:{}/v2/watch_ws", addr.port())
+    .into_client_request()
+    .unwrap();
+  let headers = request.headers_mut();
+  headers.insert(
+    "authorization",
+    format!("Bearer {ACCESS_TOKEN}").parse().unwrap(),
+  );
+  headers.insert("x-denokv-version", "3".parse().unwrap());
+  headers.insert(
+    "x-denokv-database-id",
+    uuid::Uuid::nil().to_string().parse().unwrap(),
+  );
+  let mut socket = tokio_tungstenite::connect_async(request).await.unwrap().0;
+  let req = denokv_proto::datapath::Watch {
+    keys: vec![denokv_proto::datapath::WatchKey { key }],
+    accept_value_deltas: false,
+  };
+  socket
+    .send(Message::Binary(req.encode_to_vec()))
+    .await
+    .unwrap();
+  socket
+}
+
+#[tokio::test]
+async fn watch_over_websocket() {
+  use prost::Message as _;
+  use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+  use tokio_tungstenite::tungstenite::Message;
+
+  let (_child, addr) = start_server().await;
+  let mut socket = open_watch_websocket(addr, vec![1]).await;
+  let Some(Ok(Message::Binary(data))) = socket.next().await else {
+    panic!("expected a watch output");
+  };
+  let output = denokv_proto::datapath::WatchOutput::decode(&data[..]).unwrap();
+  assert!(output.keys[0].changed);
+  assert!(output.keys[0].entry_if_changed.is_none());
+
+  let mut socket = open_watch_websocket(addr, vec![0; 4096]).await;
+  let Some(Ok(Message::Close(Some(frame)))) = socket.next().await else {
+    panic!("expected the watch to be rejected");
+  };
+  assert_eq!(frame.code, CloseCode::Policy);
+
+  // The remote client resumes a watch opened over a WebSocket like a
+  // streamed one.
+  let remote = denokv_remote::Remote::new(
+    ReqwestClient(reqwest::Client::new()),
+    DummyPermissions,
+    denokv_remote::MetadataEndpoint {
+      url: format!("http://localhost:{}", addr.port()).parse().unwrap(),
+      access_token: ACCESS_TOKEN.to_string(),
+    },
+  )
+  .with_websocket_watch();
+  let mut outputs = remote.watch(vec![vec![1]]);
+  let first = outputs.next().await.unwrap().unwrap();
+  assert!(matches!(first[0], WatchKeyOutput::Changed { entry: None }));
+  remote
+    .atomic_write(AtomicWrite {
+      checks: vec![],
+      mutations: vec![denokv_proto::Mutation {
+        key: vec![1],
+        kind: denokv_proto::MutationKind::Set(KvValue::U64(7)),
+        expire_at: None,
+      }],
+      enqueues: vec![],
+    })
+    .await
+    .unwrap()
+    .expect("commit success");
+  let next = outputs.next().await.unwrap().unwrap();
+  let WatchKeyOutput::Changed { entry: Some(entry) } = &next[0] else {
+    panic!("expected the new value");
+  };
+  assert!(matches!(entry.value, KvValue::U64(7)));
+}
+
+/// Exchange metadata over a fresh HTTP connection on `stream`.
+async fn exchange_metadata_over<S>(
+  stream: S,
+  http2: bool,
+) -> hyper::Result<serde_json::Value>
+where
+  S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+  let (mut sender, connection) = hyper::client::conn::Builder::new()
+    .http2_only(http2)
+    .handshake(stream)
+    .await?;
+  tokio::spawn(connection);
+  let request = hyper::Request::post("http://localhost/")
+    .header("authorization", format!("Bearer {ACCESS_TOKEN}"))
+    .header("content-type", "application/json")
+    .body(hyper::Body::from(r#"{"supportedVersions":[2,3]}"#))
+    .unwrap();
+  let response = sender.send_request(request).await?;
+  assert_eq!(response.status(), hyper::StatusCode::OK);
+  let body = hyper::body::to_bytes(response.into_body()).await?;
+  Ok(serde_json::from_slice(&body).unwrap())
+}
+
+#[tokio::test]
+async fn http2_only() {
+  let (_child, addr) = start_server_with_args(&["--http2-only"]).await;
+
+  let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+  let metadata = exchange_metadata_over(stream, true).await.unwrap();
+  assert_eq!(metadata["version"], 3);
+
+  let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+  assert!(exchange_metadata_over(stream, false).await.is_err());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn unix_socket() {
+  let dir = tempfile::tempdir().unwrap();
+  let path = dir.path().join("denokv.sock");
+  let (_child, listening) =
+    spawn_server(&["--unix-socket", path.to_str().unwrap()]).await;
+  assert_eq!(listening, format!("unix:{}", path.display()));
+
+  for http2 in [false, true] {
+    let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+    let metadata = exchange_metadata_over(stream, http2).await.unwrap();
+    assert_eq!(metadata["version"], 3);
+  }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn graceful_restart() {
+  use prost::Message as _;
+  use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+  use tokio_tungstenite::tungstenite::Message;
+
+  let (mut old, addr) = start_server_with_args(&["--reuse-port"]).await;
+  let base = format!("http://localhost:{}", addr.port());
+  let client = reqwest::Client::new();
+
+  let response = client
+    .post(format!("{base}/v2/watch"))
+    .bearer_auth(ACCESS_TOKEN)
+    .header("x-denokv-version", "3")
+    .header("x-denokv-database-id", uuid::Uuid::nil().to_string())
+    .body(
+      denokv_proto::datapath::Watch {
+        keys: vec![denokv_proto::datapath::WatchKey { key: vec![1] }],
+        accept_value_deltas: false,
+      }
+      .encode_to_vec(),
+    )
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(response.status(), 200);
+  let mut streamed = response.bytes_stream();
+  streamed.next().await.unwrap().unwrap();
+  let mut socket = open_watch_websocket(addr, vec![1]).await;
+  socket.next().await.unwrap().unwrap();
+
+  // The replacement binds the same port while the old server still runs.
+  let addr_arg = addr.to_string();
+  let (_new, new_addr) =
+    start_server_with_args(&["--reuse-port", "--addr", &addr_arg]).await;
+  assert_eq!(new_addr, addr);
+
+  let status = tokio::process::Command::new("kill")
+    .arg("-TERM")
+    .arg(old.id().unwrap().to_string())
+    .status()
+    .await
+    .unwrap();
+  assert!(status.success());
+
+  // Watches end cleanly, telling clients to reconnect.
+  while let Some(chunk) = streamed.next().await {
+    chunk.unwrap();
+  }
+  let close = loop {
+    match socket.next().await.unwrap().unwrap() {
+      Message::Close(close) => break close.unwrap(),
+      _ => continue,
+    }
+  };
+  assert_eq!(close.code, CloseCode::Away);
+  let status = tokio::time::timeout(Duration::from_secs(10), old.wait())
+    .await
+    .expect("old server drained")
+    .unwrap();
+  assert!(status.success());
+
+  let response = client
+    .post(&base)
+    .bearer_auth(ACCESS_TOKEN)
+    .json(&serde_json::json!({ "supportedVersions": [2, 3] }))
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(response.status(), 200);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn config_file_reload() {
+  let dir = tempfile::tempdir().unwrap();
+  let path = dir.path().join("denokv.toml");
+  let config = |token: &str| {
+    format!(
+      r#"
+log_level = "info"
+
+[serve]
+max_read_entries = 100
+
+[[serve.tokens]]
+name = "app"
+token = "{token}"
+namespace = "app"
+permissions = ["read"]
+"#
+    )
+  };
+  std::fs::write(&path, config("first-app-token")).unwrap();
+  let (child, addr) =
+    start_server_with_args(&["--config", path.to_str().unwrap()]).await;
+
+  let client = reqwest::Client::new();
+  let exchange_metadata = |token: &'static str| {
+    client
+      .post(format!("http://localhost:{}", addr.port()))
+      .bearer_auth(token)
+      .json(&serde_json::json!({ "supportedVersions": [2, 3] }))
+      .send()
+  };
+  assert_eq!(
+    exchange_metadata("first-app-token").await.unwrap().status(),
+    200
+  );
+  assert_eq!(
+    exchange_metadata("second-app-token")
+      .await
+      .unwrap()
+      .status(),
+    401
+  );
+
+  std::fs::write(&path, config("second-app-token")).unwrap();
+  let status = tokio::process::Command::new("kill")
+    .arg("-HUP")
+    .arg(child.id().unwrap().to_string())
+    .status()
+    .await
+    .unwrap();
+  assert!(status.success());
+
+  let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+  while exchange_metadata("second-app-token")
+    .await
+    .unwrap()
+    .status()
+    != 200
+  {
+    assert!(
+      tokio::time::Instant::now() < deadline,
+      "tokens not reloaded"
+    );
+    tokio::time::sleep(Duration::from_millis(50)).await;
+  }
+  assert_eq!(
+    exchange_metadata("first-app-token").await.unwrap().status(),
+    401
+  );
+  assert_eq!(exchange_metadata(ACCESS_TOKEN).await.unwrap().status(), 200);
+}
+
+#[tokio::test]
+async fn health_checks() {
+  let health_addr = std::net::TcpListener::bind("127.0.0.1:0")
+    .unwrap()
+    .local_addr()
+    .unwrap()
+    .to_string();
+  let (_child, _addr) = start_server_with_args(&[
+    "--health-listen",
+    &health_addr,
+    "--log-format",
+    "json",
+  ])
+  .await;
+
+  let client = reqwest::Client::new();
+  let response = client
+    .get(format!("http://{health_addr}/healthz"))
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(response.status(), 200);
+  assert_eq!(response.text().await.unwrap(), "ok");
+
+  let response = client
+    .get(format!("http://{health_addr}/readyz"))
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(response.status(), 200);
+  let body: serde_json::Value = response.json().await.unwrap();
+  assert_eq!(body["ready"], true);
+  assert_eq!(body["database"]["healthy"], true);
+}
+
+#[tokio::test]
+async fn prometheus_metrics() {
+  let (_child, addr) = start_server().await;
+  let client = ReqwestClient(reqwest::Client::new());
+  let url = format!("http://localhost:{}", addr.port()).parse().unwrap();
+  let metadata_endpoint = denokv_remote::MetadataEndpoint {
+    url,
+    access_token: ACCESS_TOKEN.to_string(),
+  };
+  let remote =
+    denokv_remote::Remote::new(client, DummyPermissions, metadata_endpoint);
+  remote
+    .atomic_write(AtomicWrite {
+      checks: vec![],
+      mutations: vec![denokv_proto::Mutation {
+        key: vec![1],
+        kind: denokv_proto::MutationKind::Set(KvValue::U64(1)),
+        expire_at: None,
+      }],
+      enqueues: vec![],
+    })
+    .await
+    .unwrap()
+    .expect("commit success");
+
+  let client = reqwest::Client::new();
+  let metrics_url = format!("http://localhost:{}/metrics", addr.port());
+  let response = client.get(&metrics_url).send().await.unwrap();
+  assert_eq!(response.status(), 401);
+
+  let metrics = client
+    .get(&metrics_url)
+    .bearer_auth(ACCESS_TOKEN)
+    .send()
+    .await
+    .unwrap()
+    .text()
+    .await
+    .unwrap();
+  assert!(
+    metrics.contains(
+      r#"denokv_http_requests_total{endpoint="/v2/atomic_write",status="200"} 1"#
+    ),
+    "{metrics}"
+  );
+  assert!(
+    metrics.contains("denokv_auth_failures_total 1"),
+    "{metrics}"
+  );
+}
+
+#[tokio::test]
+async fn openapi_document() {
+  let (_child, addr) = start_server().await;
+  let url = format!("http://localhost:{}/openapi.json", addr.port());
+  let response = reqwest::get(&url).await.unwrap();
+  assert_eq!(response.status(), 200);
+  let document: serde_json::Value = response.json().await.unwrap();
+
+  let paths = document["paths"].as_object().unwrap();
+  for path in [
+    "/",
+    "/metrics",
+    "/index_advice",
+    "/rate_limit",
+    "/namespace_modes",
+    "/blind_index",
+  ] {
+    assert!(paths.contains_key(path), "{path} is missing");
+  }
+  assert_eq!(
+    document["paths"]["/namespace_modes"]["post"]["operationId"],
+    "set_namespace_mode"
+  );
+  let schemas = &document["components"]["schemas"];
+  assert_eq!(
+    schemas["Mode"]["enum"],
+    serde_json::json!(["active", "read_only", "frozen"])
+  );
+  assert!(schemas["DatabaseMetadata"]["properties"]["expiresAt"].is_object());
+  assert_eq!(
+    document["components"]["securitySchemes"]["bearer"]["scheme"],
+    "bearer"
+  );
+}
+
 #[tokio::test]
 async fn no_auth() {
   let (_child, addr) = start_server().await;
@@ -725,14 +2726,183 @@ async fn sum_values() {
   ));
 }
 
+#[tokio::test]
+async fn open_database_from_url() {
+  let (_child, addr) = start_server().await;
+  std::env::set_var(denokv_open::ACCESS_TOKEN_ENV, ACCESS_TOKEN);
+  let sqlite_file = tempfile::NamedTempFile::new().unwrap();
+  let sqlite_url = format!("sqlite://{}", sqlite_file.path().display());
+  let urls = [
+    format!("http://localhost:{}", addr.port()),
+    sqlite_url.clone(),
+    "sqlite://:memory:".to_string(),
+  ];
+
+  for url in &urls {
+    let db = denokv_open::open_database(url).await.unwrap();
+    let write = AtomicWrite {
+      checks: vec![],
+      mutations: vec![denokv_proto::Mutation {
+        key: vec![1],
+        kind: denokv_proto::MutationKind::Set(KvValue::Bytes(
+          url.clone().into(),
+        )),
+        expire_at: None,
+      }],
+      enqueues: vec![],
+    };
+    db.atomic_write(write).await.unwrap().expect("write failed");
+    let ranges = db
+      .snapshot_read(
+        vec![ReadRange {
+          start: vec![1],
+          end: vec![2],
+          limit: NonZeroU32::try_from(1).unwrap(),
+          reverse: false,
+        }],
+        denokv_proto::SnapshotReadOptions {
+          consistency: denokv_proto::Consistency::Strong,
+        },
+      )
+      .await
+      .unwrap();
+    let KvValue::Bytes(value) = &ranges[0].entries[0].value else {
+      panic!("expected bytes");
+    };
+    assert_eq!(value, url.as_bytes());
+    db.close();
+  }
+
+  // The file is an ordinary SQLite database of Deno KV.
+  let sqlite = denokv_open::open_database(&sqlite_url).await.unwrap();
+  let ranges = sqlite
+    .snapshot_read(
+      vec![ReadRange {
+        start: vec![],
+        end: vec![0xff],
+        limit: NonZeroU32::try_from(10).unwrap(),
+        reverse: false,
+      }],
+      denokv_proto::SnapshotReadOptions {
+        consistency: denokv_proto::Consistency::Strong,
+      },
+    )
+    .await
+    .unwrap();
+  assert_eq!(ranges[0].entries.len(), 1);
+  sqlite.close();
+
+  assert!(denokv_open::open_database("mysql://localhost/kv")
+    .await
+    .is_err());
+  assert!(denokv_open::open_database("kv.sqlite3").await.is_err());
+}
+
+#[tokio::test]
+async fn seed_fixture() {
+  let fixture = tempfile::Builder::new().suffix(".toml").tempfile().unwrap();
+  std::fs::write(
+    fixture.path(),
+    r#"
+      name = "demo"
+
+      [[namespaces]]
+      namespace = "shop"
+
+      [[namespaces.entries]]
+      key = '["users", 1.0]'
+      value = { name = "Alice", joined = 2024-01-01T00:00:00Z }
+
+      [[namespaces.entries]]
+      key = '["visits"]'
+      u64 = 3
+      expire_in_ms = 3600000
+
+      [[namespaces.messages]]
+      value = { type = "welcome" }
+    "#,
+  )
+  .unwrap();
+  let sqlite_file = tempfile::NamedTempFile::new().unwrap();
+  let seed = || {
+    tokio::process::Command::new(denokv_exe())
+      .arg("--sqlite-path")
+      .arg(sqlite_file.path())
+      .arg("seed")
+      .arg("--idempotent")
+      .arg(fixture.path())
+      .output()
+  };
+  let output = seed().await.unwrap();
+  assert!(output.status.success(), "{output:?}");
+  let stdout = String::from_utf8(output.stdout).unwrap();
+  assert!(
+    stdout.contains("Seeded 'shop' with 2 entry(s) and 1 message(s)"),
+    "{stdout}"
+  );
+  let output = seed().await.unwrap();
+  assert!(output.status.success(), "{output:?}");
+  let stdout = String::from_utf8(output.stdout).unwrap();
+  assert!(stdout.contains("Skipped 'shop'"), "{stdout}");
+
+  let sqlite_url = format!("sqlite://{}", sqlite_file.path().display());
+  let sqlite = denokv_open::open_database(&sqlite_url).await.unwrap();
+  let prefix = denokv_proto::encode_key(&denokv_proto::Key(vec![
+    denokv_proto::KeyPart::String("shop".to_string()),
+  ]))
+  .unwrap();
+  let ranges = sqlite
+    .snapshot_read(
+      vec![ReadRange {
+        start: prefix.clone(),
+        end: [&prefix[..], &[0xff]].concat(),
+        limit: NonZeroU32::try_from(10).unwrap(),
+        reverse: false,
+      }],
+      denokv_proto::SnapshotReadOptions {
+        consistency: denokv_proto::Consistency::Strong,
+      },
+    )
+    .await
+    .unwrap();
+  sqlite.close();
+  let entries = &ranges[0].entries;
+  assert_eq!(entries.len(), 2);
+  let KvValue::V8(user) = &entries[0].value else {
+    panic!("expected a V8 value");
+  };
+  // An object rather than a `Map`.
+  assert_eq!(user[2], b'o');
+  #[derive(serde::Deserialize, Debug, PartialEq)]
+  struct User {
+    name: String,
+    joined: denokv_proto::v8::Date,
+  }
+  assert_eq!(
+    denokv_proto::v8::from_slice::<User>(user).unwrap(),
+    User {
+      name: "Alice".to_string(),
+      joined: denokv_proto::v8::Date(1704067200000.0),
+    }
+  );
+  assert!(matches!(entries[1].value, KvValue::U64(3)));
+}
+
 async fn read_key_1<P: RemotePermissions, T: RemoteTransport>(
   remote: &denokv_remote::Remote<P, T>,
+) -> denokv_proto::KvEntry {
+  read_key(remote, &[1]).await
+}
+
+async fn read_key<P: RemotePermissions, T: RemoteTransport>(
+  remote: &denokv_remote::Remote<P, T>,
+  key: &[u8],
 ) -> denokv_proto::KvEntry {
   let ranges = remote
     .snapshot_read(
       vec![ReadRange {
-        start: vec![1],
-        end: vec![2],
+        start: key.to_vec(),
+        end: [key, &[0]].concat(),
         limit: NonZeroU32::try_from(1).unwrap(),
         reverse: false,
       }],
@@ -745,6 +2915,26 @@ async fn read_key_1<P: RemotePermissions, T: RemoteTransport>(
   assert_eq!(ranges.len(), 1);
   let range = ranges.into_iter().next().unwrap();
   assert_eq!(range.entries.len(), 1);
-  assert_eq!(range.entries[0].key, vec![1]);
+  assert_eq!(range.entries[0].key, key);
   range.entries.into_iter().next().unwrap()
 }
+
+async fn read_all_keys<P: RemotePermissions, T: RemoteTransport>(
+  remote: &denokv_remote::Remote<P, T>,
+) -> Vec<Vec<u8>> {
+  let ranges = remote
+    .snapshot_read(
+      vec![ReadRange {
+        start: vec![],
+        end: vec![0xff],
+        limit: NonZeroU32::try_from(10).unwrap(),
+        reverse: false,
+      }],
+      denokv_proto::SnapshotReadOptions {
+        consistency: denokv_proto::Consistency::Strong,
+      },
+    )
+    .await
+    .unwrap();
+  ranges[0].entries.iter().map(|e| e.key.clone()).collect()
+}
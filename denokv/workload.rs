@@ -0,0 +1,370 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! Capture and replay of anonymized workloads.
+//!
+//! The recorder writes one JSON line per datapath operation: when it
+//! started, how long it took, and its shape — key hashes, limits, value and
+//! payload sizes — but no key or value contents. Keys are hashed with a
+//! secret chosen per recording and never written out, so a trace shows which
+//! operations touched the same key without revealing the key, even to
+//! someone hashing candidate keys. The replayer turns a trace back into
+//! operations on synthetic keys and values of the recorded sizes and runs
+//! them against any backend at the recorded pace, or faster.
+
+use std::collections::BTreeMap;
+use std::hash::BuildHasher;
+use std::hash::RandomState;
+use std::io::BufRead;
+use std::io::BufWriter;
+use std::io::Write;
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use denokv_proto::encode_key;
+use denokv_proto::AtomicWrite;
+use denokv_proto::Enqueue;
+use denokv_proto::Key;
+use denokv_proto::KeyPart;
+use denokv_proto::KvValue;
+use denokv_proto::Mutation;
+use denokv_proto::MutationKind;
+use denokv_proto::ReadRange;
+use denokv_proto::ReadRangeOutput;
+use denokv_proto::SnapshotReadOptions;
+use log::warn;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::DatabaseBackend;
+
+/// Records waiting to be written beyond this are dropped rather than
+/// slowing down requests.
+const RECORDER_BACKLOG: usize = 10_000;
+
+#[derive(Serialize, Deserialize)]
+pub struct TraceRecord {
+  /// Milliseconds since the recording started.
+  pub at_ms: u64,
+  pub duration_us: u64,
+  #[serde(flatten)]
+  pub op: TraceOp,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum TraceOp {
+  Read {
+    ranges: Vec<TraceRange>,
+  },
+  Write {
+    checks: Vec<u64>,
+    mutations: Vec<TraceMutation>,
+    enqueues: Vec<TraceEnqueue>,
+  },
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TraceRange {
+  pub start: u64,
+  pub limit: u32,
+  /// Entries the read actually returned.
+  pub returned: u32,
+  pub reverse: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TraceMutation {
+  pub key: u64,
+  pub kind: TraceMutationKind,
+  pub value_size: u32,
+  pub expires: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceMutationKind {
+  Set,
+  Delete,
+  Sum,
+  Min,
+  Max,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TraceEnqueue {
+  pub payload_size: u32,
+  pub delay_ms: u64,
+}
+
+/// Appends trace records to a file from a background thread.
+pub struct WorkloadRecorder {
+  started: Instant,
+  /// SipHash with random keys, so that key hashes can not be reversed by
+  /// hashing candidate keys.
+  hasher: RandomState,
+  records: mpsc::SyncSender<TraceRecord>,
+  dropped: AtomicU64,
+}
+
+impl WorkloadRecorder {
+  pub fn open(path: &Path) -> anyhow::Result<Self> {
+    let mut file = BufWriter::new(std::fs::File::create(path)?);
+    let (records, receiver) =
+      mpsc::sync_channel::<TraceRecord>(RECORDER_BACKLOG);
+    std::thread::spawn(move || {
+      while let Ok(record) = receiver.recv() {
+        let mut result = write_record(&mut file, &record);
+        for record in receiver.try_iter() {
+          result = result.and_then(|()| write_record(&mut file, &record));
+        }
+        if let Err(e) = result.and_then(|()| Ok(file.flush()?)) {
+          warn!("Stopped recording the workload: {e}");
+          return;
+        }
+      }
+    });
+    Ok(Self {
+      started: Instant::now(),
+      hasher: RandomState::new(),
+      records,
+      dropped: AtomicU64::new(0),
+    })
+  }
+
+  pub fn record_read(
+    &self,
+    started: Instant,
+    requests: &[ReadRange],
+    outputs: &[ReadRangeOutput],
+  ) {
+    let ranges = requests
+      .iter()
+      .zip(outputs)
+      .map(|(request, output)| TraceRange {
+        start: self.hash_key(&request.start),
+        limit: request.limit.get(),
+        returned: output.entries.len() as u32,
+        reverse: request.reverse,
+      })
+      .collect();
+    self.record(started, TraceOp::Read { ranges });
+  }
+
+  /// The shape of `write`, to be recorded once it has finished.
+  pub fn trace_write(&self, write: &AtomicWrite) -> TraceOp {
+    let now = denokv_proto::time::utc_now();
+    TraceOp::Write {
+      checks: write.checks.iter().map(|c| self.hash_key(&c.key)).collect(),
+      mutations: write
+        .mutations
+        .iter()
+        .map(|mutation| TraceMutation {
+          key: self.hash_key(&mutation.key),
+          kind: match mutation.kind {
+            MutationKind::Delete => TraceMutationKind::Delete,
+            MutationKind::Sum { .. } => TraceMutationKind::Sum,
+            MutationKind::Min(_) => TraceMutationKind::Min,
+            MutationKind::Max(_) => TraceMutationKind::Max,
+            MutationKind::Set(_)
+            | MutationKind::SetSuffixVersionstampedKey(_) => {
+              TraceMutationKind::Set
+            }
+          },
+          value_size: mutation.kind.value().map_or(0, |value| {
+            denokv_proto::encode_value(value).0.len() as u32
+          }),
+          expires: mutation.expire_at.is_some(),
+        })
+        .collect(),
+      enqueues: write
+        .enqueues
+        .iter()
+        .map(|enqueue| TraceEnqueue {
+          payload_size: enqueue.payload.len() as u32,
+          delay_ms: (enqueue.deadline - now).num_milliseconds().max(0) as u64,
+        })
+        .collect(),
+    }
+  }
+
+  pub fn record(&self, started: Instant, op: TraceOp) {
+    let record = TraceRecord {
+      at_ms: started.duration_since(self.started).as_millis() as u64,
+      duration_us: started.elapsed().as_micros() as u64,
+      op,
+    };
+    if self.records.try_send(record).is_err()
+      && self.dropped.fetch_add(1, Ordering::Relaxed) == 0
+    {
+      warn!("Workload recorder is falling behind; dropping records");
+    }
+  }
+
+  fn hash_key(&self, key: &[u8]) -> u64 {
+    self.hasher.hash_one(key)
+  }
+}
+
+fn write_record(
+  file: &mut impl Write,
+  record: &TraceRecord,
+) -> anyhow::Result<()> {
+  serde_json::to_writer(&mut *file, record)?;
+  file.write_all(b"\n")?;
+  Ok(())
+}
+
+/// Latencies of replayed operations, by operation type.
+#[derive(Default)]
+pub struct ReplayReport {
+  pub latencies: BTreeMap<&'static str, Vec<Duration>>,
+  pub errors: u64,
+}
+
+/// Replay the trace at `path` against `database`, `speed` times as fast as
+/// it was recorded. Operations start at their recorded offsets regardless of
+/// whether earlier ones have finished, like the original clients did.
+pub async fn replay(
+  database: DatabaseBackend,
+  path: &Path,
+  speed: f64,
+) -> anyhow::Result<ReplayReport> {
+  let file = std::io::BufReader::new(std::fs::File::open(path)?);
+  let report = Arc::new(std::sync::Mutex::new(ReplayReport::default()));
+  let start = tokio::time::Instant::now();
+  let mut tasks = tokio::task::JoinSet::new();
+
+  for line in file.lines() {
+    let line = line?;
+    if line.is_empty() {
+      continue;
+    }
+    let record: TraceRecord = serde_json::from_str(&line)?;
+    let at = Duration::from_secs_f64(record.at_ms as f64 / 1000.0 / speed);
+    tokio::time::sleep_until(start + at).await;
+
+    let database = database.clone();
+    let report = report.clone();
+    tasks.spawn(async move {
+      let started = Instant::now();
+      let (kind, result) = match record.op {
+        TraceOp::Read { ranges } => {
+          let requests = ranges.iter().map(replay_range).collect();
+          let options = SnapshotReadOptions {
+            consistency: denokv_proto::Consistency::Strong,
+          };
+          (
+            "read",
+            database.snapshot_read(requests, options).await.map(drop),
+          )
+        }
+        TraceOp::Write {
+          checks,
+          mutations,
+          enqueues,
+        } => {
+          // Recorded checks can not be reproduced meaningfully, but they are
+          // part of the write's cost, so they are replayed against a key
+          // that is expected to be absent.
+          let write = AtomicWrite {
+            checks: checks
+              .iter()
+              .map(|key| denokv_proto::Check {
+                key: replay_key(*key ^ u64::MAX),
+                versionstamp: None,
+              })
+              .collect(),
+            mutations: mutations.iter().map(replay_mutation).collect(),
+            enqueues: enqueues.iter().map(replay_enqueue).collect(),
+          };
+          ("write", database.atomic_write(write).await.map(drop))
+        }
+      };
+      let mut report = report.lock().unwrap();
+      match result {
+        Ok(()) => report
+          .latencies
+          .entry(kind)
+          .or_default()
+          .push(started.elapsed()),
+        Err(_) => report.errors += 1,
+      }
+    });
+  }
+  while tasks.join_next().await.is_some() {}
+
+  let report = std::mem::take(&mut *report.lock().unwrap());
+  Ok(report)
+}
+
+/// The key replayed operations use for the recorded key hash `hash`.
+fn replay_key(hash: u64) -> Vec<u8> {
+  encode_key(&Key(vec![
+    KeyPart::String("__replay".to_string()),
+    KeyPart::Bytes(hash.to_be_bytes().to_vec()),
+  ]))
+  .expect("replay keys always encode")
+}
+
+fn replay_range(range: &TraceRange) -> ReadRange {
+  let mut end =
+    encode_key(&Key(vec![KeyPart::String("__replay".to_string())])).unwrap();
+  end.push(0xff);
+  ReadRange {
+    start: replay_key(range.start),
+    end,
+    // Reading as many entries as the original read returned reproduces its
+    // cost even though the synthetic keyspace differs.
+    limit: NonZeroU32::new(range.returned.min(range.limit))
+      .unwrap_or(NonZeroU32::MIN),
+    reverse: false,
+  }
+}
+
+fn replay_mutation(mutation: &TraceMutation) -> Mutation {
+  let counter = KvValue::U64(1);
+  let kind = match mutation.kind {
+    TraceMutationKind::Set => {
+      MutationKind::Set(KvValue::Bytes(vec![0; mutation.value_size as usize]))
+    }
+    TraceMutationKind::Delete => MutationKind::Delete,
+    TraceMutationKind::Sum => MutationKind::Sum {
+      value: counter,
+      min_v8: vec![],
+      max_v8: vec![],
+      clamp: false,
+    },
+    TraceMutationKind::Min => MutationKind::Min(counter),
+    TraceMutationKind::Max => MutationKind::Max(counter),
+  };
+  Mutation {
+    // Counter mutations need a key of their own so that they do not trip
+    // over a byte value set at the same key.
+    key: match mutation.kind {
+      TraceMutationKind::Set | TraceMutationKind::Delete => {
+        replay_key(mutation.key)
+      }
+      _ => replay_key(mutation.key.rotate_left(1)),
+    },
+    kind,
+    expire_at: mutation
+      .expires
+      .then(|| denokv_proto::time::utc_now() + chrono::Duration::hours(1)),
+  }
+}
+
+fn replay_enqueue(enqueue: &TraceEnqueue) -> Enqueue {
+  Enqueue {
+    payload: vec![0; enqueue.payload_size as usize],
+    deadline: denokv_proto::time::utc_now()
+      + chrono::Duration::milliseconds(enqueue.delay_ms as i64),
+    keys_if_undelivered: vec![],
+    backoff_schedule: None,
+  }
+}
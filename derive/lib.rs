@@ -0,0 +1,188 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! `#[derive(KvEntity)]`, re-exported by `denokv_remote` with its `entity`
+//! feature. See the `KvEntity` trait there.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::parse_macro_input;
+use syn::spanned::Spanned;
+use syn::Data;
+use syn::DeriveInput;
+use syn::Expr;
+use syn::ExprArray;
+use syn::Fields;
+use syn::Lit;
+use syn::LitStr;
+
+/// Store a struct under a key built from a pattern of constants and its
+/// own fields:
+///
+/// ```ignore
+/// #[derive(Serialize, Deserialize, KvEntity)]
+/// #[kv(key = ["users", id, "profile"])]
+/// struct Profile {
+///   id: u64,
+///   name: String,
+/// }
+/// ```
+///
+/// String and integer literals in the pattern are stored as they are, and
+/// field names are replaced by the value of the field. `#[kv(codec =
+/// "json")]` stores values as JSON instead of in the V8 format.
+///
+/// Besides `KvEntity`, the struct gets a `key_of` function that builds the
+/// key from the key fields, to load an entity by.
+#[proc_macro_derive(KvEntity, attributes(kv))]
+pub fn derive_kv_entity(input: TokenStream) -> TokenStream {
+  let input = parse_macro_input!(input as DeriveInput);
+  expand(input)
+    .unwrap_or_else(syn::Error::into_compile_error)
+    .into()
+}
+
+enum Part<'a> {
+  Constant(TokenStream2),
+  Field(&'a syn::Ident, &'a syn::Type),
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+  let Data::Struct(data) = &input.data else {
+    return Err(syn::Error::new(
+      input.span(),
+      "KvEntity can only be derived for structs",
+    ));
+  };
+  let Fields::Named(fields) = &data.fields else {
+    return Err(syn::Error::new(
+      input.span(),
+      "KvEntity needs a struct with named fields",
+    ));
+  };
+
+  let mut pattern = None;
+  let mut codec = None;
+  for attr in input.attrs.iter().filter(|attr| attr.path().is_ident("kv")) {
+    attr.parse_nested_meta(|meta| {
+      if meta.path.is_ident("key") {
+        pattern = Some(meta.value()?.parse::<ExprArray>()?);
+        Ok(())
+      } else if meta.path.is_ident("codec") {
+        let name = meta.value()?.parse::<LitStr>()?;
+        codec = Some(match name.value().as_str() {
+          "v8" => quote!(V8),
+          "json" => quote!(Json),
+          _ => {
+            return Err(syn::Error::new(
+              name.span(),
+              "the codec must be \"v8\" or \"json\"",
+            ))
+          }
+        });
+        Ok(())
+      } else {
+        Err(meta.error("expected `key` or `codec`"))
+      }
+    })?;
+  }
+  let Some(pattern) = pattern else {
+    return Err(syn::Error::new(
+      input.span(),
+      "KvEntity needs a key pattern, like #[kv(key = [\"users\", id])]",
+    ));
+  };
+
+  let private = quote!(::denokv_remote::__private);
+  let mut parts = Vec::new();
+  for element in &pattern.elems {
+    let part = match element {
+      Expr::Lit(lit) => match &lit.lit {
+        Lit::Str(s) => Part::Constant(
+          quote!(#private::KeyPart::String(::std::string::String::from(#s))),
+        ),
+        Lit::Int(n) => {
+          let n = proc_macro2::Literal::i128_suffixed(n.base10_parse()?);
+          Part::Constant(
+            quote!(#private::KeyPart::Int(::std::convert::From::from(#n))),
+          )
+        }
+        _ => {
+          return Err(syn::Error::new(
+            lit.span(),
+            "only string and integer literals can be part of a key",
+          ))
+        }
+      },
+      Expr::Path(path) if path.path.get_ident().is_some() => {
+        let ident = path.path.get_ident().unwrap();
+        let field = fields
+          .named
+          .iter()
+          .find(|field| field.ident.as_ref() == Some(ident))
+          .ok_or_else(|| {
+            syn::Error::new(ident.span(), format!("no field named `{ident}`"))
+          })?;
+        Part::Field(ident, &field.ty)
+      }
+      _ => {
+        return Err(syn::Error::new(
+          element.span(),
+          "expected a literal or a field name",
+        ))
+      }
+    };
+    parts.push(part);
+  }
+
+  let prefix = parts.iter().map_while(|part| match part {
+    Part::Constant(part) => Some(part),
+    Part::Field(..) => None,
+  });
+  let key_parts = parts.iter().map(|part| match part {
+    Part::Constant(part) => part.clone(),
+    Part::Field(ident, _) => {
+      quote!(::denokv_remote::ToKeyPart::to_key_part(#ident))
+    }
+  });
+  let params = parts.iter().filter_map(|part| match part {
+    Part::Field(ident, ty) => Some(quote!(#ident: &#ty)),
+    Part::Constant(_) => None,
+  });
+  let args = parts.iter().filter_map(|part| match part {
+    Part::Field(ident, _) => Some(quote!(&self.#ident)),
+    Part::Constant(_) => None,
+  });
+  let codec = codec.map(|codec| {
+    quote!(const CODEC: ::denokv_remote::Codec = ::denokv_remote::Codec::#codec;)
+  });
+
+  let name = &input.ident;
+  let vis = &input.vis;
+  let (impl_generics, ty_generics, where_clause) =
+    input.generics.split_for_impl();
+  let key_of_doc = format!("The key of the `{name}` with these key fields.");
+  Ok(quote! {
+    impl #impl_generics #name #ty_generics #where_clause {
+      #[doc = #key_of_doc]
+      #[allow(clippy::ptr_arg)]
+      #vis fn key_of(#(#params),*) -> #private::Key {
+        #private::Key(::std::vec![#(#key_parts),*])
+      }
+    }
+
+    impl #impl_generics ::denokv_remote::KvEntity for #name #ty_generics
+      #where_clause
+    {
+      #codec
+
+      fn prefix() -> #private::Key {
+        #private::Key(::std::vec![#(#prefix),*])
+      }
+
+      fn key(&self) -> #private::Key {
+        Self::key_of(#(#args),*)
+      }
+    }
+  })
+}
@@ -0,0 +1,490 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! A load generator for any Deno KV database, to size a database before
+//! going to production and to reproduce performance reports:
+//!
+//! ```sh
+//! cargo run --release -p denokv_open --example loadgen -- \
+//!   --url postgres://postgres@localhost/denokv \
+//!   --duration 60 --concurrency 64 --read 80 --write 15 --queue 5 \
+//!   --distribution zipf --prefill
+//! ```
+//!
+//! Every worker runs operations back to back, picking each at random by the
+//! weights of the mix: reads of `--read-limit` consecutive keys, writes of
+//! one value and enqueues of one message, which `--consumers` tasks dequeue
+//! and finish. Throughput is printed every `--report-interval` seconds, and
+//! latency percentiles per operation at the end, as JSON with `--json`.
+//!
+//! Keys are written under `--prefix` and left in place, as are messages
+//! still queued at the end. KV Connect servers do not serve queues, so run
+//! against them with `--queue 0 --consumers 0`.
+
+use std::collections::BTreeMap;
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use clap::Parser;
+use clap::ValueEnum;
+use denokv_proto::encode_key;
+use denokv_proto::AtomicWrite;
+use denokv_proto::BoxedDatabase;
+use denokv_proto::Consistency;
+use denokv_proto::Database;
+use denokv_proto::Enqueue;
+use denokv_proto::Key;
+use denokv_proto::KeyPart;
+use denokv_proto::KvValue;
+use denokv_proto::Mutation;
+use denokv_proto::MutationKind;
+use denokv_proto::QueueMessageHandle;
+use denokv_proto::ReadRange;
+use denokv_proto::SnapshotReadOptions;
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand::SeedableRng;
+
+/// Mutations per write when prefilling.
+const PREFILL_BATCH: u64 = 100;
+
+#[derive(Parser)]
+struct Options {
+  /// The database to load, in any form `denokv_open::open_database` takes.
+  #[clap(long, env = "DENO_KV_URL")]
+  url: String,
+  /// Seconds to run for.
+  #[clap(long, default_value = "30")]
+  duration: u64,
+  /// Workers running operations concurrently.
+  #[clap(long, default_value = "32")]
+  concurrency: usize,
+  /// Weight of reads in the mix.
+  #[clap(long, default_value = "80")]
+  read: u32,
+  /// Weight of writes in the mix.
+  #[clap(long, default_value = "15")]
+  write: u32,
+  /// Weight of enqueues in the mix.
+  #[clap(long, default_value = "5")]
+  queue: u32,
+  /// Tasks dequeuing and finishing messages.
+  #[clap(long, default_value = "1")]
+  consumers: usize,
+  /// Number of distinct keys.
+  #[clap(long, default_value = "100000")]
+  keys: u64,
+  /// How keys are picked.
+  #[clap(long, value_enum, default_value = "uniform")]
+  distribution: Distribution,
+  /// The exponent of the zipf distribution. Higher is more skewed.
+  #[clap(long, default_value = "1.0")]
+  zipf_exponent: f64,
+  /// Entries read per read, starting at the picked key.
+  #[clap(long, default_value = "1")]
+  read_limit: NonZeroU32,
+  /// Bytes per written value.
+  #[clap(long, default_value = "256")]
+  value_size: usize,
+  /// Read with eventual rather than strong consistency.
+  #[clap(long)]
+  eventual: bool,
+  /// Write every key once before starting, so that reads find values.
+  #[clap(long)]
+  prefill: bool,
+  /// The first part of every key.
+  #[clap(long, default_value = "loadgen")]
+  prefix: String,
+  /// Seconds between throughput reports. 0 disables them.
+  #[clap(long, default_value = "5")]
+  report_interval: u64,
+  /// Print the final report as JSON.
+  #[clap(long)]
+  json: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy)]
+enum Distribution {
+  /// Every key is as likely.
+  Uniform,
+  /// The first keys are the hottest, the n-th is picked with a
+  /// probability proportional to 1 / n^exponent.
+  Zipf,
+}
+
+#[derive(Clone, Copy)]
+enum Op {
+  Read,
+  Write,
+  Enqueue,
+}
+
+impl Op {
+  fn name(self) -> &'static str {
+    match self {
+      Op::Read => "read",
+      Op::Write => "write",
+      Op::Enqueue => "enqueue",
+    }
+  }
+}
+
+/// Picks key indexes by the configured distribution.
+enum KeyPicker {
+  Uniform(u64),
+  /// The cumulative probability of each key.
+  Zipf(Vec<f64>),
+}
+
+impl KeyPicker {
+  fn new(options: &Options) -> Self {
+    match options.distribution {
+      Distribution::Uniform => KeyPicker::Uniform(options.keys),
+      Distribution::Zipf => {
+        let weights: Vec<f64> = (1..=options.keys)
+          .map(|n| 1.0 / (n as f64).powf(options.zipf_exponent))
+          .collect();
+        let total: f64 = weights.iter().sum();
+        let mut cumulative = 0.0;
+        let cdf = weights
+          .iter()
+          .map(|weight| {
+            cumulative += weight / total;
+            cumulative
+          })
+          .collect();
+        KeyPicker::Zipf(cdf)
+      }
+    }
+  }
+
+  fn pick(&self, rng: &mut impl Rng) -> u64 {
+    match self {
+      KeyPicker::Uniform(keys) => rng.gen_range(0..*keys),
+      KeyPicker::Zipf(cdf) => {
+        let p: f64 = rng.gen();
+        (cdf.partition_point(|&c| c < p) as u64).min(cdf.len() as u64 - 1)
+      }
+    }
+  }
+}
+
+/// Latencies and errors per operation.
+#[derive(Default)]
+struct Stats {
+  latencies: BTreeMap<&'static str, Vec<Duration>>,
+  errors: BTreeMap<&'static str, u64>,
+}
+
+impl Stats {
+  fn record<T, E>(
+    &mut self,
+    op: &'static str,
+    started: Instant,
+    result: &Result<T, E>,
+  ) {
+    match result {
+      Ok(_) => self
+        .latencies
+        .entry(op)
+        .or_default()
+        .push(started.elapsed()),
+      Err(_) => *self.errors.entry(op).or_default() += 1,
+    }
+  }
+
+  fn counts(&self) -> BTreeMap<&'static str, usize> {
+    self
+      .latencies
+      .iter()
+      .map(|(op, latencies)| (*op, latencies.len()))
+      .collect()
+  }
+}
+
+struct Load {
+  db: BoxedDatabase,
+  options: Options,
+  keys: KeyPicker,
+  stats: Mutex<Stats>,
+  end: Instant,
+}
+
+impl Load {
+  fn key(&self, index: u64) -> Vec<u8> {
+    encode_key(&Key(vec![
+      KeyPart::String(self.options.prefix.clone()),
+      KeyPart::Bytes(index.to_be_bytes().to_vec()),
+    ]))
+    .expect("load keys always encode")
+  }
+
+  fn set(&self, index: u64, value: &[u8]) -> Mutation {
+    Mutation {
+      key: self.key(index),
+      kind: MutationKind::Set(KvValue::Bytes(value.to_vec())),
+      expire_at: None,
+    }
+  }
+
+  fn pick_op(&self, rng: &mut impl Rng) -> Op {
+    let options = &self.options;
+    let pick = rng.gen_range(0..options.read + options.write + options.queue);
+    if pick < options.read {
+      Op::Read
+    } else if pick < options.read + options.write {
+      Op::Write
+    } else {
+      Op::Enqueue
+    }
+  }
+
+  async fn prefill(&self) -> Result<(), deno_error::JsErrorBox> {
+    let value = vec![0; self.options.value_size];
+    let mut start = 0;
+    while start < self.options.keys {
+      let end = (start + PREFILL_BATCH).min(self.options.keys);
+      let write = AtomicWrite {
+        checks: vec![],
+        mutations: (start..end).map(|index| self.set(index, &value)).collect(),
+        enqueues: vec![],
+      };
+      self.db.atomic_write(write).await?;
+      start = end;
+    }
+    Ok(())
+  }
+
+  async fn work(&self) {
+    let mut rng = StdRng::from_entropy();
+    let value = vec![0; self.options.value_size];
+    while Instant::now() < self.end {
+      let op = self.pick_op(&mut rng);
+      let key = self.keys.pick(&mut rng);
+      let started = Instant::now();
+      let result = match op {
+        Op::Read => {
+          let mut end = self.key(u64::MAX);
+          end.push(0);
+          let request = ReadRange {
+            start: self.key(key),
+            end,
+            limit: self.options.read_limit,
+            reverse: false,
+          };
+          let consistency = if self.options.eventual {
+            Consistency::Eventual
+          } else {
+            Consistency::Strong
+          };
+          let options = SnapshotReadOptions { consistency };
+          self
+            .db
+            .snapshot_read(vec![request], options)
+            .await
+            .map(drop)
+        }
+        Op::Write => {
+          let write = AtomicWrite {
+            checks: vec![],
+            mutations: vec![self.set(key, &value)],
+            enqueues: vec![],
+          };
+          self.db.atomic_write(write).await.map(drop)
+        }
+        Op::Enqueue => {
+          let now = denokv_proto::time::utc_now();
+          let write = AtomicWrite {
+            checks: vec![],
+            mutations: vec![],
+            enqueues: vec![Enqueue {
+              // When it was enqueued, to time its delivery.
+              payload: now.timestamp_micros().to_be_bytes().to_vec(),
+              deadline: now,
+              keys_if_undelivered: vec![],
+              backoff_schedule: None,
+            }],
+          };
+          self.db.atomic_write(write).await.map(drop)
+        }
+      };
+      self
+        .stats
+        .lock()
+        .unwrap()
+        .record(op.name(), started, &result);
+    }
+  }
+
+  /// Dequeue and finish messages until the end. Delivery latency is the
+  /// time from enqueueing a message to dequeuing it.
+  async fn consume(&self) {
+    loop {
+      let remaining = self.end.saturating_duration_since(Instant::now());
+      let started = Instant::now();
+      let message =
+        match tokio::time::timeout(remaining, self.db.dequeue_next_message())
+          .await
+        {
+          Err(_) => return,
+          Ok(Ok(Some(message))) => message,
+          Ok(Ok(None)) => {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            continue;
+          }
+          Ok(Err(e)) => {
+            self.stats.lock().unwrap().record(
+              "dequeue",
+              started,
+              &Err::<(), _>(e),
+            );
+            continue;
+          }
+        };
+      let result = self.finish(message).await;
+      let mut stats = self.stats.lock().unwrap();
+      stats.record("dequeue", started, &result);
+      if let Ok(Some(enqueued_at)) = result {
+        let delivered = denokv_proto::time::utc_now().timestamp_micros();
+        let latency =
+          Duration::from_micros((delivered - enqueued_at).max(0) as u64);
+        stats.latencies.entry("delivery").or_default().push(latency);
+      }
+    }
+  }
+
+  /// Finish `message`, returning when it was enqueued if it was by a load
+  /// generator.
+  async fn finish(
+    &self,
+    mut message: Box<dyn QueueMessageHandle>,
+  ) -> Result<Option<i64>, deno_error::JsErrorBox> {
+    let payload = message.take_payload().await?;
+    message.finish(true).await?;
+    Ok(payload.try_into().ok().map(i64::from_be_bytes))
+  }
+
+  async fn report_progress(&self) {
+    let interval = Duration::from_secs(self.options.report_interval);
+    let started = Instant::now();
+    let mut last = BTreeMap::new();
+    while Instant::now() + interval <= self.end {
+      tokio::time::sleep(interval).await;
+      let counts = self.stats.lock().unwrap().counts();
+      let rates: Vec<String> = counts
+        .iter()
+        .map(|(op, count)| {
+          let done = count - last.get(op).copied().unwrap_or(0);
+          format!("{op}={:.0}/s", done as f64 / interval.as_secs_f64())
+        })
+        .collect();
+      eprintln!("{:>4}s  {}", started.elapsed().as_secs(), rates.join("  "));
+      last = counts;
+    }
+  }
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+  sorted[((sorted.len() - 1) as f64 * p) as usize]
+}
+
+fn print_report(stats: Stats, elapsed: Duration, json: bool) {
+  let mut ops: Vec<&'static str> = stats.latencies.keys().copied().collect();
+  ops.extend(
+    stats
+      .errors
+      .keys()
+      .filter(|op| !stats.latencies.contains_key(*op)),
+  );
+  let mut report = serde_json::Map::new();
+  for op in ops {
+    let mut latencies = stats.latencies.get(op).cloned().unwrap_or_default();
+    latencies.sort();
+    let errors = stats.errors.get(op).copied().unwrap_or(0);
+    let rate = latencies.len() as f64 / elapsed.as_secs_f64();
+    if json {
+      let micros = |d: Duration| d.as_micros() as u64;
+      let mut entry = serde_json::json!({
+        "count": latencies.len(),
+        "errors": errors,
+        "per_second": rate,
+      });
+      if !latencies.is_empty() {
+        entry["p50_us"] = micros(percentile(&latencies, 0.5)).into();
+        entry["p90_us"] = micros(percentile(&latencies, 0.9)).into();
+        entry["p99_us"] = micros(percentile(&latencies, 0.99)).into();
+        entry["max_us"] = micros(latencies[latencies.len() - 1]).into();
+      }
+      report.insert(op.to_string(), entry);
+    } else if latencies.is_empty() {
+      println!("{op}\tcount=0\terrors={errors}");
+    } else {
+      println!(
+        "{op}\tcount={}\t{rate:.0}/s\tp50={:?}\tp90={:?}\tp99={:?}\tmax={:?}\terrors={errors}",
+        latencies.len(),
+        percentile(&latencies, 0.5),
+        percentile(&latencies, 0.9),
+        percentile(&latencies, 0.99),
+        latencies[latencies.len() - 1],
+      );
+    }
+  }
+  if json {
+    println!("{}", serde_json::Value::Object(report));
+  }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), deno_error::JsErrorBox> {
+  let options = Options::parse();
+  if options.keys == 0 {
+    return Err(deno_error::JsErrorBox::type_error(
+      "--keys must be at least 1",
+    ));
+  }
+  if options.read + options.write + options.queue == 0 {
+    return Err(deno_error::JsErrorBox::type_error(
+      "at least one of --read, --write and --queue must be above 0",
+    ));
+  }
+
+  let db = denokv_open::open_database(&options.url).await?;
+  let keys = KeyPicker::new(&options);
+  let mut load = Load {
+    db,
+    options,
+    keys,
+    stats: Mutex::default(),
+    end: Instant::now(),
+  };
+  if load.options.prefill {
+    eprintln!("Prefilling {} keys", load.options.keys);
+    load.prefill().await?;
+  }
+
+  let started = Instant::now();
+  load.end = started + Duration::from_secs(load.options.duration);
+  let load = Arc::new(load);
+  let mut tasks = tokio::task::JoinSet::new();
+  for _ in 0..load.options.concurrency {
+    let load = load.clone();
+    tasks.spawn(async move { load.work().await });
+  }
+  for _ in 0..load.options.consumers {
+    let load = load.clone();
+    tasks.spawn(async move { load.consume().await });
+  }
+  if load.options.report_interval > 0 {
+    let load = load.clone();
+    tasks.spawn(async move { load.report_progress().await });
+  }
+  while tasks.join_next().await.is_some() {}
+  let elapsed = started.elapsed();
+
+  load.db.close();
+  let stats = std::mem::take(&mut *load.stats.lock().unwrap());
+  print_report(stats, elapsed, load.options.json);
+  Ok(())
+}
@@ -0,0 +1,110 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! Open a Deno KV database of any backend from a URL, so that applications
+//! can switch backends through configuration alone. The scheme picks the
+//! backend:
+//!
+//! - `postgres://` and `postgresql://`: a PostgreSQL database with the
+//!   default [`PostgresConfig`] for the URL, whose query parameters are
+//!   passed on to the server as usual.
+//! - `sqlite://<path>`: a SQLite file as written by Deno and the upstream
+//!   denokv server, created if it does not exist. `sqlite://:memory:` is an
+//!   in-memory database that lives as long as the returned handle.
+//! - `http://` and `https://`: the KV Connect metadata endpoint of a remote
+//!   server, authenticated with the access token in `DENO_KV_ACCESS_TOKEN`
+//!   like Deno does.
+//!
+//! Any number of databases can be opened in one process, of the same or of
+//! different backends.
+
+use std::path::Path;
+
+use deno_error::JsErrorBox;
+use denokv_postgres::Postgres;
+use denokv_postgres::PostgresConfig;
+use denokv_proto::BoxedDatabase;
+use denokv_remote::AllowAllPermissions;
+use denokv_remote::MetadataEndpoint;
+use denokv_remote::Remote;
+use denokv_remote::ReqwestTransport;
+use denokv_sqlite::Sqlite;
+use denokv_sqlite::SqliteConfig;
+use denokv_sqlite::SqliteNotifier;
+use rand::SeedableRng;
+use rusqlite::OpenFlags;
+use url::Url;
+
+/// The environment variable holding the access token for remote databases.
+pub const ACCESS_TOKEN_ENV: &str = "DENO_KV_ACCESS_TOKEN";
+
+/// Open the database at `url`. See the [crate] documentation for the
+/// supported URLs.
+pub async fn open_database(url: &str) -> Result<BoxedDatabase, JsErrorBox> {
+  let Some((scheme, rest)) = url.split_once("://") else {
+    return Err(JsErrorBox::type_error(format!(
+      "database URL {url:?} has no scheme"
+    )));
+  };
+  match scheme.to_ascii_lowercase().as_str() {
+    "postgres" | "postgresql" => {
+      let postgres = Postgres::new(PostgresConfig::new(url.to_string()))
+        .await
+        .map_err(JsErrorBox::from_err)?;
+      Ok(BoxedDatabase::new(postgres))
+    }
+    "sqlite" => Ok(BoxedDatabase::new(open_sqlite(rest)?)),
+    "http" | "https" => {
+      let url = Url::parse(url).map_err(|e| {
+        JsErrorBox::type_error(format!("invalid database URL: {e}"))
+      })?;
+      let access_token = std::env::var(ACCESS_TOKEN_ENV).map_err(|_| {
+        JsErrorBox::type_error(format!(
+          "{ACCESS_TOKEN_ENV} must be set to open a remote database"
+        ))
+      })?;
+      let remote = Remote::new(
+        ReqwestTransport::default(),
+        AllowAllPermissions,
+        MetadataEndpoint { url, access_token },
+      );
+      Ok(BoxedDatabase::new(remote))
+    }
+    _ => Err(JsErrorBox::type_error(format!(
+      "unsupported database URL scheme {scheme:?}, expected postgres, \
+       sqlite, http or https"
+    ))),
+  }
+}
+
+/// Open the SQLite database at `path`, or in memory for `:memory:`.
+fn open_sqlite(path: &str) -> Result<Sqlite, JsErrorBox> {
+  if path.is_empty() {
+    return Err(JsErrorBox::type_error("sqlite:// URL has no path"));
+  }
+  let flags = OpenFlags::SQLITE_OPEN_READ_WRITE
+    | OpenFlags::SQLITE_OPEN_CREATE
+    | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+  let path = Path::new(path).to_path_buf();
+  // Every worker opens its own connection, and in-memory databases are not
+  // shared between connections, so a single worker serves all requests.
+  let config = SqliteConfig {
+    num_workers: 1,
+    batch_timeout: None,
+  };
+  Sqlite::new(
+    move || {
+      let conn = if path == Path::new(":memory:") {
+        rusqlite::Connection::open_in_memory()
+      } else {
+        rusqlite::Connection::open_with_flags(&path, flags)
+      };
+      Ok((
+        conn.map_err(|e| JsErrorBox::generic(e.to_string()))?,
+        Box::new(rand::rngs::StdRng::from_entropy()),
+      ))
+    },
+    SqliteNotifier::default(),
+    config,
+  )
+  .map_err(JsErrorBox::from_err)
+}
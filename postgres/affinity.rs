@@ -0,0 +1,96 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+//! Connection affinity for writes to hot keys.
+//!
+//! Every atomic write takes whichever pooled connection is free. Under a
+//! skewed workload, writes to the same few keys then prepare the same
+//! statements on connection after connection and wait on the server, each
+//! in a transaction of its own, for the row locks the previous writes hold.
+//!
+//! With [`PostgresConfig::affinity_lanes`](crate::PostgresConfig::affinity_lanes)
+//! set, keys are hashed to that many lanes. A write whose checked and
+//! mutated keys all hash to the same lane runs on the lane's connection,
+//! which the lane keeps checked out of the pool between writes, so writes
+//! to a key reuse one connection with its statements prepared and queue
+//! for it in this process rather than for row locks on the server. Writes
+//! with keys in several lanes take a connection from the pool as before.
+//! A lane drops its connection after a failed write, and takes a new one
+//! for the next write.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use denokv_proto::AtomicWrite;
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::pool::Client;
+
+/// The connection of each lane, taken from the pool on first use.
+pub(crate) struct AffinityLanes {
+    lanes: Vec<Mutex<Option<Client>>>,
+}
+
+impl AffinityLanes {
+    /// Returns `None` for 0 lanes.
+    pub fn new(count: usize) -> Option<Self> {
+        (count > 0).then(|| Self { lanes: (0..count).map(|_| Mutex::new(None)).collect() })
+    }
+
+    /// The lane every key `write` checks or mutates hashes to, or `None`
+    /// if they hash to different lanes or there are none.
+    pub fn lane_of(&self, write: &AtomicWrite) -> Option<usize> {
+        let keys = write.checks.iter().map(|check| &check.key).chain(write.mutations.iter().map(|mutation| &mutation.key));
+        let mut lane = None;
+        for key in keys {
+            let this = self.lane_of_key(key);
+            if *lane.get_or_insert(this) != this {
+                return None;
+            }
+        }
+        lane
+    }
+
+    fn lane_of_key(&self, key: &[u8]) -> usize {
+        // SipHash with fixed keys, so that a key stays in its lane.
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.lanes.len() as u64) as usize
+    }
+
+    /// Wait until `lane` is free and take it, with its connection if it
+    /// has one.
+    pub async fn lock(&self, lane: usize) -> MutexGuard<'_, Option<Client>> {
+        self.lanes[lane].lock().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use denokv_proto::{Check, KvValue, Mutation, MutationKind};
+
+    use super::*;
+
+    fn write(keys: &[&[u8]]) -> AtomicWrite {
+        AtomicWrite {
+            checks: vec![Check { key: keys[0].to_vec(), versionstamp: None }],
+            mutations: keys[1..].iter()
+                .map(|key| Mutation { key: key.to_vec(), kind: MutationKind::Set(KvValue::U64(1)), expire_at: None })
+                .collect(),
+            enqueues: vec![],
+        }
+    }
+
+    #[test]
+    fn routes_writes_with_keys_in_one_lane() {
+        assert!(AffinityLanes::new(0).is_none());
+        let lanes = AffinityLanes::new(8).unwrap();
+        let lane = lanes.lane_of(&write(&[b"hot", b"hot"]));
+        assert_eq!(lane, Some(lanes.lane_of_key(b"hot")));
+        assert_eq!(lanes.lane_of(&write(&[b"hot"])), lane);
+
+        let other = (0u8..=255).map(|i| vec![i]).find(|key| lanes.lane_of_key(key) != lane.unwrap()).unwrap();
+        assert_eq!(lanes.lane_of(&write(&[b"hot", &other])), None);
+        let empty = AtomicWrite { checks: vec![], mutations: vec![], enqueues: vec![] };
+        assert_eq!(lanes.lane_of(&empty), None);
+    }
+}
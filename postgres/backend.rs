@@ -1,125 +1,245 @@
 // Copyright 2023 rawkakani. All rights reserved. MIT license.
 
-use std::collections::HashMap;
-use std::num::NonZeroU32;
+use std::borrow::Cow;
 
-use chrono::{DateTime, Utc};
-use deadpool_postgres::{Client, Pool};
+use chrono::{DateTime, TimeZone, Utc};
 use denokv_proto::{
-    AtomicWrite, Check, CommitResult, Enqueue, KvEntry, KvValue, Mutation, MutationKind,
-    ReadRange, Versionstamp,
+    AtomicWrite, Check, CommitResult, KvChange, KvEntry, KvValue, Mutation, MutationKind, ReadRange,
+    Versionstamp,
 };
-use serde_json::Value;
-use tokio_postgres::Row;
-
-use crate::error::{PostgresError, PostgresResult};
-use crate::message_handle::PostgresMessageHandle;
+use futures::{pin_mut, Stream, StreamExt, TryStreamExt};
+use tokio_postgres::types::ToSql;
+use tokio_postgres::{Row, RowStream, Statement, Transaction};
+use uuid::Uuid;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::backfill::{BackfillProgress, LEASE_TTL};
+use crate::change_notify;
+use crate::config::{queue_running_deadline, Durability, PostgresConfig};
+use crate::driver::{self, Le64Op};
+use crate::decode::{
+    decode_entry, decode_value, decode_versionstamp, row_checksum, verify_checksum,
+};
+use crate::error::{CorruptionKind, PostgresError, PostgresResult};
+use crate::message_handle::{set_undelivered, Keepalive, PostgresMessageHandle};
+use crate::pool::{Client, ConnectionPool, Pool};
+use crate::progress::{ProgressCallback, ProgressReporter};
+use crate::queue_metadata::{QueueMetadata, METADATA_ENCODING};
+use crate::reaper::NamespaceDeletion;
+use crate::range::KeyBounds;
+use crate::statement_log::StatementLog;
+use crate::table_partitioning::{TableLayout, TablePartitioning};
+use crate::tables::Tables;
+
+/// Number of rows fetched per round trip by `verify`.
+const VERIFY_BATCH_SIZE: i64 = 1000;
+/// `sample_keys` aims to see this many matching rows per requested sample,
+/// so that the sample is drawn from a reasonable population.
+const SAMPLE_OVERSCAN: f64 = 4.0;
 
 /// PostgreSQL backend implementation
 pub struct PostgresBackend {
     pub pool: Pool,
+    pub config: PostgresConfig,
+    pub tables: Tables,
+    /// The channel changes are notified on, from [`Tables::channel`].
+    pub channel: String,
+    statement_log: StatementLog,
 }
 
-impl PostgresBackend {
-    pub fn new(pool: Pool) -> Self {
-        Self { pool }
-    }
+/// The statements that apply mutations, from
+/// [`PostgresBackend::write_statements`].
+struct WriteStatements {
+    increment_version: Statement,
+    check: Statement,
+    set: Statement,
+    set_many: Statement,
+    set_versionstamped_key: Statement,
+    delete: Statement,
+}
 
-    /// Initialize the database schema
-    pub async fn initialize_schema(&self) -> PostgresResult<()> {
-        let conn = self.pool.get().await?;
+/// The outcome of a full `kv_store` integrity scan.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Total number of rows scanned.
+    pub rows_scanned: u64,
+    /// Rows that have no checksum yet and were only checked for decodability.
+    pub rows_without_checksum: u64,
+    /// Keys of rows that failed verification, with the reason.
+    pub corrupt: Vec<(Vec<u8>, CorruptionKind)>,
+}
 
+/// What `repair` found and changed. In dry-run mode nothing is changed but
+/// the counts still describe what would have been fixed.
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// `queue_running` rows whose message no longer exists.
+    pub orphaned_running_removed: u64,
+    /// Whether the `data_version` row was missing and had to be recreated.
+    pub version_row_recreated: bool,
+    /// The version counter before and after, if it was behind the highest
+    /// versionstamp stored in `kv_store`.
+    pub version_advanced: Option<(i64, i64)>,
+    /// Whether the secondary indexes of `kv_store` and the queue tables were
+    /// rebuilt.
+    pub reindexed: bool,
+}
 
-        // Create the main KV table
-        conn.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS kv_store (
-                key BYTEA PRIMARY KEY,
-                value BYTEA NOT NULL,
-                value_encoding INTEGER NOT NULL,
-                versionstamp BYTEA NOT NULL,
-                created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
-                updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
-                expires_at BIGINT
-            )
-            "#,
-            &[],
-        ).await?;
+/// Progress of a `bulk_import` call.
+#[derive(Debug, Default)]
+pub struct BulkImportReport {
+    /// Number of mutations already committed by earlier runs of the same
+    /// import when this run started.
+    pub resumed_from: u64,
+    /// Sub-transactions committed by this run.
+    pub batches_committed: u64,
+    /// Mutations applied by this run.
+    pub mutations_applied: u64,
+}
 
-        // Create indexes for performance
-        conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_kv_versionstamp ON kv_store(versionstamp)",
-            &[],
-        ).await?;
+/// The view entries derived from one source key of a materialized view,
+/// as keys relative to the view's target prefix. Empty if the source key
+/// was deleted or derives nothing.
+pub struct ViewRow {
+    pub source_key: Vec<u8>,
+    pub entries: Vec<(Vec<u8>, KvValue)>,
+}
 
-        conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_kv_expires_at ON kv_store(expires_at) WHERE expires_at IS NOT NULL",
-            &[],
-        ).await?;
+/// The outcome of [`PostgresBackend::apply_backfill_batch`].
+pub enum BackfillBatch {
+    /// The batch was committed, writing these keys.
+    Committed(Vec<Vec<u8>>),
+    /// An entry changed after it was read, so nothing was written.
+    Conflict,
+    /// Another owner holds the job's lease, so nothing was written.
+    LeaseLost,
+}
 
-        conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_kv_updated_at ON kv_store(updated_at)",
-            &[],
-        ).await?;
+/// A queue message that failed
+/// [`PostgresConfig::max_delivery_attempts`] times, kept in the
+/// `queue_dead_letter` table until it is requeued or deleted.
+#[derive(Debug, Clone)]
+pub struct DeadLetter {
+    pub id: Uuid,
+    /// The V8-serialized payload, as enqueued.
+    pub payload: Vec<u8>,
+    pub keys_if_undelivered: Vec<Vec<u8>>,
+    /// The ephemeral namespace the message was enqueued in, if any.
+    pub namespace: Option<Vec<u8>>,
+    pub priority: i16,
+    pub attempts: u32,
+    pub enqueued_at: Option<DateTime<Utc>>,
+    pub failed_at: DateTime<Utc>,
+}
 
-        // Create queue tables
-        conn.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS queue_messages (
-                id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
-                payload BYTEA NOT NULL,
-                deadline BIGINT NOT NULL,
-                keys_if_undelivered BYTEA[] NOT NULL,
-                backoff_schedule INTEGER[],
-                created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
-                retry_count INTEGER DEFAULT 0
-            )
-            "#,
-            &[],
-        ).await?;
+/// A sampled key and the number of key and value bytes it stores.
+#[derive(Debug, Clone)]
+pub struct KeySample {
+    pub key: Vec<u8>,
+    pub size: u64,
+}
 
-        conn.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS queue_running (
-                message_id UUID PRIMARY KEY REFERENCES queue_messages(id),
-                deadline BIGINT NOT NULL,
-                started_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
-                updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
-            )
-            "#,
-            &[],
-        ).await?;
+/// A representative sample of the keys under a prefix, with estimates for
+/// the prefix as a whole.
+#[derive(Debug, Default)]
+pub struct KeySampleReport {
+    /// Up to the requested number of keys, chosen uniformly from the rows
+    /// that were scanned.
+    pub samples: Vec<KeySample>,
+    /// Estimated number of live keys under the prefix.
+    pub estimated_keys: u64,
+    /// Estimated number of key and value bytes under the prefix.
+    pub estimated_bytes: u64,
+    /// Fraction of the table that was scanned; 1.0 means the prefix was read
+    /// in full and the estimates are exact.
+    pub fraction_scanned: f64,
+}
 
-        // Create indexes for queue
-        conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_queue_deadline ON queue_messages(deadline)",
-            &[],
-        ).await?;
+/// How many queue messages are in each state, as of one consistent
+/// snapshot.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct QueueDepth {
+    /// Messages due and waiting to be dequeued.
+    pub ready: u64,
+    /// Messages enqueued with a delay that is not over yet, or waiting to
+    /// be retried.
+    pub scheduled: u64,
+    /// Messages dequeued and not finished yet.
+    pub running: u64,
+    /// Messages in the dead-letter table.
+    pub dead_letter: u64,
+}
 
-        conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_queue_running_deadline ON queue_running(deadline)",
-            &[],
-        ).await?;
+/// How much is stored under a key prefix, as of one consistent snapshot.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PrefixUsage {
+    /// Live keys under the prefix.
+    pub keys: u64,
+    /// Key and value bytes of those keys.
+    pub bytes: u64,
+}
 
-        // Monotonic version counter — matches SQLite's data_version table.
-        // The single row is locked by UPDATE during atomic_write, which
-        // serializes all writers without needing SERIALIZABLE isolation.
-        conn.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS data_version (
-                k INTEGER PRIMARY KEY DEFAULT 0,
-                version BIGINT NOT NULL DEFAULT 0
-            )
-            "#,
-            &[],
-        ).await?;
+impl PostgresBackend {
+    pub fn new(pool: Pool, config: PostgresConfig) -> Self {
+        let statement_log = StatementLog::new(config.statement_log_sample_rate);
+        let tables = Tables::new(&config);
+        let channel = tables.channel();
+        Self {
+            pool,
+            config,
+            tables,
+            channel,
+            statement_log,
+        }
+    }
 
-        // Seed the row if it doesn't exist
-        conn.execute(
-            "INSERT INTO data_version (k, version) VALUES (0, 0) ON CONFLICT DO NOTHING",
-            &[],
-        ).await?;
+    /// `statement` naming the configured tables. See [`Tables::sql`].
+    pub fn sql<'a>(&self, statement: &'a str) -> Cow<'a, str> {
+        self.tables.sql(statement)
+    }
+
+    /// `statement` for the configured tables, prepared on `conn` or taken
+    /// from the statements `conn` keeps prepared.
+    pub async fn prepare(&self, conn: &Client, statement: &str) -> PostgresResult<Statement> {
+        Ok(Pool::prepare_cached(conn, &self.sql(statement), self.config.statement_cache_size).await?)
+    }
 
+    /// The statements writes run, prepared before their transaction starts
+    /// as the transaction borrows the connection.
+    async fn write_statements(&self, conn: &Client) -> PostgresResult<WriteStatements> {
+        let (increment_version, check, set, set_many, set_versionstamped_key, delete) = futures::try_join!(
+            self.prepare(conn, driver::INCREMENT_VERSION),
+            self.prepare(conn, driver::CHECK),
+            self.prepare(conn, driver::SET),
+            self.prepare(conn, driver::SET_MANY),
+            self.prepare(conn, driver::SET_VERSIONSTAMPED_KEY),
+            self.prepare(conn, driver::DELETE),
+        )?;
+        Ok(WriteStatements { increment_version, check, set, set_many, set_versionstamped_key, delete })
+    }
+
+    /// Initialize the database schema
+    pub async fn initialize_schema(&self) -> PostgresResult<()> {
+        let conn = self.pool.get().await?;
+        if let Some(schema) = self.tables.schema() {
+            conn.execute(&format!("CREATE SCHEMA IF NOT EXISTS {schema}"), &[]).await?;
+        }
+        if self.config.table_partitioning != TablePartitioning::None {
+            let layout = conn.query_opt(&*self.sql(driver::KV_STORE_LAYOUT), &[]).await?.map(|row| TableLayout {
+                strategy: row.get("strategy"),
+                partitions: row.get("partitions"),
+            });
+            let statements = self.config.table_partitioning.statements(&self.tables, layout);
+            if !statements.is_empty() {
+                // In one transaction, so that no key is written before its
+                // partition exists.
+                conn.batch_execute(&statements.join(";\n")).await?;
+            }
+        }
+        for statement in driver::SCHEMA {
+            conn.execute(&*self.sql(statement), &[]).await?;
+        }
+        conn.execute(&*self.sql(driver::RECORD_SCHEMA_VERSION), &[&(driver::SCHEMA_VERSION as i32)]).await?;
         Ok(())
     }
 
@@ -129,63 +249,99 @@ impl PostgresBackend {
         conn: &Client,
         request: &ReadRange,
     ) -> PostgresResult<Vec<KvEntry>> {
+        let bounds = KeyBounds::from_request(request);
+        let sample = self.statement_log.begin("read_range", || {
+            vec![bounds.start.len(), bounds.end.map_or(0, <[u8]>::len), 8, 8]
+        });
+        let rows = self.query_range(conn, request).await?;
+        pin_mut!(rows);
+
+        let mut entries = Vec::new();
+        while let Some(row) = rows.try_next().await? {
+            entries.push(self.decode_row(&row)?);
+        }
+        sample.finish(entries.len() as u64);
+
+        Ok(entries)
+    }
+
+    /// Fetch the next `limit` entries of a scan from `start` up to `end`,
+    /// in the snapshot of `tx`, with their expiry times. Entries expired at
+    /// `now_ms` are excluded.
+    pub async fn scan_batch(
+        &self,
+        tx: &Transaction<'_>,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: i64,
+        now_ms: i64,
+    ) -> PostgresResult<Vec<(KvEntry, Option<DateTime<Utc>>)>> {
+        let sample = self.statement_log.begin("scan", || vec![start.len(), end.map_or(0, <[u8]>::len), 8, 8]);
+        let rows = tx.query(&*self.sql(driver::SCAN), &[&start, &end, &limit, &now_ms]).await?;
+        sample.finish(rows.len() as u64);
+        rows.iter()
+            .map(|row| {
+                let expires_at: Option<i64> = row.get("expires_at");
+                Ok((self.decode_row(row)?, expires_at.and_then(|ms| Utc.timestamp_millis_opt(ms).single())))
+            })
+            .collect()
+    }
+
+    /// Read several ranges in one round trip, excluding expired entries.
+    pub async fn read_ranges(
+        &self,
+        conn: &Client,
+        requests: &[ReadRange],
+    ) -> PostgresResult<Vec<Vec<KvEntry>>> {
+        let arrays = driver::RangeArrays::new(requests);
         let now_ms = crate::time::utc_now().timestamp_millis();
-        let query = if request.reverse {
-            r#"
-            SELECT key, value, value_encoding, versionstamp
-            FROM kv_store
-            WHERE key >= $1 AND key < $2
-              AND (expires_at IS NULL OR expires_at > $4)
-            ORDER BY key DESC
-            LIMIT $3
-            "#
-        } else {
-            r#"
-            SELECT key, value, value_encoding, versionstamp
-            FROM kv_store
-            WHERE key >= $1 AND key < $2
-              AND (expires_at IS NULL OR expires_at > $4)
-            ORDER BY key ASC
-            LIMIT $3
-            "#
-        };
+        let sample = self.statement_log.begin("read_ranges", || arrays.param_sizes());
+        let params: [&(dyn ToSql + Sync); 5] =
+            [&arrays.starts, &arrays.ends, &arrays.limits, &arrays.reverse, &now_ms];
+        let statement = self.prepare(conn, driver::READ_RANGES).await?;
+        let rows = conn.query(&statement, &params).await?;
+        sample.finish(rows.len() as u64);
+
+        let rows = rows.iter()
+            .map(|row| Ok((row.get("range_index"), self.decode_row(row)?)))
+            .collect::<PostgresResult<Vec<_>>>()?;
+        driver::split_ranges(requests, rows)
+    }
 
-        let rows = conn.query(query, &[
-            &request.start,
-            &request.end,
-            &(request.limit.get() as i64),
-            &now_ms,
-        ]).await?;
+    /// Read a range of entries as a stream, decoding each row as it arrives
+    /// from the server instead of buffering the whole result set. Used for
+    /// export and backup, where a single range can span the entire store.
+    pub async fn read_range_stream<'a>(
+        &'a self,
+        conn: &Client,
+        request: &ReadRange,
+    ) -> PostgresResult<impl Stream<Item = PostgresResult<KvEntry>> + Send + 'a> {
+        let rows = self.query_range(conn, request).await?;
+        Ok(rows.map(move |row| self.decode_row(&row?)))
+    }
 
-        let mut entries = Vec::new();
-        for row in rows {
-            let key: Vec<u8> = row.get("key");
-            let value: Vec<u8> = row.get("value");
-            let encoding: i32 = row.get("value_encoding");
-            let versionstamp: Vec<u8> = row.get("versionstamp");
-
-            let kv_value = match encoding {
-                1 => KvValue::V8(value),
-                2 => {
-                    let mut buf = [0; 8];
-                    buf.copy_from_slice(&value);
-                    KvValue::U64(u64::from_le_bytes(buf))
-                }
-                3 => KvValue::Bytes(value),
-                _ => return Err(PostgresError::InvalidData(format!("Unknown encoding: {}", encoding))),
-            };
+    async fn query_range(&self, conn: &Client, request: &ReadRange) -> PostgresResult<RowStream> {
+        let now_ms = crate::time::utc_now().timestamp_millis();
+        let bounds = KeyBounds::from_request(request);
+        let query = if request.reverse { driver::READ_RANGE_REVERSE } else { driver::READ_RANGE };
 
-            let mut versionstamp_array = [0; 10];
-            versionstamp_array.copy_from_slice(&versionstamp);
+        let limit = request.limit.get() as i64;
+        let params: [&(dyn ToSql + Sync); 4] = [&bounds.start, &bounds.end, &limit, &now_ms];
+        let statement = self.prepare(conn, query).await?;
+        Ok(conn.query_raw(&statement, params).await?)
+    }
 
-            entries.push(KvEntry {
-                key,
-                value: kv_value,
-                versionstamp: versionstamp_array,
-            });
+    fn decode_row(&self, row: &Row) -> PostgresResult<KvEntry> {
+        let key: Vec<u8> = row.get("key");
+        let value: Vec<u8> = row.get("value");
+        let encoding: i32 = row.get("value_encoding");
+        let versionstamp: &[u8] = row.get("versionstamp");
+        if self.config.verify_checksums {
+            if let Err(kind) = verify_checksum(&key, &value, encoding, row.get("checksum")) {
+                return Err(PostgresError::CorruptRow { key, kind });
+            }
         }
-
-        Ok(entries)
+        decode_entry(key, value, encoding, versionstamp)
     }
 
     /// Perform an atomic write operation.
@@ -199,354 +355,396 @@ impl PostgresBackend {
         &self,
         conn: &mut Client,
         write: AtomicWrite,
+        namespace: Option<&[u8]>,
+        progress: Option<&ProgressCallback<'_>>,
+        durability: Durability,
+        priority: i16,
     ) -> PostgresResult<Option<CommitResult>> {
+        if let Some(limits) = &self.config.write_limits {
+            limits.check(&write)?;
+        }
+        let mut progress = ProgressReporter::new(progress, write.mutations.len());
+        let statements = self.write_statements(conn).await?;
         let tx = conn.transaction().await?;
+        if durability == Durability::Asynchronous {
+            // SET LOCAL only lasts until the end of the transaction.
+            tx.batch_execute(driver::ASYNCHRONOUS_COMMIT).await?;
+        }
 
         // Lock the version counter first — this serializes all writers.
         // The row lock is held until tx.commit() / rollback.
-        let new_version: i64 = tx.query_one(
-            "UPDATE data_version SET version = version + 1 WHERE k = 0 RETURNING version",
-            &[],
-        ).await?.get(0);
+        let sample = self.statement_log.begin("increment_version", Vec::new);
+        let new_version: i64 = tx.query_one(&statements.increment_version, &[]).await?.get(0);
+        sample.finish(1);
 
         // Perform checks — treat expired keys as non-existent
         let now_ms = crate::time::utc_now().timestamp_millis();
         for check in &write.checks {
-            let row = tx.query_opt(
-                "SELECT versionstamp FROM kv_store WHERE key = $1 AND (expires_at IS NULL OR expires_at > $2)",
-                &[&check.key, &now_ms],
-            ).await?;
+            let sample = self.statement_log.begin("check", || vec![check.key.len(), 8]);
+            let row = tx.query_opt(&statements.check, &[&check.key, &now_ms]).await?;
+            sample.finish(row.is_some() as u64);
 
             let current_versionstamp = row.map(|r| r.get::<_, Vec<u8>>("versionstamp"));
-
-            if let Some(expected) = &check.versionstamp {
-                if current_versionstamp.as_ref().map(|v| v.as_slice()) != Some(expected.as_slice()) {
-                    return Ok(None); // Check failed
-                }
-            } else if current_versionstamp.is_some() {
-                return Ok(None); // Expected key to not exist, but it does
+            if !driver::check_passes(check.versionstamp.as_ref(), current_versionstamp.as_deref()) {
+                return Ok(None);
             }
         }
 
         // Convert version to 10-byte versionstamp (matches SQLite format)
         let versionstamp = version_to_versionstamp(new_version);
 
-        // Perform mutations
-        for mutation in &write.mutations {
+        self.apply_mutations(&tx, &statements, &write.mutations, &versionstamp, &mut progress).await?;
+
+        // Handle enqueues
+        for enqueue in &write.enqueues {
+            let metadata = QueueMetadata::of(enqueue).encode();
+
+            let sample = self.statement_log.begin("enqueue", || {
+                vec![enqueue.payload.len(), 8, metadata.len(), 4, namespace.map_or(0, <[u8]>::len), 2]
+            });
+            let rows = tx.execute(
+                &*self.sql(driver::ENQUEUE),
+                &[&enqueue.payload, &enqueue.deadline.timestamp_millis(), &metadata, &METADATA_ENCODING, &namespace, &priority],
+            ).await?;
+            sample.finish(rows);
+        }
+        if !write.enqueues.is_empty() {
+            change_notify::notify_queue(&tx, &self.tables.queue_channel()).await?;
+        }
+
+        change_notify::notify(&tx, &self.channel, &write.mutations).await?;
+        tx.commit().await?;
+        progress.committed();
+        Ok(Some(CommitResult {
+            versionstamp,
+            durable: durability == Durability::Synchronous,
+            versionstamped_keys: denokv_proto::versionstamped_keys(&write.mutations, &versionstamp),
+        }))
+    }
+
+    /// Apply `mutations` inside `tx`, stamping every written row with
+    /// `versionstamp`.
+    async fn apply_mutations(
+        &self,
+        tx: &tokio_postgres::Transaction<'_>,
+        statements: &WriteStatements,
+        mutations: &[Mutation],
+        versionstamp: &Versionstamp,
+        progress: &mut ProgressReporter<'_>,
+    ) -> PostgresResult<()> {
+        let mut rest = mutations;
+        while let Some(mutation) = rest.first() {
+            // Consecutive sets are applied in one statement.
+            let run = driver::set_run(rest);
+            if run > 1 {
+                let arrays = driver::SetArrays::new(&rest[..run]);
+                let sample = self.statement_log.begin("set_many", || arrays.param_sizes());
+                let rows = tx.execute(
+                    &statements.set_many,
+                    &[&arrays.keys, &arrays.value_slices(), &arrays.encodings, &versionstamp.as_slice(), &arrays.expires_at, &arrays.checksums],
+                ).await?;
+                sample.finish(rows);
+                rest[..run].iter().for_each(|mutation| progress.applied(mutation));
+                rest = &rest[run..];
+                continue;
+            }
+            rest = &rest[1..];
             match &mutation.kind {
                 MutationKind::Set(value) => {
                     let (value_bytes, encoding) = self.encode_value(value);
+                    let value_bytes: &[u8] = &value_bytes;
                     let expires_at = mutation.expire_at;
 
-                    tx.execute(
-                        r#"
-                        INSERT INTO kv_store (key, value, value_encoding, versionstamp, expires_at, updated_at)
-                        VALUES ($1, $2, $3, $4, $5, NOW())
-                        ON CONFLICT (key) DO UPDATE SET
-                            value = EXCLUDED.value,
-                            value_encoding = EXCLUDED.value_encoding,
-                            versionstamp = EXCLUDED.versionstamp,
-                            expires_at = EXCLUDED.expires_at,
-                            updated_at = NOW()
-                        "#,
-                        &[&mutation.key, &value_bytes, &(encoding as i32), &versionstamp.as_slice(), &expires_at.map(|dt| dt.timestamp_millis())],
+                    let sample = self.statement_log.begin("set", || {
+                        vec![mutation.key.len(), value_bytes.len(), 4, 10, 8, 8]
+                    });
+                    let rows = tx.execute(
+                        &statements.set,
+                        &[&mutation.key, &value_bytes, &encoding, &versionstamp.as_slice(), &expires_at.map(|dt| dt.timestamp_millis()), &row_checksum(&mutation.key, value_bytes, encoding)],
                     ).await?;
+                    sample.finish(rows);
                 }
                 MutationKind::Delete => {
-                    tx.execute(
-                        "DELETE FROM kv_store WHERE key = $1",
-                        &[&mutation.key],
-                    ).await?;
+                    let sample = self.statement_log.begin("delete", || vec![mutation.key.len(), 10]);
+                    let rows = tx.execute(&statements.delete, &[&mutation.key, &versionstamp.as_slice()]).await?;
+                    sample.finish(rows);
+                }
+                MutationKind::Sum { value: value @ KvValue::U64(_), .. } => {
+                    self.handle_le64_mutation(tx, &mutation.key, Le64Op::Sum, value, versionstamp).await?;
                 }
-                MutationKind::Sum { value, .. } => {
-                    self.handle_sum_mutation(&tx, &mutation.key, value, &versionstamp).await?;
+                MutationKind::Sum { value, min_v8, max_v8, clamp } => {
+                    self.handle_sum_v8_mutation(tx, statements, &mutation.key, versionstamp, |current| {
+                        driver::sum_v8(current, value, min_v8, max_v8, *clamp)
+                    }).await?;
                 }
                 MutationKind::Min(value) => {
-                    self.handle_min_mutation(&tx, &mutation.key, value, &versionstamp).await?;
+                    self.handle_le64_mutation(tx, &mutation.key, Le64Op::Min, value, versionstamp).await?;
                 }
                 MutationKind::Max(value) => {
-                    self.handle_max_mutation(&tx, &mutation.key, value, &versionstamp).await?;
+                    self.handle_le64_mutation(tx, &mutation.key, Le64Op::Max, value, versionstamp).await?;
                 }
                 MutationKind::SetSuffixVersionstampedKey(value) => {
-                    let mut new_key = mutation.key.clone();
-                    new_key.extend_from_slice(&versionstamp);
+                    let new_key = driver::changed_key(&mutation.key, true, versionstamp);
 
                     let (value_bytes, encoding) = self.encode_value(value);
+                    let value_bytes: &[u8] = &value_bytes;
                     let expires_at = mutation.expire_at;
 
-                    tx.execute(
-                        r#"
-                        INSERT INTO kv_store (key, value, value_encoding, versionstamp, expires_at, updated_at)
-                        VALUES ($1, $2, $3, $4, $5, NOW())
-                        "#,
-                        &[&new_key, &value_bytes, &(encoding as i32), &versionstamp.as_slice(), &expires_at.map(|dt| dt.timestamp_millis())],
+                    let sample = self.statement_log.begin("set_suffix_versionstamped_key", || {
+                        vec![new_key.len(), value_bytes.len(), 4, 10, 8, 8]
+                    });
+                    let rows = tx.execute(
+                        &statements.set_versionstamped_key,
+                        &[&new_key, &value_bytes, &encoding, &versionstamp.as_slice(), &expires_at.map(|dt| dt.timestamp_millis()), &row_checksum(&new_key, value_bytes, encoding)],
                     ).await?;
+                    sample.finish(rows);
                 }
             }
+            progress.applied(mutation);
         }
+        Ok(())
+    }
 
-        // Handle enqueues
-        for enqueue in &write.enqueues {
-            let keys_json = serde_json::to_string(&enqueue.keys_if_undelivered)?;
-            let backoff_json = enqueue.backoff_schedule.as_ref().map(|b| serde_json::to_string(b)).transpose()?;
+    /// Apply a bulk import of `mutations` that does not need to be atomic as
+    /// a whole.
+    ///
+    /// With `bulk_import_batch_size` set, the mutations are committed in
+    /// sub-transactions of at most that many mutations, each with its own
+    /// versionstamp, and the number committed so far is recorded under
+    /// `import_id` in the same transaction. Calling this again with the same
+    /// `import_id` and mutations resumes after the last committed batch, and
+    /// is a no-op once the import has completed; calling it with other
+    /// mutations fails with [`PostgresError::BulkImportMismatch`]. Without a
+    /// batch size the whole import is a single transaction.
+    pub async fn bulk_import(
+        &self,
+        conn: &mut Client,
+        import_id: &str,
+        mutations: &[Mutation],
+        progress: Option<&ProgressCallback<'_>>,
+    ) -> PostgresResult<BulkImportReport> {
+        let mut progress = ProgressReporter::new(progress, mutations.len());
+        let batch_size = self
+            .config
+            .bulk_import_batch_size
+            .unwrap_or(mutations.len())
+            .max(1);
+        let mut report = BulkImportReport::default();
+        let total = mutations.len() as i64;
+        let fingerprint = mutations_fingerprint(mutations);
+
+        let statements = self.write_statements(conn).await?;
+
+        loop {
+            let tx = conn.transaction().await?;
+
+            // Lock the journal row so that two runs of the same import can
+            // not apply the same batch twice.
+            tx.execute(
+                &*self.sql(
+                    "INSERT INTO bulk_import_journal (import_id, total, fingerprint) VALUES ($1, $2, $3) \
+                     ON CONFLICT DO NOTHING",
+                ),
+                &[&import_id, &total, &fingerprint],
+            ).await?;
+            let row = tx.query_one(
+                &*self.sql("SELECT committed, total, fingerprint FROM bulk_import_journal WHERE import_id = $1 FOR UPDATE"),
+                &[&import_id],
+            ).await?;
+            let committed: i64 = row.get(0);
+            let journaled: (Option<i64>, Option<Vec<u8>>) = (row.get(1), row.get(2));
+            // Imports journaled before the fingerprint was kept can not be
+            // told apart and resume as before.
+            if journaled != (None, None) && journaled != (Some(total), Some(fingerprint.clone())) {
+                return Err(PostgresError::BulkImportMismatch(import_id.to_string()));
+            }
+            // A journal written by a 64-bit process may count past what a
+            // 32-bit one can index; it is past the end either way.
+            let offset = usize::try_from(committed).unwrap_or(usize::MAX);
+            if report.batches_committed == 0 {
+                report.resumed_from = committed as u64;
+                progress.resume(&mutations[..offset.min(mutations.len())]);
+            }
+            if offset >= mutations.len() {
+                tx.commit().await?;
+                return Ok(report);
+            }
+            let end = (offset + batch_size).min(mutations.len());
+
+            let sample = self.statement_log.begin("increment_version", Vec::new);
+            let new_version: i64 = tx.query_one(&statements.increment_version, &[]).await?.get(0);
+            sample.finish(1);
+            let versionstamp = version_to_versionstamp(new_version);
+
+            self.apply_mutations(&tx, &statements, &mutations[offset..end], &versionstamp, &mut progress).await?;
+            change_notify::notify(&tx, &self.channel, &mutations[offset..end]).await?;
 
             tx.execute(
-                r#"
-                INSERT INTO queue_messages (payload, deadline, keys_if_undelivered, backoff_schedule)
-                VALUES ($1, $2, $3, $4)
-                "#,
-                &[&enqueue.payload, &enqueue.deadline.timestamp_millis(), &keys_json, &backoff_json],
+                &*self.sql("UPDATE bulk_import_journal SET committed = $2, updated_at = NOW() WHERE import_id = $1"),
+                &[&import_id, &(end as i64)],
             ).await?;
-        }
+            tx.commit().await?;
+            progress.committed();
 
-        tx.commit().await?;
-        Ok(Some(CommitResult { versionstamp }))
+            report.batches_committed += 1;
+            report.mutations_applied += (end - offset) as u64;
+        }
     }
 
-    /// Handle sum mutation
-    async fn handle_sum_mutation(
+    /// Combine the LE64 value of `key`, if any, with `value`.
+    async fn handle_le64_mutation(
         &self,
         tx: &tokio_postgres::Transaction<'_>,
         key: &[u8],
+        op: Le64Op,
         value: &KvValue,
         versionstamp: &Versionstamp,
     ) -> PostgresResult<()> {
-        let (value_bytes, encoding) = self.encode_value(value);
-        
-        if encoding != 2 {
-            return Err(PostgresError::InvalidData("Sum operation only supports U64 values".to_string()));
-        }
+        let operand = op.operand(value)?;
 
-        let sum_value = match value {
-            KvValue::U64(v) => *v as i64,
-            _ => return Err(PostgresError::InvalidData("Sum operation only supports U64 values".to_string())),
-        };
+        let current = self.get_value(tx, key).await?;
+        let current = op.current(current)?;
+        let new_value_bytes = op.apply(current.as_deref(), operand).to_le_bytes().to_vec();
 
-        // First, try to get the current value
-        let current_row = tx.query_opt(
-            "SELECT value FROM kv_store WHERE key = $1 AND value_encoding = 2",
-            &[&key],
-        ).await?;
-
-        let new_value = if let Some(row) = current_row {
-            // Parse current value as i64 and add sum_value
-            let current_bytes: Vec<u8> = row.get(0);
-            if current_bytes.len() == 8 {
-                let mut bytes_array = [0u8; 8];
-                bytes_array.copy_from_slice(&current_bytes);
-                let current_int = i64::from_le_bytes(bytes_array);
-                current_int + sum_value
-            } else {
-                sum_value
-            }
-        } else {
-            sum_value
-        };
-
-        let new_value_bytes = new_value.to_le_bytes().to_vec();
-
-        tx.execute(
-            r#"
-            INSERT INTO kv_store (key, value, value_encoding, versionstamp, updated_at)
-            VALUES ($1, $2, 2, $3, NOW())
-            ON CONFLICT (key) DO UPDATE SET
-                value = $2,
-                versionstamp = EXCLUDED.versionstamp,
-                updated_at = NOW()
-            WHERE kv_store.value_encoding = 2
-            "#,
-            &[&key, &new_value_bytes, &versionstamp.as_slice()],
+        let sample = self.statement_log.begin("set_le64", || vec![key.len(), 8, 10, 8]);
+        let rows = tx.execute(
+            &*self.sql(driver::SET_LE64),
+            &[&key, &new_value_bytes, &versionstamp.as_slice(), &row_checksum(key, &new_value_bytes, 2)],
         ).await?;
+        sample.finish(rows);
 
         Ok(())
     }
 
-    /// Handle min mutation
-    async fn handle_min_mutation(
+    /// Replace the value of `key` with the V8 sum `sum` computes from it.
+    async fn handle_sum_v8_mutation(
         &self,
         tx: &tokio_postgres::Transaction<'_>,
+        statements: &WriteStatements,
         key: &[u8],
-        value: &KvValue,
         versionstamp: &Versionstamp,
+        sum: impl FnOnce(Option<(Vec<u8>, i32)>) -> PostgresResult<KvValue>,
     ) -> PostgresResult<()> {
-        let (value_bytes, encoding) = self.encode_value(value);
-        
-        if encoding != 2 {
-            return Err(PostgresError::InvalidData("Min operation only supports U64 values".to_string()));
-        }
-
-        let min_value = match value {
-            KvValue::U64(v) => *v as i64,
-            _ => return Err(PostgresError::InvalidData("Min operation only supports U64 values".to_string())),
-        };
-
-        // First, try to get the current value
-        let current_row = tx.query_opt(
-            "SELECT value FROM kv_store WHERE key = $1 AND value_encoding = 2",
-            &[&key],
-        ).await?;
-
-        let new_value = if let Some(row) = current_row {
-            // Parse current value as i64 and take minimum
-            let current_bytes: Vec<u8> = row.get(0);
-            if current_bytes.len() == 8 {
-                let mut bytes_array = [0u8; 8];
-                bytes_array.copy_from_slice(&current_bytes);
-                let current_int = i64::from_le_bytes(bytes_array);
-                current_int.min(min_value)
-            } else {
-                min_value
-            }
-        } else {
-            min_value
-        };
-
-        let new_value_bytes = new_value.to_le_bytes().to_vec();
-
-        tx.execute(
-            r#"
-            INSERT INTO kv_store (key, value, value_encoding, versionstamp, updated_at)
-            VALUES ($1, $2, 2, $3, NOW())
-            ON CONFLICT (key) DO UPDATE SET
-                value = $2,
-                versionstamp = EXCLUDED.versionstamp,
-                updated_at = NOW()
-            WHERE kv_store.value_encoding = 2
-            "#,
-            &[&key, &new_value_bytes, &versionstamp.as_slice()],
+        let current = self.get_value(tx, key).await?;
+        let value = sum(current)?;
+        let (value_bytes, encoding) = self.encode_value(&value);
+        let value_bytes: &[u8] = &value_bytes;
+
+        let sample = self.statement_log.begin("set", || vec![key.len(), value_bytes.len(), 4, 10, 8, 8]);
+        let rows = tx.execute(
+            &statements.set,
+            &[&key, &value_bytes, &encoding, &versionstamp.as_slice(), &None::<i64>, &row_checksum(key, value_bytes, encoding)],
         ).await?;
+        sample.finish(rows);
 
         Ok(())
     }
 
-    /// Handle max mutation
-    async fn handle_max_mutation(
+    /// The value of `key` and its encoding, for read-modify-write mutations.
+    async fn get_value(
         &self,
         tx: &tokio_postgres::Transaction<'_>,
         key: &[u8],
-        value: &KvValue,
-        versionstamp: &Versionstamp,
-    ) -> PostgresResult<()> {
-        let (value_bytes, encoding) = self.encode_value(value);
-        
-        if encoding != 2 {
-            return Err(PostgresError::InvalidData("Max operation only supports U64 values".to_string()));
-        }
-
-        let max_value = match value {
-            KvValue::U64(v) => *v as i64,
-            _ => return Err(PostgresError::InvalidData("Max operation only supports U64 values".to_string())),
-        };
-
-        // First, try to get the current value
-        let current_row = tx.query_opt(
-            "SELECT value FROM kv_store WHERE key = $1 AND value_encoding = 2",
-            &[&key],
-        ).await?;
-
-        let new_value = if let Some(row) = current_row {
-            // Parse current value as i64 and take maximum
-            let current_bytes: Vec<u8> = row.get(0);
-            if current_bytes.len() == 8 {
-                let mut bytes_array = [0u8; 8];
-                bytes_array.copy_from_slice(&current_bytes);
-                let current_int = i64::from_le_bytes(bytes_array);
-                current_int.max(max_value)
-            } else {
-                max_value
-            }
-        } else {
-            max_value
-        };
-
-        let new_value_bytes = new_value.to_le_bytes().to_vec();
-
-        tx.execute(
-            r#"
-            INSERT INTO kv_store (key, value, value_encoding, versionstamp, updated_at)
-            VALUES ($1, $2, 2, $3, NOW())
-            ON CONFLICT (key) DO UPDATE SET
-                value = $2,
-                versionstamp = EXCLUDED.versionstamp,
-                updated_at = NOW()
-            WHERE kv_store.value_encoding = 2
-            "#,
-            &[&key, &new_value_bytes, &versionstamp.as_slice()],
-        ).await?;
-
-        Ok(())
+    ) -> PostgresResult<Option<(Vec<u8>, i32)>> {
+        let sample = self.statement_log.begin("get_value", || vec![key.len()]);
+        let row = tx.query_opt(&*self.sql(driver::GET_VALUE), &[&key]).await?;
+        sample.finish(row.is_some() as u64);
+        Ok(row.map(|row| (row.get(0), row.get(1))))
     }
 
     /// Dequeue the next message from the queue
+    /// The deadline of the next message of `namespace` that is not running,
+    /// in milliseconds since the epoch.
+    pub async fn next_message_deadline(&self, namespace: Option<&[u8]>) -> PostgresResult<Option<i64>> {
+        let conn = self.pool.get().await?;
+        Ok(conn.query_one(&*self.sql(driver::NEXT_DEADLINE), &[&namespace]).await?.get(0))
+    }
+
     pub async fn dequeue_next_message(
         &self,
         conn: &mut Client,
+        namespace: Option<&[u8]>,
     ) -> PostgresResult<Option<PostgresMessageHandle>> {
         let tx = conn.transaction().await?;
+        let now_ms = crate::time::utc_now().timestamp_millis();
 
         // Find the next message to process
-        let row = tx.query_opt(
-            r#"
-            SELECT id, payload, deadline, keys_if_undelivered, backoff_schedule
-            FROM queue_messages
-            WHERE deadline <= NOW()
-            AND id NOT IN (SELECT message_id FROM queue_running)
-            ORDER BY deadline ASC
-            LIMIT 1
-            FOR UPDATE SKIP LOCKED
-            "#,
-            &[],
-        ).await?;
+        let sample = self.statement_log.begin("dequeue", || vec![8]);
+        let row = tx.query_opt(&*self.sql(driver::DEQUEUE), &[&now_ms, &namespace]).await?;
+        sample.finish(row.is_some() as u64);
 
         if let Some(row) = row {
-            let id_str: String = row.get("id");
-            let id = uuid::Uuid::parse_str(&id_str)?;
+            let id: uuid::Uuid = row.get("id");
             let payload: Vec<u8> = row.get("payload");
-            let deadline_str: String = row.get("deadline");
-            let deadline_naive = chrono::NaiveDateTime::parse_from_str(&deadline_str, "%Y-%m-%d %H:%M:%S%.f")
-                .map_err(|e| PostgresError::InvalidData(format!("Invalid deadline format: {}", e)))?;
-            let deadline: DateTime<Utc> = DateTime::from_naive_utc_and_offset(deadline_naive, Utc);
-            let keys_json: String = row.get("keys_if_undelivered");
-            let keys_if_undelivered: Vec<Vec<u8>> = serde_json::from_str(&keys_json)?;
-            let backoff_json: Option<String> = row.get("backoff_schedule");
-            let backoff_schedule: Option<Vec<u32>> = if let Some(json) = backoff_json {
-                Some(serde_json::from_str(&json)?)
-            } else {
-                None
-            };
 
-            // Move to running table
-            tx.execute(
-                r#"
-                INSERT INTO queue_running (message_id, deadline, started_at, updated_at)
-                VALUES ($1, $2, NOW(), NOW())
-                "#,
-                &[&id_str, &deadline_str],
-            ).await?;
+            // Move to running table. Messages still running past this
+            // deadline are requeued by queue_cleanup.
+            let running_deadline = queue_running_deadline(self.config.queue_visibility_timeout, now_ms);
+            tx.execute(&*self.sql(driver::START_RUNNING), &[&id, &running_deadline]).await?;
 
             tx.commit().await?;
 
+            let pool = self.pool.clone();
+            let extend_running = self.sql(driver::EXTEND_RUNNING).into_owned();
+            let keepalive = Keepalive::spawn(self.config.queue_visibility_timeout, move |deadline| {
+                let pool = pool.clone();
+                let extend_running = extend_running.clone();
+                async move {
+                    let conn = pool.get().await?;
+                    Ok(conn.execute(&*extend_running, &[&id, &deadline]).await? > 0)
+                }
+            });
+
             Ok(Some(PostgresMessageHandle {
                 id,
                 payload: Some(payload),
                 pool: self.pool.clone(),
+                tables: self.tables.clone(),
+                max_delivery_attempts: self.config.max_delivery_attempts,
+                keepalive,
             }))
         } else {
             Ok(None)
         }
     }
 
-    /// Delete all expired keys. Returns the number of rows removed.
-    pub async fn collect_expired(&self) -> PostgresResult<u64> {
+    /// Delete up to `limit` expired keys, those that expired first first.
+    /// Returns the number of rows removed.
+    pub async fn collect_expired(&self, limit: i64) -> PostgresResult<u64> {
         let conn = self.pool.get().await?;
         let now_ms = crate::time::utc_now().timestamp_millis();
-        let deleted = conn.execute(
-            "DELETE FROM kv_store WHERE expires_at IS NOT NULL AND expires_at <= $1",
-            &[&now_ms],
-        ).await?;
+        let sample = self.statement_log.begin("collect_expired", || vec![8, 8]);
+        let deleted = conn.execute(&*self.sql(driver::COLLECT_EXPIRED), &[&now_ms, &limit]).await?;
+        sample.finish(deleted);
         Ok(deleted)
     }
 
+    /// Delete tombstones older than the configured retention and move the
+    /// tombstone horizon past them. Returns the number of tombstones removed.
+    pub async fn trim_tombstones(&self) -> PostgresResult<u64> {
+        let conn = self.pool.get().await?;
+        let retention = self.config.tombstone_retention as f64;
+        let sample = self.statement_log.begin("trim_tombstones", || vec![8]);
+        let row = conn.query_one(
+            &*self.sql(r#"
+            WITH trimmed AS (
+                DELETE FROM kv_tombstones
+                WHERE deleted_at < NOW() - make_interval(secs => $1)
+                RETURNING versionstamp
+            ), horizon AS (
+                UPDATE data_version
+                SET tombstone_horizon = GREATEST(tombstone_horizon, (SELECT max(versionstamp) FROM trimmed))
+                WHERE k = 0 AND EXISTS (SELECT 1 FROM trimmed)
+            )
+            SELECT count(*) FROM trimmed
+            "#),
+            &[&retention],
+        ).await?;
+        let trimmed = row.get::<_, i64>(0) as u64;
+        sample.finish(trimmed);
+        Ok(trimmed)
+    }
+
     /// Requeue messages stuck in queue_running past their deadline.
     /// This recovers from dead workers that never finished their messages.
     /// Returns the number of messages requeued.
@@ -556,48 +754,41 @@ impl PostgresBackend {
         let now_ms = crate::time::utc_now().timestamp_millis();
 
         // Find running messages past their deadline (dead worker recovery)
-        let rows = tx.query(
-            "SELECT message_id FROM queue_running WHERE deadline <= $1 LIMIT 100",
-            &[&now_ms],
-        ).await?;
+        let rows = tx.query(&*self.sql(driver::OVERDUE_RUNNING), &[&now_ms]).await?;
 
         let mut requeued = 0u64;
         for row in &rows {
-            let message_id: String = row.get("message_id");
+            let message_id: uuid::Uuid = row.get("message_id");
 
             // Fetch the original message to get backoff info
-            let msg_row = tx.query_opt(
-                r#"SELECT backoff_schedule, retry_count
-                   FROM queue_messages WHERE id = $1"#,
-                &[&message_id],
-            ).await?;
+            let msg_row = tx.query_opt(&*self.sql(driver::FAILED_MESSAGE), &[&message_id]).await?;
 
             // Remove from running table
-            tx.execute("DELETE FROM queue_running WHERE message_id = $1", &[&message_id]).await?;
+            tx.execute(&*self.sql(driver::STOP_RUNNING), &[&message_id]).await?;
 
             if let Some(msg) = msg_row {
-                let backoff_json: Option<String> = msg.get("backoff_schedule");
+                let metadata = message_metadata(&msg)?;
                 let retry_count: i32 = msg.get("retry_count");
-                let backoff_schedule: Vec<u64> = backoff_json
-                    .and_then(|j| serde_json::from_str(&j).ok())
-                    .unwrap_or_default();
 
-                if !backoff_schedule.is_empty() {
-                    let delay_ms = backoff_schedule[0] as i64;
-                    let new_deadline = now_ms + delay_ms;
-                    let remaining = serde_json::to_string(&backoff_schedule[1..])
-                        .unwrap_or_else(|_| "[]".to_string());
-
-                    tx.execute(
-                        r#"UPDATE queue_messages
-                           SET deadline = $1, backoff_schedule = $2, retry_count = $3
-                           WHERE id = $4"#,
-                        &[&new_deadline, &remaining, &(retry_count + 1), &message_id],
-                    ).await?;
-                    requeued += 1;
-                } else {
-                    // No retries left — delete the message
-                    tx.execute("DELETE FROM queue_messages WHERE id = $1", &[&message_id]).await?;
+                match driver::failure(&metadata.backoff_schedule, retry_count, self.config.max_delivery_attempts, now_ms) {
+                    driver::Failure::Retry(new_deadline, remaining) => {
+                        tx.execute(
+                            &*self.sql(driver::RETRY_MESSAGE),
+                            &[&new_deadline, &metadata.retried(remaining).encode(), &METADATA_ENCODING, &(retry_count + 1), &message_id],
+                        ).await?;
+                        change_notify::notify_queue(&tx, &self.tables.queue_channel()).await?;
+                        requeued += 1;
+                    }
+                    driver::Failure::DeadLetter => {
+                        tx.execute(&*self.sql(driver::DEAD_LETTER_MESSAGE), &[&message_id]).await?;
+                    }
+                    driver::Failure::Undelivered => {
+                        // No retries left — deliver the payload to
+                        // keys_if_undelivered and delete the message
+                        let payload: Vec<u8> = msg.get("payload");
+                        set_undelivered(&tx, &self.tables, &metadata.keys_if_undelivered, &payload).await?;
+                        tx.execute(&*self.sql(driver::DELETE_MESSAGE), &[&message_id]).await?;
+                    }
                 }
             }
         }
@@ -606,24 +797,1047 @@ impl PostgresBackend {
         Ok(requeued)
     }
 
-    /// Encode a value for storage
-    fn encode_value(&self, value: &KvValue) -> (Vec<u8>, i32) {
-        match value {
-            KvValue::V8(v) => (v.clone(), 1),
-            KvValue::Bytes(v) => (v.clone(), 3),
-            KvValue::U64(v) => {
-                let mut buf = [0; 8];
-                buf.copy_from_slice(&v.to_le_bytes());
-                (buf.to_vec(), 2)
+    /// Up to `limit` dead-lettered messages, those that failed first first.
+    pub async fn dead_letters(&self, limit: i64) -> PostgresResult<Vec<DeadLetter>> {
+        let conn = self.pool.get().await?;
+        let rows = conn.query(
+            &*self.sql(r#"
+            SELECT id, payload, metadata, metadata_encoding, keys_if_undelivered, backoff_schedule, namespace,
+                priority, attempts,
+                (EXTRACT(EPOCH FROM enqueued_at) * 1000)::int8 AS enqueued_at_ms,
+                (EXTRACT(EPOCH FROM failed_at) * 1000)::int8 AS failed_at_ms
+            FROM queue_dead_letter
+            ORDER BY failed_at, id
+            LIMIT $1
+            "#),
+            &[&limit],
+        ).await?;
+        rows.iter().map(dead_letter).collect()
+    }
+
+    /// Move the dead-lettered message `id` back into the queue, due now,
+    /// with the backoff schedule it had left and its attempts counted from
+    /// zero. Returns false if there is no such message.
+    pub async fn requeue_dead_letter(&self, id: &Uuid) -> PostgresResult<bool> {
+        let conn = self.pool.get().await?;
+        let now_ms = crate::time::utc_now().timestamp_millis();
+        let requeued = conn.execute(
+            &*self.sql(r#"
+            WITH requeued AS (DELETE FROM queue_dead_letter WHERE id = $1 RETURNING *)
+            INSERT INTO queue_messages (
+                id, payload, deadline, metadata, metadata_encoding, keys_if_undelivered, backoff_schedule, namespace,
+                priority
+            )
+            SELECT id, payload, $2, metadata, metadata_encoding, keys_if_undelivered, backoff_schedule, namespace,
+                priority
+            FROM requeued
+            "#),
+            &[id, &now_ms],
+        ).await?;
+        if requeued > 0 {
+            conn.execute(driver::NOTIFY, &[&self.tables.queue_channel(), &""]).await?;
+        }
+        Ok(requeued > 0)
+    }
+
+    /// Delete the dead-lettered message `id`. Returns false if there is no
+    /// such message.
+    pub async fn delete_dead_letter(&self, id: &Uuid) -> PostgresResult<bool> {
+        let conn = self.pool.get().await?;
+        let deleted = conn.execute(&*self.sql("DELETE FROM queue_dead_letter WHERE id = $1"), &[id]).await?;
+        Ok(deleted > 0)
+    }
+
+    /// Register the durable subscription `name` for keys starting with
+    /// `prefix`. A new subscription starts at the current version, so only
+    /// later writes are delivered. Registering an existing subscription
+    /// again keeps its position; registering it with a different prefix is
+    /// an error.
+    pub async fn register_subscription(&self, name: &str, prefix: &[u8]) -> PostgresResult<()> {
+        let conn = self.pool.get().await?;
+        let row = conn.query_one(
+            &*self.sql(r#"
+            WITH current AS (SELECT version FROM data_version WHERE k = 0)
+            INSERT INTO subscriptions (name, prefix, last_versionstamp)
+            SELECT $1, $2, int8send(version) || '\x0000'::bytea FROM current
+            ON CONFLICT (name) DO UPDATE SET name = EXCLUDED.name
+            RETURNING prefix
+            "#),
+            &[&name, &prefix],
+        ).await?;
+        let existing: &[u8] = row.get("prefix");
+        if existing != prefix {
+            return Err(PostgresError::InvalidConfig(format!(
+                "Subscription {name:?} is already registered with prefix {existing:?}"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Fetch up to `limit` entries under the subscription's prefix that were
+    /// written after its cursor, in commit order. Keys written several times
+    /// since the cursor are delivered once, with their latest value, and
+    /// deletions are not delivered. The cursor is not moved; see
+    /// `ack_subscription`.
+    pub async fn poll_subscription(&self, name: &str, limit: i64) -> PostgresResult<Vec<KvEntry>> {
+        let conn = self.pool.get().await?;
+        let Some(subscription) = conn.query_opt(
+            &*self.sql("SELECT prefix, last_versionstamp, last_key FROM subscriptions WHERE name = $1"),
+            &[&name],
+        ).await? else {
+            return Err(PostgresError::InvalidData(format!("Unknown subscription {name:?}")));
+        };
+        let prefix: &[u8] = subscription.get("prefix");
+        let end = crate::partition::prefix_upper_bound(prefix);
+        let last_versionstamp: &[u8] = subscription.get("last_versionstamp");
+        let last_key: &[u8] = subscription.get("last_key");
+        let now_ms = crate::time::utc_now().timestamp_millis();
+
+        let sample = self.statement_log.begin("poll_subscription", || {
+            vec![prefix.len(), end.as_ref().map_or(0, Vec::len), 10, last_key.len(), 8, 8]
+        });
+        let rows = conn.query(
+            &*self.sql(r#"
+            SELECT key, value, value_encoding, versionstamp, checksum
+            FROM kv_store
+            WHERE key >= $1 AND ($2::bytea IS NULL OR key < $2)
+              AND (versionstamp, key) > ($3, $4)
+              AND (expires_at IS NULL OR expires_at > $5)
+            ORDER BY versionstamp, key
+            LIMIT $6
+            "#),
+            &[&prefix, &end, &last_versionstamp, &last_key, &now_ms, &limit],
+        ).await?;
+        sample.finish(rows.len() as u64);
+
+        rows.iter().map(|row| self.decode_row(row)).collect()
+    }
+
+    /// Fetch up to `limit` changes to keys starting with `prefix` that
+    /// were committed after the change at `after_key` and `after`, in commit
+    /// order. Unlike `poll_subscription` this delivers deletions, including
+    /// keys that have expired but not been collected yet, and expects the
+    /// caller to keep track of its position. Starting from the zero
+    /// versionstamp returns the whole keyspace under `prefix`.
+    ///
+    /// Fails with `ChangefeedTruncated` if deletions after the position have
+    /// already been trimmed.
+    pub async fn poll_changes(
+        &self,
+        prefix: &[u8],
+        after: &Versionstamp,
+        after_key: &[u8],
+        limit: i64,
+    ) -> PostgresResult<Vec<KvChange>> {
+        let conn = self.pool.get().await?;
+        let horizon: Vec<u8> = conn.query_one(
+            &*self.sql("SELECT tombstone_horizon FROM data_version WHERE k = 0"),
+            &[],
+        ).await?.get(0);
+        if *after != [0; 10] && after.as_slice() < horizon.as_slice() {
+            return Err(PostgresError::ChangefeedTruncated);
+        }
+
+        let end = crate::partition::prefix_upper_bound(prefix);
+        let sample = self.statement_log.begin("poll_changes", || {
+            vec![prefix.len(), end.as_ref().map_or(0, Vec::len), 10, after_key.len(), 8]
+        });
+        // A key deleted and set again by the same write has a tombstone at
+        // the same versionstamp as its row; the row wins.
+        let rows = conn.query(
+            &*self.sql(r#"
+            SELECT key, value, value_encoding, versionstamp, checksum, expires_at
+            FROM (
+                SELECT key, value, value_encoding, versionstamp, checksum, expires_at
+                FROM kv_store
+                WHERE key >= $1 AND ($2::bytea IS NULL OR key < $2)
+                  AND (versionstamp, key) > ($3, $4)
+                UNION ALL
+                SELECT t.key, NULL, NULL, t.versionstamp, NULL, NULL
+                FROM kv_tombstones t
+                WHERE t.key >= $1 AND ($2::bytea IS NULL OR t.key < $2)
+                  AND (t.versionstamp, t.key) > ($3, $4)
+                  AND NOT EXISTS (
+                      SELECT 1 FROM kv_store s
+                      WHERE s.key = t.key AND s.versionstamp >= t.versionstamp
+                  )
+            ) changes
+            ORDER BY versionstamp, key
+            LIMIT $5
+            "#),
+            &[&prefix, &end, &after.as_slice(), &after_key, &limit],
+        ).await?;
+        sample.finish(rows.len() as u64);
+
+        let now_ms = crate::time::utc_now().timestamp_millis();
+        rows.iter()
+            .map(|row| {
+                let expires_at: Option<i64> = row.get("expires_at");
+                let deleted = row.get::<_, Option<&[u8]>>("value").is_none();
+                let expired = expires_at.is_some_and(|ms| ms <= now_ms);
+                if deleted || expired {
+                    return Ok(KvChange {
+                        key: row.get("key"),
+                        value: None,
+                        versionstamp: decode_versionstamp(row.get("versionstamp"))
+                            .map_err(|kind| PostgresError::CorruptRow { key: row.get("key"), kind })?,
+                        expire_at: None,
+                    });
+                }
+                let entry = self.decode_row(row)?;
+                Ok(KvChange {
+                    key: entry.key,
+                    value: Some(entry.value),
+                    versionstamp: entry.versionstamp,
+                    expire_at: expires_at.and_then(|ms| Utc.timestamp_millis_opt(ms).single()),
+                })
+            })
+            .collect()
+    }
+
+    /// Record that everything up to and including the entry at `key` and
+    /// `versionstamp` has been delivered to the subscription. The cursor
+    /// never moves backwards.
+    pub async fn ack_subscription(&self, name: &str, versionstamp: &Versionstamp, key: &[u8]) -> PostgresResult<()> {
+        let conn = self.pool.get().await?;
+        conn.execute(
+            &*self.sql(r#"
+            UPDATE subscriptions
+            SET last_versionstamp = $2, last_key = $3, updated_at = NOW()
+            WHERE name = $1 AND (last_versionstamp, last_key) < ($2, $3)
+            "#),
+            &[&name, &versionstamp.as_slice(), &key],
+        ).await?;
+        Ok(())
+    }
+
+    /// Remove a durable subscription. Returns whether it existed.
+    pub async fn delete_subscription(&self, name: &str) -> PostgresResult<bool> {
+        let conn = self.pool.get().await?;
+        let rows = conn.execute(&*self.sql("DELETE FROM subscriptions WHERE name = $1"), &[&name]).await?;
+        Ok(rows > 0)
+    }
+
+    /// Register the materialized view `name` from keys under `source_prefix`
+    /// to keys under `target_prefix`. A new view starts at the zero
+    /// versionstamp, so it is built from everything already under the
+    /// source prefix. Registering an existing view again keeps its position;
+    /// registering it with different prefixes is an error.
+    pub async fn register_view(&self, name: &str, source_prefix: &[u8], target_prefix: &[u8]) -> PostgresResult<()> {
+        let conn = self.pool.get().await?;
+        let row = conn.query_one(
+            &*self.sql(r#"
+            INSERT INTO materialized_views (name, source_prefix, target_prefix, last_versionstamp)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (name) DO UPDATE SET name = EXCLUDED.name
+            RETURNING source_prefix, target_prefix
+            "#),
+            &[&name, &source_prefix, &target_prefix, &[0u8; 10].as_slice()],
+        ).await?;
+        let existing: (&[u8], &[u8]) = (row.get("source_prefix"), row.get("target_prefix"));
+        if existing != (source_prefix, target_prefix) {
+            return Err(PostgresError::InvalidConfig(format!(
+                "View {name:?} is already registered from {:?} to {:?}", existing.0, existing.1
+            )));
+        }
+        Ok(())
+    }
+
+    /// The position of the view `name` in the change feed of its source.
+    pub async fn view_cursor(&self, name: &str) -> PostgresResult<(Versionstamp, Vec<u8>)> {
+        let conn = self.pool.get().await?;
+        let Some(row) = conn.query_opt(
+            &*self.sql("SELECT last_versionstamp, last_key FROM materialized_views WHERE name = $1"),
+            &[&name],
+        ).await? else {
+            return Err(PostgresError::InvalidData(format!("Unknown view {name:?}")));
+        };
+        let versionstamp = decode_versionstamp(row.get("last_versionstamp"))
+            .map_err(|kind| PostgresError::CorruptRow { key: Vec::new(), kind })?;
+        Ok((versionstamp, row.get("last_key")))
+    }
+
+    /// Replace the view entries derived from each source key in `rows`,
+    /// and move the cursor of the view `name` from `after` to `next`, in
+    /// one transaction. Returns the view keys that were written or deleted,
+    /// or `None` if the cursor was no longer at `after` because another
+    /// maintainer of the view got there first.
+    pub async fn apply_view_batch(
+        &self,
+        conn: &mut Client,
+        name: &str,
+        after: (&Versionstamp, &[u8]),
+        next: (&Versionstamp, &[u8]),
+        rows: &[ViewRow],
+    ) -> PostgresResult<Option<Vec<Vec<u8>>>> {
+        let statements = self.write_statements(conn).await?;
+        let tx = conn.transaction().await?;
+
+        let Some(view) = tx.query_opt(
+            &*self.sql("SELECT target_prefix, last_versionstamp, last_key FROM materialized_views WHERE name = $1 FOR UPDATE"),
+            &[&name],
+        ).await? else {
+            return Err(PostgresError::InvalidData(format!("Unknown view {name:?}")));
+        };
+        let cursor: (&[u8], &[u8]) = (view.get("last_versionstamp"), view.get("last_key"));
+        if cursor != (after.0.as_slice(), after.1) {
+            return Ok(None);
+        }
+        let target_prefix: &[u8] = view.get("target_prefix");
+
+        let mut mutations = Vec::new();
+        for row in rows {
+            let previous: Vec<Vec<u8>> = tx.query_opt(
+                &*self.sql("SELECT target_keys FROM materialized_view_keys WHERE view_name = $1 AND source_key = $2"),
+                &[&name, &row.source_key],
+            ).await?.map(|r| r.get(0)).unwrap_or_default();
+            let entries: Vec<(Vec<u8>, &KvValue)> = row.entries.iter()
+                .map(|(suffix, value)| ([target_prefix, suffix].concat(), value))
+                .collect();
+
+            for key in previous {
+                if !entries.iter().any(|(k, _)| *k == key) {
+                    mutations.push(Mutation { key, kind: MutationKind::Delete, expire_at: None });
+                }
+            }
+            for (key, value) in &entries {
+                mutations.push(Mutation {
+                    key: key.clone(),
+                    kind: MutationKind::Set((*value).clone()),
+                    expire_at: None,
+                });
+            }
+
+            let target_keys: Vec<Vec<u8>> = entries.into_iter().map(|(key, _)| key).collect();
+            if target_keys.is_empty() {
+                tx.execute(
+                    &*self.sql("DELETE FROM materialized_view_keys WHERE view_name = $1 AND source_key = $2"),
+                    &[&name, &row.source_key],
+                ).await?;
+            } else {
+                tx.execute(
+                    &*self.sql(r#"
+                    INSERT INTO materialized_view_keys (view_name, source_key, target_keys)
+                    VALUES ($1, $2, $3)
+                    ON CONFLICT (view_name, source_key) DO UPDATE SET target_keys = EXCLUDED.target_keys
+                    "#),
+                    &[&name, &row.source_key, &target_keys],
+                ).await?;
+            }
+        }
+
+        if !mutations.is_empty() {
+            let sample = self.statement_log.begin("increment_version", Vec::new);
+            let new_version: i64 = tx.query_one(&statements.increment_version, &[]).await?.get(0);
+            sample.finish(1);
+            let versionstamp = version_to_versionstamp(new_version);
+            self.apply_mutations(&tx, &statements, &mutations, &versionstamp, &mut ProgressReporter::none()).await?;
+        }
+
+        tx.execute(
+            &*self.sql("UPDATE materialized_views SET last_versionstamp = $2, last_key = $3, updated_at = NOW() WHERE name = $1"),
+            &[&name, &next.0.as_slice(), &next.1],
+        ).await?;
+        tx.commit().await?;
+        Ok(Some(mutations.into_iter().map(|m| m.key).collect()))
+    }
+
+    /// Take the lease on the backfill job `name` for `owner` for
+    /// [`LEASE_TTL`], registering the job if it is new. Returns the job's
+    /// progress, or `None` if another owner holds the lease or the job is
+    /// done.
+    pub async fn claim_backfill(&self, name: &str, owner: &Uuid) -> PostgresResult<Option<BackfillProgress>> {
+        let conn = self.pool.get().await?;
+        conn.execute(&*self.sql("INSERT INTO backfill_jobs (name) VALUES ($1) ON CONFLICT DO NOTHING"), &[&name]).await?;
+        let row = conn.query_opt(
+            &*self.sql(r#"
+            UPDATE backfill_jobs
+            SET lease_owner = $2, lease_expires_at = NOW() + make_interval(secs => $3), updated_at = NOW()
+            WHERE name = $1 AND NOT done AND (lease_owner IS NULL OR lease_owner = $2 OR lease_expires_at <= NOW())
+            RETURNING last_key, processed, rewritten, done
+            "#),
+            &[&name, owner, &LEASE_TTL.as_secs_f64()],
+        ).await?;
+        Ok(row.as_ref().map(backfill_progress))
+    }
+
+    /// The progress of the backfill job `name`, or `None` if it was never
+    /// claimed.
+    pub async fn backfill_progress(&self, name: &str) -> PostgresResult<Option<BackfillProgress>> {
+        let conn = self.pool.get().await?;
+        let row = conn.query_opt(
+            &*self.sql("SELECT last_key, processed, rewritten, done FROM backfill_jobs WHERE name = $1"),
+            &[&name],
+        ).await?;
+        Ok(row.as_ref().map(backfill_progress))
+    }
+
+    /// Apply `mutations` if every one of `checks` passes, and move the
+    /// checkpoint of the backfill job `name` to `next`, in one transaction,
+    /// renewing the lease of `owner`. The lease is released once
+    /// `next` is done.
+    pub async fn apply_backfill_batch(
+        &self,
+        conn: &mut Client,
+        name: &str,
+        owner: &Uuid,
+        checks: &[Check],
+        mutations: &[Mutation],
+        next: &BackfillProgress,
+    ) -> PostgresResult<BackfillBatch> {
+        let statements = self.write_statements(conn).await?;
+        let tx = conn.transaction().await?;
+
+        let holder: Option<Uuid> = tx.query_opt(
+            &*self.sql("SELECT lease_owner FROM backfill_jobs WHERE name = $1 FOR UPDATE"),
+            &[&name],
+        ).await?.and_then(|row| row.get(0));
+        if holder.as_ref() != Some(owner) {
+            return Ok(BackfillBatch::LeaseLost);
+        }
+
+        if !mutations.is_empty() {
+            // Checks run after the counter is locked, as in atomic_write, so
+            // no write can land between them and the mutations.
+            let sample = self.statement_log.begin("increment_version", Vec::new);
+            let new_version: i64 = tx.query_one(&statements.increment_version, &[]).await?.get(0);
+            sample.finish(1);
+            let now_ms = crate::time::utc_now().timestamp_millis();
+            for check in checks {
+                let row = tx.query_opt(&statements.check, &[&check.key, &now_ms]).await?;
+                let current = row.map(|r| r.get::<_, Vec<u8>>("versionstamp"));
+                if !driver::check_passes(check.versionstamp.as_ref(), current.as_deref()) {
+                    return Ok(BackfillBatch::Conflict);
+                }
             }
+            self.apply_mutations(&tx, &statements, mutations, &version_to_versionstamp(new_version), &mut ProgressReporter::none()).await?;
         }
+
+        tx.execute(
+            &*self.sql(r#"
+            UPDATE backfill_jobs
+            SET last_key = $2, processed = $3, rewritten = $4, done = $5,
+                lease_owner = CASE WHEN $5 THEN NULL ELSE lease_owner END,
+                lease_expires_at = NOW() + make_interval(secs => $6), updated_at = NOW()
+            WHERE name = $1
+            "#),
+            &[&name, &next.after, &(next.processed as i64), &(next.rewritten as i64), &next.done, &LEASE_TTL.as_secs_f64()],
+        ).await?;
+        tx.commit().await?;
+        Ok(BackfillBatch::Committed(mutations.iter().map(|m| m.key.clone()).collect()))
+    }
+
+    /// Delete every view entry of the view `name`. With `unregister` the
+    /// view itself is removed too; otherwise its cursor goes back to the
+    /// zero versionstamp so that it is rebuilt. Returns the deleted view
+    /// keys, or `None` if the view does not exist.
+    pub async fn clear_view(&self, conn: &mut Client, name: &str, unregister: bool) -> PostgresResult<Option<Vec<Vec<u8>>>> {
+        let statements = self.write_statements(conn).await?;
+        let tx = conn.transaction().await?;
+        if tx.query_opt(&*self.sql("SELECT 1 FROM materialized_views WHERE name = $1 FOR UPDATE"), &[&name]).await?.is_none() {
+            return Ok(None);
+        }
+
+        let keys: Vec<Vec<u8>> = tx.query(
+            &*self.sql("DELETE FROM materialized_view_keys WHERE view_name = $1 RETURNING target_keys"),
+            &[&name],
+        ).await?.iter().flat_map(|row| row.get::<_, Vec<Vec<u8>>>(0)).collect();
+        if !keys.is_empty() {
+            let mutations: Vec<Mutation> = keys.iter()
+                .map(|key| Mutation { key: key.clone(), kind: MutationKind::Delete, expire_at: None })
+                .collect();
+            let sample = self.statement_log.begin("increment_version", Vec::new);
+            let new_version: i64 = tx.query_one(&statements.increment_version, &[]).await?.get(0);
+            sample.finish(1);
+            self.apply_mutations(&tx, &statements, &mutations, &version_to_versionstamp(new_version), &mut ProgressReporter::none()).await?;
+        }
+
+        if unregister {
+            tx.execute(&*self.sql("DELETE FROM materialized_views WHERE name = $1"), &[&name]).await?;
+        } else {
+            tx.execute(
+                &*self.sql("UPDATE materialized_views SET last_versionstamp = $2, last_key = '', updated_at = NOW() WHERE name = $1"),
+                &[&name, &[0u8; 10].as_slice()],
+            ).await?;
+        }
+        tx.commit().await?;
+        Ok(Some(keys))
+    }
+
+    /// Delete every key under `prefix` and every queue message enqueued in
+    /// the namespace `prefix`. Returns the number of keys and messages
+    /// removed.
+    pub async fn drop_namespace(&self, prefix: &[u8]) -> PostgresResult<u64> {
+        let mut conn = self.pool.get().await?;
+        let tx = conn.transaction().await?;
+        let end = crate::partition::prefix_upper_bound(prefix);
+        tx.execute(
+            &*self.sql("DELETE FROM queue_running WHERE message_id IN (SELECT id FROM queue_messages WHERE namespace = $1)"),
+            &[&prefix],
+        ).await?;
+        let messages = tx.execute(&*self.sql("DELETE FROM queue_messages WHERE namespace = $1"), &[&prefix]).await?;
+        let messages = messages + tx.execute(&*self.sql("DELETE FROM queue_dead_letter WHERE namespace = $1"), &[&prefix]).await?;
+        // Dropping the keys is a write like any other as far as change
+        // feeds are concerned.
+        let version: i64 = tx.query_one(&*self.sql(driver::INCREMENT_VERSION), &[]).await?.get(0);
+        let keys = tx.execute(
+            &*self.sql(r#"
+            WITH deleted AS (
+                DELETE FROM kv_store WHERE key >= $1 AND ($2::bytea IS NULL OR key < $2)
+                RETURNING key
+            )
+            INSERT INTO kv_tombstones (key, versionstamp)
+            SELECT key, $3 FROM deleted
+            ON CONFLICT (key) DO UPDATE SET
+                versionstamp = EXCLUDED.versionstamp,
+                deleted_at = NOW()
+            "#),
+            &[&prefix, &end, &version_to_versionstamp(version).as_slice()],
+        ).await?;
+        tx.commit().await?;
+        Ok(keys + messages)
+    }
+
+    /// Journal the deletion of everything under `prefix`, unless it is
+    /// pending already, starting it over if it completed before. Returns
+    /// its journal entry.
+    pub async fn request_namespace_deletion(&self, prefix: &[u8]) -> PostgresResult<NamespaceDeletion> {
+        let conn = self.pool.get().await?;
+        conn.execute(
+            &*self.sql(r#"
+            INSERT INTO namespace_deletions (prefix) VALUES ($1)
+            ON CONFLICT (prefix) DO UPDATE SET
+                deleted_keys = 0,
+                deleted_tombstones = 0,
+                deleted_view_keys = 0,
+                deleted_messages = 0,
+                requested_at = NOW(),
+                updated_at = NOW(),
+                completed_at = NULL
+            WHERE namespace_deletions.completed_at IS NOT NULL
+            "#),
+            &[&prefix],
+        ).await?;
+        let row = conn.query_one(
+            &*self.sql(&format!("SELECT {NAMESPACE_DELETION_COLUMNS} FROM namespace_deletions WHERE prefix = $1")),
+            &[&prefix],
+        ).await?;
+        Ok(namespace_deletion(&row))
+    }
+
+    /// The journal entries of namespace deletions, oldest request first,
+    /// only those not completed yet if `pending`.
+    pub async fn namespace_deletions(&self, pending: bool) -> PostgresResult<Vec<NamespaceDeletion>> {
+        let conn = self.pool.get().await?;
+        let rows = conn.query(
+            &*self.sql(&format!(
+                "SELECT {NAMESPACE_DELETION_COLUMNS} FROM namespace_deletions WHERE NOT $1 OR completed_at IS NULL ORDER BY requested_at, prefix"
+            )),
+            &[&pending],
+        ).await?;
+        Ok(rows.iter().map(namespace_deletion).collect())
+    }
+
+    /// The journal entry of the deletion of `prefix`, if one was requested.
+    pub async fn namespace_deletion(&self, prefix: &[u8]) -> PostgresResult<Option<NamespaceDeletion>> {
+        let conn = self.pool.get().await?;
+        let row = conn.query_opt(
+            &*self.sql(&format!("SELECT {NAMESPACE_DELETION_COLUMNS} FROM namespace_deletions WHERE prefix = $1")),
+            &[&prefix],
+        ).await?;
+        Ok(row.as_ref().map(namespace_deletion))
+    }
+
+    /// Delete up to `limit` rows of each kind under the namespace `prefix`,
+    /// adding them to its journal entry in the same transaction, and mark the
+    /// deletion complete if nothing was left. Returns the number of rows
+    /// deleted and the updated entry, or `None` if the deletion is not
+    /// pending.
+    ///
+    /// The keys and their tombstones are deleted without leaving tombstones,
+    /// as the namespace's history goes with it. Keys of materialized views
+    /// derived from keys under the prefix are deleted like any write, since
+    /// they may be read elsewhere.
+    pub async fn reap_namespace_batch(&self, prefix: &[u8], limit: i64) -> PostgresResult<Option<(u64, NamespaceDeletion)>> {
+        let mut conn = self.pool.get().await?;
+        let statements = self.write_statements(&conn).await?;
+        let tx = conn.transaction().await?;
+        // Locking the entry keeps two reapers of the same namespace from
+        // counting each other's rows.
+        let pending = tx.query_opt(
+            &*self.sql("SELECT 1 FROM namespace_deletions WHERE prefix = $1 AND completed_at IS NULL FOR UPDATE"),
+            &[&prefix],
+        ).await?.is_some();
+        if !pending {
+            return Ok(None);
+        }
+        let end = crate::partition::prefix_upper_bound(prefix);
+        let params: [&(dyn ToSql + Sync); 3] = [&prefix, &end, &limit];
+
+        let keys = tx.execute(
+            &*self.sql(r#"
+            DELETE FROM kv_store WHERE key IN (
+                SELECT key FROM kv_store
+                WHERE key >= $1 AND ($2::bytea IS NULL OR key < $2)
+                LIMIT $3
+            )
+            "#),
+            &params,
+        ).await?;
+        let tombstones = tx.execute(
+            &*self.sql(r#"
+            DELETE FROM kv_tombstones WHERE key IN (
+                SELECT key FROM kv_tombstones
+                WHERE key >= $1 AND ($2::bytea IS NULL OR key < $2)
+                LIMIT $3
+            )
+            "#),
+            &params,
+        ).await?;
+        let view_rows = tx.query(
+            &*self.sql(r#"
+            DELETE FROM materialized_view_keys WHERE (view_name, source_key) IN (
+                SELECT view_name, source_key FROM materialized_view_keys
+                WHERE source_key >= $1 AND ($2::bytea IS NULL OR source_key < $2)
+                LIMIT $3
+            )
+            RETURNING target_keys
+            "#),
+            &params,
+        ).await?;
+        let view_keys: Vec<Mutation> = view_rows.iter()
+            .flat_map(|row| row.get::<_, Vec<Vec<u8>>>(0))
+            .map(|key| Mutation { key, kind: MutationKind::Delete, expire_at: None })
+            .collect();
+        if !view_keys.is_empty() {
+            let sample = self.statement_log.begin("increment_version", Vec::new);
+            let new_version: i64 = tx.query_one(&statements.increment_version, &[]).await?.get(0);
+            sample.finish(1);
+            self.apply_mutations(&tx, &statements, &view_keys, &version_to_versionstamp(new_version), &mut ProgressReporter::none()).await?;
+        }
+        let messages: i64 = tx.query_one(
+            &*self.sql(r#"
+            WITH doomed AS (
+                SELECT id FROM queue_messages
+                WHERE namespace >= $1 AND ($2::bytea IS NULL OR namespace < $2)
+                LIMIT $3
+            ), running AS (
+                DELETE FROM queue_running WHERE message_id IN (SELECT id FROM doomed)
+            ), deleted AS (
+                DELETE FROM queue_messages WHERE id IN (SELECT id FROM doomed)
+                RETURNING id
+            )
+            SELECT count(*) FROM deleted
+            "#),
+            &params,
+        ).await?.get(0);
+        let dead_letters = tx.execute(
+            &*self.sql(r#"
+            DELETE FROM queue_dead_letter WHERE id IN (
+                SELECT id FROM queue_dead_letter
+                WHERE namespace >= $1 AND ($2::bytea IS NULL OR namespace < $2)
+                LIMIT $3
+            )
+            "#),
+            &params,
+        ).await?;
+
+        let messages = messages as u64 + dead_letters;
+        let deleted = keys + tombstones + view_rows.len() as u64 + messages;
+        let row = tx.query_one(
+            &*self.sql(&format!(r#"
+            UPDATE namespace_deletions SET
+                deleted_keys = deleted_keys + $2,
+                deleted_tombstones = deleted_tombstones + $3,
+                deleted_view_keys = deleted_view_keys + $4,
+                deleted_messages = deleted_messages + $5,
+                updated_at = NOW(),
+                completed_at = CASE WHEN $6 THEN NOW() END
+            WHERE prefix = $1
+            RETURNING {NAMESPACE_DELETION_COLUMNS}
+            "#)),
+            &[&prefix, &(keys as i64), &(tombstones as i64), &(view_keys.len() as i64), &(messages as i64), &(deleted == 0)],
+        ).await?;
+        tx.commit().await?;
+        Ok(Some((deleted + view_keys.len() as u64, namespace_deletion(&row))))
+    }
+
+    /// Sample `n` keys under `prefix` without reading the whole keyspace.
+    ///
+    /// Pages of `kv_store` are sampled with `TABLESAMPLE SYSTEM`, starting at
+    /// a fraction sized from the table's row estimate and growing tenfold
+    /// while too few rows under the prefix turn up. Once sampling would read
+    /// most of the table anyway, the prefix is read in full through the
+    /// primary key index instead, which is cheap precisely when the prefix is
+    /// too small for sampling to find it.
+    pub async fn sample_keys(&self, prefix: &[u8], n: usize) -> PostgresResult<KeySampleReport> {
+        let conn = self.pool.get().await?;
+        let end = crate::partition::prefix_upper_bound(prefix);
+        let now_ms = crate::time::utc_now().timestamp_millis();
+
+        // A partitioned kv_store holds no rows itself; its partitions are
+        // analyzed separately.
+        let row = conn.query_one(
+            &*self.sql(
+                r#"
+                SELECT COALESCE(
+                    (SELECT sum(p.reltuples) FROM pg_inherits i JOIN pg_class p ON p.oid = i.inhrelid
+                     WHERE i.inhparent = c.oid AND p.reltuples > 0),
+                    c.reltuples
+                )::float8 AS reltuples
+                FROM pg_class c WHERE c.oid = 'kv_store'::regclass
+                "#,
+            ),
+            &[],
+        ).await?;
+        let reltuples: f64 = row.get("reltuples");
+        // Never analyzed tables report -1.
+        let mut percent = if reltuples > 0.0 {
+            (100.0 * n as f64 * SAMPLE_OVERSCAN / reltuples).max(0.01)
+        } else {
+            100.0
+        };
+
+        loop {
+            let exact = percent >= 50.0;
+            let query = if exact {
+                r#"
+                SELECT key, octet_length(key) + octet_length(value) AS size
+                FROM kv_store
+                WHERE key >= $1 AND ($2::bytea IS NULL OR key < $2)
+                  AND (expires_at IS NULL OR expires_at > $3)
+                "#
+            } else {
+                r#"
+                SELECT key, octet_length(key) + octet_length(value) AS size
+                FROM kv_store TABLESAMPLE SYSTEM ($4)
+                WHERE key >= $1 AND ($2::bytea IS NULL OR key < $2)
+                  AND (expires_at IS NULL OR expires_at > $3)
+                "#
+            };
+            // TABLESAMPLE takes a `real`.
+            let sample_percent = percent as f32;
+            let params: Vec<&(dyn ToSql + Sync)> = if exact {
+                vec![&prefix, &end, &now_ms]
+            } else {
+                vec![&prefix, &end, &now_ms, &sample_percent]
+            };
+            let rows = conn.query_raw(&*self.sql(query), params).await?;
+            pin_mut!(rows);
+
+            // Reservoir sampling keeps the sample uniform over every
+            // matching row without holding them all.
+            let mut samples: Vec<KeySample> = Vec::with_capacity(n);
+            let mut seen: u64 = 0;
+            let mut bytes: u64 = 0;
+            while let Some(row) = rows.try_next().await? {
+                let size: i32 = row.get("size");
+                let sample = KeySample { key: row.get("key"), size: size as u64 };
+                seen += 1;
+                bytes += sample.size;
+                if samples.len() < n {
+                    samples.push(sample);
+                } else {
+                    let slot = rand::random::<u64>() % seen;
+                    if slot < n as u64 {
+                        samples[slot as usize] = sample;
+                    }
+                }
+            }
+
+            if exact || seen as f64 >= n as f64 * SAMPLE_OVERSCAN {
+                let fraction = if exact { 1.0 } else { percent / 100.0 };
+                samples.sort_by(|a, b| a.key.cmp(&b.key));
+                return Ok(KeySampleReport {
+                    samples,
+                    estimated_keys: (seen as f64 / fraction) as u64,
+                    estimated_bytes: (bytes as f64 / fraction) as u64,
+                    fraction_scanned: fraction,
+                });
+            }
+            percent *= 10.0;
+        }
+    }
+
+    /// Count the live keys under `prefix` and their bytes. Reads every
+    /// matching row, so on large prefixes this is as slow as a scan of them.
+    pub async fn prefix_usage(&self, prefix: &[u8]) -> PostgresResult<PrefixUsage> {
+        let conn = self.pool.get().await?;
+        let end = crate::partition::prefix_upper_bound(prefix);
+        let now_ms = crate::time::utc_now().timestamp_millis();
+        let row = conn.query_one(
+            &*self.sql(r#"
+            SELECT count(*) AS keys,
+                   coalesce(sum(octet_length(key) + octet_length(value)), 0)::int8 AS bytes
+            FROM kv_store
+            WHERE key >= $1 AND ($2::bytea IS NULL OR key < $2)
+              AND (expires_at IS NULL OR expires_at > $3)
+            "#),
+            &[&prefix, &end, &now_ms],
+        ).await?;
+        let keys: i64 = row.get("keys");
+        let bytes: i64 = row.get("bytes");
+        Ok(PrefixUsage { keys: keys as u64, bytes: bytes as u64 })
+    }
+
+    /// Count the queue messages of every namespace by state.
+    pub async fn queue_depth(&self) -> PostgresResult<QueueDepth> {
+        let conn = self.pool.get().await?;
+        let now_ms = crate::time::utc_now().timestamp_millis();
+        let row = conn.query_one(&*self.sql(driver::QUEUE_DEPTH), &[&now_ms]).await?;
+        let count = |column: &str| row.get::<_, i64>(column) as u64;
+        Ok(QueueDepth {
+            ready: count("ready"),
+            scheduled: count("scheduled"),
+            running: count("running"),
+            dead_letter: count("dead_letter"),
+        })
+    }
+
+    /// Scan every row of `kv_store` and report rows whose checksum does not
+    /// match or that cannot be decoded. Expired rows are included.
+    pub async fn verify(&self) -> PostgresResult<VerifyReport> {
+        let conn = self.pool.get().await?;
+        let mut report = VerifyReport::default();
+        let mut after: Option<Vec<u8>> = None;
+
+        loop {
+            let rows = match &after {
+                None => conn.query(
+                    &*self.sql("SELECT key, value, value_encoding, versionstamp, checksum FROM kv_store ORDER BY key LIMIT $1"),
+                    &[&VERIFY_BATCH_SIZE],
+                ).await?,
+                Some(last) => conn.query(
+                    &*self.sql("SELECT key, value, value_encoding, versionstamp, checksum FROM kv_store WHERE key > $1 ORDER BY key LIMIT $2"),
+                    &[last, &VERIFY_BATCH_SIZE],
+                ).await?,
+            };
+            let done = (rows.len() as i64) < VERIFY_BATCH_SIZE;
+
+            for row in &rows {
+                let key: Vec<u8> = row.get("key");
+                let value: Vec<u8> = row.get("value");
+                let encoding: i32 = row.get("value_encoding");
+                let versionstamp: Vec<u8> = row.get("versionstamp");
+                let checksum: Option<i64> = row.get("checksum");
+
+                report.rows_scanned += 1;
+                if checksum.is_none() {
+                    report.rows_without_checksum += 1;
+                }
+                let result = verify_checksum(&key, &value, encoding, checksum)
+                    .and_then(|()| decode_versionstamp(&versionstamp))
+                    .and_then(|_| decode_value(value, encoding));
+                if let Err(kind) = result {
+                    report.corrupt.push((key.clone(), kind));
+                }
+                after = Some(key);
+            }
+
+            if done {
+                break;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Fix inconsistencies in internal state that the normal write paths
+    /// cannot recover from on their own:
+    ///
+    /// - `queue_running` rows pointing at messages that no longer exist
+    /// - a missing `data_version` row
+    /// - a `data_version` counter behind the highest stored versionstamp,
+    ///   which would hand out versionstamps that go backwards
+    ///
+    /// With `reindex` the secondary indexes are rebuilt as well. The whole
+    /// repair runs in one transaction holding the version counter lock, so
+    /// it is safe to run against a live database.
+    pub async fn repair(&self, dry_run: bool, reindex: bool) -> PostgresResult<RepairReport> {
+        let mut conn = self.pool.get().await?;
+        let tx = conn.transaction().await?;
+        let mut report = RepairReport::default();
+
+        let version_row = tx.query_opt(
+            &*self.sql("SELECT version FROM data_version WHERE k = 0 FOR UPDATE"),
+            &[],
+        ).await?;
+        let current_version = match version_row {
+            Some(row) => row.get::<_, i64>(0),
+            None => {
+                report.version_row_recreated = true;
+                if !dry_run {
+                    tx.execute(&*self.sql("INSERT INTO data_version (k, version) VALUES (0, 0)"), &[]).await?;
+                }
+                0
+            }
+        };
+
+        let max_versionstamp: Option<Vec<u8>> = tx.query_opt(
+            &*self.sql("SELECT versionstamp FROM kv_store ORDER BY versionstamp DESC LIMIT 1"),
+            &[],
+        ).await?.map(|row| row.get(0));
+        if let Some(max_versionstamp) = max_versionstamp {
+            let max_version = versionstamp_to_version(&max_versionstamp)
+                .ok_or_else(|| PostgresError::InvalidData(format!(
+                    "Highest versionstamp in kv_store is {} bytes, expected 10",
+                    max_versionstamp.len()
+                )))?;
+            if max_version > current_version {
+                report.version_advanced = Some((current_version, max_version));
+                if !dry_run {
+                    tx.execute(
+                        &*self.sql("UPDATE data_version SET version = $1 WHERE k = 0"),
+                        &[&max_version],
+                    ).await?;
+                }
+            }
+        }
+
+        let orphan_query = if dry_run {
+            "SELECT count(*) FROM queue_running r WHERE NOT EXISTS (SELECT 1 FROM queue_messages m WHERE m.id = r.message_id)"
+        } else {
+            "WITH d AS (DELETE FROM queue_running r WHERE NOT EXISTS (SELECT 1 FROM queue_messages m WHERE m.id = r.message_id) RETURNING 1) SELECT count(*) FROM d"
+        };
+        report.orphaned_running_removed = tx.query_one(&*self.sql(orphan_query), &[]).await?.get::<_, i64>(0) as u64;
+
+        if reindex && !dry_run {
+            for table in ["kv_store", "queue_messages", "queue_running"] {
+                tx.execute(&*self.sql(&format!("REINDEX TABLE {table}")), &[]).await?;
+            }
+            report.reindexed = true;
+        }
+
+        tx.commit().await?;
+        Ok(report)
+    }
+
+    /// Encode a value for storage. V8 and byte values are borrowed from the
+    /// mutation rather than copied, so multi-megabyte values are not
+    /// duplicated on their way into the SQL parameters.
+    fn encode_value<'a>(&self, value: &'a KvValue) -> (Cow<'a, [u8]>, i32) {
+        let (bytes, encoding) = denokv_proto::encode_value(value);
+        (bytes, encoding as i32)
     }
 }
 
 /// Convert a monotonic i64 version to a 10-byte versionstamp.
 /// Matches the SQLite backend format: 8-byte big-endian version + 2 zero bytes.
-fn version_to_versionstamp(version: i64) -> Versionstamp {
+pub(crate) fn version_to_versionstamp(version: i64) -> Versionstamp {
     let mut versionstamp = [0u8; 10];
     versionstamp[..8].copy_from_slice(&version.to_be_bytes());
     versionstamp
-}
\ No newline at end of file
+}
+
+/// Inverse of `version_to_versionstamp`. Returns None if the slice is not a
+/// 10-byte versionstamp.
+fn versionstamp_to_version(versionstamp: &[u8]) -> Option<i64> {
+    let versionstamp: &Versionstamp = versionstamp.try_into().ok()?;
+    Some(i64::from_be_bytes(versionstamp[..8].try_into().unwrap()))
+}
+
+/// A 128-bit hash of the mutations of a bulk import, journaled with it so
+/// that it only resumes with the same mutations.
+fn mutations_fingerprint(mutations: &[Mutation]) -> Vec<u8> {
+    let mut hasher = Xxh3::new();
+    for mutation in mutations {
+        hasher.update(&(mutation.key.len() as u64).to_le_bytes());
+        hasher.update(&mutation.key);
+        let (tag, value) = match &mutation.kind {
+            MutationKind::Set(value) => (0u8, Some(value)),
+            MutationKind::Delete => (1, None),
+            MutationKind::Sum { value, min_v8, max_v8, clamp } => {
+                for bound in [min_v8, max_v8] {
+                    hasher.update(&(bound.len() as u64).to_le_bytes());
+                    hasher.update(bound);
+                }
+                hasher.update(&[*clamp as u8]);
+                (2, Some(value))
+            }
+            MutationKind::Min(value) => (3, Some(value)),
+            MutationKind::Max(value) => (4, Some(value)),
+            MutationKind::SetSuffixVersionstampedKey(value) => (5, Some(value)),
+        };
+        hasher.update(&[tag]);
+        if let Some(value) = value {
+            let (bytes, encoding) = denokv_proto::encode_value(value);
+            hasher.update(&(encoding as i32).to_le_bytes());
+            hasher.update(&(bytes.len() as u64).to_le_bytes());
+            hasher.update(&bytes);
+        }
+        let expire_at = mutation.expire_at.map(|expire_at| expire_at.timestamp_millis());
+        hasher.update(&expire_at.unwrap_or(-1).to_le_bytes());
+    }
+    hasher.digest128().to_le_bytes().to_vec()
+}
+
+fn backfill_progress(row: &Row) -> BackfillProgress {
+    BackfillProgress {
+        after: row.get("last_key"),
+        processed: row.get::<_, i64>("processed") as u64,
+        rewritten: row.get::<_, i64>("rewritten") as u64,
+        done: row.get("done"),
+    }
+}
+
+/// The columns of `namespace_deletions` read by [`namespace_deletion`].
+const NAMESPACE_DELETION_COLUMNS: &str = r#"
+    prefix, deleted_keys, deleted_tombstones, deleted_view_keys, deleted_messages,
+    (EXTRACT(EPOCH FROM requested_at) * 1000)::int8 AS requested_at_ms,
+    (EXTRACT(EPOCH FROM completed_at) * 1000)::int8 AS completed_at_ms
+"#;
+
+fn namespace_deletion(row: &Row) -> NamespaceDeletion {
+    let time = |ms: i64| Utc.timestamp_millis_opt(ms).single();
+    NamespaceDeletion {
+        prefix: row.get("prefix"),
+        deleted_keys: row.get::<_, i64>("deleted_keys") as u64,
+        deleted_tombstones: row.get::<_, i64>("deleted_tombstones") as u64,
+        deleted_view_keys: row.get::<_, i64>("deleted_view_keys") as u64,
+        deleted_messages: row.get::<_, i64>("deleted_messages") as u64,
+        requested_at: time(row.get("requested_at_ms")).unwrap_or_default(),
+        completed_at: row.get::<_, Option<i64>>("completed_at_ms").and_then(time),
+    }
+}
+
+/// The metadata of a row of `queue_messages` or `queue_dead_letter`.
+pub(crate) fn message_metadata(row: &Row) -> PostgresResult<QueueMetadata> {
+    QueueMetadata::decode(
+        row.get("metadata"),
+        row.get("metadata_encoding"),
+        row.get("keys_if_undelivered"),
+        row.get("backoff_schedule"),
+    )
+}
+
+fn dead_letter(row: &Row) -> PostgresResult<DeadLetter> {
+    let time = |ms: i64| Utc.timestamp_millis_opt(ms).single();
+    Ok(DeadLetter {
+        id: row.get("id"),
+        payload: row.get("payload"),
+        keys_if_undelivered: message_metadata(row)?.keys_if_undelivered,
+        namespace: row.get("namespace"),
+        priority: row.get("priority"),
+        attempts: row.get::<_, i32>("attempts") as u32,
+        enqueued_at: row.get::<_, Option<i64>>("enqueued_at_ms").and_then(time),
+        failed_at: time(row.get("failed_at_ms")).unwrap_or_default(),
+    })
+}
+
+/// `n` as a `LIMIT` or batch size. Sizes beyond what a BIGINT holds, which
+/// a 64-bit `usize` can reach, saturate rather than wrap around to a
+/// negative limit.
+pub(crate) fn sql_limit(n: usize) -> i64 {
+    i64::try_from(n).unwrap_or(i64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sql_limits_saturate() {
+        assert_eq!(sql_limit(1000), 1000);
+        assert_eq!(sql_limit(usize::MAX), if usize::BITS < 64 { usize::MAX as i64 } else { i64::MAX });
+    }
+}
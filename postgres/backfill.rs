@@ -0,0 +1,218 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use denokv_proto::{Check, KvEntry, KvValue, Mutation, MutationKind};
+use futures::{pin_mut, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::backend::BackfillBatch;
+use crate::error::PostgresResult;
+use crate::partition::prefix_upper_bound;
+use crate::Postgres;
+
+/// How long a leader holds a job without renewing its lease, which it does
+/// with every batch. A leader that stops is replaced after this long.
+pub(crate) const LEASE_TTL: Duration = Duration::from_secs(30);
+
+type Transform = dyn Fn(&KvEntry) -> Option<KvValue> + Send + Sync;
+
+/// A job rewriting the entries under a prefix, such as into a new format
+/// after the shape of the stored values changed. Run by
+/// [`Postgres::backfill`].
+///
+/// The transform maps an entry to its new value, or to `None` if it needs
+/// no change, in particular because it is in the new format already. Every
+/// instance of an application may run the same job: they elect a leader
+/// with a lease, and only the leader rewrites entries. The leader walks the
+/// prefix with [`Postgres::scan`] and commits each batch of rewritten
+/// entries together with a checkpoint and the renewal of its lease, so a
+/// new leader resumes where the last one stopped. Rewrites check the
+/// versionstamp the scan read, so an entry changed since is read and
+/// transformed again rather than overwritten. Expiry times are kept.
+///
+/// Jobs are kept by name in the `backfill_jobs` table. A finished job is
+/// not run again under the same name.
+#[derive(Clone)]
+pub struct BackfillJob {
+    name: String,
+    prefix: Vec<u8>,
+    transform: Arc<Transform>,
+    batch_size: NonZeroU32,
+    rate_limit: Option<f64>,
+}
+
+impl BackfillJob {
+    pub fn new(
+        name: impl Into<String>,
+        prefix: Vec<u8>,
+        transform: impl Fn(&KvEntry) -> Option<KvValue> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            prefix,
+            transform: Arc::new(transform),
+            batch_size: NonZeroU32::new(1000).unwrap(),
+            rate_limit: None,
+        }
+    }
+
+    /// Read and commit this many entries at a time. Defaults to 1000.
+    pub fn with_batch_size(mut self, batch_size: NonZeroU32) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Read at most this many entries per second on average. A batch must
+    /// take less than the lease time of 30 seconds.
+    pub fn with_rate_limit(mut self, entries_per_sec: f64) -> Self {
+        self.rate_limit = Some(entries_per_sec);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// How far a backfill job has come, as of its last checkpoint.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackfillProgress {
+    /// The last key processed.
+    pub after: Option<Vec<u8>>,
+    /// Entries processed, including those that needed no change.
+    pub processed: u64,
+    /// Entries written with a new value.
+    pub rewritten: u64,
+    pub done: bool,
+}
+
+/// Runs a [`BackfillJob`] in the background until it is done, leading it
+/// whenever no other instance does. Created by [`Postgres::backfill`]; the
+/// job stops, to be resumed by another instance, when this is dropped.
+pub struct BackfillRunner {
+    task: Option<tokio::task::JoinHandle<()>>,
+}
+
+impl Drop for BackfillRunner {
+    fn drop(&mut self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+}
+
+impl BackfillRunner {
+    pub(crate) fn new(postgres: Postgres, job: BackfillJob) -> Self {
+        Self { task: Some(tokio::spawn(run(postgres, job))) }
+    }
+
+    /// Wait until the job is done, by whichever instance led it.
+    pub async fn wait(mut self) {
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+        }
+    }
+}
+
+async fn run(postgres: Postgres, job: BackfillJob) {
+    let owner = Uuid::new_v4();
+    loop {
+        let result = match postgres.backend.claim_backfill(&job.name, &owner).await {
+            Ok(Some(progress)) => lead(&postgres, &job, &owner, progress).await,
+            Ok(None) => match postgres.backend.backfill_progress(&job.name).await {
+                Ok(Some(progress)) if progress.done => {
+                    postgres.metrics.set_backfill_done(&job.name);
+                    return;
+                }
+                Ok(_) => Ok(()),
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            log::warn!("Backfill {:?} failed: {e}", job.name);
+        }
+        tokio::time::sleep(crate::SUBSCRIPTION_POLL_INTERVAL).await;
+    }
+}
+
+/// Rewrite the rest of `job` while holding its lease. Returns early
+/// without an error if the lease is lost.
+async fn lead(postgres: &Postgres, job: &BackfillJob, owner: &Uuid, mut progress: BackfillProgress) -> PostgresResult<()> {
+    let start = match &progress.after {
+        Some(after) => [&after[..], &[0]].concat(),
+        None => job.prefix.clone(),
+    };
+    let entries = postgres.scan_range(start, prefix_upper_bound(&job.prefix), job.batch_size, job.rate_limit);
+    pin_mut!(entries);
+    let mut batch = Vec::new();
+    loop {
+        let entry = entries.try_next().await?;
+        let finished = entry.is_none();
+        batch.extend(entry);
+        let full = batch.len() >= job.batch_size.get() as usize || finished;
+        if full && !batch.is_empty() && !rewrite(postgres, job, owner, &mut progress, std::mem::take(&mut batch)).await? {
+            return Ok(());
+        }
+        if finished {
+            break;
+        }
+    }
+
+    let done = BackfillProgress { done: true, ..progress };
+    let mut conn = postgres.get_connection().await?;
+    postgres.backend.apply_backfill_batch(&mut conn, &job.name, owner, &[], &[], &done).await?;
+    Ok(())
+}
+
+/// Transform `entries` and commit those that changed along with the
+/// checkpoint after them. Returns whether the lease was held.
+async fn rewrite(
+    postgres: &Postgres,
+    job: &BackfillJob,
+    owner: &Uuid,
+    progress: &mut BackfillProgress,
+    mut entries: Vec<(KvEntry, Option<DateTime<Utc>>)>,
+) -> PostgresResult<bool> {
+    let (first, last) = (entries[0].0.key.clone(), entries[entries.len() - 1].0.key.clone());
+    loop {
+        let mut checks = Vec::new();
+        let mut mutations = Vec::new();
+        for (entry, expire_at) in &entries {
+            if let Some(value) = (job.transform)(entry) {
+                checks.push(Check { key: entry.key.clone(), versionstamp: Some(entry.versionstamp) });
+                mutations.push(Mutation { key: entry.key.clone(), kind: MutationKind::Set(value), expire_at: *expire_at });
+            }
+        }
+        let next = BackfillProgress {
+            after: Some(last.clone()),
+            processed: progress.processed + entries.len() as u64,
+            rewritten: progress.rewritten + mutations.len() as u64,
+            done: false,
+        };
+
+        let mut conn = postgres.get_connection().await?;
+        match postgres.backend.apply_backfill_batch(&mut conn, &job.name, owner, &checks, &mutations, &next).await? {
+            BackfillBatch::Committed(keys) => {
+                postgres.notify_keys(&keys);
+                postgres.metrics.record_backfill(&job.name, entries.len() as u64, mutations.len() as u64);
+                *progress = next;
+                return Ok(true);
+            }
+            BackfillBatch::LeaseLost => return Ok(false),
+            BackfillBatch::Conflict => {}
+        }
+
+        // An entry changed after the scan read it. Start over with the
+        // batch as it is now.
+        entries = postgres
+            .scan_range(first.clone(), Some([&last[..], &[0]].concat()), job.batch_size, None)
+            .try_collect()
+            .await?;
+    }
+}
@@ -0,0 +1,174 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+//! Key change notifications between instances sharing a database.
+//!
+//! Every write transaction sends at most one `NOTIFY` on the channel of
+//! [`Tables::channel`], no matter how many keys it changes. The payload lists the changed keys,
+//! sorted and deduplicated, each as its length in bytes, a colon and its
+//! bytes in hex. PostgreSQL rejects payloads of 8000 bytes or more, so
+//! larger batches send [`RESYNC`] instead, which wakes every watcher.
+//!
+//! Transactions that enqueue or reschedule queue messages also notify the
+//! channel of [`Tables::queue_channel`], without a payload, so that
+//! consumers waiting for messages wake up.
+//!
+//! [`Tables::channel`]: crate::tables::Tables::channel
+//! [`Tables::queue_channel`]: crate::tables::Tables::queue_channel
+
+use std::time::Duration;
+
+use denokv_proto::Mutation;
+use futures::StreamExt;
+use tokio::sync::mpsc;
+use tokio_postgres::{AsyncMessage, NoTls, Transaction};
+
+use crate::driver;
+use crate::error::{PostgresError, PostgresResult};
+use crate::notifier::PostgresNotifier;
+
+/// The payload of a notification about more keys than fit in one.
+pub const RESYNC: &str = "resync";
+
+/// The longest payload PostgreSQL accepts.
+const MAX_PAYLOAD_LEN: usize = 7999;
+
+/// How long the listener waits before reconnecting after losing its
+/// connection.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// The payload notifying a change to `keys`.
+pub fn encode_payload<'a>(keys: impl IntoIterator<Item = &'a [u8]>) -> String {
+    let mut keys: Vec<&[u8]> = keys.into_iter().collect();
+    keys.sort_unstable();
+    keys.dedup();
+    let mut payload = String::new();
+    for key in keys {
+        payload.push_str(&key.len().to_string());
+        payload.push(':');
+        payload.push_str(&hex::encode(key));
+        if payload.len() > MAX_PAYLOAD_LEN {
+            return RESYNC.to_string();
+        }
+    }
+    payload
+}
+
+/// The keys notified by `payload`, or `None` if every watcher should be
+/// woken because it is [`RESYNC`] or malformed.
+pub fn decode_payload(payload: &str) -> Option<Vec<Vec<u8>>> {
+    let mut keys = Vec::new();
+    let mut rest = payload;
+    while !rest.is_empty() {
+        let (len, tail) = rest.split_once(':')?;
+        let hex_len = len.parse::<usize>().ok()?.checked_mul(2)?;
+        let key = hex::decode(tail.get(..hex_len)?).ok()?;
+        keys.push(key);
+        rest = &tail[hex_len..];
+    }
+    Some(keys)
+}
+
+/// Notify other instances listening on `channel` of the keys changed by
+/// `mutations` once `tx` commits. Nothing is sent if there are no mutations.
+pub async fn notify(tx: &Transaction<'_>, channel: &str, mutations: &[Mutation]) -> PostgresResult<()> {
+    if mutations.is_empty() {
+        return Ok(());
+    }
+    let payload = encode_payload(mutations.iter().map(|m| m.key.as_slice()));
+    tx.execute(driver::NOTIFY, &[&channel, &payload]).await?;
+    Ok(())
+}
+
+/// Notify consumers listening on `queue_channel` that messages were
+/// enqueued or rescheduled once `tx` commits.
+pub async fn notify_queue(tx: &Transaction<'_>, queue_channel: &str) -> PostgresResult<()> {
+    tx.execute(driver::NOTIFY, &[&queue_channel, &""]).await?;
+    Ok(())
+}
+
+/// Listen on `channel` for key changes made through any instance, including
+/// this one, and wake the matching watchers of `notifier`, and on
+/// `queue_channel` for queue messages, waking its waiting consumers, until
+/// the task is aborted.
+/// Every watcher and consumer is woken after (re)connecting, since changes
+/// may have been missed in between.
+pub async fn listen(config: tokio_postgres::Config, channel: String, queue_channel: String, notifier: PostgresNotifier) {
+    loop {
+        if let Err(e) = listen_once(&config, &channel, &queue_channel, &notifier).await {
+            log::warn!("Lost the key change listener connection: {e}");
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn listen_once(
+    config: &tokio_postgres::Config,
+    channel: &str,
+    queue_channel: &str,
+    notifier: &PostgresNotifier,
+) -> PostgresResult<()> {
+    let (client, mut connection) = config.connect(NoTls).await?;
+    let (sender, mut messages) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut stream = futures::stream::poll_fn(move |cx| connection.poll_message(cx));
+        while let Some(message) = stream.next().await {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    client.batch_execute(&format!("LISTEN {channel}; LISTEN {queue_channel}")).await?;
+    notifier.notify_all();
+    notifier.notify_enqueued();
+
+    while let Some(message) = messages.recv().await {
+        if let AsyncMessage::Notification(notification) = message? {
+            if notification.channel() == queue_channel {
+                notifier.notify_enqueued();
+                continue;
+            }
+            match decode_payload(notification.payload()) {
+                Some(keys) => {
+                    for key in &keys {
+                        notifier.notify_key_update(key);
+                    }
+                }
+                None => notifier.notify_all(),
+            }
+        }
+    }
+    Err(PostgresError::ConnectionFailed("The connection was closed".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload_round_trip() {
+        let keys: Vec<&[u8]> = vec![b"\x02b\x00", b"\x02a\x00", b"", b"\x02b\x00"];
+        let payload = encode_payload(keys);
+        assert_eq!(payload, "0:3:0261003:026200");
+        assert_eq!(
+            decode_payload(&payload),
+            Some(vec![vec![], b"\x02a\x00".to_vec(), b"\x02b\x00".to_vec()])
+        );
+        assert_eq!(decode_payload(""), Some(vec![]));
+    }
+
+    #[test]
+    fn oversized_payload_resyncs() {
+        let keys: Vec<Vec<u8>> = (0..1000u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        let payload = encode_payload(keys.iter().map(Vec::as_slice));
+        assert_eq!(payload, RESYNC);
+        assert_eq!(decode_payload(&payload), None);
+    }
+
+    #[test]
+    fn malformed_payload_resyncs() {
+        assert_eq!(decode_payload("3:0261"), None);
+        assert_eq!(decode_payload("x:00"), None);
+        assert_eq!(decode_payload("1:zz"), None);
+    }
+}
@@ -0,0 +1,369 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::config::PostgresConfig;
+use crate::error::{PostgresError, PostgresResult};
+use crate::pressure::{Pressure, RetryBudget, DEFAULT_RETRY_AFTER};
+
+/// Delay before the first retry. Doubles with every further attempt, up to
+/// [`MAX_RETRY_DELAY`].
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(50);
+
+/// The longest delay before a retry, jitter aside.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// The most retries [`PostgresConfig::max_retries`] may ask for.
+pub const MAX_RETRIES: u32 = 10;
+
+/// The kinds of operation that get their own circuit breaker, so that e.g.
+/// a failing queue table does not stop reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OperationClass {
+    Read,
+    Write,
+    Queue,
+}
+
+impl fmt::Display for OperationClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OperationClass::Read => "read",
+            OperationClass::Write => "write",
+            OperationClass::Queue => "queue",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Operations run normally.
+    Closed,
+    /// Operations fail fast until the cool-down has passed.
+    Open,
+    /// The cool-down has passed; the next outcome decides whether the
+    /// circuit closes again or reopens.
+    HalfOpen,
+}
+
+/// Emitted whenever a circuit breaker changes state.
+#[derive(Debug, Clone, Serialize)]
+pub struct CircuitEvent {
+    pub class: OperationClass,
+    pub from: CircuitState,
+    pub to: CircuitState,
+}
+
+/// One circuit breaker per [`OperationClass`], sharing an event channel and
+/// a retry budget.
+pub struct CircuitBreakers {
+    pub read: CircuitBreaker,
+    pub write: CircuitBreaker,
+    pub queue: CircuitBreaker,
+    events: broadcast::Sender<CircuitEvent>,
+}
+
+impl CircuitBreakers {
+    pub fn new(config: &PostgresConfig) -> Self {
+        let (events, _) = broadcast::channel(16);
+        let budget = Arc::new(RetryBudget::new(config.retry_budget));
+        let breaker = |class| CircuitBreaker {
+            class,
+            threshold: config.circuit_breaker_threshold,
+            cooldown: Duration::from_secs(config.circuit_breaker_cooldown),
+            max_retries: config.max_retries,
+            budget: budget.clone(),
+            state: Mutex::default(),
+            events: events.clone(),
+            retries: AtomicU64::new(0),
+        };
+        Self {
+            read: breaker(OperationClass::Read),
+            write: breaker(OperationClass::Write),
+            queue: breaker(OperationClass::Queue),
+            events,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<CircuitEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn get(&self, class: OperationClass) -> &CircuitBreaker {
+        match class {
+            OperationClass::Read => &self.read,
+            OperationClass::Write => &self.write,
+            OperationClass::Queue => &self.queue,
+        }
+    }
+}
+
+/// Stops retry storms during an outage. After `threshold` consecutive
+/// transient failures the circuit opens and operations fail fast with
+/// [`PostgresError::CircuitOpen`] for the cool-down period.
+pub struct CircuitBreaker {
+    class: OperationClass,
+    /// 0 disables the breaker.
+    threshold: u32,
+    cooldown: Duration,
+    max_retries: u32,
+    budget: Arc<RetryBudget>,
+    state: Mutex<BreakerState>,
+    events: broadcast::Sender<CircuitEvent>,
+    /// Retries of failed operations so far.
+    retries: AtomicU64,
+}
+
+#[derive(Default)]
+struct BreakerState {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+    half_open: bool,
+}
+
+impl BreakerState {
+    fn current(&self) -> CircuitState {
+        if self.open_until.is_some() {
+            CircuitState::Open
+        } else if self.half_open {
+            CircuitState::HalfOpen
+        } else {
+            CircuitState::Closed
+        }
+    }
+}
+
+impl CircuitBreaker {
+    /// Run `op`, retrying transient failures with exponential backoff. Only
+    /// use this for operations that are safe to repeat. Retries stop as
+    /// soon as the circuit is no longer closed, and fail with
+    /// [`Pressure::RetryBudgetExhausted`] once the retry budget is spent.
+    pub async fn run<T, F, Fut>(&self, mut op: F) -> PostgresResult<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = PostgresResult<T>>,
+    {
+        let mut attempt = 0;
+        self.budget.deposit();
+        loop {
+            self.admit()?;
+            let result = op().await;
+            self.observe(&result);
+            match result {
+                Err(err)
+                    if err.is_transient()
+                        && attempt < self.max_retries
+                        && self.state() == CircuitState::Closed =>
+                {
+                    if !self.budget.withdraw() {
+                        return Err(PostgresError::Overloaded {
+                            pressure: Pressure::RetryBudgetExhausted,
+                            retry_after: DEFAULT_RETRY_AFTER,
+                        });
+                    }
+                    let delay = retry_delay(attempt);
+                    let jitter = delay.mul_f64(rand::random::<f64>() * 0.5);
+                    tracing::debug!(attempt = attempt + 1, error = %err, "retrying after a transient failure");
+                    tokio::time::sleep(delay + jitter).await;
+                    attempt += 1;
+                    self.retries.fetch_add(1, Ordering::Relaxed);
+                }
+                result => {
+                    // Reported on the span of the operation, if it has the
+                    // field.
+                    tracing::Span::current().record("attempts", attempt + 1);
+                    return result;
+                }
+            }
+        }
+    }
+
+    /// How many times failed operations were retried so far.
+    #[cfg(feature = "metrics")]
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    /// Fail fast if the circuit is open.
+    pub fn admit(&self) -> PostgresResult<()> {
+        if self.threshold == 0 {
+            return Ok(());
+        }
+        let mut state = self.state.lock().unwrap();
+        if let Some(until) = state.open_until {
+            if Instant::now() < until {
+                return Err(PostgresError::CircuitOpen(self.class));
+            }
+            state.open_until = None;
+            state.half_open = true;
+            self.emit(CircuitState::Open, CircuitState::HalfOpen);
+        }
+        Ok(())
+    }
+
+    /// Record the outcome of an operation. Only transient failures count
+    /// against the circuit; any other result shows the server is healthy.
+    pub fn observe<T>(&self, result: &PostgresResult<T>) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let from = state.current();
+        match result {
+            Err(err) if err.is_transient() => {
+                state.consecutive_failures += 1;
+                if from == CircuitState::HalfOpen
+                    || (from == CircuitState::Closed && state.consecutive_failures >= self.threshold)
+                {
+                    state.consecutive_failures = 0;
+                    state.half_open = false;
+                    state.open_until = Some(Instant::now() + self.cooldown);
+                    self.emit(from, CircuitState::Open);
+                }
+            }
+            _ => {
+                state.consecutive_failures = 0;
+                if from == CircuitState::HalfOpen {
+                    state.half_open = false;
+                    self.emit(from, CircuitState::Closed);
+                }
+            }
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.state.lock().unwrap().current()
+    }
+
+    /// How much longer the circuit stays open, if it is.
+    pub fn open_for(&self) -> Option<Duration> {
+        let until = self.state.lock().unwrap().open_until?;
+        Some(until.saturating_duration_since(Instant::now()))
+    }
+
+    fn emit(&self, from: CircuitState, to: CircuitState) {
+        log::warn!("{} circuit breaker: {from:?} -> {to:?}", self.class);
+        // No subscribers is fine.
+        let _ = self.events.send(CircuitEvent {
+            class: self.class,
+            from,
+            to,
+        });
+    }
+}
+
+/// The delay before retry number `attempt + 1`, jitter aside.
+fn retry_delay(attempt: u32) -> Duration {
+    2u32.checked_pow(attempt)
+        .and_then(|factor| RETRY_BASE_DELAY.checked_mul(factor))
+        .map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breakers(threshold: u32, cooldown: u64) -> CircuitBreakers {
+        let config = PostgresConfig::default()
+            .with_max_retries(0)
+            .with_circuit_breaker(threshold, cooldown);
+        CircuitBreakers::new(&config)
+    }
+
+    fn failure() -> PostgresResult<()> {
+        Err(PostgresError::ConnectionFailed("down".to_string()))
+    }
+
+    #[test]
+    fn opens_after_threshold_and_fails_fast() {
+        let breakers = breakers(3, 60);
+        let mut events = breakers.subscribe();
+        for _ in 0..2 {
+            breakers.write.observe(&failure());
+        }
+        assert_eq!(breakers.write.state(), CircuitState::Closed);
+        breakers.write.observe(&failure());
+        assert_eq!(breakers.write.state(), CircuitState::Open);
+        assert!(matches!(
+            breakers.write.admit(),
+            Err(PostgresError::CircuitOpen(OperationClass::Write))
+        ));
+        // Other classes are unaffected.
+        assert!(breakers.read.admit().is_ok());
+
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.class, OperationClass::Write);
+        assert_eq!((event.from, event.to), (CircuitState::Closed, CircuitState::Open));
+    }
+
+    #[test]
+    fn non_transient_errors_do_not_count() {
+        let breakers = breakers(1, 60);
+        breakers.read.observe::<()>(&Err(PostgresError::InvalidData("bad".to_string())));
+        assert_eq!(breakers.read.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn half_open_closes_on_success_and_reopens_on_failure() {
+        let breakers = breakers(1, 0);
+        breakers.queue.observe(&failure());
+        assert_eq!(breakers.queue.state(), CircuitState::Open);
+
+        breakers.queue.admit().unwrap();
+        assert_eq!(breakers.queue.state(), CircuitState::HalfOpen);
+        breakers.queue.observe(&failure());
+        assert_eq!(breakers.queue.state(), CircuitState::Open);
+
+        breakers.queue.admit().unwrap();
+        breakers.queue.observe(&Ok(()));
+        assert_eq!(breakers.queue.state(), CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn sheds_once_the_retry_budget_is_spent() {
+        let config = PostgresConfig::default()
+            .with_max_retries(1)
+            .with_retry_budget(0.01)
+            .with_circuit_breaker(0, 60);
+        let breakers = CircuitBreakers::new(&config);
+        let mut shed = false;
+        for _ in 0..20 {
+            match breakers.read.run(|| async { failure() }).await {
+                Err(PostgresError::ConnectionFailed(_)) => {}
+                Err(PostgresError::Overloaded { pressure, .. }) => {
+                    assert_eq!(pressure, Pressure::RetryBudgetExhausted);
+                    shed = true;
+                    break;
+                }
+                result => panic!("unexpected result {result:?}"),
+            }
+        }
+        assert!(shed);
+    }
+
+    #[test]
+    fn retry_delay_is_capped() {
+        assert_eq!(retry_delay(0), RETRY_BASE_DELAY);
+        assert_eq!(retry_delay(2), RETRY_BASE_DELAY * 4);
+        assert_eq!(retry_delay(MAX_RETRIES), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(40), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn disabled_with_zero_threshold() {
+        let breakers = breakers(0, 60);
+        for _ in 0..10 {
+            breakers.read.observe(&failure());
+        }
+        assert!(breakers.read.admit().is_ok());
+    }
+}
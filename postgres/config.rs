@@ -2,6 +2,12 @@
 
 use serde::{Deserialize, Serialize};
 
+use crate::circuit_breaker::MAX_RETRIES;
+use crate::error::{PostgresError, PostgresResult};
+use crate::limits::WriteLimits;
+use crate::table_partitioning::TablePartitioning;
+use crate::tables;
+
 /// Configuration for PostgreSQL backend
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PostgresConfig {
@@ -17,6 +23,339 @@ pub struct PostgresConfig {
     
     /// Statement timeout in seconds
     pub statement_timeout: u64,
+
+    /// Verify the per-row checksum of every entry returned by a read.
+    /// Checksums are always written; rows without one are not checked.
+    #[serde(default)]
+    pub verify_checksums: bool,
+
+    /// Fraction of executed statements (0.0 to 1.0) to log with their
+    /// parameter sizes, duration and row count. Logged at trace level under
+    /// the `denokv_postgres::statements` target. 0.0 disables logging.
+    #[serde(default)]
+    pub statement_log_sample_rate: f64,
+
+    /// Split bulk imports into sub-transactions of at most this many
+    /// mutations, journaling progress so an interrupted import can resume.
+    /// Atomic writes are never split. `None` imports in one transaction.
+    #[serde(default)]
+    pub bulk_import_batch_size: Option<usize>,
+
+    /// Connection URLs of read replicas. Eventually consistent reads are
+    /// routed to the fastest healthy replica; strong reads and all writes go
+    /// to the primary.
+    #[serde(default)]
+    pub read_replica_urls: Vec<String>,
+
+    /// Seconds of replication lag beyond which a replica serves no
+    /// eventually consistent reads until it catches up. Lag is measured
+    /// every few seconds; if every replica lags, reads go to the primary.
+    /// `None` only reports the lag.
+    #[serde(default)]
+    pub max_replica_lag: Option<f64>,
+
+    /// Key prefixes stored in other clusters. Only used by
+    /// [`PartitionedPostgres`](crate::PartitionedPostgres).
+    #[serde(default)]
+    pub partitions: Vec<PartitionRule>,
+
+    /// Maximum sustained write operations per second. `None` is unlimited.
+    #[serde(default)]
+    pub write_ops_per_sec: Option<f64>,
+
+    /// Maximum sustained written bytes per second. `None` is unlimited.
+    #[serde(default)]
+    pub write_bytes_per_sec: Option<f64>,
+
+    /// Number of throttled writes allowed to wait before new writes are
+    /// rejected with a backpressure error.
+    #[serde(default = "default_write_queue_limit")]
+    pub write_queue_limit: usize,
+
+    /// How many times an operation that is safe to repeat is retried after
+    /// a transient failure, at most 10.
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+
+    /// Consecutive transient failures after which operations of that class
+    /// fail fast. 0 disables the circuit breaker.
+    #[serde(default = "default_circuit_breaker_threshold")]
+    pub circuit_breaker_threshold: u32,
+
+    /// Seconds an open circuit fails fast before letting operations through
+    /// again.
+    #[serde(default = "default_circuit_breaker_cooldown")]
+    pub circuit_breaker_cooldown: u64,
+
+    /// Seconds an operation waits for a pooled connection before it is
+    /// shed as overloaded. 0 waits as long as it takes.
+    #[serde(default = "default_pool_wait_timeout")]
+    pub pool_wait_timeout: u64,
+
+    /// Lanes keys are hashed to for connection affinity. An atomic write
+    /// whose keys all hash to one lane runs on the lane's connection, which
+    /// stays checked out of the pool, so that writes to hot keys reuse its
+    /// prepared statements and queue in this process rather than for row
+    /// locks. Must be less than `max_connections`. 0 takes a connection from
+    /// the pool for every write.
+    #[serde(default)]
+    pub affinity_lanes: usize,
+
+    /// Statements each connection keeps prepared, so that the hot reads and
+    /// writes are parsed and planned once per connection rather than on
+    /// every call. A full cache is emptied before it grows further. 0
+    /// prepares every statement each time it runs.
+    #[serde(default = "default_statement_cache_size")]
+    pub statement_cache_size: usize,
+
+    /// Retries allowed per operation on average, on top of a small reserve,
+    /// so that retries can not multiply the load during an incident. 0
+    /// allows every retry up to `max_retries`.
+    #[serde(default = "default_retry_budget")]
+    pub retry_budget: f64,
+
+    /// Seconds deletions are kept for change feeds. A consumer that falls
+    /// further behind than this has to start over from an empty copy.
+    #[serde(default = "default_tombstone_retention")]
+    pub tombstone_retention: u64,
+
+    /// Keep a connection listening for key changes made through other
+    /// instances, so that their writes wake watchers here too. Writes are
+    /// notified to listeners either way.
+    #[serde(default = "default_listen_for_changes")]
+    pub listen_for_changes: bool,
+
+    /// Seconds a watch may go without being polled before its
+    /// subscriptions are released and it fails, so that watches abandoned
+    /// by consumers that never drop them do not pile up. A watch waiting
+    /// for changes is polled a few times per lease. 0 keeps subscriptions
+    /// until the watch is dropped.
+    #[serde(default = "default_watch_lease")]
+    pub watch_lease: u64,
+
+    /// Log a warning at startup for each server setting that is likely to
+    /// cause slow or unreliable operation. See
+    /// [`Postgres::check_server_settings`](crate::Postgres::check_server_settings).
+    #[serde(default = "default_check_server_settings")]
+    pub check_server_settings: bool,
+
+    /// Record the shapes of range scans, for
+    /// [`Postgres::index_advice`](crate::Postgres::index_advice). Costs a
+    /// lock per scan, so it is meant for diagnosing slow reads.
+    #[serde(default)]
+    pub record_range_scans: bool,
+
+    /// Seconds for which keys this process wrote or read from the primary
+    /// are remembered with their versionstamps. An eventually consistent
+    /// read from a replica that returns an older version of such a key is
+    /// read again from the primary, bounding how stale reads can look to
+    /// this process. 0 disables read repair.
+    #[serde(default)]
+    pub read_repair_window: u64,
+
+    /// Seconds between sweeps deleting expired rows. Expired rows are never
+    /// returned by reads either way; sweeping only reclaims their space. 0
+    /// disables the sweeper.
+    #[serde(default = "default_expiry_sweep_interval")]
+    pub expiry_sweep_interval: u64,
+
+    /// Expired rows deleted per statement by a sweep, which deletes batch
+    /// after batch until none are left, so that no single statement holds
+    /// locks on a large number of rows.
+    #[serde(default = "default_expiry_sweep_batch_size")]
+    pub expiry_sweep_batch_size: usize,
+
+    /// Rows inserted, updated or deleted in `kv_store`, `kv_tombstones` or
+    /// `queue_messages` since the table was last analyzed, by anyone, after
+    /// which a background task analyzes it. Keeps the planner's statistics,
+    /// and the estimates of [`Postgres::sample_keys`](crate::Postgres::sample_keys)
+    /// that rely on them, fresh on tables too large for autovacuum to
+    /// analyze often. Checked every minute. 0 leaves it to autovacuum.
+    #[serde(default = "default_analyze_threshold")]
+    pub analyze_threshold: u64,
+
+    /// Rows the namespace reaper deletes from each table per transaction.
+    /// See [`Postgres::delete_namespace`](crate::Postgres::delete_namespace).
+    #[serde(default = "default_reaper_batch_size")]
+    pub reaper_batch_size: usize,
+
+    /// Rows per second the namespace reaper deletes at most, so that
+    /// reaping a large namespace neither crowds out other writes nor floods
+    /// replication. `None` deletes as fast as batches commit.
+    #[serde(default)]
+    pub reaper_rows_per_sec: Option<f64>,
+
+    /// Schema holding the tables, created if it does not exist. `None`
+    /// leaves the tables to the connection's `search_path`, normally
+    /// `public`.
+    #[serde(default)]
+    pub schema: Option<String>,
+
+    /// Prepended to the name of every table and index, so that several
+    /// instances can share a schema.
+    #[serde(default)]
+    pub table_prefix: String,
+
+    /// Whether `kv_store` is created as a hash or range partitioned table.
+    /// Only applies when the table does not exist yet. See
+    /// [`TablePartitioning`].
+    #[serde(default)]
+    pub table_partitioning: TablePartitioning,
+
+    /// Split a partition of a partitioned `kv_store` in two once the
+    /// planner estimates it to hold more than this many rows. Writes wait
+    /// while half of its rows move, so keep this well below the size at
+    /// which that takes long. 0, the default, never splits. See
+    /// [`Postgres::manage_partitions`](crate::Postgres::manage_partitions).
+    #[serde(default)]
+    pub partition_split_rows: u64,
+
+    /// Archive the range partitions of `kv_store` no key was written to
+    /// for this many seconds, leaving their keys to read as expired. 0, the
+    /// default, never archives. See
+    /// [`Postgres::manage_partitions`](crate::Postgres::manage_partitions).
+    #[serde(default)]
+    pub partition_archive_after: u64,
+
+    /// Limits atomic writes are checked against before they touch the
+    /// database, Deno KV's by default. `None` leaves oversized writes to
+    /// fail in PostgreSQL, if at all.
+    #[serde(default = "default_write_limits")]
+    pub write_limits: Option<WriteLimits>,
+
+    /// When atomic writes are acknowledged. Individual writes can ask for
+    /// otherwise with
+    /// [`Postgres::atomic_write_with_durability`](crate::Postgres::atomic_write_with_durability).
+    #[serde(default)]
+    pub durability: Durability,
+
+    /// Delivery attempts after which a failing queue message is moved to
+    /// the `queue_dead_letter` table rather than retried, to be listed and
+    /// requeued with
+    /// [`Postgres::dead_letters`](crate::Postgres::dead_letters). `None`
+    /// retries messages for as long as their backoff schedule lasts.
+    #[serde(default)]
+    pub max_delivery_attempts: Option<u32>,
+
+    /// Seconds a dequeued message may go without a keepalive before it
+    /// counts as failed, its worker presumably gone, and is retried like a
+    /// message that failed. While its handle is alive, the deadline of a
+    /// message is pushed back every third of this, so this only bounds how
+    /// long a worker that died holds on to its messages. Overdue messages
+    /// are released by the queue cleanup every 30 seconds, or with
+    /// [`Postgres::requeue_overdue_messages`](crate::Postgres::requeue_overdue_messages).
+    /// At most a week.
+    #[serde(default = "default_queue_visibility_timeout")]
+    pub queue_visibility_timeout: u64,
+
+    /// Run the periodic expiry sweeps, tombstone trimming, queue cleanup
+    /// and analyzing only on the instance elected leader among those
+    /// sharing the tables, rather than on all of them. The election keeps a
+    /// connection of its own to `url`, made without TLS.
+    #[serde(default = "default_elect_maintenance_leader")]
+    pub elect_maintenance_leader: bool,
+}
+
+fn default_write_limits() -> Option<WriteLimits> {
+    Some(WriteLimits::default())
+}
+
+fn default_write_queue_limit() -> usize {
+    1000
+}
+
+fn default_max_retries() -> u32 {
+    2
+}
+
+fn default_circuit_breaker_threshold() -> u32 {
+    5
+}
+
+fn default_circuit_breaker_cooldown() -> u64 {
+    30
+}
+
+fn default_pool_wait_timeout() -> u64 {
+    5
+}
+
+fn default_retry_budget() -> f64 {
+    0.2
+}
+
+fn default_tombstone_retention() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+fn default_listen_for_changes() -> bool {
+    true
+}
+
+fn default_watch_lease() -> u64 {
+    60
+}
+
+fn default_check_server_settings() -> bool {
+    true
+}
+
+/// The longest [`PostgresConfig::queue_visibility_timeout`], a week.
+const MAX_QUEUE_VISIBILITY_TIMEOUT: u64 = 7 * 24 * 60 * 60;
+
+fn default_queue_visibility_timeout() -> u64 {
+    // As long as SQLite lets a message go without a keepalive.
+    5
+}
+
+fn default_elect_maintenance_leader() -> bool {
+    true
+}
+
+fn default_analyze_threshold() -> u64 {
+    100_000
+}
+
+fn default_expiry_sweep_interval() -> u64 {
+    60
+}
+
+fn default_expiry_sweep_batch_size() -> usize {
+    1000
+}
+
+fn default_statement_cache_size() -> usize {
+    64
+}
+
+fn default_reaper_batch_size() -> usize {
+    1000
+}
+
+/// When an atomic write is acknowledged, reported back as
+/// [`CommitResult::durable`](denokv_proto::CommitResult::durable).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Durability {
+    /// Once the server's `synchronous_commit` setting is satisfied, which
+    /// by default means once the write has been flushed to disk.
+    #[default]
+    Synchronous,
+    /// As soon as it commits, with `synchronous_commit` off for its
+    /// transaction. Much faster where flushing is slow, but a crash of the
+    /// server can lose the writes of the last few hundred milliseconds (up
+    /// to three times `wal_writer_delay`). A lost write is lost entirely,
+    /// and later writes are lost with it, so atomicity and ordering hold.
+    Asynchronous,
+}
+
+/// Maps keys starting with `prefix` to the cluster at `url`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PartitionRule {
+    /// Encoded key prefix, e.g. the encoding of the key `["eu"]`.
+    pub prefix: Vec<u8>,
+    /// PostgreSQL connection URL of the cluster holding these keys.
+    pub url: String,
 }
 
 impl Default for PostgresConfig {
@@ -26,6 +365,43 @@ impl Default for PostgresConfig {
             max_connections: 10,
             connection_timeout: 30,
             statement_timeout: 60,
+            verify_checksums: false,
+            statement_log_sample_rate: 0.0,
+            bulk_import_batch_size: None,
+            read_replica_urls: Vec::new(),
+            max_replica_lag: None,
+            partitions: Vec::new(),
+            write_ops_per_sec: None,
+            write_bytes_per_sec: None,
+            write_queue_limit: default_write_queue_limit(),
+            max_retries: default_max_retries(),
+            circuit_breaker_threshold: default_circuit_breaker_threshold(),
+            circuit_breaker_cooldown: default_circuit_breaker_cooldown(),
+            pool_wait_timeout: default_pool_wait_timeout(),
+            affinity_lanes: 0,
+            statement_cache_size: default_statement_cache_size(),
+            retry_budget: default_retry_budget(),
+            tombstone_retention: default_tombstone_retention(),
+            listen_for_changes: default_listen_for_changes(),
+            watch_lease: default_watch_lease(),
+            check_server_settings: default_check_server_settings(),
+            record_range_scans: false,
+            read_repair_window: 0,
+            expiry_sweep_interval: default_expiry_sweep_interval(),
+            expiry_sweep_batch_size: default_expiry_sweep_batch_size(),
+            analyze_threshold: default_analyze_threshold(),
+            reaper_batch_size: default_reaper_batch_size(),
+            reaper_rows_per_sec: None,
+            schema: None,
+            table_prefix: String::new(),
+            table_partitioning: TablePartitioning::None,
+            partition_split_rows: 0,
+            partition_archive_after: 0,
+            write_limits: default_write_limits(),
+            durability: Durability::default(),
+            max_delivery_attempts: None,
+            queue_visibility_timeout: default_queue_visibility_timeout(),
+            elect_maintenance_leader: default_elect_maintenance_leader(),
         }
     }
 }
@@ -56,4 +432,441 @@ impl PostgresConfig {
         self.statement_timeout = timeout;
         self
     }
-}
\ No newline at end of file
+
+    /// Enable checksum verification on reads
+    pub fn with_verify_checksums(mut self, verify: bool) -> Self {
+        self.verify_checksums = verify;
+        self
+    }
+
+    /// Set the fraction of statements to log at trace level
+    pub fn with_statement_log_sample_rate(mut self, sample_rate: f64) -> Self {
+        self.statement_log_sample_rate = sample_rate;
+        self
+    }
+
+    /// Set the maximum number of mutations per bulk import sub-transaction
+    pub fn with_bulk_import_batch_size(mut self, batch_size: usize) -> Self {
+        self.bulk_import_batch_size = Some(batch_size);
+        self
+    }
+
+    /// Add a read replica for eventually consistent reads
+    pub fn with_read_replica(mut self, url: String) -> Self {
+        self.read_replica_urls.push(url);
+        self
+    }
+
+    /// Skip read replicas lagging more than `seconds` behind the primary,
+    /// or never if `None`
+    pub fn with_max_replica_lag(mut self, seconds: Option<f64>) -> Self {
+        self.max_replica_lag = seconds;
+        self
+    }
+
+    /// Store keys starting with `prefix` in the cluster at `url`
+    pub fn with_partition(mut self, prefix: Vec<u8>, url: String) -> Self {
+        self.partitions.push(PartitionRule { prefix, url });
+        self
+    }
+
+    /// Throttle writes to at most `ops_per_sec` operations and
+    /// `bytes_per_sec` bytes per second
+    pub fn with_write_throttle(mut self, ops_per_sec: Option<f64>, bytes_per_sec: Option<f64>) -> Self {
+        self.write_ops_per_sec = ops_per_sec;
+        self.write_bytes_per_sec = bytes_per_sec;
+        self
+    }
+
+    /// Set how many throttled writes may wait before writes are rejected
+    pub fn with_write_queue_limit(mut self, limit: usize) -> Self {
+        self.write_queue_limit = limit;
+        self
+    }
+
+    /// Set how many times transient failures are retried
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Configure the circuit breaker. A threshold of 0 disables it.
+    pub fn with_circuit_breaker(mut self, threshold: u32, cooldown: u64) -> Self {
+        self.circuit_breaker_threshold = threshold;
+        self.circuit_breaker_cooldown = cooldown;
+        self
+    }
+
+    /// Set how many seconds operations wait for a pooled connection
+    pub fn with_pool_wait_timeout(mut self, seconds: u64) -> Self {
+        self.pool_wait_timeout = seconds;
+        self
+    }
+
+    /// Run atomic writes whose keys hash to the same of `lanes` lanes on
+    /// that lane's connection, or take a pooled one for every write if 0
+    pub fn with_affinity_lanes(mut self, lanes: usize) -> Self {
+        self.affinity_lanes = lanes;
+        self
+    }
+
+    /// Keep up to `size` statements prepared per connection, or none if 0
+    pub fn with_statement_cache_size(mut self, size: usize) -> Self {
+        self.statement_cache_size = size;
+        self
+    }
+
+    /// Set the average number of retries allowed per operation
+    pub fn with_retry_budget(mut self, ratio: f64) -> Self {
+        self.retry_budget = ratio;
+        self
+    }
+
+    /// Set how many seconds deletions are kept for change feeds
+    pub fn with_tombstone_retention(mut self, seconds: u64) -> Self {
+        self.tombstone_retention = seconds;
+        self
+    }
+
+    /// Enable or disable listening for changes made through other instances
+    pub fn with_listen_for_changes(mut self, listen: bool) -> Self {
+        self.listen_for_changes = listen;
+        self
+    }
+
+    /// Release the subscriptions of watches not polled for `seconds`, or
+    /// never if 0
+    pub fn with_watch_lease(mut self, seconds: u64) -> Self {
+        self.watch_lease = seconds;
+        self
+    }
+
+    /// Enable or disable checking the server's settings at startup
+    pub fn with_check_server_settings(mut self, check: bool) -> Self {
+        self.check_server_settings = check;
+        self
+    }
+
+    /// Enable or disable recording the shapes of range scans
+    pub fn with_record_range_scans(mut self, record: bool) -> Self {
+        self.record_range_scans = record;
+        self
+    }
+
+    /// Set how many seconds recently seen versions are used to repair
+    /// stale replica reads. 0 disables read repair.
+    pub fn with_read_repair_window(mut self, seconds: u64) -> Self {
+        self.read_repair_window = seconds;
+        self
+    }
+
+    /// Sweep expired rows every `interval` seconds, `batch_size` rows per
+    /// statement. An interval of 0 disables the sweeper.
+    pub fn with_expiry_sweep(mut self, interval: u64, batch_size: usize) -> Self {
+        self.expiry_sweep_interval = interval;
+        self.expiry_sweep_batch_size = batch_size;
+        self
+    }
+
+    /// Analyze tables once `rows` of them changed since they were last
+    /// analyzed, or leave it to autovacuum if 0
+    pub fn with_analyze_threshold(mut self, rows: u64) -> Self {
+        self.analyze_threshold = rows;
+        self
+    }
+
+    /// Reap deleted namespaces `batch_size` rows per table and transaction,
+    /// at most `rows_per_sec` rows per second if set
+    pub fn with_namespace_reaper(mut self, batch_size: usize, rows_per_sec: Option<f64>) -> Self {
+        self.reaper_batch_size = batch_size;
+        self.reaper_rows_per_sec = rows_per_sec;
+        self
+    }
+
+    /// Keep the tables in `schema` instead of the `search_path`
+    pub fn with_schema(mut self, schema: String) -> Self {
+        self.schema = Some(schema);
+        self
+    }
+
+    /// Prepend `prefix` to the name of every table and index
+    pub fn with_table_prefix(mut self, prefix: String) -> Self {
+        self.table_prefix = prefix;
+        self
+    }
+
+    /// Create `kv_store` partitioned as `partitioning` if it does not exist
+    pub fn with_table_partitioning(mut self, partitioning: TablePartitioning) -> Self {
+        self.table_partitioning = partitioning;
+        self
+    }
+
+    /// Split partitions of `kv_store` estimated to hold more than `rows`
+    pub fn with_partition_split_rows(mut self, rows: u64) -> Self {
+        self.partition_split_rows = rows;
+        self
+    }
+
+    /// Archive range partitions of `kv_store` not written to for `seconds`
+    pub fn with_partition_archive_after(mut self, seconds: u64) -> Self {
+        self.partition_archive_after = seconds;
+        self
+    }
+
+    /// Check atomic writes against `limits`, or not at all if `None`
+    pub fn with_write_limits(mut self, limits: Option<WriteLimits>) -> Self {
+        self.write_limits = limits;
+        self
+    }
+
+    /// Acknowledge atomic writes with `durability`
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Dead-letter messages that failed `attempts` times, or never if
+    /// `None`
+    pub fn with_max_delivery_attempts(mut self, attempts: Option<u32>) -> Self {
+        self.max_delivery_attempts = attempts;
+        self
+    }
+
+    /// Redeliver dequeued messages not kept alive for `seconds`
+    pub fn with_queue_visibility_timeout(mut self, seconds: u64) -> Self {
+        self.queue_visibility_timeout = seconds;
+        self
+    }
+
+    /// Run background maintenance on the elected leader only, or on every
+    /// instance
+    pub fn with_elect_maintenance_leader(mut self, elect: bool) -> Self {
+        self.elect_maintenance_leader = elect;
+        self
+    }
+
+    /// Check that the settings make sense, returning warnings about those
+    /// that are allowed but probably not intended. Every invalid setting is
+    /// listed in the error, not just the first. Run by
+    /// [`Postgres::new`](crate::Postgres::new), which logs the warnings.
+    pub fn validate(&self) -> PostgresResult<Vec<String>> {
+        let mut errors = Vec::new();
+        let mut warnings = Vec::new();
+
+        check_url("url", &self.url, &mut errors);
+        for (i, url) in self.read_replica_urls.iter().enumerate() {
+            check_url(&format!("read_replica_urls[{i}]"), url, &mut errors);
+        }
+        for (i, rule) in self.partitions.iter().enumerate() {
+            check_url(&format!("partitions[{i}].url"), &rule.url, &mut errors);
+            if rule.prefix.is_empty() {
+                errors.push(format!("partitions[{i}].prefix is empty, so it would hold every key"));
+            }
+            if self.partitions[..i].iter().any(|other| other.prefix == rule.prefix) {
+                errors.push(format!("partitions[{i}].prefix is the prefix of an earlier partition too"));
+            }
+        }
+
+        tables::check_names(self.schema.as_deref(), &self.table_prefix, &mut errors);
+        self.table_partitioning.validate(&mut errors);
+        if self.partition_split_rows > 0 && self.table_partitioning == TablePartitioning::None {
+            errors.push("partition_split_rows is set, but table_partitioning is none, so there are no partitions to split".to_string());
+        }
+        if self.partition_archive_after > 0 && !matches!(self.table_partitioning, TablePartitioning::Range { .. }) {
+            errors.push("partition_archive_after is set, but only range partitions hold keys that go cold together".to_string());
+        }
+        if let Some(limits) = &self.write_limits {
+            limits.validate(&mut errors);
+        }
+
+        if self.max_connections == 0 {
+            errors.push("max_connections is 0, so no connection could ever be made".to_string());
+        }
+        if self.affinity_lanes > 0 && self.affinity_lanes >= self.max_connections {
+            errors.push(format!(
+                "affinity_lanes ({}) is not less than max_connections ({}), so the lanes could hold every connection",
+                self.affinity_lanes, self.max_connections,
+            ));
+        }
+        if self.connection_timeout == 0 {
+            errors.push("connection_timeout is 0, so every connection attempt would time out".to_string());
+        }
+        if self.statement_timeout == 0 {
+            errors.push("statement_timeout is 0, so every statement would time out".to_string());
+        } else if self.statement_timeout < self.connection_timeout {
+            warnings.push(format!(
+                "statement_timeout ({}s) is shorter than connection_timeout ({}s), so operations may time out while still connecting",
+                self.statement_timeout, self.connection_timeout,
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.statement_log_sample_rate) {
+            errors.push(format!("statement_log_sample_rate is {}, expected 0.0 to 1.0", self.statement_log_sample_rate));
+        }
+        if self.bulk_import_batch_size == Some(0) {
+            errors.push("bulk_import_batch_size is 0; leave it unset to import in one transaction".to_string());
+        }
+        if self.max_delivery_attempts == Some(0) {
+            errors.push("max_delivery_attempts is 0, so no message could ever be delivered; leave it unset to retry for as long as backoff schedules last".to_string());
+        }
+        if self.queue_visibility_timeout == 0 {
+            errors.push("queue_visibility_timeout is 0, so every dequeued message would be delivered again right away".to_string());
+        } else if self.queue_visibility_timeout > MAX_QUEUE_VISIBILITY_TIMEOUT {
+            errors.push(format!(
+                "queue_visibility_timeout is {}s, expected at most {MAX_QUEUE_VISIBILITY_TIMEOUT}s (a week)",
+                self.queue_visibility_timeout,
+            ));
+        }
+        if self.expiry_sweep_interval > 0 && self.expiry_sweep_batch_size == 0 {
+            errors.push("expiry_sweep_batch_size is 0, so sweeps would never delete anything; set expiry_sweep_interval to 0 to disable them".to_string());
+        }
+        if self.reaper_batch_size == 0 {
+            errors.push("reaper_batch_size is 0, so deleted namespaces would never be reaped".to_string());
+        }
+        if let Some(rate) = self.reaper_rows_per_sec.filter(|rate| rate.is_nan() || *rate <= 0.0) {
+            errors.push(format!("reaper_rows_per_sec is {rate}, expected a positive rate"));
+        }
+
+        for (name, rate) in [("write_ops_per_sec", self.write_ops_per_sec), ("write_bytes_per_sec", self.write_bytes_per_sec)] {
+            match rate {
+                Some(rate) if rate.is_nan() || rate < 0.0 => errors.push(format!("{name} is {rate}, expected a positive rate")),
+                Some(0.0) => warnings.push(format!("{name} is 0, which does not limit writes; leave it unset instead")),
+                _ => {}
+            }
+        }
+        let throttled = [self.write_ops_per_sec, self.write_bytes_per_sec].iter().flatten().any(|rate| *rate > 0.0);
+        if throttled && self.write_queue_limit == 0 {
+            warnings.push("write_queue_limit is 0, so every write that has to wait for the throttle is rejected".to_string());
+        }
+
+        if self.max_retries > MAX_RETRIES {
+            errors.push(format!("max_retries is {}, expected at most {MAX_RETRIES}", self.max_retries));
+        }
+        if self.circuit_breaker_threshold > 0 && self.circuit_breaker_cooldown == 0 {
+            warnings.push("circuit_breaker_cooldown is 0, so an open circuit never fails fast; set circuit_breaker_threshold to 0 to disable it".to_string());
+        }
+        match self.max_replica_lag {
+            Some(lag) if lag.is_nan() || lag <= 0.0 => errors.push(format!("max_replica_lag is {lag}, expected a positive number of seconds")),
+            Some(_) if self.read_replica_urls.is_empty() => warnings.push("max_replica_lag is set without read replicas, so it has no effect".to_string()),
+            _ => {}
+        }
+        if self.retry_budget.is_nan() || self.retry_budget < 0.0 {
+            errors.push(format!("retry_budget is {}, expected 0 or more", self.retry_budget));
+        }
+
+        if errors.is_empty() {
+            Ok(warnings)
+        } else {
+            Err(PostgresError::InvalidConfig(errors.join("; ")))
+        }
+    }
+}
+
+/// The deadline of a message dequeued or kept alive at `now_ms`, when it
+/// counts as failed unless it finished, with a
+/// [`PostgresConfig::queue_visibility_timeout`] of `visibility_timeout`.
+pub(crate) fn queue_running_deadline(visibility_timeout: u64, now_ms: i64) -> i64 {
+    let timeout_ms = i64::try_from(visibility_timeout).unwrap_or(i64::MAX).saturating_mul(1000);
+    now_ms.saturating_add(timeout_ms)
+}
+
+fn check_url(name: &str, url: &str, errors: &mut Vec<String>) {
+    if let Err(e) = url.parse::<tokio_postgres::Config>() {
+        errors.push(format!("{name} is not a valid PostgreSQL URL: {e}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> PostgresConfig {
+        PostgresConfig::new("postgresql://postgres@localhost:5432/denokv".to_string())
+    }
+
+    fn errors(config: &PostgresConfig) -> String {
+        match config.validate() {
+            Err(PostgresError::InvalidConfig(errors)) => errors,
+            other => panic!("expected an invalid configuration, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn default_is_valid() {
+        assert_eq!(base().validate().unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn lists_every_error() {
+        let config = base()
+            .with_max_connections(0)
+            .with_affinity_lanes(1)
+            .with_statement_timeout(0)
+            .with_read_replica("not a url".to_string())
+            .with_statement_log_sample_rate(1.5)
+            .with_max_retries(100)
+            .with_retry_budget(f64::NAN)
+            .with_max_delivery_attempts(Some(0))
+            .with_queue_visibility_timeout(0)
+            .with_max_replica_lag(Some(0.0))
+            .with_namespace_reaper(0, Some(-1.0))
+            .with_table_partitioning(TablePartitioning::Hash { partitions: 0 })
+            .with_partition_archive_after(60);
+        let errors = errors(&config);
+        for setting in ["max_connections", "affinity_lanes", "statement_timeout", "read_replica_urls[0]", "statement_log_sample_rate", "max_retries", "retry_budget", "max_delivery_attempts", "queue_visibility_timeout", "max_replica_lag", "reaper_batch_size", "reaper_rows_per_sec", "table_partitioning", "partition_archive_after"] {
+            assert!(errors.contains(setting), "{setting} missing from {errors}");
+        }
+        assert!(!errors.contains("connection_timeout"));
+    }
+
+    #[test]
+    fn bounds_queue_visibility_timeout() {
+        let config = base().with_queue_visibility_timeout(u64::MAX);
+        assert!(errors(&config).contains("queue_visibility_timeout is 18446744073709551615s"));
+        assert_eq!(queue_running_deadline(config.queue_visibility_timeout, 1_000), i64::MAX);
+        assert_eq!(queue_running_deadline(30, 1_000), 31_000);
+    }
+
+    #[test]
+    fn rejects_overlapping_partitions() {
+        let url = "postgresql://postgres@localhost:5432/eu".to_string();
+        let config = base().with_partition(b"\x02eu\x00".to_vec(), url.clone()).with_partition(b"\x02eu\x00".to_vec(), url.clone());
+        assert!(errors(&config).contains("partitions[1].prefix"));
+        assert!(errors(&base().with_partition(vec![], url)).contains("partitions[0].prefix is empty"));
+    }
+
+    #[test]
+    fn manages_only_partitioned_tables() {
+        assert!(errors(&base().with_partition_split_rows(1_000_000)).contains("partition_split_rows"));
+        let range = TablePartitioning::Range { split_points: vec![vec![0x80]] };
+        let config = base().with_table_partitioning(range).with_partition_split_rows(1_000_000).with_partition_archive_after(86_400);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_unsafe_table_names() {
+        assert!(base().with_schema("tenant_a".to_string()).with_table_prefix("kv_".to_string()).validate().is_ok());
+        assert!(errors(&base().with_schema("a; DROP TABLE x".to_string())).contains("schema"));
+        assert!(errors(&base().with_table_prefix("App".to_string())).contains("table_prefix"));
+    }
+
+    #[test]
+    fn rejects_zero_write_limits() {
+        let limits = WriteLimits { max_checks: 0, ..Default::default() };
+        assert!(errors(&base().with_write_limits(Some(limits))).contains("write_limits.max_checks"));
+        assert!(base().with_write_limits(None).validate().is_ok());
+    }
+
+    #[test]
+    fn warns_about_surprising_settings() {
+        let warnings = base()
+            .with_connection_timeout(30)
+            .with_statement_timeout(10)
+            .with_write_throttle(Some(0.0), Some(1000.0))
+            .with_write_queue_limit(0)
+            .with_circuit_breaker(5, 0)
+            .validate()
+            .unwrap();
+        assert_eq!(warnings.len(), 4, "{warnings:?}");
+        assert!(warnings[0].starts_with("statement_timeout (10s) is shorter than connection_timeout (30s)"));
+    }
+}
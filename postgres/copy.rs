@@ -0,0 +1,257 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+//! Copying keys into another database, such as production data into a
+//! staging environment, with [`Transforms`] anonymizing values on the way.
+
+use denokv_proto::v8::{self, FieldEdit};
+use denokv_proto::{AtomicWrite, Database, KvValue, Mutation, MutationKind};
+
+use crate::backend::PostgresBackend;
+use crate::error::{PostgresError, PostgresResult};
+use crate::limits::WriteLimits;
+use crate::throttle;
+
+/// How many changes are read from the source at a time.
+const COPY_BATCH_SIZE: i64 = 1000;
+
+type ValueTransform = Box<dyn Fn(&[u8], KvValue) -> PostgresResult<Option<KvValue>> + Send + Sync>;
+
+/// Value transforms applied by [`Postgres::copy_to`](crate::Postgres::copy_to),
+/// each to the keys under one prefix. Every transform whose prefix a key
+/// starts with is applied, in the order they were added. A transform
+/// returning `None` leaves the entry out of the copy.
+#[derive(Default)]
+pub struct Transforms {
+    rules: Vec<(Vec<u8>, ValueTransform)>,
+}
+
+/// What [`Postgres::copy_to`](crate::Postgres::copy_to) copied.
+#[derive(Debug, Default)]
+pub struct CopyReport {
+    /// Entries written to the destination.
+    pub entries_copied: u64,
+    /// Entries a transform left out.
+    pub entries_skipped: u64,
+    /// Keys deleted in the destination because they were deleted in the
+    /// source, within the tombstone retention.
+    pub keys_deleted: u64,
+}
+
+impl Transforms {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pass the values of keys starting with `prefix` through `transform`,
+    /// which gets the key too.
+    pub fn map(
+        mut self,
+        prefix: Vec<u8>,
+        transform: impl Fn(&[u8], KvValue) -> Option<KvValue> + Send + Sync + 'static,
+    ) -> Self {
+        self.rules.push((prefix, Box::new(move |key, value| Ok(transform(key, value)))));
+        self
+    }
+
+    /// Leave the keys starting with `prefix` out of the copy.
+    pub fn skip(self, prefix: Vec<u8>) -> Self {
+        self.map(prefix, |_, _| None)
+    }
+
+    /// Remove the properties named in `fields` from the objects and maps in
+    /// V8 values under `prefix`, at any depth.
+    pub fn drop_fields(self, prefix: Vec<u8>, fields: Vec<String>) -> Self {
+        self.edit_fields(prefix, move |name, _| {
+            if fields.iter().any(|field| field == name) {
+                FieldEdit::Remove
+            } else {
+                FieldEdit::Keep
+            }
+        })
+    }
+
+    /// Replace string properties named in `fields` in V8 values under
+    /// `prefix`, at any depth, with a hex hash of the string. Equal strings
+    /// hash alike, so values still match up across keys. The hash is not
+    /// cryptographic: a guessable string such as an email address can be
+    /// found by hashing candidates, so drop fields that must not be
+    /// recoverable.
+    pub fn hash_fields(self, prefix: Vec<u8>, fields: Vec<String>) -> Self {
+        self.edit_fields(prefix, move |name, value| match value {
+            Some(value) if fields.iter().any(|field| field == name) => {
+                FieldEdit::Replace(format!("{:032x}", xxhash_rust::xxh3::xxh3_128(value.as_bytes())))
+            }
+            _ => FieldEdit::Keep,
+        })
+    }
+
+    /// Other values than V8 ones are left alone. A V8 value that can not be
+    /// decoded fails the copy rather than being copied unedited.
+    fn edit_fields(
+        mut self,
+        prefix: Vec<u8>,
+        edit: impl Fn(&str, Option<&str>) -> FieldEdit + Send + Sync + 'static,
+    ) -> Self {
+        self.rules.push((
+            prefix,
+            Box::new(move |key, value| match value {
+                KvValue::V8(bytes) => v8::edit_fields(&bytes, &edit).map(|bytes| Some(KvValue::V8(bytes))).map_err(|e| {
+                    PostgresError::DeserializationError(format!(
+                        "Failed to edit the value of {}: {e}",
+                        denokv_proto::format_key(key)
+                    ))
+                }),
+                value => Ok(Some(value)),
+            }),
+        ));
+        self
+    }
+
+    /// `value` of `key` with the matching transforms applied, or `None` if
+    /// one of them left it out.
+    pub fn apply(&self, key: &[u8], mut value: KvValue) -> PostgresResult<Option<KvValue>> {
+        for (prefix, transform) in &self.rules {
+            if key.starts_with(prefix) {
+                match transform(key, value)? {
+                    Some(transformed) => value = transformed,
+                    None => return Ok(None),
+                }
+            }
+        }
+        Ok(Some(value))
+    }
+}
+
+/// Copy the keys under `prefix` from `backend` into `dest`, following the
+/// change feed from its start so that writes made during the copy are
+/// copied too. Expiry times are kept; versionstamps are assigned by `dest`.
+pub async fn copy_to<D: Database>(
+    backend: &PostgresBackend,
+    prefix: &[u8],
+    dest: &D,
+    transforms: &Transforms,
+) -> PostgresResult<CopyReport> {
+    let limits = WriteLimits::default();
+    let mut report = CopyReport::default();
+    let (mut after, mut after_key) = ([0; 10], Vec::new());
+    loop {
+        let changes = backend.poll_changes(prefix, &after, &after_key, COPY_BATCH_SIZE).await?;
+        let done = (changes.len() as i64) < COPY_BATCH_SIZE;
+        let Some(last) = changes.last() else {
+            return Ok(report);
+        };
+        (after, after_key) = (last.versionstamp, last.key.clone());
+
+        let mut mutations = Vec::with_capacity(changes.len());
+        for change in changes {
+            let kind = match change.value {
+                Some(value) => match transforms.apply(&change.key, value)? {
+                    Some(value) => {
+                        report.entries_copied += 1;
+                        MutationKind::Set(value)
+                    }
+                    None => {
+                        report.entries_skipped += 1;
+                        continue;
+                    }
+                },
+                None => {
+                    report.keys_deleted += 1;
+                    MutationKind::Delete
+                }
+            };
+            mutations.push(Mutation { key: change.key, kind, expire_at: change.expire_at });
+        }
+        for write in batches(mutations, &limits) {
+            dest.atomic_write(write)
+                .await
+                .map_err(|e| PostgresError::DatabaseError(format!("Failed to write to the destination: {e}")))?;
+        }
+        if done {
+            return Ok(report);
+        }
+    }
+}
+
+/// Split `mutations` into atomic writes within `limits`. A mutation too
+/// large on its own is written alone, for the destination to reject.
+fn batches(mutations: Vec<Mutation>, limits: &WriteLimits) -> Vec<AtomicWrite> {
+    let mut writes = Vec::new();
+    let mut batch: Vec<Mutation> = Vec::new();
+    let mut size: usize = 0;
+    for mutation in mutations {
+        let mutation_size = usize::try_from(throttle::mutations_size([&mutation])).unwrap_or(usize::MAX);
+        if !batch.is_empty() && (batch.len() >= limits.max_mutations || size.saturating_add(mutation_size) > limits.max_total_size) {
+            writes.push(AtomicWrite { checks: vec![], mutations: std::mem::take(&mut batch), enqueues: vec![] });
+            size = 0;
+        }
+        size = size.saturating_add(mutation_size);
+        batch.push(mutation);
+    }
+    if !batch.is_empty() {
+        writes.push(AtomicWrite { checks: vec![], mutations: batch, enqueues: vec![] });
+    }
+    writes
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        email: String,
+        ssn: Option<String>,
+    }
+
+    fn user() -> KvValue {
+        let user = User { email: "zoe@example.com".to_string(), ssn: Some("123".to_string()) };
+        KvValue::V8(v8::to_vec(&user).unwrap())
+    }
+
+    fn decode(value: Option<KvValue>) -> User {
+        match value {
+            Some(KvValue::V8(bytes)) => v8::from_slice(&bytes).unwrap(),
+            other => panic!("expected a V8 value, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn applies_matching_transforms_in_order() {
+        let transforms = Transforms::new()
+            .drop_fields(b"\x02users".to_vec(), vec!["ssn".to_string()])
+            .hash_fields(b"\x02users".to_vec(), vec!["email".to_string()])
+            .skip(b"\x02sessions".to_vec())
+            .map(b"\x02counters".to_vec(), |_, _| Some(KvValue::U64(0)));
+
+        let anonymized = decode(transforms.apply(b"\x02users\x00\x021\x00", user()).unwrap());
+        assert_eq!(anonymized.ssn, None);
+        assert_eq!(anonymized.email, format!("{:032x}", xxhash_rust::xxh3::xxh3_128(b"zoe@example.com")));
+        // Bytes values have no fields.
+        assert!(matches!(transforms.apply(b"\x02users\x00", KvValue::Bytes(vec![1])).unwrap(), Some(KvValue::Bytes(_))));
+
+        assert!(transforms.apply(b"\x02sessions\x00", user()).unwrap().is_none());
+        assert!(matches!(transforms.apply(b"\x02counters\x00", KvValue::U64(7)).unwrap(), Some(KvValue::U64(0))));
+        assert_eq!(decode(transforms.apply(b"\x02orders\x00", user()).unwrap()).ssn.as_deref(), Some("123"));
+    }
+
+    #[test]
+    fn fails_on_undecodable_values() {
+        let transforms = Transforms::new().drop_fields(Vec::new(), vec!["ssn".to_string()]);
+        assert!(matches!(
+            transforms.apply(b"\x02a", KvValue::V8(vec![0xff])),
+            Err(PostgresError::DeserializationError(_))
+        ));
+    }
+
+    #[test]
+    fn batches_within_limits() {
+        let set = |i: u8, size| Mutation { key: vec![i], kind: MutationKind::Set(KvValue::Bytes(vec![0; size])), expire_at: None };
+        let limits = WriteLimits { max_mutations: 2, max_total_size: 100, ..Default::default() };
+        let writes = batches(vec![set(0, 10), set(1, 10), set(2, 10), set(3, 90), set(4, 200)], &limits);
+        let sizes: Vec<usize> = writes.iter().map(|write| write.mutations.len()).collect();
+        assert_eq!(sizes, vec![2, 1, 1, 1]);
+    }
+}
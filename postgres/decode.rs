@@ -0,0 +1,142 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+use denokv_proto::{KvEntry, KvValue, Versionstamp};
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::error::{CorruptionKind, PostgresError, PostgresResult};
+
+/// Value encoding tags stored in the `value_encoding` column.
+pub const ENCODING_V8: i32 = 1;
+pub const ENCODING_LE64: i32 = 2;
+pub const ENCODING_BYTES: i32 = 3;
+
+/// Decode a raw `kv_store` row into a `KvEntry`.
+///
+/// Rows are never trusted to have the expected shape: a truncated
+/// versionstamp or a LE64 value of the wrong length is reported as
+/// `PostgresError::CorruptRow` instead of panicking.
+pub fn decode_entry(
+    key: Vec<u8>,
+    value: Vec<u8>,
+    encoding: i32,
+    versionstamp: &[u8],
+) -> PostgresResult<KvEntry> {
+    let versionstamp = match decode_versionstamp(versionstamp) {
+        Ok(versionstamp) => versionstamp,
+        Err(kind) => return Err(PostgresError::CorruptRow { key, kind }),
+    };
+    let value = match decode_value(value, encoding) {
+        Ok(value) => value,
+        Err(kind) => return Err(PostgresError::CorruptRow { key, kind }),
+    };
+    Ok(KvEntry {
+        key,
+        value,
+        versionstamp,
+    })
+}
+
+/// Decode a stored value according to its encoding tag.
+pub fn decode_value(value: Vec<u8>, encoding: i32) -> Result<KvValue, CorruptionKind> {
+    match encoding {
+        ENCODING_V8 => Ok(KvValue::V8(value)),
+        ENCODING_LE64 => {
+            let buf: [u8; 8] = value
+                .as_slice()
+                .try_into()
+                .map_err(|_| CorruptionKind::U64Length(value.len()))?;
+            Ok(KvValue::U64(u64::from_le_bytes(buf)))
+        }
+        ENCODING_BYTES => Ok(KvValue::Bytes(value)),
+        _ => Err(CorruptionKind::UnknownEncoding(encoding)),
+    }
+}
+
+/// Decode a stored versionstamp, which must be exactly 10 bytes.
+pub fn decode_versionstamp(versionstamp: &[u8]) -> Result<Versionstamp, CorruptionKind> {
+    versionstamp
+        .try_into()
+        .map_err(|_| CorruptionKind::VersionstampLength(versionstamp.len()))
+}
+
+/// Compute the checksum stored alongside a row in the `checksum` column.
+///
+/// The key is length-prefixed so that moving bytes between key and value
+/// changes the checksum.
+pub fn row_checksum(key: &[u8], value: &[u8], encoding: i32) -> i64 {
+    let mut hasher = Xxh3::new();
+    hasher.update(&(key.len() as u64).to_le_bytes());
+    hasher.update(key);
+    hasher.update(value);
+    hasher.update(&encoding.to_le_bytes());
+    hasher.digest() as i64
+}
+
+/// Check a row against its stored checksum. Rows written before checksums
+/// were introduced have no checksum and always pass.
+pub fn verify_checksum(
+    key: &[u8],
+    value: &[u8],
+    encoding: i32,
+    checksum: Option<i64>,
+) -> Result<(), CorruptionKind> {
+    match checksum {
+        Some(expected) if expected != row_checksum(key, value, encoding) => {
+            Err(CorruptionKind::ChecksumMismatch)
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_well_formed_rows() {
+        let entry = decode_entry(b"k".to_vec(), 7u64.to_le_bytes().to_vec(), ENCODING_LE64, &[1; 10]).unwrap();
+        assert_eq!(entry.key, b"k");
+        assert!(matches!(entry.value, KvValue::U64(7)));
+        assert_eq!(entry.versionstamp, [1; 10]);
+    }
+
+    #[test]
+    fn reports_corrupt_rows() {
+        let err = decode_entry(b"k".to_vec(), vec![], ENCODING_BYTES, &[0; 9]).unwrap_err();
+        assert!(matches!(
+            err,
+            PostgresError::CorruptRow { kind: CorruptionKind::VersionstampLength(9), .. }
+        ));
+
+        let err = decode_entry(b"k".to_vec(), vec![1, 2, 3], ENCODING_LE64, &[0; 10]).unwrap_err();
+        assert!(matches!(
+            err,
+            PostgresError::CorruptRow { kind: CorruptionKind::U64Length(3), .. }
+        ));
+
+        let err = decode_entry(b"k".to_vec(), vec![], 42, &[0; 10]).unwrap_err();
+        assert!(matches!(
+            err,
+            PostgresError::CorruptRow { kind: CorruptionKind::UnknownEncoding(42), .. }
+        ));
+    }
+
+    #[test]
+    fn checksums_detect_modification() {
+        let checksum = row_checksum(b"key", b"value", ENCODING_BYTES);
+        assert_eq!(verify_checksum(b"key", b"value", ENCODING_BYTES, Some(checksum)), Ok(()));
+        assert_eq!(verify_checksum(b"key", b"value", ENCODING_BYTES, None), Ok(()));
+        assert_eq!(
+            verify_checksum(b"key", b"valuf", ENCODING_BYTES, Some(checksum)),
+            Err(CorruptionKind::ChecksumMismatch)
+        );
+        assert_eq!(
+            verify_checksum(b"keyv", b"alue", ENCODING_BYTES, Some(checksum)),
+            Err(CorruptionKind::ChecksumMismatch)
+        );
+        assert_eq!(
+            verify_checksum(b"key", b"value", ENCODING_V8, Some(checksum)),
+            Err(CorruptionKind::ChecksumMismatch)
+        );
+    }
+}
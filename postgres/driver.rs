@@ -0,0 +1,912 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+//! The statements and rules shared by the drivers.
+//!
+//! [`crate::Postgres`] talks to the server through tokio-postgres and a
+//! [`crate::ConnectionPool`], and with the `driver-sqlx` feature
+//! `SqlxPostgres` talks to it through sqlx. Both run the statements and apply the rules defined here,
+//! so that they store data the same way and can share a database.
+
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+use denokv_proto::{Enqueue, KvEntry, KvValue, Mutation, MutationKind, ReadRange, Versionstamp};
+use denokv_sqlite::SumOperand;
+
+use crate::decode::{decode_value, row_checksum, ENCODING_LE64};
+use crate::error::{PostgresError, PostgresResult};
+use crate::range::KeyBounds;
+
+/// Retry delays in milliseconds of messages enqueued without a backoff
+/// schedule, matching SQLite.
+pub const DEFAULT_BACKOFF_SCHEDULE: [u32; 5] = [100, 1000, 5000, 30000, 60000];
+
+/// Creates the main KV table, first of [`SCHEMA`]. Partitioned tables are
+/// created from it by [`crate::TablePartitioning`].
+pub const CREATE_KV_STORE: &str = r#"
+    CREATE TABLE IF NOT EXISTS kv_store (
+        key BYTEA PRIMARY KEY,
+        value BYTEA NOT NULL,
+        value_encoding INTEGER NOT NULL,
+        versionstamp BYTEA NOT NULL,
+        created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+        updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+        expires_at BIGINT
+    )
+"#;
+
+/// The statements creating and upgrading the schema, in order. Each is safe
+/// to run again on a database that already has it.
+pub const SCHEMA: &[&str] = &[
+    // The main KV table
+    CREATE_KV_STORE,
+    // Per-row checksum of key + value + encoding. Nullable so that
+    // databases created before checksums were introduced keep working.
+    "ALTER TABLE kv_store ADD COLUMN IF NOT EXISTS checksum BIGINT",
+    // Indexes for performance
+    "CREATE INDEX IF NOT EXISTS idx_kv_versionstamp ON kv_store(versionstamp)",
+    "CREATE INDEX IF NOT EXISTS idx_kv_expires_at ON kv_store(expires_at) WHERE expires_at IS NOT NULL",
+    "CREATE INDEX IF NOT EXISTS idx_kv_updated_at ON kv_store(updated_at)",
+    // Queue tables
+    r#"
+    CREATE TABLE IF NOT EXISTS queue_messages (
+        id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+        payload BYTEA NOT NULL,
+        deadline BIGINT NOT NULL,
+        keys_if_undelivered BYTEA[] NOT NULL,
+        backoff_schedule INTEGER[],
+        created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+        retry_count INTEGER DEFAULT 0
+    )
+    "#,
+    r#"
+    CREATE TABLE IF NOT EXISTS queue_running (
+        message_id UUID PRIMARY KEY REFERENCES queue_messages(id),
+        deadline BIGINT NOT NULL,
+        started_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+        updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+    )
+    "#,
+    // Messages enqueued through an ephemeral namespace carry its key
+    // prefix, so that they are only dequeued and dropped with it. NULL for
+    // messages of the database itself.
+    "ALTER TABLE queue_messages ADD COLUMN IF NOT EXISTS namespace BYTEA",
+    // Due messages with a higher priority are dequeued first. 0 for
+    // messages enqueued without one.
+    "ALTER TABLE queue_messages ADD COLUMN IF NOT EXISTS priority SMALLINT NOT NULL DEFAULT 0",
+    // Indexes for the queue
+    "CREATE INDEX IF NOT EXISTS idx_queue_deadline ON queue_messages(deadline)",
+    "CREATE INDEX IF NOT EXISTS idx_queue_priority_deadline ON queue_messages(priority DESC, deadline)",
+    "CREATE INDEX IF NOT EXISTS idx_queue_running_deadline ON queue_running(deadline)",
+    // Monotonic version counter — matches SQLite's data_version table. The
+    // single row is locked by UPDATE during atomic_write, which serializes
+    // all writers without needing SERIALIZABLE isolation.
+    r#"
+    CREATE TABLE IF NOT EXISTS data_version (
+        k INTEGER PRIMARY KEY DEFAULT 0,
+        version BIGINT NOT NULL DEFAULT 0
+    )
+    "#,
+    // Seed the row if it doesn't exist
+    "INSERT INTO data_version (k, version) VALUES (0, 0) ON CONFLICT DO NOTHING",
+    // Keys deleted within the tombstone retention, so that change feeds can
+    // deliver deletions. `tombstone_horizon` is the newest versionstamp of a
+    // tombstone trimmed since; a feed positioned before it may have missed
+    // deletions.
+    r#"
+    CREATE TABLE IF NOT EXISTS kv_tombstones (
+        key BYTEA PRIMARY KEY,
+        versionstamp BYTEA NOT NULL,
+        deleted_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+    )
+    "#,
+    "CREATE INDEX IF NOT EXISTS idx_kv_tombstones_versionstamp ON kv_tombstones(versionstamp)",
+    "ALTER TABLE data_version ADD COLUMN IF NOT EXISTS tombstone_horizon BYTEA NOT NULL DEFAULT ''",
+    // Durable change-stream subscriptions and how far each has been
+    // delivered, as a (versionstamp, key) cursor.
+    r#"
+    CREATE TABLE IF NOT EXISTS subscriptions (
+        name TEXT PRIMARY KEY,
+        prefix BYTEA NOT NULL,
+        last_versionstamp BYTEA NOT NULL,
+        last_key BYTEA NOT NULL DEFAULT '',
+        updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+    )
+    "#,
+    // Materialized views and how far each has applied the change feed of
+    // its source prefix, as a (versionstamp, key) cursor.
+    r#"
+    CREATE TABLE IF NOT EXISTS materialized_views (
+        name TEXT PRIMARY KEY,
+        source_prefix BYTEA NOT NULL,
+        target_prefix BYTEA NOT NULL,
+        last_versionstamp BYTEA NOT NULL,
+        last_key BYTEA NOT NULL DEFAULT '',
+        updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+    )
+    "#,
+    // The view keys derived from each source key, so that they can be
+    // removed when the source key changes.
+    r#"
+    CREATE TABLE IF NOT EXISTS materialized_view_keys (
+        view_name TEXT NOT NULL REFERENCES materialized_views(name) ON DELETE CASCADE,
+        source_key BYTEA NOT NULL,
+        target_keys BYTEA[] NOT NULL,
+        PRIMARY KEY (view_name, source_key)
+    )
+    "#,
+    // Progress of batched bulk imports, so an interrupted import can be
+    // resumed from the last committed batch.
+    r#"
+    CREATE TABLE IF NOT EXISTS bulk_import_journal (
+        import_id TEXT PRIMARY KEY,
+        committed BIGINT NOT NULL DEFAULT 0,
+        updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+    )
+    "#,
+    // Checkpoints and leases of backfill jobs.
+    r#"
+    CREATE TABLE IF NOT EXISTS backfill_jobs (
+        name TEXT PRIMARY KEY,
+        lease_owner UUID,
+        lease_expires_at TIMESTAMPTZ,
+        last_key BYTEA,
+        processed BIGINT NOT NULL DEFAULT 0,
+        rewritten BIGINT NOT NULL DEFAULT 0,
+        done BOOLEAN NOT NULL DEFAULT FALSE,
+        updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+    )
+    "#,
+    // Messages that failed `max_delivery_attempts` times, kept until they
+    // are requeued or deleted. `backoff_schedule` is what was left of it.
+    r#"
+    CREATE TABLE IF NOT EXISTS queue_dead_letter (
+        id UUID PRIMARY KEY,
+        payload BYTEA NOT NULL,
+        keys_if_undelivered BYTEA[] NOT NULL,
+        backoff_schedule INTEGER[],
+        namespace BYTEA,
+        priority SMALLINT NOT NULL DEFAULT 0,
+        attempts INTEGER NOT NULL,
+        enqueued_at TIMESTAMPTZ,
+        failed_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+    )
+    "#,
+    "CREATE INDEX IF NOT EXISTS idx_queue_dead_letter_failed ON queue_dead_letter(failed_at)",
+    // The keys_if_undelivered and backoff schedule of a message, in the
+    // encoding tagged by metadata_encoding. NULL for messages enqueued
+    // before, which keep them in the array columns. See queue_metadata.rs.
+    "ALTER TABLE queue_messages ADD COLUMN IF NOT EXISTS metadata BYTEA",
+    "ALTER TABLE queue_messages ADD COLUMN IF NOT EXISTS metadata_encoding INTEGER",
+    "ALTER TABLE queue_messages ALTER COLUMN keys_if_undelivered DROP NOT NULL",
+    "ALTER TABLE queue_dead_letter ADD COLUMN IF NOT EXISTS metadata BYTEA",
+    "ALTER TABLE queue_dead_letter ADD COLUMN IF NOT EXISTS metadata_encoding INTEGER",
+    "ALTER TABLE queue_dead_letter ALTER COLUMN keys_if_undelivered DROP NOT NULL",
+    // Namespaces whose rows are being deleted in the background, with how
+    // many rows of each kind are gone so far. `completed_at` is set once
+    // nothing is left.
+    r#"
+    CREATE TABLE IF NOT EXISTS namespace_deletions (
+        prefix BYTEA PRIMARY KEY,
+        deleted_keys BIGINT NOT NULL DEFAULT 0,
+        deleted_tombstones BIGINT NOT NULL DEFAULT 0,
+        deleted_view_keys BIGINT NOT NULL DEFAULT 0,
+        deleted_messages BIGINT NOT NULL DEFAULT 0,
+        requested_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        completed_at TIMESTAMPTZ
+    )
+    "#,
+    // The highest SCHEMA_VERSION that opened the database, so that health
+    // checks can tell which release last upgraded it.
+    "ALTER TABLE data_version ADD COLUMN IF NOT EXISTS schema_version INTEGER NOT NULL DEFAULT 0",
+    // The number of mutations of a bulk import and a fingerprint of them, so
+    // that resuming it with other mutations fails instead of skipping the
+    // wrong ones. NULL for imports journaled before.
+    "ALTER TABLE bulk_import_journal ADD COLUMN IF NOT EXISTS total BIGINT",
+    "ALTER TABLE bulk_import_journal ADD COLUMN IF NOT EXISTS fingerprint BYTEA",
+];
+
+/// The version of the schema created by [`SCHEMA`]: the number of its
+/// statements, which are only ever appended to.
+pub const SCHEMA_VERSION: u32 = SCHEMA.len() as u32;
+
+/// Records that the schema is at version `$1`, unless a newer release
+/// upgraded it further.
+pub const RECORD_SCHEMA_VERSION: &str = "UPDATE data_version SET schema_version = GREATEST(schema_version, $1) WHERE k = 0";
+
+/// The partitioning strategy of `kv_store`, `h`, `r` or NULL if it is not
+/// partitioned, and its number of partitions. No row if it does not exist.
+pub const KV_STORE_LAYOUT: &str = r#"
+    SELECT p.partstrat::text AS strategy,
+           (SELECT count(*) FROM pg_inherits i WHERE i.inhparent = c.oid) AS partitions
+    FROM pg_class c
+    LEFT JOIN pg_partitioned_table p ON p.partrelid = c.oid
+    WHERE c.oid = to_regclass('kv_store')
+"#;
+
+/// Lists the partitions of `kv_store` with their bounds as printed by
+/// `pg_get_expr` and their rows as estimated by the planner, -1 if they
+/// were never analyzed.
+pub const KV_STORE_PARTITIONS: &str = r#"
+    SELECT c.relname, pg_get_expr(c.relpartbound, c.oid) AS bound, c.reltuples::int8 AS rows
+    FROM pg_inherits i
+    JOIN pg_class c ON c.oid = i.inhrelid
+    WHERE i.inhparent = to_regclass('kv_store')
+    ORDER BY c.relname
+"#;
+
+/// Lists the tables next to `kv_store` whose names start with `$1`, the
+/// partitions the partition manager archived, with the bounds they had as
+/// recorded in their comments.
+pub const ARCHIVED_PARTITIONS: &str = r#"
+    SELECT c.relname, obj_description(c.oid, 'pg_class') AS bound
+    FROM pg_class c
+    WHERE c.relnamespace = (SELECT relnamespace FROM pg_class WHERE oid = to_regclass('kv_store'))
+      AND c.relkind = 'r'
+      AND starts_with(c.relname, $1)
+    ORDER BY c.relname
+"#;
+
+/// The key in the middle of the planner's histogram of the keys of the
+/// table `$1`, NULL if it has none.
+pub const MEDIAN_KEY: &str = r#"
+    SELECT b[array_length(b, 1) / 2 + 1] FROM (
+        SELECT s.histogram_bounds::text::bytea[] AS b
+        FROM pg_stats s
+        JOIN pg_namespace n ON n.nspname = s.schemaname
+        JOIN pg_class c ON c.relnamespace = n.oid AND c.relname = s.tablename
+        WHERE c.oid = to_regclass($1) AND s.attname = 'key'
+    ) AS histogram
+"#;
+
+/// Compares pairs of BYTEA values, given as two arrays, returning one
+/// boolean per pair in order.
+pub const BYTE_ORDER_CHECK: &str =
+    "SELECT a < b FROM unnest($1::bytea[], $2::bytea[]) WITH ORDINALITY AS t(a, b, i) ORDER BY i";
+
+/// Reads the live entries with `$1 <= key < $2` (no upper bound if `$2` is
+/// NULL), at most `$3` of them, as of `$4` milliseconds since the epoch.
+pub const READ_RANGE: &str = r#"
+    SELECT key, value, value_encoding, versionstamp, checksum
+    FROM kv_store
+    WHERE key >= $1 AND ($2::bytea IS NULL OR key < $2)
+      AND (expires_at IS NULL OR expires_at > $4)
+    ORDER BY key ASC
+    LIMIT $3
+"#;
+
+/// [`READ_RANGE`] with the expiry time of each entry, for scans that write
+/// entries back.
+pub const SCAN: &str = r#"
+    SELECT key, value, value_encoding, versionstamp, checksum, expires_at
+    FROM kv_store
+    WHERE key >= $1 AND ($2::bytea IS NULL OR key < $2)
+      AND (expires_at IS NULL OR expires_at > $4)
+    ORDER BY key ASC
+    LIMIT $3
+"#;
+
+/// [`READ_RANGE`] from the end of the range.
+pub const READ_RANGE_REVERSE: &str = r#"
+    SELECT key, value, value_encoding, versionstamp, checksum
+    FROM kv_store
+    WHERE key >= $1 AND ($2::bytea IS NULL OR key < $2)
+      AND (expires_at IS NULL OR expires_at > $4)
+    ORDER BY key DESC
+    LIMIT $3
+"#;
+
+/// Reads several ranges in one round trip: [`READ_RANGE`] or
+/// [`READ_RANGE_REVERSE`] for each element of the arrays `$1` (starts),
+/// `$2` (ends, NULL if open ended), `$3` (limits) and `$4` (reverse flags),
+/// as of `$5` milliseconds since the epoch. `range_index` is the 1-based
+/// position of the range a row belongs to. Rows come in no particular
+/// order; see [`split_ranges`].
+pub const READ_RANGES: &str = r#"
+    SELECT r.range_index, kv.key, kv.value, kv.value_encoding, kv.versionstamp, kv.checksum
+    FROM unnest($1::bytea[], $2::bytea[], $3::bigint[], $4::boolean[])
+        WITH ORDINALITY AS r(start_key, end_key, max_rows, reverse, range_index)
+    CROSS JOIN LATERAL (
+        (SELECT key, value, value_encoding, versionstamp, checksum
+         FROM kv_store
+         WHERE NOT r.reverse
+           AND key >= r.start_key AND (r.end_key IS NULL OR key < r.end_key)
+           AND (expires_at IS NULL OR expires_at > $5)
+         ORDER BY key ASC
+         LIMIT r.max_rows)
+        UNION ALL
+        (SELECT key, value, value_encoding, versionstamp, checksum
+         FROM kv_store
+         WHERE r.reverse
+           AND key >= r.start_key AND (r.end_key IS NULL OR key < r.end_key)
+           AND (expires_at IS NULL OR expires_at > $5)
+         ORDER BY key DESC
+         LIMIT r.max_rows)
+    ) kv
+"#;
+
+/// Lets the current transaction commit without waiting for its WAL to be
+/// flushed. See [`crate::Durability::Asynchronous`].
+pub const ASYNCHRONOUS_COMMIT: &str = "SET LOCAL synchronous_commit = off";
+
+/// Takes the next version, locking the counter until the transaction ends.
+pub const INCREMENT_VERSION: &str = "UPDATE data_version SET version = version + 1 WHERE k = 0 RETURNING version";
+
+/// The versionstamp of `$1` if it exists and has not expired at `$2`.
+pub const CHECK: &str =
+    "SELECT versionstamp FROM kv_store WHERE key = $1 AND (expires_at IS NULL OR expires_at > $2)";
+
+/// Sets `$1` to the value `$2` with encoding `$3`, versionstamp `$4`,
+/// expiry `$5` and checksum `$6`.
+pub const SET: &str = r#"
+    INSERT INTO kv_store (key, value, value_encoding, versionstamp, expires_at, checksum, updated_at)
+    VALUES ($1, $2, $3, $4, $5, $6, NOW())
+    ON CONFLICT (key) DO UPDATE SET
+        value = EXCLUDED.value,
+        value_encoding = EXCLUDED.value_encoding,
+        versionstamp = EXCLUDED.versionstamp,
+        expires_at = EXCLUDED.expires_at,
+        checksum = EXCLUDED.checksum,
+        updated_at = NOW()
+"#;
+
+/// [`SET`] for several distinct keys in one statement: sets each element
+/// of `$1` to the elements at the same position of `$2` (values), `$3`
+/// (encodings), `$5` (expiry, NULL for none) and `$6` (checksums), all with
+/// versionstamp `$4`.
+pub const SET_MANY: &str = r#"
+    INSERT INTO kv_store (key, value, value_encoding, versionstamp, expires_at, checksum, updated_at)
+    SELECT key, value, value_encoding, $4, expires_at, checksum, NOW()
+    FROM unnest($1::bytea[], $2::bytea[], $3::integer[], $5::bigint[], $6::bigint[])
+        AS t(key, value, value_encoding, expires_at, checksum)
+    ON CONFLICT (key) DO UPDATE SET
+        value = EXCLUDED.value,
+        value_encoding = EXCLUDED.value_encoding,
+        versionstamp = EXCLUDED.versionstamp,
+        expires_at = EXCLUDED.expires_at,
+        checksum = EXCLUDED.checksum,
+        updated_at = NOW()
+"#;
+
+/// [`SET`] for a key that can not exist yet, having the versionstamp of the
+/// write as its suffix.
+pub const SET_VERSIONSTAMPED_KEY: &str = r#"
+    INSERT INTO kv_store (key, value, value_encoding, versionstamp, expires_at, checksum, updated_at)
+    VALUES ($1, $2, $3, $4, $5, $6, NOW())
+"#;
+
+/// Deletes `$1`, leaving a tombstone with versionstamp `$2` if it existed.
+pub const DELETE: &str = r#"
+    WITH deleted AS (DELETE FROM kv_store WHERE key = $1 RETURNING key)
+    INSERT INTO kv_tombstones (key, versionstamp)
+    SELECT key, $2 FROM deleted
+    ON CONFLICT (key) DO UPDATE SET
+        versionstamp = EXCLUDED.versionstamp,
+        deleted_at = NOW()
+"#;
+
+/// The value of `$1` and its encoding.
+pub const GET_VALUE: &str = "SELECT value, value_encoding FROM kv_store WHERE key = $1";
+
+/// Sets `$1` to the LE64 value `$2` with versionstamp `$3` and checksum
+/// `$4`, unless it holds a value of another encoding.
+pub const SET_LE64: &str = r#"
+    INSERT INTO kv_store (key, value, value_encoding, versionstamp, checksum, updated_at)
+    VALUES ($1, $2, 2, $3, $4, NOW())
+    ON CONFLICT (key) DO UPDATE SET
+        value = $2,
+        versionstamp = EXCLUDED.versionstamp,
+        checksum = EXCLUDED.checksum,
+        updated_at = NOW()
+    WHERE kv_store.value_encoding = 2
+"#;
+
+/// Enqueues the payload `$1`, due at `$2`, with the queue metadata `$3`
+/// in encoding `$4`, namespace `$5` and priority `$6`.
+pub const ENQUEUE: &str = r#"
+    INSERT INTO queue_messages (payload, deadline, metadata, metadata_encoding, namespace, priority)
+    VALUES ($1, $2, $3, $4, $5, $6)
+"#;
+
+/// Locks the id and payload of the next message of namespace `$2` due at
+/// `$1` that is not running yet: the one with the highest priority, and of
+/// those the one due first.
+pub const DEQUEUE: &str = r#"
+    SELECT id, payload
+    FROM queue_messages
+    WHERE deadline <= $1
+    AND id NOT IN (SELECT message_id FROM queue_running)
+    AND namespace IS NOT DISTINCT FROM $2
+    ORDER BY priority DESC, deadline ASC
+    LIMIT 1
+    FOR UPDATE SKIP LOCKED
+"#;
+
+/// The earliest deadline of the messages of namespace `$1` that are not
+/// running, or NULL if there are none.
+pub const NEXT_DEADLINE: &str = r#"
+    SELECT min(deadline) FROM queue_messages
+    WHERE id NOT IN (SELECT message_id FROM queue_running)
+    AND namespace IS NOT DISTINCT FROM $1
+"#;
+
+/// Counts the messages of every namespace due at `$1` and not running yet,
+/// due later, running, and dead-lettered.
+pub const QUEUE_DEPTH: &str = r#"
+    SELECT
+        (SELECT count(*) FROM queue_messages
+         WHERE deadline <= $1 AND id NOT IN (SELECT message_id FROM queue_running)) AS ready,
+        (SELECT count(*) FROM queue_messages
+         WHERE deadline > $1 AND id NOT IN (SELECT message_id FROM queue_running)) AS scheduled,
+        (SELECT count(*) FROM queue_running) AS running,
+        (SELECT count(*) FROM queue_dead_letter) AS dead_letter
+"#;
+
+/// Marks the message `$1` as running until `$2`.
+pub const START_RUNNING: &str = r#"
+    INSERT INTO queue_running (message_id, deadline, started_at, updated_at)
+    VALUES ($1, $2, NOW(), NOW())
+"#;
+
+/// Pushes the deadline of the running message `$1` back to `$2`. Updates no
+/// row once the message finished or was released as overdue.
+pub const EXTEND_RUNNING: &str = r#"
+    UPDATE queue_running SET deadline = $2, updated_at = NOW()
+    WHERE message_id = $1
+"#;
+
+/// Marks the message `$1` as no longer running.
+pub const STOP_RUNNING: &str = "DELETE FROM queue_running WHERE message_id = $1";
+
+/// Deletes the message `$1`.
+pub const DELETE_MESSAGE: &str = "DELETE FROM queue_messages WHERE id = $1";
+
+/// What is needed to decide the fate of the failed message `$1`, whether
+/// its worker gave up on it or died.
+pub const FAILED_MESSAGE: &str = r#"
+    SELECT payload, deadline, metadata, metadata_encoding, keys_if_undelivered, backoff_schedule, retry_count
+    FROM queue_messages WHERE id = $1
+"#;
+
+/// Requeues the message `$5` at `$1` with the queue metadata `$2` in
+/// encoding `$3`, which replaces the array columns of older messages, and
+/// retry count `$4`.
+pub const RETRY_MESSAGE: &str = r#"
+    UPDATE queue_messages
+    SET deadline = $1, metadata = $2, metadata_encoding = $3, keys_if_undelivered = NULL, backoff_schedule = NULL,
+        retry_count = $4
+    WHERE id = $5
+"#;
+
+/// Sets the `keys_if_undelivered` key `$1` to the V8-encoded payload `$2`
+/// of a message that permanently failed, with versionstamp `$3` and
+/// checksum `$4`. Any expiry of the key is cleared.
+pub const SET_UNDELIVERED: &str = r#"
+    INSERT INTO kv_store (key, value, value_encoding, versionstamp, expires_at, checksum, updated_at)
+    VALUES ($1, $2, 1, $3, NULL, $4, NOW())
+    ON CONFLICT (key) DO UPDATE SET
+        value = EXCLUDED.value,
+        value_encoding = EXCLUDED.value_encoding,
+        versionstamp = EXCLUDED.versionstamp,
+        expires_at = NULL,
+        checksum = EXCLUDED.checksum,
+        updated_at = NOW()
+"#;
+
+/// Moves the message `$1`, which failed its last allowed delivery attempt,
+/// to the dead letter queue.
+pub const DEAD_LETTER_MESSAGE: &str = r#"
+    WITH failed AS (DELETE FROM queue_messages WHERE id = $1 RETURNING *)
+    INSERT INTO queue_dead_letter (
+        id, payload, metadata, metadata_encoding, keys_if_undelivered, backoff_schedule, namespace, priority, attempts,
+        enqueued_at
+    )
+    SELECT id, payload, metadata, metadata_encoding, keys_if_undelivered, backoff_schedule, namespace, priority,
+        COALESCE(retry_count, 0) + 1, created_at
+    FROM failed
+"#;
+
+/// Running messages past their deadline `$1`, whose workers have died.
+pub const OVERDUE_RUNNING: &str = "SELECT message_id FROM queue_running WHERE deadline <= $1 LIMIT 100";
+
+/// Deletes up to `$2` keys expired at `$1`, those that expired first first.
+/// Rows locked by a concurrent write are left for the next batch.
+pub const COLLECT_EXPIRED: &str = r#"
+    DELETE FROM kv_store WHERE key IN (
+        SELECT key FROM kv_store
+        WHERE expires_at IS NOT NULL AND expires_at <= $1
+        ORDER BY expires_at
+        LIMIT $2
+        FOR UPDATE SKIP LOCKED
+    )
+"#;
+
+/// Sends the payload `$2` on the channel `$1` when the transaction commits.
+pub const NOTIFY: &str = "SELECT pg_notify($1, $2)";
+
+/// The array parameters of [`READ_RANGES`] for `requests`.
+pub struct RangeArrays<'a> {
+    pub starts: Vec<&'a [u8]>,
+    pub ends: Vec<Option<&'a [u8]>>,
+    pub limits: Vec<i64>,
+    pub reverse: Vec<bool>,
+}
+
+impl<'a> RangeArrays<'a> {
+    pub fn new(requests: &'a [ReadRange]) -> Self {
+        let mut arrays = Self {
+            starts: Vec::with_capacity(requests.len()),
+            ends: Vec::with_capacity(requests.len()),
+            limits: Vec::with_capacity(requests.len()),
+            reverse: Vec::with_capacity(requests.len()),
+        };
+        for request in requests {
+            let bounds = KeyBounds::from_request(request);
+            arrays.starts.push(bounds.start);
+            arrays.ends.push(bounds.end);
+            arrays.limits.push(request.limit.get() as i64);
+            arrays.reverse.push(request.reverse);
+        }
+        arrays
+    }
+
+    /// The byte sizes of the parameters, for the statement log.
+    pub fn param_sizes(&self) -> Vec<usize> {
+        vec![
+            self.starts.iter().map(|start| start.len()).sum(),
+            self.ends.iter().flatten().map(|end| end.len()).sum(),
+            8 * self.limits.len(),
+            self.reverse.len(),
+            8,
+        ]
+    }
+}
+
+/// The number of [`MutationKind::Set`] mutations `mutations` starts with,
+/// which can be applied together by [`SET_MANY`].
+pub fn set_run(mutations: &[Mutation]) -> usize {
+    mutations.iter().take_while(|m| matches!(m.kind, MutationKind::Set(_))).count()
+}
+
+/// The array parameters of [`SET_MANY`] for a run of Set mutations. A key
+/// set more than once is only set to its last value, since one statement
+/// can not upsert a row twice.
+pub struct SetArrays<'a> {
+    pub keys: Vec<&'a [u8]>,
+    pub values: Vec<Cow<'a, [u8]>>,
+    pub encodings: Vec<i32>,
+    pub expires_at: Vec<Option<i64>>,
+    pub checksums: Vec<i64>,
+}
+
+impl<'a> SetArrays<'a> {
+    pub fn new(mutations: &'a [Mutation]) -> Self {
+        let mut arrays = Self {
+            keys: Vec::with_capacity(mutations.len()),
+            values: Vec::with_capacity(mutations.len()),
+            encodings: Vec::with_capacity(mutations.len()),
+            expires_at: Vec::with_capacity(mutations.len()),
+            checksums: Vec::with_capacity(mutations.len()),
+        };
+        let mut seen = HashSet::with_capacity(mutations.len());
+        for mutation in mutations.iter().rev() {
+            let MutationKind::Set(value) = &mutation.kind else {
+                panic!("SetArrays only takes Set mutations");
+            };
+            if !seen.insert(mutation.key.as_slice()) {
+                continue;
+            }
+            let (bytes, encoding) = denokv_proto::encode_value(value);
+            let encoding = encoding as i32;
+            arrays.checksums.push(row_checksum(&mutation.key, &bytes, encoding));
+            arrays.keys.push(&mutation.key);
+            arrays.values.push(bytes);
+            arrays.encodings.push(encoding);
+            arrays.expires_at.push(mutation.expire_at.map(|dt| dt.timestamp_millis()));
+        }
+        arrays
+    }
+
+    /// The values as slices, the type the drivers bind.
+    pub fn value_slices(&self) -> Vec<&[u8]> {
+        self.values.iter().map(|value| &**value).collect()
+    }
+
+    /// The byte sizes of the parameters, for the statement log.
+    pub fn param_sizes(&self) -> Vec<usize> {
+        vec![
+            self.keys.iter().map(|key| key.len()).sum(),
+            self.values.iter().map(|value| value.len()).sum(),
+            4 * self.encodings.len(),
+            10,
+            8 * self.expires_at.len(),
+            8 * self.checksums.len(),
+        ]
+    }
+}
+
+/// Split the rows of [`READ_RANGES`], given as `(range_index, entry)`, into
+/// the entries of each of `requests`, in the order each range is read in.
+pub fn split_ranges(
+    requests: &[ReadRange],
+    rows: impl IntoIterator<Item = (i64, KvEntry)>,
+) -> PostgresResult<Vec<Vec<KvEntry>>> {
+    let mut outputs: Vec<Vec<KvEntry>> = requests.iter().map(|_| Vec::new()).collect();
+    for (range_index, entry) in rows {
+        let output = usize::try_from(range_index - 1)
+            .ok()
+            .and_then(|index| outputs.get_mut(index))
+            .ok_or_else(|| PostgresError::InvalidData(format!("Row for unknown range {range_index}")))?;
+        output.push(entry);
+    }
+    for (request, entries) in requests.iter().zip(&mut outputs) {
+        if request.reverse {
+            entries.sort_by(|a, b| b.key.cmp(&a.key));
+        } else {
+            entries.sort_by(|a, b| a.key.cmp(&b.key));
+        }
+    }
+    Ok(outputs)
+}
+
+/// Whether a check expecting `expected` passes for a key currently at
+/// `current`, or missing if `None`.
+pub fn check_passes(expected: Option<&Versionstamp>, current: Option<&[u8]>) -> bool {
+    current == expected.map(|expected| expected.as_slice())
+}
+
+/// The keys mutated by `mutations`, each once, flagged if the versionstamp
+/// of the write is appended to them.
+pub fn mutated_keys(mutations: &[Mutation]) -> Vec<(Vec<u8>, bool)> {
+    let mut keys: Vec<(Vec<u8>, bool)> = mutations.iter()
+        .map(|m| (m.key.clone(), matches!(m.kind, MutationKind::SetSuffixVersionstampedKey(_))))
+        .collect();
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+/// The key changed by a mutation of `key` committed at `versionstamp`.
+pub fn changed_key(key: &[u8], versionstamped: bool, versionstamp: &Versionstamp) -> Vec<u8> {
+    if versionstamped {
+        denokv_proto::versionstamped_key(key, versionstamp)
+    } else {
+        key.to_vec()
+    }
+}
+
+/// The mutations combining a stored LE64 value with an operand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Le64Op {
+    Sum,
+    Min,
+    Max,
+}
+
+impl Le64Op {
+    /// The operand `value` as stored, rejecting values that are not U64.
+    pub fn operand(self, value: &KvValue) -> PostgresResult<i64> {
+        match value {
+            KvValue::U64(v) => Ok(*v as i64),
+            _ => Err(PostgresError::InvalidData(format!("{self:?} operation only supports U64 values"))),
+        }
+    }
+
+    /// The bytes of the LE64 value `current` read with [`GET_VALUE`], if the
+    /// key has a value. Like SQLite, values of other encodings are not
+    /// overwritten but fail the mutation.
+    pub fn current(self, current: Option<(Vec<u8>, i32)>) -> PostgresResult<Option<Vec<u8>>> {
+        match current {
+            Some((bytes, ENCODING_LE64)) => Ok(Some(bytes)),
+            Some(_) => Err(PostgresError::TypeMismatch(format!(
+                "Failed to perform '{}' mutation on a non-U64 value in the database",
+                format!("{self:?}").to_lowercase()
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    /// The value to store given the `current` bytes of an LE64 value, if the
+    /// key holds one. Anything other than 8 bytes counts as no value. Values
+    /// are unsigned: sums wrap around at 2^64.
+    pub fn apply(self, current: Option<&[u8]>, operand: i64) -> i64 {
+        let Some(current) = current.and_then(|bytes| <[u8; 8]>::try_from(bytes).ok()) else {
+            return operand;
+        };
+        let current = u64::from_le_bytes(current);
+        let operand = operand as u64;
+        (match self {
+            Le64Op::Sum => current.wrapping_add(operand),
+            Le64Op::Min => current.min(operand),
+            Le64Op::Max => current.max(operand),
+        }) as i64
+    }
+}
+
+/// The value a sum of the V8 number or bigint `operand` leaves in a key
+/// holding `current`, as `(value, encoding)` read with [`GET_VALUE`]. The
+/// result is clamped to or must lie within `min_v8..=max_v8`, where given,
+/// as in SQLite. A key without a value takes the operand as it is.
+pub fn sum_v8(
+    current: Option<(Vec<u8>, i32)>,
+    operand: &KvValue,
+    min_v8: &[u8],
+    max_v8: &[u8],
+    clamp: bool,
+) -> PostgresResult<KvValue> {
+    let (operand, result_min, result_max) = SumOperand::parse_sum(operand, min_v8, max_v8)?;
+    let Some((bytes, encoding)) = current else {
+        return Ok(operand.encode());
+    };
+    let current = decode_value(bytes, encoding)
+        .map_err(|_| PostgresError::TypeMismatch("Invalid sum operand".into()))?;
+    let current = SumOperand::parse(&current).map_err(|e| PostgresError::TypeMismatch(e.to_string()))?;
+    Ok(current.sum(operand, result_min, result_max, clamp)?.encode())
+}
+
+/// The backoff schedule stored for `enqueue`, in milliseconds.
+pub fn backoff_schedule(enqueue: &Enqueue) -> Vec<i32> {
+    enqueue.backoff_schedule.as_deref()
+        .unwrap_or(&DEFAULT_BACKOFF_SCHEDULE)
+        .iter()
+        .map(|&ms| ms.min(i32::MAX as u32) as i32)
+        .collect()
+}
+
+/// The new deadline and remaining backoff schedule of a message whose
+/// delivery failed at `now_ms`, or `None` if its retries are exhausted.
+pub fn retry(backoff_schedule: &[i32], now_ms: i64) -> Option<(i64, &[i32])> {
+    let (delay_ms, remaining) = backoff_schedule.split_first()?;
+    Some((now_ms + *delay_ms as i64, remaining))
+}
+
+/// What becomes of a message whose delivery failed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Failure<'a> {
+    /// Deliver it again at the deadline, with the rest of its backoff
+    /// schedule.
+    Retry(i64, &'a [i32]),
+    /// Move it to the dead letter queue: it used up its delivery attempts.
+    DeadLetter,
+    /// Write its payload to its `keys_if_undelivered` and delete it: it
+    /// used up its backoff schedule.
+    Undelivered,
+}
+
+/// The fate of a message that failed at `now_ms` after `retry_count`
+/// earlier retries, with `backoff_schedule` left. Running out of delivery
+/// attempts takes precedence over running out of the schedule.
+pub fn failure(backoff_schedule: &[i32], retry_count: i32, max_delivery_attempts: Option<u32>, now_ms: i64) -> Failure<'_> {
+    if max_delivery_attempts.is_some_and(|max| i64::from(retry_count) + 1 >= i64::from(max)) {
+        return Failure::DeadLetter;
+    }
+    match retry(backoff_schedule, now_ms) {
+        Some((deadline, remaining)) => Failure::Retry(deadline, remaining),
+        None => Failure::Undelivered,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_runs() {
+        let mutation = |key: &[u8], kind: MutationKind| Mutation { key: key.to_vec(), kind, expire_at: None };
+        let set = |key: &[u8], value: u64| mutation(key, MutationKind::Set(KvValue::U64(value)));
+        let mutations = [set(b"a", 1), set(b"b", 2), set(b"a", 3), mutation(b"c", MutationKind::Delete), set(b"d", 4)];
+        assert_eq!(set_run(&mutations), 3);
+        assert_eq!(set_run(&mutations[3..]), 0);
+        assert_eq!(set_run(&mutations[4..]), 1);
+
+        // The last value of a key set twice wins.
+        let arrays = SetArrays::new(&mutations[..3]);
+        let mut sets: Vec<_> = arrays.keys.iter().zip(arrays.value_slices()).collect();
+        sets.sort();
+        assert_eq!(sets, [(&&b"a"[..], &3u64.to_le_bytes()[..]), (&&b"b"[..], &2u64.to_le_bytes()[..])]);
+        assert_eq!(arrays.encodings, [ENCODING_LE64; 2]);
+        assert_eq!(arrays.checksums.len(), 2);
+    }
+
+    #[test]
+    fn le64_ops() {
+        let stored = 7i64.to_le_bytes();
+        assert_eq!(Le64Op::Sum.apply(Some(&stored), 3), 10);
+        assert_eq!(Le64Op::Min.apply(Some(&stored), 3), 3);
+        assert_eq!(Le64Op::Max.apply(Some(&stored), 3), 7);
+        assert_eq!(Le64Op::Sum.apply(None, 3), 3);
+        assert_eq!(Le64Op::Max.apply(Some(&[1, 2]), 3), 3);
+        assert!(matches!(Le64Op::Min.operand(&KvValue::Bytes(vec![])), Err(PostgresError::InvalidData(m)) if m == "Min operation only supports U64 values"));
+
+        // Unsigned, wrapping around.
+        let max = u64::MAX.to_le_bytes();
+        assert_eq!(Le64Op::Sum.apply(Some(&max), 2) as u64, 1);
+        assert_eq!(Le64Op::Max.apply(Some(&max), 3) as u64, u64::MAX);
+        assert_eq!(Le64Op::Min.apply(Some(&max), 3), 3);
+
+        assert_eq!(Le64Op::Sum.current(Some((stored.to_vec(), ENCODING_LE64))).unwrap(), Some(stored.to_vec()));
+        assert_eq!(Le64Op::Sum.current(None).unwrap(), None);
+        assert!(matches!(
+            Le64Op::Max.current(Some((vec![], 1))),
+            Err(PostgresError::TypeMismatch(m)) if m == "Failed to perform 'max' mutation on a non-U64 value in the database"
+        ));
+    }
+
+    #[test]
+    fn v8_sums() {
+        use denokv_proto::v8;
+        let number = |n: f64| v8::to_vec(&n).unwrap();
+        let bigint = |n: i128| v8::to_vec(&n).unwrap();
+        let sum = |current: Option<Vec<u8>>, operand: Vec<u8>, min: Vec<u8>, max: Vec<u8>, clamp| {
+            sum_v8(current.map(|v| (v, 1)), &KvValue::V8(operand), &min, &max, clamp)
+        };
+        let number_of = |value: PostgresResult<KvValue>| match value.unwrap() {
+            KvValue::V8(bytes) => v8::from_slice::<f64>(&bytes).unwrap(),
+            value => panic!("expected a V8 value, got {value:?}"),
+        };
+        let bigint_of = |value: PostgresResult<KvValue>| match value.unwrap() {
+            KvValue::V8(bytes) => v8::from_slice::<i128>(&bytes).unwrap(),
+            value => panic!("expected a V8 value, got {value:?}"),
+        };
+
+        // The bounds only apply to sums.
+        assert_eq!(number_of(sum(None, number(5.0), number(0.0), number(1.0), false)), 5.0);
+        assert_eq!(number_of(sum(Some(number(1.5)), number(2.0), vec![], vec![], false)), 3.5);
+        assert_eq!(number_of(sum(Some(number(1.5)), number(2.0), vec![], number(3.0), true)), 3.0);
+        assert_eq!(bigint_of(sum(Some(bigint(8)), bigint(5), vec![], bigint(10), true)), 10);
+        assert_eq!(bigint_of(sum(Some(bigint(2)), bigint(-5), bigint(0), vec![], true)), 0);
+        assert!(matches!(sum(Some(bigint(8)), bigint(5), vec![], bigint(10), false), Err(PostgresError::SumOutOfRange)));
+        assert!(matches!(sum(Some(number(1.0)), bigint(1), vec![], vec![], false), Err(PostgresError::TypeMismatch(_))));
+        assert!(matches!(sum(Some(number(1.0)), number(1.0), bigint(0), vec![], false), Err(PostgresError::TypeMismatch(_))));
+
+        // A bigint added to a U64 value keeps it a U64 value.
+        let current = Some((7u64.to_le_bytes().to_vec(), ENCODING_LE64));
+        assert!(matches!(sum_v8(current, &KvValue::V8(bigint(3)), &[], &[], false), Ok(KvValue::U64(10))));
+    }
+
+    #[test]
+    fn checks_and_retries() {
+        let versionstamp = [1; 10];
+        assert!(check_passes(None, None));
+        assert!(!check_passes(None, Some(&versionstamp)));
+        assert!(check_passes(Some(&versionstamp), Some(&versionstamp)));
+        assert!(!check_passes(Some(&versionstamp), Some(&[2; 10])));
+        assert!(!check_passes(Some(&versionstamp), None));
+
+        assert_eq!(retry(&[100, 1000], 5), Some((105, &[1000][..])));
+        assert_eq!(retry(&[], 5), None);
+
+        assert_eq!(failure(&[100, 1000], 0, None, 5), Failure::Retry(105, &[1000][..]));
+        assert_eq!(failure(&[1000], 1, Some(3), 5), Failure::Retry(1005, &[][..]));
+        assert_eq!(failure(&[100, 1000], 2, Some(3), 5), Failure::DeadLetter);
+        assert_eq!(failure(&[], 2, Some(3), 5), Failure::DeadLetter);
+        assert_eq!(failure(&[], 2, Some(4), 5), Failure::Undelivered);
+    }
+
+    #[test]
+    fn splits_ranges() {
+        let range = |start: &[u8], reverse| ReadRange {
+            start: start.to_vec(),
+            end: vec![0xff],
+            limit: std::num::NonZeroU32::new(10).unwrap(),
+            reverse,
+        };
+        let entry = |key: &[u8]| KvEntry { key: key.to_vec(), value: KvValue::U64(0), versionstamp: [0; 10] };
+        let requests = [range(b"a", false), range(b"b", true), range(b"c", false)];
+        let arrays = RangeArrays::new(&requests);
+        assert_eq!(arrays.ends, [None, None, None]);
+        assert_eq!(arrays.reverse, [false, true, false]);
+
+        let rows = [(2, entry(b"b")), (1, entry(b"b")), (2, entry(b"c")), (1, entry(b"a"))];
+        let outputs = split_ranges(&requests, rows).unwrap();
+        let keys: Vec<Vec<&[u8]>> = outputs
+            .iter()
+            .map(|entries| entries.iter().map(|entry| &entry.key[..]).collect())
+            .collect();
+        assert_eq!(keys, [vec![&b"a"[..], b"b"], vec![b"c", b"b"], vec![]]);
+
+        assert!(split_ranges(&requests, [(4, entry(b"a"))]).is_err());
+    }
+}
@@ -1,8 +1,14 @@
 // Copyright 2023 rawkakani. All rights reserved. MIT license.
 
+use std::time::Duration;
+
 use deno_error::{JsErrorBox, JsErrorClass};
 use thiserror::Error;
 
+use crate::circuit_breaker::OperationClass;
+use crate::limits::WriteLimit;
+use crate::pressure::Pressure;
+
 /// PostgreSQL-specific errors
 #[derive(Error, Debug)]
 pub enum PostgresError {
@@ -35,20 +41,150 @@ pub enum PostgresError {
 
     #[error("Pool error: {0}")]
     PoolError(String),
+
+    #[error("Too many writes are waiting, try again later")]
+    Backpressure,
+
+    #[error("Too many recent failures of {0} operations, failing fast")]
+    CircuitOpen(OperationClass),
+
+    /// The operation was shed rather than left waiting; retrying after
+    /// `retry_after` is likely to succeed.
+    #[error("The database is overloaded ({pressure}), try again in {}s", retry_after.as_secs_f64())]
+    Overloaded { pressure: Pressure, retry_after: Duration },
+
+    #[error("The change feed no longer has the deletions after this position; start over from an empty copy")]
+    ChangefeedTruncated,
+
+    /// The write was rejected before touching the database because it
+    /// exceeds one of the configured [`crate::WriteLimits`].
+    #[error("Atomic write exceeds the {limit} limit: {actual} > {max}")]
+    WriteLimitExceeded { limit: WriteLimit, actual: usize, max: usize },
+
+    #[error("Corrupt row for key {}: {kind}", denokv_proto::format_key(key))]
+    CorruptRow { key: Vec<u8>, kind: CorruptionKind },
+
+    /// A mutation does not fit the type of its operands or of the value it
+    /// changes, as the SQLite backend reports it.
+    #[error("{0}")]
+    TypeMismatch(String),
+
+    #[error("The result of a Sum operation would exceed its range limit")]
+    SumOutOfRange,
+
+    /// A watch was not polled for longer than
+    /// [`crate::PostgresConfig::watch_lease`], and its subscriptions were
+    /// released. Watching again resumes it.
+    #[error("The watch was not polled for too long and was closed")]
+    WatchLeaseExpired,
+
+    /// A [`crate::FencedWriter`] was asked to write while this instance
+    /// does not hold the lease of its role, or after a later leader set the
+    /// fence.
+    #[error("This instance is not the leader for {0}")]
+    NotLeader(String),
+
+    /// A bulk import was resumed with other mutations than it was started
+    /// with, so the journaled progress does not apply to them.
+    #[error("Bulk import {0} was started with other mutations")]
+    BulkImportMismatch(String),
+}
+
+/// The reason a stored row failed to decode.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum CorruptionKind {
+    #[error("versionstamp is {0} bytes, expected 10")]
+    VersionstampLength(usize),
+
+    #[error("LE64 value is {0} bytes, expected 8")]
+    U64Length(usize),
+
+    #[error("unknown value encoding {0}")]
+    UnknownEncoding(i32),
+
+    #[error("checksum mismatch")]
+    ChecksumMismatch,
+}
+
+impl PostgresError {
+    /// Whether the error is likely caused by the server or network being
+    /// unavailable rather than by the request, so that retrying may help.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            PostgresError::ConnectionFailed(_) | PostgresError::PoolError(_) | PostgresError::Timeout(_)
+        )
+    }
 }
 
 impl From<tokio_postgres::Error> for PostgresError {
     fn from(err: tokio_postgres::Error) -> Self {
-        PostgresError::DatabaseError(err.to_string())
+        // Errors without a SQLSTATE come from the connection itself. Class
+        // 08 is a connection exception and 57P0x a server shutdown.
+        let connection_lost = match err.code() {
+            None => true,
+            Some(code) => code.code().starts_with("08") || code.code().starts_with("57P0"),
+        };
+        if connection_lost {
+            PostgresError::ConnectionFailed(err.to_string())
+        } else {
+            PostgresError::DatabaseError(err.to_string())
+        }
     }
 }
 
+#[cfg(feature = "pool-deadpool")]
 impl From<deadpool_postgres::PoolError> for PostgresError {
     fn from(err: deadpool_postgres::PoolError) -> Self {
         PostgresError::PoolError(err.to_string())
     }
 }
 
+#[cfg(feature = "pool-bb8")]
+impl From<bb8::RunError<tokio_postgres::Error>> for PostgresError {
+    fn from(err: bb8::RunError<tokio_postgres::Error>) -> Self {
+        match err {
+            bb8::RunError::User(err) => err.into(),
+            bb8::RunError::TimedOut => PostgresError::PoolError(err.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "driver-sqlx")]
+impl From<sqlx::Error> for PostgresError {
+    fn from(err: sqlx::Error) -> Self {
+        match &err {
+            sqlx::Error::Database(db) => {
+                // As for tokio-postgres: class 08 is a connection exception
+                // and 57P0x a server shutdown.
+                let code = db.code().unwrap_or_default();
+                if code.starts_with("08") || code.starts_with("57P0") {
+                    PostgresError::ConnectionFailed(err.to_string())
+                } else {
+                    PostgresError::DatabaseError(err.to_string())
+                }
+            }
+            sqlx::Error::Io(_) | sqlx::Error::Tls(_) | sqlx::Error::Protocol(_) | sqlx::Error::WorkerCrashed => {
+                PostgresError::ConnectionFailed(err.to_string())
+            }
+            sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed => PostgresError::PoolError(err.to_string()),
+            sqlx::Error::ColumnDecode { .. } | sqlx::Error::Decode(_) | sqlx::Error::ColumnNotFound(_) => {
+                PostgresError::DeserializationError(err.to_string())
+            }
+            _ => PostgresError::DatabaseError(err.to_string()),
+        }
+    }
+}
+
+impl From<denokv_sqlite::SumError> for PostgresError {
+    fn from(err: denokv_sqlite::SumError) -> Self {
+        match err {
+            denokv_sqlite::SumError::TypeMismatch(msg) => PostgresError::TypeMismatch(msg),
+            denokv_sqlite::SumError::OutOfRange => PostgresError::SumOutOfRange,
+        }
+    }
+}
+
 impl From<serde_json::Error> for PostgresError {
     fn from(err: serde_json::Error) -> Self {
         PostgresError::SerializationError(err.to_string())
@@ -0,0 +1,302 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+//! Writes that only the leader for a role makes, even while a deposed
+//! leader still believes it leads.
+//!
+//! The instances that want to lead a role race for its lease key,
+//! `["__fence_lease", <role>]`, with checked writes. The holder renews the
+//! lease every third of [`LEASE_TIME`]; another instance takes it over once
+//! it saw the lease go unrenewed for a whole [`LEASE_TIME`], timed on its
+//! own clock so that clock skew between instances does not matter. Taking
+//! the lease also sets the fence key of the role, `["__fence", <role>]`, and
+//! the versionstamp of that write is the new leader's token. Every write a
+//! [`FencedWriter`] makes checks that the fence key still has its token, so
+//! a leader that was paused or cut off, and has yet to notice it lost the
+//! lease, fails to write as soon as a later leader has set the fence,
+//! whatever it believes.
+
+use std::num::NonZeroU32;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use async_trait::async_trait;
+use deno_error::JsErrorBox;
+use denokv_proto::{
+    encode_key, AtomicWrite, Check, CommitResult, Consistency, Database, Key, KeyPart, KvValue, Mutation,
+    MutationKind, ReadRange, ReadRangeOutput, SnapshotReadOptions, Versionstamp, WatchKeyOutput,
+};
+use futures::Stream;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::error::PostgresError;
+use crate::message_handle::PostgresMessageHandle;
+use crate::Postgres;
+
+/// The first part of the fence keys.
+const FENCE_KEY: &str = "__fence";
+
+/// The first part of the lease keys.
+const LEASE_KEY: &str = "__fence_lease";
+
+/// How long a lease may go unrenewed before another instance takes it over.
+const LEASE_TIME: Duration = Duration::from_secs(15);
+
+/// A [`Postgres`] database that only takes writes while this instance
+/// holds the lease of a role, created by [`Postgres::fenced_writer`]. See
+/// the [module documentation](self).
+///
+/// Writes fail with [`PostgresError::NotLeader`] while the instance does
+/// not lead, including from when a later leader set the fence until the
+/// instance notices it lost the lease. Reads, watches and the queue are
+/// passed through unfenced. Dropping the last clone resigns.
+#[derive(Clone)]
+pub struct FencedWriter {
+    inner: Arc<FenceInner>,
+}
+
+struct FenceInner {
+    postgres: Postgres,
+    role: String,
+    key: Vec<u8>,
+    lease_key: Vec<u8>,
+    /// The versionstamp of the lease this instance last wrote, while it
+    /// leads.
+    lease: Arc<Mutex<Option<Versionstamp>>>,
+    /// The versionstamp of the fence this instance set, while it leads.
+    token: watch::Receiver<Option<Versionstamp>>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for FenceInner {
+    fn drop(&mut self) {
+        self.task.abort();
+        // Resign, so that the next leader does not wait out the lease.
+        let Some(lease) = self.lease.lock().unwrap().take() else {
+            return;
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let postgres = self.postgres.clone();
+        let write = AtomicWrite {
+            checks: vec![Check { key: self.lease_key.clone(), versionstamp: Some(lease) }],
+            mutations: vec![Mutation { key: self.lease_key.clone(), kind: MutationKind::Delete, expire_at: None }],
+            enqueues: vec![],
+        };
+        runtime.spawn(async move {
+            if let Err(e) = postgres.atomic_write(write).await {
+                log::warn!("Failed to resign a fenced lease: {e}");
+            }
+        });
+    }
+}
+
+impl FencedWriter {
+    pub(crate) fn new(postgres: Postgres, role: &str) -> Self {
+        let key = role_key(FENCE_KEY, role);
+        let lease_key = role_key(LEASE_KEY, role);
+        let lease = Arc::new(Mutex::new(None));
+        let (sender, token) = watch::channel(None);
+        let campaign = Campaign {
+            postgres: postgres.clone(),
+            key: key.clone(),
+            lease_key: lease_key.clone(),
+            lease: lease.clone(),
+            token: sender,
+        };
+        let task = tokio::spawn(campaign.run());
+        let inner = FenceInner { postgres, role: role.to_string(), key, lease_key, lease, token, task };
+        Self { inner: Arc::new(inner) }
+    }
+
+    /// The fence key of the role.
+    pub fn fence_key(&self) -> &[u8] {
+        &self.inner.key
+    }
+
+    /// The fencing token that writes are checked against, or `None` while
+    /// this instance does not lead.
+    pub fn token(&self) -> Option<Versionstamp> {
+        *self.inner.token.borrow()
+    }
+
+    /// Whether this instance leads, as of its last renewal of the lease.
+    pub fn is_leader(&self) -> bool {
+        self.token().is_some()
+    }
+
+    /// Wait until this instance leads and has set the fence.
+    pub async fn acquired(&self) {
+        let mut token = self.inner.token.clone();
+        // The sender lives as long as the task, which lives as long as `self`.
+        let _ = token.wait_for(Option::is_some).await;
+    }
+
+    fn not_leader(&self) -> JsErrorBox {
+        JsErrorBox::from_err(PostgresError::NotLeader(self.inner.role.clone()))
+    }
+}
+
+fn role_key(kind: &str, role: &str) -> Vec<u8> {
+    encode_key(&Key(vec![KeyPart::String(kind.to_string()), KeyPart::String(role.to_string())]))
+        .expect("string key parts always encode")
+}
+
+/// The versionstamp `key` is stored with, or `None` if it is not set.
+async fn stored_versionstamp(postgres: &Postgres, key: &[u8]) -> Result<Option<Versionstamp>, JsErrorBox> {
+    let range = ReadRange {
+        start: key.to_vec(),
+        end: key.iter().copied().chain(Some(0)).collect(),
+        limit: NonZeroU32::new(1).unwrap(),
+        reverse: false,
+    };
+    let outputs = postgres.snapshot_read(vec![range], SnapshotReadOptions { consistency: Consistency::Strong }).await?;
+    Ok(outputs.into_iter().next().and_then(|output| output.entries.into_iter().next()).map(|entry| entry.versionstamp))
+}
+
+fn set(key: &[u8]) -> Mutation {
+    Mutation { key: key.to_vec(), kind: MutationKind::Set(KvValue::Bytes(Vec::new())), expire_at: None }
+}
+
+/// The background task of a [`FencedWriter`], which takes the lease when it
+/// is free and renews it while it holds it.
+struct Campaign {
+    postgres: Postgres,
+    key: Vec<u8>,
+    lease_key: Vec<u8>,
+    lease: Arc<Mutex<Option<Versionstamp>>>,
+    token: watch::Sender<Option<Versionstamp>>,
+}
+
+impl Campaign {
+    async fn run(self) {
+        // The lease as another instance last wrote it, and when this
+        // instance first saw it so.
+        let mut observed: Option<(Option<Versionstamp>, Instant)> = None;
+        let mut renewed_at = Instant::now();
+        loop {
+            let held = *self.lease.lock().unwrap();
+            let token = *self.token.borrow();
+            let result = match (held, token) {
+                (Some(lease), Some(token)) => self.renew(lease, token).await.map(|renewed| {
+                    if renewed {
+                        renewed_at = Instant::now();
+                    } else {
+                        log::info!("Lost the lease {}", denokv_proto::format_key(&self.lease_key));
+                        self.resign();
+                    }
+                }),
+                _ => self.acquire(&mut observed).await.map(|acquired| {
+                    if acquired {
+                        renewed_at = Instant::now();
+                    }
+                }),
+            };
+            if let Err(e) = result {
+                log::warn!("Failed to update the lease {}: {e}", denokv_proto::format_key(&self.lease_key));
+                // Another instance may take over once the lease is due.
+                if renewed_at.elapsed() >= LEASE_TIME {
+                    self.resign();
+                }
+            }
+            tokio::time::sleep(LEASE_TIME / 3).await;
+        }
+    }
+
+    fn resign(&self) {
+        *self.lease.lock().unwrap() = None;
+        self.token.send_replace(None);
+    }
+
+    /// Take the lease if it is free or went unrenewed for [`LEASE_TIME`],
+    /// setting the fence with it. Returns whether it was taken.
+    async fn acquire(&self, observed: &mut Option<(Option<Versionstamp>, Instant)>) -> Result<bool, JsErrorBox> {
+        let current = stored_versionstamp(&self.postgres, &self.lease_key).await?;
+        let due = current.is_none()
+            || match *observed {
+                Some((seen, since)) if seen == current => since.elapsed() >= LEASE_TIME,
+                _ => {
+                    *observed = Some((current, Instant::now()));
+                    false
+                }
+            };
+        if !due {
+            return Ok(false);
+        }
+        let write = AtomicWrite {
+            checks: vec![Check { key: self.lease_key.clone(), versionstamp: current }],
+            mutations: vec![set(&self.lease_key), set(&self.key)],
+            enqueues: vec![],
+        };
+        let Some(commit) = self.postgres.atomic_write(write).await? else {
+            // Another instance was quicker.
+            *observed = None;
+            return Ok(false);
+        };
+        *observed = None;
+        *self.lease.lock().unwrap() = Some(commit.versionstamp);
+        self.token.send_replace(Some(commit.versionstamp));
+        Ok(true)
+    }
+
+    /// Renew the lease, as long as the fence is still this instance's.
+    /// Returns whether it was renewed.
+    async fn renew(&self, lease: Versionstamp, token: Versionstamp) -> Result<bool, JsErrorBox> {
+        let write = AtomicWrite {
+            checks: vec![
+                Check { key: self.lease_key.clone(), versionstamp: Some(lease) },
+                Check { key: self.key.clone(), versionstamp: Some(token) },
+            ],
+            mutations: vec![set(&self.lease_key)],
+            enqueues: vec![],
+        };
+        let Some(commit) = self.postgres.atomic_write(write).await? else {
+            return Ok(false);
+        };
+        *self.lease.lock().unwrap() = Some(commit.versionstamp);
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl Database for FencedWriter {
+    type QMH = PostgresMessageHandle;
+
+    async fn snapshot_read(
+        &self,
+        requests: Vec<ReadRange>,
+        options: SnapshotReadOptions,
+    ) -> Result<Vec<ReadRangeOutput>, JsErrorBox> {
+        self.inner.postgres.snapshot_read(requests, options).await
+    }
+
+    async fn atomic_write(
+        &self,
+        mut write: AtomicWrite,
+    ) -> Result<Option<CommitResult>, JsErrorBox> {
+        let Some(token) = self.token() else {
+            return Err(self.not_leader());
+        };
+        write.checks.push(Check { key: self.inner.key.clone(), versionstamp: Some(token) });
+        let commit = self.inner.postgres.atomic_write(write).await?;
+        // A failed check may be the fence; tell it apart from the caller's.
+        if commit.is_none() && stored_versionstamp(&self.inner.postgres, &self.inner.key).await? != Some(token) {
+            return Err(self.not_leader());
+        }
+        Ok(commit)
+    }
+
+    async fn dequeue_next_message(&self) -> Result<Option<Self::QMH>, JsErrorBox> {
+        self.inner.postgres.dequeue_next_message().await
+    }
+
+    fn watch(&self, keys: Vec<Vec<u8>>) -> Pin<Box<dyn Stream<Item = Result<Vec<WatchKeyOutput>, JsErrorBox>> + Send>> {
+        self.inner.postgres.watch(keys)
+    }
+
+    fn close(&self) {}
+}
@@ -0,0 +1,28 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+//! Fuzz the `kv_store` row decoding path.
+//!
+//! Input layout: `[encoding, versionstamp_len, key_len, ...bytes]`. The
+//! remaining bytes are split into versionstamp, key and value so that every
+//! column can take arbitrary lengths. Decoding must never panic.
+//!
+//! Run with `cargo +nightly fuzz run decode_entry` from `postgres/`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let [encoding, vs_len, key_len, rest @ ..] = data else {
+        return;
+    };
+    let (versionstamp, rest) = rest.split_at((*vs_len as usize).min(rest.len()));
+    let (key, value) = rest.split_at((*key_len as usize).min(rest.len()));
+
+    let _ = denokv_postgres::decode_entry(
+        key.to_vec(),
+        value.to_vec(),
+        *encoding as i8 as i32,
+        versionstamp,
+    );
+});
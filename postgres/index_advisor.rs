@@ -0,0 +1,249 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use denokv_proto::ReadRange;
+use serde::Serialize;
+
+use crate::range::is_single_key;
+
+/// How many distinct shapes are recorded. Scans of new shapes beyond this
+/// are not recorded, so that recording can not grow without bound.
+const MAX_SHAPES: usize = 10_000;
+
+/// Range scans observed under one key prefix in one direction.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RangeScanShape {
+    /// The bytes the start and end of the scanned ranges have in common.
+    pub prefix: Vec<u8>,
+    pub reverse: bool,
+    /// How many scans had this shape.
+    pub scans: u64,
+    /// The smallest and largest limit of those scans.
+    pub min_limit: u32,
+    pub max_limit: u32,
+    /// Entries returned by those scans in total.
+    pub rows_returned: u64,
+}
+
+/// An index that would speed up frequent range scans under a prefix, as
+/// suggested by [`crate::Postgres::index_advice`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct IndexSuggestion {
+    pub prefix: Vec<u8>,
+    /// Scans under the prefix, in either direction and including those of
+    /// longer prefixes the index also covers.
+    pub scans: u64,
+    /// Why the index is suggested.
+    pub reason: String,
+    /// The statement creating the index.
+    pub ddl: String,
+}
+
+/// Records the shapes of range scans while diagnostics are enabled with
+/// [`crate::PostgresConfig::record_range_scans`].
+#[derive(Default)]
+pub struct RangeScanRecorder {
+    shapes: Mutex<HashMap<(Vec<u8>, bool), RangeScanShape>>,
+}
+
+impl RangeScanRecorder {
+    /// Record that `request` returned `returned` entries. Reads of a single
+    /// key are not range scans and are ignored.
+    pub fn record(&self, request: &ReadRange, returned: usize) {
+        if is_single_key(request) {
+            return;
+        }
+        let prefix = common_prefix(&request.start, &request.end);
+        let limit = request.limit.get();
+        let mut shapes = self.shapes.lock().unwrap();
+        let len = shapes.len();
+        let shape = match shapes.get_mut(&(prefix.to_vec(), request.reverse)) {
+            Some(shape) => shape,
+            None if len >= MAX_SHAPES => return,
+            None => shapes.entry((prefix.to_vec(), request.reverse)).or_insert(RangeScanShape {
+                prefix: prefix.to_vec(),
+                reverse: request.reverse,
+                scans: 0,
+                min_limit: limit,
+                max_limit: limit,
+                rows_returned: 0,
+            }),
+        };
+        shape.scans += 1;
+        shape.min_limit = shape.min_limit.min(limit);
+        shape.max_limit = shape.max_limit.max(limit);
+        shape.rows_returned += returned as u64;
+    }
+
+    /// The recorded shapes, most frequent first.
+    pub fn shapes(&self) -> Vec<RangeScanShape> {
+        let mut shapes: Vec<_> = self.shapes.lock().unwrap().values().cloned().collect();
+        shapes.sort_by(|a, b| b.scans.cmp(&a.scans).then_with(|| a.prefix.cmp(&b.prefix)));
+        shapes
+    }
+}
+
+/// Suggest a partial index for every prefix scanned at least `min_scans`
+/// times, most scanned first.
+///
+/// Every range scan can use the primary key, but under a hot prefix a
+/// partial index on just that prefix is much smaller and more likely to
+/// stay cached, and including `expires_at` lets the expiry filter run
+/// without visiting the table for rows it rejects. The index is descending
+/// if the prefix is only scanned in reverse. Prefixes under another
+/// suggested prefix are left out, since its index covers them too.
+pub fn advise(shapes: &[RangeScanShape], min_scans: u64) -> Vec<IndexSuggestion> {
+    let mut by_prefix: HashMap<&[u8], Vec<&RangeScanShape>> = HashMap::new();
+    for shape in shapes {
+        if !shape.prefix.is_empty() {
+            by_prefix.entry(&shape.prefix).or_default().push(shape);
+        }
+    }
+
+    let mut prefixes: Vec<&[u8]> = by_prefix.keys().copied().collect();
+    prefixes.sort();
+    let mut suggestions: Vec<IndexSuggestion> = Vec::new();
+    for prefix in prefixes {
+        let covered: Vec<&RangeScanShape> = shapes
+            .iter()
+            .filter(|shape| !shape.prefix.is_empty() && shape.prefix.starts_with(prefix))
+            .collect();
+        let scans: u64 = covered.iter().map(|shape| shape.scans).sum();
+        if scans < min_scans {
+            continue;
+        }
+        // Sorted, so an enclosing suggestion comes first.
+        if suggestions.iter().any(|s| prefix.starts_with(&s.prefix)) {
+            continue;
+        }
+        let reverse_only = covered.iter().all(|shape| shape.reverse);
+        let rows: u64 = covered.iter().map(|shape| shape.rows_returned).sum();
+        let max_limit = covered.iter().map(|shape| shape.max_limit).max().unwrap_or(0);
+        suggestions.push(IndexSuggestion {
+            prefix: prefix.to_vec(),
+            scans,
+            reason: format!(
+                "{scans} scan(s) of a {}-byte prefix{}, returning {:.1} row(s) on average with limits up to {max_limit}",
+                prefix.len(),
+                if reverse_only { " in reverse" } else { "" },
+                rows as f64 / scans as f64,
+            ),
+            ddl: index_ddl(prefix, reverse_only),
+        });
+    }
+    suggestions.sort_by(|a, b| b.scans.cmp(&a.scans).then_with(|| a.prefix.cmp(&b.prefix)));
+    suggestions
+}
+
+fn common_prefix<'a>(a: &'a [u8], b: &[u8]) -> &'a [u8] {
+    let len = a.iter().zip(b).take_while(|(a, b)| a == b).count();
+    &a[..len]
+}
+
+/// The smallest key greater than every key starting with `prefix`, or
+/// `None` if there is none.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let last = prefix.iter().rposition(|&byte| byte != 0xff)?;
+    let mut end = prefix[..=last].to_vec();
+    end[last] += 1;
+    Some(end)
+}
+
+fn index_ddl(prefix: &[u8], descending: bool) -> String {
+    let name = format!("idx_kv_prefix_{:016x}", xxhash_rust::xxh3::xxh3_64(prefix));
+    let mut condition = format!("key >= '\\x{}'::bytea", hex::encode(prefix));
+    if let Some(end) = prefix_end(prefix) {
+        condition.push_str(&format!(" AND key < '\\x{}'::bytea", hex::encode(end)));
+    }
+    format!(
+        "CREATE INDEX CONCURRENTLY IF NOT EXISTS {name} ON kv_store (key{}) INCLUDE (expires_at) WHERE {condition};",
+        if descending { " DESC" } else { "" },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use super::*;
+
+    fn scan(start: &[u8], end: &[u8], limit: u32, reverse: bool) -> ReadRange {
+        ReadRange {
+            start: start.to_vec(),
+            end: end.to_vec(),
+            limit: NonZeroU32::new(limit).unwrap(),
+            reverse,
+        }
+    }
+
+    #[test]
+    fn records_shapes_by_prefix() {
+        let recorder = RangeScanRecorder::default();
+        recorder.record(&scan(b"\x02users\x00\x00", b"\x02users\x00\xff", 10, false), 10);
+        recorder.record(&scan(b"\x02users\x00\x02a\x00", b"\x02users\x00\xff", 100, false), 3);
+        recorder.record(&scan(b"\x02users\x00\x00", b"\x02users\x00\xff", 1, true), 1);
+        // A point read.
+        recorder.record(&scan(b"\x02users\x00", b"\x02users\x00\x00", 1, false), 1);
+
+        assert_eq!(recorder.shapes(), vec![
+            RangeScanShape {
+                prefix: b"\x02users\x00".to_vec(),
+                reverse: false,
+                scans: 2,
+                min_limit: 10,
+                max_limit: 100,
+                rows_returned: 13,
+            },
+            RangeScanShape {
+                prefix: b"\x02users\x00".to_vec(),
+                reverse: true,
+                scans: 1,
+                min_limit: 1,
+                max_limit: 1,
+                rows_returned: 1,
+            },
+        ]);
+    }
+
+    #[test]
+    fn advises_enclosing_prefixes() {
+        let shape = |prefix: &[u8], reverse, scans| RangeScanShape {
+            prefix: prefix.to_vec(),
+            reverse,
+            scans,
+            min_limit: 10,
+            max_limit: 10,
+            rows_returned: scans * 5,
+        };
+        let shapes = [
+            shape(b"\x02users\x00", false, 60),
+            shape(b"\x02users\x00\x02admins\x00", true, 60),
+            shape(b"\x02logs\x00", true, 200),
+            shape(b"\x02rare\x00", false, 5),
+            shape(b"", false, 1000),
+        ];
+        let advice = advise(&shapes, 100);
+        assert_eq!(advice.len(), 2);
+        assert_eq!(advice[0].prefix, b"\x02logs\x00");
+        assert_eq!(
+            advice[0].ddl,
+            format!(
+                "CREATE INDEX CONCURRENTLY IF NOT EXISTS idx_kv_prefix_{:016x} ON kv_store (key DESC) INCLUDE (expires_at) WHERE key >= '\\x026c6f677300'::bytea AND key < '\\x026c6f677301'::bytea;",
+                xxhash_rust::xxh3::xxh3_64(b"\x02logs\x00"),
+            )
+        );
+        assert_eq!(advice[1].prefix, b"\x02users\x00");
+        assert_eq!(advice[1].scans, 120);
+        assert!(advice[1].ddl.contains("(key) INCLUDE"));
+    }
+
+    #[test]
+    fn prefix_ends() {
+        assert_eq!(prefix_end(b"\x02a\x00"), Some(b"\x02a\x01".to_vec()));
+        assert_eq!(prefix_end(b"\x02\xff"), Some(b"\x03".to_vec()));
+        assert_eq!(prefix_end(b"\xff\xff"), None);
+    }
+}
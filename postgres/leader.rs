@@ -0,0 +1,101 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+//! Leader election among the instances sharing a database.
+//!
+//! Each candidate keeps a connection of its own outside the pool and tries
+//! to take a session-level advisory lock named after the role. The one that
+//! gets it leads until its connection ends, at which point the server
+//! releases the lock and another candidate takes it on its next attempt.
+//! A leader checks its connection every [`CHECK_INTERVAL`], so one whose
+//! connection silently broke may go on believing it leads until then, and
+//! work done under a leadership must be safe to run twice.
+
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio_postgres::NoTls;
+
+use crate::error::PostgresResult;
+
+/// How often candidates try to take the lock, and the leader checks that it
+/// still holds it.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Takes the advisory lock named `$1`, returning whether it was free.
+const TRY_LOCK: &str = "SELECT pg_try_advisory_lock(hashtextextended($1, 0))";
+
+/// This instance's candidacy for a role among the instances sharing the
+/// tables, such as running background maintenance. Created by
+/// [`Postgres::elect_leader`](crate::Postgres::elect_leader); dropping it
+/// resigns, letting another instance take over.
+pub struct Leadership {
+    is_leader: watch::Receiver<bool>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Leadership {
+    fn drop(&mut self) {
+        // Dropping the connection releases the lock.
+        self.task.abort();
+    }
+}
+
+impl Leadership {
+    pub(crate) fn new(config: tokio_postgres::Config, lock: String) -> Self {
+        let (sender, is_leader) = watch::channel(false);
+        Self { is_leader, task: tokio::spawn(run(config, lock, sender)) }
+    }
+
+    /// Whether this instance leads, as of its last check.
+    pub fn is_leader(&self) -> bool {
+        *self.is_leader.borrow()
+    }
+
+    /// Wait until this instance leads.
+    pub async fn acquired(&self) {
+        let mut is_leader = self.is_leader.clone();
+        // The sender lives as long as `self`.
+        let _ = is_leader.wait_for(|leader| *leader).await;
+    }
+
+    /// A receiver of the leadership status, for tasks that outlive a borrow
+    /// of this.
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.is_leader.clone()
+    }
+}
+
+async fn run(config: tokio_postgres::Config, lock: String, is_leader: watch::Sender<bool>) {
+    loop {
+        if let Err(e) = campaign(&config, &lock, &is_leader).await {
+            log::warn!("Leader election for {lock} lost its connection: {e}");
+        }
+        is_leader.send_replace(false);
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
+
+/// Try to take `lock` until the connection fails, then keep checking the
+/// connection while holding it.
+async fn campaign(config: &tokio_postgres::Config, lock: &str, is_leader: &watch::Sender<bool>) -> PostgresResult<()> {
+    let (client, connection) = config.connect(NoTls).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            log::debug!("Leader election connection closed: {e}");
+        }
+    });
+
+    loop {
+        if !*is_leader.borrow() {
+            let acquired: bool = client.query_one(TRY_LOCK, &[&lock]).await?.get(0);
+            if acquired {
+                log::info!("Became the leader for {lock}");
+                is_leader.send_replace(true);
+            }
+        } else {
+            client.simple_query("SELECT 1").await?;
+        }
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
@@ -1,68 +1,291 @@
 // Copyright 2023 rawkakani. All rights reserved. MIT license.
 
+mod affinity;
 mod backend;
+mod backfill;
+mod change_notify;
+mod circuit_breaker;
 mod config;
+mod copy;
+mod decode;
+mod driver;
 mod error;
+mod fencing;
+mod index_advisor;
+mod leader;
+mod limits;
+mod local_replica;
 mod message_handle;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(not(feature = "metrics"))]
+#[path = "metrics_disabled.rs"]
+mod metrics;
+mod namespace;
 mod notifier;
+mod partition;
+mod partition_manager;
+mod pool;
+mod pressure;
+mod progress;
+mod queue_metadata;
+mod range;
+mod read_repair;
+mod reaper;
+mod replicas;
+mod settings;
+#[cfg(feature = "driver-sqlx")]
+mod sqlx_driver;
+mod statement_log;
+mod statistics;
+mod stats;
+mod table_partitioning;
+mod tables;
+mod throttle;
 mod time;
+mod views;
 
-use std::collections::HashMap;
+use std::num::NonZeroU32;
 use std::pin::Pin;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use async_stream::try_stream;
+use async_stream::{stream, try_stream};
 use async_trait::async_trait;
-use chrono::DateTime;
-use chrono::Utc;
-use deadpool_postgres::{Config, Pool, Runtime, Manager};
+use chrono::{DateTime, Utc};
 use deno_error::JsErrorBox;
 use denokv_proto::{
-    AtomicWrite, CommitResult, Database, KvEntry, KvValue, QueueMessageHandle,
-    ReadRange, ReadRangeOutput, SnapshotReadOptions, Versionstamp, WatchKeyOutput,
+    AtomicWrite, CommitResult, Consistency, Database, KvEntry, Mutation, MutationKind, ReadRange,
+    ReadRangeOutput, SnapshotReadOptions, WatchKeyOutput,
 };
-use futures::Stream;
-use tokio::sync::{watch, RwLock};
-use tokio_postgres::NoTls;
+use futures::{pin_mut, Stream, TryStreamExt};
+#[cfg(feature = "metrics")]
+use prometheus::proto::MetricFamily;
+use tokio::sync::watch;
+use tokio_postgres::IsolationLevel;
+use tracing::field::Empty;
+use tracing::Instrument;
+use uuid::Uuid;
 
-pub use config::PostgresConfig;
-pub use error::{PostgresError, PostgresResult};
+pub use backfill::{BackfillJob, BackfillProgress, BackfillRunner};
+pub use circuit_breaker::{CircuitEvent, CircuitState, OperationClass};
+pub use config::{Durability, PartitionRule, PostgresConfig};
+pub use copy::{CopyReport, Transforms};
+pub use decode::decode_entry;
+pub use driver::SCHEMA_VERSION;
+pub use error::{CorruptionKind, PostgresError, PostgresResult};
+pub use fencing::FencedWriter;
+pub use index_advisor::{IndexSuggestion, RangeScanShape};
+pub use leader::Leadership;
+pub use limits::{WriteLimit, WriteLimits};
+pub use local_replica::LocalReplica;
+pub use namespace::EphemeralNamespace;
+pub use partition::PartitionedPostgres;
+pub use partition_manager::{ArchivedPartition, PartitionReport};
+pub use pool::{ConnectionPool, Pool};
+#[cfg(feature = "pool-bb8")]
+pub use pool::{Bb8Pool, PgConnection, PgConnectionManager};
+pub use pressure::Pressure;
+pub use progress::{ProgressCallback, WriteProgress};
+pub use reaper::NamespaceDeletion;
+pub use settings::SettingWarning;
+pub use statistics::AnalyzedTable;
+#[cfg(feature = "driver-sqlx")]
+pub use sqlx_driver::{SqlxMessageHandle, SqlxPostgres};
+pub use stats::{CircuitStates, Health, PoolStatus, ServerInfo};
+pub use table_partitioning::TablePartitioning;
+pub use views::{MaterializedView, ViewMaintainer};
 
+pub use backend::{BulkImportReport, DeadLetter, KeySample, KeySampleReport, PrefixUsage, QueueDepth, RepairReport, VerifyReport};
+use affinity::AffinityLanes;
 use backend::PostgresBackend;
+use circuit_breaker::CircuitBreakers;
+use index_advisor::RangeScanRecorder;
 use message_handle::PostgresMessageHandle;
+use metrics::BackendMetrics;
 use notifier::PostgresNotifier;
+use read_repair::ObservedVersions;
+use replicas::ReplicaSet;
+use settings::ServerSettings;
+use throttle::WriteThrottle;
+
+/// How many entries a durable subscription fetches per query.
+const SUBSCRIPTION_BATCH_SIZE: i64 = 100;
+
+/// How long an idle durable subscription waits for a local write before
+/// checking the database for writes made through other instances.
+const SUBSCRIPTION_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the queue depth is counted for the metrics.
+#[cfg(feature = "metrics")]
+const QUEUE_DEPTH_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How often tables are checked for changes since they were last analyzed.
+const ANALYZE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long [`Postgres::health`] waits for the database to answer.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Parse `url`, naming the connections after this crate unless it names
+/// them itself.
+fn parse_url(url: &str) -> PostgresResult<tokio_postgres::Config> {
+    let mut pg_config = url.parse::<tokio_postgres::Config>()
+        .map_err(|e| PostgresError::InvalidConfig(format!("Invalid PostgreSQL URL: {}", e)))?;
+    if pg_config.get_application_name().is_none() {
+        pg_config.application_name(stats::DEFAULT_APPLICATION_NAME);
+    }
+    Ok(pg_config)
+}
+
+/// Parse `url` and build a connection pool for it.
+fn build_pool(url: &str, max_size: usize) -> PostgresResult<Pool> {
+    Pool::build(parse_url(url)?, max_size)
+}
+
+/// Fail on an invalid `config`, and log warnings about surprising settings.
+fn validate_config(config: &PostgresConfig) -> PostgresResult<()> {
+    for warning in config.validate()? {
+        log::warn!("PostgreSQL configuration: {warning}");
+    }
+    Ok(())
+}
+
+/// Sleep for `duration`, returning early with true if `shutdown` is set or
+/// its sender dropped in the meantime.
+async fn sleep_until_shutdown(duration: Duration, shutdown: &mut watch::Receiver<bool>) -> bool {
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => false,
+        _ = shutdown.wait_for(|closed| *closed) => true,
+    }
+}
+
+/// A span around the statements of one step of an operation, timing the
+/// time spent in the database apart from waiting for a connection.
+fn sql_span(statement: &'static str) -> tracing::Span {
+    tracing::info_span!("sql", statement)
+}
+
+/// The lease of watches, if they have one.
+fn watch_lease(config: &PostgresConfig) -> Option<Duration> {
+    (config.watch_lease > 0).then(|| Duration::from_secs(config.watch_lease))
+}
+
+/// Release the subscriptions of watches whose lease expired, every half
+/// lease, until `shutdown` is set.
+async fn release_expired_watches(notifier: PostgresNotifier, lease: Duration, mut shutdown: watch::Receiver<bool>) {
+    while !sleep_until_shutdown(lease / 2, &mut shutdown).await {
+        let released = notifier.release_expired();
+        if released > 0 {
+            log::debug!("Released {released} watch(es) not polled for {}s", lease.as_secs());
+        }
+    }
+}
+
+/// Delete expired rows batch by batch until none are left or `shutdown` is
+/// set. Returns the number of rows removed.
+async fn collect_expired(backend: &PostgresBackend, shutdown: &watch::Receiver<bool>) -> PostgresResult<u64> {
+    let batch_size = backend::sql_limit(backend.config.expiry_sweep_batch_size.max(1));
+    let mut collected = 0;
+    while !*shutdown.borrow() {
+        let deleted = backend.collect_expired(batch_size).await?;
+        collected += deleted;
+        if deleted < batch_size as u64 {
+            break;
+        }
+    }
+    Ok(collected)
+}
+
+/// Whether this instance is to run background maintenance: always, unless
+/// it takes part in an election and is not the leader.
+fn leads(maintenance: &Option<Arc<Leadership>>) -> bool {
+    maintenance.as_ref().is_none_or(|leadership| leadership.is_leader())
+}
+
+async fn analyze(backend: &PostgresBackend, metrics: &BackendMetrics, min_modified_rows: u64) -> PostgresResult<Vec<AnalyzedTable>> {
+    let conn = backend.pool.get().await?;
+    let analyzed = statistics::analyze_stale(&conn, &backend.tables, min_modified_rows).await?;
+    for table in &analyzed {
+        log::info!("Analyzed {} after {} changed rows", table.table, table.modified_rows);
+        metrics.record_analyze(&table.table);
+    }
+    Ok(analyzed)
+}
 
 /// PostgreSQL implementation of the DenoKV Database trait
 #[derive(Clone)]
 pub struct Postgres {
     pool: Pool,
+    replicas: Arc<ReplicaSet>,
+    throttle: Option<Arc<WriteThrottle>>,
+    /// Connections kept for writes to hot keys, if configured.
+    affinity: Option<Arc<AffinityLanes>>,
+    breakers: Arc<CircuitBreakers>,
     notifier: PostgresNotifier,
     backend: Arc<PostgresBackend>,
+    metrics: Arc<BackendMetrics>,
+    /// How long operations wait for a pooled connection before they are shed.
+    pool_wait_timeout: Duration,
+    /// Shapes of range scans, while diagnostics are enabled.
+    range_scans: Option<Arc<RangeScanRecorder>>,
+    /// Versions recently seen by this process, with read repair enabled.
+    observed: Option<Arc<ObservedVersions>>,
+    /// Set to true by [`Database::close`]; background tasks stop when it is
+    /// set or dropped.
+    shutdown: Arc<watch::Sender<bool>>,
 }
 
 impl Postgres {
     /// Create a new PostgreSQL database instance
     pub async fn new(config: PostgresConfig) -> PostgresResult<Self> {
-        // Parse the connection string
-        let pg_config = config.url.parse::<tokio_postgres::Config>()
-            .map_err(|e| PostgresError::InvalidConfig(format!("Invalid PostgreSQL URL: {}", e)))?;
-
-        // Create deadpool manager
-        let manager = Manager::new(pg_config, NoTls);
-        
-        // Create the connection pool
-        let pool = Pool::builder(manager)
-            .max_size(config.max_connections)
-            .build()
-            .map_err(|e| PostgresError::ConnectionFailed(format!("Failed to create connection pool: {}", e)))?;
-
-        // Test the connection
+        validate_config(&config)?;
+        let pool = build_pool(&config.url, config.max_connections)?;
+        Self::open(pool, config).await
+    }
+
+    /// Create a PostgreSQL database instance on a pool built by the
+    /// application, for example with its own TLS setup, hooks or metrics.
+    /// Schema checks and background tasks run as with [`Postgres::new`].
+    ///
+    /// The pool keeps its own size limit; `config.max_connections` only
+    /// limits the pools of read replicas, which are connected to as with
+    /// [`Postgres::new`]. `config.url` is still used, without TLS, for the
+    /// connection listening for changes made through other instances; set
+    /// `listen_for_changes` to false if that connection can not be made.
+    pub async fn from_pool(pool: Pool, config: PostgresConfig) -> PostgresResult<Self> {
+        validate_config(&config)?;
+        Self::open(pool, config).await
+    }
+
+    async fn open(pool: Pool, config: PostgresConfig) -> PostgresResult<Self> {
+        let replicas = config.read_replica_urls.iter()
+            .map(|url| build_pool(url, config.max_connections))
+            .collect::<PostgresResult<Vec<_>>>()?;
+
+        // Test the connection and make sure key ordering matches memcmp
         let conn = pool.get().await
             .map_err(|e| PostgresError::ConnectionFailed(format!("Failed to get connection: {}", e)))?;
+        range::check_byte_order(&conn).await?;
+        drop(conn);
+
+        let throttle = WriteThrottle::new(
+            config.write_ops_per_sec,
+            config.write_bytes_per_sec,
+            config.write_queue_limit,
+        ).map(Arc::new);
+        let affinity = AffinityLanes::new(config.affinity_lanes).map(Arc::new);
+        let breakers = Arc::new(CircuitBreakers::new(&config));
+        let pool_wait_timeout = Duration::from_secs(config.pool_wait_timeout);
+        let max_replica_lag = config.max_replica_lag.map(Duration::from_secs_f64);
+
+        let range_scans = config.record_range_scans.then(Default::default);
+        let observed = (config.read_repair_window > 0)
+            .then(|| Arc::new(ObservedVersions::new(Duration::from_secs(config.read_repair_window))));
+        let listen_config = config.listen_for_changes.then(|| parse_url(&config.url)).transpose()?;
+        let election_config = config.elect_maintenance_leader.then(|| parse_url(&config.url)).transpose()?;
 
         // Initialize the database schema
-        let backend = Arc::new(PostgresBackend::new(pool.clone()));
+        let backend = Arc::new(PostgresBackend::new(pool.clone(), config));
         backend.initialize_schema().await?;
 
         // Create notifier
@@ -70,20 +293,77 @@ impl Postgres {
 
         let pg = Postgres {
             pool,
+            replicas: Arc::new(ReplicaSet::new(replicas, max_replica_lag)),
+            throttle,
+            affinity,
+            breakers,
             notifier,
             backend,
+            metrics: Arc::new(BackendMetrics::new()),
+            pool_wait_timeout,
+            range_scans,
+            observed,
+            shutdown: Arc::new(watch::channel(false).0),
         };
 
+        if pg.backend.config.check_server_settings {
+            match pg.check_server_settings().await {
+                Ok(warnings) => {
+                    for warning in warnings {
+                        log::warn!("PostgreSQL setting {warning}");
+                    }
+                }
+                Err(e) => log::warn!("Failed to check PostgreSQL settings: {e}"),
+            }
+        }
+
+        // Wake watchers and queue consumers for writes made through other
+        // instances.
+        if let Some(listen_config) = listen_config {
+            let listener = change_notify::listen(
+                listen_config,
+                pg.backend.channel.clone(),
+                pg.backend.tables.queue_channel(),
+                pg.notifier.clone(),
+            );
+            let mut shutdown = pg.shutdown.subscribe();
+            tokio::spawn(async move {
+                tokio::select! {
+                    _ = listener => {}
+                    _ = shutdown.wait_for(|closed| *closed) => {}
+                }
+            });
+        }
+        if let Some(lease) = watch_lease(&pg.backend.config) {
+            tokio::spawn(release_expired_watches(pg.notifier.clone(), lease, pg.shutdown.subscribe()));
+        }
+
         // Spawn background tasks matching SQLite backend behaviour:
-        //  1. Periodic expired-key collection (every 60 s)
-        //  2. Periodic queue cleanup — requeue messages stuck in queue_running
+        //  1. Periodic expired-key collection, if enabled
+        //  2. Periodic tombstone trimming (every 60 s)
+        //  3. Periodic queue cleanup — requeue messages stuck in queue_running
         //     past their deadline (every 30 s)
-        {
+        //  4. Analyzing tables that changed a lot, if enabled (every 60 s)
+        //  5. Reaping namespaces deleted with `delete_namespace` (every 10 s)
+        //  6. Managing the partitions of a partitioned kv_store, if enabled
+        //     (every 5 min)
+        // With leader election, they skip their turns unless this instance
+        // leads. Each stops once the database is closed or every clone of it
+        // has been dropped, after finishing the statement it is running; the
+        // election ends with the last of them.
+        let maintenance = election_config
+            .map(|config| Arc::new(Leadership::new(config, format!("{}:maintenance", pg.backend.channel))));
+        if pg.backend.config.expiry_sweep_interval > 0 {
+            let interval = Duration::from_secs(pg.backend.config.expiry_sweep_interval);
             let backend = pg.backend.clone();
+            let maintenance = maintenance.clone();
+            let mut shutdown = pg.shutdown.subscribe();
             tokio::spawn(async move {
-                loop {
-                    tokio::time::sleep(Duration::from_secs(60)).await;
-                    match backend.collect_expired().await {
+                while !sleep_until_shutdown(interval, &mut shutdown).await {
+                    if !leads(&maintenance) {
+                        continue;
+                    }
+                    match collect_expired(&backend, &shutdown).await {
                         Ok(n) if n > 0 => {
                             eprintln!("[denokv/postgres] collected {n} expired key(s)");
                         }
@@ -97,9 +377,86 @@ impl Postgres {
         }
         {
             let backend = pg.backend.clone();
+            let maintenance = maintenance.clone();
+            let mut shutdown = pg.shutdown.subscribe();
             tokio::spawn(async move {
-                loop {
-                    tokio::time::sleep(Duration::from_secs(30)).await;
+                while !sleep_until_shutdown(Duration::from_secs(60), &mut shutdown).await {
+                    if !leads(&maintenance) {
+                        continue;
+                    }
+                    if let Err(e) = backend.trim_tombstones().await {
+                        eprintln!("[denokv/postgres] trim_tombstones error: {e}");
+                    }
+                }
+            });
+        }
+        if pg.backend.config.analyze_threshold > 0 {
+            let backend = pg.backend.clone();
+            let metrics = pg.metrics.clone();
+            let maintenance = maintenance.clone();
+            let mut shutdown = pg.shutdown.subscribe();
+            tokio::spawn(async move {
+                while !sleep_until_shutdown(ANALYZE_CHECK_INTERVAL, &mut shutdown).await {
+                    if !leads(&maintenance) {
+                        continue;
+                    }
+                    if let Err(e) = analyze(&backend, &metrics, backend.config.analyze_threshold).await {
+                        eprintln!("[denokv/postgres] analyze error: {e}");
+                    }
+                }
+            });
+        }
+        {
+            let backend = pg.backend.clone();
+            let metrics = pg.metrics.clone();
+            let maintenance = maintenance.clone();
+            let mut shutdown = pg.shutdown.subscribe();
+            tokio::spawn(async move {
+                while !sleep_until_shutdown(reaper::REAP_INTERVAL, &mut shutdown).await {
+                    if !leads(&maintenance) {
+                        continue;
+                    }
+                    // Stop between batches once this instance no longer leads.
+                    let proceed = || !*shutdown.borrow() && leads(&maintenance);
+                    if let Err(e) = reaper::reap(&backend, &metrics, proceed).await {
+                        eprintln!("[denokv/postgres] namespace reaper error: {e}");
+                    }
+                }
+            });
+        }
+        if pg.backend.config.partition_split_rows > 0 || pg.backend.config.partition_archive_after > 0 {
+            let backend = pg.backend.clone();
+            let maintenance = maintenance.clone();
+            let mut shutdown = pg.shutdown.subscribe();
+            tokio::spawn(async move {
+                while !sleep_until_shutdown(partition_manager::MANAGE_INTERVAL, &mut shutdown).await {
+                    if !leads(&maintenance) {
+                        continue;
+                    }
+                    match partition_manager::manage(&backend).await {
+                        Ok(report) => {
+                            for partition in &report.archived {
+                                log::info!("Archived the cold partition {}", partition.table);
+                            }
+                            for (partition, new) in &report.split {
+                                log::info!("Split {partition}, moving half of its rows to {new}");
+                            }
+                        }
+                        Err(e) => {
+                            eprintln!("[denokv/postgres] partition manager error: {e}");
+                        }
+                    }
+                }
+            });
+        }
+        {
+            let backend = pg.backend.clone();
+            let mut shutdown = pg.shutdown.subscribe();
+            tokio::spawn(async move {
+                while !sleep_until_shutdown(Duration::from_secs(30), &mut shutdown).await {
+                    if !leads(&maintenance) {
+                        continue;
+                    }
                     match backend.queue_cleanup().await {
                         Ok(n) if n > 0 => {
                             eprintln!("[denokv/postgres] requeued {n} dead queue message(s)");
@@ -112,112 +469,916 @@ impl Postgres {
                 }
             });
         }
+        if !pg.replicas.is_empty() {
+            let replicas = pg.replicas.clone();
+            let metrics = pg.metrics.clone();
+            let mut shutdown = pg.shutdown.subscribe();
+            tokio::spawn(async move {
+                loop {
+                    for (index, lag) in replicas.measure_lags().await.into_iter().enumerate() {
+                        metrics.set_replica_lag(index, lag);
+                    }
+                    if sleep_until_shutdown(replicas::LAG_CHECK_INTERVAL, &mut shutdown).await {
+                        break;
+                    }
+                }
+            });
+        }
+        #[cfg(feature = "metrics")]
+        {
+            let backend = pg.backend.clone();
+            let metrics = pg.metrics.clone();
+            let mut shutdown = pg.shutdown.subscribe();
+            tokio::spawn(async move {
+                loop {
+                    match backend.queue_depth().await {
+                        Ok(depth) => metrics.set_queue_depth(depth),
+                        Err(e) => log::debug!("Failed to count the queue depth: {e}"),
+                    }
+                    if sleep_until_shutdown(QUEUE_DEPTH_INTERVAL, &mut shutdown).await {
+                        break;
+                    }
+                }
+            });
+        }
 
         Ok(pg)
     }
 
-    /// Get a connection from the pool
-    async fn get_connection(&self) -> PostgresResult<deadpool_postgres::Client> {
-        self.pool.get().await
-            .map_err(|e| PostgresError::ConnectionFailed(format!("Failed to get connection: {}", e)))
+    /// Delete every expired row now, rather than on the next sweep. Returns
+    /// the number of rows removed.
+    pub async fn collect_expired(&self) -> PostgresResult<u64> {
+        collect_expired(&self.backend, &self.shutdown.subscribe()).await
     }
-}
 
-#[async_trait]
-impl Database for Postgres {
-    type QMH = PostgresMessageHandle;
+    /// Release the messages whose worker did not finish them within
+    /// [`PostgresConfig::queue_visibility_timeout`], now rather than on the
+    /// next queue cleanup. Each is retried, dead-lettered or written to its
+    /// `keys_if_undelivered` as if it had failed. Returns the number of
+    /// messages requeued for another delivery.
+    pub async fn requeue_overdue_messages(&self) -> PostgresResult<u64> {
+        let requeued = self.backend.queue_cleanup().await?;
+        if requeued > 0 {
+            self.notifier.notify_enqueued();
+        }
+        Ok(requeued)
+    }
 
-    async fn snapshot_read(
+    /// Wait until [`Database::dequeue_next_message`] may find a message,
+    /// for consumers to call whenever it found none instead of polling: the
+    /// next scheduled message is due, or a message is enqueued or
+    /// rescheduled through this instance, or with
+    /// [`PostgresConfig::listen_for_changes`] through any instance. Returns
+    /// after `timeout` regardless, and returns at once if a message is due
+    /// already. The message may still be taken by another consumer first.
+    pub async fn wait_for_message(&self, timeout: Duration) -> PostgresResult<()> {
+        self.wait_for_message_in(None, timeout).await
+    }
+
+    /// `wait_for_message`, only considering messages of `namespace`.
+    pub(crate) async fn wait_for_message_in(&self, namespace: Option<&[u8]>, timeout: Duration) -> PostgresResult<()> {
+        // Listen before looking, so that no enqueue in between is missed.
+        let mut enqueued = std::pin::pin!(self.notifier.enqueued());
+        enqueued.as_mut().enable();
+        let wait = match self.backend.next_message_deadline(namespace).await? {
+            Some(deadline) => {
+                let now_ms = time::utc_now().timestamp_millis();
+                Duration::from_millis(deadline.saturating_sub(now_ms).max(0) as u64).min(timeout)
+            }
+            None => timeout,
+        };
+        let _ = tokio::time::timeout(wait, enqueued).await;
+        Ok(())
+    }
+
+    /// Scan the whole keyspace for rows that fail checksum verification or
+    /// cannot be decoded.
+    pub async fn verify(&self) -> PostgresResult<VerifyReport> {
+        self.backend.verify().await
+    }
+
+    /// A view of this database under a fresh, unique key prefix that
+    /// deletes its keys and queue messages when dropped. See
+    /// [`EphemeralNamespace`].
+    pub fn ephemeral_namespace(&self) -> EphemeralNamespace {
+        EphemeralNamespace::new(self.clone())
+    }
+
+    /// Keep `cache` in sync with this database and serve eventually
+    /// consistent reads from it. See [`LocalReplica`].
+    pub fn local_replica(&self, cache: denokv_sqlite::Sqlite) -> LocalReplica {
+        LocalReplica::new(self.clone(), cache)
+    }
+
+    /// Sample `n` keys under `prefix` and estimate the number of keys and
+    /// bytes stored under it, without scanning the whole keyspace. Useful
+    /// for finding hot prefixes and sizing migrations.
+    pub async fn sample_keys(&self, prefix: &[u8], n: usize) -> PostgresResult<KeySampleReport> {
+        self.backend.sample_keys(prefix, n).await
+    }
+
+    /// Count the keys stored under `prefix` and their bytes exactly. Unlike
+    /// [`Postgres::sample_keys`] this reads every key under the prefix.
+    pub async fn prefix_usage(&self, prefix: &[u8]) -> PostgresResult<PrefixUsage> {
+        self.backend.prefix_usage(prefix).await
+    }
+
+    /// Count the queue messages of every namespace by state: ready to be
+    /// dequeued, scheduled for later, running and dead-lettered.
+    pub async fn queue_depth(&self) -> PostgresResult<QueueDepth> {
+        self.backend.queue_depth().await
+    }
+
+    /// Fix detectable inconsistencies in internal state. See
+    /// [`RepairReport`] for what is checked. With `dry_run` nothing is
+    /// changed and the report describes what would have been fixed.
+    pub async fn repair(&self, dry_run: bool, reindex: bool) -> PostgresResult<RepairReport> {
+        self.backend.repair(dry_run, reindex).await
+    }
+
+    /// Apply a bulk import that does not need to be atomic as a whole,
+    /// split into resumable sub-transactions according to
+    /// [`PostgresConfig::bulk_import_batch_size`]. Use [`Database::atomic_write`]
+    /// for writes that must be all-or-nothing.
+    pub async fn bulk_import(
         &self,
-        requests: Vec<ReadRange>,
-        options: SnapshotReadOptions,
-    ) -> Result<Vec<ReadRangeOutput>, JsErrorBox> {
-        let conn = self.get_connection().await
-            .map_err(JsErrorBox::from_err)?;
+        import_id: &str,
+        mutations: &[Mutation],
+    ) -> PostgresResult<BulkImportReport> {
+        self.bulk_import_with_progress(import_id, mutations, None).await
+    }
 
-        let mut outputs = Vec::new();
-        for request in requests {
-            let entries = self.backend.read_range(&conn, &request).await
-                .map_err(JsErrorBox::from_err)?;
-            outputs.push(ReadRangeOutput { entries });
+    /// [`Postgres::bulk_import`], calling `progress` as mutations are
+    /// applied and batches committed, e.g. to show a progress bar.
+    pub async fn bulk_import_with_progress(
+        &self,
+        import_id: &str,
+        mutations: &[Mutation],
+        progress: Option<&ProgressCallback<'_>>,
+    ) -> PostgresResult<BulkImportReport> {
+        if let Some(throttle) = &self.throttle {
+            throttle.acquire(mutations.len() as u64, throttle::mutations_size(mutations)).await?;
+        }
+        let mut conn = self.get_connection().await?;
+        let report = self.backend.bulk_import(&mut conn, import_id, mutations, progress).await?;
+        if report.mutations_applied > 0 {
+            for mutation in mutations {
+                self.notifier.notify_key_update(&mutation.key);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Watch every key starting with `prefix`. The stream yields once
+    /// immediately and then whenever a write through this instance, or with
+    /// [`PostgresConfig::listen_for_changes`] through any instance, changes
+    /// a key under the prefix; consumers re-read whatever range they need.
+    /// Matching a change against prefix watchers costs O(key length)
+    /// regardless of how many watchers there are.
+    pub fn watch_prefix(&self, prefix: Vec<u8>) -> impl Stream<Item = ()> + Send + 'static {
+        let mut subscription = self.notifier.subscribe_prefix(prefix);
+        stream! {
+            yield ();
+            loop {
+                subscription.wait_for_change().await;
+                yield ();
+            }
         }
+    }
 
-        Ok(outputs)
+    /// Register the durable subscription `name` on keys starting with
+    /// `prefix`. It starts at the current version; registering an existing
+    /// subscription again keeps its position.
+    pub async fn register_subscription(&self, name: &str, prefix: &[u8]) -> PostgresResult<()> {
+        self.backend.register_subscription(name, prefix).await
     }
 
-    async fn atomic_write(
+    /// Follow the durable subscription `name` on keys starting with
+    /// `prefix`, registering it if it does not exist yet.
+    ///
+    /// The backend records how far the subscription has been delivered, so a
+    /// consumer that reopens it after a restart resumes where it left off
+    /// instead of missing the changes made in between. An entry counts as
+    /// delivered once the consumer asks for the item after it, so delivery
+    /// is at-least-once: the last entry handed out before a crash may be
+    /// delivered again. Deletions are not delivered.
+    pub fn subscription_stream(
+        &self,
+        name: String,
+        prefix: Vec<u8>,
+    ) -> impl Stream<Item = PostgresResult<KvEntry>> + Send + 'static {
+        let backend = self.backend.clone();
+        let mut changes = self.notifier.subscribe_prefix(prefix.clone());
+        try_stream! {
+            backend.register_subscription(&name, &prefix).await?;
+            loop {
+                let entries = backend.poll_subscription(&name, SUBSCRIPTION_BATCH_SIZE).await?;
+                let Some(last) = entries.last() else {
+                    // Writes through other instances are only notified here
+                    // while listening for changes, so poll again after a
+                    // while regardless.
+                    let _ = tokio::time::timeout(SUBSCRIPTION_POLL_INTERVAL, changes.wait_for_change()).await;
+                    continue;
+                };
+                let (versionstamp, key) = (last.versionstamp, last.key.clone());
+                for entry in entries {
+                    yield entry;
+                }
+                backend.ack_subscription(&name, &versionstamp, &key).await?;
+            }
+        }
+    }
+
+    /// Remove the durable subscription `name`. Returns whether it existed.
+    pub async fn delete_subscription(&self, name: &str) -> PostgresResult<bool> {
+        self.backend.delete_subscription(name).await
+    }
+
+    /// Bring `view` up to date with its source, registering it if it does
+    /// not exist yet. A new view is built from everything already under its
+    /// source prefix. Returns the number of source changes applied.
+    pub async fn refresh_view(&self, view: &MaterializedView) -> PostgresResult<u64> {
+        views::refresh(self, view).await
+    }
+
+    /// Keep `view` up to date in the background until the returned
+    /// [`ViewMaintainer`] is dropped. See [`MaterializedView`].
+    pub fn maintain_view(&self, view: MaterializedView) -> ViewMaintainer {
+        ViewMaintainer::new(self.clone(), view)
+    }
+
+    /// Remove the view `name` and every entry it derived. Returns whether it
+    /// existed. A running [`ViewMaintainer`] for it registers it again.
+    pub async fn delete_view(&self, name: &str) -> PostgresResult<bool> {
+        let mut conn = self.get_connection().await?;
+        let Some(keys) = self.backend.clear_view(&mut conn, name, true).await? else {
+            return Ok(false);
+        };
+        self.notify_keys(&keys);
+        Ok(true)
+    }
+
+    /// The shapes of the range scans served so far, most frequent first.
+    /// Empty unless [`PostgresConfig::record_range_scans`] is set.
+    pub fn range_scan_shapes(&self) -> Vec<RangeScanShape> {
+        self.range_scans.as_ref().map_or_else(Vec::new, |recorder| recorder.shapes())
+    }
+
+    /// Partial indexes that would speed up the prefixes scanned at least
+    /// `min_scans` times so far, with the DDL creating them. Only advises
+    /// while [`PostgresConfig::record_range_scans`] is set. The indexes are
+    /// not created.
+    pub fn index_advice(&self, min_scans: u64) -> Vec<IndexSuggestion> {
+        let mut advice = index_advisor::advise(&self.range_scan_shapes(), min_scans);
+        for suggestion in &mut advice {
+            suggestion.ddl = self.backend.sql(&suggestion.ddl).into_owned();
+        }
+        advice
+    }
+
+    /// Change the write rate limits of [`PostgresConfig::write_ops_per_sec`]
+    /// and [`PostgresConfig::write_bytes_per_sec`] without reconnecting.
+    /// Returns `false`, changing nothing, if writes were opened unthrottled.
+    pub fn set_write_rates(&self, ops_per_sec: Option<f64>, bytes_per_sec: Option<f64>) -> bool {
+        match &self.throttle {
+            Some(throttle) => {
+                throttle.set_rates(ops_per_sec, bytes_per_sec);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Subscribe to circuit breaker state changes, e.g. to raise alerts.
+    pub fn circuit_events(&self) -> tokio::sync::broadcast::Receiver<CircuitEvent> {
+        self.breakers.subscribe()
+    }
+
+    /// Current size and utilisation of the connection pool.
+    pub fn pool_status(&self) -> PoolStatus {
+        PoolStatus::from_pool(&self.pool)
+    }
+
+    /// Check that the database answers a query within a few seconds, for
+    /// readiness probes, reading the schema version on the way. Problems are
+    /// reported in the result rather than as an error.
+    pub async fn health(&self) -> Health {
+        let started = Instant::now();
+        let check = async {
+            let conn = self.get_connection().await?;
+            let row = conn.query_one(&*self.backend.sql("SELECT schema_version FROM data_version WHERE k = 0"), &[]).await?;
+            Ok::<_, PostgresError>(row.get::<_, i32>(0) as u32)
+        };
+        let result = match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, check).await {
+            Ok(result) => result,
+            Err(_) => Err(PostgresError::ConnectionFailed("Health check timed out".to_string())),
+        };
+        let circuits = CircuitStates {
+            read: self.breakers.read.state(),
+            write: self.breakers.write.state(),
+            queue: self.breakers.queue.state(),
+        };
+        Health {
+            healthy: result.is_ok() && !circuits.any_open(),
+            latency: result.is_ok().then(|| started.elapsed()),
+            schema_version: result.as_ref().ok().copied(),
+            error: result.err().map(|e| e.to_string()),
+            pool: self.pool_status(),
+            circuits,
+            replica_lags: self.replicas.lags(),
+        }
+    }
+
+    /// Analyze `kv_store`, `kv_tombstones` and `queue_messages` if at least
+    /// `min_modified_rows` of their rows changed since they were last
+    /// analyzed, returning the tables analyzed. 0 analyzes all of them.
+    /// Runs in the background with [`PostgresConfig::analyze_threshold`].
+    pub async fn analyze(&self, min_modified_rows: u64) -> PostgresResult<Vec<AnalyzedTable>> {
+        analyze(&self.backend, &self.metrics, min_modified_rows).await
+    }
+
+    /// Delete everything stored under `prefix` in the background: its keys
+    /// and their tombstones, the keys materialized views derived from them,
+    /// and queue messages enqueued through ephemeral namespaces under it.
+    /// The request is journaled and this returns right away; the
+    /// maintenance leader deletes the rows batch by batch. Requesting a
+    /// deletion that is pending already changes nothing, and one that
+    /// completed starts over. See [`Postgres::namespace_deletion`] for its
+    /// progress.
+    pub async fn delete_namespace(&self, prefix: &[u8]) -> PostgresResult<NamespaceDeletion> {
+        if prefix.is_empty() {
+            return Err(PostgresError::InvalidData("The namespace prefix is empty, so it would delete every key".to_string()));
+        }
+        self.backend.request_namespace_deletion(prefix).await
+    }
+
+    /// The progress of the deletion of `prefix`, or `None` if it was never
+    /// requested.
+    pub async fn namespace_deletion(&self, prefix: &[u8]) -> PostgresResult<Option<NamespaceDeletion>> {
+        self.backend.namespace_deletion(prefix).await
+    }
+
+    /// Every namespace deletion requested, completed or not, oldest first.
+    pub async fn namespace_deletions(&self) -> PostgresResult<Vec<NamespaceDeletion>> {
+        self.backend.namespace_deletions(false).await
+    }
+
+    /// Reap the pending namespace deletions now, until every one of them
+    /// completed, returning them. Runs in the background on the maintenance
+    /// leader.
+    pub async fn reap_namespaces(&self) -> PostgresResult<Vec<NamespaceDeletion>> {
+        reaper::reap(&self.backend, &self.metrics, || true).await
+    }
+
+    /// Archive the range partitions of a partitioned `kv_store` that went
+    /// cold and split those that grew large now, as configured by
+    /// [`PostgresConfig::partition_archive_after`] and
+    /// [`PostgresConfig::partition_split_rows`]. Runs in the background on
+    /// the maintenance leader every five minutes.
+    pub async fn manage_partitions(&self) -> PostgresResult<PartitionReport> {
+        partition_manager::manage(&self.backend).await
+    }
+
+    /// The partitions archived by [`Postgres::manage_partitions`] and not
+    /// dropped yet.
+    pub async fn archived_partitions(&self) -> PostgresResult<Vec<ArchivedPartition>> {
+        partition_manager::archived(&self.backend).await
+    }
+
+    /// Stream the rows of an archived partition in the binary format of
+    /// `COPY`, for storing elsewhere, such as in an object store. `COPY ...
+    /// FROM STDIN (FORMAT binary)` loads them back into a table with the
+    /// columns of `kv_store`. The stream holds its own pooled connection
+    /// until it is dropped.
+    pub fn export_archived_partition(
+        &self,
+        partition: &ArchivedPartition,
+    ) -> impl Stream<Item = PostgresResult<bytes::Bytes>> + Send + 'static {
+        partition_manager::export(self.backend.clone(), partition.table.clone())
+    }
+
+    /// Drop an archived partition, once it was exported.
+    pub async fn drop_archived_partition(&self, partition: &ArchivedPartition) -> PostgresResult<()> {
+        partition_manager::drop_archived(&self.backend, &partition.table).await
+    }
+
+    /// Check the server's settings for ones that commonly cause slow or
+    /// unreliable operation: too few connections for the pool, risky
+    /// `synchronous_commit` modes, a small `work_mem`, autovacuum disabled
+    /// for the server or the tables of this instance, and partition pruning
+    /// disabled for a partitioned `kv_store`.
+    pub async fn check_server_settings(&self) -> PostgresResult<Vec<SettingWarning>> {
+        let conn = self.get_connection().await?;
+        let settings = ServerSettings::query(&conn, &self.backend.tables).await?;
+        // The listening and election connections are made outside the pool.
+        let config = &self.backend.config;
+        let connections = self.pool_status().max_size + config.listen_for_changes as usize + config.elect_maintenance_leader as usize;
+        Ok(settings::check(&settings, connections))
+    }
+
+    /// Prometheus metrics of the database: operation counts and latencies,
+    /// retries, the connection pool, the circuit breakers and the queue
+    /// depth, counted every 15 seconds.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> Vec<MetricFamily> {
+        self.metrics.gather(&self.pool, &self.breakers)
+    }
+
+    /// Register the metrics of [`Postgres::metrics`] with `registry`, so that
+    /// they are gathered with the application's own. Fails if the registry
+    /// already has metrics of the same names, such as those of another
+    /// database; use [`Postgres::metrics`] and label them apart instead.
+    #[cfg(feature = "metrics")]
+    pub fn register_metrics(&self, registry: &prometheus::Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(metrics::Collector::new(self.metrics.clone(), self.pool.clone(), self.breakers.clone())))
+    }
+
+    /// The replication lag of each read replica, in the order they were
+    /// configured, as last measured. `None` for replicas not measured yet
+    /// or that are not standbys.
+    pub fn replica_lags(&self) -> Vec<Option<Duration>> {
+        self.replicas.lags()
+    }
+
+    /// Query the server for its version, this application's connection
+    /// count and replication lag.
+    pub async fn server_info(&self) -> PostgresResult<ServerInfo> {
+        let conn = self.get_connection().await?;
+        ServerInfo::query(&conn).await
+    }
+
+    /// Copy the keys under `prefix` into `dest`, such as a staging
+    /// database or a SQLite file, passing their values through
+    /// `transforms`. Writes made during the copy are copied too. Expiry
+    /// times are kept, versionstamps are assigned by `dest`.
+    pub async fn copy_to<D: Database>(
+        &self,
+        prefix: &[u8],
+        dest: &D,
+        transforms: &Transforms,
+    ) -> PostgresResult<CopyReport> {
+        copy::copy_to(&self.backend, prefix, dest, transforms).await
+    }
+
+    /// Stream every entry in `request` without buffering the result set.
+    /// The stream holds its own pooled connection until it is dropped, which
+    /// makes it suitable for exporting or backing up large keyspaces.
+    pub fn read_range_stream(
+        &self,
+        request: ReadRange,
+    ) -> impl Stream<Item = PostgresResult<KvEntry>> + Send + 'static {
+        let backend = self.backend.clone();
+        try_stream! {
+            let conn = backend.pool.get().await
+                .map_err(|e| PostgresError::ConnectionFailed(format!("Failed to get connection: {}", e)))?;
+            let entries = backend.read_range_stream(&conn, &request).await?;
+            pin_mut!(entries);
+            while let Some(entry) = entries.try_next().await? {
+                yield entry;
+            }
+        }
+    }
+
+    /// Walk every entry under `prefix` in key order, fetching `batch_size`
+    /// entries per query, for backfills and batch jobs. The whole walk
+    /// reads one REPEATABLE READ snapshot taken when it starts, so entries
+    /// written meanwhile are neither skipped nor seen twice however long it
+    /// takes. With `rate_limit`, at most that many entries per second are
+    /// fetched on average.
+    ///
+    /// The stream holds a pooled connection and a read-only transaction
+    /// until it is dropped. The transaction keeps PostgreSQL from vacuuming
+    /// rows changed since the snapshot, so slow scans of large prefixes are
+    /// best run off-peak.
+    pub fn scan(
+        &self,
+        prefix: Vec<u8>,
+        batch_size: NonZeroU32,
+        rate_limit: Option<f64>,
+    ) -> impl Stream<Item = PostgresResult<KvEntry>> + Send + 'static {
+        let end = partition::prefix_upper_bound(&prefix);
+        self.scan_range(prefix, end, batch_size, rate_limit).map_ok(|(entry, _)| entry)
+    }
+
+    /// [`Postgres::scan`] of the keys from `start` up to `end`, with their
+    /// expiry times.
+    pub(crate) fn scan_range(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        batch_size: NonZeroU32,
+        rate_limit: Option<f64>,
+    ) -> impl Stream<Item = PostgresResult<(KvEntry, Option<DateTime<Utc>>)>> + Send + 'static {
+        let backend = self.backend.clone();
+        try_stream! {
+            let mut conn = backend.pool.get().await
+                .map_err(|e| PostgresError::ConnectionFailed(format!("Failed to get connection: {}", e)))?;
+            let tx = conn.build_transaction()
+                .isolation_level(IsolationLevel::RepeatableRead)
+                .read_only(true)
+                .start()
+                .await?;
+            let throttle = WriteThrottle::new(rate_limit, None, 1);
+            let now_ms = crate::time::utc_now().timestamp_millis();
+            let limit = batch_size.get() as i64;
+            let mut start = start;
+            loop {
+                if let Some(throttle) = &throttle {
+                    throttle.acquire(limit as u64, 0).await?;
+                }
+                let entries = backend.scan_batch(&tx, &start, end.as_deref(), limit, now_ms).await?;
+                let done = (entries.len() as i64) < limit;
+                if let Some((last, _)) = entries.last() {
+                    // The smallest key after the last one.
+                    start = [&last.key[..], &[0]].concat();
+                }
+                for entry in entries {
+                    yield entry;
+                }
+                if done {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Run `job` in the background until it is done or the returned
+    /// [`BackfillRunner`] is dropped. See [`BackfillJob`].
+    pub fn backfill(&self, job: BackfillJob) -> BackfillRunner {
+        BackfillRunner::new(self.clone(), job)
+    }
+
+    /// Stand for election as the leader for the role `name` among the
+    /// instances sharing the tables, such as to run a periodic job on one of
+    /// them only. See [`Leadership`]. The candidacy keeps a connection of its
+    /// own to [`PostgresConfig::url`], made without TLS, and lasts until the
+    /// returned [`Leadership`] is dropped. Background maintenance is elected
+    /// under the name `maintenance`.
+    pub fn elect_leader(&self, name: &str) -> PostgresResult<Leadership> {
+        let config = parse_url(&self.backend.config.url)?;
+        Ok(Leadership::new(config, format!("{}:{name}", self.backend.channel)))
+    }
+
+    /// The progress of the backfill job `name` as of its last checkpoint, or
+    /// `None` if no instance has started it.
+    pub async fn backfill_progress(&self, name: &str) -> PostgresResult<Option<BackfillProgress>> {
+        self.backend.backfill_progress(name).await
+    }
+
+    /// Campaign for the lease of the role `name` among the instances sharing
+    /// the tables, and write through the returned [`FencedWriter`] only while
+    /// holding it, so that a deposed leader can not overwrite the work of the
+    /// next one. See [`FencedWriter`].
+    pub fn fenced_writer(&self, name: &str) -> FencedWriter {
+        FencedWriter::new(self.clone(), name)
+    }
+
+    /// [`Database::atomic_write`], calling `progress` as mutations are
+    /// applied and once the write has committed. Nothing is committed until
+    /// the end, so the progress of a write that fails is lost.
+    pub async fn atomic_write_with_progress(
         &self,
         write: AtomicWrite,
+        progress: &ProgressCallback<'_>,
     ) -> Result<Option<CommitResult>, JsErrorBox> {
-        // Collect mutated keys before the write consumes them
-        let mutated_keys: Vec<Vec<u8>> = write.mutations.iter()
-            .map(|m| m.key.clone())
-            .collect();
+        self.atomic_write_in(write, None, Some(progress), self.backend.config.durability, 0).await
+    }
 
-        let mut conn = self.get_connection().await
-            .map_err(JsErrorBox::from_err)?;
+    /// [`Database::atomic_write`], acknowledged with `durability` rather
+    /// than the configured [`PostgresConfig::durability`], such as to write
+    /// disposable data without waiting for the disk.
+    pub async fn atomic_write_with_durability(
+        &self,
+        write: AtomicWrite,
+        durability: Durability,
+    ) -> Result<Option<CommitResult>, JsErrorBox> {
+        self.atomic_write_in(write, None, None, durability, 0).await
+    }
 
-        let result = self.backend.atomic_write(&mut conn, write).await
-            .map_err(JsErrorBox::from_err)?;
+    /// [`Database::atomic_write`], enqueueing its messages with `priority`.
+    /// Of the messages that are due, those with the highest priority are
+    /// dequeued first, and of those the ones due first. Messages enqueued
+    /// otherwise have priority 0, so a negative priority puts messages
+    /// behind them. Retries keep the priority.
+    pub async fn atomic_write_with_priority(
+        &self,
+        write: AtomicWrite,
+        priority: i16,
+    ) -> Result<Option<CommitResult>, JsErrorBox> {
+        self.atomic_write_in(write, None, None, self.backend.config.durability, priority).await
+    }
+
+    /// Up to `limit` of the messages moved to the dead letter queue after
+    /// failing [`PostgresConfig::max_delivery_attempts`] times, those that
+    /// failed first first.
+    pub async fn dead_letters(&self, limit: usize) -> PostgresResult<Vec<DeadLetter>> {
+        self.backend.dead_letters(backend::sql_limit(limit)).await
+    }
+
+    /// Move the dead-lettered message `id` back into the queue, due now and
+    /// with all of its delivery attempts. Returns false if there is no such
+    /// message.
+    pub async fn requeue_dead_letter(&self, id: &Uuid) -> PostgresResult<bool> {
+        let requeued = self.backend.requeue_dead_letter(id).await?;
+        if requeued {
+            self.notifier.notify_enqueued();
+        }
+        Ok(requeued)
+    }
+
+    /// Delete the dead-lettered message `id` for good, without writing its
+    /// `keys_if_undelivered`. Returns false if there is no such message.
+    pub async fn delete_dead_letter(&self, id: &Uuid) -> PostgresResult<bool> {
+        self.backend.delete_dead_letter(id).await
+    }
+
+    /// `atomic_write`, enqueueing messages into `namespace`.
+    #[tracing::instrument(
+        name = "atomic_write",
+        skip_all,
+        fields(
+            checks = write.checks.len(),
+            mutations = write.mutations.len(),
+            enqueues = write.enqueues.len(),
+            bytes = throttle::write_size(&write),
+            committed = Empty,
+            attempts = Empty,
+        ),
+    )]
+    pub(crate) async fn atomic_write_in(
+        &self,
+        write: AtomicWrite,
+        namespace: Option<&[u8]>,
+        progress: Option<&ProgressCallback<'_>>,
+        durability: Durability,
+        priority: i16,
+    ) -> Result<Option<CommitResult>, JsErrorBox> {
+        // Collect mutated keys before the write consumes them, noting the
+        // ones that get the versionstamp appended.
+        let mutated_keys = driver::mutated_keys(&write.mutations);
+        let enqueues = !write.enqueues.is_empty();
+        // Versionstamped keys are new, so no read could have seen them.
+        let written: Vec<(Vec<u8>, bool)> = match &self.observed {
+            Some(_) => write.mutations.iter()
+                .filter(|m| !matches!(m.kind, MutationKind::SetSuffixVersionstampedKey(_)))
+                .map(|m| (m.key.clone(), !matches!(m.kind, MutationKind::Delete)))
+                .collect(),
+            None => Vec::new(),
+        };
+
+        let started = Instant::now();
+        if let Some(throttle) = &self.throttle {
+            let ops = (write.mutations.len() + write.enqueues.len()) as u64;
+            throttle.acquire(ops, throttle::write_size(&write)).await
+                .map_err(|e| self.shed(e))?;
+        }
+
+        let mut lane = match &self.affinity {
+            Some(lanes) => match lanes.lane_of(&write) {
+                Some(index) => Some(lanes.lock(index).await),
+                None => None,
+            },
+            None => None,
+        };
+        // Only acquiring the connection is retried: once the write has been
+        // sent it may have committed even if the reply is lost.
+        let mut pooled = None;
+        let conn = match lane.as_deref_mut() {
+            Some(Some(conn)) if !conn.is_closed() => conn,
+            Some(slot) => slot.insert(self.breakers.write.run(|| self.get_connection()).await.map_err(|e| self.shed(e))?),
+            None => pooled.insert(self.breakers.write.run(|| self.get_connection()).await.map_err(|e| self.shed(e))?),
+        };
+
+        let result = self.backend.atomic_write(conn, write, namespace, progress, durability, priority)
+            .instrument(sql_span("atomic_write"))
+            .await;
+        if result.is_err() {
+            // The connection may be left in any state; the lane takes a new one.
+            if let Some(slot) = lane.as_deref_mut() {
+                *slot = None;
+            }
+        }
+        drop(lane);
+        self.breakers.write.observe(&result);
+        self.metrics.observe("atomic_write", result.is_ok(), started.elapsed());
+        let result = result.map_err(JsErrorBox::from_err)?;
+        tracing::Span::current().record("committed", result.is_some());
 
         // Notify watchers of changed keys after a successful commit
-        if result.is_some() {
-            for key in &mutated_keys {
-                self.notifier.notify_key_update(key);
+        if let Some(commit) = &result {
+            for (key, versionstamped) in mutated_keys {
+                self.notifier.notify_key_update(&driver::changed_key(&key, versionstamped, &commit.versionstamp));
+            }
+            if enqueues {
+                self.notifier.notify_enqueued();
+            }
+        }
+        if let (Some(observed), Some(commit)) = (&self.observed, &result) {
+            for (key, exists) in &written {
+                observed.observe(key, commit.versionstamp, *exists);
             }
         }
 
         Ok(result)
     }
 
-    async fn dequeue_next_message(&self) -> Result<Option<Self::QMH>, JsErrorBox> {
-        let mut conn = self.get_connection().await
-            .map_err(JsErrorBox::from_err)?;
+    /// `dequeue_next_message`, only considering messages of `namespace`.
+    #[tracing::instrument(
+        name = "dequeue_next_message",
+        skip_all,
+        fields(namespaced = namespace.is_some(), dequeued = Empty, attempts = Empty),
+    )]
+    pub(crate) async fn dequeue_next_message_in(
+        &self,
+        namespace: Option<&[u8]>,
+    ) -> Result<Option<PostgresMessageHandle>, JsErrorBox> {
+        let started = Instant::now();
+        let mut conn = self.breakers.queue.run(|| self.get_connection()).await
+            .map_err(|e| self.shed(e))?;
 
-        let message_handle = self.backend.dequeue_next_message(&mut conn).await
-            .map_err(JsErrorBox::from_err)?;
+        let message_handle = self.backend.dequeue_next_message(&mut conn, namespace)
+            .instrument(sql_span("dequeue"))
+            .await;
+        self.breakers.queue.observe(&message_handle);
+        self.metrics.observe("dequeue", message_handle.is_ok(), started.elapsed());
+        let message_handle = message_handle.map_err(JsErrorBox::from_err)?;
+        tracing::Span::current().record("dequeued", message_handle.is_some());
 
         Ok(message_handle)
     }
 
-    fn watch(&self, keys: Vec<Vec<u8>>) -> Pin<Box<dyn Stream<Item = Result<Vec<WatchKeyOutput>, JsErrorBox>> + Send>> {
-        let backend = self.backend.clone();
-        let notifier = self.notifier.clone();
+    async fn read_ranges(
+        &self,
+        pool: &Pool,
+        requests: &[ReadRange],
+    ) -> PostgresResult<Vec<ReadRangeOutput>> {
+        let conn = pressure::get_connection(pool, self.pool_wait_timeout).await?;
 
-        let stream = try_stream! {
-            // Subscribe to key changes
-            let mut subscriptions = Vec::new();
-            for key in &keys {
-                subscriptions.push(notifier.subscribe(key.clone()));
+        // A single range is read on its own; several share one round trip.
+        let results = match requests {
+            [request] => vec![self.backend.read_range(&conn, request).instrument(sql_span("read_range")).await?],
+            _ => self.backend.read_ranges(&conn, requests).instrument(sql_span("read_ranges")).await?,
+        };
+
+        let mut outputs = Vec::with_capacity(requests.len());
+        for (request, entries) in requests.iter().zip(results) {
+            if let Some(recorder) = &self.range_scans {
+                recorder.record(request, entries.len());
             }
+            outputs.push(ReadRangeOutput { entries });
+        }
 
-            loop {
-                // Get current values
-                let conn = backend.pool.get().await
-                    .map_err(|e| JsErrorBox::generic(format!("Failed to get connection: {}", e)))?;
-
-                let mut outputs = Vec::new();
-                for key in &keys {
-                    let request = ReadRange {
-                        start: key.clone(),
-                        end: key.iter().copied().chain(Some(0)).collect(),
-                        limit: std::num::NonZeroU32::new(1).unwrap(),
-                        reverse: false,
-                    };
-
-                    let entries = backend.read_range(&conn, &request).await
-                        .map_err(JsErrorBox::from_err)?;
-                    
-                    let entry = entries.into_iter().next();
-                    outputs.push(WatchKeyOutput::Changed { entry });
+        Ok(outputs)
+    }
+
+    /// Wake the watchers of `keys` in this process.
+    fn notify_keys(&self, keys: &[Vec<u8>]) {
+        for key in keys {
+            self.notifier.notify_key_update(key);
+        }
+    }
+
+    /// Get a connection from the pool
+    async fn get_connection(&self) -> PostgresResult<pool::Client> {
+        pressure::get_connection(&self.pool, self.pool_wait_timeout).await
+    }
+
+    /// Report errors that mean the database is under too much load as
+    /// [`PostgresError::Overloaded`], with a hint when to try again.
+    fn shed(&self, err: PostgresError) -> JsErrorBox {
+        let err = match err {
+            PostgresError::Backpressure => PostgresError::Overloaded {
+                pressure: Pressure::WriteQueueFull,
+                retry_after: pressure::DEFAULT_RETRY_AFTER,
+            },
+            PostgresError::CircuitOpen(class) => PostgresError::Overloaded {
+                pressure: Pressure::CircuitOpen,
+                retry_after: self.breakers.get(class).open_for()
+                    .unwrap_or(pressure::DEFAULT_RETRY_AFTER),
+            },
+            err => err,
+        };
+        if let PostgresError::Overloaded { pressure, .. } = &err {
+            self.metrics.record_shed(*pressure);
+        }
+        JsErrorBox::from_err(err)
+    }
+}
+
+fn count_entries(outputs: &[ReadRangeOutput]) -> usize {
+    outputs.iter().map(|output| output.entries.len()).sum()
+}
+
+/// Read the current values of watched keys.
+async fn read_watched_keys(backend: &PostgresBackend, keys: &[Vec<u8>]) -> Result<Vec<WatchKeyOutput>, JsErrorBox> {
+    let conn = backend.pool.get().await
+        .map_err(|e| JsErrorBox::generic(format!("Failed to get connection: {}", e)))?;
+
+    let mut outputs = Vec::new();
+    for key in keys {
+        let request = ReadRange {
+            start: key.clone(),
+            end: key.iter().copied().chain(Some(0)).collect(),
+            limit: NonZeroU32::new(1).unwrap(),
+            reverse: false,
+        };
+
+        let entries = backend.read_range(&conn, &request).instrument(sql_span("read_range")).await
+            .map_err(JsErrorBox::from_err)?;
+
+        let entry = entries.into_iter().next();
+        outputs.push(WatchKeyOutput::Changed { entry });
+    }
+    let found = outputs.iter().filter(|output| matches!(output, WatchKeyOutput::Changed { entry: Some(_) })).count();
+    tracing::Span::current().record("entries", found);
+    Ok(outputs)
+}
+
+#[async_trait]
+impl Database for Postgres {
+    type QMH = PostgresMessageHandle;
+
+    #[tracing::instrument(
+        skip_all,
+        fields(
+            ranges = requests.len(),
+            limit = requests.iter().map(|request| request.limit.get() as u64).sum::<u64>(),
+            consistency = ?options.consistency,
+            replica = Empty,
+            entries = Empty,
+            attempts = Empty,
+        ),
+    )]
+    async fn snapshot_read(
+        &self,
+        requests: Vec<ReadRange>,
+        options: SnapshotReadOptions,
+    ) -> Result<Vec<ReadRangeOutput>, JsErrorBox> {
+        if options.consistency == Consistency::Eventual {
+            if let Some((index, pool)) = self.replicas.choose() {
+                let start = Instant::now();
+                match self.read_ranges(pool, &requests).await {
+                    Ok(outputs) => {
+                        self.replicas.record_success(index, start.elapsed());
+                        match &self.observed {
+                            // The replica is behind what this process has
+                            // seen, so repeat the read on the primary.
+                            Some(observed) if observed.is_stale(&requests, &outputs) => {
+                                self.metrics.record_stale_read(index);
+                            }
+                            _ => {
+                                let span = tracing::Span::current();
+                                span.record("replica", index);
+                                span.record("entries", count_entries(&outputs));
+                                return Ok(outputs);
+                            }
+                        }
+                    }
+                    // Fall back to the primary for this read.
+                    Err(e) => {
+                        eprintln!("[denokv/postgres] read replica {index} error: {e}");
+                        self.replicas.record_failure(index);
+                    }
                 }
+            } else if self.replicas.any_lagging() {
+                self.metrics.record_lag_fallback();
+            }
+        }
+
+        let started = Instant::now();
+        let result = self.breakers.read.run(|| self.read_ranges(&self.pool, &requests)).await;
+        self.metrics.observe("snapshot_read", result.is_ok(), started.elapsed());
+        if let Ok(outputs) = &result {
+            tracing::Span::current().record("entries", count_entries(outputs));
+        }
+        if let (Some(observed), Ok(outputs)) = (&self.observed, &result) {
+            observed.observe_outputs(outputs);
+        }
+        result.map_err(|e| self.shed(e))
+    }
 
+    async fn atomic_write(
+        &self,
+        write: AtomicWrite,
+    ) -> Result<Option<CommitResult>, JsErrorBox> {
+        self.atomic_write_in(write, None, None, self.backend.config.durability, 0).await
+    }
+
+    async fn dequeue_next_message(&self) -> Result<Option<Self::QMH>, JsErrorBox> {
+        self.dequeue_next_message_in(None).await
+    }
+
+    fn watch(&self, keys: Vec<Vec<u8>>) -> Pin<Box<dyn Stream<Item = Result<Vec<WatchKeyOutput>, JsErrorBox>> + Send>> {
+        let backend = self.backend.clone();
+        let mut subscription = self.notifier.subscribe_watch(&keys, watch_lease(&self.backend.config));
+
+        let stream = try_stream! {
+            loop {
+                let span = tracing::info_span!("watch_poll", keys = keys.len(), entries = Empty);
+                let outputs = read_watched_keys(&backend, &keys).instrument(span).await?;
                 yield outputs;
 
-                // Wait for changes
-                for subscription in &mut subscriptions {
-                    subscription.wait_for_change().await;
-                }
+                subscription.wait_for_change().await.map_err(JsErrorBox::from_err)?;
             }
         };
 
@@ -225,7 +1386,8 @@ impl Database for Postgres {
     }
 
     fn close(&self) {
-        // PostgreSQL connections are managed by the pool
-        // No explicit close needed
+        // PostgreSQL connections are managed by the pool, only the
+        // background tasks need stopping.
+        self.shutdown.send_replace(true);
     }
 }
\ No newline at end of file
@@ -0,0 +1,178 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+//! Deno KV's limits on atomic writes, checked before a write touches the
+//! database so that an oversized write fails the same way it would on
+//! Deno Deploy instead of with whatever PostgreSQL makes of it.
+
+use std::fmt;
+
+use denokv_proto::limits;
+use denokv_proto::AtomicWrite;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{PostgresError, PostgresResult};
+
+/// The most an atomic write may contain. Defaults to Deno KV's limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WriteLimits {
+    /// Checks per write.
+    pub max_checks: usize,
+    /// Mutations and enqueues per write.
+    pub max_mutations: usize,
+    /// Bytes of an encoded key, written or checked.
+    pub max_key_size: usize,
+    /// Bytes of an encoded value or enqueued payload.
+    pub max_value_size: usize,
+    /// Bytes of all keys, values and payloads of a write together.
+    pub max_total_size: usize,
+}
+
+impl Default for WriteLimits {
+    fn default() -> Self {
+        Self {
+            max_checks: limits::MAX_CHECKS,
+            max_mutations: limits::MAX_MUTATIONS,
+            max_key_size: limits::MAX_WRITE_KEY_SIZE_BYTES,
+            max_value_size: limits::MAX_VALUE_SIZE_BYTES,
+            max_total_size: limits::MAX_TOTAL_MUTATION_SIZE_BYTES,
+        }
+    }
+}
+
+/// Which of the [`WriteLimits`] a write exceeded. See
+/// [`PostgresError::WriteLimitExceeded`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteLimit {
+    Checks,
+    Mutations,
+    KeySize,
+    ValueSize,
+    TotalSize,
+}
+
+impl fmt::Display for WriteLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            WriteLimit::Checks => "checks",
+            WriteLimit::Mutations => "mutations",
+            WriteLimit::KeySize => "key_size",
+            WriteLimit::ValueSize => "value_size",
+            WriteLimit::TotalSize => "total_size",
+        })
+    }
+}
+
+impl WriteLimits {
+    /// Check `write` against the limits, reporting the first one exceeded.
+    /// Sizes are counted as Deno KV counts them: keys, encoded values,
+    /// enqueued payloads and undelivered keys, plus 4 bytes per backoff
+    /// interval.
+    pub fn check(&self, write: &AtomicWrite) -> PostgresResult<()> {
+        let exceeds = |limit, actual: usize, max: usize| {
+            if actual > max {
+                Err(PostgresError::WriteLimitExceeded { limit, actual, max })
+            } else {
+                Ok(())
+            }
+        };
+        exceeds(WriteLimit::Checks, write.checks.len(), self.max_checks)?;
+        exceeds(WriteLimit::Mutations, write.mutations.len() + write.enqueues.len(), self.max_mutations)?;
+
+        let mut total = 0;
+        let key = |key: &[u8], total: &mut usize| {
+            *total += key.len();
+            exceeds(WriteLimit::KeySize, key.len(), self.max_key_size)
+        };
+        for check in &write.checks {
+            key(&check.key, &mut total)?;
+        }
+        for mutation in &write.mutations {
+            key(&mutation.key, &mut total)?;
+            if let Some(value) = mutation.kind.value() {
+                let size = denokv_proto::encode_value(value).0.len();
+                exceeds(WriteLimit::ValueSize, size, self.max_value_size)?;
+                total += size;
+            }
+        }
+        for enqueue in &write.enqueues {
+            exceeds(WriteLimit::ValueSize, enqueue.payload.len(), self.max_value_size)?;
+            total += enqueue.payload.len();
+            for undelivered in &enqueue.keys_if_undelivered {
+                key(undelivered, &mut total)?;
+            }
+            total += 4 * enqueue.backoff_schedule.as_ref().map_or(0, Vec::len);
+        }
+        exceeds(WriteLimit::TotalSize, total, self.max_total_size)
+    }
+
+    /// Add an error for every limit of 0, which would reject every write
+    /// that uses it.
+    pub(crate) fn validate(&self, errors: &mut Vec<String>) {
+        for (name, max) in [
+            ("max_checks", self.max_checks),
+            ("max_mutations", self.max_mutations),
+            ("max_key_size", self.max_key_size),
+            ("max_value_size", self.max_value_size),
+            ("max_total_size", self.max_total_size),
+        ] {
+            if max == 0 {
+                errors.push(format!("write_limits.{name} is 0, so every write using it would be rejected; leave write_limits unset to disable the limits"));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use denokv_proto::{Check, Enqueue, KvValue, Mutation, MutationKind};
+
+    use super::*;
+
+    fn set(key: &[u8], value: Vec<u8>) -> Mutation {
+        Mutation { key: key.to_vec(), kind: MutationKind::Set(KvValue::Bytes(value)), expire_at: None }
+    }
+
+    fn exceeded(limits: &WriteLimits, write: &AtomicWrite) -> Option<(WriteLimit, usize, usize)> {
+        match limits.check(write) {
+            Ok(()) => None,
+            Err(PostgresError::WriteLimitExceeded { limit, actual, max }) => Some((limit, actual, max)),
+            Err(e) => panic!("unexpected error {e}"),
+        }
+    }
+
+    #[test]
+    fn checks_counts_and_sizes() {
+        let limits = WriteLimits::default();
+        let mut write = AtomicWrite { checks: vec![], mutations: vec![set(b"a", vec![0; 10])], enqueues: vec![] };
+        assert_eq!(exceeded(&limits, &write), None);
+
+        write.checks = (0..11).map(|_| Check { key: b"a".to_vec(), versionstamp: None }).collect();
+        assert_eq!(exceeded(&limits, &write), Some((WriteLimit::Checks, 11, 10)));
+        write.checks.clear();
+
+        write.mutations = vec![set(&[1; 2049], vec![])];
+        assert_eq!(exceeded(&limits, &write), Some((WriteLimit::KeySize, 2049, 2048)));
+
+        write.mutations = vec![set(b"a", vec![0; 65537])];
+        assert_eq!(exceeded(&limits, &write), Some((WriteLimit::ValueSize, 65537, 65536)));
+
+        // Each value fits, together they do not.
+        write.mutations = (0..14u8).map(|i| set(&[i], vec![0; 60_000])).collect();
+        assert!(matches!(exceeded(&limits, &write), Some((WriteLimit::TotalSize, _, 819200))));
+    }
+
+    #[test]
+    fn counts_enqueues_as_mutations() {
+        let limits = WriteLimits { max_mutations: 2, ..Default::default() };
+        let enqueue = Enqueue {
+            payload: vec![0; 8],
+            deadline: denokv_proto::time::utc_now(),
+            keys_if_undelivered: vec![b"undelivered".to_vec()],
+            backoff_schedule: Some(vec![100, 1000]),
+        };
+        let write = AtomicWrite { checks: vec![], mutations: vec![set(b"a", vec![])], enqueues: vec![enqueue.clone(), enqueue] };
+        assert_eq!(exceeded(&limits, &write), Some((WriteLimit::Mutations, 3, 2)));
+    }
+}
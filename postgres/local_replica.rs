@@ -0,0 +1,214 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use deno_error::JsErrorBox;
+use denokv_proto::{
+    AtomicWrite, CommitResult, Consistency, Database, ReadRange, ReadRangeOutput,
+    SnapshotReadOptions, WatchKeyOutput,
+};
+use denokv_sqlite::Sqlite;
+use futures::Stream;
+#[cfg(feature = "metrics")]
+use prometheus::proto::MetricFamily;
+
+use crate::backend::{PostgresBackend, PrefixUsage};
+use crate::error::{PostgresError, PostgresResult};
+use crate::index_advisor::IndexSuggestion;
+use crate::message_handle::PostgresMessageHandle;
+use crate::stats::Health;
+use crate::notifier::PostgresNotifier;
+use crate::Postgres;
+
+/// How many changes the replica fetches and applies per transaction.
+const REPLICA_BATCH_SIZE: i64 = 1000;
+
+/// Eventually consistent reads are only served locally if the replica has
+/// caught up with the database this recently.
+const REPLICA_MAX_STALENESS: Duration = Duration::from_secs(10);
+
+/// A [`Postgres`] database with a local SQLite replica, created by
+/// [`Postgres::local_replica`].
+///
+/// A background task follows the change feed of the whole keyspace into the
+/// SQLite database, keeping the versionstamps assigned by PostgreSQL.
+/// Eventually consistent reads and watches are served from the replica;
+/// strong reads, writes and the queue go to PostgreSQL. This gives edge
+/// nodes local reads while all data stays in one place.
+///
+/// The replica stores how far it has followed the feed, so reopening the
+/// same SQLite file resumes where it left off. A replica that is not caught
+/// up — still loading, cut off from PostgreSQL for longer than
+/// `REPLICA_MAX_STALENESS`, or offline for longer than the tombstone
+/// retention — serves nothing and all reads go to PostgreSQL. In the last
+/// case the SQLite file has to be deleted to start over.
+#[derive(Clone)]
+pub struct LocalReplica {
+    postgres: Postgres,
+    cache: Sqlite,
+    sync: Arc<ReplicaSync>,
+}
+
+struct ReplicaSync {
+    /// When the last poll that found the replica caught up started.
+    caught_up_at: Arc<Mutex<Option<Instant>>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for ReplicaSync {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl LocalReplica {
+    pub(crate) fn new(postgres: Postgres, cache: Sqlite) -> Self {
+        let caught_up_at = Arc::new(Mutex::new(None));
+        let task = tokio::spawn(follow(
+            postgres.backend.clone(),
+            postgres.notifier.clone(),
+            cache.clone(),
+            caught_up_at.clone(),
+        ));
+        Self {
+            postgres,
+            cache,
+            sync: Arc::new(ReplicaSync { caught_up_at, task }),
+        }
+    }
+
+    /// Whether reads are currently served by the replica.
+    pub fn is_caught_up(&self) -> bool {
+        self.sync
+            .caught_up_at
+            .lock()
+            .unwrap()
+            .is_some_and(|at| at.elapsed() < REPLICA_MAX_STALENESS)
+    }
+
+    /// The health of the PostgreSQL database. See [`Postgres::health`].
+    pub async fn health(&self) -> Health {
+        self.postgres.health().await
+    }
+
+    /// See [`Postgres::index_advice`].
+    pub fn index_advice(&self, min_scans: u64) -> Vec<IndexSuggestion> {
+        self.postgres.index_advice(min_scans)
+    }
+
+    /// The usage of `prefix` in the PostgreSQL database. See
+    /// [`Postgres::prefix_usage`].
+    pub async fn prefix_usage(&self, prefix: &[u8]) -> PostgresResult<PrefixUsage> {
+        self.postgres.prefix_usage(prefix).await
+    }
+
+    /// The metrics of the PostgreSQL database. See [`Postgres::metrics`].
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> Vec<MetricFamily> {
+        self.postgres.metrics()
+    }
+
+    /// See [`Postgres::set_write_rates`].
+    pub fn set_write_rates(&self, ops_per_sec: Option<f64>, bytes_per_sec: Option<f64>) -> bool {
+        self.postgres.set_write_rates(ops_per_sec, bytes_per_sec)
+    }
+}
+
+/// Apply the change feed to `cache` until the task is aborted.
+async fn follow(
+    backend: Arc<PostgresBackend>,
+    notifier: PostgresNotifier,
+    cache: Sqlite,
+    caught_up_at: Arc<Mutex<Option<Instant>>>,
+) {
+    let mut local_writes = notifier.subscribe_prefix(Vec::new());
+    let (mut after, mut after_key) = loop {
+        match cache.replication_cursor().await {
+            Ok(stored) => break stored.unwrap_or(([0; 10], Vec::new())),
+            Err(e) => {
+                log::warn!("Failed to read the local replica position: {e}");
+                tokio::time::sleep(crate::SUBSCRIPTION_POLL_INTERVAL).await;
+            }
+        }
+    };
+    loop {
+        let started = Instant::now();
+        let changes = match backend.poll_changes(&[], &after, &after_key, REPLICA_BATCH_SIZE).await {
+            Ok(changes) => changes,
+            Err(PostgresError::ChangefeedTruncated) => {
+                log::error!("The local replica fell behind the tombstone retention and stopped; delete it to start over");
+                *caught_up_at.lock().unwrap() = None;
+                return;
+            }
+            Err(e) => {
+                log::warn!("Failed to fetch changes for the local replica: {e}");
+                tokio::time::sleep(crate::SUBSCRIPTION_POLL_INTERVAL).await;
+                continue;
+            }
+        };
+
+        let caught_up = (changes.len() as i64) < REPLICA_BATCH_SIZE;
+        if let Some(last) = changes.last() {
+            let next = (last.versionstamp, last.key.clone());
+            if let Err(e) = cache.apply_changes(changes).await {
+                log::warn!("Failed to apply changes to the local replica: {e}");
+                tokio::time::sleep(crate::SUBSCRIPTION_POLL_INTERVAL).await;
+                continue;
+            }
+            (after, after_key) = next;
+        }
+        if caught_up {
+            *caught_up_at.lock().unwrap() = Some(started);
+            // Writes through other instances are only notified here while
+            // listening for changes, so poll again after a while regardless.
+            let _ = tokio::time::timeout(crate::SUBSCRIPTION_POLL_INTERVAL, local_writes.wait_for_change()).await;
+        }
+    }
+}
+
+#[async_trait]
+impl Database for LocalReplica {
+    type QMH = PostgresMessageHandle;
+
+    async fn snapshot_read(
+        &self,
+        requests: Vec<ReadRange>,
+        options: SnapshotReadOptions,
+    ) -> Result<Vec<ReadRangeOutput>, JsErrorBox> {
+        if options.consistency == Consistency::Eventual && self.is_caught_up() {
+            match self.cache.snapshot_read(requests.clone(), options.clone()).await {
+                Ok(outputs) => return Ok(outputs),
+                // Fall back to PostgreSQL for this read.
+                Err(e) => log::warn!("Local replica read failed: {e}"),
+            }
+        }
+        self.postgres.snapshot_read(requests, options).await
+    }
+
+    async fn atomic_write(
+        &self,
+        write: AtomicWrite,
+    ) -> Result<Option<CommitResult>, JsErrorBox> {
+        self.postgres.atomic_write(write).await
+    }
+
+    async fn dequeue_next_message(&self) -> Result<Option<Self::QMH>, JsErrorBox> {
+        self.postgres.dequeue_next_message().await
+    }
+
+    fn watch(&self, keys: Vec<Vec<u8>>) -> Pin<Box<dyn Stream<Item = Result<Vec<WatchKeyOutput>, JsErrorBox>> + Send>> {
+        if self.is_caught_up() {
+            self.cache.watch(keys)
+        } else {
+            self.postgres.watch(keys)
+        }
+    }
+
+    fn close(&self) {
+        self.sync.task.abort();
+        self.cache.close();
+    }
+}
@@ -1,98 +1,130 @@
 // Copyright 2023 rawkakani. All rights reserved. MIT license.
 
+use std::future::Future;
+use std::time::Duration;
+
 use async_trait::async_trait;
-use chrono::Utc;
-use deadpool_postgres::Pool;
 use deno_error::JsErrorBox;
 use denokv_proto::QueueMessageHandle;
+use tokio::task::JoinHandle;
+use tokio_postgres::Transaction;
 use uuid::Uuid;
 
+use crate::backend::{message_metadata, version_to_versionstamp};
+use crate::change_notify;
+use crate::config::queue_running_deadline;
+use crate::decode::row_checksum;
+use crate::driver;
 use crate::error::{PostgresError, PostgresResult};
+use crate::pool::Pool;
+use crate::queue_metadata::METADATA_ENCODING;
+use crate::tables::Tables;
 
 /// PostgreSQL message handle for queue operations
 pub struct PostgresMessageHandle {
     pub id: Uuid,
     pub payload: Option<Vec<u8>>,
     pub pool: Pool,
+    pub tables: Tables,
+    /// [`crate::PostgresConfig::max_delivery_attempts`]
+    pub max_delivery_attempts: Option<u32>,
+    pub(crate) keepalive: Keepalive,
+}
+
+/// Pushes back the deadline of a running message for as long as its handle
+/// is alive, so that handlers that run longer than
+/// [`crate::PostgresConfig::queue_visibility_timeout`] are not delivered
+/// again. Stops when dropped.
+pub(crate) struct Keepalive(JoinHandle<()>);
+
+impl Keepalive {
+    /// Call `extend` with a new deadline every third of `visibility_timeout`
+    /// seconds, until it reports that the message is no longer running.
+    pub fn spawn<F, Fut>(visibility_timeout: u64, mut extend: F) -> Self
+    where
+        F: FnMut(i64) -> Fut + Send + 'static,
+        Fut: Future<Output = PostgresResult<bool>> + Send,
+    {
+        let interval = Duration::from_secs(visibility_timeout) / 3;
+        Self(tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let now_ms = crate::time::utc_now().timestamp_millis();
+                match extend(queue_running_deadline(visibility_timeout, now_ms)).await {
+                    Ok(true) => {}
+                    // Finished, or released as overdue after all.
+                    Ok(false) => return,
+                    // The next attempt may still make the deadline.
+                    Err(e) => log::warn!("Failed to extend the deadline of a running message: {e}"),
+                }
+            }
+        }))
+    }
+
+    /// Stop extending the deadline.
+    pub fn stop(&self) {
+        self.0.abort();
+    }
+}
+
+impl Drop for Keepalive {
+    fn drop(&mut self) {
+        self.stop();
+    }
 }
 
 impl PostgresMessageHandle {
     /// Finish processing a message.
     ///
     /// On success: remove from queue_running and delete the message.
-    /// On failure: apply backoff schedule and requeue, or write
+    /// On failure: apply backoff schedule and requeue, move the message to
+    /// queue_dead_letter when it used up its delivery attempts, or write
     /// keys_if_undelivered when retries are exhausted (matching SQLite).
     pub async fn finish(&self, success: bool) -> PostgresResult<()> {
+        self.keepalive.stop();
         let mut conn = self.pool.get().await?;
         let tx = conn.transaction().await?;
-        let id_str = self.id.to_string();
+        let id = &self.id;
 
         if success {
             // Remove from running and delete the original message
-            tx.execute("DELETE FROM queue_running WHERE message_id = $1", &[&id_str]).await?;
-            tx.execute("DELETE FROM queue_messages WHERE id = $1", &[&id_str]).await?;
+            tx.execute(&*self.tables.sql(driver::STOP_RUNNING), &[id]).await?;
+            tx.execute(&*self.tables.sql(driver::DELETE_MESSAGE), &[id]).await?;
         } else {
             // Fetch the message metadata for requeue decisions
-            let row = tx.query_opt(
-                r#"SELECT payload, deadline, keys_if_undelivered, backoff_schedule, retry_count
-                   FROM queue_messages WHERE id = $1"#,
-                &[&id_str],
-            ).await?;
+            let row = tx.query_opt(&*self.tables.sql(driver::FAILED_MESSAGE), &[id]).await?;
 
             if let Some(row) = row {
                 let payload: Vec<u8> = row.get("payload");
-                let keys_json: String = row.get("keys_if_undelivered");
-                let backoff_json: Option<String> = row.get("backoff_schedule");
+                let metadata = message_metadata(&row)?;
                 let retry_count: i32 = row.get("retry_count");
 
-                let backoff_schedule: Vec<u64> = backoff_json
-                    .and_then(|j| serde_json::from_str(&j).ok())
-                    .unwrap_or_default();
-
                 // Remove from running table
-                tx.execute("DELETE FROM queue_running WHERE message_id = $1", &[&id_str]).await?;
-
-                if !backoff_schedule.is_empty() {
-                    // Requeue with next backoff delay
-                    let delay_ms = backoff_schedule[0] as i64;
-                    let new_deadline = crate::time::utc_now().timestamp_millis() + delay_ms;
-                    let remaining_backoff = serde_json::to_string(&backoff_schedule[1..])
-                        .unwrap_or_else(|_| "[]".to_string());
-
-                    tx.execute(
-                        r#"UPDATE queue_messages
-                           SET deadline = $1, backoff_schedule = $2, retry_count = $3
-                           WHERE id = $4"#,
-                        &[&new_deadline, &remaining_backoff, &(retry_count + 1), &id_str],
-                    ).await?;
-                } else {
-                    // No more retries — handle keys_if_undelivered, then delete
-                    let keys_if_undelivered: Vec<Vec<u8>> = serde_json::from_str(&keys_json)
-                        .unwrap_or_default();
-
-                    if !keys_if_undelivered.is_empty() {
-                        // Write a tombstone value to each key so watchers are notified
-                        for key in &keys_if_undelivered {
-                            let empty_value: Vec<u8> = Vec::new();
-                            tx.execute(
-                                r#"INSERT INTO kv_store (key, value, value_encoding, versionstamp, updated_at)
-                                   VALUES ($1, $2, 1, $3, NOW())
-                                   ON CONFLICT (key) DO UPDATE SET
-                                       value = EXCLUDED.value,
-                                       value_encoding = EXCLUDED.value_encoding,
-                                       versionstamp = EXCLUDED.versionstamp,
-                                       updated_at = NOW()"#,
-                                &[key, &empty_value, &payload.as_slice()],
-                            ).await?;
-                        }
+                tx.execute(&*self.tables.sql(driver::STOP_RUNNING), &[id]).await?;
+
+                let now_ms = crate::time::utc_now().timestamp_millis();
+                match driver::failure(&metadata.backoff_schedule, retry_count, self.max_delivery_attempts, now_ms) {
+                    driver::Failure::Retry(new_deadline, remaining_backoff) => {
+                        // Requeue with next backoff delay
+                        tx.execute(
+                            &*self.tables.sql(driver::RETRY_MESSAGE),
+                            &[&new_deadline, &metadata.retried(remaining_backoff).encode(), &METADATA_ENCODING, &(retry_count + 1), id],
+                        ).await?;
+                        change_notify::notify_queue(&tx, &self.tables.queue_channel()).await?;
+                    }
+                    driver::Failure::DeadLetter => {
+                        tx.execute(&*self.tables.sql(driver::DEAD_LETTER_MESSAGE), &[id]).await?;
+                    }
+                    driver::Failure::Undelivered => {
+                        // No more retries — deliver the payload to
+                        // keys_if_undelivered, then delete the exhausted message
+                        set_undelivered(&tx, &self.tables, &metadata.keys_if_undelivered, &payload).await?;
+                        tx.execute(&*self.tables.sql(driver::DELETE_MESSAGE), &[id]).await?;
                     }
-
-                    // Delete the exhausted message
-                    tx.execute("DELETE FROM queue_messages WHERE id = $1", &[&id_str]).await?;
                 }
             } else {
                 // Message was already removed — just clean up running entry
-                tx.execute("DELETE FROM queue_running WHERE message_id = $1", &[&id_str]).await?;
+                tx.execute(&*self.tables.sql(driver::STOP_RUNNING), &[id]).await?;
             }
         }
 
@@ -107,6 +139,26 @@ impl PostgresMessageHandle {
     }
 }
 
+/// Write `payload` to each of `keys`, the `keys_if_undelivered` of a
+/// message that permanently failed, as one write with its own versionstamp,
+/// and notify watchers once `tx` commits.
+pub(crate) async fn set_undelivered(tx: &Transaction<'_>, tables: &Tables, keys: &[Vec<u8>], payload: &[u8]) -> PostgresResult<()> {
+    if keys.is_empty() {
+        return Ok(());
+    }
+    let new_version: i64 = tx.query_one(&*tables.sql(driver::INCREMENT_VERSION), &[]).await?.get(0);
+    let versionstamp = version_to_versionstamp(new_version);
+    for key in keys {
+        tx.execute(
+            &*tables.sql(driver::SET_UNDELIVERED),
+            &[key, &payload, &versionstamp.as_slice(), &row_checksum(key, payload, 1)],
+        ).await?;
+    }
+    let notification = change_notify::encode_payload(keys.iter().map(Vec::as_slice));
+    tx.execute(driver::NOTIFY, &[&tables.channel(), &notification]).await?;
+    Ok(())
+}
+
 #[async_trait]
 impl QueueMessageHandle for PostgresMessageHandle {
     async fn finish(&self, success: bool) -> Result<(), JsErrorBox> {
@@ -0,0 +1,402 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use prometheus::core::Desc;
+use prometheus::proto::{LabelPair, MetricFamily};
+use prometheus::{GaugeVec, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry};
+
+use crate::backend::QueueDepth;
+use crate::circuit_breaker::{CircuitBreakers, CircuitState};
+use crate::pool::Pool;
+use crate::pressure::Pressure;
+use crate::stats::PoolStatus;
+
+/// Prometheus metrics of one [`crate::Postgres`] database.
+///
+/// Operation latencies are recorded as they happen, and the queue depth
+/// whenever it is counted; the pool, circuit breaker and retry metrics are
+/// read when the metrics are gathered.
+pub(crate) struct BackendMetrics {
+    registry: Registry,
+    operation_duration: HistogramVec,
+    pool_connections: IntGaugeVec,
+    pool_max_connections: IntGauge,
+    pool_waiting: IntGauge,
+    circuit_open: IntGaugeVec,
+    retries: IntCounterVec,
+    /// Held while the values read at gathering are updated, so that
+    /// concurrent gatherings do not count the same retries twice.
+    gathering: Mutex<()>,
+    queue_messages: IntGaugeVec,
+    shed: IntCounterVec,
+    stale_replica_reads: IntCounterVec,
+    replica_lag: GaugeVec,
+    replica_lag_fallbacks: IntCounter,
+    analyzed: IntCounterVec,
+    backfill_entries: IntCounterVec,
+    backfill_done: IntGaugeVec,
+    reaped_rows: IntCounter,
+    namespaces_deleted: IntCounter,
+}
+
+impl BackendMetrics {
+    pub fn new() -> Self {
+        let operation_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "denokv_postgres_operation_duration_seconds",
+                "Time taken by database operations, by operation and outcome.",
+            ),
+            &["operation", "outcome"],
+        )
+        .unwrap();
+        let pool_connections = IntGaugeVec::new(
+            Opts::new(
+                "denokv_postgres_pool_connections",
+                "Open connections of the pool, by whether they are in use.",
+            ),
+            &["state"],
+        )
+        .unwrap();
+        let pool_max_connections = IntGauge::new(
+            "denokv_postgres_pool_max_connections",
+            "Maximum number of connections the pool will open.",
+        )
+        .unwrap();
+        let pool_waiting = IntGauge::new(
+            "denokv_postgres_pool_waiting",
+            "Callers waiting for a connection from the pool.",
+        )
+        .unwrap();
+        let circuit_open = IntGaugeVec::new(
+            Opts::new(
+                "denokv_postgres_circuit_open",
+                "Whether a circuit breaker is open and failing operations fast.",
+            ),
+            &["circuit"],
+        )
+        .unwrap();
+        let retries = IntCounterVec::new(
+            Opts::new(
+                "denokv_postgres_retries_total",
+                "Failed operations that were retried, by circuit.",
+            ),
+            &["circuit"],
+        )
+        .unwrap();
+        let queue_messages = IntGaugeVec::new(
+            Opts::new(
+                "denokv_postgres_queue_messages",
+                "Queue messages as last counted, by whether they are ready, scheduled, running or dead-lettered.",
+            ),
+            &["state"],
+        )
+        .unwrap();
+        let shed = IntCounterVec::new(
+            Opts::new(
+                "denokv_postgres_shed_total",
+                "Operations shed because the database was overloaded, by pressure.",
+            ),
+            &["pressure"],
+        )
+        .unwrap();
+
+        let stale_replica_reads = IntCounterVec::new(
+            Opts::new(
+                "denokv_postgres_stale_replica_reads_total",
+                "Eventually consistent reads repeated on the primary because the replica returned older versions than this process had seen, by replica.",
+            ),
+            &["replica"],
+        )
+        .unwrap();
+
+        let replica_lag = GaugeVec::new(
+            Opts::new(
+                "denokv_postgres_replica_lag_seconds",
+                "Replication lag of a read replica as last measured, by replica.",
+            ),
+            &["replica"],
+        )
+        .unwrap();
+        let replica_lag_fallbacks = IntCounter::new(
+            "denokv_postgres_replica_lag_fallbacks_total",
+            "Eventually consistent reads served by the primary because the replicas lagged more than max_replica_lag.",
+        )
+        .unwrap();
+        let analyzed = IntCounterVec::new(
+            Opts::new(
+                "denokv_postgres_analyze_total",
+                "Times a table was analyzed by this process because many of its rows changed, by table.",
+            ),
+            &["table"],
+        )
+        .unwrap();
+
+        let backfill_entries = IntCounterVec::new(
+            Opts::new(
+                "denokv_postgres_backfill_entries_total",
+                "Entries processed by backfill jobs led by this process, by job and whether they were rewritten.",
+            ),
+            &["job", "outcome"],
+        )
+        .unwrap();
+        let backfill_done = IntGaugeVec::new(
+            Opts::new(
+                "denokv_postgres_backfill_done",
+                "Whether a backfill job run by this process has finished.",
+            ),
+            &["job"],
+        )
+        .unwrap();
+
+        let reaped_rows = IntCounter::new(
+            "denokv_postgres_reaped_rows_total",
+            "Rows of deleted namespaces deleted by the namespace reaper of this process.",
+        )
+        .unwrap();
+        let namespaces_deleted = IntCounter::new(
+            "denokv_postgres_namespace_deletions_total",
+            "Namespace deletions completed by this process.",
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(operation_duration.clone())).unwrap();
+        registry.register(Box::new(pool_connections.clone())).unwrap();
+        registry.register(Box::new(pool_max_connections.clone())).unwrap();
+        registry.register(Box::new(pool_waiting.clone())).unwrap();
+        registry.register(Box::new(circuit_open.clone())).unwrap();
+        registry.register(Box::new(retries.clone())).unwrap();
+        registry.register(Box::new(queue_messages.clone())).unwrap();
+        registry.register(Box::new(shed.clone())).unwrap();
+        registry.register(Box::new(stale_replica_reads.clone())).unwrap();
+        registry.register(Box::new(replica_lag.clone())).unwrap();
+        registry.register(Box::new(replica_lag_fallbacks.clone())).unwrap();
+        registry.register(Box::new(analyzed.clone())).unwrap();
+        registry.register(Box::new(backfill_entries.clone())).unwrap();
+        registry.register(Box::new(backfill_done.clone())).unwrap();
+        registry.register(Box::new(reaped_rows.clone())).unwrap();
+        registry.register(Box::new(namespaces_deleted.clone())).unwrap();
+        Self {
+            registry,
+            operation_duration,
+            pool_connections,
+            pool_max_connections,
+            pool_waiting,
+            circuit_open,
+            retries,
+            gathering: Mutex::new(()),
+            queue_messages,
+            shed,
+            stale_replica_reads,
+            replica_lag,
+            replica_lag_fallbacks,
+            analyzed,
+            backfill_entries,
+            backfill_done,
+            reaped_rows,
+            namespaces_deleted,
+        }
+    }
+
+    /// Record that `operation` took `elapsed` and whether it succeeded.
+    pub fn observe(&self, operation: &str, ok: bool, elapsed: Duration) {
+        let outcome = if ok { "ok" } else { "error" };
+        self.operation_duration
+            .with_label_values(&[operation, outcome])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Count an operation shed because of `pressure`.
+    pub fn record_shed(&self, pressure: Pressure) {
+        self.shed.with_label_values(&[&pressure.to_string()]).inc();
+    }
+
+    /// Count a read from replica `index` that had to be repaired.
+    pub fn record_stale_read(&self, index: usize) {
+        self.stale_replica_reads.with_label_values(&[&index.to_string()]).inc();
+    }
+
+    /// Set the replication lag of replica `index`, or remove it if it is
+    /// not known.
+    pub fn set_replica_lag(&self, index: usize, lag: Option<Duration>) {
+        let replica = index.to_string();
+        match lag {
+            Some(lag) => self.replica_lag.with_label_values(&[&replica]).set(lag.as_secs_f64()),
+            None => {
+                let _ = self.replica_lag.remove_label_values(&[&replica]);
+            }
+        }
+    }
+
+    /// Count an eventually consistent read served by the primary because
+    /// the replicas lagged.
+    pub fn record_lag_fallback(&self) {
+        self.replica_lag_fallbacks.inc();
+    }
+
+    pub fn record_analyze(&self, table: &str) {
+        self.analyzed.with_label_values(&[table]).inc();
+    }
+
+    /// Count entries of backfill `job` that were processed, `rewritten` of
+    /// them with a new value.
+    pub fn record_backfill(&self, job: &str, processed: u64, rewritten: u64) {
+        self.backfill_entries.with_label_values(&[job, "rewritten"]).inc_by(rewritten);
+        self.backfill_entries.with_label_values(&[job, "unchanged"]).inc_by(processed - rewritten);
+    }
+
+    pub fn set_backfill_done(&self, job: &str) {
+        self.backfill_done.with_label_values(&[job]).set(1);
+    }
+
+    pub fn record_reaped(&self, rows: u64) {
+        self.reaped_rows.inc_by(rows);
+    }
+
+    pub fn record_namespace_deleted(&self) {
+        self.namespaces_deleted.inc();
+    }
+
+    pub fn set_queue_depth(&self, depth: QueueDepth) {
+        for (state, count) in [
+            ("ready", depth.ready),
+            ("scheduled", depth.scheduled),
+            ("running", depth.running),
+            ("dead_letter", depth.dead_letter),
+        ] {
+            self.queue_messages.with_label_values(&[state]).set(count as i64);
+        }
+    }
+
+    pub fn gather(&self, pool: &Pool, breakers: &CircuitBreakers) -> Vec<MetricFamily> {
+        self.update(pool, breakers);
+        self.registry.gather()
+    }
+
+    /// Read the values that are only known to the pool and the circuit
+    /// breakers.
+    fn update(&self, pool: &Pool, breakers: &CircuitBreakers) {
+        let _gathering = self.gathering.lock().unwrap();
+        let status = PoolStatus::from_pool(pool);
+        self.pool_connections.with_label_values(&["in_use"]).set(status.in_use as i64);
+        self.pool_connections.with_label_values(&["available"]).set(status.available as i64);
+        self.pool_max_connections.set(status.max_size as i64);
+        self.pool_waiting.set(status.waiting as i64);
+        for (circuit, breaker) in
+            [("read", &breakers.read), ("write", &breakers.write), ("queue", &breakers.queue)]
+        {
+            let open = breaker.state() == CircuitState::Open;
+            self.circuit_open.with_label_values(&[circuit]).set(open as i64);
+            let retries = self.retries.with_label_values(&[circuit]);
+            retries.inc_by(breaker.retries().saturating_sub(retries.get()));
+        }
+    }
+
+    /// The metrics themselves, without the registry gathering them.
+    fn collectors(&self) -> [&dyn prometheus::core::Collector; 16] {
+        [
+            &self.operation_duration,
+            &self.pool_connections,
+            &self.pool_max_connections,
+            &self.pool_waiting,
+            &self.circuit_open,
+            &self.retries,
+            &self.queue_messages,
+            &self.shed,
+            &self.stale_replica_reads,
+            &self.replica_lag,
+            &self.replica_lag_fallbacks,
+            &self.analyzed,
+            &self.backfill_entries,
+            &self.backfill_done,
+            &self.reaped_rows,
+            &self.namespaces_deleted,
+        ]
+    }
+}
+
+/// The metrics of one database as a collector of an application's
+/// registry. See [`crate::Postgres::register_metrics`].
+pub(crate) struct Collector {
+    metrics: Arc<BackendMetrics>,
+    pool: Pool,
+    breakers: Arc<CircuitBreakers>,
+}
+
+impl Collector {
+    pub fn new(metrics: Arc<BackendMetrics>, pool: Pool, breakers: Arc<CircuitBreakers>) -> Self {
+        Self { metrics, pool, breakers }
+    }
+}
+
+impl prometheus::core::Collector for Collector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.metrics.collectors().into_iter().flat_map(|collector| collector.desc()).collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.metrics.update(&self.pool, &self.breakers);
+        self.metrics.collectors().into_iter().flat_map(|collector| collector.collect()).collect()
+    }
+}
+
+/// Merge the metrics of several databases into one set of families, with
+/// a `label` telling them apart.
+pub(crate) fn merge_labelled(
+    label: &str,
+    sources: impl IntoIterator<Item = (String, Vec<MetricFamily>)>,
+) -> Vec<MetricFamily> {
+    let mut merged: Vec<MetricFamily> = Vec::new();
+    for (value, families) in sources {
+        for mut family in families {
+            for metric in family.mut_metric().iter_mut() {
+                let mut pair = LabelPair::new();
+                pair.set_name(label.to_string());
+                pair.set_value(value.clone());
+                metric.mut_label().push(pair);
+            }
+            match merged.iter_mut().find(|merged| merged.get_name() == family.get_name()) {
+                Some(existing) => existing.mut_metric().extend(family.take_metric()),
+                None => merged.push(family),
+            }
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_families_by_name() {
+        let metrics = BackendMetrics::new();
+        metrics.observe("snapshot_read", true, Duration::from_millis(3));
+        let families = || metrics.registry.gather();
+
+        let merged = merge_labelled(
+            "partition",
+            [("0".to_string(), families()), ("1".to_string(), families())],
+        );
+        let durations = merged
+            .iter()
+            .find(|family| family.get_name() == "denokv_postgres_operation_duration_seconds")
+            .unwrap();
+        let partitions: Vec<_> = durations
+            .get_metric()
+            .iter()
+            .map(|metric| {
+                metric
+                    .get_label()
+                    .iter()
+                    .find(|pair| pair.get_name() == "partition")
+                    .unwrap()
+                    .get_value()
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(partitions, ["0", "1"]);
+    }
+}
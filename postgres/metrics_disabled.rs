@@ -0,0 +1,35 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+//! Stands in for the metrics when the `metrics` feature is disabled.
+
+use std::time::Duration;
+
+use crate::pressure::Pressure;
+
+pub(crate) struct BackendMetrics;
+
+impl BackendMetrics {
+    pub fn new() -> Self {
+        Self
+    }
+
+    pub fn observe(&self, _operation: &str, _ok: bool, _elapsed: Duration) {}
+
+    pub fn record_shed(&self, _pressure: Pressure) {}
+
+    pub fn record_stale_read(&self, _index: usize) {}
+
+    pub fn set_replica_lag(&self, _index: usize, _lag: Option<Duration>) {}
+
+    pub fn record_lag_fallback(&self) {}
+
+    pub fn record_analyze(&self, _table: &str) {}
+
+    pub fn record_backfill(&self, _job: &str, _processed: u64, _rewritten: u64) {}
+
+    pub fn set_backfill_done(&self, _job: &str) {}
+
+    pub fn record_reaped(&self, _rows: u64) {}
+
+    pub fn record_namespace_deleted(&self) {}
+}
@@ -0,0 +1,187 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use deno_error::JsErrorBox;
+use denokv_proto::{
+    encode_key, AtomicWrite, CommitResult, Database, Key, KeyPart, KvEntry, ReadRange,
+    ReadRangeOutput, SnapshotReadOptions, WatchKeyOutput,
+};
+use futures::{Stream, StreamExt};
+
+use crate::error::PostgresResult;
+use crate::message_handle::PostgresMessageHandle;
+use crate::partition::prefix_upper_bound;
+use crate::Postgres;
+
+/// A view of a [`Postgres`] database scoped to a unique key prefix, created
+/// by [`Postgres::ephemeral_namespace`].
+///
+/// Keys are transparently prefixed on the way in and stripped on the way
+/// out, and queue messages enqueued through the namespace are only dequeued
+/// through it. Everything the namespace stored is deleted when the last
+/// clone is dropped, or when [`EphemeralNamespace::discard`] is awaited.
+/// This lets integration tests and preview environments share one database
+/// without seeing each other's data.
+#[derive(Clone)]
+pub struct EphemeralNamespace {
+    inner: Arc<NamespaceInner>,
+}
+
+struct NamespaceInner {
+    postgres: Postgres,
+    prefix: Vec<u8>,
+    discarded: AtomicBool,
+}
+
+impl EphemeralNamespace {
+    pub(crate) fn new(postgres: Postgres) -> Self {
+        let name = format!("__ephemeral/{}", uuid::Uuid::new_v4());
+        let prefix = encode_key(&Key(vec![KeyPart::String(name)]))
+            .expect("a single string key part always encodes");
+        Self {
+            inner: Arc::new(NamespaceInner {
+                postgres,
+                prefix,
+                discarded: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// The key prefix under which the namespace stores its keys.
+    pub fn prefix(&self) -> &[u8] {
+        &self.inner.prefix
+    }
+
+    /// Wait until a message of the namespace may be due. See
+    /// [`Postgres::wait_for_message`].
+    pub async fn wait_for_message(&self, timeout: Duration) -> PostgresResult<()> {
+        self.inner.postgres.wait_for_message_in(Some(&self.inner.prefix), timeout).await
+    }
+
+    /// Delete everything the namespace stored and wait for it to finish.
+    /// Other clones of the namespace keep working but start out empty, and
+    /// what they store is deleted when the last of them is dropped.
+    pub async fn discard(self) -> PostgresResult<()> {
+        self.inner.postgres.backend.drop_namespace(&self.inner.prefix).await?;
+        // Without other clones nothing can be stored any more, so dropping
+        // this one need not delete again.
+        if Arc::strong_count(&self.inner) == 1 {
+            self.inner.discarded.store(true, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    fn scoped(&self, key: &[u8]) -> Vec<u8> {
+        [self.inner.prefix.as_slice(), key].concat()
+    }
+
+    fn unscoped(&self, mut entry: KvEntry) -> KvEntry {
+        entry.key.drain(..self.inner.prefix.len());
+        entry
+    }
+}
+
+impl Drop for NamespaceInner {
+    fn drop(&mut self) {
+        if self.discarded.load(Ordering::SeqCst) {
+            return;
+        }
+        // Dropping outside of a runtime leaves the keys behind; there is no
+        // way to run the cleanup.
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            log::warn!("Ephemeral namespace dropped outside of a Tokio runtime; its keys were not deleted");
+            return;
+        };
+        let backend = self.postgres.backend.clone();
+        let prefix = std::mem::take(&mut self.prefix);
+        runtime.spawn(async move {
+            if let Err(e) = backend.drop_namespace(&prefix).await {
+                log::warn!("Failed to delete ephemeral namespace: {e}");
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl Database for EphemeralNamespace {
+    type QMH = PostgresMessageHandle;
+
+    async fn snapshot_read(
+        &self,
+        requests: Vec<ReadRange>,
+        options: SnapshotReadOptions,
+    ) -> Result<Vec<ReadRangeOutput>, JsErrorBox> {
+        let requests = requests
+            .into_iter()
+            .map(|request| ReadRange {
+                start: self.scoped(&request.start),
+                end: if request.is_open_ended() {
+                    prefix_upper_bound(&self.inner.prefix).expect("the prefix ends in a terminator")
+                } else {
+                    self.scoped(&request.end)
+                },
+                ..request
+            })
+            .collect();
+        let outputs = self.inner.postgres.snapshot_read(requests, options).await?;
+        Ok(outputs
+            .into_iter()
+            .map(|output| ReadRangeOutput {
+                entries: output.entries.into_iter().map(|entry| self.unscoped(entry)).collect(),
+            })
+            .collect())
+    }
+
+    async fn atomic_write(
+        &self,
+        mut write: AtomicWrite,
+    ) -> Result<Option<CommitResult>, JsErrorBox> {
+        for check in &mut write.checks {
+            check.key = self.scoped(&check.key);
+        }
+        for mutation in &mut write.mutations {
+            mutation.key = self.scoped(&mutation.key);
+        }
+        for enqueue in &mut write.enqueues {
+            for key in &mut enqueue.keys_if_undelivered {
+                *key = self.scoped(key);
+            }
+        }
+        let durability = self.inner.postgres.backend.config.durability;
+        let commit = self.inner.postgres.atomic_write_in(write, Some(&self.inner.prefix), None, durability, 0).await?;
+        Ok(commit.map(|mut commit| {
+            for key in &mut commit.versionstamped_keys {
+                key.drain(..self.inner.prefix.len());
+            }
+            commit
+        }))
+    }
+
+    async fn dequeue_next_message(&self) -> Result<Option<Self::QMH>, JsErrorBox> {
+        self.inner.postgres.dequeue_next_message_in(Some(&self.inner.prefix)).await
+    }
+
+    fn watch(&self, keys: Vec<Vec<u8>>) -> Pin<Box<dyn Stream<Item = Result<Vec<WatchKeyOutput>, JsErrorBox>> + Send>> {
+        let keys = keys.iter().map(|key| self.scoped(key)).collect();
+        let namespace = self.clone();
+        let stream = self.inner.postgres.watch(keys).map(move |outputs| {
+            Ok(outputs?
+                .into_iter()
+                .map(|output| match output {
+                    WatchKeyOutput::Changed { entry } => WatchKeyOutput::Changed {
+                        entry: entry.map(|entry| namespace.unscoped(entry)),
+                    },
+                    WatchKeyOutput::Unchanged => WatchKeyOutput::Unchanged,
+                })
+                .collect())
+        });
+        Box::pin(stream)
+    }
+
+    fn close(&self) {}
+}
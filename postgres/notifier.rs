@@ -1,8 +1,14 @@
 // Copyright 2023 rawkakani. All rights reserved. MIT license.
 
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
-use tokio::sync::watch;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::futures::Notified;
+use tokio::sync::{watch, Notify};
+use tokio::time::Instant;
+
+use crate::error::{PostgresError, PostgresResult};
 
 /// PostgreSQL notifier for key change events
 #[derive(Clone, Default)]
@@ -18,40 +24,298 @@ impl PostgresNotifier {
 
 #[derive(Default)]
 struct PostgresNotifierInner {
-    key_watchers: RwLock<HashMap<Vec<u8>, watch::Sender<()>>>,
+    watchers: RwLock<WatcherNode>,
+    /// The watches subscribed under a lease, by lease id.
+    leases: Mutex<HashMap<u64, LeasedWatch>>,
+    next_lease: AtomicU64,
+    /// Wakes consumers waiting for queue messages.
+    enqueued: Notify,
+}
+
+/// The subscriptions of a watch, held for as long as the watch renews its
+/// lease.
+struct LeasedWatch {
+    duration: Duration,
+    lease: Arc<Lease>,
+    keys: Vec<Vec<u8>>,
+}
+
+struct Lease {
+    renewed_at: Mutex<Instant>,
+    /// Set once the subscriptions were released for lack of renewal.
+    released: AtomicBool,
+}
+
+/// A byte-wise trie of subscriptions. Notifying a key walks the trie along
+/// the key's bytes, so the cost depends on the key length and not on the
+/// number of subscribers.
+#[derive(Default)]
+struct WatcherNode {
+    children: HashMap<u8, WatcherNode>,
+    /// Subscribers to exactly the key ending at this node.
+    key: Option<Subscribers>,
+    /// Subscribers to every key starting with the key ending at this node.
+    prefix: Option<Subscribers>,
+}
+
+/// The channel of the subscriptions to a key or prefix. They are counted
+/// rather than taken from the channel's receivers, since a watch whose
+/// lease expired keeps its receivers until it is dropped.
+struct Subscribers {
+    sender: watch::Sender<()>,
+    count: usize,
+}
+
+impl WatcherNode {
+    fn slot(&mut self, kind: SubscriptionKind) -> &mut Option<Subscribers> {
+        match kind {
+            SubscriptionKind::Key => &mut self.key,
+            SubscriptionKind::Prefix => &mut self.prefix,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.children.is_empty() && self.key.is_none() && self.prefix.is_none()
+    }
+
+    /// Count one subscription at `path` less, dropping the sender with the
+    /// last one and pruning nodes that become empty. Returns whether this
+    /// node is now empty.
+    fn unsubscribe(&mut self, path: &[u8], kind: SubscriptionKind) -> bool {
+        match path.split_first() {
+            None => {
+                let slot = self.slot(kind);
+                if let Some(subscribers) = slot {
+                    subscribers.count -= 1;
+                    if subscribers.count == 0 {
+                        *slot = None;
+                    }
+                }
+            }
+            Some((byte, rest)) => {
+                if let Some(child) = self.children.get_mut(byte) {
+                    if child.unsubscribe(rest, kind) {
+                        self.children.remove(byte);
+                    }
+                }
+            }
+        }
+        self.is_empty()
+    }
+}
+
+#[derive(Clone, Copy)]
+enum SubscriptionKind {
+    Key,
+    Prefix,
 }
 
 impl PostgresNotifier {
     /// Subscribe to changes for a specific key
     pub fn subscribe(&self, key: Vec<u8>) -> PostgresKeySubscription {
-        let mut key_watchers = self.inner.key_watchers.write().unwrap();
-        let receiver = match key_watchers.entry(key.clone()) {
-            std::collections::hash_map::Entry::Occupied(entry) => entry.get().subscribe(),
-            std::collections::hash_map::Entry::Vacant(entry) => {
+        self.subscribe_kind(key, SubscriptionKind::Key)
+    }
+
+    /// Subscribe to changes for every key starting with `prefix`
+    pub fn subscribe_prefix(&self, prefix: Vec<u8>) -> PostgresKeySubscription {
+        self.subscribe_kind(prefix, SubscriptionKind::Prefix)
+    }
+
+    fn subscribe_kind(&self, path: Vec<u8>, kind: SubscriptionKind) -> PostgresKeySubscription {
+        let mut watchers = self.inner.watchers.write().unwrap();
+        let mut node = &mut *watchers;
+        for byte in &path {
+            node = node.children.entry(*byte).or_default();
+        }
+        let receiver = match node.slot(kind) {
+            Some(subscribers) => {
+                subscribers.count += 1;
+                subscribers.sender.subscribe()
+            }
+            slot @ None => {
                 let (sender, receiver) = watch::channel(());
-                entry.insert(sender);
+                *slot = Some(Subscribers { sender, count: 1 });
                 receiver
             }
         };
         PostgresKeySubscription {
             notifier: Arc::downgrade(&self.inner),
-            key: Some(key),
+            path: Some(path),
+            kind,
             receiver,
         }
     }
 
     /// Notify that a key has changed
     pub fn notify_key_update(&self, key: &[u8]) {
-        let key_watchers = self.inner.key_watchers.read().unwrap();
-        if let Some(sender) = key_watchers.get(key) {
-            sender.send(()).ok(); // Ignore if no receivers
+        let watchers = self.inner.watchers.read().unwrap();
+        let mut node = &*watchers;
+        for byte in key {
+            if let Some(subscribers) = &node.prefix {
+                subscribers.sender.send(()).ok(); // Ignore if no receivers
+            }
+            match node.children.get(byte) {
+                Some(child) => node = child,
+                None => return,
+            }
+        }
+        for subscribers in [&node.prefix, &node.key].into_iter().flatten() {
+            subscribers.sender.send(()).ok();
+        }
+    }
+
+    /// Notify every subscriber, for changes to keys that are not known.
+    pub fn notify_all(&self) {
+        fn wake(node: &WatcherNode) {
+            for subscribers in [&node.prefix, &node.key].into_iter().flatten() {
+                subscribers.sender.send(()).ok();
+            }
+            node.children.values().for_each(wake);
+        }
+        wake(&self.inner.watchers.read().unwrap());
+    }
+
+    /// Wake every consumer waiting for queue messages, since one was
+    /// enqueued or rescheduled.
+    pub fn notify_enqueued(&self) {
+        self.inner.enqueued.notify_waiters();
+    }
+
+    /// Completes on the next [`notify_enqueued`](Self::notify_enqueued)
+    /// once polled or [enabled](Notified::enable); only later calls count.
+    pub fn enqueued(&self) -> Notified<'_> {
+        self.inner.enqueued.notified()
+    }
+
+    /// Subscribe a watch to changes of `keys`. With a `lease`, the watch has
+    /// to renew it by waiting for changes at least that often, or
+    /// [`release_expired`](Self::release_expired) releases its
+    /// subscriptions, so that a watch its consumer stopped polling without
+    /// dropping it does not stay subscribed forever.
+    pub fn subscribe_watch(&self, keys: &[Vec<u8>], lease: Option<Duration>) -> WatchSubscription {
+        let subscriptions = keys.iter().map(|key| self.subscribe(key.clone())).collect();
+        let lease = lease.map(|duration| {
+            let id = self.inner.next_lease.fetch_add(1, Ordering::Relaxed);
+            let lease = Arc::new(Lease { renewed_at: Mutex::new(Instant::now()), released: AtomicBool::new(false) });
+            let watch = LeasedWatch { duration, lease: lease.clone(), keys: keys.to_vec() };
+            self.inner.leases.lock().unwrap().insert(id, watch);
+            (id, duration, lease)
+        });
+        WatchSubscription {
+            notifier: Arc::downgrade(&self.inner),
+            subscriptions,
+            lease,
+        }
+    }
+
+    /// Release the subscriptions of the watches whose lease expired,
+    /// returning how many watches were released. They fail the next time
+    /// they are polled.
+    pub fn release_expired(&self) -> usize {
+        let expired: Vec<LeasedWatch> = {
+            let mut leases = self.inner.leases.lock().unwrap();
+            let ids: Vec<u64> = leases
+                .iter()
+                .filter(|(_, watch)| {
+                    // Released under the lock renewals take, so that a
+                    // renewal either comes first or sees the release.
+                    let renewed_at = watch.lease.renewed_at.lock().unwrap();
+                    let expired = renewed_at.elapsed() > watch.duration;
+                    if expired {
+                        watch.lease.released.store(true, Ordering::SeqCst);
+                    }
+                    expired
+                })
+                .map(|(id, _)| *id)
+                .collect();
+            ids.iter().filter_map(|id| leases.remove(id)).collect()
+        };
+        if !expired.is_empty() {
+            let mut watchers = self.inner.watchers.write().unwrap();
+            for watch in &expired {
+                for key in &watch.keys {
+                    watchers.unsubscribe(key, SubscriptionKind::Key);
+                }
+            }
+        }
+        expired.len()
+    }
+}
+
+/// The subscriptions of one watch to a set of keys, made with
+/// [`PostgresNotifier::subscribe_watch`].
+pub struct WatchSubscription {
+    notifier: std::sync::Weak<PostgresNotifierInner>,
+    subscriptions: Vec<PostgresKeySubscription>,
+    lease: Option<(u64, Duration, Arc<Lease>)>,
+}
+
+impl WatchSubscription {
+    /// Wait for a change to any of the keys, then forget the changes to
+    /// all of them, since they are all read again. Renews the lease while
+    /// waiting, and fails once it has expired.
+    pub async fn wait_for_change(&mut self) -> PostgresResult<()> {
+        let renew_every = self.lease.as_ref().map(|(_, duration, _)| *duration / 3);
+        loop {
+            self.renew()?;
+            let change = async {
+                if self.subscriptions.is_empty() {
+                    futures::future::pending::<()>().await;
+                }
+                futures::future::select_all(
+                    self.subscriptions.iter_mut().map(|subscription| Box::pin(subscription.wait_for_change())),
+                ).await;
+            };
+            match renew_every {
+                Some(interval) => {
+                    if tokio::time::timeout(interval, change).await.is_ok() {
+                        break;
+                    }
+                }
+                None => {
+                    change.await;
+                    break;
+                }
+            }
+        }
+        self.renew()?;
+        for subscription in &mut self.subscriptions {
+            subscription.mark_seen();
+        }
+        Ok(())
+    }
+
+    fn renew(&self) -> PostgresResult<()> {
+        let Some((_, _, lease)) = &self.lease else {
+            return Ok(());
+        };
+        let mut renewed_at = lease.renewed_at.lock().unwrap();
+        if lease.released.load(Ordering::SeqCst) {
+            return Err(PostgresError::WatchLeaseExpired);
+        }
+        *renewed_at = Instant::now();
+        Ok(())
+    }
+}
+
+impl Drop for WatchSubscription {
+    fn drop(&mut self) {
+        let (Some((id, _, _)), Some(notifier)) = (&self.lease, self.notifier.upgrade()) else {
+            return;
+        };
+        if notifier.leases.lock().unwrap().remove(id).is_none() {
+            // Released when the lease expired, which unsubscribed the keys.
+            for subscription in &mut self.subscriptions {
+                subscription.path = None;
+            }
         }
     }
 }
 
 pub struct PostgresKeySubscription {
     notifier: std::sync::Weak<PostgresNotifierInner>,
-    key: Option<Vec<u8>>,
+    path: Option<Vec<u8>>,
+    kind: SubscriptionKind,
     receiver: watch::Receiver<()>,
 }
 
@@ -60,23 +324,126 @@ impl PostgresKeySubscription {
     pub async fn wait_for_change(&mut self) {
         let _ = self.receiver.changed().await;
     }
+
+    /// Forget changes made so far, so that only later ones are waited for.
+    pub fn mark_seen(&mut self) {
+        self.receiver.borrow_and_update();
+    }
 }
 
 impl Drop for PostgresKeySubscription {
     fn drop(&mut self) {
-        if let Some(notifier) = self.notifier.upgrade() {
-            let key = self.key.take().unwrap();
-            let mut key_watchers = notifier.key_watchers.write().unwrap();
-            match key_watchers.entry(key) {
-                std::collections::hash_map::Entry::Occupied(entry) => {
-                    // If there is only one subscriber left (this struct), then remove
-                    // the entry from the map.
-                    if entry.get().receiver_count() == 1 {
-                        entry.remove();
-                    }
-                }
-                std::collections::hash_map::Entry::Vacant(_) => unreachable!("the entry should still exist"),
-            }
+        if let (Some(notifier), Some(path)) = (self.notifier.upgrade(), self.path.take()) {
+            let mut watchers = notifier.watchers.write().unwrap();
+            watchers.unsubscribe(&path, self.kind);
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    async fn changed(subscription: &mut PostgresKeySubscription) -> bool {
+        tokio::time::timeout(Duration::from_millis(50), subscription.wait_for_change())
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn prefix_and_key_subscriptions() {
+        let notifier = PostgresNotifier::new();
+        let mut users = notifier.subscribe_prefix(b"users/".to_vec());
+        let mut everything = notifier.subscribe_prefix(Vec::new());
+        let mut alice = notifier.subscribe(b"users/alice".to_vec());
+
+        notifier.notify_key_update(b"users/bob");
+        assert!(changed(&mut users).await);
+        assert!(changed(&mut everything).await);
+        assert!(!changed(&mut alice).await);
+
+        notifier.notify_key_update(b"users/alice");
+        assert!(changed(&mut alice).await);
+        assert!(changed(&mut users).await);
+        assert!(changed(&mut everything).await);
+
+        notifier.notify_key_update(b"users");
+        assert!(!changed(&mut users).await);
+        assert!(changed(&mut everything).await);
+    }
+
+    #[tokio::test]
+    async fn notify_all_wakes_every_subscription() {
+        let notifier = PostgresNotifier::new();
+        let mut users = notifier.subscribe_prefix(b"users/".to_vec());
+        let mut alice = notifier.subscribe(b"users/alice".to_vec());
+        notifier.notify_all();
+        assert!(changed(&mut users).await);
+        assert!(changed(&mut alice).await);
+    }
+
+    #[tokio::test]
+    async fn notify_enqueued_wakes_waiting_consumers() {
+        let notifier = PostgresNotifier::new();
+        notifier.notify_enqueued();
+        let mut enqueued = std::pin::pin!(notifier.enqueued());
+        enqueued.as_mut().enable();
+        assert!(tokio::time::timeout(Duration::from_millis(50), enqueued.as_mut()).await.is_err());
+        notifier.notify_enqueued();
+        assert!(tokio::time::timeout(Duration::from_millis(50), enqueued).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn mark_seen_forgets_earlier_changes() {
+        let notifier = PostgresNotifier::new();
+        let mut alice = notifier.subscribe(b"users/alice".to_vec());
+        notifier.notify_key_update(b"users/alice");
+        alice.mark_seen();
+        assert!(!changed(&mut alice).await);
+        notifier.notify_key_update(b"users/alice");
+        assert!(changed(&mut alice).await);
+    }
+
+    #[test]
+    fn dropping_last_subscription_prunes_trie() {
+        let notifier = PostgresNotifier::new();
+        let a = notifier.subscribe(b"abc".to_vec());
+        let b = notifier.subscribe(b"abc".to_vec());
+        let c = notifier.subscribe_prefix(b"ab".to_vec());
+        drop(a);
+        drop(c);
+        assert!(!notifier.inner.watchers.read().unwrap().is_empty());
+        drop(b);
+        assert!(notifier.inner.watchers.read().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_watch_leases_are_released() {
+        let notifier = PostgresNotifier::new();
+        let lease = Some(Duration::from_secs(60));
+        let mut abandoned = notifier.subscribe_watch(&[b"abc".to_vec(), b"abd".to_vec()], lease);
+        let mut polled = notifier.subscribe_watch(&[b"abc".to_vec()], lease);
+
+        // Waiting renews the lease a few times per lease.
+        let waiting = tokio::time::timeout(Duration::from_secs(150), polled.wait_for_change());
+        let sweeps = async {
+            tokio::time::sleep(Duration::from_secs(120)).await;
+            notifier.release_expired()
+        };
+        let (waited, released) = tokio::join!(waiting, sweeps);
+        assert!(waited.is_err(), "nothing changed");
+        assert_eq!(released, 1);
+
+        // The abandoned watch fails once polled, the other one still sees
+        // changes.
+        notifier.notify_key_update(b"abc");
+        assert!(matches!(abandoned.wait_for_change().await, Err(PostgresError::WatchLeaseExpired)));
+        assert!(polled.wait_for_change().await.is_ok());
+
+        drop(abandoned);
+        drop(polled);
+        assert!(notifier.inner.watchers.read().unwrap().is_empty());
+        assert!(notifier.inner.leases.lock().unwrap().is_empty());
+    }
+}
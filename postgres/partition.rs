@@ -0,0 +1,320 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use async_stream::try_stream;
+use async_trait::async_trait;
+use deno_error::JsErrorBox;
+use denokv_proto::{
+    AtomicWrite, CommitResult, Database, ReadRange, ReadRangeOutput, SnapshotReadOptions,
+    WatchKeyOutput,
+};
+use futures::{Stream, StreamExt};
+#[cfg(feature = "metrics")]
+use prometheus::proto::MetricFamily;
+
+use crate::backend::PrefixUsage;
+use crate::config::PostgresConfig;
+use crate::error::PostgresResult;
+use crate::index_advisor::IndexSuggestion;
+use crate::message_handle::PostgresMessageHandle;
+#[cfg(feature = "metrics")]
+use crate::metrics::merge_labelled;
+use crate::stats::Health;
+use crate::Postgres;
+
+/// Routes keys to different PostgreSQL clusters by key prefix.
+///
+/// Keys that start with the prefix of a [`PartitionRule`] live in that
+/// rule's cluster (the longest matching prefix wins), and everything else
+/// lives in the cluster of [`PostgresConfig::url`]. Reads that span several
+/// partitions are fanned out and merged in key order. An atomic write must
+/// stay within one partition, because atomicity can not be guaranteed
+/// across clusters.
+///
+/// [`PartitionRule`]: crate::PartitionRule
+#[derive(Clone)]
+pub struct PartitionedPostgres {
+    inner: Arc<PartitionedInner>,
+}
+
+struct PartitionedInner {
+    /// The default partition first, then one per rule.
+    nodes: Vec<Postgres>,
+    /// Key prefix and index into `nodes`, longest prefix first.
+    rules: Vec<(Vec<u8>, usize)>,
+    /// Where the next dequeue starts looking, so that no partition's queue
+    /// is starved.
+    next_dequeue: AtomicUsize,
+}
+
+impl PartitionedPostgres {
+    /// Open the default cluster and one cluster per partition rule. Every
+    /// partition uses the settings of `config` apart from its URL.
+    pub async fn new(config: PostgresConfig) -> PostgresResult<Self> {
+        // The nodes validate their own settings, but not the partition rules.
+        config.validate()?;
+        let mut rules = Vec::new();
+        let mut nodes = Vec::new();
+        for rule in &config.partitions {
+            let mut partition_config = config.clone();
+            partition_config.url = rule.url.clone();
+            partition_config.partitions = Vec::new();
+            partition_config.read_replica_urls = Vec::new();
+            rules.push((rule.prefix.clone(), nodes.len() + 1));
+            nodes.push(Postgres::new(partition_config).await?);
+        }
+        let mut default_config = config;
+        default_config.partitions = Vec::new();
+        nodes.insert(0, Postgres::new(default_config).await?);
+        rules.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+
+        Ok(Self {
+            inner: Arc::new(PartitionedInner {
+                nodes,
+                rules,
+                next_dequeue: AtomicUsize::new(0),
+            }),
+        })
+    }
+
+    /// The cluster holding keys that match no partition rule.
+    pub fn default_partition(&self) -> &Postgres {
+        &self.inner.nodes[0]
+    }
+
+    /// Change the write rate limits of every partition. See
+    /// [`Postgres::set_write_rates`].
+    pub fn set_write_rates(&self, ops_per_sec: Option<f64>, bytes_per_sec: Option<f64>) -> bool {
+        let mut throttled = true;
+        for node in &self.inner.nodes {
+            throttled &= node.set_write_rates(ops_per_sec, bytes_per_sec);
+        }
+        throttled
+    }
+
+    /// The health of every partition, the default partition first. See
+    /// [`Postgres::health`].
+    pub async fn health(&self) -> Vec<Health> {
+        futures::future::join_all(self.inner.nodes.iter().map(Postgres::health)).await
+    }
+
+    /// The usage of `prefix` summed over the partitions. See
+    /// [`Postgres::prefix_usage`].
+    pub async fn prefix_usage(&self, prefix: &[u8]) -> PostgresResult<PrefixUsage> {
+        let usages = futures::future::try_join_all(self.inner.nodes.iter().map(|node| node.prefix_usage(prefix))).await?;
+        Ok(usages.into_iter().fold(PrefixUsage::default(), |total, usage| PrefixUsage {
+            keys: total.keys + usage.keys,
+            bytes: total.bytes + usage.bytes,
+        }))
+    }
+
+    /// The index advice of every partition, the default partition first.
+    /// See [`Postgres::index_advice`].
+    pub fn index_advice(&self, min_scans: u64) -> Vec<Vec<IndexSuggestion>> {
+        self.inner.nodes.iter().map(|node| node.index_advice(min_scans)).collect()
+    }
+
+    /// The metrics of every partition, labelled with the index of the
+    /// partition, 0 being the default partition. See [`Postgres::metrics`].
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> Vec<MetricFamily> {
+        let partitions = self.inner.nodes.iter().enumerate();
+        merge_labelled(
+            "partition",
+            partitions.map(|(index, node)| (index.to_string(), node.metrics())),
+        )
+    }
+
+    fn partition_index(&self, key: &[u8]) -> usize {
+        self.inner
+            .rules
+            .iter()
+            .find(|(prefix, _)| key.starts_with(prefix))
+            .map_or(0, |(_, index)| *index)
+    }
+
+    /// Indices of the partitions that may hold keys in `request`.
+    fn partitions_for_range(&self, request: &ReadRange) -> Vec<usize> {
+        let end = (!request.is_open_ended()).then_some(request.end.as_slice());
+        let mut indices = Vec::new();
+        let mut contained = false;
+        for (prefix, index) in &self.inner.rules {
+            let upper = prefix_upper_bound(prefix);
+            let starts_before_end = end.is_none_or(|end| prefix.as_slice() < end);
+            let ends_after_start = upper
+                .as_deref()
+                .is_none_or(|upper| request.start.as_slice() < upper);
+            if starts_before_end && ends_after_start {
+                indices.push(*index);
+                let starts_inside = request.start.starts_with(prefix);
+                let ends_inside = match (end, upper.as_deref()) {
+                    (Some(end), Some(upper)) => end <= upper,
+                    (_, None) => true,
+                    (None, Some(_)) => false,
+                };
+                contained |= starts_inside && ends_inside;
+            }
+        }
+        if !contained {
+            indices.push(0);
+        }
+        indices
+    }
+}
+
+/// The smallest key greater than every key starting with `prefix`, or
+/// `None` if there is none.
+pub(crate) fn prefix_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut upper = prefix.to_vec();
+    while let Some(last) = upper.pop() {
+        if last < 0xff {
+            upper.push(last + 1);
+            return Some(upper);
+        }
+    }
+    None
+}
+
+#[async_trait]
+impl Database for PartitionedPostgres {
+    type QMH = PostgresMessageHandle;
+
+    async fn snapshot_read(
+        &self,
+        requests: Vec<ReadRange>,
+        options: SnapshotReadOptions,
+    ) -> Result<Vec<ReadRangeOutput>, JsErrorBox> {
+        let mut outputs = Vec::with_capacity(requests.len());
+        for request in requests {
+            let indices = self.partitions_for_range(&request);
+            let reads = indices.iter().map(|index| {
+                self.inner.nodes[*index].snapshot_read(vec![request.clone()], options.clone())
+            });
+            let mut entries = Vec::new();
+            for output in futures::future::try_join_all(reads).await? {
+                entries.extend(output.into_iter().flat_map(|output| output.entries));
+            }
+            if indices.len() > 1 {
+                entries.sort_by(|a, b| a.key.cmp(&b.key));
+                if request.reverse {
+                    entries.reverse();
+                }
+                entries.truncate(request.limit.get() as usize);
+            }
+            outputs.push(ReadRangeOutput { entries });
+        }
+        Ok(outputs)
+    }
+
+    async fn atomic_write(
+        &self,
+        write: AtomicWrite,
+    ) -> Result<Option<CommitResult>, JsErrorBox> {
+        let keys = write
+            .checks
+            .iter()
+            .map(|check| &check.key)
+            .chain(write.mutations.iter().map(|mutation| &mutation.key))
+            .chain(write.enqueues.iter().flat_map(|enqueue| &enqueue.keys_if_undelivered));
+        let mut partition = None;
+        for key in keys {
+            let index = self.partition_index(key);
+            if partition.is_some_and(|p| p != index) {
+                return Err(JsErrorBox::type_error(
+                    "Atomic write spans multiple partitions",
+                ));
+            }
+            partition = Some(index);
+        }
+        self.inner.nodes[partition.unwrap_or(0)].atomic_write(write).await
+    }
+
+    async fn dequeue_next_message(&self) -> Result<Option<Self::QMH>, JsErrorBox> {
+        let count = self.inner.nodes.len();
+        let start = self.inner.next_dequeue.fetch_add(1, Ordering::Relaxed);
+        for offset in 0..count {
+            let node = &self.inner.nodes[(start + offset) % count];
+            if let Some(message) = node.dequeue_next_message().await? {
+                return Ok(Some(message));
+            }
+        }
+        Ok(None)
+    }
+
+    fn watch(&self, keys: Vec<Vec<u8>>) -> Pin<Box<dyn Stream<Item = Result<Vec<WatchKeyOutput>, JsErrorBox>> + Send>> {
+        // Group the keys by partition, remembering where each one goes in
+        // the combined output.
+        let mut groups: Vec<(usize, Vec<usize>, Vec<Vec<u8>>)> = Vec::new();
+        for (position, key) in keys.iter().enumerate() {
+            let index = self.partition_index(key);
+            match groups.iter_mut().find(|(i, _, _)| *i == index) {
+                Some((_, positions, keys)) => {
+                    positions.push(position);
+                    keys.push(key.clone());
+                }
+                None => groups.push((index, vec![position], vec![key.clone()])),
+            }
+        }
+        if groups.len() <= 1 {
+            let index = groups.first().map_or(0, |(index, _, _)| *index);
+            return self.inner.nodes[index].watch(keys);
+        }
+
+        let total = keys.len();
+        let mut positions = Vec::with_capacity(groups.len());
+        let mut streams = Vec::with_capacity(groups.len());
+        for (group, (index, group_positions, group_keys)) in groups.into_iter().enumerate() {
+            positions.push(group_positions);
+            streams.push(self.inner.nodes[index].watch(group_keys).map(move |r| (group, r)));
+        }
+        let mut merged = futures::stream::select_all(streams);
+
+        let stream = try_stream! {
+            // Hold back the first delivery until every partition has
+            // reported. After that, keys in partitions that did not report
+            // are unchanged.
+            let mut pending: Vec<Option<Vec<WatchKeyOutput>>> = positions.iter().map(|_| None).collect();
+            let mut started = false;
+            while let Some((group, outputs)) = merged.next().await {
+                pending[group] = Some(outputs?);
+                if !started && pending.iter().any(Option::is_none) {
+                    continue;
+                }
+                started = true;
+
+                let mut combined: Vec<WatchKeyOutput> = (0..total).map(|_| WatchKeyOutput::Unchanged).collect();
+                for (group, outputs) in pending.iter_mut().enumerate() {
+                    if let Some(outputs) = outputs.take() {
+                        for (position, output) in positions[group].iter().zip(outputs) {
+                            combined[*position] = output;
+                        }
+                    }
+                }
+                yield combined;
+            }
+        };
+
+        Box::pin(stream)
+    }
+
+    fn close(&self) {
+        for node in &self.inner.nodes {
+            node.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upper_bound_skips_trailing_ff() {
+        assert_eq!(prefix_upper_bound(&[0x02, b'e', b'u', 0x00]), Some(vec![0x02, b'e', b'u', 0x01]));
+        assert_eq!(prefix_upper_bound(&[0x02, 0xff, 0xff]), Some(vec![0x03]));
+        assert_eq!(prefix_upper_bound(&[0xff]), None);
+    }
+}
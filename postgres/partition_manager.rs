@@ -0,0 +1,414 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+//! Keeping a partitioned `kv_store` in shape as the keyspace grows.
+//!
+//! [`TablePartitioning`](crate::TablePartitioning) lays the partitions out
+//! once, when `kv_store` is created. The partition manager carries on from
+//! there. The maintenance leader runs it every [`MANAGE_INTERVAL`], and
+//! [`Postgres::manage_partitions`](crate::Postgres::manage_partitions)
+//! runs it on demand. Each run:
+//!
+//! - Archives the range partitions no key was written to for
+//!   [`PostgresConfig::partition_archive_after`](crate::PostgresConfig::partition_archive_after)
+//!   seconds. The partition is detached and renamed `kv_store_archive_p<n>`,
+//!   and an empty partition with the same bounds takes its place, so its
+//!   keys read as if they expired and can be written again. Archived
+//!   tables stay in the database until
+//!   [`Postgres::export_archived_partition`](crate::Postgres::export_archived_partition)
+//!   copied them elsewhere, such as to an object store, and
+//!   [`Postgres::drop_archived_partition`](crate::Postgres::drop_archived_partition)
+//!   dropped them.
+//! - Splits the partitions the planner estimates to hold more than
+//!   [`PostgresConfig::partition_split_rows`](crate::PostgresConfig::partition_split_rows)
+//!   rows in two: a range partition at the middle key of its statistics, a
+//!   hash partition of modulus `m` into two of modulus `2m`. The partition
+//!   is detached while the rows of its new half move, so writes to
+//!   `kv_store` wait for the split.
+//! - Analyzes the partitions it split, so that the planner and the next
+//!   run work from their new row counts.
+//!
+//! Splits keep the bounds of the partitions disjoint and as narrow as the
+//! rows they hold, so that range reads only scan the partitions they
+//! overlap as long as `enable_partition_pruning` is on, which
+//! [`Postgres::check_server_settings`](crate::Postgres::check_server_settings)
+//! checks.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_stream::try_stream;
+use bytes::Bytes;
+use futures::{pin_mut, Stream, TryStreamExt};
+use serde::Serialize;
+
+use crate::backend::PostgresBackend;
+use crate::driver;
+use crate::error::{PostgresError, PostgresResult};
+use crate::table_partitioning::{bytea, MAX_TABLE_PARTITIONS};
+use crate::tables::Tables;
+
+/// How often the maintenance leader runs the partition manager.
+pub(crate) const MANAGE_INTERVAL: Duration = Duration::from_secs(300);
+
+/// A partition of `kv_store` the partition manager archived, kept as a
+/// table of its own until it is exported and dropped.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ArchivedPartition {
+    /// The table holding its rows, qualified with the schema if one is
+    /// configured.
+    pub table: String,
+    /// The first key of its range, `None` from the start of the keyspace.
+    pub start: Option<Vec<u8>>,
+    /// The key its range ended before, `None` through the end of the
+    /// keyspace.
+    pub end: Option<Vec<u8>>,
+}
+
+/// What a run of the partition manager changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct PartitionReport {
+    /// The partitions that were archived.
+    pub archived: Vec<ArchivedPartition>,
+    /// The partitions that were split, each with the partition created for
+    /// the half of its rows that moved.
+    pub split: Vec<(String, String)>,
+}
+
+/// The bounds of a partition of `kv_store`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Bound {
+    Hash { modulus: u32, remainder: u32 },
+    /// `None` for `MINVALUE` and `MAXVALUE`.
+    Range { from: Option<Vec<u8>>, to: Option<Vec<u8>> },
+}
+
+impl Bound {
+    /// Parse a bound as printed by `pg_get_expr`, such as
+    /// `FOR VALUES FROM (MINVALUE) TO ('\x80')`.
+    fn parse(expr: &str) -> Option<Self> {
+        let expr = expr.strip_prefix("FOR VALUES ")?;
+        if let Some(hash) = expr.strip_prefix("WITH (modulus ") {
+            let (modulus, remainder) = hash.strip_suffix(')')?.split_once(", remainder ")?;
+            return Some(Self::Hash { modulus: modulus.parse().ok()?, remainder: remainder.parse().ok()? });
+        }
+        let (from, to) = expr.strip_prefix("FROM (")?.strip_suffix(')')?.split_once(") TO (")?;
+        Some(Self::Range { from: parse_key(from)?, to: parse_key(to)? })
+    }
+
+    /// The `FOR VALUES` clause of a partition with these bounds.
+    fn sql(&self) -> String {
+        match self {
+            Self::Hash { modulus, remainder } => format!("FOR VALUES WITH (MODULUS {modulus}, REMAINDER {remainder})"),
+            Self::Range { from, to } => {
+                let from = from.as_deref().map_or("MINVALUE".to_string(), bytea);
+                let to = to.as_deref().map_or("MAXVALUE".to_string(), bytea);
+                format!("FOR VALUES FROM ({from}) TO ({to})")
+            }
+        }
+    }
+}
+
+/// Parse one end of a range bound: `MINVALUE`, `MAXVALUE` or a BYTEA
+/// literal, whose backslash is doubled when `standard_conforming_strings`
+/// is off.
+fn parse_key(literal: &str) -> Option<Option<Vec<u8>>> {
+    if literal == "MINVALUE" || literal == "MAXVALUE" {
+        return Some(None);
+    }
+    let literal = literal.strip_prefix('E').unwrap_or(literal);
+    let hex = literal.strip_prefix('\'')?.strip_suffix('\'')?.trim_start_matches('\\').strip_prefix('x')?;
+    hex::decode(hex).ok().map(Some)
+}
+
+/// A partition of `kv_store` named `kv_store_p<n>`, as the partition
+/// manager and [`TablePartitioning`](crate::TablePartitioning) name them.
+struct Partition {
+    /// Qualified with the schema if one is configured.
+    table: String,
+    /// The `n` of `kv_store_p<n>`.
+    number: u32,
+    bound: Bound,
+    /// As estimated by the planner, -1 if the partition was never analyzed.
+    rows: i64,
+}
+
+/// How the tables of the partition manager are named.
+struct Names {
+    /// `kv_store`, qualified with the schema if one is configured.
+    table: String,
+    schema: Option<String>,
+    /// `kv_store` with the table prefix, as named within its schema.
+    relname: String,
+}
+
+impl Names {
+    fn new(tables: &Tables) -> Self {
+        let table = tables.sql("kv_store").into_owned();
+        let relname = table.rsplit('.').next().unwrap_or_default().to_string();
+        Self { table, schema: tables.schema().map(str::to_string), relname }
+    }
+
+    fn qualified(&self, relname: &str) -> String {
+        match &self.schema {
+            Some(schema) => format!("{schema}.{relname}"),
+            None => relname.to_string(),
+        }
+    }
+
+    fn partition_prefix(&self) -> String {
+        format!("{}_p", self.relname)
+    }
+
+    fn partition(&self, number: u32) -> String {
+        format!("{}{number}", self.partition_prefix())
+    }
+
+    fn archive_prefix(&self) -> String {
+        format!("{}_archive_p", self.relname)
+    }
+}
+
+/// The partitions of `kv_store` named by the partition manager, and the
+/// number of the next partition it creates.
+async fn partitions(backend: &PostgresBackend, names: &Names) -> PostgresResult<(Vec<Partition>, u32)> {
+    let conn = backend.pool.get().await?;
+    let partition_prefix = names.partition_prefix();
+    let mut partitions = Vec::new();
+    let mut next = 0;
+    for row in conn.query(&*backend.tables.sql(driver::KV_STORE_PARTITIONS), &[]).await? {
+        let relname: String = row.get("relname");
+        let Some(number) = relname.strip_prefix(&partition_prefix).and_then(|n| n.parse::<u32>().ok()) else {
+            continue;
+        };
+        next = next.max(number + 1);
+        if let Some(bound) = row.get::<_, Option<String>>("bound").as_deref().and_then(Bound::parse) {
+            partitions.push(Partition { table: names.qualified(&relname), number, bound, rows: row.get("rows") });
+        }
+    }
+    // Archived partitions keep their numbers, which are not handed out again.
+    for row in conn.query(&*backend.tables.sql(driver::ARCHIVED_PARTITIONS), &[&names.archive_prefix()]).await? {
+        let relname: String = row.get("relname");
+        if let Some(number) = relname.strip_prefix(&names.archive_prefix()).and_then(|n| n.parse::<u32>().ok()) {
+            next = next.max(number + 1);
+        }
+    }
+    Ok((partitions, next))
+}
+
+/// Archive cold range partitions and split large partitions, as
+/// configured. See the [module documentation](self).
+pub(crate) async fn manage(backend: &PostgresBackend) -> PostgresResult<PartitionReport> {
+    let names = Names::new(&backend.tables);
+    let mut report = PartitionReport::default();
+    let (partitions, mut next) = partitions(backend, &names).await?;
+
+    if backend.config.partition_archive_after > 0 {
+        for partition in partitions.iter().filter(|partition| matches!(partition.bound, Bound::Range { .. })) {
+            if let Some(archived) = archive(backend, &names, partition, next).await? {
+                report.archived.push(archived);
+                next += 1;
+            }
+        }
+    }
+
+    let split_rows = i64::try_from(backend.config.partition_split_rows).unwrap_or(i64::MAX);
+    if split_rows > 0 {
+        let mut count = partitions.len();
+        for partition in partitions.iter().filter(|partition| partition.rows > split_rows) {
+            if count >= MAX_TABLE_PARTITIONS {
+                log::warn!("{} has {count} partitions, the most allowed, so no more are split", names.table);
+                break;
+            }
+            if let Some(new) = split(backend, &names, partition, next).await? {
+                report.split.push((partition.table.clone(), new));
+                next += 1;
+                count += 1;
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Archive `partition` if no key was written to it for
+/// `partition_archive_after` seconds, putting the empty partition numbered
+/// `number` in its place. Empty partitions are left alone.
+async fn archive(backend: &PostgresBackend, names: &Names, partition: &Partition, number: u32) -> PostgresResult<Option<ArchivedPartition>> {
+    let Bound::Range { from, to } = &partition.bound else {
+        return Ok(None);
+    };
+    let after = backend.config.partition_archive_after as f64;
+    let cold = format!("SELECT max(updated_at) < NOW() - make_interval(secs => $1) FROM {}", partition.table);
+    let mut conn = backend.pool.get().await?;
+    if conn.query_one(&cold, &[&after]).await?.get::<_, Option<bool>>(0) != Some(true) {
+        return Ok(None);
+    }
+
+    let tx = conn.transaction().await?;
+    tx.batch_execute(&format!("ALTER TABLE {} DETACH PARTITION {}", names.table, partition.table)).await?;
+    // Writes wait for the detach, but one may have landed since the check.
+    if tx.query_one(&cold, &[&after]).await?.get::<_, Option<bool>>(0) != Some(true) {
+        tx.rollback().await?;
+        return Ok(None);
+    }
+    let relname = format!("{}{}", names.archive_prefix(), partition.number);
+    let archived = names.qualified(&relname);
+    let bound = partition.bound.sql();
+    tx.batch_execute(&format!(
+        "ALTER TABLE {partition} RENAME TO {relname};
+         COMMENT ON TABLE {archived} IS {comment};
+         CREATE TABLE {replacement} PARTITION OF {table} {bound}",
+        partition = partition.table,
+        comment = literal(&bound),
+        replacement = names.qualified(&names.partition(number)),
+        table = names.table,
+    )).await?;
+    tx.commit().await?;
+    Ok(Some(ArchivedPartition { table: archived, start: from.clone(), end: to.clone() }))
+}
+
+/// Split `partition` in two, moving half of its rows into the partition
+/// numbered `number`. Returns the new partition, or `None` if the
+/// partition can not be split.
+async fn split(backend: &PostgresBackend, names: &Names, partition: &Partition, number: u32) -> PostgresResult<Option<String>> {
+    let mut conn = backend.pool.get().await?;
+    let (kept, moved, moves) = match &partition.bound {
+        Bound::Hash { modulus, remainder } => {
+            let Some(split_modulus) = modulus.checked_mul(2) else {
+                return Ok(None);
+            };
+            (
+                Bound::Hash { modulus: split_modulus, remainder: *remainder },
+                Bound::Hash { modulus: split_modulus, remainder: remainder + modulus },
+                format!("NOT satisfies_hash_partition('{}'::regclass, {split_modulus}, {remainder}, key)", names.table),
+            )
+        }
+        Bound::Range { from, to } => {
+            let row = conn.query_opt(driver::MEDIAN_KEY, &[&partition.table]).await?;
+            let Some(middle) = row.and_then(|row| row.get::<_, Option<Vec<u8>>>(0)) else {
+                return Ok(None);
+            };
+            // Statistics gathered before keys at the edges were deleted may
+            // point at a bound itself.
+            if from.as_ref().is_some_and(|from| middle <= *from) || to.as_ref().is_some_and(|to| middle >= *to) {
+                return Ok(None);
+            }
+            let moves = format!("key >= {}", bytea(&middle));
+            (
+                Bound::Range { from: from.clone(), to: Some(middle.clone()) },
+                Bound::Range { from: Some(middle), to: to.clone() },
+                moves,
+            )
+        }
+    };
+
+    let new = names.qualified(&names.partition(number));
+    let tx = conn.transaction().await?;
+    tx.batch_execute(&format!(
+        "ALTER TABLE {table} DETACH PARTITION {partition};
+         CREATE TABLE {new} PARTITION OF {table} {moved};
+         WITH moved AS (DELETE FROM {partition} WHERE {moves} RETURNING *) INSERT INTO {table} SELECT * FROM moved;
+         ALTER TABLE {table} ATTACH PARTITION {partition} {kept};
+         ANALYZE {partition};
+         ANALYZE {new}",
+        table = names.table,
+        partition = partition.table,
+        moved = moved.sql(),
+        kept = kept.sql(),
+    )).await?;
+    tx.commit().await?;
+    Ok(Some(new))
+}
+
+/// `text` as a string literal, whatever `standard_conforming_strings` is.
+fn literal(text: &str) -> String {
+    format!("E'{}'", text.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// The partitions archived and not dropped yet.
+pub(crate) async fn archived(backend: &PostgresBackend) -> PostgresResult<Vec<ArchivedPartition>> {
+    let names = Names::new(&backend.tables);
+    let conn = backend.pool.get().await?;
+    let rows = conn.query(&*backend.tables.sql(driver::ARCHIVED_PARTITIONS), &[&names.archive_prefix()]).await?;
+    Ok(rows
+        .iter()
+        .map(|row| {
+            let (start, end) = match row.get::<_, Option<String>>("bound").as_deref().and_then(Bound::parse) {
+                Some(Bound::Range { from, to }) => (from, to),
+                _ => (None, None),
+            };
+            ArchivedPartition { table: names.qualified(row.get("relname")), start, end }
+        })
+        .collect())
+}
+
+/// Fail unless `table` is an archived partition, so that no other table
+/// is exported or dropped.
+async fn check_archived(backend: &PostgresBackend, table: &str) -> PostgresResult<()> {
+    if archived(backend).await?.iter().any(|partition| partition.table == table) {
+        Ok(())
+    } else {
+        Err(PostgresError::InvalidData(format!("{table} is not an archived partition")))
+    }
+}
+
+/// The rows of the archived partition `table` in the binary format of
+/// `COPY`.
+pub(crate) fn export(backend: Arc<PostgresBackend>, table: String) -> impl Stream<Item = PostgresResult<Bytes>> + Send + 'static {
+    try_stream! {
+        check_archived(&backend, &table).await?;
+        let conn = backend.pool.get().await?;
+        let chunks = conn.copy_out(&*format!("COPY {table} TO STDOUT (FORMAT binary)")).await?;
+        pin_mut!(chunks);
+        while let Some(chunk) = chunks.try_next().await? {
+            yield chunk;
+        }
+    }
+}
+
+/// Drop the archived partition `table`.
+pub(crate) async fn drop_archived(backend: &PostgresBackend, table: &str) -> PostgresResult<()> {
+    check_archived(backend, table).await?;
+    let conn = backend.pool.get().await?;
+    conn.batch_execute(&format!("DROP TABLE {table}")).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bounds() {
+        assert_eq!(
+            Bound::parse("FOR VALUES WITH (modulus 4, remainder 1)"),
+            Some(Bound::Hash { modulus: 4, remainder: 1 })
+        );
+        assert_eq!(
+            Bound::parse(r"FOR VALUES FROM (MINVALUE) TO ('\x026d')"),
+            Some(Bound::Range { from: None, to: Some(b"\x02m".to_vec()) })
+        );
+        // With standard_conforming_strings off.
+        assert_eq!(
+            Bound::parse(r"FOR VALUES FROM ('\\x80') TO (MAXVALUE)"),
+            Some(Bound::Range { from: Some(vec![0x80]), to: None })
+        );
+        assert_eq!(Bound::parse("DEFAULT"), None);
+
+        for bound in [
+            Bound::Range { from: Some(vec![0x02, 0x6d]), to: Some(vec![0x80]) },
+            Bound::Range { from: None, to: None },
+        ] {
+            assert_eq!(Bound::parse(&bound.sql()), Some(bound));
+        }
+    }
+
+    #[test]
+    fn names_tables_next_to_kv_store() {
+        let config = crate::PostgresConfig { schema: Some("tenant".to_string()), table_prefix: "app_".to_string(), ..Default::default() };
+        let names = Names::new(&Tables::new(&config));
+        assert_eq!(names.table, "tenant.app_kv_store");
+        assert_eq!(names.qualified(&names.partition(3)), "tenant.app_kv_store_p3");
+        assert_eq!(names.archive_prefix(), "app_kv_store_archive_p");
+        assert_eq!(literal(r"TO (E'\\x80')"), r"E'TO (E\'\\\\x80\')'");
+    }
+}
@@ -0,0 +1,245 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+//! Connection pooling.
+//!
+//! The backend takes its connections from a [`ConnectionPool`]. deadpool is
+//! used by default; the `pool-bb8` feature switches to bb8 for applications
+//! that already depend on it, and takes precedence if both are enabled so
+//! that the features stay additive. Disable default features to leave
+//! deadpool out of the dependency tree. [`Pool`] is whichever is selected.
+
+use async_trait::async_trait;
+use tokio_postgres::Statement;
+
+use crate::error::PostgresResult;
+use crate::stats::PoolStatus;
+
+#[cfg(not(any(feature = "pool-deadpool", feature = "pool-bb8")))]
+compile_error!("denokv_postgres needs either the `pool-deadpool` or the `pool-bb8` feature");
+
+/// The connection pool used by [`crate::Postgres`].
+#[cfg(not(feature = "pool-bb8"))]
+pub type Pool = deadpool_postgres::Pool;
+
+/// The connection pool used by [`crate::Postgres`].
+#[cfg(feature = "pool-bb8")]
+pub type Pool = Bb8Pool;
+
+/// A connection checked out of [`Pool`]. It derefs to a
+/// [`tokio_postgres::Client`] and goes back to the pool when dropped.
+pub type Client = <Pool as ConnectionPool>::Connection;
+
+/// A pool of PostgreSQL connections made with tokio-postgres.
+#[async_trait]
+pub trait ConnectionPool: Clone + Send + Sync + Sized + 'static {
+    /// A checked out connection, returned to the pool when dropped.
+    type Connection: Send + Sync + 'static;
+    type Error: std::fmt::Display + Send;
+
+    /// Build a pool of at most `max_size` connections to `config`, made
+    /// without TLS. Connections are made on demand.
+    fn build(config: tokio_postgres::Config, max_size: usize) -> PostgresResult<Self>;
+
+    /// Take a connection, waiting for one to become free if the pool is at
+    /// its size limit.
+    async fn get(&self) -> Result<Self::Connection, Self::Error>;
+
+    fn status(&self) -> PoolStatus;
+
+    /// Prepare `sql` on `conn`, or reuse the statement `conn` prepared for
+    /// it before. A connection keeps at most about `capacity` statements,
+    /// and none if it is 0.
+    async fn prepare_cached(conn: &Self::Connection, sql: &str, capacity: usize) -> Result<Statement, tokio_postgres::Error>;
+}
+
+#[cfg(feature = "pool-deadpool")]
+#[async_trait]
+impl ConnectionPool for deadpool_postgres::Pool {
+    type Connection = deadpool_postgres::Client;
+    type Error = deadpool_postgres::PoolError;
+
+    fn build(config: tokio_postgres::Config, max_size: usize) -> PostgresResult<Self> {
+        let manager = deadpool_postgres::Manager::new(config, tokio_postgres::NoTls);
+        deadpool_postgres::Pool::builder(manager)
+            .max_size(max_size)
+            .build()
+            .map_err(|e| crate::PostgresError::ConnectionFailed(format!("Failed to create connection pool: {}", e)))
+    }
+
+    async fn get(&self) -> Result<Self::Connection, Self::Error> {
+        deadpool_postgres::Pool::get(self).await
+    }
+
+    fn status(&self) -> PoolStatus {
+        let status = deadpool_postgres::Pool::status(self);
+        // deadpool counts waiting callers as negative availability.
+        let available = status.available.max(0) as usize;
+        PoolStatus {
+            max_size: status.max_size,
+            size: status.size,
+            in_use: status.size.saturating_sub(available),
+            available,
+            waiting: (-status.available).max(0) as usize,
+        }
+    }
+
+    async fn prepare_cached(conn: &Self::Connection, sql: &str, capacity: usize) -> Result<Statement, tokio_postgres::Error> {
+        if capacity == 0 {
+            return conn.prepare(sql).await;
+        }
+        let statement = conn.prepare_cached(sql).await?;
+        // deadpool's cache is unbounded.
+        if conn.statement_cache.size() > capacity {
+            conn.statement_cache.clear();
+        }
+        Ok(statement)
+    }
+}
+
+#[cfg(feature = "pool-bb8")]
+pub use self::bb8_pool::{Bb8Pool, PgConnection, PgConnectionManager};
+
+#[cfg(feature = "pool-bb8")]
+mod bb8_pool {
+    use std::collections::HashMap;
+    use std::ops::{Deref, DerefMut};
+    use std::sync::Mutex;
+
+    use async_trait::async_trait;
+    use tokio_postgres::{NoTls, Statement};
+
+    use super::ConnectionPool;
+    use crate::error::{PostgresError, PostgresResult};
+    use crate::stats::PoolStatus;
+
+    /// Makes tokio-postgres connections without TLS for a bb8 pool.
+    #[derive(Debug, Clone)]
+    pub struct PgConnectionManager {
+        config: tokio_postgres::Config,
+    }
+
+    impl PgConnectionManager {
+        pub fn new(config: tokio_postgres::Config) -> Self {
+            Self { config }
+        }
+    }
+
+    /// A tokio-postgres connection, with the statements it keeps prepared
+    /// by their SQL. It derefs to a [`tokio_postgres::Client`].
+    pub struct PgConnection {
+        client: tokio_postgres::Client,
+        statements: Mutex<HashMap<String, Statement>>,
+    }
+
+    impl Deref for PgConnection {
+        type Target = tokio_postgres::Client;
+
+        fn deref(&self) -> &Self::Target {
+            &self.client
+        }
+    }
+
+    impl DerefMut for PgConnection {
+        fn deref_mut(&mut self) -> &mut Self::Target {
+            &mut self.client
+        }
+    }
+
+    impl bb8::ManageConnection for PgConnectionManager {
+        type Connection = PgConnection;
+        type Error = tokio_postgres::Error;
+
+        async fn connect(&self) -> Result<Self::Connection, Self::Error> {
+            let (client, connection) = self.config.connect(NoTls).await?;
+            tokio::spawn(async move {
+                if let Err(e) = connection.await {
+                    log::warn!("PostgreSQL connection error: {}", e);
+                }
+            });
+            Ok(PgConnection { client, statements: Mutex::default() })
+        }
+
+        async fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
+            conn.simple_query("").await.map(|_| ())
+        }
+
+        fn has_broken(&self, conn: &mut Self::Connection) -> bool {
+            conn.is_closed()
+        }
+    }
+
+    /// A bb8 pool of tokio-postgres connections. bb8 does not report its
+    /// size limit, so it is kept alongside.
+    #[derive(Clone)]
+    pub struct Bb8Pool {
+        pool: bb8::Pool<PgConnectionManager>,
+        max_size: usize,
+    }
+
+    impl Bb8Pool {
+        /// Wrap a pool built by the application, which was limited to
+        /// `max_size` connections.
+        pub fn new(pool: bb8::Pool<PgConnectionManager>, max_size: usize) -> Self {
+            Self { pool, max_size }
+        }
+
+        pub async fn get(&self) -> Result<bb8::PooledConnection<'static, PgConnectionManager>, bb8::RunError<tokio_postgres::Error>> {
+            self.pool.get_owned().await
+        }
+
+        /// Unlike deadpool, bb8 does not count waiting callers, so
+        /// `waiting` is always 0.
+        pub fn status(&self) -> PoolStatus {
+            let state = self.pool.state();
+            let size = state.connections as usize;
+            let available = state.idle_connections as usize;
+            PoolStatus {
+                max_size: self.max_size,
+                size,
+                in_use: size.saturating_sub(available),
+                available,
+                waiting: 0,
+            }
+        }
+    }
+
+    #[async_trait]
+    impl ConnectionPool for Bb8Pool {
+        type Connection = bb8::PooledConnection<'static, PgConnectionManager>;
+        type Error = bb8::RunError<tokio_postgres::Error>;
+
+        fn build(config: tokio_postgres::Config, max_size: usize) -> PostgresResult<Self> {
+            let max_size_u32 = u32::try_from(max_size)
+                .ok()
+                .filter(|&size| size > 0)
+                .ok_or_else(|| PostgresError::InvalidConfig(format!("Invalid pool size: {}", max_size)))?;
+            let pool = bb8::Pool::builder()
+                .max_size(max_size_u32)
+                .build_unchecked(PgConnectionManager::new(config));
+            Ok(Self::new(pool, max_size))
+        }
+
+        async fn get(&self) -> Result<Self::Connection, Self::Error> {
+            Bb8Pool::get(self).await
+        }
+
+        fn status(&self) -> PoolStatus {
+            Bb8Pool::status(self)
+        }
+
+        async fn prepare_cached(conn: &Self::Connection, sql: &str, capacity: usize) -> Result<Statement, tokio_postgres::Error> {
+            if let Some(statement) = conn.statements.lock().unwrap().get(sql) {
+                return Ok(statement.clone());
+            }
+            let statement = conn.prepare(sql).await?;
+            if capacity > 0 {
+                let mut statements = conn.statements.lock().unwrap();
+                if statements.len() >= capacity {
+                    statements.clear();
+                }
+                statements.insert(sql.to_string(), statement.clone());
+            }
+            Ok(statement)
+        }
+    }
+}
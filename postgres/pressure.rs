@@ -0,0 +1,129 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::error::{PostgresError, PostgresResult};
+use crate::pool::{Client, Pool};
+
+/// How long clients are asked to wait before retrying when the cause of the
+/// pressure gives no better estimate.
+pub const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// The most retries a [`RetryBudget`] saves up, which is also what it
+/// starts with so that an idle database can still retry.
+const RETRY_BUDGET_MAX_BALANCE: f64 = 10.0;
+
+/// Why an operation was shed instead of waiting. See
+/// [`PostgresError::Overloaded`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pressure {
+    /// No pooled connection became free within the pool wait timeout.
+    PoolExhausted,
+    /// Too many throttled writes are waiting already.
+    WriteQueueFull,
+    /// A circuit breaker is open after repeated connection failures.
+    CircuitOpen,
+    /// Transient failures are retried so often that further retries would
+    /// only add to the load.
+    RetryBudgetExhausted,
+}
+
+impl fmt::Display for Pressure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Pressure::PoolExhausted => "pool_exhausted",
+            Pressure::WriteQueueFull => "write_queue_full",
+            Pressure::CircuitOpen => "circuit_open",
+            Pressure::RetryBudgetExhausted => "retry_budget_exhausted",
+        })
+    }
+}
+
+/// Take a connection from `pool`, shedding the operation if none becomes
+/// free within `timeout`. A zero timeout waits as long as it takes.
+pub async fn get_connection(pool: &Pool, timeout: Duration) -> PostgresResult<Client> {
+    let get = async {
+        pool.get().await
+            .map_err(|e| PostgresError::ConnectionFailed(format!("Failed to get connection: {}", e)))
+    };
+    if timeout.is_zero() {
+        return get.await;
+    }
+    match tokio::time::timeout(timeout, get).await {
+        Ok(result) => result,
+        Err(_) => Err(PostgresError::Overloaded {
+            pressure: Pressure::PoolExhausted,
+            retry_after: DEFAULT_RETRY_AFTER,
+        }),
+    }
+}
+
+/// Caps retries at a fraction of operations, so that retrying can not
+/// multiply the load on a struggling database.
+///
+/// Every operation deposits `ratio` retries and every retry withdraws one.
+/// A ratio of 0 disables the budget.
+pub struct RetryBudget {
+    ratio: f64,
+    balance: Mutex<f64>,
+}
+
+impl RetryBudget {
+    pub fn new(ratio: f64) -> Self {
+        Self {
+            ratio,
+            balance: Mutex::new(RETRY_BUDGET_MAX_BALANCE),
+        }
+    }
+
+    pub fn deposit(&self) {
+        let mut balance = self.balance.lock().unwrap();
+        *balance = (*balance + self.ratio).min(RETRY_BUDGET_MAX_BALANCE);
+    }
+
+    /// Whether a retry may be made, taking it from the budget if so.
+    pub fn withdraw(&self) -> bool {
+        if self.ratio <= 0.0 {
+            return true;
+        }
+        let mut balance = self.balance.lock().unwrap();
+        if *balance < 1.0 {
+            return false;
+        }
+        *balance -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_budget_refills_with_operations() {
+        let budget = RetryBudget::new(0.5);
+        for _ in 0..RETRY_BUDGET_MAX_BALANCE as usize {
+            assert!(budget.withdraw());
+        }
+        assert!(!budget.withdraw());
+
+        budget.deposit();
+        assert!(!budget.withdraw());
+        budget.deposit();
+        assert!(budget.withdraw());
+        assert!(!budget.withdraw());
+    }
+
+    #[test]
+    fn zero_ratio_disables_the_budget() {
+        let budget = RetryBudget::new(0.0);
+        for _ in 0..100 {
+            assert!(budget.withdraw());
+        }
+    }
+}
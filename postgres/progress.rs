@@ -0,0 +1,125 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+use denokv_proto::Mutation;
+
+use crate::throttle::mutations_size;
+
+/// How many mutations are applied between two progress reports.
+const PROGRESS_INTERVAL: u64 = 100;
+
+/// How far a large write has got, as reported to the callback of
+/// [`Postgres::atomic_write_with_progress`] or
+/// [`Postgres::bulk_import_with_progress`].
+///
+/// [`Postgres::atomic_write_with_progress`]: crate::Postgres::atomic_write_with_progress
+/// [`Postgres::bulk_import_with_progress`]: crate::Postgres::bulk_import_with_progress
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteProgress {
+    /// Mutations in the whole write.
+    pub mutations_total: u64,
+    /// Mutations applied so far, including those not committed yet.
+    pub mutations_applied: u64,
+    /// Mutations committed so far. An atomic write commits all of them at
+    /// the end; a bulk import commits them batch by batch.
+    pub mutations_committed: u64,
+    /// Key and value bytes of the applied mutations.
+    pub bytes_written: u64,
+}
+
+/// Called with the progress of a write every hundred mutations and after
+/// every commit.
+pub type ProgressCallback<'a> = dyn Fn(WriteProgress) + Send + Sync + 'a;
+
+/// Tracks the progress of a write and reports it to a callback, if any.
+pub struct ProgressReporter<'a> {
+    callback: Option<&'a ProgressCallback<'a>>,
+    progress: WriteProgress,
+}
+
+impl<'a> ProgressReporter<'a> {
+    pub fn new(callback: Option<&'a ProgressCallback<'a>>, mutations_total: usize) -> Self {
+        Self {
+            callback,
+            progress: WriteProgress {
+                mutations_total: mutations_total as u64,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// A reporter for writes nobody follows.
+    pub fn none() -> Self {
+        Self::new(None, 0)
+    }
+
+    /// Count `mutations` as committed before this write started, as when a
+    /// bulk import resumes.
+    pub fn resume(&mut self, mutations: &[Mutation]) {
+        if self.callback.is_some() {
+            self.progress.bytes_written = mutations_size(mutations);
+        }
+        self.progress.mutations_applied = mutations.len() as u64;
+        self.progress.mutations_committed = mutations.len() as u64;
+    }
+
+    pub fn applied(&mut self, mutation: &Mutation) {
+        let Some(callback) = self.callback else {
+            return;
+        };
+        self.progress.mutations_applied += 1;
+        self.progress.bytes_written += mutations_size([mutation]);
+        if self.progress.mutations_applied.is_multiple_of(PROGRESS_INTERVAL) {
+            callback(self.progress);
+        }
+    }
+
+    /// Report that everything applied so far has been committed.
+    pub fn committed(&mut self) {
+        self.progress.mutations_committed = self.progress.mutations_applied;
+        if let Some(callback) = self.callback {
+            callback(self.progress);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use denokv_proto::{KvValue, MutationKind};
+
+    use super::*;
+
+    #[test]
+    fn reports_every_interval_and_commit() {
+        let reports = Mutex::new(Vec::new());
+        let callback = |progress| reports.lock().unwrap().push(progress);
+        let mutation = Mutation {
+            key: vec![1, 2, 3],
+            kind: MutationKind::Delete,
+            expire_at: None,
+        };
+        let mut reporter = ProgressReporter::new(Some(&callback), 250);
+        for _ in 0..250 {
+            reporter.applied(&mutation);
+        }
+        reporter.committed();
+
+        let reports = reports.into_inner().unwrap();
+        let applied: Vec<u64> = reports.iter().map(|p| p.mutations_applied).collect();
+        assert_eq!(applied, [100, 200, 250]);
+        assert_eq!(reports[1].mutations_committed, 0);
+        assert_eq!(reports[2], WriteProgress {
+            mutations_total: 250,
+            mutations_applied: 250,
+            mutations_committed: 250,
+            bytes_written: 750,
+        });
+
+        let set = Mutation { kind: MutationKind::Set(KvValue::Bytes(vec![0; 10])), ..mutation };
+        let ignore = |_: WriteProgress| {};
+        let mut reporter = ProgressReporter::new(Some(&ignore), 1);
+        reporter.resume(std::slice::from_ref(&set));
+        assert_eq!(reporter.progress.bytes_written, mutations_size([&set]));
+    }
+}
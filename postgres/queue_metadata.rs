@@ -0,0 +1,137 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+//! How the drivers store the metadata of a queue message: its
+//! `keys_if_undelivered` and what is left of its backoff schedule.
+//!
+//! The metadata lives in the `metadata` column of `queue_messages` and
+//! `queue_dead_letter`, tagged with its encoding in `metadata_encoding` as
+//! values are in `kv_store`. Changing the encoding therefore needs no table
+//! rewrite: a new encoding gets a new tag and becomes
+//! [`METADATA_ENCODING`], and rows in the older ones keep decoding.
+//! Messages enqueued before the column existed have it NULL and keep their
+//! metadata in the `keys_if_undelivered` and `backoff_schedule` array
+//! columns. Every message is upgraded to the current encoding when a failed
+//! delivery writes its remaining backoff schedule back.
+//!
+//! Releases from before the column can not process messages enqueued by
+//! later ones, so they should not share the tables with them.
+
+use denokv_proto::Enqueue;
+use serde::{Deserialize, Serialize};
+
+use crate::driver;
+use crate::error::{PostgresError, PostgresResult};
+
+/// Metadata encoding tags stored in the `metadata_encoding` column.
+pub const METADATA_ENCODING_JSON: i32 = 1;
+
+/// The encoding new metadata is written in.
+pub const METADATA_ENCODING: i32 = METADATA_ENCODING_JSON;
+
+/// The metadata of a queue message, whatever the encoding it is stored in.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueueMetadata {
+    pub keys_if_undelivered: Vec<Vec<u8>>,
+    /// The delays in milliseconds of the retries left.
+    pub backoff_schedule: Vec<i32>,
+}
+
+/// [`METADATA_ENCODING_JSON`]: a JSON object with the keys in hex.
+#[derive(Serialize, Deserialize)]
+struct JsonMetadata {
+    keys_if_undelivered: Vec<String>,
+    backoff_schedule: Vec<i32>,
+}
+
+impl QueueMetadata {
+    /// The metadata of a message as it is enqueued.
+    pub fn of(enqueue: &Enqueue) -> Self {
+        Self {
+            keys_if_undelivered: enqueue.keys_if_undelivered.clone(),
+            backoff_schedule: driver::backoff_schedule(enqueue),
+        }
+    }
+
+    /// The metadata once a retry used up the start of the backoff schedule,
+    /// leaving `remaining`.
+    pub fn retried(&self, remaining: &[i32]) -> Self {
+        Self {
+            keys_if_undelivered: self.keys_if_undelivered.clone(),
+            backoff_schedule: remaining.to_vec(),
+        }
+    }
+
+    /// The metadata in [`METADATA_ENCODING`].
+    pub fn encode(&self) -> Vec<u8> {
+        let json = JsonMetadata {
+            keys_if_undelivered: self.keys_if_undelivered.iter().map(hex::encode).collect(),
+            backoff_schedule: self.backoff_schedule.clone(),
+        };
+        serde_json::to_vec(&json).expect("queue metadata serializes")
+    }
+
+    /// Decode the metadata of a row: the `metadata` column and its encoding
+    /// tag if it was written, or else the array columns it had before.
+    pub fn decode(
+        metadata: Option<Vec<u8>>,
+        encoding: Option<i32>,
+        keys_if_undelivered: Option<Vec<Vec<u8>>>,
+        backoff_schedule: Option<Vec<i32>>,
+    ) -> PostgresResult<Self> {
+        let Some(metadata) = metadata else {
+            return Ok(Self {
+                keys_if_undelivered: keys_if_undelivered.unwrap_or_default(),
+                backoff_schedule: backoff_schedule.unwrap_or_default(),
+            });
+        };
+        match encoding {
+            Some(METADATA_ENCODING_JSON) => {
+                let json: JsonMetadata = serde_json::from_slice(&metadata)
+                    .map_err(|e| PostgresError::DeserializationError(format!("Invalid queue metadata: {e}")))?;
+                let keys_if_undelivered = json
+                    .keys_if_undelivered
+                    .iter()
+                    .map(hex::decode)
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| PostgresError::DeserializationError(format!("Invalid queue metadata: {e}")))?;
+                Ok(Self { keys_if_undelivered, backoff_schedule: json.backoff_schedule })
+            }
+            encoding => Err(PostgresError::DeserializationError(format!(
+                "Unknown queue metadata encoding {encoding:?}"
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata() -> QueueMetadata {
+        QueueMetadata {
+            keys_if_undelivered: vec![b"failed".to_vec(), vec![]],
+            backoff_schedule: vec![100, 1000],
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let encoded = metadata().encode();
+        let decoded = QueueMetadata::decode(Some(encoded), Some(METADATA_ENCODING), None, None).unwrap();
+        assert_eq!(decoded, metadata());
+        assert_eq!(decoded.retried(&[1000]).backoff_schedule, vec![1000]);
+    }
+
+    #[test]
+    fn decodes_array_columns() {
+        let decoded = QueueMetadata::decode(None, None, Some(metadata().keys_if_undelivered), Some(vec![100, 1000])).unwrap();
+        assert_eq!(decoded, metadata());
+        assert_eq!(QueueMetadata::decode(None, None, Some(vec![]), None).unwrap(), QueueMetadata::default());
+    }
+
+    #[test]
+    fn rejects_unknown_encodings() {
+        assert!(QueueMetadata::decode(Some(metadata().encode()), Some(99), None, None).is_err());
+        assert!(QueueMetadata::decode(Some(b"{".to_vec()), Some(METADATA_ENCODING_JSON), None, None).is_err());
+    }
+}
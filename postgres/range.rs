@@ -0,0 +1,113 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+use denokv_proto::ReadRange;
+
+use crate::driver;
+use crate::error::{PostgresError, PostgresResult};
+use crate::pool::Client;
+
+/// Pairs of keys whose relative order the server must agree with. Range
+/// reads and the primary key index both rely on BYTEA comparing like
+/// `memcmp`: unsigned bytes, shorter prefix first, empty sorts lowest.
+const BYTE_ORDER_SAMPLES: &[(&[u8], &[u8])] = &[
+    (b"", b"\x00"),
+    (b"\x00", b"\x01"),
+    (b"\x01", b"\x01\x00"),
+    (b"\x01\x00", b"\x02"),
+    (b"\x7f", b"\x80"),
+    (b"\x80", b"\xff"),
+    (b"\x02a\x00", b"\x02b\x00"),
+    (b"\x02B\x00", b"\x02a\x00"),
+    (b"\xfe\xff", b"\xff"),
+];
+
+/// The SQL bounds of a range read. `end` is `None` when the range extends to
+/// the end of the keyspace (see `ReadRange::is_open_ended`), in which case no
+/// upper bound may be applied: `key < ''` matches nothing.
+#[derive(Debug, PartialEq, Eq)]
+pub struct KeyBounds<'a> {
+    pub start: &'a [u8],
+    pub end: Option<&'a [u8]>,
+}
+
+impl<'a> KeyBounds<'a> {
+    pub fn from_request(request: &'a ReadRange) -> Self {
+        let end = if request.is_open_ended() {
+            None
+        } else {
+            Some(request.end.as_slice())
+        };
+        Self {
+            start: &request.start,
+            end,
+        }
+    }
+}
+
+/// Whether `request` reads at most the one key `start`, as a get does.
+pub fn is_single_key(request: &ReadRange) -> bool {
+    request.end.len() == request.start.len() + 1
+        && request.end.starts_with(&request.start)
+        && request.end.last() == Some(&0)
+}
+
+/// Assert that the server orders BYTEA values like `memcmp`. Called at
+/// startup so a misbehaving server fails loudly instead of returning wrong
+/// range results.
+pub async fn check_byte_order(conn: &Client) -> PostgresResult<()> {
+    let (lower, upper) = byte_order_samples();
+    let rows = conn.query(driver::BYTE_ORDER_CHECK, &[&lower, &upper]).await?;
+    verify_byte_order(rows.iter().map(|row| row.get(0)))
+}
+
+/// The two sides of [`BYTE_ORDER_SAMPLES`], as arrays for
+/// [`driver::BYTE_ORDER_CHECK`].
+pub fn byte_order_samples() -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+    BYTE_ORDER_SAMPLES
+        .iter()
+        .map(|(a, b)| (a.to_vec(), b.to_vec()))
+        .unzip()
+}
+
+/// Fail unless the server found every lower sample less than its upper one.
+pub fn verify_byte_order(less: impl IntoIterator<Item = bool>) -> PostgresResult<()> {
+    for ((a, b), less) in BYTE_ORDER_SAMPLES.iter().zip(less) {
+        if !less {
+            return Err(PostgresError::InvalidConfig(format!(
+                "PostgreSQL does not order BYTEA like memcmp: expected {a:?} < {b:?}"
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use super::*;
+
+    #[test]
+    fn samples_follow_memcmp_order() {
+        for (a, b) in BYTE_ORDER_SAMPLES {
+            assert!(a < b, "{a:?} < {b:?}");
+        }
+    }
+
+    #[test]
+    fn sentinel_ends_are_unbounded() {
+        let request = ReadRange {
+            start: vec![1],
+            end: vec![],
+            limit: NonZeroU32::new(1).unwrap(),
+            reverse: false,
+        };
+        assert_eq!(KeyBounds::from_request(&request), KeyBounds { start: &[1], end: None });
+
+        let request = ReadRange { end: vec![0xff, 0x01], ..request };
+        assert_eq!(KeyBounds::from_request(&request), KeyBounds { start: &[1], end: None });
+
+        let request = ReadRange { end: vec![2, 0xff], ..request };
+        assert_eq!(KeyBounds::from_request(&request), KeyBounds { start: &[1], end: Some(&[2, 0xff]) });
+    }
+}
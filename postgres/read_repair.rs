@@ -0,0 +1,144 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use denokv_proto::{ReadRange, ReadRangeOutput, Versionstamp};
+
+use crate::range::is_single_key;
+
+/// How many keys are remembered at most. Beyond this, keys observed longer
+/// ago than the window are forgotten first, and if that is not enough the
+/// new key is not remembered.
+const MAX_OBSERVED_KEYS: usize = 100_000;
+
+/// The versionstamps at which this process recently saw keys, written by
+/// its own writes or returned by reads from the primary, for detecting
+/// stale replica reads. See [`crate::PostgresConfig::read_repair_window`].
+pub struct ObservedVersions {
+    window: Duration,
+    keys: Mutex<HashMap<Vec<u8>, Observation>>,
+}
+
+struct Observation {
+    versionstamp: Versionstamp,
+    /// Whether the key existed at that version, or had been deleted.
+    exists: bool,
+    at: Instant,
+}
+
+impl ObservedVersions {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            keys: Mutex::default(),
+        }
+    }
+
+    /// Remember that `key` was at `versionstamp`, where it existed or had
+    /// been deleted, unless a newer version of it has been seen already.
+    pub fn observe(&self, key: &[u8], versionstamp: Versionstamp, exists: bool) {
+        let now = Instant::now();
+        let mut keys = self.keys.lock().unwrap();
+        if let Some(seen) = keys.get_mut(key) {
+            if versionstamp >= seen.versionstamp {
+                *seen = Observation { versionstamp, exists, at: now };
+            }
+            return;
+        }
+        if keys.len() >= MAX_OBSERVED_KEYS {
+            keys.retain(|_, seen| now.duration_since(seen.at) < self.window);
+            if keys.len() >= MAX_OBSERVED_KEYS {
+                return;
+            }
+        }
+        keys.insert(key.to_vec(), Observation { versionstamp, exists, at: now });
+    }
+
+    /// Remember the versions of every entry in `outputs`.
+    pub fn observe_outputs(&self, outputs: &[ReadRangeOutput]) {
+        for entry in outputs.iter().flat_map(|output| &output.entries) {
+            self.observe(&entry.key, entry.versionstamp, true);
+        }
+    }
+
+    /// Whether `outputs`, read from a replica for `requests`, miss a change
+    /// this process saw within the window: an entry older than the version
+    /// seen, or no entry for a single-key read of a key seen to exist.
+    pub fn is_stale(&self, requests: &[ReadRange], outputs: &[ReadRangeOutput]) -> bool {
+        let now = Instant::now();
+        let keys = self.keys.lock().unwrap();
+        let seen = |key: &[u8]| keys.get(key).filter(|seen| now.duration_since(seen.at) < self.window);
+        requests.iter().zip(outputs).any(|(request, output)| {
+            let outdated = output.entries.iter().any(|entry| {
+                seen(&entry.key).is_some_and(|seen| entry.versionstamp < seen.versionstamp)
+            });
+            let missing = output.entries.is_empty()
+                && is_single_key(request)
+                && seen(&request.start).is_some_and(|seen| seen.exists);
+            outdated || missing
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use denokv_proto::{KvEntry, KvValue};
+
+    use super::*;
+
+    fn versionstamp(version: u8) -> Versionstamp {
+        [0, 0, 0, 0, 0, 0, 0, version, 0, 0]
+    }
+
+    fn read(key: &[u8]) -> ReadRange {
+        ReadRange {
+            start: key.to_vec(),
+            end: [key, &[0]].concat(),
+            limit: NonZeroU32::new(1).unwrap(),
+            reverse: false,
+        }
+    }
+
+    fn output(key: &[u8], version: Option<u8>) -> ReadRangeOutput {
+        ReadRangeOutput {
+            entries: version
+                .map(|version| KvEntry {
+                    key: key.to_vec(),
+                    value: KvValue::U64(1),
+                    versionstamp: versionstamp(version),
+                })
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn detects_outdated_and_missing_entries() {
+        let observed = ObservedVersions::new(Duration::from_secs(60));
+        observed.observe(b"a", versionstamp(5), true);
+        observed.observe(b"a", versionstamp(3), false);
+        observed.observe(b"deleted", versionstamp(5), false);
+
+        assert!(!observed.is_stale(&[read(b"a")], &[output(b"a", Some(5))]));
+        assert!(!observed.is_stale(&[read(b"a")], &[output(b"a", Some(6))]));
+        assert!(observed.is_stale(&[read(b"a")], &[output(b"a", Some(4))]));
+        assert!(observed.is_stale(&[read(b"a")], &[output(b"a", None)]));
+        // Keys this process has not seen can not be judged.
+        assert!(!observed.is_stale(&[read(b"b")], &[output(b"b", None)]));
+        assert!(!observed.is_stale(&[read(b"b")], &[output(b"b", Some(1))]));
+
+        assert!(!observed.is_stale(&[read(b"deleted")], &[output(b"deleted", None)]));
+        assert!(observed.is_stale(&[read(b"deleted")], &[output(b"deleted", Some(4))]));
+    }
+
+    #[test]
+    fn forgets_observations_outside_the_window() {
+        let observed = ObservedVersions::new(Duration::ZERO);
+        observed.observe(b"a", versionstamp(5), true);
+        assert!(!observed.is_stale(&[read(b"a")], &[output(b"a", Some(4))]));
+    }
+}
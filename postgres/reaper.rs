@@ -0,0 +1,99 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+//! Deleting everything stored under a namespace in the background, such as
+//! the key prefix of a tenant that was removed.
+//!
+//! A namespace of millions of rows can not be deleted in one transaction,
+//! which would lock every row, write all of its WAL at once and likely time
+//! out. [`Postgres::delete_namespace`](crate::Postgres::delete_namespace)
+//! instead journals the request in the `namespace_deletions` table and
+//! returns. The maintenance leader looks for pending deletions every
+//! [`REAP_INTERVAL`] and deletes their rows in batches of
+//! [`PostgresConfig::reaper_batch_size`](crate::PostgresConfig::reaper_batch_size)
+//! per table, at most
+//! [`PostgresConfig::reaper_rows_per_sec`](crate::PostgresConfig::reaper_rows_per_sec)
+//! rows per second. Each batch adds what it deleted to the journal in the
+//! same transaction, so a deletion survives restarts and a new leader
+//! carries on where the last one stopped. The first batch that finds
+//! nothing left marks the deletion complete.
+//!
+//! Writes to the namespace while it is reaped are deleted too if they land
+//! before the last batch, so the namespace should be closed to writes
+//! first.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::backend::{self, PostgresBackend};
+use crate::error::PostgresResult;
+use crate::metrics::BackendMetrics;
+use crate::throttle::WriteThrottle;
+
+/// How often the maintenance leader looks for pending deletions.
+pub(crate) const REAP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The journal entry of the deletion of a namespace, with how many rows of
+/// each kind are gone so far.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NamespaceDeletion {
+    /// The key prefix of the namespace.
+    pub prefix: Vec<u8>,
+    /// Keys under the prefix.
+    pub deleted_keys: u64,
+    /// Tombstones of keys under the prefix deleted before.
+    pub deleted_tombstones: u64,
+    /// Keys of materialized views derived from keys under the prefix.
+    pub deleted_view_keys: u64,
+    /// Queue messages and dead letters enqueued through an ephemeral
+    /// namespace under the prefix.
+    pub deleted_messages: u64,
+    pub requested_at: DateTime<Utc>,
+    /// When the last batch found nothing left, or `None` while the deletion
+    /// is pending.
+    pub completed_at: Option<DateTime<Utc>>,
+}
+
+impl NamespaceDeletion {
+    pub fn is_complete(&self) -> bool {
+        self.completed_at.is_some()
+    }
+}
+
+/// Reap the pending deletions one after the other, each until it completes
+/// or `proceed` returns false, returning those that completed.
+pub(crate) async fn reap(
+    backend: &PostgresBackend,
+    metrics: &BackendMetrics,
+    proceed: impl Fn() -> bool,
+) -> PostgresResult<Vec<NamespaceDeletion>> {
+    let batch_size = backend::sql_limit(backend.config.reaper_batch_size.max(1));
+    let throttle = WriteThrottle::new(backend.config.reaper_rows_per_sec, None, 1);
+    let mut completed = Vec::new();
+    for deletion in backend.namespace_deletions(true).await? {
+        while proceed() {
+            let Some((deleted, progress)) = backend.reap_namespace_batch(&deletion.prefix, batch_size).await? else {
+                break;
+            };
+            metrics.record_reaped(deleted);
+            if progress.is_complete() {
+                log::info!(
+                    "Deleted namespace {}: {} keys, {} tombstones, {} view keys and {} queue messages",
+                    denokv_proto::format_key(&progress.prefix),
+                    progress.deleted_keys,
+                    progress.deleted_tombstones,
+                    progress.deleted_view_keys,
+                    progress.deleted_messages,
+                );
+                metrics.record_namespace_deleted();
+                completed.push(progress);
+                break;
+            }
+            if let Some(throttle) = &throttle {
+                throttle.acquire(deleted, 0).await?;
+            }
+        }
+    }
+    Ok(completed)
+}
@@ -0,0 +1,254 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::PostgresResult;
+use crate::pool::Pool;
+
+/// Weight of the newest sample in the latency moving average.
+const LATENCY_EWMA_ALPHA: f64 = 0.2;
+
+/// Consecutive failures after which a replica is quarantined.
+const QUARANTINE_AFTER_FAILURES: u32 = 3;
+
+/// How long a quarantined replica is skipped before it is tried again.
+const QUARANTINE_DURATION: Duration = Duration::from_secs(30);
+
+/// How often the replication lag of each replica is measured.
+pub const LAG_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Time since the last replayed transaction was committed on the primary,
+/// or 0 if the replica has replayed all the WAL it received, so that a
+/// replica of an idle primary does not look like it is falling behind.
+/// NULL if the server is not replaying WAL.
+const REPLICATION_LAG: &str = r#"
+    SELECT CASE
+        WHEN NOT pg_is_in_recovery() THEN NULL
+        WHEN pg_last_wal_receive_lsn() = pg_last_wal_replay_lsn() THEN 0
+        ELSE EXTRACT(EPOCH FROM now() - pg_last_xact_replay_timestamp())
+    END::float8
+"#;
+
+/// Read replicas used for eventually consistent reads.
+///
+/// Each read goes to the better of two randomly picked healthy replicas
+/// ("power of two choices"), judged by a moving average of observed read
+/// latency. A replica that fails several reads in a row is quarantined for a
+/// while, and one whose last measured replication lag is over `max_lag` is
+/// skipped until it catches up; if no replica is left, reads fall back to
+/// the primary.
+pub struct ReplicaSet {
+    replicas: Vec<Replica>,
+    max_lag: Option<Duration>,
+}
+
+struct Replica {
+    pool: Pool,
+    health: Mutex<ReplicaHealth>,
+}
+
+#[derive(Default)]
+struct ReplicaHealth {
+    /// Moving average of read latency in seconds, `None` until the first
+    /// successful read.
+    latency: Option<f64>,
+    consecutive_failures: u32,
+    quarantined_until: Option<Instant>,
+    /// Replication lag as last measured, `None` until measured or if the
+    /// server is not a standby.
+    lag: Option<Duration>,
+}
+
+impl ReplicaHealth {
+    fn is_healthy(&self, now: Instant) -> bool {
+        self.quarantined_until.is_none_or(|until| now >= until)
+    }
+
+    fn is_lagging(&self, max_lag: Option<Duration>) -> bool {
+        matches!((self.lag, max_lag), (Some(lag), Some(max_lag)) if lag > max_lag)
+    }
+}
+
+impl ReplicaSet {
+    pub fn new(pools: Vec<Pool>, max_lag: Option<Duration>) -> Self {
+        Self {
+            replicas: pools
+                .into_iter()
+                .map(|pool| Replica {
+                    pool,
+                    health: Mutex::default(),
+                })
+                .collect(),
+            max_lag,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.replicas.is_empty()
+    }
+
+    /// The replication lag of each replica as last measured.
+    pub fn lags(&self) -> Vec<Option<Duration>> {
+        self.replicas.iter().map(|replica| replica.health.lock().unwrap().lag).collect()
+    }
+
+    /// Whether any healthy replica is skipped for lagging too far behind.
+    pub fn any_lagging(&self) -> bool {
+        let now = Instant::now();
+        self.replicas.iter().any(|replica| {
+            let health = replica.health.lock().unwrap();
+            health.is_healthy(now) && health.is_lagging(self.max_lag)
+        })
+    }
+
+    /// Measure the replication lag of every replica, returning the
+    /// measurements. A replica that can not be measured counts as a failed
+    /// read and keeps its last measurement.
+    pub async fn measure_lags(&self) -> Vec<Option<Duration>> {
+        for (index, replica) in self.replicas.iter().enumerate() {
+            match measure_lag(&replica.pool).await {
+                Ok(lag) => {
+                    let mut health = replica.health.lock().unwrap();
+                    let was_lagging = health.is_lagging(self.max_lag);
+                    health.lag = lag;
+                    match (was_lagging, health.is_lagging(self.max_lag)) {
+                        (false, true) => log::warn!("Read replica {index} lags {lag:?} behind the primary; eventual reads skip it"),
+                        (true, false) => log::info!("Read replica {index} caught up with the primary"),
+                        _ => {}
+                    }
+                }
+                Err(e) => {
+                    log::debug!("Failed to measure the replication lag of read replica {index}: {e}");
+                    self.record_failure(index);
+                }
+            }
+        }
+        self.lags()
+    }
+
+    /// Pick the replica to serve the next read, or `None` if there are no
+    /// healthy replicas.
+    pub fn choose(&self) -> Option<(usize, &Pool)> {
+        let now = Instant::now();
+        let healthy: Vec<(usize, f64)> = self
+            .replicas
+            .iter()
+            .enumerate()
+            .filter_map(|(i, replica)| {
+                let health = replica.health.lock().unwrap();
+                // Unmeasured replicas score best so that they get sampled.
+                (health.is_healthy(now) && !health.is_lagging(self.max_lag))
+                    .then(|| (i, health.latency.unwrap_or(0.0)))
+            })
+            .collect();
+
+        let index = match healthy.len() {
+            0 => return None,
+            1 => healthy[0].0,
+            n => {
+                let a = rand::random::<usize>() % n;
+                let b = (a + 1 + rand::random::<usize>() % (n - 1)) % n;
+                if healthy[a].1 <= healthy[b].1 {
+                    healthy[a].0
+                } else {
+                    healthy[b].0
+                }
+            }
+        };
+        Some((index, &self.replicas[index].pool))
+    }
+
+    /// Record a successful read served by replica `index`.
+    pub fn record_success(&self, index: usize, latency: Duration) {
+        let mut health = self.replicas[index].health.lock().unwrap();
+        let sample = latency.as_secs_f64();
+        health.latency = Some(match health.latency {
+            Some(avg) => avg + LATENCY_EWMA_ALPHA * (sample - avg),
+            None => sample,
+        });
+        health.consecutive_failures = 0;
+        health.quarantined_until = None;
+    }
+
+    /// Record a failed read on replica `index`, quarantining it once it has
+    /// failed too many times in a row.
+    pub fn record_failure(&self, index: usize) {
+        let mut health = self.replicas[index].health.lock().unwrap();
+        health.consecutive_failures += 1;
+        if health.consecutive_failures >= QUARANTINE_AFTER_FAILURES {
+            health.consecutive_failures = 0;
+            health.quarantined_until = Some(Instant::now() + QUARANTINE_DURATION);
+        }
+    }
+}
+
+async fn measure_lag(pool: &Pool) -> PostgresResult<Option<Duration>> {
+    let conn = pool.get().await?;
+    let lag: Option<f64> = conn.query_one(REPLICATION_LAG, &[]).await?.get(0);
+    Ok(lag.map(|secs| Duration::from_secs_f64(secs.max(0.0))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::ConnectionPool;
+
+    // The tests run in a runtime because bb8 pools start a reaper task.
+    fn replica_set(n: usize) -> ReplicaSet {
+        replica_set_with_max_lag(n, None)
+    }
+
+    fn replica_set_with_max_lag(n: usize, max_lag: Option<Duration>) -> ReplicaSet {
+        let pools = (0..n)
+            .map(|_| {
+                let config = "postgresql://localhost/unused".parse().unwrap();
+                Pool::build(config, 1).unwrap()
+            })
+            .collect();
+        ReplicaSet::new(pools, max_lag)
+    }
+
+    #[tokio::test]
+    async fn prefers_faster_replica() {
+        let set = replica_set(2);
+        set.record_success(0, Duration::from_millis(50));
+        set.record_success(1, Duration::from_millis(5));
+        for _ in 0..20 {
+            assert_eq!(set.choose().unwrap().0, 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn quarantines_failing_replica() {
+        let set = replica_set(2);
+        for _ in 0..QUARANTINE_AFTER_FAILURES {
+            set.record_failure(1);
+        }
+        for _ in 0..20 {
+            assert_eq!(set.choose().unwrap().0, 0);
+        }
+        for _ in 0..QUARANTINE_AFTER_FAILURES {
+            set.record_failure(0);
+        }
+        assert!(set.choose().is_none());
+    }
+
+    #[tokio::test]
+    async fn skips_lagging_replica() {
+        let set = replica_set_with_max_lag(2, Some(Duration::from_secs(1)));
+        set.replicas[1].health.lock().unwrap().lag = Some(Duration::from_secs(5));
+        assert!(set.any_lagging());
+        for _ in 0..20 {
+            assert_eq!(set.choose().unwrap().0, 0);
+        }
+        set.replicas[0].health.lock().unwrap().lag = Some(Duration::from_secs(2));
+        assert!(set.choose().is_none());
+
+        // Without a maximum, lag is only reported.
+        let set = replica_set(1);
+        set.replicas[0].health.lock().unwrap().lag = Some(Duration::from_secs(5));
+        assert!(!set.any_lagging());
+        assert_eq!(set.choose().unwrap().0, 0);
+    }
+}
@@ -0,0 +1,233 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+//! Server settings that commonly explain slow or unreliable operation.
+//! They are checked at startup, logging a warning for each problem, and on
+//! demand by [`crate::Postgres::check_server_settings`].
+
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::error::PostgresResult;
+use crate::pool::Client;
+use crate::tables::Tables;
+
+/// Tables whose rows churn, leaving dead rows for autovacuum to clean up.
+const CHURNING_TABLES: &[&str] = &["kv_store", "kv_tombstones", "queue_messages", "queue_running"];
+
+/// The PostgreSQL default. Reads of several ranges sort their results in
+/// memory up to this size and on disk beyond it.
+const MIN_WORK_MEM: i64 = 4 * 1024 * 1024;
+
+/// A server setting that is likely to hurt, and what to do about it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SettingWarning {
+    pub setting: String,
+    pub value: String,
+    pub advice: String,
+}
+
+impl fmt::Display for SettingWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} = {}: {}", self.setting, self.value, self.advice)
+    }
+}
+
+/// The settings that are checked, as read from the server.
+#[derive(Debug, Clone)]
+pub struct ServerSettings {
+    pub max_connections: i64,
+    /// Connections kept for superusers and, from PostgreSQL 16, roles with
+    /// `pg_use_reserved_connections`.
+    pub reserved_connections: i64,
+    pub synchronous_commit: String,
+    /// `work_mem` in bytes.
+    pub work_mem: i64,
+    pub autovacuum: bool,
+    pub track_counts: bool,
+    /// Tables of this instance with autovacuum disabled in their storage
+    /// parameters.
+    pub unvacuumed_tables: Vec<String>,
+    pub enable_partition_pruning: bool,
+    /// Whether `kv_store` is a partitioned table.
+    pub partitioned: bool,
+}
+
+impl ServerSettings {
+    pub async fn query(conn: &Client, tables: &Tables) -> PostgresResult<Self> {
+        let row = conn.query_one(
+            r#"
+            SELECT
+                current_setting('max_connections')::int8 AS max_connections,
+                current_setting('superuser_reserved_connections')::int8
+                    + COALESCE(current_setting('reserved_connections', true), '0')::int8 AS reserved_connections,
+                current_setting('synchronous_commit') AS synchronous_commit,
+                pg_size_bytes(current_setting('work_mem')) AS work_mem,
+                current_setting('autovacuum')::bool AS autovacuum,
+                current_setting('track_counts')::bool AS track_counts,
+                current_setting('enable_partition_pruning')::bool AS enable_partition_pruning,
+                EXISTS (SELECT 1 FROM pg_partitioned_table WHERE partrelid = to_regclass($1)) AS partitioned
+            "#,
+            &[&tables.sql("kv_store").into_owned()],
+        ).await?;
+        let names: Vec<String> = CHURNING_TABLES.iter().map(|table| tables.sql(table).into_owned()).collect();
+        let unvacuumed_tables = conn.query(
+            r#"
+            SELECT c.relname FROM pg_class c
+            WHERE c.oid = ANY(ARRAY(SELECT to_regclass(t) FROM unnest($1::text[]) AS t))
+              AND EXISTS (
+                SELECT 1 FROM unnest(c.reloptions) AS o
+                WHERE lower(o) IN ('autovacuum_enabled=false', 'autovacuum_enabled=off', 'autovacuum_enabled=no', 'autovacuum_enabled=0')
+              )
+            ORDER BY c.relname
+            "#,
+            &[&names],
+        ).await?.iter().map(|row| row.get(0)).collect();
+        Ok(Self {
+            max_connections: row.get("max_connections"),
+            reserved_connections: row.get("reserved_connections"),
+            synchronous_commit: row.get("synchronous_commit"),
+            work_mem: row.get("work_mem"),
+            autovacuum: row.get("autovacuum"),
+            track_counts: row.get("track_counts"),
+            unvacuumed_tables,
+            enable_partition_pruning: row.get("enable_partition_pruning"),
+            partitioned: row.get("partitioned"),
+        })
+    }
+}
+
+/// Warn about the settings in `settings` that are likely to hurt an
+/// instance opening up to `connections` connections to the server.
+pub fn check(settings: &ServerSettings, connections: usize) -> Vec<SettingWarning> {
+    let mut warnings = Vec::new();
+    let mut warn = |setting: &str, value: String, advice: String| {
+        warnings.push(SettingWarning { setting: setting.to_string(), value, advice });
+    };
+
+    let available = (settings.max_connections - settings.reserved_connections).max(0);
+    if connections as i64 > available {
+        warn(
+            "max_connections",
+            settings.max_connections.to_string(),
+            format!(
+                "{available} connection(s) are available to clients, but this instance alone may open {connections}, so \
+                 requests will wait for or fail to get connections. Raise max_connections, lower the pool size or put a \
+                 connection pooler in front of the server"
+            ),
+        );
+    }
+
+    match settings.synchronous_commit.as_str() {
+        "off" => warn(
+            "synchronous_commit",
+            settings.synchronous_commit.clone(),
+            "commits are acknowledged before they reach disk, so a crash can lose writes that were reported as \
+             committed. Set it to on unless that is acceptable"
+                .to_string(),
+        ),
+        "remote_apply" => warn(
+            "synchronous_commit",
+            settings.synchronous_commit.clone(),
+            "every commit waits for standbys to apply it, adding their replay lag to write latency. Use on or \
+             remote_write unless reads from standbys must see every write"
+                .to_string(),
+        ),
+        _ => {}
+    }
+
+    if settings.work_mem < MIN_WORK_MEM {
+        warn(
+            "work_mem",
+            format!("{}kB", settings.work_mem / 1024),
+            format!("reads of several ranges sort on disk beyond work_mem. Raise it to at least {}MB", MIN_WORK_MEM / 1024 / 1024),
+        );
+    }
+
+    if !settings.autovacuum {
+        warn(
+            "autovacuum",
+            "off".to_string(),
+            "dead rows left by overwrites, deletes and finished queue messages are not cleaned up, so tables and \
+             indexes bloat and reads slow down over time. Turn it on"
+                .to_string(),
+        );
+    } else if !settings.track_counts {
+        warn(
+            "track_counts",
+            "off".to_string(),
+            "autovacuum relies on it to find tables to vacuum, so none are. Turn it on".to_string(),
+        );
+    }
+    for table in &settings.unvacuumed_tables {
+        warn(
+            &format!("{table}.autovacuum_enabled"),
+            "false".to_string(),
+            format!("dead rows in {table} are not cleaned up, so it bloats and slows down. Run ALTER TABLE {table} RESET (autovacuum_enabled)"),
+        );
+    }
+
+    if settings.partitioned && !settings.enable_partition_pruning {
+        warn(
+            "enable_partition_pruning",
+            "off".to_string(),
+            "every read of kv_store scans all of its partitions instead of those holding the keys it reads. Turn it on"
+                .to_string(),
+        );
+    }
+
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn healthy() -> ServerSettings {
+        ServerSettings {
+            max_connections: 100,
+            reserved_connections: 3,
+            synchronous_commit: "on".to_string(),
+            work_mem: MIN_WORK_MEM,
+            autovacuum: true,
+            track_counts: true,
+            unvacuumed_tables: vec![],
+            enable_partition_pruning: true,
+            partitioned: false,
+        }
+    }
+
+    fn settings_warned(settings: &ServerSettings, connections: usize) -> Vec<String> {
+        check(settings, connections).into_iter().map(|warning| warning.setting).collect()
+    }
+
+    #[test]
+    fn accepts_sound_settings() {
+        assert!(check(&healthy(), 97).is_empty());
+    }
+
+    #[test]
+    fn warns_about_each_setting() {
+        assert_eq!(settings_warned(&healthy(), 98), ["max_connections"]);
+        let settings = ServerSettings {
+            synchronous_commit: "off".to_string(),
+            work_mem: 64 * 1024,
+            track_counts: false,
+            unvacuumed_tables: vec!["kv_store".to_string()],
+            enable_partition_pruning: false,
+            partitioned: true,
+            ..healthy()
+        };
+        assert_eq!(
+            settings_warned(&settings, 10),
+            ["synchronous_commit", "work_mem", "track_counts", "kv_store.autovacuum_enabled", "enable_partition_pruning"]
+        );
+        assert_eq!(check(&settings, 10)[1].value, "64kB");
+        // track_counts only matters to autovacuum.
+        let settings = ServerSettings { autovacuum: false, track_counts: false, ..healthy() };
+        assert_eq!(settings_warned(&settings, 10), ["autovacuum"]);
+        // Partition pruning only matters to partitioned tables.
+        let settings = ServerSettings { enable_partition_pruning: false, ..healthy() };
+        assert!(check(&settings, 10).is_empty());
+    }
+}
@@ -0,0 +1,707 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+use std::borrow::Cow;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_stream::try_stream;
+use async_trait::async_trait;
+use deno_error::JsErrorBox;
+use denokv_proto::{
+    AtomicWrite, CommitResult, Database, KvEntry, MutationKind, QueueMessageHandle, ReadRange,
+    ReadRangeOutput, SnapshotReadOptions, Versionstamp, WatchKeyOutput,
+};
+use futures::Stream;
+use sqlx::postgres::{PgListener, PgPool, PgRow};
+use sqlx::{AssertSqlSafe, Postgres as Pg, Row, Transaction};
+use tokio::sync::watch;
+use uuid::Uuid;
+
+use crate::backend::{sql_limit, version_to_versionstamp};
+use crate::change_notify;
+use crate::config::{queue_running_deadline, Durability, PostgresConfig};
+use crate::decode::{decode_entry, row_checksum, verify_checksum};
+use crate::driver::{self, Le64Op};
+use crate::error::{PostgresError, PostgresResult};
+use crate::message_handle::Keepalive;
+use crate::notifier::PostgresNotifier;
+use crate::queue_metadata::{QueueMetadata, METADATA_ENCODING};
+use crate::range::{self, KeyBounds};
+use crate::table_partitioning::{TableLayout, TablePartitioning};
+use crate::tables::Tables;
+
+/// How long the change listener waits before listening again after failing
+/// to.
+const LISTEN_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// A [`Database`] over a [`sqlx::PgPool`], for applications that use sqlx
+/// for everything else. Stores data exactly like [`crate::Postgres`], so the
+/// two can share a database.
+///
+/// Only the [`Database`] operations are supported: reads, atomic writes,
+/// queues and watches, with expired keys swept and dead queue messages
+/// requeued in the background. Read replicas, throttling, circuit breakers
+/// and the other extensions of [`crate::Postgres`] are not available.
+#[derive(Clone)]
+pub struct SqlxPostgres {
+    pool: PgPool,
+    config: Arc<PostgresConfig>,
+    tables: Tables,
+    notifier: PostgresNotifier,
+    /// Set to true by [`Database::close`]; background tasks stop when it is
+    /// set or dropped.
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+impl SqlxPostgres {
+    /// Check the server and the schema, creating or upgrading it as needed,
+    /// and start the background tasks. Of `config`, the URL, pool size and
+    /// the settings of features this driver lacks are ignored.
+    pub async fn new(pool: PgPool, config: PostgresConfig) -> PostgresResult<Self> {
+        crate::validate_config(&config)?;
+
+        let (lower, upper) = range::byte_order_samples();
+        let less: Vec<bool> = sqlx::query_scalar(driver::BYTE_ORDER_CHECK)
+            .bind(lower)
+            .bind(upper)
+            .fetch_all(&pool)
+            .await?;
+        range::verify_byte_order(less)?;
+
+        let tables = Tables::new(&config);
+        if let Some(schema) = tables.schema() {
+            sqlx::query(AssertSqlSafe(format!("CREATE SCHEMA IF NOT EXISTS {schema}"))).execute(&pool).await?;
+        }
+        if config.table_partitioning != TablePartitioning::None {
+            let layout = sqlx::query(sql(&tables, driver::KV_STORE_LAYOUT)).fetch_optional(&pool).await?.map(|row| TableLayout {
+                strategy: row.get("strategy"),
+                partitions: row.get("partitions"),
+            });
+            let statements = config.table_partitioning.statements(&tables, layout);
+            if !statements.is_empty() {
+                let mut tx = pool.begin().await?;
+                for statement in statements {
+                    sqlx::query(AssertSqlSafe(statement)).execute(&mut *tx).await?;
+                }
+                tx.commit().await?;
+            }
+        }
+        for statement in driver::SCHEMA {
+            sqlx::query(sql(&tables, statement)).execute(&pool).await?;
+        }
+        sqlx::query(sql(&tables, driver::RECORD_SCHEMA_VERSION)).bind(driver::SCHEMA_VERSION as i32).execute(&pool).await?;
+
+        let db = Self {
+            pool,
+            config: Arc::new(config),
+            tables,
+            notifier: PostgresNotifier::new(),
+            shutdown: Arc::new(watch::channel(false).0),
+        };
+
+        if db.config.listen_for_changes {
+            let listener = listen(db.pool.clone(), db.tables.channel(), db.notifier.clone());
+            let mut shutdown = db.shutdown.subscribe();
+            tokio::spawn(async move {
+                tokio::select! {
+                    _ = listener => {}
+                    _ = shutdown.wait_for(|closed| *closed) => {}
+                }
+            });
+        }
+        if let Some(lease) = crate::watch_lease(&db.config) {
+            tokio::spawn(crate::release_expired_watches(db.notifier.clone(), lease, db.shutdown.subscribe()));
+        }
+        if db.config.expiry_sweep_interval > 0 {
+            let interval = Duration::from_secs(db.config.expiry_sweep_interval);
+            let sweeper = db.clone_for_task();
+            let mut shutdown = db.shutdown.subscribe();
+            tokio::spawn(async move {
+                while !crate::sleep_until_shutdown(interval, &mut shutdown).await {
+                    if let Err(e) = sweeper.collect_expired().await {
+                        eprintln!("[denokv/postgres] collect_expired error: {e}");
+                    }
+                }
+            });
+        }
+        {
+            let cleaner = db.clone_for_task();
+            let mut shutdown = db.shutdown.subscribe();
+            tokio::spawn(async move {
+                while !crate::sleep_until_shutdown(Duration::from_secs(30), &mut shutdown).await {
+                    if let Err(e) = cleaner.queue_cleanup().await {
+                        eprintln!("[denokv/postgres] queue_cleanup error: {e}");
+                    }
+                }
+            });
+        }
+
+        Ok(db)
+    }
+
+    /// A clone for a background task, which must not keep the tasks alive
+    /// by holding the shutdown sender.
+    fn clone_for_task(&self) -> TaskHandle {
+        TaskHandle {
+            pool: self.pool.clone(),
+            config: self.config.clone(),
+            tables: self.tables.clone(),
+        }
+    }
+
+    /// Delete every expired row now, rather than on the next sweep. Returns
+    /// the number of rows removed.
+    pub async fn collect_expired(&self) -> PostgresResult<u64> {
+        self.clone_for_task().collect_expired().await
+    }
+
+    async fn read_ranges(&self, requests: &[ReadRange]) -> PostgresResult<Vec<ReadRangeOutput>> {
+        let results = match requests {
+            [request] => vec![read_range(&self.pool, &self.config, &self.tables, request).await?],
+            _ => {
+                let arrays = driver::RangeArrays::new(requests);
+                let rows = sqlx::query(sql(&self.tables, driver::READ_RANGES))
+                    .bind(arrays.starts)
+                    .bind(arrays.ends)
+                    .bind(arrays.limits)
+                    .bind(arrays.reverse)
+                    .bind(crate::time::utc_now().timestamp_millis())
+                    .fetch_all(&self.pool)
+                    .await?;
+                let rows = rows.iter()
+                    .map(|row| Ok((row.try_get("range_index")?, decode_row(row, self.config.verify_checksums)?)))
+                    .collect::<PostgresResult<Vec<_>>>()?;
+                driver::split_ranges(requests, rows)?
+            }
+        };
+        Ok(results.into_iter().map(|entries| ReadRangeOutput { entries }).collect())
+    }
+
+    async fn write(&self, write: &AtomicWrite) -> PostgresResult<Option<CommitResult>> {
+        if let Some(limits) = &self.config.write_limits {
+            limits.check(write)?;
+        }
+        let mut tx = self.pool.begin().await?;
+        if self.config.durability == Durability::Asynchronous {
+            sqlx::query(driver::ASYNCHRONOUS_COMMIT).execute(&mut *tx).await?;
+        }
+
+        // Lock the version counter first — this serializes all writers.
+        let new_version: i64 = sqlx::query_scalar(sql(&self.tables, driver::INCREMENT_VERSION))
+            .fetch_one(&mut *tx)
+            .await?;
+
+        // Perform checks — treat expired keys as non-existent
+        let now_ms = crate::time::utc_now().timestamp_millis();
+        for check in &write.checks {
+            let current: Option<Vec<u8>> = sqlx::query_scalar(sql(&self.tables, driver::CHECK))
+                .bind(&check.key)
+                .bind(now_ms)
+                .fetch_optional(&mut *tx)
+                .await?;
+            if !driver::check_passes(check.versionstamp.as_ref(), current.as_deref()) {
+                return Ok(None);
+            }
+        }
+
+        let versionstamp = version_to_versionstamp(new_version);
+        let mut rest = &write.mutations[..];
+        while let Some(mutation) = rest.first() {
+            // Consecutive sets are applied in one statement.
+            let run = driver::set_run(rest);
+            if run > 1 {
+                let arrays = driver::SetArrays::new(&rest[..run]);
+                sqlx::query(sql(&self.tables, driver::SET_MANY))
+                    .bind(&arrays.keys)
+                    .bind(arrays.value_slices())
+                    .bind(&arrays.encodings)
+                    .bind(versionstamp.as_slice())
+                    .bind(&arrays.expires_at)
+                    .bind(&arrays.checksums)
+                    .execute(&mut *tx)
+                    .await?;
+                rest = &rest[run..];
+                continue;
+            }
+            rest = &rest[1..];
+            let expires_at = mutation.expire_at.map(|dt| dt.timestamp_millis());
+            match &mutation.kind {
+                MutationKind::Set(value) => {
+                    set(&mut tx, &self.tables, driver::SET, &mutation.key, value, &versionstamp, expires_at).await?;
+                }
+                MutationKind::SetSuffixVersionstampedKey(value) => {
+                    let key = driver::changed_key(&mutation.key, true, &versionstamp);
+                    set(&mut tx, &self.tables, driver::SET_VERSIONSTAMPED_KEY, &key, value, &versionstamp, expires_at).await?;
+                }
+                MutationKind::Delete => {
+                    sqlx::query(sql(&self.tables, driver::DELETE))
+                        .bind(&mutation.key)
+                        .bind(versionstamp.as_slice())
+                        .execute(&mut *tx)
+                        .await?;
+                }
+                MutationKind::Sum { value: value @ denokv_proto::KvValue::U64(_), .. } => {
+                    set_le64(&mut tx, &self.tables, &mutation.key, Le64Op::Sum, value, &versionstamp).await?;
+                }
+                MutationKind::Sum { value, min_v8, max_v8, clamp } => {
+                    let current = get_value(&mut tx, &self.tables, &mutation.key).await?;
+                    let value = driver::sum_v8(current, value, min_v8, max_v8, *clamp)?;
+                    set(&mut tx, &self.tables, driver::SET, &mutation.key, &value, &versionstamp, None).await?;
+                }
+                MutationKind::Min(value) => {
+                    set_le64(&mut tx, &self.tables, &mutation.key, Le64Op::Min, value, &versionstamp).await?;
+                }
+                MutationKind::Max(value) => {
+                    set_le64(&mut tx, &self.tables, &mutation.key, Le64Op::Max, value, &versionstamp).await?;
+                }
+            }
+        }
+
+        for enqueue in &write.enqueues {
+            sqlx::query(sql(&self.tables, driver::ENQUEUE))
+                .bind(&enqueue.payload)
+                .bind(enqueue.deadline.timestamp_millis())
+                .bind(QueueMetadata::of(enqueue).encode())
+                .bind(METADATA_ENCODING)
+                .bind(None::<Vec<u8>>)
+                .bind(0i16)
+                .execute(&mut *tx)
+                .await?;
+        }
+        if !write.enqueues.is_empty() {
+            notify_queue(&mut tx, &self.tables).await?;
+        }
+
+        if !write.mutations.is_empty() {
+            let payload = change_notify::encode_payload(write.mutations.iter().map(|m| m.key.as_slice()));
+            sqlx::query(driver::NOTIFY).bind(self.tables.channel()).bind(payload).execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
+        Ok(Some(CommitResult {
+            versionstamp,
+            durable: self.config.durability == Durability::Synchronous,
+            versionstamped_keys: denokv_proto::versionstamped_keys(&write.mutations, &versionstamp),
+        }))
+    }
+
+    async fn dequeue(&self) -> PostgresResult<Option<SqlxMessageHandle>> {
+        let mut tx = self.pool.begin().await?;
+        let now_ms = crate::time::utc_now().timestamp_millis();
+
+        let row = sqlx::query(sql(&self.tables, driver::DEQUEUE))
+            .bind(now_ms)
+            .bind(None::<Vec<u8>>)
+            .fetch_optional(&mut *tx)
+            .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let id: Uuid = row.try_get("id")?;
+        let payload: Vec<u8> = row.try_get("payload")?;
+
+        let running_deadline = queue_running_deadline(self.config.queue_visibility_timeout, now_ms);
+        sqlx::query(sql(&self.tables, driver::START_RUNNING))
+            .bind(id)
+            .bind(running_deadline)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        let pool = self.pool.clone();
+        let tables = self.tables.clone();
+        let keepalive = Keepalive::spawn(self.config.queue_visibility_timeout, move |deadline| {
+            let query = sqlx::query(sql(&tables, driver::EXTEND_RUNNING)).bind(id).bind(deadline);
+            let pool = pool.clone();
+            async move { Ok(query.execute(&pool).await?.rows_affected() > 0) }
+        });
+
+        Ok(Some(SqlxMessageHandle {
+            id,
+            payload: Some(payload),
+            pool: self.pool.clone(),
+            tables: self.tables.clone(),
+            max_delivery_attempts: self.config.max_delivery_attempts,
+            keepalive,
+        }))
+    }
+}
+
+/// What the background tasks of a [`SqlxPostgres`] need of it.
+struct TaskHandle {
+    pool: PgPool,
+    config: Arc<PostgresConfig>,
+    tables: Tables,
+}
+
+impl TaskHandle {
+    /// Delete expired rows batch by batch until none are left.
+    async fn collect_expired(&self) -> PostgresResult<u64> {
+        let batch_size = sql_limit(self.config.expiry_sweep_batch_size.max(1));
+        let mut collected = 0;
+        loop {
+            let now_ms = crate::time::utc_now().timestamp_millis();
+            let deleted = sqlx::query(sql(&self.tables, driver::COLLECT_EXPIRED))
+                .bind(now_ms)
+                .bind(batch_size)
+                .execute(&self.pool)
+                .await?
+                .rows_affected();
+            collected += deleted;
+            if deleted < batch_size as u64 {
+                return Ok(collected);
+            }
+        }
+    }
+
+    /// Requeue messages stuck in queue_running past their deadline.
+    async fn queue_cleanup(&self) -> PostgresResult<u64> {
+        let mut tx = self.pool.begin().await?;
+        let now_ms = crate::time::utc_now().timestamp_millis();
+
+        let overdue: Vec<Uuid> = sqlx::query_scalar(sql(&self.tables, driver::OVERDUE_RUNNING))
+            .bind(now_ms)
+            .fetch_all(&mut *tx)
+            .await?;
+        let mut requeued = 0;
+        for id in overdue {
+            let message = sqlx::query(sql(&self.tables, driver::FAILED_MESSAGE))
+                .bind(id)
+                .fetch_optional(&mut *tx)
+                .await?;
+            sqlx::query(sql(&self.tables, driver::STOP_RUNNING)).bind(id).execute(&mut *tx).await?;
+
+            if let Some(message) = message {
+                let metadata = message_metadata(&message)?;
+                let retry_count: i32 = message.try_get("retry_count")?;
+                match driver::failure(&metadata.backoff_schedule, retry_count, self.config.max_delivery_attempts, now_ms) {
+                    driver::Failure::Retry(deadline, remaining) => {
+                        retry(&mut tx, &self.tables, id, deadline, &metadata.retried(remaining), retry_count).await?;
+                        requeued += 1;
+                    }
+                    driver::Failure::DeadLetter => {
+                        sqlx::query(sql(&self.tables, driver::DEAD_LETTER_MESSAGE)).bind(id).execute(&mut *tx).await?;
+                    }
+                    driver::Failure::Undelivered => {
+                        let payload: Vec<u8> = message.try_get("payload")?;
+                        set_undelivered(&mut tx, &self.tables, &metadata.keys_if_undelivered, &payload).await?;
+                        sqlx::query(sql(&self.tables, driver::DELETE_MESSAGE)).bind(id).execute(&mut *tx).await?;
+                    }
+                }
+            }
+        }
+
+        tx.commit().await?;
+        Ok(requeued)
+    }
+}
+
+async fn read_range(pool: &PgPool, config: &PostgresConfig, tables: &Tables, request: &ReadRange) -> PostgresResult<Vec<KvEntry>> {
+    let bounds = KeyBounds::from_request(request);
+    let query = if request.reverse { driver::READ_RANGE_REVERSE } else { driver::READ_RANGE };
+    let rows = sqlx::query(sql(tables, query))
+        .bind(bounds.start)
+        .bind(bounds.end)
+        .bind(request.limit.get() as i64)
+        .bind(crate::time::utc_now().timestamp_millis())
+        .fetch_all(pool)
+        .await?;
+    rows.iter().map(|row| decode_row(row, config.verify_checksums)).collect()
+}
+
+/// `statement` naming the tables configured in `tables`, as sqlx takes it.
+fn sql(tables: &Tables, statement: &'static str) -> AssertSqlSafe<Cow<'static, str>> {
+    AssertSqlSafe(tables.sql(statement))
+}
+
+fn decode_row(row: &PgRow, verify_checksums: bool) -> PostgresResult<KvEntry> {
+    let key: Vec<u8> = row.try_get("key")?;
+    let value: Vec<u8> = row.try_get("value")?;
+    let encoding: i32 = row.try_get("value_encoding")?;
+    let versionstamp: Vec<u8> = row.try_get("versionstamp")?;
+    if verify_checksums {
+        if let Err(kind) = verify_checksum(&key, &value, encoding, row.try_get("checksum")?) {
+            return Err(PostgresError::CorruptRow { key, kind });
+        }
+    }
+    decode_entry(key, value, encoding, &versionstamp)
+}
+
+async fn set(
+    tx: &mut Transaction<'_, Pg>,
+    tables: &Tables,
+    statement: &'static str,
+    key: &[u8],
+    value: &denokv_proto::KvValue,
+    versionstamp: &Versionstamp,
+    expires_at: Option<i64>,
+) -> PostgresResult<()> {
+    let (bytes, encoding) = denokv_proto::encode_value(value);
+    let encoding = encoding as i32;
+    let checksum = row_checksum(key, &bytes, encoding);
+    sqlx::query(sql(tables, statement))
+        .bind(key)
+        .bind(&*bytes)
+        .bind(encoding)
+        .bind(versionstamp.as_slice())
+        .bind(expires_at)
+        .bind(checksum)
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+async fn set_le64(
+    tx: &mut Transaction<'_, Pg>,
+    tables: &Tables,
+    key: &[u8],
+    op: Le64Op,
+    value: &denokv_proto::KvValue,
+    versionstamp: &Versionstamp,
+) -> PostgresResult<()> {
+    let operand = op.operand(value)?;
+    let current = op.current(get_value(tx, tables, key).await?)?;
+    let bytes = op.apply(current.as_deref(), operand).to_le_bytes();
+    sqlx::query(sql(tables, driver::SET_LE64))
+        .bind(key)
+        .bind(bytes.as_slice())
+        .bind(versionstamp.as_slice())
+        .bind(row_checksum(key, &bytes, 2))
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+/// The value of `key` and its encoding, for read-modify-write mutations.
+async fn get_value(
+    tx: &mut Transaction<'_, Pg>,
+    tables: &Tables,
+    key: &[u8],
+) -> PostgresResult<Option<(Vec<u8>, i32)>> {
+    Ok(sqlx::query_as(sql(tables, driver::GET_VALUE))
+        .bind(key)
+        .fetch_optional(&mut **tx)
+        .await?)
+}
+
+/// The metadata of a row of `queue_messages`.
+fn message_metadata(row: &PgRow) -> PostgresResult<QueueMetadata> {
+    QueueMetadata::decode(
+        row.try_get("metadata")?,
+        row.try_get("metadata_encoding")?,
+        row.try_get("keys_if_undelivered")?,
+        row.try_get("backoff_schedule")?,
+    )
+}
+
+async fn retry(
+    tx: &mut Transaction<'_, Pg>,
+    tables: &Tables,
+    id: Uuid,
+    deadline: i64,
+    metadata: &QueueMetadata,
+    retry_count: i32,
+) -> PostgresResult<()> {
+    sqlx::query(sql(tables, driver::RETRY_MESSAGE))
+        .bind(deadline)
+        .bind(metadata.encode())
+        .bind(METADATA_ENCODING)
+        .bind(retry_count + 1)
+        .bind(id)
+        .execute(&mut **tx)
+        .await?;
+    notify_queue(tx, tables).await
+}
+
+/// Wake consumers of [`crate::Postgres`] instances sharing the tables, since
+/// a message was enqueued or rescheduled. See [`change_notify`].
+async fn notify_queue(tx: &mut Transaction<'_, Pg>, tables: &Tables) -> PostgresResult<()> {
+    sqlx::query(driver::NOTIFY).bind(tables.queue_channel()).bind("").execute(&mut **tx).await?;
+    Ok(())
+}
+
+/// Write `payload` to each of `keys`, like
+/// [`crate::message_handle::set_undelivered`] does.
+async fn set_undelivered(tx: &mut Transaction<'_, Pg>, tables: &Tables, keys: &[Vec<u8>], payload: &[u8]) -> PostgresResult<()> {
+    if keys.is_empty() {
+        return Ok(());
+    }
+    let new_version: i64 = sqlx::query_scalar(sql(tables, driver::INCREMENT_VERSION)).fetch_one(&mut **tx).await?;
+    let versionstamp = version_to_versionstamp(new_version);
+    for key in keys {
+        sqlx::query(sql(tables, driver::SET_UNDELIVERED))
+            .bind(key)
+            .bind(payload)
+            .bind(versionstamp.as_slice())
+            .bind(row_checksum(key, payload, 1))
+            .execute(&mut **tx)
+            .await?;
+    }
+    let notification = change_notify::encode_payload(keys.iter().map(Vec::as_slice));
+    sqlx::query(driver::NOTIFY).bind(tables.channel()).bind(notification).execute(&mut **tx).await?;
+    Ok(())
+}
+
+/// Listen on `channel` for key changes made through any instance and wake
+/// the matching watchers of `notifier`, like [`change_notify::listen`] does.
+async fn listen(pool: PgPool, channel: String, notifier: PostgresNotifier) {
+    loop {
+        let mut listener = match PgListener::connect_with(&pool).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                log::warn!("Failed to connect the key change listener: {e}");
+                tokio::time::sleep(LISTEN_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        if let Err(e) = listener.listen(&channel).await {
+            log::warn!("Failed to listen for key changes: {e}");
+            tokio::time::sleep(LISTEN_RETRY_DELAY).await;
+            continue;
+        }
+        notifier.notify_all();
+        loop {
+            match listener.try_recv().await {
+                Ok(Some(notification)) => match change_notify::decode_payload(notification.payload()) {
+                    Some(keys) => {
+                        for key in &keys {
+                            notifier.notify_key_update(key);
+                        }
+                    }
+                    None => notifier.notify_all(),
+                },
+                // The connection was lost and is reestablished on the next
+                // call; changes may have been missed in between.
+                Ok(None) => notifier.notify_all(),
+                Err(e) => {
+                    log::warn!("Lost the key change listener connection: {e}");
+                    tokio::time::sleep(LISTEN_RETRY_DELAY).await;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Database for SqlxPostgres {
+    type QMH = SqlxMessageHandle;
+
+    async fn snapshot_read(
+        &self,
+        requests: Vec<ReadRange>,
+        _options: SnapshotReadOptions,
+    ) -> Result<Vec<ReadRangeOutput>, JsErrorBox> {
+        self.read_ranges(&requests).await.map_err(JsErrorBox::from_err)
+    }
+
+    async fn atomic_write(
+        &self,
+        write: AtomicWrite,
+    ) -> Result<Option<CommitResult>, JsErrorBox> {
+        let result = self.write(&write).await.map_err(JsErrorBox::from_err)?;
+        if let Some(commit) = &result {
+            for (key, versionstamped) in driver::mutated_keys(&write.mutations) {
+                self.notifier.notify_key_update(&driver::changed_key(&key, versionstamped, &commit.versionstamp));
+            }
+        }
+        Ok(result)
+    }
+
+    async fn dequeue_next_message(&self) -> Result<Option<Self::QMH>, JsErrorBox> {
+        self.dequeue().await.map_err(JsErrorBox::from_err)
+    }
+
+    fn watch(&self, keys: Vec<Vec<u8>>) -> Pin<Box<dyn Stream<Item = Result<Vec<WatchKeyOutput>, JsErrorBox>> + Send>> {
+        let pool = self.pool.clone();
+        let config = self.config.clone();
+        let tables = self.tables.clone();
+        let mut subscription = self.notifier.subscribe_watch(&keys, crate::watch_lease(&config));
+
+        let stream = try_stream! {
+            loop {
+                let mut outputs = Vec::new();
+                for key in &keys {
+                    let request = ReadRange {
+                        start: key.clone(),
+                        end: key.iter().copied().chain(Some(0)).collect(),
+                        limit: std::num::NonZeroU32::new(1).unwrap(),
+                        reverse: false,
+                    };
+                    let entries = read_range(&pool, &config, &tables, &request).await.map_err(JsErrorBox::from_err)?;
+                    outputs.push(WatchKeyOutput::Changed { entry: entries.into_iter().next() });
+                }
+
+                yield outputs;
+
+                subscription.wait_for_change().await.map_err(JsErrorBox::from_err)?;
+            }
+        };
+
+        Box::pin(stream)
+    }
+
+    fn close(&self) {
+        self.shutdown.send_replace(true);
+    }
+}
+
+/// A queue message dequeued by [`SqlxPostgres`].
+pub struct SqlxMessageHandle {
+    id: Uuid,
+    payload: Option<Vec<u8>>,
+    pool: PgPool,
+    tables: Tables,
+    max_delivery_attempts: Option<u32>,
+    keepalive: Keepalive,
+}
+
+impl SqlxMessageHandle {
+    /// Finish processing the message. A failed message is retried after the
+    /// next delay of its backoff schedule, or once that is exhausted, its
+    /// `keys_if_undelivered` are set and it is deleted. A message that used
+    /// up its delivery attempts is moved to the dead letter queue instead.
+    async fn finish(&self, success: bool) -> PostgresResult<()> {
+        self.keepalive.stop();
+        let mut tx = self.pool.begin().await?;
+        let id = self.id;
+        sqlx::query(sql(&self.tables, driver::STOP_RUNNING)).bind(id).execute(&mut *tx).await?;
+
+        if success {
+            sqlx::query(sql(&self.tables, driver::DELETE_MESSAGE)).bind(id).execute(&mut *tx).await?;
+        } else if let Some(row) = sqlx::query(sql(&self.tables, driver::FAILED_MESSAGE)).bind(id).fetch_optional(&mut *tx).await? {
+            let payload: Vec<u8> = row.try_get("payload")?;
+            let metadata = message_metadata(&row)?;
+            let retry_count: i32 = row.try_get("retry_count")?;
+
+            let now_ms = crate::time::utc_now().timestamp_millis();
+            match driver::failure(&metadata.backoff_schedule, retry_count, self.max_delivery_attempts, now_ms) {
+                driver::Failure::Retry(deadline, remaining) => {
+                    retry(&mut tx, &self.tables, id, deadline, &metadata.retried(remaining), retry_count).await?;
+                }
+                driver::Failure::DeadLetter => {
+                    sqlx::query(sql(&self.tables, driver::DEAD_LETTER_MESSAGE)).bind(id).execute(&mut *tx).await?;
+                }
+                driver::Failure::Undelivered => {
+                    set_undelivered(&mut tx, &self.tables, &metadata.keys_if_undelivered, &payload).await?;
+                    sqlx::query(sql(&self.tables, driver::DELETE_MESSAGE)).bind(id).execute(&mut *tx).await?;
+                }
+            }
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl QueueMessageHandle for SqlxMessageHandle {
+    async fn finish(&self, success: bool) -> Result<(), JsErrorBox> {
+        self.finish(success).await.map_err(JsErrorBox::from_err)
+    }
+
+    async fn take_payload(&mut self) -> Result<Vec<u8>, JsErrorBox> {
+        self.payload.take()
+            .ok_or_else(|| JsErrorBox::from_err(PostgresError::InvalidData("Payload already taken".to_string())))
+    }
+}
@@ -0,0 +1,53 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+use std::time::Instant;
+
+/// Log target for sampled statement logs. Enable with
+/// `RUST_LOG=denokv_postgres::statements=trace`.
+pub const TARGET: &str = "denokv_postgres::statements";
+
+/// Samples executed statements and logs their name, parameter sizes,
+/// duration and row count at trace level. Parameter values are never
+/// logged.
+#[derive(Clone, Copy, Debug)]
+pub struct StatementLog {
+    sample_rate: f64,
+}
+
+impl StatementLog {
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            sample_rate: sample_rate.clamp(0.0, 1.0),
+        }
+    }
+
+    /// Start timing a statement. `param_sizes` is only evaluated when the
+    /// statement is sampled.
+    pub fn begin(
+        &self,
+        name: &'static str,
+        param_sizes: impl FnOnce() -> Vec<usize>,
+    ) -> StatementSample {
+        let sampled = self.sample_rate > 0.0
+            && log::log_enabled!(target: TARGET, log::Level::Trace)
+            && rand::random::<f64>() < self.sample_rate;
+        StatementSample(sampled.then(|| (name, param_sizes(), Instant::now())))
+    }
+}
+
+/// A statement in flight. Call `finish` with the affected or returned row
+/// count once it completes.
+#[must_use]
+pub struct StatementSample(Option<(&'static str, Vec<usize>, Instant)>);
+
+impl StatementSample {
+    pub fn finish(self, rows: u64) {
+        if let Some((name, param_sizes, start)) = self.0 {
+            log::trace!(
+                target: TARGET,
+                "{name} params={param_sizes:?} rows={rows} duration={:?}",
+                start.elapsed()
+            );
+        }
+    }
+}
@@ -0,0 +1,53 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+//! Keeping the planner's statistics of the busiest tables fresh.
+//!
+//! Autovacuum analyzes a table once a fraction of its rows changed, 10% by
+//! default, which on a table of a billion keys can take months of writes.
+//! Until then the planner, and estimates read from `pg_class`, work from
+//! outdated row counts and key distributions. Analyzing once a fixed number
+//! of rows changed keeps them close regardless of the table's size.
+
+use serde::Serialize;
+
+use crate::error::PostgresResult;
+use crate::pool::Client;
+use crate::tables::Tables;
+
+/// The tables whose statistics matter for reads and that see most writes.
+const ANALYZED_TABLES: &[&str] = &["kv_store", "kv_tombstones", "queue_messages"];
+
+/// A table that was analyzed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AnalyzedTable {
+    /// The table's name, qualified with its schema if one is configured.
+    pub table: String,
+    /// Rows inserted, updated or deleted since it was last analyzed, as
+    /// counted by the server.
+    pub modified_rows: i64,
+}
+
+/// Analyze the tables with at least `min_modified_rows` changed rows since
+/// they were last analyzed.
+pub async fn analyze_stale(conn: &Client, tables: &Tables, min_modified_rows: u64) -> PostgresResult<Vec<AnalyzedTable>> {
+    let names: Vec<String> = ANALYZED_TABLES.iter().map(|table| tables.sql(table).into_owned()).collect();
+    let min_modified_rows = i64::try_from(min_modified_rows).unwrap_or(i64::MAX);
+    let rows = conn.query(
+        r#"
+        SELECT t AS table_name, s.n_mod_since_analyze
+        FROM unnest($1::text[]) AS t
+        JOIN pg_stat_user_tables s ON s.relid = to_regclass(t)
+        WHERE s.n_mod_since_analyze >= $2
+        "#,
+        &[&names, &min_modified_rows],
+    ).await?;
+
+    let mut analyzed = Vec::with_capacity(rows.len());
+    for row in rows {
+        let table: String = row.get("table_name");
+        // The names are checked to be plain identifiers when configured.
+        conn.batch_execute(&format!("ANALYZE {table}")).await?;
+        analyzed.push(AnalyzedTable { table, modified_rows: row.get("n_mod_since_analyze") });
+    }
+    Ok(analyzed)
+}
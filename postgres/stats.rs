@@ -0,0 +1,111 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::circuit_breaker::CircuitState;
+use crate::error::PostgresResult;
+use crate::pool::{Client, ConnectionPool, Pool};
+
+/// Application name set on connections unless the URL specifies one. Used to
+/// find this process's connections in `pg_stat_activity`.
+pub const DEFAULT_APPLICATION_NAME: &str = "denokv";
+
+/// A snapshot of the connection pool.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct PoolStatus {
+    /// Maximum number of connections the pool will open.
+    pub max_size: usize,
+    /// Connections currently open.
+    pub size: usize,
+    /// Open connections checked out by a caller.
+    pub in_use: usize,
+    /// Open connections idle in the pool.
+    pub available: usize,
+    /// Callers waiting for a connection.
+    pub waiting: usize,
+}
+
+impl PoolStatus {
+    pub fn from_pool(pool: &Pool) -> Self {
+        ConnectionPool::status(pool)
+    }
+}
+
+/// Information about the PostgreSQL server the pool is connected to.
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerInfo {
+    /// The `server_version` setting, e.g. `15.4`.
+    pub server_version: String,
+    /// Whether the server is a standby replaying WAL.
+    pub in_recovery: bool,
+    /// Connections in `pg_stat_activity` with this pool's application name.
+    pub connections: i64,
+    /// On a standby, time since the last replayed transaction was committed
+    /// on the primary. On a primary, the largest replay lag reported by its
+    /// replicas. `None` if there is nothing to measure.
+    pub replication_lag: Option<Duration>,
+}
+
+impl ServerInfo {
+    pub async fn query(conn: &Client) -> PostgresResult<Self> {
+        let row = conn.query_one(
+            r#"
+            SELECT
+                current_setting('server_version') AS server_version,
+                pg_is_in_recovery() AS in_recovery,
+                (SELECT count(*) FROM pg_stat_activity
+                 WHERE application_name = current_setting('application_name')) AS connections,
+                CASE WHEN pg_is_in_recovery()
+                    THEN EXTRACT(EPOCH FROM now() - pg_last_xact_replay_timestamp())::float8
+                    ELSE (SELECT EXTRACT(EPOCH FROM max(replay_lag))::float8 FROM pg_stat_replication)
+                END AS replication_lag
+            "#,
+            &[],
+        ).await?;
+
+        let replication_lag: Option<f64> = row.get("replication_lag");
+        Ok(Self {
+            server_version: row.get("server_version"),
+            in_recovery: row.get("in_recovery"),
+            connections: row.get("connections"),
+            replication_lag: replication_lag.map(|secs| Duration::from_secs_f64(secs.max(0.0))),
+        })
+    }
+}
+
+/// The outcome of [`crate::Postgres::health`].
+#[derive(Debug, Clone, Serialize)]
+pub struct Health {
+    /// Whether the check query succeeded and no circuit breaker is open.
+    pub healthy: bool,
+    /// Round trip time of the check query, if it succeeded.
+    pub latency: Option<Duration>,
+    /// Why the check query failed.
+    pub error: Option<String>,
+    pub pool: PoolStatus,
+    pub circuits: CircuitStates,
+    /// The schema version recorded in the database, if the check query
+    /// succeeded. Higher than [`crate::SCHEMA_VERSION`] once a newer release
+    /// upgraded the schema.
+    pub schema_version: Option<u32>,
+    /// The replication lag of each read replica as last measured, `None`
+    /// for those that could not be measured. Empty without read replicas.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub replica_lags: Vec<Option<Duration>>,
+}
+
+/// The state of each circuit breaker.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct CircuitStates {
+    pub read: CircuitState,
+    pub write: CircuitState,
+    pub queue: CircuitState,
+}
+
+impl CircuitStates {
+    pub fn any_open(&self) -> bool {
+        [self.read, self.write, self.queue].contains(&CircuitState::Open)
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+//! Partitioning `kv_store` within its database, for keyspaces too large to
+//! index and vacuum as one table.
+//!
+//! Where [`PartitionedPostgres`](crate::PartitionedPostgres) spreads key
+//! prefixes over clusters, [`TablePartitioning`] makes `kv_store` a
+//! partitioned table, each partition a table of its own named
+//! `kv_store_p<n>`. Statements keep naming `kv_store`: PostgreSQL routes
+//! every row to its partition and leaves out the partitions a read can not
+//! touch. The key is both the partition key and the primary key, so
+//! upserts and checks work as on a plain table.
+//!
+//! The layout is chosen when the table is created, partitions included,
+//! since PostgreSQL can not repartition a table in place. An existing table
+//! is left as it is, with a warning if it is laid out otherwise than
+//! configured. Moving to another layout means copying the keys into tables
+//! with another [`table_prefix`](crate::PostgresConfig::table_prefix), for
+//! instance with [`Postgres::copy_to`](crate::Postgres::copy_to).
+//!
+//! From then on, the partition manager of
+//! [`Postgres::manage_partitions`](crate::Postgres::manage_partitions) can
+//! split the partitions that grow large and archive those that go cold.
+
+use serde::{Deserialize, Serialize};
+
+use crate::driver;
+use crate::tables::Tables;
+
+/// The most partitions `kv_store` may be split into.
+pub const MAX_TABLE_PARTITIONS: usize = 1024;
+
+/// How `kv_store` is laid out when it is created. See the
+/// [module documentation](self).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TablePartitioning {
+    /// One plain table.
+    #[default]
+    None,
+    /// `partitions` partitions by a hash of the key. Spreads the keys, and
+    /// the vacuuming, evenly, but every range read scans each partition.
+    Hash { partitions: u32 },
+    /// A partition of the keys before the first split point, one between
+    /// each pair of consecutive split points and one from the last on.
+    /// Split points are encoded keys or key prefixes, such as the encoding
+    /// of `["users"]`, in ascending order. Range reads only scan the
+    /// partitions they overlap.
+    Range { split_points: Vec<Vec<u8>> },
+}
+
+/// How an existing `kv_store` is laid out, as read by
+/// [`driver::KV_STORE_LAYOUT`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableLayout {
+    /// `h` or `r` for hash or range partitioned tables, `None` for plain
+    /// ones.
+    pub strategy: Option<String>,
+    pub partitions: i64,
+}
+
+impl TablePartitioning {
+    pub(crate) fn validate(&self, errors: &mut Vec<String>) {
+        match self {
+            Self::None => {}
+            Self::Hash { partitions } => {
+                if *partitions == 0 {
+                    errors.push("table_partitioning has 0 hash partitions; use none for a plain table".to_string());
+                } else if *partitions as usize > MAX_TABLE_PARTITIONS {
+                    errors.push(format!("table_partitioning has {partitions} hash partitions, at most {MAX_TABLE_PARTITIONS} are allowed"));
+                }
+            }
+            Self::Range { split_points } => {
+                if split_points.is_empty() {
+                    errors.push("table_partitioning has no split points; use none for a plain table".to_string());
+                } else if split_points.len() >= MAX_TABLE_PARTITIONS {
+                    errors.push(format!(
+                        "table_partitioning has {} split points, at most {} are allowed",
+                        split_points.len(),
+                        MAX_TABLE_PARTITIONS - 1,
+                    ));
+                }
+                if split_points.first().is_some_and(Vec::is_empty) {
+                    errors.push("table_partitioning.split_points[0] is empty, so the first partition would hold no key".to_string());
+                }
+                for (i, pair) in split_points.windows(2).enumerate() {
+                    if pair[0] >= pair[1] {
+                        errors.push(format!("table_partitioning.split_points[{}] does not come after the one before it", i + 1));
+                    }
+                }
+            }
+        }
+    }
+
+    /// The number of partitions of the layout, 0 for a plain table.
+    pub fn partitions(&self) -> usize {
+        match self {
+            Self::None => 0,
+            Self::Hash { partitions } => *partitions as usize,
+            Self::Range { split_points } => split_points.len() + 1,
+        }
+    }
+
+    fn strategy(&self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::Hash { .. } => Some("h"),
+            Self::Range { .. } => Some("r"),
+        }
+    }
+
+    /// The statements creating `kv_store` with its partitions, to be run
+    /// in one transaction before [`driver::SCHEMA`], given how the table is
+    /// laid out if it exists. None if it is a plain table or exists
+    /// already, after warning if it is laid out otherwise.
+    pub fn statements(&self, tables: &Tables, existing: Option<TableLayout>) -> Vec<String> {
+        let Some(strategy) = self.strategy() else {
+            return Vec::new();
+        };
+        let table = tables.sql("kv_store");
+        if let Some(layout) = existing {
+            // The partition manager only ever adds partitions.
+            if layout.strategy.as_deref() != Some(strategy) || layout.partitions < self.partitions() as i64 {
+                let existing = match layout.strategy.as_deref() {
+                    Some("h") => format!("hash partitioned into {}", layout.partitions),
+                    Some("r") => format!("range partitioned into {}", layout.partitions),
+                    _ => "not partitioned".to_string(),
+                };
+                log::warn!(
+                    "{table} is {existing}, not partitioned as configured by table_partitioning; \
+                     the layout only applies when the table is created"
+                );
+            }
+            return Vec::new();
+        }
+
+        let method = if strategy == "h" { "HASH" } else { "RANGE" };
+        let mut statements = vec![format!("{} PARTITION BY {method} (key)", tables.sql(driver::CREATE_KV_STORE.trim_end()))];
+        let partitions = self.partitions();
+        for i in 0..partitions {
+            let bounds = match self {
+                Self::Range { split_points } => {
+                    let from = i.checked_sub(1).map_or("MINVALUE".to_string(), |i| bytea(&split_points[i]));
+                    let to = split_points.get(i).map_or("MAXVALUE".to_string(), |point| bytea(point));
+                    format!("FROM ({from}) TO ({to})")
+                }
+                _ => format!("WITH (MODULUS {partitions}, REMAINDER {i})"),
+            };
+            statements.push(format!("CREATE TABLE IF NOT EXISTS {table}_p{i} PARTITION OF {table} FOR VALUES {bounds}"));
+        }
+        statements
+    }
+}
+
+/// `bytes` as a BYTEA literal, whatever `standard_conforming_strings` is.
+pub(crate) fn bytea(bytes: &[u8]) -> String {
+    format!("E'\\\\x{}'", hex::encode(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PostgresConfig;
+
+    fn tables() -> Tables {
+        let config = PostgresConfig { schema: Some("tenant".to_string()), table_prefix: "app_".to_string(), ..Default::default() };
+        Tables::new(&config)
+    }
+
+    #[test]
+    fn creates_partitions() {
+        let statements = TablePartitioning::Hash { partitions: 2 }.statements(&tables(), None);
+        assert_eq!(statements.len(), 3);
+        assert!(statements[0].starts_with("\n    CREATE TABLE IF NOT EXISTS tenant.app_kv_store ("));
+        assert!(statements[0].ends_with(") PARTITION BY HASH (key)"));
+        assert_eq!(
+            statements[2],
+            "CREATE TABLE IF NOT EXISTS tenant.app_kv_store_p1 PARTITION OF tenant.app_kv_store FOR VALUES WITH (MODULUS 2, REMAINDER 1)"
+        );
+
+        let range = TablePartitioning::Range { split_points: vec![b"\x02m".to_vec(), b"\x02t".to_vec()] };
+        let bounds: Vec<_> = range.statements(&tables(), None).iter().skip(1).map(|s| s.split(" FOR VALUES ").nth(1).unwrap().to_string()).collect();
+        assert_eq!(bounds, [r"FROM (MINVALUE) TO (E'\\x026d')", r"FROM (E'\\x026d') TO (E'\\x0274')", r"FROM (E'\\x0274') TO (MAXVALUE)"]);
+    }
+
+    #[test]
+    fn leaves_existing_tables() {
+        let layout = TableLayout { strategy: None, partitions: 0 };
+        assert!(TablePartitioning::Hash { partitions: 2 }.statements(&tables(), Some(layout)).is_empty());
+        assert!(TablePartitioning::None.statements(&tables(), None).is_empty());
+    }
+
+    #[test]
+    fn validates() {
+        let mut errors = Vec::new();
+        TablePartitioning::Hash { partitions: 0 }.validate(&mut errors);
+        TablePartitioning::Range { split_points: vec![] }.validate(&mut errors);
+        TablePartitioning::Range { split_points: vec![b"b".to_vec(), b"a".to_vec()] }.validate(&mut errors);
+        TablePartitioning::Range { split_points: vec![vec![]] }.validate(&mut errors);
+        assert_eq!(errors.len(), 4, "{errors:?}");
+        errors.clear();
+        TablePartitioning::Range { split_points: vec![b"a".to_vec(), b"b".to_vec()] }.validate(&mut errors);
+        assert!(errors.is_empty(), "{errors:?}");
+    }
+}
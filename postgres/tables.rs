@@ -0,0 +1,185 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+//! Where the tables live. Statements are written against the plain table
+//! names; [`Tables::sql`] moves them into [`PostgresConfig::schema`] and
+//! prepends [`PostgresConfig::table_prefix`], so that several instances can
+//! share one database.
+
+use std::borrow::Cow;
+
+use crate::config::PostgresConfig;
+
+/// The tables created by [`crate::driver::SCHEMA`]. Index names starting
+/// with `idx_` are prefixed too, since indexes share a namespace with
+/// tables.
+const TABLES: &[&str] = &[
+    "kv_store",
+    "queue_messages",
+    "queue_running",
+    "data_version",
+    "kv_tombstones",
+    "subscriptions",
+    "materialized_views",
+    "materialized_view_keys",
+    "bulk_import_journal",
+    "backfill_jobs",
+    "queue_dead_letter",
+    "namespace_deletions",
+];
+
+/// The longest name of a table or index, `idx_kv_tombstones_versionstamp`.
+const LONGEST_NAME: usize = 30;
+
+/// The channel changes are notified on, before prefixing.
+const CHANNEL: &str = "denokv_changes";
+
+/// The channel queue messages are notified on, before prefixing. No longer
+/// than [`CHANNEL`], so that names valid for one are valid for the other.
+const QUEUE_CHANNEL: &str = "denokv_queue";
+
+/// PostgreSQL truncates longer identifiers.
+const MAX_IDENTIFIER_LEN: usize = 63;
+
+#[derive(Debug, Clone, Default)]
+pub struct Tables {
+    schema: Option<String>,
+    prefix: String,
+}
+
+impl Tables {
+    pub fn new(config: &PostgresConfig) -> Self {
+        Self {
+            schema: config.schema.clone(),
+            prefix: config.table_prefix.clone(),
+        }
+    }
+
+    pub fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
+    /// `statement` with the tables and indexes it names moved to where they
+    /// are configured to be. Returned as is with the default configuration.
+    pub fn sql<'a>(&self, statement: &'a str) -> Cow<'a, str> {
+        if self.schema.is_none() && self.prefix.is_empty() {
+            return Cow::Borrowed(statement);
+        }
+        let mut sql = String::with_capacity(statement.len() + 64);
+        let mut rest = statement;
+        while let Some(start) = rest.find(is_identifier_char) {
+            let len = rest[start..].find(|c| !is_identifier_char(c)).unwrap_or(rest.len() - start);
+            let word = &rest[start..start + len];
+            sql.push_str(&rest[..start]);
+            if TABLES.contains(&word) {
+                if let Some(schema) = &self.schema {
+                    sql.push_str(schema);
+                    sql.push('.');
+                }
+                sql.push_str(&self.prefix);
+            } else if word.starts_with("idx_") {
+                // Indexes are created in the schema of their table and can
+                // not be qualified.
+                sql.push_str(&self.prefix);
+            }
+            sql.push_str(word);
+            rest = &rest[start + len..];
+        }
+        sql.push_str(rest);
+        Cow::Owned(sql)
+    }
+
+    /// The channel changes are notified on, distinct for every schema and
+    /// prefix so that instances sharing a database do not wake each other.
+    pub fn channel(&self) -> String {
+        self.prefixed(CHANNEL)
+    }
+
+    /// The channel on which messages that were enqueued or rescheduled are
+    /// notified, distinct for every schema and prefix like [`Self::channel`].
+    pub fn queue_channel(&self) -> String {
+        self.prefixed(QUEUE_CHANNEL)
+    }
+
+    fn prefixed(&self, channel: &str) -> String {
+        match &self.schema {
+            Some(schema) => format!("{schema}_{}{channel}", self.prefix),
+            None => format!("{}{channel}", self.prefix),
+        }
+    }
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Check that `schema` and `prefix` can be spliced into statements unquoted
+/// and keep every name within PostgreSQL's identifier length.
+pub fn check_names(schema: Option<&str>, prefix: &str, errors: &mut Vec<String>) {
+    let valid = |name: &str| {
+        name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+            && !name.starts_with(|c: char| c.is_ascii_digit())
+    };
+    if let Some(schema) = schema {
+        if schema.is_empty() || !valid(schema) {
+            errors.push(format!("schema {schema:?} must be a lowercase identifier of letters, digits and underscores"));
+        } else if schema.len() > MAX_IDENTIFIER_LEN {
+            errors.push(format!("schema is {} bytes, PostgreSQL allows {MAX_IDENTIFIER_LEN}", schema.len()));
+        }
+    }
+    if !valid(prefix) {
+        errors.push(format!("table_prefix {prefix:?} must be lowercase letters, digits and underscores"));
+    }
+    let channel = Tables { schema: schema.map(str::to_string), prefix: prefix.to_string() }.channel();
+    let longest = (prefix.len() + LONGEST_NAME).max(channel.len());
+    if longest > MAX_IDENTIFIER_LEN {
+        errors.push(format!(
+            "table_prefix is too long: names would be up to {longest} bytes, PostgreSQL allows {MAX_IDENTIFIER_LEN}"
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tables(schema: Option<&str>, prefix: &str) -> Tables {
+        Tables { schema: schema.map(str::to_string), prefix: prefix.to_string() }
+    }
+
+    #[test]
+    fn moves_tables_and_indexes() {
+        let statement = "CREATE INDEX IF NOT EXISTS idx_kv_expires_at ON kv_store(expires_at)";
+        assert!(matches!(tables(None, "").sql(statement), Cow::Borrowed(_)));
+        assert_eq!(
+            tables(Some("tenant"), "app_").sql(statement),
+            "CREATE INDEX IF NOT EXISTS app_idx_kv_expires_at ON tenant.app_kv_store(expires_at)"
+        );
+        assert_eq!(
+            tables(None, "app_").sql("SELECT 1 FROM queue_running r WHERE NOT EXISTS (SELECT 1 FROM queue_messages m WHERE m.id = r.message_id)"),
+            "SELECT 1 FROM app_queue_running r WHERE NOT EXISTS (SELECT 1 FROM app_queue_messages m WHERE m.id = r.message_id)"
+        );
+        // Columns that merely contain a table name are left alone.
+        assert_eq!(tables(None, "app_").sql("SELECT data_version_x, kv_store"), "SELECT data_version_x, app_kv_store");
+        assert_eq!(
+            tables(Some("tenant"), "").sql("SELECT 'kv_store'::regclass"),
+            "SELECT 'tenant.kv_store'::regclass"
+        );
+    }
+
+    #[test]
+    fn channels() {
+        assert_eq!(tables(None, "").channel(), "denokv_changes");
+        assert_eq!(tables(Some("tenant"), "app_").channel(), "tenant_app_denokv_changes");
+        assert_eq!(tables(Some("tenant"), "app_").queue_channel(), "tenant_app_denokv_queue");
+    }
+
+    #[test]
+    fn checks_names() {
+        let mut errors = Vec::new();
+        check_names(Some("tenant_1"), "app_", &mut errors);
+        assert!(errors.is_empty(), "{errors:?}");
+        check_names(Some("Tenant"), "app-", &mut errors);
+        check_names(None, &"x".repeat(40), &mut errors);
+        assert_eq!(errors.len(), 3, "{errors:?}");
+    }
+}
@@ -1,9 +1,13 @@
 // Copyright 2023 rawkakani. All rights reserved. MIT license.
 
-use denokv_postgres::{Postgres, PostgresConfig};
+use denokv_postgres::{
+    BackfillJob, BackfillProgress, Durability, PartitionedPostgres, Postgres, PostgresConfig, PostgresError, PrefixUsage, Pressure, QueueDepth, TablePartitioning, WriteLimit, WriteLimits, WriteProgress,
+};
 use denokv_proto::{
-    AtomicWrite, Check, Database, KvValue, Mutation, MutationKind, ReadRange, SnapshotReadOptions,
+    AtomicWrite, Check, Consistency, Database, KvValue, Mutation, MutationKind, ReadRange,
+    SnapshotReadOptions,
 };
+use futures::{StreamExt, TryStreamExt};
 use std::num::NonZeroU32;
 
 #[tokio::test]
@@ -161,4 +165,3316 @@ async fn test_postgres_sum_operations() {
         KvValue::U64(value) => assert_eq!(*value, 15),
         _ => panic!("Expected U64 value"),
     }
-}
\ No newline at end of file
+}
+
+#[tokio::test]
+async fn test_postgres_empty_end_reads_to_end_of_keyspace() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url);
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+
+    // Keys under 0xfe 0xfe sort after everything the other tests write.
+    let keys: Vec<Vec<u8>> = vec![
+        vec![0xfe, 0xfe, 0x01],
+        vec![0xfe, 0xfe, 0x80],
+        vec![0xfe, 0xfe, 0xff, 0x00],
+    ];
+    let atomic_write = AtomicWrite {
+        checks: vec![],
+        mutations: keys
+            .iter()
+            .map(|key| Mutation {
+                key: key.clone(),
+                kind: MutationKind::Set(KvValue::Bytes(b"v".to_vec())),
+                expire_at: None,
+            })
+            .collect(),
+        enqueues: vec![],
+    };
+    postgres.atomic_write(atomic_write).await.expect("Atomic write failed");
+
+    let options = SnapshotReadOptions {
+        consistency: denokv_proto::Consistency::Strong,
+    };
+    for reverse in [false, true] {
+        let read_range = ReadRange {
+            start: vec![0xfe, 0xfe],
+            end: vec![],
+            limit: NonZeroU32::new(10).unwrap(),
+            reverse,
+        };
+        let results = postgres
+            .snapshot_read(vec![read_range], options.clone())
+            .await
+            .expect("Snapshot read failed");
+        let mut read_keys: Vec<Vec<u8>> = results[0].entries.iter().map(|e| e.key.clone()).collect();
+        if reverse {
+            read_keys.reverse();
+        }
+        assert_eq!(read_keys, keys);
+    }
+}
+
+#[tokio::test]
+async fn test_postgres_pool_status_and_server_info() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url).with_max_connections(4);
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+
+    let status = postgres.pool_status();
+    assert_eq!(status.max_size, 4);
+    assert_eq!(status.waiting, 0);
+    assert!(status.in_use <= status.size);
+
+    let info = postgres.server_info().await.expect("server_info failed");
+    assert!(!info.server_version.is_empty());
+    assert!(info.connections >= 1);
+
+    let health = postgres.health().await;
+    assert!(health.healthy, "{:?}", health.error);
+    assert!(health.latency.is_some());
+    assert!(!health.circuits.any_open());
+    assert!(health.schema_version >= Some(denokv_postgres::SCHEMA_VERSION));
+    assert!(health.replica_lags.is_empty());
+
+    #[cfg(feature = "metrics")]
+    {
+        let metrics = postgres.metrics();
+        let max_connections = metrics
+            .iter()
+            .find(|family| family.get_name() == "denokv_postgres_pool_max_connections")
+            .expect("pool metrics are exported");
+        assert_eq!(max_connections.get_metric()[0].get_gauge().get_value(), 4.0);
+    }
+}
+
+#[tokio::test]
+async fn test_postgres_read_range_stream() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url);
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+
+    // Enough rows to span several network buffers.
+    let keys: Vec<Vec<u8>> = (0u16..300)
+        .map(|i| [&[0xfd, 0x01][..], &i.to_be_bytes()].concat())
+        .collect();
+    let atomic_write = AtomicWrite {
+        checks: vec![],
+        mutations: keys
+            .iter()
+            .map(|key| Mutation {
+                key: key.clone(),
+                kind: MutationKind::Set(KvValue::Bytes(key.clone())),
+                expire_at: None,
+            })
+            .collect(),
+        enqueues: vec![],
+    };
+    postgres.atomic_write(atomic_write).await.expect("Atomic write failed");
+
+    let read_range = ReadRange {
+        start: vec![0xfd, 0x01],
+        end: vec![0xfd, 0x02],
+        limit: NonZeroU32::new(1000).unwrap(),
+        reverse: false,
+    };
+    let entries: Vec<_> = postgres
+        .read_range_stream(read_range)
+        .try_collect()
+        .await
+        .expect("Streamed read failed");
+    assert_eq!(entries.len(), keys.len());
+    for (entry, key) in entries.iter().zip(&keys) {
+        assert_eq!(&entry.key, key);
+        assert!(matches!(&entry.value, KvValue::Bytes(v) if v == key));
+    }
+}
+
+#[tokio::test]
+async fn test_postgres_sheds_when_pool_is_exhausted() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url)
+        .with_max_connections(1)
+        .with_pool_wait_timeout(1);
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+
+    let key = vec![0xfd, 0x03];
+    let atomic_write = AtomicWrite {
+        checks: vec![],
+        mutations: vec![Mutation {
+            key: key.clone(),
+            kind: MutationKind::Set(KvValue::U64(1)),
+            expire_at: None,
+        }],
+        enqueues: vec![],
+    };
+    postgres.atomic_write(atomic_write).await.expect("Atomic write failed");
+
+    let read_range = || ReadRange {
+        start: key.clone(),
+        end: vec![0xfd, 0x04],
+        limit: NonZeroU32::new(1).unwrap(),
+        reverse: false,
+    };
+    // The stream holds the only connection until it is dropped.
+    let mut stream = Box::pin(postgres.read_range_stream(read_range()));
+    stream.next().await.unwrap().expect("Streamed read failed");
+
+    let err = postgres
+        .snapshot_read(vec![read_range()], SnapshotReadOptions { consistency: Consistency::Strong })
+        .await
+        .expect_err("the read should be shed");
+    let err = err.get_inner_ref().and_then(|e| e.downcast_ref::<PostgresError>());
+    assert!(
+        matches!(err, Some(PostgresError::Overloaded { pressure: Pressure::PoolExhausted, .. })),
+        "{err:?}"
+    );
+
+    drop(stream);
+    postgres
+        .snapshot_read(vec![read_range()], SnapshotReadOptions { consistency: Consistency::Strong })
+        .await
+        .expect("the read should succeed once the connection is back");
+}
+
+#[tokio::test]
+async fn test_postgres_bulk_import_batches_and_resumes() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url).with_bulk_import_batch_size(4);
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+
+    let import_id = format!(
+        "test-{}",
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+    );
+    let mutations: Vec<Mutation> = (0u8..10)
+        .map(|i| Mutation {
+            key: vec![0xfd, 0x02, i],
+            kind: MutationKind::Set(KvValue::U64(i as u64)),
+            expire_at: None,
+        })
+        .collect();
+
+    let report = postgres
+        .bulk_import(&import_id, &mutations)
+        .await
+        .expect("Bulk import failed");
+    assert_eq!(report.batches_committed, 3);
+
+    // Simulate an interrupted import that only got through the first batch.
+    let (client, connection) = tokio_postgres::connect(&std::env::var("POSTGRES_URL").unwrap(), tokio_postgres::NoTls)
+        .await
+        .expect("Failed to connect");
+    tokio::spawn(connection);
+    client
+        .execute("UPDATE bulk_import_journal SET committed = 4 WHERE import_id = $1", &[&import_id])
+        .await
+        .expect("Failed to rewind the journal");
+
+    let report = postgres
+        .bulk_import(&import_id, &mutations)
+        .await
+        .expect("Bulk import failed");
+    assert_eq!(report.resumed_from, 4);
+    assert_eq!(report.batches_committed, 2);
+    assert_eq!(report.mutations_applied, 6);
+
+    // A completed import is not applied again.
+    let report = postgres
+        .bulk_import(&import_id, &mutations)
+        .await
+        .expect("Bulk import failed");
+    assert_eq!(report.resumed_from, 10);
+    assert_eq!(report.batches_committed, 0);
+
+    // Resuming with other mutations would skip the wrong ones.
+    let err = postgres
+        .bulk_import(&import_id, &mutations[..4])
+        .await
+        .expect_err("Bulk import with other mutations should fail");
+    assert!(matches!(err, PostgresError::BulkImportMismatch(_)), "{err}");
+
+    let read_range = ReadRange {
+        start: vec![0xfd, 0x02],
+        end: vec![0xfd, 0x03],
+        limit: NonZeroU32::new(100).unwrap(),
+        reverse: false,
+    };
+    let entries: Vec<_> = postgres
+        .read_range_stream(read_range)
+        .try_collect()
+        .await
+        .expect("Streamed read failed");
+    assert_eq!(entries.len(), 10);
+    // Each batch commits with its own versionstamp.
+    assert_ne!(entries[0].versionstamp, entries[9].versionstamp);
+    assert_eq!(entries[4].versionstamp, entries[7].versionstamp);
+}
+
+#[tokio::test]
+async fn test_postgres_eventual_reads_use_replicas() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    // One unreachable replica and one that is really the primary: reads
+    // must keep succeeding while the bad replica gets quarantined.
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url.clone())
+        .with_read_replica("postgresql://postgres@127.0.0.1:1/denokv".to_string())
+        .with_read_replica(postgres_url);
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+
+    let key = vec![0xfd, 0x03];
+    let atomic_write = AtomicWrite {
+        checks: vec![],
+        mutations: vec![Mutation {
+            key: key.clone(),
+            kind: MutationKind::Set(KvValue::U64(7)),
+            expire_at: None,
+        }],
+        enqueues: vec![],
+    };
+    postgres.atomic_write(atomic_write).await.expect("Atomic write failed");
+
+    let options = SnapshotReadOptions {
+        consistency: denokv_proto::Consistency::Eventual,
+    };
+    for _ in 0..10 {
+        let read_range = ReadRange {
+            start: key.clone(),
+            end: vec![0xfd, 0x04],
+            limit: NonZeroU32::new(1).unwrap(),
+            reverse: false,
+        };
+        let results = postgres
+            .snapshot_read(vec![read_range], options.clone())
+            .await
+            .expect("Snapshot read failed");
+        assert_eq!(results[0].entries.len(), 1);
+    }
+
+    // Neither is a standby, so neither has a lag to report.
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert_eq!(postgres.replica_lags(), [None, None]);
+}
+
+#[tokio::test]
+async fn test_postgres_partitioned_routing() {
+    // Needs a second database to act as the partition's cluster
+    let (Ok(postgres_url), Ok(partition_url)) = (
+        std::env::var("POSTGRES_URL"),
+        std::env::var("POSTGRES_PARTITION_URL"),
+    ) else {
+        println!("Skipping partition test - POSTGRES_URL or POSTGRES_PARTITION_URL not set");
+        return;
+    };
+
+    let prefix = vec![0xfd, 0x05];
+    let config = PostgresConfig::new(postgres_url)
+        .with_partition(prefix.clone(), partition_url.clone());
+    let partitioned = PartitionedPostgres::new(config)
+        .await
+        .expect("Failed to create partitioned instance");
+    let partition = Postgres::new(PostgresConfig::new(partition_url))
+        .await
+        .expect("Failed to open partition");
+
+    let set = |key: Vec<u8>| Mutation {
+        key,
+        kind: MutationKind::Set(KvValue::U64(1)),
+        expire_at: None,
+    };
+    let inside = vec![0xfd, 0x05, 0x01];
+    let before = vec![0xfd, 0x04, 0x01];
+    let after = vec![0xfd, 0x06, 0x01];
+
+    // A write touching two partitions is rejected.
+    let result = partitioned
+        .atomic_write(AtomicWrite {
+            checks: vec![],
+            mutations: vec![set(inside.clone()), set(before.clone())],
+            enqueues: vec![],
+        })
+        .await;
+    assert!(result.is_err());
+
+    for key in [&inside, &before, &after] {
+        partitioned
+            .atomic_write(AtomicWrite {
+                checks: vec![],
+                mutations: vec![set(key.clone())],
+                enqueues: vec![],
+            })
+            .await
+            .expect("Atomic write failed");
+    }
+
+    let options = SnapshotReadOptions {
+        consistency: denokv_proto::Consistency::Strong,
+    };
+    let range = |start: Vec<u8>, end: Vec<u8>, reverse| ReadRange {
+        start,
+        end,
+        limit: NonZeroU32::new(10).unwrap(),
+        reverse,
+    };
+
+    // The partitioned key only lives in the partition's cluster.
+    let results = partition
+        .snapshot_read(vec![range(prefix.clone(), vec![0xfd, 0x06], false)], options.clone())
+        .await
+        .expect("Snapshot read failed");
+    assert_eq!(results[0].entries.len(), 1);
+    assert_eq!(results[0].entries[0].key, inside);
+
+    // A range across partitions is merged in key order.
+    for reverse in [false, true] {
+        let results = partitioned
+            .snapshot_read(vec![range(before.clone(), vec![0xfd, 0x07], reverse)], options.clone())
+            .await
+            .expect("Snapshot read failed");
+        let mut keys: Vec<Vec<u8>> = results[0].entries.iter().map(|e| e.key.clone()).collect();
+        if reverse {
+            keys.reverse();
+        }
+        assert_eq!(keys, vec![before.clone(), inside.clone(), after.clone()]);
+    }
+}
+
+#[tokio::test]
+async fn test_postgres_watch_prefix() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url);
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+
+    let watch = postgres.watch_prefix(vec![0xfd, 0x07]);
+    futures::pin_mut!(watch);
+    watch.next().await.expect("initial notification");
+
+    let atomic_write = AtomicWrite {
+        checks: vec![],
+        mutations: vec![Mutation {
+            key: vec![0xfd, 0x07, 0x01],
+            kind: MutationKind::Set(KvValue::U64(1)),
+            expire_at: None,
+        }],
+        enqueues: vec![],
+    };
+    postgres.atomic_write(atomic_write).await.expect("Atomic write failed");
+
+    tokio::time::timeout(std::time::Duration::from_secs(5), watch.next())
+        .await
+        .expect("no notification for a key under the prefix")
+        .expect("watch stream ended");
+}
+
+#[tokio::test]
+async fn test_postgres_subscription_resumes_after_restart() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url);
+    let postgres = Postgres::new(config.clone()).await.expect("Failed to create PostgreSQL instance");
+
+    let name = format!(
+        "test-{}",
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+    );
+    let prefix = vec![0xfd, 0x08];
+    postgres
+        .register_subscription(&name, &prefix)
+        .await
+        .expect("Failed to register subscription");
+
+    let set = |suffix: u8| AtomicWrite {
+        checks: vec![],
+        mutations: vec![Mutation {
+            key: vec![0xfd, 0x08, suffix],
+            kind: MutationKind::Set(KvValue::U64(suffix as u64)),
+            expire_at: None,
+        }],
+        enqueues: vec![],
+    };
+    postgres.atomic_write(set(1)).await.expect("Atomic write failed");
+    postgres.atomic_write(set(2)).await.expect("Atomic write failed");
+
+    let mut stream = Box::pin(postgres.subscription_stream(name.clone(), prefix.clone()));
+    assert_eq!(next_key(&mut stream).await, vec![0xfd, 0x08, 1]);
+    assert_eq!(next_key(&mut stream).await, vec![0xfd, 0x08, 2]);
+    // Asking for more acknowledges everything delivered so far.
+    let more = tokio::time::timeout(std::time::Duration::from_millis(200), stream.next()).await;
+    assert!(more.is_err());
+    drop(stream);
+    drop(postgres);
+
+    // Changes made while no consumer is running are picked up on restart,
+    // without replaying what was already delivered.
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+    postgres.atomic_write(set(3)).await.expect("Atomic write failed");
+    let mut stream = Box::pin(postgres.subscription_stream(name.clone(), prefix));
+    assert_eq!(next_key(&mut stream).await, vec![0xfd, 0x08, 3]);
+    drop(stream);
+
+    assert!(postgres.delete_subscription(&name).await.expect("Failed to delete subscription"));
+}
+
+async fn next_key(
+    stream: &mut (impl futures::Stream<Item = denokv_postgres::PostgresResult<denokv_proto::KvEntry>> + Unpin),
+) -> Vec<u8> {
+    tokio::time::timeout(std::time::Duration::from_secs(5), stream.next())
+        .await
+        .expect("no entry delivered")
+        .expect("subscription stream ended")
+        .expect("subscription stream failed")
+        .key
+}
+
+#[tokio::test]
+async fn test_postgres_sample_keys() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url);
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+
+    let atomic_write = AtomicWrite {
+        checks: vec![],
+        mutations: (0u8..50)
+            .map(|i| Mutation {
+                key: vec![0xfd, 0x09, i],
+                kind: MutationKind::Set(KvValue::Bytes(vec![i; 10])),
+                expire_at: None,
+            })
+            .collect(),
+        enqueues: vec![],
+    };
+    postgres.atomic_write(atomic_write).await.expect("Atomic write failed");
+
+    let report = postgres
+        .sample_keys(&[0xfd, 0x09], 5)
+        .await
+        .expect("Sampling failed");
+    assert_eq!(report.samples.len(), 5);
+    for sample in &report.samples {
+        assert!(sample.key.starts_with(&[0xfd, 0x09]));
+        assert!(sample.size > 10);
+    }
+    if report.fraction_scanned == 1.0 {
+        assert_eq!(report.estimated_keys, 50);
+    }
+}
+
+#[tokio::test]
+async fn test_postgres_prefix_usage() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url);
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+
+    let write = |kind: fn(u8) -> MutationKind| AtomicWrite {
+        checks: vec![],
+        mutations: (0u8..20)
+            .map(|i| Mutation { key: vec![0xfd, 0x1e, i], kind: kind(i), expire_at: None })
+            .collect(),
+        enqueues: vec![],
+    };
+    postgres
+        .atomic_write(write(|i| MutationKind::Set(KvValue::Bytes(vec![i; 10]))))
+        .await
+        .expect("Atomic write failed");
+    // Expired keys do not count, even before they are swept.
+    postgres
+        .atomic_write(AtomicWrite {
+            checks: vec![],
+            mutations: vec![Mutation {
+                key: vec![0xfd, 0x1e, 0xff],
+                kind: MutationKind::Set(KvValue::Bytes(vec![0; 10])),
+                expire_at: Some(denokv_proto::time::utc_now() - chrono::Duration::seconds(1)),
+            }],
+            enqueues: vec![],
+        })
+        .await
+        .expect("Atomic write failed");
+
+    let usage = postgres.prefix_usage(&[0xfd, 0x1e]).await.expect("Counting failed");
+    assert_eq!(usage, PrefixUsage { keys: 20, bytes: 20 * (3 + 10) });
+
+    postgres.atomic_write(write(|_| MutationKind::Delete)).await.expect("Atomic write failed");
+    let usage = postgres.prefix_usage(&[0xfd, 0x1e]).await.expect("Counting failed");
+    assert_eq!(usage, PrefixUsage::default());
+}
+
+#[tokio::test]
+async fn test_postgres_ephemeral_namespaces_are_isolated() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url);
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+
+    let first = postgres.ephemeral_namespace();
+    let second = postgres.ephemeral_namespace();
+    let set = |value: u64| AtomicWrite {
+        checks: vec![],
+        mutations: vec![Mutation {
+            key: vec![0x02, b'k', 0x00],
+            kind: MutationKind::Set(KvValue::U64(value)),
+            expire_at: None,
+        }],
+        enqueues: vec![],
+    };
+    first.atomic_write(set(1)).await.expect("Atomic write failed");
+    second.atomic_write(set(2)).await.expect("Atomic write failed");
+
+    let read_all = ReadRange {
+        start: vec![],
+        end: vec![0xff],
+        limit: NonZeroU32::new(10).unwrap(),
+        reverse: false,
+    };
+    let options = SnapshotReadOptions {
+        consistency: denokv_proto::Consistency::Strong,
+    };
+    for (namespace, expected) in [(&first, 1), (&second, 2)] {
+        let output = namespace
+            .snapshot_read(vec![read_all.clone()], options.clone())
+            .await
+            .expect("Snapshot read failed");
+        assert_eq!(output[0].entries.len(), 1);
+        assert_eq!(output[0].entries[0].key, vec![0x02, b'k', 0x00]);
+        assert!(matches!(output[0].entries[0].value, KvValue::U64(v) if v == expected));
+    }
+
+    let prefix_range = |prefix: &[u8]| ReadRange {
+        start: prefix.to_vec(),
+        end: [prefix, &[0xff]].concat(),
+        limit: NonZeroU32::new(10).unwrap(),
+        reverse: false,
+    };
+    let first_range = prefix_range(first.prefix());
+    let second_range = prefix_range(second.prefix());
+
+    let survivor = first.clone();
+    first.discard().await.expect("Failed to discard namespace");
+    let output = postgres
+        .snapshot_read(vec![first_range.clone()], options.clone())
+        .await
+        .expect("Snapshot read failed");
+    assert!(output[0].entries.is_empty());
+    // A clone that outlives the discard keeps working.
+    survivor.atomic_write(set(3)).await.expect("Atomic write failed");
+
+    // Dropping the last handle cleans up in the background, including what
+    // was stored after a discard.
+    drop(second);
+    drop(survivor);
+    let mut remaining = usize::MAX;
+    for _ in 0..50 {
+        let output = postgres
+            .snapshot_read(vec![first_range.clone(), second_range.clone()], options.clone())
+            .await
+            .expect("Snapshot read failed");
+        remaining = output.iter().map(|output| output.entries.len()).sum();
+        if remaining == 0 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(remaining, 0);
+}
+
+#[tokio::test]
+async fn test_postgres_local_replica_follows_changes() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url);
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+
+    let cache = denokv_sqlite::Sqlite::new(
+        || {
+            let conn = rusqlite::Connection::open_in_memory()
+                .map_err(|e| deno_error::JsErrorBox::generic(e.to_string()))?;
+            Ok((conn, Box::new(<rand::rngs::StdRng as rand::SeedableRng>::from_entropy()) as _))
+        },
+        Default::default(),
+        denokv_sqlite::SqliteConfig { num_workers: 1, batch_timeout: None },
+    )
+    .expect("Failed to open SQLite cache");
+    let replica = postgres.local_replica(cache);
+
+    let key = [&[0xfd, 0x0a][..], uuid::Uuid::new_v4().as_bytes()].concat();
+    let write = |kind| AtomicWrite {
+        checks: vec![],
+        mutations: vec![Mutation { key: key.clone(), kind, expire_at: None }],
+        enqueues: vec![],
+    };
+    let read = ReadRange {
+        start: key.clone(),
+        end: key.iter().copied().chain(Some(0)).collect(),
+        limit: NonZeroU32::new(1).unwrap(),
+        reverse: false,
+    };
+    let eventual = SnapshotReadOptions {
+        consistency: denokv_proto::Consistency::Eventual,
+    };
+    let read_eventual = || async {
+        replica
+            .snapshot_read(vec![read.clone()], eventual.clone())
+            .await
+            .expect("Snapshot read failed")
+            .remove(0)
+            .entries
+    };
+
+    let commit = replica
+        .atomic_write(write(MutationKind::Set(KvValue::U64(7))))
+        .await
+        .expect("Atomic write failed")
+        .expect("Write was not committed");
+
+    // The replica keeps the versionstamps assigned by PostgreSQL.
+    let mut entries = Vec::new();
+    for _ in 0..100 {
+        entries = read_eventual().await;
+        if replica.is_caught_up() && !entries.is_empty() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    assert!(replica.is_caught_up());
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].versionstamp, commit.versionstamp);
+    assert!(matches!(entries[0].value, KvValue::U64(7)));
+
+    replica.atomic_write(write(MutationKind::Delete)).await.expect("Atomic write failed");
+    for _ in 0..100 {
+        entries = read_eventual().await;
+        if entries.is_empty() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    assert!(entries.is_empty());
+
+    replica.close();
+}
+
+#[tokio::test]
+async fn test_postgres_pubsub() {
+    use denokv_proto::pubsub::{Delivery, PubSub};
+    use std::time::Duration;
+
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let postgres = Postgres::new(PostgresConfig::new(postgres_url))
+        .await
+        .expect("Failed to create PostgreSQL instance");
+    let pubsub = PubSub::new(postgres.clone());
+    let channel = [&[0xfd, 0x0b][..], uuid::Uuid::new_v4().as_bytes()].concat();
+
+    // Messages published before subscribing are not delivered, so publish
+    // until the subscription is up.
+    let mut subscription = Box::pin(pubsub.subscribe(&channel));
+    let publish = async {
+        loop {
+            pubsub
+                .publish(&channel, b"hello".to_vec(), Delivery::AtMostOnce)
+                .await
+                .expect("Publish failed");
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    };
+    let message = tokio::time::timeout(Duration::from_secs(10), async {
+        tokio::select! {
+            message = subscription.next() => message,
+            _ = publish => unreachable!(),
+        }
+    })
+    .await
+    .expect("No message received")
+    .unwrap()
+    .expect("Subscription failed");
+    assert_eq!(message.payload, b"hello");
+
+    // Queued messages go only to listeners of their channel.
+    let other = [&channel[..], b"-other"].concat();
+    let mut listener = Box::pin(pubsub.listen(vec![channel.clone()]));
+    pubsub
+        .publish(&other, b"elsewhere".to_vec(), Delivery::AtLeastOnce)
+        .await
+        .expect("Publish failed");
+    pubsub
+        .publish(&channel, b"job".to_vec(), Delivery::AtLeastOnce)
+        .await
+        .expect("Publish failed");
+    let message = tokio::time::timeout(Duration::from_secs(10), listener.next())
+        .await
+        .expect("No message received")
+        .unwrap()
+        .expect("Listen failed");
+    assert_eq!(message.channel, channel);
+    assert_eq!(message.payload, b"job");
+    message.ack().await.expect("Ack failed");
+}
+
+#[tokio::test]
+async fn test_postgres_materialized_view() {
+    use denokv_postgres::MaterializedView;
+
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url);
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+
+    // Users by id, indexed by email.
+    let run = uuid::Uuid::new_v4();
+    let users = [&[0xfd, 0x0b][..], run.as_bytes()].concat();
+    let by_email = [&[0xfd, 0x0c][..], run.as_bytes()].concat();
+    let view = MaterializedView::new(format!("test-{run}"), users.clone(), by_email.clone(), |entry| {
+        match &entry.value {
+            KvValue::Bytes(email) => vec![(email.clone(), KvValue::Bytes(entry.key.clone()))],
+            _ => vec![],
+        }
+    });
+
+    let user = |id: u8| [users.as_slice(), &[id]].concat();
+    let set_email = |id: u8, email: &str| AtomicWrite {
+        checks: vec![],
+        mutations: vec![Mutation {
+            key: user(id),
+            kind: MutationKind::Set(KvValue::Bytes(email.as_bytes().to_vec())),
+            expire_at: None,
+        }],
+        enqueues: vec![],
+    };
+    let index = || async {
+        let read = ReadRange {
+            start: by_email.clone(),
+            end: [by_email.as_slice(), &[0xff]].concat(),
+            limit: NonZeroU32::new(100).unwrap(),
+            reverse: false,
+        };
+        let options = SnapshotReadOptions { consistency: Consistency::Strong };
+        postgres
+            .snapshot_read(vec![read], options)
+            .await
+            .expect("Snapshot read failed")
+            .remove(0)
+            .entries
+            .into_iter()
+            .map(|entry| {
+                let email = String::from_utf8(entry.key[by_email.len()..].to_vec()).unwrap();
+                let KvValue::Bytes(id) = entry.value else { panic!("unexpected value") };
+                (email, id)
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // Entries written before the view exists are indexed when it is built.
+    postgres.atomic_write(set_email(1, "ada@example.com")).await.expect("Atomic write failed");
+    postgres.atomic_write(set_email(2, "bob@example.com")).await.expect("Atomic write failed");
+    assert_eq!(postgres.refresh_view(&view).await.expect("Refresh failed"), 2);
+    assert_eq!(
+        index().await,
+        vec![("ada@example.com".to_string(), user(1)), ("bob@example.com".to_string(), user(2))]
+    );
+    assert_eq!(postgres.refresh_view(&view).await.expect("Refresh failed"), 0);
+
+    // Changing or deleting a source key replaces what it derived.
+    postgres.atomic_write(set_email(1, "ada@example.org")).await.expect("Atomic write failed");
+    let delete = AtomicWrite {
+        checks: vec![],
+        mutations: vec![Mutation { key: user(2), kind: MutationKind::Delete, expire_at: None }],
+        enqueues: vec![],
+    };
+    postgres.atomic_write(delete).await.expect("Atomic write failed");
+    assert_eq!(postgres.refresh_view(&view).await.expect("Refresh failed"), 2);
+    assert_eq!(index().await, vec![("ada@example.org".to_string(), user(1))]);
+
+    // A maintainer picks up later writes in the background.
+    let maintainer = postgres.maintain_view(view.clone());
+    postgres.atomic_write(set_email(3, "cy@example.com")).await.expect("Atomic write failed");
+    let mut entries = Vec::new();
+    for _ in 0..100 {
+        entries = index().await;
+        if entries.len() == 2 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    assert_eq!(entries[1], ("cy@example.com".to_string(), user(3)));
+    drop(maintainer);
+
+    // A view over its own output would feed itself.
+    let looping = MaterializedView::new(format!("test-{run}-loop"), users.clone(), user(1), |_| vec![]);
+    assert!(matches!(postgres.refresh_view(&looping).await, Err(PostgresError::InvalidConfig(_))));
+
+    assert!(postgres.delete_view(view.name()).await.expect("Delete failed"));
+    assert!(index().await.is_empty());
+    assert!(!postgres.delete_view(view.name()).await.expect("Delete failed"));
+}
+
+#[tokio::test]
+async fn test_postgres_workflow() {
+    use async_trait::async_trait;
+    use deno_error::JsErrorBox;
+    use denokv_proto::workflow::{RunStatus, Step, Workflow, WorkflowEngine};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url);
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+    // A queue of its own, apart from the other tests.
+    let namespace = postgres.ephemeral_namespace();
+
+    // The state of an order: whether shipping fails, and what was done.
+    type Order = (bool, Vec<String>);
+
+    struct Reserve;
+    #[async_trait]
+    impl Step<Order> for Reserve {
+        fn name(&self) -> &str {
+            "reserve"
+        }
+        async fn run(&self, (_, log): &mut Order) -> Result<(), JsErrorBox> {
+            log.push("reserve".to_string());
+            Ok(())
+        }
+        async fn compensate(&self, (_, log): &mut Order) -> Result<(), JsErrorBox> {
+            log.push("release".to_string());
+            Ok(())
+        }
+    }
+
+    /// Fails its first attempt.
+    struct Charge(Arc<AtomicU32>);
+    #[async_trait]
+    impl Step<Order> for Charge {
+        fn name(&self) -> &str {
+            "charge"
+        }
+        async fn run(&self, (_, log): &mut Order) -> Result<(), JsErrorBox> {
+            log.push("charge".to_string());
+            if self.0.fetch_add(1, Ordering::SeqCst) == 0 {
+                return Err(JsErrorBox::generic("payment service unavailable"));
+            }
+            Ok(())
+        }
+        async fn compensate(&self, (_, log): &mut Order) -> Result<(), JsErrorBox> {
+            log.push("refund".to_string());
+            Ok(())
+        }
+    }
+
+    struct Ship;
+    #[async_trait]
+    impl Step<Order> for Ship {
+        fn name(&self) -> &str {
+            "ship"
+        }
+        async fn run(&self, (fail, log): &mut Order) -> Result<(), JsErrorBox> {
+            if *fail {
+                return Err(JsErrorBox::generic("address not found"));
+            }
+            log.push("ship".to_string());
+            Ok(())
+        }
+    }
+
+    let workflow = Workflow::new("orders")
+        .step(Reserve)
+        .step(Charge(Arc::new(AtomicU32::new(0))))
+        .step(Ship)
+        .max_attempts(2)
+        .retry_backoff(std::time::Duration::from_millis(10));
+    let engine = WorkflowEngine::new(namespace.clone(), vec![0x02, b'o', 0x00], workflow);
+
+    assert!(engine.start("ok", (false, vec![])).await.expect("Start failed"));
+    assert!(engine.start("lost", (true, vec![])).await.expect("Start failed"));
+    assert!(!engine.start("ok", (false, vec![])).await.expect("Start failed"));
+
+    let done = |status| matches!(status, RunStatus::Completed | RunStatus::Failed);
+    for _ in 0..200 {
+        let ok = engine.get("ok").await.expect("Get failed").unwrap();
+        let lost = engine.get("lost").await.expect("Get failed").unwrap();
+        if done(ok.status) && done(lost.status) {
+            break;
+        }
+        if !engine.process_next().await.expect("Processing failed") {
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+    }
+
+    // The failed attempt at charging left no trace in the state.
+    let ok = engine.get("ok").await.expect("Get failed").unwrap();
+    assert_eq!(ok.status, RunStatus::Completed);
+    assert_eq!(ok.state.1, ["reserve", "charge", "ship"]);
+
+    let lost = engine.get("lost").await.expect("Get failed").unwrap();
+    assert_eq!(lost.status, RunStatus::Failed);
+    assert_eq!(lost.state.1, ["reserve", "charge", "refund", "release"]);
+    assert_eq!(lost.error.as_deref(), Some("step \"ship\" failed: address not found"));
+
+    namespace.discard().await.expect("Discard failed");
+}
+
+#[tokio::test]
+async fn test_postgres_watch_across_instances() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let writer = Postgres::new(PostgresConfig::new(postgres_url.clone()).with_listen_for_changes(false))
+        .await
+        .expect("Failed to create PostgreSQL instance");
+    let watcher = Postgres::new(PostgresConfig::new(postgres_url))
+        .await
+        .expect("Failed to create PostgreSQL instance");
+
+    let watch = watcher.watch_prefix(vec![0xfd, 0x0d]);
+    futures::pin_mut!(watch);
+    watch.next().await.expect("initial notification");
+
+    let set = |keys: Vec<Vec<u8>>| AtomicWrite {
+        checks: vec![],
+        mutations: keys
+            .into_iter()
+            .map(|key| Mutation { key, kind: MutationKind::Set(KvValue::U64(1)), expire_at: None })
+            .collect(),
+        enqueues: vec![],
+    };
+    let timeout = std::time::Duration::from_secs(5);
+
+    // Woken once listening, or by the write if it was already.
+    writer.atomic_write(set(vec![vec![0xfd, 0x0d, 0x01]])).await.expect("Atomic write failed");
+    tokio::time::timeout(timeout, watch.next()).await.expect("no notification of the first write").expect("watch stream ended");
+
+    // One key under the prefix among others.
+    writer
+        .atomic_write(set(vec![vec![0xfd, 0x0e, 0x01], vec![0xfd, 0x0d, 0x02], vec![0xfd, 0x0e, 0x02]]))
+        .await
+        .expect("Atomic write failed");
+    tokio::time::timeout(timeout, watch.next()).await.expect("no notification of a batch").expect("watch stream ended");
+
+    // Too many keys for one payload.
+    let keys = (0..1000u32).map(|i| [&[0xfd, 0x0e][..], &i.to_be_bytes()].concat()).collect();
+    writer.atomic_write(set(keys)).await.expect("Atomic write failed");
+    tokio::time::timeout(timeout, watch.next()).await.expect("no notification of a resync").expect("watch stream ended");
+}
+
+#[tokio::test]
+async fn test_postgres_index_advice() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url.clone()).with_record_range_scans(true);
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+
+    let prefix = vec![0xfd, 0x0f];
+    for reverse in [false, true, false] {
+        let scan = ReadRange {
+            start: [&prefix[..], &[0x00]].concat(),
+            end: [&prefix[..], &[0xff]].concat(),
+            limit: NonZeroU32::new(10).unwrap(),
+            reverse,
+        };
+        postgres
+            .snapshot_read(vec![scan], SnapshotReadOptions { consistency: Consistency::Strong })
+            .await
+            .expect("Snapshot read failed");
+    }
+
+    let shapes = postgres.range_scan_shapes();
+    assert_eq!(shapes.len(), 2);
+    assert_eq!((shapes[0].prefix.as_slice(), shapes[0].reverse, shapes[0].scans), (&prefix[..], false, 2));
+    assert!(postgres.index_advice(4).is_empty());
+    let advice = postgres.index_advice(3);
+    assert_eq!(advice.len(), 1);
+    assert_eq!(advice[0].prefix, prefix);
+
+    // The suggested DDL is valid.
+    let (client, connection) = tokio_postgres::connect(&postgres_url, tokio_postgres::NoTls)
+        .await
+        .expect("Failed to connect");
+    tokio::spawn(connection);
+    client.batch_execute(&advice[0].ddl).await.expect("Suggested DDL failed");
+    let name = advice[0].ddl.split_whitespace().nth(6).expect("index name");
+    client.batch_execute(&format!("DROP INDEX {name}")).await.expect("Failed to drop the index");
+}
+
+#[tokio::test]
+async fn test_postgres_write_progress() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url).with_bulk_import_batch_size(150);
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+
+    let mutations: Vec<Mutation> = (0u16..250)
+        .map(|i| Mutation {
+            key: [&[0xfd, 0x10][..], &i.to_be_bytes()].concat(),
+            kind: MutationKind::Set(KvValue::Bytes(vec![0; 6])),
+            expire_at: None,
+        })
+        .collect();
+
+    let reports = std::sync::Mutex::new(Vec::new());
+    let record = |progress: WriteProgress| reports.lock().unwrap().push(progress);
+    let write = AtomicWrite { checks: vec![], mutations: mutations.clone(), enqueues: vec![] };
+    postgres.atomic_write_with_progress(write, &record).await.expect("Atomic write failed");
+    let progress: Vec<_> = std::mem::take(&mut *reports.lock().unwrap())
+        .iter()
+        .map(|p| (p.mutations_applied, p.mutations_committed))
+        .collect();
+    assert_eq!(progress, [(100, 0), (200, 0), (250, 250)]);
+
+    let import_id = format!("progress-{}", uuid::Uuid::new_v4());
+    postgres
+        .bulk_import_with_progress(&import_id, &mutations, Some(&record))
+        .await
+        .expect("Bulk import failed");
+    let reports = reports.into_inner().unwrap();
+    let progress: Vec<_> = reports.iter().map(|p| (p.mutations_applied, p.mutations_committed)).collect();
+    assert_eq!(progress, [(100, 0), (150, 150), (200, 150), (250, 250)]);
+    let last = reports.last().unwrap();
+    assert_eq!(last.mutations_total, 250);
+    // Four key bytes and six value bytes per mutation.
+    assert_eq!(last.bytes_written, 250 * 10);
+}
+
+#[tokio::test]
+async fn test_postgres_read_repair() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    // A "replica" whose kv_store is a copy in another schema that never
+    // receives writes.
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let (client, connection) = tokio_postgres::connect(&postgres_url, tokio_postgres::NoTls)
+        .await
+        .expect("Failed to connect");
+    tokio::spawn(connection);
+    let postgres = Postgres::new(PostgresConfig::new(postgres_url.clone()))
+        .await
+        .expect("Failed to create PostgreSQL instance");
+    let schema = format!("stale_replica_{}", uuid::Uuid::new_v4().simple());
+    client
+        .batch_execute(&format!(
+            "CREATE SCHEMA {schema}; CREATE TABLE {schema}.kv_store (LIKE public.kv_store INCLUDING ALL)"
+        ))
+        .await
+        .expect("Failed to create the replica schema");
+    let separator = if postgres_url.contains('?') { '&' } else { '?' };
+    let replica_url = format!("{postgres_url}{separator}options=-c%20search_path%3D{schema}");
+
+    let key = [&[0xfd, 0x11][..], uuid::Uuid::new_v4().as_bytes()].concat();
+    let set = |value| AtomicWrite {
+        checks: vec![],
+        mutations: vec![Mutation { key: key.clone(), kind: MutationKind::Set(KvValue::U64(value)), expire_at: None }],
+        enqueues: vec![],
+    };
+    let read = || ReadRange {
+        start: key.clone(),
+        end: [&key[..], &[0]].concat(),
+        limit: NonZeroU32::new(1).unwrap(),
+        reverse: false,
+    };
+    let eventual = SnapshotReadOptions { consistency: Consistency::Eventual };
+
+    let config = PostgresConfig::new(postgres_url.clone()).with_read_replica(replica_url.clone());
+    let unrepaired = Postgres::new(config.clone()).await.expect("Failed to create PostgreSQL instance");
+    let repaired = Postgres::new(config.with_read_repair_window(60))
+        .await
+        .expect("Failed to create PostgreSQL instance");
+
+    repaired.atomic_write(set(1)).await.expect("Atomic write failed");
+    unrepaired.atomic_write(set(1)).await.expect("Atomic write failed");
+    let results = unrepaired.snapshot_read(vec![read()], eventual.clone()).await.expect("Snapshot read failed");
+    assert!(results[0].entries.is_empty(), "the replica is stale");
+    let results = repaired.snapshot_read(vec![read()], eventual.clone()).await.expect("Snapshot read failed");
+    assert!(matches!(results[0].entries[0].value, KvValue::U64(1)));
+
+    // Another process writes; copy an older version to the replica.
+    let entry = postgres.snapshot_read(vec![read()], SnapshotReadOptions { consistency: Consistency::Strong })
+        .await
+        .expect("Snapshot read failed")
+        .remove(0)
+        .entries
+        .remove(0);
+    client
+        .execute(&format!("INSERT INTO {schema}.kv_store SELECT * FROM public.kv_store WHERE key = $1"), &[&key])
+        .await
+        .expect("Failed to copy the key");
+    postgres.atomic_write(set(2)).await.expect("Atomic write failed");
+    // The repaired instance saw version 1 only, which the replica has.
+    let results = repaired.snapshot_read(vec![read()], eventual.clone()).await.expect("Snapshot read failed");
+    assert_eq!(results[0].entries[0].versionstamp, entry.versionstamp);
+    // Once it has seen version 2, it no longer accepts version 1.
+    repaired.snapshot_read(vec![read()], SnapshotReadOptions { consistency: Consistency::Strong })
+        .await
+        .expect("Snapshot read failed");
+    let results = repaired.snapshot_read(vec![read()], eventual).await.expect("Snapshot read failed");
+    assert!(matches!(results[0].entries[0].value, KvValue::U64(2)));
+
+    #[cfg(feature = "metrics")]
+    {
+        let stale_reads = repaired
+            .metrics()
+            .into_iter()
+            .find(|family| family.get_name() == "denokv_postgres_stale_replica_reads_total")
+            .expect("stale read metric")
+            .get_metric()
+            .iter()
+            .map(|metric| metric.get_counter().get_value())
+            .sum::<f64>();
+        assert_eq!(stale_reads, 2.0);
+    }
+
+    client.batch_execute(&format!("DROP SCHEMA {schema} CASCADE")).await.expect("Failed to drop the replica schema");
+}
+
+#[tokio::test]
+async fn test_postgres_watch_sees_each_mutation() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url).with_listen_for_changes(false);
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+
+    let id = uuid::Uuid::new_v4();
+    let keys: Vec<Vec<u8>> = (0u8..3).map(|i| [&[0xfd, 0x12][..], id.as_bytes(), &[i]].concat()).collect();
+    let write = |key: &Vec<u8>, kind| AtomicWrite {
+        checks: vec![],
+        mutations: vec![Mutation { key: key.clone(), kind, expire_at: None }],
+        enqueues: vec![],
+    };
+    postgres.atomic_write(write(&keys[1], MutationKind::Set(KvValue::U64(1)))).await.expect("Atomic write failed");
+
+    let watch = postgres.watch(keys.clone());
+    futures::pin_mut!(watch);
+    watch.next().await.expect("initial values").expect("watch failed");
+
+    let sum = MutationKind::Sum { value: KvValue::U64(5), min_v8: vec![], max_v8: vec![], clamp: false };
+    for (key, kind) in [
+        (&keys[0], MutationKind::Set(KvValue::U64(7))),
+        (&keys[1], MutationKind::Delete),
+        (&keys[2], sum),
+    ] {
+        postgres.atomic_write(write(key, kind)).await.expect("Atomic write failed");
+        // Only one of the keys changed, and the change is seen without the
+        // other keys changing too.
+        let outputs = tokio::time::timeout(std::time::Duration::from_secs(1), watch.next())
+            .await
+            .expect("no notification of the change")
+            .expect("watch stream ended")
+            .expect("watch failed");
+        assert_eq!(outputs.len(), 3);
+    }
+    // Nothing else changed, so nothing else is yielded.
+    assert!(tokio::time::timeout(std::time::Duration::from_millis(200), watch.next()).await.is_err());
+
+    let values: Vec<Option<u64>> = postgres
+        .snapshot_read(
+            keys.iter()
+                .map(|key| ReadRange {
+                    start: key.clone(),
+                    end: [&key[..], &[0]].concat(),
+                    limit: NonZeroU32::new(1).unwrap(),
+                    reverse: false,
+                })
+                .collect(),
+            SnapshotReadOptions { consistency: Consistency::Strong },
+        )
+        .await
+        .expect("Snapshot read failed")
+        .into_iter()
+        .map(|output| {
+            output.entries.first().map(|entry| match entry.value {
+                KvValue::U64(value) => value,
+                _ => panic!("Expected a U64 value"),
+            })
+        })
+        .collect();
+    assert_eq!(values, [Some(7), None, Some(5)]);
+}
+
+#[tokio::test]
+async fn test_postgres_collect_expired_in_batches() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url.clone()).with_expiry_sweep(0, 2);
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+
+    let prefix = [&[0xfd, 0x13][..], uuid::Uuid::new_v4().as_bytes()].concat();
+    let expired = denokv_proto::time::utc_now() - chrono::Duration::seconds(1);
+    let live = denokv_proto::time::utc_now() + chrono::Duration::hours(1);
+    let mutations = (0u8..6)
+        .map(|i| Mutation {
+            key: [&prefix[..], &[i]].concat(),
+            kind: MutationKind::Set(KvValue::U64(i as u64)),
+            expire_at: Some(if i < 5 { expired } else { live }),
+        })
+        .collect();
+    postgres
+        .atomic_write(AtomicWrite { checks: vec![], mutations, enqueues: vec![] })
+        .await
+        .expect("Atomic write failed");
+
+    // Other tests may leave expired keys behind too.
+    assert!(postgres.collect_expired().await.expect("Failed to collect expired keys") >= 5);
+
+    let (client, connection) = tokio_postgres::connect(&postgres_url, tokio_postgres::NoTls)
+        .await
+        .expect("Failed to connect");
+    tokio::spawn(connection);
+    let rows = client
+        .query("SELECT key FROM kv_store WHERE key >= $1 AND key < $2", &[&prefix, &[&prefix[..], &[0xff]].concat()])
+        .await
+        .expect("Failed to query kv_store");
+    let keys: Vec<Vec<u8>> = rows.iter().map(|row| row.get(0)).collect();
+    assert_eq!(keys, [[&prefix[..], &[5]].concat()]);
+
+    // Closing stops the sweeper and other background tasks.
+    postgres.close();
+}
+
+#[tokio::test]
+async fn test_postgres_snapshot_read_many_ranges() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url).with_expiry_sweep(0, 1000);
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+
+    let prefix = [&[0xfd, 0x16][..], uuid::Uuid::new_v4().as_bytes()].concat();
+    let key = |i: u8| [&prefix[..], &[i]].concat();
+    let expired = denokv_proto::time::utc_now() - chrono::Duration::seconds(1);
+    let mutations = (0u8..6)
+        .map(|i| Mutation {
+            key: key(i),
+            kind: MutationKind::Set(KvValue::U64(i as u64)),
+            expire_at: (i == 3).then_some(expired),
+        })
+        .collect();
+    postgres
+        .atomic_write(AtomicWrite { checks: vec![], mutations, enqueues: vec![] })
+        .await
+        .expect("Atomic write failed");
+
+    let range = |start: u8, end: u8, limit: u32, reverse: bool| ReadRange {
+        start: key(start),
+        end: key(end),
+        limit: NonZeroU32::new(limit).unwrap(),
+        reverse,
+    };
+    let outputs = postgres
+        .snapshot_read(
+            vec![
+                range(0, 6, 10, false),
+                range(0, 6, 2, true),
+                range(3, 4, 1, false),
+                range(1, 2, 1, false),
+                ReadRange { start: key(4), end: vec![0xff], limit: NonZeroU32::new(2).unwrap(), reverse: false },
+            ],
+            SnapshotReadOptions { consistency: Consistency::Strong },
+        )
+        .await
+        .expect("Snapshot read failed");
+    let keys: Vec<Vec<u8>> = outputs
+        .iter()
+        .map(|output| output.entries.iter().map(|entry| *entry.key.last().unwrap()).collect())
+        .collect();
+    assert_eq!(keys, [vec![0, 1, 2, 4, 5], vec![5, 4], vec![], vec![1], vec![4, 5]]);
+}
+
+#[tokio::test]
+async fn test_postgres_from_pool() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let pg_config: tokio_postgres::Config = postgres_url.parse().expect("Invalid POSTGRES_URL");
+    #[cfg(not(feature = "pool-bb8"))]
+    let pool = {
+        let manager = deadpool_postgres::Manager::new(pg_config, tokio_postgres::NoTls);
+        deadpool_postgres::Pool::builder(manager).max_size(3).build().expect("Failed to build pool")
+    };
+    #[cfg(feature = "pool-bb8")]
+    let pool = {
+        let manager = denokv_postgres::PgConnectionManager::new(pg_config);
+        denokv_postgres::Bb8Pool::new(bb8::Pool::builder().max_size(3).build_unchecked(manager), 3)
+    };
+
+    let config = PostgresConfig::new(postgres_url).with_max_connections(50);
+    let postgres = Postgres::from_pool(pool.clone(), config).await.expect("Failed to create PostgreSQL instance");
+
+    let key = [&[0xfd, 0x14][..], uuid::Uuid::new_v4().as_bytes()].concat();
+    postgres
+        .atomic_write(AtomicWrite {
+            checks: vec![],
+            mutations: vec![Mutation { key: key.clone(), kind: MutationKind::Set(KvValue::U64(1)), expire_at: None }],
+            enqueues: vec![],
+        })
+        .await
+        .expect("Atomic write failed");
+    let outputs = postgres
+        .snapshot_read(
+            vec![ReadRange {
+                start: key.clone(),
+                end: [&key[..], &[0]].concat(),
+                limit: NonZeroU32::new(1).unwrap(),
+                reverse: false,
+            }],
+            SnapshotReadOptions { consistency: Consistency::Strong },
+        )
+        .await
+        .expect("Snapshot read failed");
+    assert_eq!(outputs[0].entries.len(), 1);
+
+    // The application's pool is used, with its own limit.
+    let status = pool.status();
+    assert!(status.size > 0);
+    assert_eq!(status.max_size, 3);
+
+    // Invalid settings are still rejected.
+    let invalid = PostgresConfig::new(std::env::var("POSTGRES_URL").unwrap()).with_statement_timeout(0);
+    assert!(matches!(Postgres::from_pool(pool, invalid).await, Err(PostgresError::InvalidConfig(_))));
+}
+
+#[tokio::test]
+async fn test_postgres_schema_and_table_prefix_isolate_instances() {
+    use denokv_proto::Enqueue;
+
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let shared = Postgres::new(PostgresConfig::new(postgres_url.clone()))
+        .await
+        .expect("Failed to create PostgreSQL instance");
+    let tenant_config = PostgresConfig::new(postgres_url)
+        .with_schema("denokv_tenant_test".to_string())
+        .with_table_prefix("t1_".to_string());
+    let tenant = Postgres::new(tenant_config).await.expect("Failed to create tenant instance");
+
+    let key = [&[0xfd, 0x17][..], uuid::Uuid::new_v4().as_bytes()].concat();
+    let set = |value| AtomicWrite {
+        checks: vec![],
+        mutations: vec![Mutation { key: key.clone(), kind: MutationKind::Set(KvValue::U64(value)), expire_at: None }],
+        enqueues: vec![],
+    };
+    let read = || {
+        vec![ReadRange {
+            start: key.clone(),
+            end: [&key[..], &[0]].concat(),
+            limit: NonZeroU32::new(1).unwrap(),
+            reverse: false,
+        }]
+    };
+    let strong = || SnapshotReadOptions { consistency: Consistency::Strong };
+
+    tenant.atomic_write(set(1)).await.expect("Atomic write failed");
+    let outputs = shared.snapshot_read(read(), strong()).await.expect("Snapshot read failed");
+    assert!(outputs[0].entries.is_empty());
+
+    shared.atomic_write(set(2)).await.expect("Atomic write failed");
+    let outputs = tenant.snapshot_read(read(), strong()).await.expect("Snapshot read failed");
+    assert!(matches!(outputs[0].entries[0].value, KvValue::U64(1)));
+
+    // Queues are separate too.
+    tenant
+        .atomic_write(AtomicWrite {
+            checks: vec![],
+            mutations: vec![],
+            enqueues: vec![Enqueue {
+                payload: key.clone(),
+                deadline: denokv_proto::time::utc_now(),
+                keys_if_undelivered: vec![],
+                backoff_schedule: None,
+            }],
+        })
+        .await
+        .expect("Atomic write failed");
+    let mut message = tenant.dequeue_next_message().await.expect("Dequeue failed").expect("no message");
+    assert_eq!(message.take_payload().await.expect("no payload"), key);
+    message.finish(true).await.expect("Finish failed");
+}
+
+#[tokio::test]
+async fn test_postgres_failed_message_sets_keys_if_undelivered() {
+    use denokv_proto::Enqueue;
+
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let postgres = Postgres::new(PostgresConfig::new(postgres_url))
+        .await
+        .expect("Failed to create PostgreSQL instance");
+    let namespace = postgres.ephemeral_namespace();
+
+    let (delivered, expiring) = (vec![0x02, b'a', 0x00], vec![0x02, b'b', 0x00]);
+    let before = namespace
+        .atomic_write(AtomicWrite {
+            checks: vec![],
+            mutations: vec![Mutation {
+                key: expiring.clone(),
+                kind: MutationKind::Set(KvValue::U64(1)),
+                expire_at: Some(denokv_proto::time::utc_now() + chrono::Duration::hours(1)),
+            }],
+            enqueues: vec![Enqueue {
+                payload: b"payload".to_vec(),
+                deadline: denokv_proto::time::utc_now(),
+                keys_if_undelivered: vec![delivered.clone(), expiring.clone()],
+                backoff_schedule: Some(vec![]),
+            }],
+        })
+        .await
+        .expect("Atomic write failed")
+        .expect("Atomic write was rejected");
+
+    let message = namespace.dequeue_next_message().await.expect("Dequeue failed").expect("no message");
+    message.finish(false).await.expect("Finish failed");
+
+    let read = |key: &Vec<u8>| ReadRange {
+        start: key.clone(),
+        end: [&key[..], &[0]].concat(),
+        limit: NonZeroU32::new(1).unwrap(),
+        reverse: false,
+    };
+    let outputs = namespace
+        .snapshot_read(vec![read(&delivered), read(&expiring)], SnapshotReadOptions { consistency: Consistency::Strong })
+        .await
+        .expect("Snapshot read failed");
+    let entries: Vec<_> = outputs.iter().map(|output| &output.entries[0]).collect();
+    assert!(entries.iter().all(|entry| matches!(&entry.value, KvValue::V8(payload) if payload == b"payload")));
+    assert_eq!(entries[0].versionstamp, entries[1].versionstamp);
+    assert!(entries[0].versionstamp > before.versionstamp);
+    namespace.discard().await.expect("Failed to discard namespace");
+}
+
+#[tokio::test]
+async fn test_postgres_upgrades_legacy_queue_metadata() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let (client, connection) = tokio_postgres::connect(&postgres_url, tokio_postgres::NoTls)
+        .await
+        .expect("Failed to connect");
+    tokio::spawn(connection);
+    let config = PostgresConfig::new(postgres_url).with_table_prefix("metadata_test_".to_string());
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+    client
+        .batch_execute("DELETE FROM metadata_test_queue_running; DELETE FROM metadata_test_queue_messages")
+        .await
+        .expect("Failed to clear the queue");
+
+    // A message as enqueued before the metadata column existed.
+    let undelivered = [&[0xfd, 0x28][..], uuid::Uuid::new_v4().as_bytes()].concat();
+    client
+        .execute(
+            "INSERT INTO metadata_test_queue_messages (payload, deadline, keys_if_undelivered, backoff_schedule) VALUES ($1, 0, $2, $3)",
+            &[&b"payload".to_vec(), &vec![undelivered.clone()], &vec![0i32]],
+        )
+        .await
+        .expect("Failed to enqueue");
+
+    let message = postgres.dequeue_next_message().await.expect("Dequeue failed").expect("no message");
+    message.finish(false).await.expect("Finish failed");
+    let row = client
+        .query_one("SELECT metadata IS NOT NULL, keys_if_undelivered IS NULL FROM metadata_test_queue_messages", &[])
+        .await
+        .expect("the message should be retried");
+    assert!(row.get::<_, bool>(0) && row.get::<_, bool>(1), "the retry should upgrade the metadata");
+
+    let message = postgres.dequeue_next_message().await.expect("Dequeue failed").expect("no message");
+    message.finish(false).await.expect("Finish failed");
+    let outputs = postgres
+        .snapshot_read(
+            vec![ReadRange {
+                start: undelivered.clone(),
+                end: [&undelivered[..], &[0]].concat(),
+                limit: NonZeroU32::new(1).unwrap(),
+                reverse: false,
+            }],
+            SnapshotReadOptions { consistency: Consistency::Strong },
+        )
+        .await
+        .expect("Snapshot read failed");
+    assert!(matches!(&outputs[0].entries[0].value, KvValue::V8(payload) if payload == b"payload"));
+}
+
+#[tokio::test]
+async fn test_postgres_wakes_waiting_consumers() {
+    use denokv_proto::Enqueue;
+    use std::time::{Duration, Instant};
+
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url).with_table_prefix("wakeup_test_".to_string());
+    let consumer = Postgres::new(config.clone()).await.expect("Failed to create PostgreSQL instance");
+    let producer = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+    while let Some(message) = consumer.dequeue_next_message().await.expect("Dequeue failed") {
+        message.finish(true).await.expect("Finish failed");
+    }
+    let enqueue = |delay: Duration| AtomicWrite {
+        checks: vec![],
+        mutations: vec![],
+        enqueues: vec![Enqueue {
+            payload: b"payload".to_vec(),
+            deadline: denokv_proto::time::utc_now() + chrono::Duration::from_std(delay).unwrap(),
+            keys_if_undelivered: vec![],
+            backoff_schedule: None,
+        }],
+    };
+
+    // Nothing to wait for.
+    let started = Instant::now();
+    consumer.wait_for_message(Duration::from_millis(200)).await.expect("Wait failed");
+    assert!(started.elapsed() >= Duration::from_millis(200));
+
+    // A message enqueued through another instance wakes the consumer.
+    let waiting = tokio::spawn({
+        let consumer = consumer.clone();
+        async move {
+            let started = Instant::now();
+            consumer.wait_for_message(Duration::from_secs(30)).await.expect("Wait failed");
+            started.elapsed()
+        }
+    });
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    producer.atomic_write(enqueue(Duration::ZERO)).await.expect("Atomic write failed");
+    assert!(waiting.await.unwrap() < Duration::from_secs(10));
+    let message = consumer.dequeue_next_message().await.expect("Dequeue failed").expect("no message");
+    message.finish(true).await.expect("Finish failed");
+
+    // A scheduled message wakes it once due.
+    producer.atomic_write(enqueue(Duration::from_secs(1))).await.expect("Atomic write failed");
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let started = Instant::now();
+    consumer.wait_for_message(Duration::from_secs(30)).await.expect("Wait failed");
+    assert!(started.elapsed() >= Duration::from_millis(500) && started.elapsed() < Duration::from_secs(10));
+    let message = consumer.dequeue_next_message().await.expect("Dequeue failed").expect("no message");
+    message.finish(true).await.expect("Finish failed");
+}
+
+#[tokio::test]
+async fn test_postgres_checks_server_settings() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let (client, connection) = tokio_postgres::connect(&postgres_url, tokio_postgres::NoTls)
+        .await
+        .expect("Failed to connect");
+    tokio::spawn(connection);
+    let config = PostgresConfig::new(postgres_url)
+        .with_table_prefix("settings_test_".to_string())
+        .with_max_connections(100_000);
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+    client
+        .batch_execute("ALTER TABLE settings_test_queue_messages SET (autovacuum_enabled = false)")
+        .await
+        .expect("Failed to disable autovacuum");
+
+    let warnings = postgres.check_server_settings().await.expect("Failed to check settings");
+    let settings: Vec<&str> = warnings.iter().map(|warning| warning.setting.as_str()).collect();
+    assert!(settings.contains(&"max_connections"), "{warnings:?}");
+    assert!(settings.contains(&"settings_test_queue_messages.autovacuum_enabled"), "{warnings:?}");
+    assert!(!settings.iter().any(|setting| setting.starts_with("settings_test_kv_store")), "{warnings:?}");
+}
+
+#[tokio::test]
+async fn test_postgres_asynchronous_commit() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let postgres = Postgres::new(PostgresConfig::new(postgres_url.clone()).with_max_connections(1))
+        .await
+        .expect("Failed to create PostgreSQL instance");
+    let fast = Postgres::new(PostgresConfig::new(postgres_url).with_durability(Durability::Asynchronous))
+        .await
+        .expect("Failed to create PostgreSQL instance");
+
+    let key = [&[0xfd, 0x1c][..], uuid::Uuid::new_v4().as_bytes()].concat();
+    let set = |value| AtomicWrite {
+        checks: vec![],
+        mutations: vec![Mutation { key: key.clone(), kind: MutationKind::Set(KvValue::U64(value)), expire_at: None }],
+        enqueues: vec![],
+    };
+    let commit = fast.atomic_write(set(1)).await.expect("Atomic write failed").expect("Atomic write was rejected");
+    assert!(!commit.durable);
+    let commit = fast
+        .atomic_write_with_durability(set(2), Durability::Synchronous)
+        .await
+        .expect("Atomic write failed")
+        .expect("Atomic write was rejected");
+    assert!(commit.durable);
+
+    // The setting does not outlive the write's transaction on the one
+    // pooled connection.
+    let commit = postgres
+        .atomic_write_with_durability(set(3), Durability::Asynchronous)
+        .await
+        .expect("Atomic write failed")
+        .expect("Atomic write was rejected");
+    assert!(!commit.durable);
+    let commit = postgres.atomic_write(set(4)).await.expect("Atomic write failed").expect("Atomic write was rejected");
+    assert!(commit.durable);
+    let warnings = postgres.check_server_settings().await.expect("Failed to check settings");
+    assert!(!warnings.iter().any(|warning| warning.setting == "synchronous_commit"), "{warnings:?}");
+
+    let outputs = fast
+        .snapshot_read(
+            vec![ReadRange {
+                start: key.clone(),
+                end: [&key[..], &[0]].concat(),
+                limit: NonZeroU32::new(1).unwrap(),
+                reverse: false,
+            }],
+            SnapshotReadOptions { consistency: Consistency::Strong },
+        )
+        .await
+        .expect("Snapshot read failed");
+    assert!(matches!(outputs[0].entries[0].value, KvValue::U64(4)));
+}
+
+#[tokio::test]
+async fn test_postgres_dequeues_by_priority() {
+    use denokv_proto::Enqueue;
+
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    // A schema of its own, so that no other test dequeues these messages.
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url).with_schema("denokv_priority_test".to_string());
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+    while let Some(message) = postgres.dequeue_next_message().await.expect("Dequeue failed") {
+        message.finish(true).await.expect("Finish failed");
+    }
+
+    let now = denokv_proto::time::utc_now();
+    let enqueue = |payload: &[u8], seconds_ago| AtomicWrite {
+        checks: vec![],
+        mutations: vec![],
+        enqueues: vec![Enqueue {
+            payload: payload.to_vec(),
+            deadline: now - chrono::Duration::seconds(seconds_ago),
+            keys_if_undelivered: vec![],
+            backoff_schedule: None,
+        }],
+    };
+    postgres.atomic_write_with_priority(enqueue(b"low", 3), -1).await.expect("Atomic write failed");
+    postgres.atomic_write(enqueue(b"normal", 2)).await.expect("Atomic write failed");
+    postgres.atomic_write(enqueue(b"normal, due later", 1)).await.expect("Atomic write failed");
+    postgres.atomic_write_with_priority(enqueue(b"urgent", 0), 5).await.expect("Atomic write failed");
+
+    let mut order = Vec::new();
+    while let Some(mut message) = postgres.dequeue_next_message().await.expect("Dequeue failed") {
+        order.push(message.take_payload().await.expect("no payload"));
+        message.finish(true).await.expect("Finish failed");
+    }
+    assert_eq!(order, [&b"urgent"[..], b"normal", b"normal, due later", b"low"]);
+}
+
+#[tokio::test]
+async fn test_postgres_queue_depth() {
+    use denokv_proto::Enqueue;
+
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    // A fresh schema of its own, as messages scheduled by earlier runs are
+    // never dequeued.
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let (client, connection) = tokio_postgres::connect(&postgres_url, tokio_postgres::NoTls)
+        .await
+        .expect("Failed to connect");
+    tokio::spawn(connection);
+    client
+        .batch_execute("DROP SCHEMA IF EXISTS denokv_queue_depth_test CASCADE")
+        .await
+        .expect("Failed to drop the schema");
+    let config = PostgresConfig::new(postgres_url).with_schema("denokv_queue_depth_test".to_string());
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+    assert_eq!(postgres.queue_depth().await.expect("Counting failed"), QueueDepth::default());
+
+    let now = denokv_proto::time::utc_now();
+    let enqueue = |deadline| Enqueue {
+        payload: b"message".to_vec(),
+        deadline,
+        keys_if_undelivered: vec![],
+        backoff_schedule: None,
+    };
+    let write = AtomicWrite {
+        checks: vec![],
+        mutations: vec![],
+        enqueues: vec![enqueue(now), enqueue(now), enqueue(now + chrono::Duration::hours(1))],
+    };
+    postgres.atomic_write(write).await.expect("Atomic write failed");
+    let message = postgres.dequeue_next_message().await.expect("Dequeue failed").expect("no message");
+    assert_eq!(
+        postgres.queue_depth().await.expect("Counting failed"),
+        QueueDepth { ready: 1, scheduled: 1, running: 1, dead_letter: 0 }
+    );
+    message.finish(true).await.expect("Finish failed");
+    assert_eq!(
+        postgres.queue_depth().await.expect("Counting failed"),
+        QueueDepth { ready: 1, scheduled: 1, running: 0, dead_letter: 0 }
+    );
+
+    #[cfg(feature = "metrics")]
+    {
+        let registry = prometheus::Registry::new();
+        postgres.register_metrics(&registry).expect("Registering failed");
+        assert!(postgres.register_metrics(&registry).is_err(), "the names are taken");
+        let families = registry.gather();
+        for name in ["denokv_postgres_operation_duration_seconds", "denokv_postgres_retries_total", "denokv_postgres_pool_connections"] {
+            assert!(families.iter().any(|family| family.get_name() == name), "{name} is registered");
+        }
+    }
+}
+
+type RecordedSpan = (&'static tracing::Metadata<'static>, Vec<(&'static str, String)>);
+
+/// Keeps the fields of every span created on this thread, and which one was
+/// entered last.
+#[derive(Clone, Default)]
+struct SpanRecorder {
+    spans: std::sync::Arc<std::sync::Mutex<Vec<RecordedSpan>>>,
+    entered: std::sync::Arc<std::sync::Mutex<Vec<tracing::span::Id>>>,
+}
+
+struct FieldRecorder<'a>(&'a mut Vec<(&'static str, String)>);
+
+impl tracing::field::Visit for FieldRecorder<'_> {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        self.0.push((field.name(), format!("{value:?}")));
+    }
+
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        self.0.push((field.name(), value.to_string()));
+    }
+}
+
+impl tracing::Subscriber for SpanRecorder {
+    fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, attributes: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        let mut fields = Vec::new();
+        attributes.record(&mut FieldRecorder(&mut fields));
+        let mut spans = self.spans.lock().unwrap();
+        spans.push((attributes.metadata(), fields));
+        tracing::span::Id::from_u64(spans.len() as u64)
+    }
+
+    fn record(&self, span: &tracing::span::Id, values: &tracing::span::Record<'_>) {
+        let mut spans = self.spans.lock().unwrap();
+        values.record(&mut FieldRecorder(&mut spans[span.into_u64() as usize - 1].1));
+    }
+
+    fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+    fn event(&self, _: &tracing::Event<'_>) {}
+
+    fn enter(&self, span: &tracing::span::Id) {
+        self.entered.lock().unwrap().push(span.clone());
+    }
+
+    fn exit(&self, _: &tracing::span::Id) {
+        self.entered.lock().unwrap().pop();
+    }
+
+    fn current_span(&self) -> tracing_core::span::Current {
+        match self.entered.lock().unwrap().last() {
+            Some(id) => tracing_core::span::Current::new(id.clone(), self.spans.lock().unwrap()[id.into_u64() as usize - 1].0),
+            None => tracing_core::span::Current::none(),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_postgres_traces_operations() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let postgres = Postgres::new(PostgresConfig::new(postgres_url)).await.expect("Failed to create PostgreSQL instance");
+    let recorder = SpanRecorder::default();
+    let _guard = tracing::subscriber::set_default(recorder.clone());
+
+    let prefix = [&[0xfd, 0x1f][..], uuid::Uuid::new_v4().as_bytes()].concat();
+    let set = |suffix: u8| Mutation {
+        key: [&prefix[..], &[suffix]].concat(),
+        kind: MutationKind::Set(KvValue::U64(suffix as u64)),
+        expire_at: None,
+    };
+    let write = AtomicWrite { checks: vec![], mutations: vec![set(1), set(2)], enqueues: vec![] };
+    postgres.atomic_write(write).await.expect("Atomic write failed");
+    let read = ReadRange {
+        start: prefix.clone(),
+        end: [&prefix[..], &[0xff]].concat(),
+        limit: NonZeroU32::new(10).unwrap(),
+        reverse: false,
+    };
+    let options = SnapshotReadOptions { consistency: Consistency::Strong };
+    postgres.snapshot_read(vec![read], options).await.expect("Snapshot read failed");
+
+    let spans = recorder.spans.lock().unwrap();
+    let field = |name: &str, field: &str| {
+        let (_, fields) = spans.iter().find(|(span, _)| span.name() == name).unwrap_or_else(|| panic!("no {name} span"));
+        fields.iter().rev().find(|(key, _)| *key == field).map(|(_, value)| value.clone())
+    };
+    assert_eq!(field("atomic_write", "mutations").as_deref(), Some("2"));
+    assert_eq!(field("atomic_write", "committed").as_deref(), Some("true"));
+    assert_eq!(field("snapshot_read", "ranges").as_deref(), Some("1"));
+    assert_eq!(field("snapshot_read", "limit").as_deref(), Some("10"));
+    assert_eq!(field("snapshot_read", "entries").as_deref(), Some("2"));
+    assert_eq!(field("snapshot_read", "attempts").as_deref(), Some("1"));
+    let statements: Vec<_> = spans
+        .iter()
+        .filter(|(span, _)| span.name() == "sql")
+        .map(|(_, fields)| fields[0].1.clone())
+        .collect();
+    assert_eq!(statements, ["atomic_write", "read_range"]);
+}
+
+#[tokio::test]
+async fn test_postgres_analyzes_changed_tables() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url)
+        .with_schema("denokv_analyze_test".to_string())
+        .with_analyze_threshold(0);
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+
+    let mut analyzed: Vec<_> = postgres.analyze(0).await.expect("Analyze failed").into_iter().map(|table| table.table).collect();
+    analyzed.sort();
+    assert_eq!(
+        analyzed,
+        ["denokv_analyze_test.kv_store", "denokv_analyze_test.kv_tombstones", "denokv_analyze_test.queue_messages"]
+    );
+    assert!(postgres.analyze(u64::MAX).await.expect("Analyze failed").is_empty());
+}
+
+#[tokio::test]
+async fn test_postgres_dead_letters_poisoned_messages() {
+    use denokv_proto::Enqueue;
+
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    // A schema of its own, so that no other test dequeues these messages.
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url)
+        .with_schema("denokv_dead_letter_test".to_string())
+        .with_max_delivery_attempts(Some(2));
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+    while let Some(message) = postgres.dequeue_next_message().await.expect("Dequeue failed") {
+        message.finish(true).await.expect("Finish failed");
+    }
+    for dead_letter in postgres.dead_letters(100).await.expect("Listing dead letters failed") {
+        postgres.delete_dead_letter(&dead_letter.id).await.expect("Delete failed");
+    }
+
+    let undelivered = [&[0xfd, 0x1d][..], uuid::Uuid::new_v4().as_bytes()].concat();
+    let write = AtomicWrite {
+        checks: vec![],
+        mutations: vec![],
+        enqueues: vec![Enqueue {
+            payload: b"poison".to_vec(),
+            deadline: denokv_proto::time::utc_now(),
+            keys_if_undelivered: vec![undelivered.clone()],
+            backoff_schedule: Some(vec![0, 0, 0]),
+        }],
+    };
+    postgres.atomic_write(write).await.expect("Atomic write failed");
+    let fail_twice = || async {
+        for _ in 0..2 {
+            let message = postgres.dequeue_next_message().await.expect("Dequeue failed").expect("no message");
+            message.finish(false).await.expect("Finish failed");
+        }
+        assert!(postgres.dequeue_next_message().await.expect("Dequeue failed").is_none());
+    };
+
+    // The backoff schedule would allow two more retries.
+    fail_twice().await;
+    let dead_letters = postgres.dead_letters(100).await.expect("Listing dead letters failed");
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0].payload, b"poison");
+    assert_eq!(dead_letters[0].attempts, 2);
+    assert_eq!(dead_letters[0].keys_if_undelivered, vec![undelivered.clone()]);
+    let read = ReadRange {
+        start: undelivered.clone(),
+        end: [&undelivered[..], &[0]].concat(),
+        limit: NonZeroU32::new(1).unwrap(),
+        reverse: false,
+    };
+    let options = SnapshotReadOptions { consistency: Consistency::Strong };
+    let output = postgres.snapshot_read(vec![read], options).await.expect("Snapshot read failed");
+    assert!(output[0].entries.is_empty(), "a dead-lettered message is not undelivered");
+
+    // A requeued message gets all of its attempts back.
+    let id = dead_letters[0].id;
+    assert!(postgres.requeue_dead_letter(&id).await.expect("Requeue failed"));
+    assert!(!postgres.requeue_dead_letter(&id).await.expect("Requeue failed"));
+    assert!(postgres.dead_letters(100).await.expect("Listing dead letters failed").is_empty());
+    fail_twice().await;
+
+    assert!(postgres.delete_dead_letter(&id).await.expect("Delete failed"));
+    assert!(postgres.dead_letters(100).await.expect("Listing dead letters failed").is_empty());
+}
+
+#[tokio::test]
+async fn test_postgres_elects_one_leader() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let first = Postgres::new(PostgresConfig::new(postgres_url.clone())).await.expect("Failed to create PostgreSQL instance");
+    let second = Postgres::new(PostgresConfig::new(postgres_url)).await.expect("Failed to create PostgreSQL instance");
+    let role = format!("test_{}", uuid::Uuid::new_v4());
+
+    let leader = first.elect_leader(&role).expect("Election failed");
+    tokio::time::timeout(std::time::Duration::from_secs(10), leader.acquired()).await.expect("no leader was elected");
+    let follower = second.elect_leader(&role).expect("Election failed");
+    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    assert!(!follower.is_leader());
+
+    // The follower takes over once the leader resigns.
+    drop(leader);
+    tokio::time::timeout(std::time::Duration::from_secs(15), follower.acquired()).await.expect("the follower did not take over");
+}
+
+#[tokio::test]
+async fn test_postgres_batches_consecutive_sets() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let postgres = Postgres::new(PostgresConfig::new(postgres_url)).await.expect("Failed to create PostgreSQL instance");
+    let prefix = [&[0xfd, 0x29][..], uuid::Uuid::new_v4().as_bytes()].concat();
+    let key = |i: u8| [&prefix[..], &[i]].concat();
+    let set = |i: u8, value: u64| Mutation { key: key(i), kind: MutationKind::Set(KvValue::U64(value)), expire_at: None };
+
+    // Sets of 100 keys, one of them twice, then a delete and more sets of
+    // the keys before it.
+    let mut mutations: Vec<_> = (0..100).map(|i| set(i, i as u64)).collect();
+    mutations.push(set(7, 700));
+    mutations.push(Mutation { key: key(8), kind: MutationKind::Delete, expire_at: None });
+    mutations.push(set(9, 900));
+    mutations.push(Mutation {
+        key: key(10),
+        kind: MutationKind::Set(KvValue::Bytes(b"expired".to_vec())),
+        expire_at: Some(denokv_proto::time::utc_now() - chrono::Duration::seconds(1)),
+    });
+    mutations.push(set(11, 1100));
+    let commit = postgres
+        .atomic_write(AtomicWrite { checks: vec![], mutations, enqueues: vec![] })
+        .await
+        .expect("Atomic write failed")
+        .expect("Atomic write was rejected");
+
+    let range = ReadRange {
+        start: prefix.clone(),
+        end: [&prefix[..], &[0xff]].concat(),
+        limit: NonZeroU32::new(1000).unwrap(),
+        reverse: false,
+    };
+    let outputs = postgres
+        .snapshot_read(vec![range], SnapshotReadOptions { consistency: Consistency::Strong })
+        .await
+        .expect("Snapshot read failed");
+    let entries = &outputs[0].entries;
+    assert_eq!(entries.len(), 98);
+    assert!(entries.iter().all(|entry| entry.versionstamp == commit.versionstamp));
+    let value = |i: u8| entries.iter().find(|entry| entry.key == key(i)).map(|entry| &entry.value);
+    assert!(matches!(value(0), Some(KvValue::U64(0))));
+    assert!(matches!(value(7), Some(KvValue::U64(700))));
+    assert!(value(8).is_none());
+    assert!(matches!(value(9), Some(KvValue::U64(900))));
+    assert!(value(10).is_none());
+    assert!(matches!(value(11), Some(KvValue::U64(1100))));
+    assert!(matches!(value(99), Some(KvValue::U64(99))));
+}
+
+#[tokio::test]
+async fn test_postgres_rejects_writes_over_limits() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let postgres = Postgres::new(PostgresConfig::new(postgres_url.clone()))
+        .await
+        .expect("Failed to create PostgreSQL instance");
+
+    let prefix = [&[0xfd, 0x18][..], uuid::Uuid::new_v4().as_bytes()].concat();
+    let set = |key: &[u8], size: usize| Mutation {
+        key: key.to_vec(),
+        kind: MutationKind::Set(KvValue::Bytes(vec![0; size])),
+        expire_at: None,
+    };
+    let oversized = AtomicWrite {
+        checks: vec![],
+        mutations: vec![set(&prefix, 10), set(&[&prefix[..], &[0]].concat(), 70_000)],
+        enqueues: vec![],
+    };
+    let err = postgres.atomic_write(oversized.clone()).await.expect_err("the write should be rejected");
+    let err = err.get_inner_ref().and_then(|e| e.downcast_ref::<PostgresError>());
+    assert!(
+        matches!(err, Some(PostgresError::WriteLimitExceeded { limit: WriteLimit::ValueSize, max: 65536, .. })),
+        "{err:?}"
+    );
+
+    // Nothing of the rejected write was stored.
+    let read = ReadRange {
+        start: prefix.clone(),
+        end: [&prefix[..], &[0xff]].concat(),
+        limit: NonZeroU32::new(10).unwrap(),
+        reverse: false,
+    };
+    let outputs = postgres
+        .snapshot_read(vec![read], SnapshotReadOptions { consistency: Consistency::Strong })
+        .await
+        .expect("Snapshot read failed");
+    assert!(outputs[0].entries.is_empty());
+
+    // With the limits raised or disabled, the same write goes through.
+    let raised = WriteLimits { max_value_size: 100_000, ..Default::default() };
+    for limits in [Some(raised), None] {
+        let postgres = Postgres::new(PostgresConfig::new(postgres_url.clone()).with_write_limits(limits))
+            .await
+            .expect("Failed to create PostgreSQL instance");
+        postgres.atomic_write(oversized.clone()).await.expect("Atomic write failed");
+    }
+}
+
+#[tokio::test]
+async fn test_postgres_copy_with_transforms() {
+    use denokv_postgres::Transforms;
+    use denokv_proto::v8;
+
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct User {
+        name: String,
+        email: String,
+        ssn: Option<String>,
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let source = Postgres::new(PostgresConfig::new(postgres_url.clone()))
+        .await
+        .expect("Failed to create PostgreSQL instance");
+
+    let prefix = [&[0xfd, 0x19][..], uuid::Uuid::new_v4().as_bytes()].concat();
+    let key = |name: &[u8]| [&prefix[..], name].concat();
+    let user = User { name: "Zoë".to_string(), email: "zoe@example.com".to_string(), ssn: Some("123".to_string()) };
+    let set = |name: &[u8], value| Mutation { key: key(name), kind: MutationKind::Set(value), expire_at: None };
+    source
+        .atomic_write(AtomicWrite {
+            checks: vec![],
+            mutations: vec![
+                set(b"users/1", KvValue::V8(v8::to_vec(&user).unwrap())),
+                set(b"sessions/1", KvValue::Bytes(b"secret".to_vec())),
+                set(b"counter", KvValue::U64(7)),
+                set(b"gone", KvValue::U64(1)),
+            ],
+            enqueues: vec![],
+        })
+        .await
+        .expect("Atomic write failed");
+    source
+        .atomic_write(AtomicWrite {
+            checks: vec![],
+            mutations: vec![Mutation { key: key(b"gone"), kind: MutationKind::Delete, expire_at: None }],
+            enqueues: vec![],
+        })
+        .await
+        .expect("Atomic write failed");
+
+    let transforms = Transforms::new()
+        .drop_fields(key(b"users/"), vec!["ssn".to_string()])
+        .hash_fields(key(b"users/"), vec!["email".to_string()])
+        .skip(key(b"sessions/"));
+    let read = || ReadRange {
+        start: prefix.clone(),
+        end: [&prefix[..], &[0xff]].concat(),
+        limit: NonZeroU32::new(10).unwrap(),
+        reverse: false,
+    };
+
+    let sqlite = denokv_sqlite::Sqlite::new(
+        || {
+            let conn = rusqlite::Connection::open_in_memory()
+                .map_err(|e| deno_error::JsErrorBox::generic(e.to_string()))?;
+            Ok((conn, Box::new(<rand::rngs::StdRng as rand::SeedableRng>::from_entropy()) as _))
+        },
+        Default::default(),
+        denokv_sqlite::SqliteConfig { num_workers: 1, batch_timeout: None },
+    )
+    .expect("Failed to open SQLite database");
+    let staging = Postgres::new(PostgresConfig::new(postgres_url).with_schema("denokv_copy_test".to_string()))
+        .await
+        .expect("Failed to create staging instance");
+
+    let report = source.copy_to(&prefix, &sqlite, &transforms).await.expect("Copy to SQLite failed");
+    assert_eq!((report.entries_copied, report.entries_skipped, report.keys_deleted), (2, 1, 1));
+    let report = source.copy_to(&prefix, &staging, &transforms).await.expect("Copy to PostgreSQL failed");
+    assert_eq!(report.entries_copied, 2);
+
+    let strong = || SnapshotReadOptions { consistency: Consistency::Strong };
+    let from_sqlite = sqlite.snapshot_read(vec![read()], strong()).await.expect("Snapshot read failed");
+    let from_staging = staging.snapshot_read(vec![read()], strong()).await.expect("Snapshot read failed");
+    for outputs in [from_sqlite, from_staging] {
+        let entries = &outputs[0].entries;
+        let keys: Vec<&[u8]> = entries.iter().map(|entry| &entry.key[prefix.len()..]).collect();
+        assert_eq!(keys, vec![&b"counter"[..], b"users/1"]);
+        assert!(matches!(entries[0].value, KvValue::U64(7)));
+        let KvValue::V8(bytes) = &entries[1].value else { panic!("expected a V8 value") };
+        let copied: User = v8::from_slice(bytes.as_slice()).unwrap();
+        assert_eq!(copied.name, "Zoë");
+        assert_eq!(copied.ssn, None);
+        assert_eq!(copied.email.len(), 32);
+        assert_ne!(copied.email, user.email);
+    }
+}
+
+#[tokio::test]
+async fn test_postgres_scan_reads_one_snapshot() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let postgres = Postgres::new(PostgresConfig::new(postgres_url))
+        .await
+        .expect("Failed to create PostgreSQL instance");
+
+    let prefix = [&[0xfd, 0x1a][..], uuid::Uuid::new_v4().as_bytes()].concat();
+    let key = |i: u8| [&prefix[..], &[i]].concat();
+    let write = |mutations| AtomicWrite { checks: vec![], mutations, enqueues: vec![] };
+    let set = |i: u8, value| Mutation { key: key(i), kind: MutationKind::Set(KvValue::U64(value)), expire_at: None };
+    postgres.atomic_write(write((0..25).map(|i| set(i * 2, 1)).collect())).await.expect("Atomic write failed");
+
+    let started = std::time::Instant::now();
+    let mut scan = Box::pin(postgres.scan(prefix.clone(), NonZeroU32::new(10).unwrap(), Some(20.0)));
+    let first = scan.next().await.unwrap().expect("Scan failed");
+    assert_eq!(first.key, key(0));
+
+    // Changes after the scan started are not seen, even in later batches.
+    postgres
+        .atomic_write(write(vec![
+            set(1, 2),
+            set(40, 2),
+            Mutation { key: key(48), kind: MutationKind::Delete, expire_at: None },
+        ]))
+        .await
+        .expect("Atomic write failed");
+    let rest: Vec<_> = scan.try_collect().await.expect("Scan failed");
+    assert_eq!(rest.len(), 24);
+    assert!(rest.iter().enumerate().all(|(i, entry)| entry.key == key((i as u8 + 1) * 2)));
+    assert!(rest.iter().all(|entry| matches!(entry.value, KvValue::U64(1))));
+
+    // 30 entries were fetched at 20 per second, starting with a full
+    // second's worth.
+    assert!(started.elapsed() >= std::time::Duration::from_millis(400), "{:?}", started.elapsed());
+}
+
+#[tokio::test]
+async fn test_postgres_backfill_runs_once_across_instances() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let a = Postgres::new(PostgresConfig::new(postgres_url.clone()))
+        .await
+        .expect("Failed to create PostgreSQL instance");
+    let b = Postgres::new(PostgresConfig::new(postgres_url))
+        .await
+        .expect("Failed to create PostgreSQL instance");
+
+    let id = uuid::Uuid::new_v4();
+    let prefix = [&[0xfd, 0x1b][..], id.as_bytes()].concat();
+    let key = |i: u8| [&prefix[..], &[i]].concat();
+    // The last five entries are in the new format already.
+    let mutations = (0..30u8)
+        .map(|i| {
+            let value = if i < 25 { KvValue::U64(i as u64) } else { KvValue::Bytes(vec![i]) };
+            Mutation { key: key(i), kind: MutationKind::Set(value), expire_at: None }
+        })
+        .collect();
+    a.atomic_write(AtomicWrite { checks: vec![], mutations, enqueues: vec![] })
+        .await
+        .expect("Atomic write failed");
+
+    let name = format!("to_bytes_{id}");
+    let job = BackfillJob::new(name.clone(), prefix.clone(), |entry| match entry.value {
+        KvValue::U64(n) => Some(KvValue::Bytes(vec![n as u8])),
+        _ => None,
+    })
+    .with_batch_size(NonZeroU32::new(7).unwrap());
+    assert_eq!(a.backfill_progress(&name).await.expect("Progress failed"), None);
+    let (runner_a, runner_b) = (a.backfill(job.clone()), b.backfill(job));
+    tokio::time::timeout(std::time::Duration::from_secs(30), async {
+        runner_a.wait().await;
+        runner_b.wait().await;
+    })
+    .await
+    .expect("Backfill did not finish");
+
+    let progress = b.backfill_progress(&name).await.expect("Progress failed").expect("No checkpoint");
+    assert_eq!(
+        progress,
+        BackfillProgress { after: Some(key(29)), processed: 30, rewritten: 25, done: true }
+    );
+    let entries: Vec<_> = a.scan(prefix.clone(), NonZeroU32::new(100).unwrap(), None).try_collect().await.expect("Scan failed");
+    assert_eq!(entries.len(), 30);
+    assert!(entries.iter().enumerate().all(|(i, entry)| matches!(&entry.value, KvValue::Bytes(b) if b == &[i as u8])));
+}
+
+#[cfg(feature = "driver-sqlx")]
+#[tokio::test]
+async fn test_sqlx_driver_shares_data_with_postgres() {
+    use denokv_postgres::SqlxPostgres;
+    use denokv_proto::{Check, Enqueue, QueueMessageHandle};
+
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let pool = sqlx::PgPool::connect(&postgres_url).await.expect("Failed to connect with sqlx");
+    let sqlx_db = SqlxPostgres::new(pool, PostgresConfig::new(postgres_url.clone()))
+        .await
+        .expect("Failed to create sqlx instance");
+    let postgres = Postgres::new(PostgresConfig::new(postgres_url)).await.expect("Failed to create PostgreSQL instance");
+
+    let prefix = [&[0xfd, 0x15][..], uuid::Uuid::new_v4().as_bytes()].concat();
+    let key = |i: u8| [&prefix[..], &[i]].concat();
+    let read = |db_key: Vec<u8>| ReadRange {
+        start: db_key.clone(),
+        end: [&db_key[..], &[0]].concat(),
+        limit: NonZeroU32::new(1).unwrap(),
+        reverse: false,
+    };
+    let strong = || SnapshotReadOptions { consistency: Consistency::Strong };
+
+    // A watch through sqlx sees writes made through tokio-postgres.
+    let watch = sqlx_db.watch(vec![key(3)]);
+    futures::pin_mut!(watch);
+    watch.next().await.expect("initial values").expect("watch failed");
+
+    let mutation = |i: u8, kind| Mutation { key: key(i), kind, expire_at: None };
+    let commit = sqlx_db
+        .atomic_write(AtomicWrite {
+            checks: vec![Check { key: key(0), versionstamp: None }],
+            mutations: vec![
+                mutation(0, MutationKind::Set(KvValue::Bytes(b"sqlx".to_vec()))),
+                mutation(1, MutationKind::Sum { value: KvValue::U64(2), min_v8: vec![], max_v8: vec![], clamp: false }),
+                mutation(1, MutationKind::Max(KvValue::U64(5))),
+                mutation(2, MutationKind::SetSuffixVersionstampedKey(KvValue::U64(9))),
+            ],
+            enqueues: vec![Enqueue {
+                payload: prefix.clone(),
+                deadline: denokv_proto::time::utc_now(),
+                keys_if_undelivered: vec![],
+                backoff_schedule: None,
+            }],
+        })
+        .await
+        .expect("Atomic write failed")
+        .expect("Check failed");
+
+    // What sqlx wrote reads the same through tokio-postgres, and back.
+    let requests = vec![read(key(0)), read(key(1)), read(commit.versionstamped_keys[0].clone())];
+    let through_postgres = postgres.snapshot_read(requests.clone(), strong()).await.expect("Snapshot read failed");
+    let through_sqlx = sqlx_db.snapshot_read(requests, strong()).await.expect("Snapshot read failed");
+    for outputs in [&through_postgres, &through_sqlx] {
+        assert!(matches!(&outputs[0].entries[0].value, KvValue::Bytes(bytes) if bytes == b"sqlx"));
+        assert!(matches!(outputs[1].entries[0].value, KvValue::U64(5)));
+        assert!(matches!(outputs[2].entries[0].value, KvValue::U64(9)));
+        assert!(outputs.iter().all(|output| output.entries[0].versionstamp == commit.versionstamp));
+    }
+
+    // The check now fails, since the key exists.
+    let failed = sqlx_db
+        .atomic_write(AtomicWrite {
+            checks: vec![Check { key: key(0), versionstamp: None }],
+            mutations: vec![mutation(0, MutationKind::Delete)],
+            enqueues: vec![],
+        })
+        .await
+        .expect("Atomic write failed");
+    assert!(failed.is_none());
+
+    postgres
+        .atomic_write(AtomicWrite { checks: vec![], mutations: vec![mutation(3, MutationKind::Set(KvValue::U64(1)))], enqueues: vec![] })
+        .await
+        .expect("Atomic write failed");
+    let outputs = tokio::time::timeout(std::time::Duration::from_secs(5), watch.next())
+        .await
+        .expect("no notification of the change")
+        .expect("watch stream ended")
+        .expect("watch failed");
+    assert!(matches!(&outputs[0], denokv_proto::WatchKeyOutput::Changed { entry: Some(_) }));
+
+    // Other tests may have left messages due too; put those back.
+    loop {
+        let mut message = sqlx_db.dequeue_next_message().await.expect("Dequeue failed").expect("no message");
+        let ours = message.take_payload().await.expect("no payload") == prefix;
+        message.finish(ours).await.expect("Finish failed");
+        if ours {
+            break;
+        }
+    }
+
+    sqlx_db.close();
+}
+
+#[tokio::test]
+async fn test_postgres_reaps_deleted_namespaces() {
+    use denokv_postgres::MaterializedView;
+
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    // A schema of its own, so that the reapers of other tests do not
+    // complete the deletions first.
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url)
+        .with_schema("denokv_reaper_test".to_string())
+        .with_namespace_reaper(10, None);
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+    assert!(matches!(postgres.delete_namespace(&[]).await, Err(PostgresError::InvalidData(_))));
+
+    let run = uuid::Uuid::new_v4();
+    let namespace = [&[0xfd, 0x20][..], run.as_bytes()].concat();
+    let index = [&[0xfd, 0x21][..], run.as_bytes()].concat();
+    let neighbour = [&[0xfd, 0x20][..], uuid::Uuid::new_v4().as_bytes()].concat();
+    let set = |key: Vec<u8>| Mutation { key, kind: MutationKind::Set(KvValue::U64(1)), expire_at: None };
+    let mut mutations: Vec<_> = (0..25u8).map(|i| set([namespace.as_slice(), &[i]].concat())).collect();
+    mutations.push(set(neighbour.clone()));
+    postgres.atomic_write(AtomicWrite { checks: vec![], mutations, enqueues: vec![] }).await.expect("Atomic write failed");
+    let delete = Mutation { key: [namespace.as_slice(), &[0]].concat(), kind: MutationKind::Delete, expire_at: None };
+    postgres.atomic_write(AtomicWrite { checks: vec![], mutations: vec![delete], enqueues: vec![] }).await.expect("Atomic write failed");
+
+    let view = MaterializedView::new(format!("reaper-{run}"), namespace.clone(), index.clone(), {
+        let len = namespace.len();
+        move |entry| vec![(entry.key[len..].to_vec(), KvValue::U64(1))]
+    });
+    // The keys and the deletion.
+    assert_eq!(postgres.refresh_view(&view).await.expect("Refresh failed"), 25);
+
+    let count = |prefix: Vec<u8>| {
+        let postgres = postgres.clone();
+        async move {
+            let read = ReadRange {
+                end: [prefix.as_slice(), &[0xff]].concat(),
+                start: prefix,
+                limit: NonZeroU32::new(100).unwrap(),
+                reverse: false,
+            };
+            let options = SnapshotReadOptions { consistency: Consistency::Strong };
+            postgres.snapshot_read(vec![read], options).await.expect("Snapshot read failed").remove(0).entries.len()
+        }
+    };
+
+    let requested = postgres.delete_namespace(&namespace).await.expect("Delete failed");
+    assert!(!requested.is_complete());
+    assert_eq!(requested.deleted_keys, 0);
+    // Requesting a pending deletion again changes nothing.
+    assert_eq!(postgres.delete_namespace(&namespace).await.expect("Delete failed").requested_at, requested.requested_at);
+
+    postgres.reap_namespaces().await.expect("Reaping failed");
+    let deletion = postgres.namespace_deletion(&namespace).await.expect("Reading the deletion failed").expect("The deletion is journaled");
+    assert!(deletion.is_complete());
+    assert_eq!(deletion.deleted_keys, 24);
+    assert_eq!(deletion.deleted_tombstones, 1);
+    assert_eq!(deletion.deleted_view_keys, 24);
+    assert_eq!(deletion.deleted_messages, 0);
+    assert_eq!(count(namespace.clone()).await, 0);
+    assert_eq!(count(index.clone()).await, 0);
+    assert_eq!(count(neighbour.clone()).await, 1);
+    assert!(postgres.namespace_deletions().await.expect("Listing deletions failed").contains(&deletion));
+
+    // A completed deletion starts over.
+    postgres.atomic_write(AtomicWrite { checks: vec![], mutations: vec![set([namespace.as_slice(), &[1]].concat())], enqueues: vec![] }).await.expect("Atomic write failed");
+    assert!(!postgres.delete_namespace(&namespace).await.expect("Delete failed").is_complete());
+    postgres.reap_namespaces().await.expect("Reaping failed");
+    let deletion = postgres.namespace_deletion(&namespace).await.expect("Reading the deletion failed").unwrap();
+    assert!(deletion.is_complete());
+    assert_eq!(deletion.deleted_keys, 1);
+    assert_eq!(count(namespace).await, 0);
+
+    assert!(postgres.delete_view(view.name()).await.expect("Delete failed"));
+}
+
+#[tokio::test]
+async fn test_postgres_affinity_lanes_serialize_hot_key_writes() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url).with_max_connections(4).with_affinity_lanes(2);
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+
+    let key = [&[0xfd, 0x22][..], uuid::Uuid::new_v4().as_bytes()].concat();
+    let sum = || AtomicWrite {
+        checks: vec![],
+        mutations: vec![Mutation {
+            key: key.clone(),
+            kind: MutationKind::Sum { value: KvValue::U64(1), min_v8: vec![], max_v8: vec![], clamp: false },
+            expire_at: None,
+        }],
+        enqueues: vec![],
+    };
+    let writes = (0..20).map(|_| postgres.atomic_write(sum()));
+    for result in futures::future::join_all(writes).await {
+        result.expect("Sum failed");
+    }
+
+    let read = ReadRange {
+        start: key.clone(),
+        end: [key.as_slice(), &[0]].concat(),
+        limit: NonZeroU32::new(1).unwrap(),
+        reverse: false,
+    };
+    let options = SnapshotReadOptions { consistency: Consistency::Strong };
+    let entries = postgres.snapshot_read(vec![read], options).await.expect("Snapshot read failed").remove(0).entries;
+    assert!(matches!(entries[0].value, KvValue::U64(20)));
+    // The lane keeps its connection between writes.
+    assert!(postgres.pool_status().in_use >= 1);
+}
+
+#[tokio::test]
+async fn test_postgres_expired_entries_are_hidden_before_sweeping() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url).with_expiry_sweep(0, 1000);
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+
+    let prefix = [&[0xfd, 0x23][..], uuid::Uuid::new_v4().as_bytes()].concat();
+    let key = |i: u8| [&prefix[..], &[i]].concat();
+    let now = denokv_proto::time::utc_now();
+    // Expired a millisecond ago, expiring shortly, and never expiring.
+    let expiries = [Some(now - chrono::Duration::milliseconds(1)), Some(now + chrono::Duration::milliseconds(500)), None];
+    let mutations = expiries
+        .iter()
+        .enumerate()
+        .map(|(i, expire_at)| Mutation { key: key(i as u8), kind: MutationKind::Set(KvValue::U64(i as u64)), expire_at: *expire_at })
+        .collect();
+    let commit = postgres
+        .atomic_write(AtomicWrite { checks: vec![], mutations, enqueues: vec![] })
+        .await
+        .expect("Atomic write failed")
+        .expect("Atomic write was rejected");
+
+    let read = |reverse: bool| {
+        let postgres = &postgres;
+        let request = ReadRange { start: key(0), end: key(3), limit: NonZeroU32::new(10).unwrap(), reverse };
+        async move {
+            let outputs = postgres
+                .snapshot_read(vec![request], SnapshotReadOptions { consistency: Consistency::Strong })
+                .await
+                .expect("Snapshot read failed");
+            outputs[0].entries.iter().map(|entry| *entry.key.last().unwrap()).collect::<Vec<u8>>()
+        }
+    };
+    let check = |i: u8, versionstamp| AtomicWrite {
+        checks: vec![Check { key: key(i), versionstamp }],
+        mutations: vec![],
+        enqueues: vec![],
+    };
+    assert_eq!(read(false).await, [1, 2]);
+    assert_eq!(read(true).await, [2, 1]);
+    // An expired key checks as missing.
+    assert!(postgres.atomic_write(check(0, None)).await.expect("Atomic write failed").is_some());
+    assert!(postgres.atomic_write(check(0, Some(commit.versionstamp))).await.expect("Atomic write failed").is_none());
+    assert!(postgres.atomic_write(check(1, Some(commit.versionstamp))).await.expect("Atomic write failed").is_some());
+
+    // Once its expiry time passes, the second key is hidden too, although
+    // nothing swept it.
+    tokio::time::sleep(std::time::Duration::from_millis(600)).await;
+    assert_eq!(read(false).await, [2]);
+    assert!(postgres.atomic_write(check(1, None)).await.expect("Atomic write failed").is_some());
+    assert!(postgres.atomic_write(check(1, Some(commit.versionstamp))).await.expect("Atomic write failed").is_none());
+}
+
+#[tokio::test]
+async fn test_postgres_caches_prepared_statements() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let key = [&[0xfd, 0x24][..], uuid::Uuid::new_v4().as_bytes()].concat();
+    for cache_size in [64, 0] {
+        // A single connection, so that its prepared statements can be
+        // listed afterwards.
+        let pg_config: tokio_postgres::Config = postgres_url.parse().expect("Invalid POSTGRES_URL");
+        #[cfg(not(feature = "pool-bb8"))]
+        let pool = {
+            let manager = deadpool_postgres::Manager::new(pg_config, tokio_postgres::NoTls);
+            deadpool_postgres::Pool::builder(manager).max_size(1).build().expect("Failed to build pool")
+        };
+        #[cfg(feature = "pool-bb8")]
+        let pool = {
+            let manager = denokv_postgres::PgConnectionManager::new(pg_config);
+            denokv_postgres::Bb8Pool::new(bb8::Pool::builder().max_size(1).build_unchecked(manager), 1)
+        };
+        let config = PostgresConfig::new(postgres_url.clone())
+            .with_statement_cache_size(cache_size)
+            .with_listen_for_changes(false)
+            .with_elect_maintenance_leader(false)
+            .with_expiry_sweep(0, 1000);
+        let postgres = Postgres::from_pool(pool.clone(), config).await.expect("Failed to create PostgreSQL instance");
+
+        let prepared = || async {
+            let conn = pool.get().await.expect("Failed to get a connection");
+            // Not counting this query, which is prepared as it runs.
+            let count: i64 = conn
+                .query_one("SELECT count(*) FROM pg_prepared_statements WHERE statement NOT LIKE '%pg_prepared_statements%'", &[])
+                .await
+                .expect("Failed to list prepared statements")
+                .get(0);
+            count
+        };
+        let round = || async {
+            postgres
+                .atomic_write(AtomicWrite {
+                    checks: vec![Check { key: key.clone(), versionstamp: None }],
+                    mutations: vec![Mutation { key: key.clone(), kind: MutationKind::Delete, expire_at: None }],
+                    enqueues: vec![],
+                })
+                .await
+                .expect("Atomic write failed");
+            postgres
+                .snapshot_read(
+                    vec![ReadRange {
+                        start: key.clone(),
+                        end: [&key[..], &[0]].concat(),
+                        limit: NonZeroU32::new(1).unwrap(),
+                        reverse: false,
+                    }],
+                    SnapshotReadOptions { consistency: Consistency::Strong },
+                )
+                .await
+                .expect("Snapshot read failed");
+        };
+
+        round().await;
+        let after_first = prepared().await;
+        for _ in 0..5 {
+            round().await;
+        }
+        let after_more = prepared().await;
+        if cache_size == 0 {
+            assert_eq!((after_first, after_more), (0, 0));
+        } else {
+            // The write's five statements and the read, prepared once.
+            assert!(after_first >= 6, "{after_first} statements prepared");
+            assert_eq!(after_more, after_first);
+        }
+        postgres.close();
+    }
+}
+
+#[tokio::test]
+async fn test_v8_sums() {
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url).with_listen_for_changes(false);
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+
+    let id = uuid::Uuid::new_v4();
+    let key = |i: u8| [&[0xfd, 0x25][..], id.as_bytes(), &[i]].concat();
+    let bigint = |n: i128| KvValue::V8(denokv_proto::v8::to_vec(&n).unwrap());
+    let bound = |n: i128| denokv_proto::v8::to_vec(&n).unwrap();
+    let write = |key: Vec<u8>, kind| AtomicWrite {
+        checks: vec![],
+        mutations: vec![Mutation { key, kind, expire_at: None }],
+        enqueues: vec![],
+    };
+    let sum = |value, max_v8, clamp| MutationKind::Sum { value, min_v8: vec![], max_v8, clamp };
+    let read = |key: Vec<u8>| {
+        let postgres = &postgres;
+        async move {
+            let output = postgres
+                .snapshot_read(
+                    vec![ReadRange { end: [&key[..], &[0]].concat(), start: key, limit: NonZeroU32::new(1).unwrap(), reverse: false }],
+                    SnapshotReadOptions { consistency: Consistency::Strong },
+                )
+                .await
+                .expect("Snapshot read failed");
+            output[0].entries[0].value.clone()
+        }
+    };
+    let bigint_of = |value: KvValue| match value {
+        KvValue::V8(bytes) => denokv_proto::v8::from_slice::<i128>(&bytes).unwrap(),
+        value => panic!("expected a V8 value, got {value:?}"),
+    };
+
+    // Sums start from the operand, and are clamped to the bounds.
+    postgres.atomic_write(write(key(0), sum(bigint(7), vec![], false))).await.expect("Sum failed");
+    postgres.atomic_write(write(key(0), sum(bigint(5), vec![], false))).await.expect("Sum failed");
+    assert_eq!(bigint_of(read(key(0)).await), 12);
+    postgres.atomic_write(write(key(0), sum(bigint(5), bound(15), true))).await.expect("Sum failed");
+    assert_eq!(bigint_of(read(key(0)).await), 15);
+
+    // Without clamping, a sum out of range fails the write.
+    let err = postgres.atomic_write(write(key(0), sum(bigint(1), bound(15), false))).await.expect_err("the sum is out of range");
+    let err = err.get_inner_ref().and_then(|e| e.downcast_ref::<PostgresError>());
+    assert!(matches!(err, Some(PostgresError::SumOutOfRange)), "{err:?}");
+    assert_eq!(bigint_of(read(key(0)).await), 15);
+
+    // Values of other types are left alone.
+    postgres.atomic_write(write(key(1), MutationKind::Set(KvValue::Bytes(vec![1])))).await.expect("Set failed");
+    for kind in [sum(bigint(1), vec![], false), sum(KvValue::U64(1), vec![], false), MutationKind::Max(KvValue::U64(1))] {
+        let err = postgres.atomic_write(write(key(1), kind)).await.expect_err("the types mismatch");
+        let err = err.get_inner_ref().and_then(|e| e.downcast_ref::<PostgresError>());
+        assert!(matches!(err, Some(PostgresError::TypeMismatch(_))), "{err:?}");
+    }
+    assert!(matches!(read(key(1)).await, KvValue::Bytes(bytes) if bytes == [1]));
+
+    // U64 sums wrap around.
+    postgres.atomic_write(write(key(2), MutationKind::Set(KvValue::U64(u64::MAX)))).await.expect("Set failed");
+    postgres.atomic_write(write(key(2), sum(KvValue::U64(2), vec![], false))).await.expect("Sum failed");
+    assert!(matches!(read(key(2)).await, KvValue::U64(1)));
+}
+
+#[tokio::test]
+async fn test_postgres_versionstamped_keys() {
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url).with_listen_for_changes(false);
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+
+    async fn write_versionstamped(database: &impl Database) {
+        let prefix = [&[0xfd, 0x26][..], uuid::Uuid::new_v4().as_bytes(), &[0x00]].concat();
+        let commit = database
+            .atomic_write(AtomicWrite {
+                checks: vec![],
+                mutations: vec![
+                    Mutation { key: prefix.clone(), kind: MutationKind::SetSuffixVersionstampedKey(KvValue::U64(1)), expire_at: None },
+                    Mutation { key: prefix.clone(), kind: MutationKind::Set(KvValue::U64(2)), expire_at: None },
+                ],
+                enqueues: vec![],
+            })
+            .await
+            .expect("Atomic write failed")
+            .expect("Check failed");
+        let key = denokv_proto::versionstamped_key(&prefix, &commit.versionstamp);
+        assert_eq!(commit.versionstamped_keys, std::slice::from_ref(&key));
+
+        let outputs = database
+            .snapshot_read(
+                vec![ReadRange { start: key.clone(), end: [&key[..], &[0]].concat(), limit: NonZeroU32::new(1).unwrap(), reverse: false }],
+                SnapshotReadOptions { consistency: Consistency::Strong },
+            )
+            .await
+            .expect("Snapshot read failed");
+        assert!(matches!(outputs[0].entries[0].value, KvValue::U64(1)));
+        assert_eq!(outputs[0].entries[0].versionstamp, commit.versionstamp);
+    }
+
+    write_versionstamped(&postgres).await;
+    // Keys written through a namespace are reported relative to it.
+    let namespace = postgres.ephemeral_namespace();
+    write_versionstamped(&namespace).await;
+    namespace.discard().await.expect("Failed to discard the namespace");
+}
+
+#[tokio::test]
+async fn test_postgres_redelivers_messages_of_crashed_workers() {
+    use denokv_proto::Enqueue;
+
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    // A schema of its own, so that no other test dequeues these messages.
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url)
+        .with_schema("denokv_visibility_test".to_string())
+        .with_listen_for_changes(false)
+        .with_queue_visibility_timeout(1);
+    let postgres = Postgres::new(config.clone()).await.expect("Failed to create PostgreSQL instance");
+    while let Some(message) = postgres.dequeue_next_message().await.expect("Dequeue failed") {
+        message.finish(true).await.expect("Finish failed");
+    }
+
+    let write = AtomicWrite {
+        checks: vec![],
+        mutations: vec![],
+        enqueues: vec![Enqueue {
+            payload: b"crash".to_vec(),
+            deadline: denokv_proto::time::utc_now(),
+            keys_if_undelivered: vec![],
+            backoff_schedule: Some(vec![0]),
+        }],
+    };
+    postgres.atomic_write(write).await.expect("Atomic write failed");
+
+    // The worker goes away without finishing the message, and its runtime
+    // takes the keepalive of the message with it.
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let worker = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+            let message = worker.dequeue_next_message().await.expect("Dequeue failed").expect("no message");
+            std::mem::forget(message);
+        });
+    }).join().unwrap();
+    assert!(postgres.dequeue_next_message().await.expect("Dequeue failed").is_none());
+    assert_eq!(postgres.requeue_overdue_messages().await.expect("Requeue failed"), 0, "the message is not overdue yet");
+
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    assert_eq!(postgres.requeue_overdue_messages().await.expect("Requeue failed"), 1);
+    let mut message = postgres.dequeue_next_message().await.expect("Dequeue failed").expect("the message is redelivered");
+    assert_eq!(message.take_payload().await.expect("no payload"), b"crash");
+    message.finish(true).await.expect("Finish failed");
+}
+
+#[tokio::test]
+async fn test_postgres_keeps_running_messages_alive() {
+    use denokv_proto::Enqueue;
+
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    // A schema of its own, so that no other test dequeues these messages.
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let config = PostgresConfig::new(postgres_url)
+        .with_schema("denokv_keepalive_test".to_string())
+        .with_listen_for_changes(false)
+        .with_queue_visibility_timeout(1);
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+    while let Some(message) = postgres.dequeue_next_message().await.expect("Dequeue failed") {
+        message.finish(true).await.expect("Finish failed");
+    }
+
+    let write = AtomicWrite {
+        checks: vec![],
+        mutations: vec![],
+        enqueues: vec![Enqueue {
+            payload: b"slow".to_vec(),
+            deadline: denokv_proto::time::utc_now(),
+            keys_if_undelivered: vec![],
+            backoff_schedule: Some(vec![0]),
+        }],
+    };
+    postgres.atomic_write(write).await.expect("Atomic write failed");
+
+    // The handler runs for longer than the visibility timeout.
+    let message = postgres.dequeue_next_message().await.expect("Dequeue failed").expect("no message");
+    tokio::time::sleep(std::time::Duration::from_millis(2500)).await;
+    assert_eq!(postgres.requeue_overdue_messages().await.expect("Requeue failed"), 0, "the message is kept alive");
+    assert!(postgres.dequeue_next_message().await.expect("Dequeue failed").is_none());
+    message.finish(true).await.expect("Finish failed");
+    assert!(postgres.dequeue_next_message().await.expect("Dequeue failed").is_none());
+}
+
+#[tokio::test]
+async fn test_postgres_fences_deposed_leaders() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let first = Postgres::new(PostgresConfig::new(postgres_url.clone())).await.expect("Failed to create PostgreSQL instance");
+    let second = Postgres::new(PostgresConfig::new(postgres_url)).await.expect("Failed to create PostgreSQL instance");
+    let role = format!("test_{}", uuid::Uuid::new_v4());
+    let key = [&[0xfd, 0x27][..], uuid::Uuid::new_v4().as_bytes()].concat();
+    let set = |value: u64| AtomicWrite {
+        checks: vec![],
+        mutations: vec![Mutation { key: key.clone(), kind: MutationKind::Set(KvValue::U64(value)), expire_at: None }],
+        enqueues: vec![],
+    };
+    let not_leader = |err: deno_error::JsErrorBox| {
+        let err = err.get_inner_ref().and_then(|e| e.downcast_ref::<PostgresError>());
+        matches!(err, Some(PostgresError::NotLeader(_)))
+    };
+
+    let leader = first.fenced_writer(&role);
+    tokio::time::timeout(std::time::Duration::from_secs(10), leader.acquired()).await.expect("no leader was elected");
+    assert!(leader.atomic_write(set(1)).await.expect("Atomic write failed").is_some());
+    let follower = second.fenced_writer(&role);
+    assert!(not_leader(follower.atomic_write(set(2)).await.expect_err("the follower should not write")));
+
+    // A failed check of the caller's own is not mistaken for the fence.
+    let mut checked = set(3);
+    checked.checks.push(Check { key: key.clone(), versionstamp: None });
+    assert!(leader.atomic_write(checked).await.expect("Atomic write failed").is_none());
+
+    // Another instance that believes it took over sets the fence, as after
+    // a split brain; the old leader can no longer write.
+    let fence = AtomicWrite {
+        checks: vec![],
+        mutations: vec![Mutation { key: leader.fence_key().to_vec(), kind: MutationKind::Set(KvValue::Bytes(vec![])), expire_at: None }],
+        enqueues: vec![],
+    };
+    second.atomic_write(fence).await.expect("Atomic write failed");
+    assert!(leader.is_leader());
+    assert!(not_leader(leader.atomic_write(set(4)).await.expect_err("the deposed leader should not write")));
+
+    let range = ReadRange {
+        start: key.clone(),
+        end: key.iter().copied().chain(Some(0)).collect(),
+        limit: std::num::NonZeroU32::new(1).unwrap(),
+        reverse: false,
+    };
+    let outputs = first
+        .snapshot_read(vec![range], SnapshotReadOptions { consistency: Consistency::Strong })
+        .await
+        .expect("Snapshot read failed");
+    assert!(matches!(outputs[0].entries[0].value, KvValue::U64(1)));
+
+    // Once the leader resigns, the follower takes over without waiting out
+    // the lease.
+    drop(leader);
+    tokio::time::timeout(std::time::Duration::from_secs(10), follower.acquired()).await.expect("the follower did not take over");
+    assert!(follower.atomic_write(set(5)).await.expect("Atomic write failed").is_some());
+}
+
+#[tokio::test]
+async fn test_postgres_partitions_kv_store() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let (client, connection) = tokio_postgres::connect(&postgres_url, tokio_postgres::NoTls)
+        .await
+        .expect("Failed to connect");
+    tokio::spawn(connection);
+    client
+        .batch_execute("DROP TABLE IF EXISTS partitioned_test_kv_store CASCADE")
+        .await
+        .expect("Failed to drop the table");
+
+    let config = PostgresConfig::new(postgres_url.clone()).with_table_prefix("partitioned_test_".to_string());
+    let range = TablePartitioning::Range { split_points: vec![vec![0x80]] };
+    let postgres = Postgres::new(config.clone().with_table_partitioning(range))
+        .await
+        .expect("Failed to create PostgreSQL instance");
+
+    let keys = [vec![0x10, 0x01], vec![0x80], vec![0xf0, 0x01]];
+    let write = AtomicWrite {
+        checks: vec![],
+        mutations: keys
+            .iter()
+            .map(|key| Mutation { key: key.clone(), kind: MutationKind::Set(KvValue::U64(1)), expire_at: None })
+            .collect(),
+        enqueues: vec![],
+    };
+    postgres.atomic_write(write).await.expect("Atomic write failed").expect("Atomic write was rejected");
+
+    let rows = client
+        .query("SELECT tableoid::regclass::text AS partition, key FROM partitioned_test_kv_store ORDER BY key", &[])
+        .await
+        .expect("Failed to read partitions");
+    let partitions: Vec<(String, Vec<u8>)> = rows.iter().map(|row| (row.get("partition"), row.get("key"))).collect();
+    assert_eq!(
+        partitions,
+        [
+            ("partitioned_test_kv_store_p0".to_string(), keys[0].clone()),
+            ("partitioned_test_kv_store_p1".to_string(), keys[1].clone()),
+            ("partitioned_test_kv_store_p1".to_string(), keys[2].clone()),
+        ]
+    );
+
+    // Reads across partitions see every key, in order.
+    let range = ReadRange { start: vec![0x00], end: vec![0xff], limit: NonZeroU32::new(10).unwrap(), reverse: true };
+    let outputs = postgres
+        .snapshot_read(vec![range], SnapshotReadOptions { consistency: Consistency::Strong })
+        .await
+        .expect("Snapshot read failed");
+    let read: Vec<_> = outputs[0].entries.iter().map(|entry| entry.key.clone()).collect();
+    assert_eq!(read, [keys[2].clone(), keys[1].clone(), keys[0].clone()]);
+    let report = postgres.sample_keys(&[0xf0], 5).await.expect("Sampling failed");
+    assert_eq!(report.samples.len(), 1);
+
+    // An existing table keeps its layout.
+    let hash = TablePartitioning::Hash { partitions: 4 };
+    Postgres::new(config.with_table_partitioning(hash)).await.expect("Failed to create PostgreSQL instance");
+    let row = client
+        .query_one(
+            "SELECT p.partstrat::text AS strategy FROM pg_partitioned_table p WHERE p.partrelid = 'partitioned_test_kv_store'::regclass",
+            &[],
+        )
+        .await
+        .expect("Failed to read the layout");
+    assert_eq!(row.get::<_, String>("strategy"), "r");
+}
+
+#[tokio::test]
+async fn test_postgres_manages_partitions() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let (client, connection) = tokio_postgres::connect(&postgres_url, tokio_postgres::NoTls)
+        .await
+        .expect("Failed to connect");
+    tokio::spawn(connection);
+    client
+        .batch_execute(
+            r"
+            DROP TABLE IF EXISTS managed_test_kv_store CASCADE;
+            DO $$
+            DECLARE archived text;
+            BEGIN
+                FOR archived IN SELECT relname FROM pg_class WHERE relname LIKE 'managed\_test\_kv\_store\_archive\_p%' LOOP
+                    EXECUTE format('DROP TABLE %I', archived);
+                END LOOP;
+            END $$
+            ",
+        )
+        .await
+        .expect("Failed to drop the tables");
+
+    let range = TablePartitioning::Range { split_points: vec![vec![0x80]] };
+    let config = PostgresConfig::new(postgres_url)
+        .with_table_prefix("managed_test_".to_string())
+        .with_table_partitioning(range)
+        .with_partition_split_rows(100)
+        .with_partition_archive_after(2);
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+    let set = |keys: Vec<Vec<u8>>| AtomicWrite {
+        checks: vec![],
+        mutations: keys
+            .into_iter()
+            .map(|key| Mutation { key, kind: MutationKind::Set(KvValue::U64(1)), expire_at: None })
+            .collect(),
+        enqueues: vec![],
+    };
+
+    // The first partition goes cold while the second fills up.
+    postgres.atomic_write(set(vec![vec![0x10]])).await.expect("Atomic write failed").expect("Atomic write was rejected");
+    tokio::time::sleep(std::time::Duration::from_millis(2500)).await;
+    let keys: Vec<Vec<u8>> = (0..300u16).map(|i| [&[0x90][..], &i.to_be_bytes()].concat()).collect();
+    for batch in keys.chunks(100) {
+        postgres.atomic_write(set(batch.to_vec())).await.expect("Atomic write failed").expect("Atomic write was rejected");
+    }
+    client.batch_execute("ANALYZE managed_test_kv_store_p1").await.expect("Failed to analyze");
+
+    let report = postgres.manage_partitions().await.expect("Managing the partitions failed");
+    assert_eq!(report.archived.len(), 1);
+    assert_eq!(report.archived[0].table, "managed_test_kv_store_archive_p0");
+    assert_eq!((report.archived[0].start.clone(), report.archived[0].end.clone()), (None, Some(vec![0x80])));
+    assert_eq!(report.split, [("managed_test_kv_store_p1".to_string(), "managed_test_kv_store_p3".to_string())]);
+
+    // The archived key reads as gone and can be written again, and the keys
+    // of the split partition are all still there, in order.
+    let read = |start: Vec<u8>, end: Vec<u8>| ReadRange { start, end, limit: NonZeroU32::new(1000).unwrap(), reverse: false };
+    let outputs = postgres
+        .snapshot_read(vec![read(vec![0x00], vec![0x80]), read(vec![0x80], vec![0xff])], SnapshotReadOptions { consistency: Consistency::Strong })
+        .await
+        .expect("Snapshot read failed");
+    assert!(outputs[0].entries.is_empty());
+    let read_keys: Vec<_> = outputs[1].entries.iter().map(|entry| entry.key.clone()).collect();
+    assert_eq!(read_keys, keys);
+    postgres.atomic_write(set(vec![vec![0x10]])).await.expect("Atomic write failed").expect("Atomic write was rejected");
+
+    let rows = client
+        .query("SELECT tableoid::regclass::text AS partition, count(*) FROM managed_test_kv_store WHERE key >= '\\x80' GROUP BY 1 ORDER BY 1", &[])
+        .await
+        .expect("Failed to read partitions");
+    let partitions: Vec<String> = rows.iter().map(|row| row.get("partition")).collect();
+    assert_eq!(partitions, ["managed_test_kv_store_p1", "managed_test_kv_store_p3"]);
+
+    // Range reads only scan the partitions they overlap.
+    let plan: Vec<String> = client
+        .query("EXPLAIN SELECT * FROM managed_test_kv_store WHERE key >= '\\x900120' AND key < '\\x900130'", &[])
+        .await
+        .expect("Failed to explain")
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    assert_eq!(plan.iter().filter(|line| line.contains("managed_test_kv_store_p")).count(), 1, "{plan:#?}");
+
+    // The archived partition is exported and dropped.
+    let archived = postgres.archived_partitions().await.expect("Listing the archived partitions failed");
+    assert_eq!(archived, report.archived);
+    let export: Vec<u8> = postgres
+        .export_archived_partition(&archived[0])
+        .map_ok(|chunk| chunk.to_vec())
+        .try_concat()
+        .await
+        .expect("Export failed");
+    assert!(export.starts_with(b"PGCOPY\n\xff\r\n\0"));
+    postgres.drop_archived_partition(&archived[0]).await.expect("Dropping the archived partition failed");
+    assert!(postgres.archived_partitions().await.expect("Listing the archived partitions failed").is_empty());
+    let other = denokv_postgres::ArchivedPartition { table: "managed_test_kv_store".to_string(), start: None, end: None };
+    assert!(postgres.drop_archived_partition(&other).await.is_err());
+}
+
+#[tokio::test]
+async fn test_postgres_splits_hash_partitions() {
+    // Skip test if no PostgreSQL is available
+    if std::env::var("POSTGRES_URL").is_err() {
+        println!("Skipping PostgreSQL test - POSTGRES_URL not set");
+        return;
+    }
+
+    let postgres_url = std::env::var("POSTGRES_URL").unwrap();
+    let (client, connection) = tokio_postgres::connect(&postgres_url, tokio_postgres::NoTls)
+        .await
+        .expect("Failed to connect");
+    tokio::spawn(connection);
+    client
+        .batch_execute("DROP TABLE IF EXISTS hash_managed_test_kv_store CASCADE")
+        .await
+        .expect("Failed to drop the table");
+
+    let config = PostgresConfig::new(postgres_url)
+        .with_table_prefix("hash_managed_test_".to_string())
+        .with_table_partitioning(TablePartitioning::Hash { partitions: 2 })
+        .with_partition_split_rows(50);
+    let postgres = Postgres::new(config).await.expect("Failed to create PostgreSQL instance");
+    let keys: Vec<Vec<u8>> = (0..200u16).map(|i| i.to_be_bytes().to_vec()).collect();
+    for batch in keys.chunks(100) {
+        let write = AtomicWrite {
+            checks: vec![],
+            mutations: batch
+                .iter()
+                .map(|key| Mutation { key: key.clone(), kind: MutationKind::Set(KvValue::U64(1)), expire_at: None })
+                .collect(),
+            enqueues: vec![],
+        };
+        postgres.atomic_write(write).await.expect("Atomic write failed").expect("Atomic write was rejected");
+    }
+    client
+        .batch_execute("ANALYZE hash_managed_test_kv_store_p0; ANALYZE hash_managed_test_kv_store_p1")
+        .await
+        .expect("Failed to analyze");
+
+    let report = postgres.manage_partitions().await.expect("Managing the partitions failed");
+    assert_eq!(
+        report.split,
+        [
+            ("hash_managed_test_kv_store_p0".to_string(), "hash_managed_test_kv_store_p2".to_string()),
+            ("hash_managed_test_kv_store_p1".to_string(), "hash_managed_test_kv_store_p3".to_string()),
+        ]
+    );
+    let rows = client
+        .query("SELECT count(DISTINCT tableoid) AS partitions, count(*) AS keys FROM hash_managed_test_kv_store", &[])
+        .await
+        .expect("Failed to count the partitions");
+    assert_eq!((rows[0].get::<_, i64>("partitions"), rows[0].get::<_, i64>("keys")), (4, 200));
+
+    let read = ReadRange { start: vec![], end: vec![0xff], limit: NonZeroU32::new(1000).unwrap(), reverse: false };
+    let outputs = postgres
+        .snapshot_read(vec![read], SnapshotReadOptions { consistency: Consistency::Strong })
+        .await
+        .expect("Snapshot read failed");
+    let read_keys: Vec<_> = outputs[0].entries.iter().map(|entry| entry.key.clone()).collect();
+    assert_eq!(read_keys, keys);
+}
@@ -0,0 +1,228 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use denokv_proto::{AtomicWrite, Mutation};
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+use crate::error::{PostgresError, PostgresResult};
+
+/// Limits the rate of writes reaching the primary.
+///
+/// Writes take tokens from an operations bucket and a bytes bucket, each
+/// refilled at its configured rate and holding at most one second's worth.
+/// Writes that can not be admitted yet wait in FIFO order; once
+/// `max_waiting` writes are waiting, further writes fail immediately with
+/// [`PostgresError::Backpressure`].
+pub struct WriteThrottle {
+    buckets: Mutex<Buckets>,
+    /// Rates set by [`WriteThrottle::set_rates`], applied by the next write.
+    new_rates: std::sync::Mutex<Option<(Option<f64>, Option<f64>)>>,
+    waiting: AtomicUsize,
+    max_waiting: usize,
+}
+
+struct Buckets {
+    ops: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl Buckets {
+    fn set_rates(&mut self, ops_per_sec: Option<f64>, bytes_per_sec: Option<f64>, now: Instant) {
+        for (bucket, rate) in [(&mut self.ops, ops_per_sec), (&mut self.bytes, bytes_per_sec)] {
+            match (bucket.as_mut(), rate.filter(|r| *r > 0.0)) {
+                (Some(current), Some(rate)) => {
+                    current.refill(now);
+                    current.rate = rate;
+                    current.tokens = current.tokens.min(rate);
+                }
+                (None, Some(rate)) => *bucket = Some(TokenBucket::new(rate)),
+                (_, None) => *bucket = None,
+            }
+        }
+    }
+}
+
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            tokens: rate,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled_at = now;
+    }
+
+    /// How long until `cost` tokens can be taken. A cost above the bucket
+    /// size only has to wait for a full bucket and leaves it in debt.
+    fn wait_for(&self, cost: f64) -> Duration {
+        let needed = cost.min(self.rate) - self.tokens;
+        if needed <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(needed / self.rate)
+        }
+    }
+}
+
+impl WriteThrottle {
+    /// Returns `None` if neither rate is set.
+    pub fn new(ops_per_sec: Option<f64>, bytes_per_sec: Option<f64>, max_waiting: usize) -> Option<Self> {
+        let bucket = |rate: Option<f64>| rate.filter(|r| *r > 0.0).map(TokenBucket::new);
+        let buckets = Buckets {
+            ops: bucket(ops_per_sec),
+            bytes: bucket(bytes_per_sec),
+        };
+        if buckets.ops.is_none() && buckets.bytes.is_none() {
+            return None;
+        }
+        Some(Self {
+            buckets: Mutex::new(buckets),
+            new_rates: std::sync::Mutex::new(None),
+            waiting: AtomicUsize::new(0),
+            max_waiting,
+        })
+    }
+
+    /// Change the rates. A rate that is not set no longer limits writes.
+    pub fn set_rates(&self, ops_per_sec: Option<f64>, bytes_per_sec: Option<f64>) {
+        *self.new_rates.lock().unwrap() = Some((ops_per_sec, bytes_per_sec));
+    }
+
+    /// Wait until a write of `ops` operations and `bytes` bytes may proceed.
+    pub async fn acquire(&self, ops: u64, bytes: u64) -> PostgresResult<()> {
+        if self.waiting.fetch_add(1, Ordering::SeqCst) >= self.max_waiting {
+            self.waiting.fetch_sub(1, Ordering::SeqCst);
+            return Err(PostgresError::Backpressure);
+        }
+        let _waiting = WaitingGuard(&self.waiting);
+
+        // The lock is held while sleeping, which makes it the FIFO queue.
+        let mut guard = self.buckets.lock().await;
+        let buckets = &mut *guard;
+        loop {
+            let now = Instant::now();
+            if let Some((ops_per_sec, bytes_per_sec)) = self.new_rates.lock().unwrap().take() {
+                buckets.set_rates(ops_per_sec, bytes_per_sec, now);
+            }
+            let mut wait = Duration::ZERO;
+            for (bucket, cost) in [(&mut buckets.ops, ops), (&mut buckets.bytes, bytes)] {
+                if let Some(bucket) = bucket {
+                    bucket.refill(now);
+                    wait = wait.max(bucket.wait_for(cost as f64));
+                }
+            }
+            if wait.is_zero() {
+                break;
+            }
+            tokio::time::sleep(wait).await;
+        }
+        for (bucket, cost) in [(&mut buckets.ops, ops), (&mut buckets.bytes, bytes)] {
+            if let Some(bucket) = bucket {
+                bucket.tokens -= cost as f64;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Approximate number of bytes `mutations` write, for the bytes bucket.
+pub fn mutations_size<'a>(mutations: impl IntoIterator<Item = &'a Mutation>) -> u64 {
+    mutations
+        .into_iter()
+        .map(|mutation| {
+            let value = mutation
+                .kind
+                .value()
+                .map_or(0, |value| denokv_proto::encode_value(value).0.len());
+            (mutation.key.len() + value) as u64
+        })
+        .sum()
+}
+
+/// Approximate number of bytes `write` writes, including enqueued payloads.
+pub fn write_size(write: &AtomicWrite) -> u64 {
+    let enqueued: usize = write.enqueues.iter().map(|enqueue| enqueue.payload.len()).sum();
+    mutations_size(&write.mutations) + enqueued as u64
+}
+
+struct WaitingGuard<'a>(&'a AtomicUsize);
+
+impl Drop for WaitingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_once_burst_is_used() {
+        let throttle = WriteThrottle::new(Some(20.0), None, 100).unwrap();
+        let start = Instant::now();
+        for _ in 0..20 {
+            throttle.acquire(1, 0).await.unwrap();
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        throttle.acquire(1, 0).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_millis(50));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rejects_when_queue_is_full() {
+        let throttle = Arc::new(WriteThrottle::new(None, Some(10.0), 1).unwrap());
+        throttle.acquire(1, 10).await.unwrap();
+
+        let mut waiter = {
+            let throttle = throttle.clone();
+            tokio::spawn(async move { throttle.acquire(1, 1).await })
+        };
+        // Let the waiter take its place in the queue; it waits 100ms for
+        // a token.
+        tokio::time::advance(Duration::from_millis(50)).await;
+        assert!(futures::poll!(&mut waiter).is_pending());
+        assert!(matches!(throttle.acquire(1, 1).await, Err(PostgresError::Backpressure)));
+        tokio::time::advance(Duration::from_millis(50)).await;
+        waiter.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn applies_new_rates() {
+        let throttle = WriteThrottle::new(Some(1.0), None, 100).unwrap();
+        throttle.acquire(1, 0).await.unwrap();
+        throttle.set_rates(Some(1000.0), None);
+        let start = Instant::now();
+        throttle.acquire(1, 0).await.unwrap();
+        throttle.acquire(1, 0).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        throttle.set_rates(None, None);
+        let start = Instant::now();
+        for _ in 0..2000 {
+            throttle.acquire(1, 0).await.unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn disabled_without_rates() {
+        assert!(WriteThrottle::new(None, None, 10).is_none());
+        assert!(WriteThrottle::new(Some(0.0), None, 10).is_none());
+    }
+}
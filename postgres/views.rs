@@ -0,0 +1,171 @@
+// Copyright 2023 rawkakani. All rights reserved. MIT license.
+
+use std::sync::Arc;
+
+use denokv_proto::{KvEntry, KvValue};
+
+use crate::backend::ViewRow;
+use crate::error::{PostgresError, PostgresResult};
+use crate::Postgres;
+
+/// How many source changes a view applies per transaction.
+const VIEW_BATCH_SIZE: i64 = 500;
+
+type Transform = dyn Fn(&KvEntry) -> Vec<(Vec<u8>, KvValue)> + Send + Sync;
+
+/// Entries under a target prefix derived from the keys under a source
+/// prefix, such as an index of users by email.
+///
+/// The transform maps each source entry to the view entries it derives, as
+/// encoded keys relative to the target prefix and their values. Views are
+/// brought up to date by [`Postgres::refresh_view`], or continuously by
+/// [`Postgres::maintain_view`], from the change feed of the source prefix:
+/// when a source key changes or is deleted, the view entries it derived
+/// before are replaced. Each batch of changes is applied in one transaction
+/// together with the position of the view in the feed, so a restarted
+/// maintainer resumes where the last one stopped and no change is applied
+/// twice.
+///
+/// A view key derived from two source keys belongs to whichever changed
+/// last, and goes away when that one changes; derive unique keys, e.g. by
+/// ending them with the source key, for views that are not one-to-one.
+#[derive(Clone)]
+pub struct MaterializedView {
+    name: String,
+    source_prefix: Vec<u8>,
+    target_prefix: Vec<u8>,
+    transform: Arc<Transform>,
+}
+
+impl MaterializedView {
+    /// The view `name`, whose position in the change feed is stored under
+    /// that name. The prefixes must not overlap.
+    pub fn new(
+        name: impl Into<String>,
+        source_prefix: Vec<u8>,
+        target_prefix: Vec<u8>,
+        transform: impl Fn(&KvEntry) -> Vec<(Vec<u8>, KvValue)> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            source_prefix,
+            target_prefix,
+            transform: Arc::new(transform),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// A background task keeping a [`MaterializedView`] up to date, created by
+/// [`Postgres::maintain_view`]. The task stops when this is dropped.
+pub struct ViewMaintainer {
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for ViewMaintainer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl ViewMaintainer {
+    pub(crate) fn new(postgres: Postgres, view: MaterializedView) -> Self {
+        Self { task: tokio::spawn(maintain(postgres, view)) }
+    }
+}
+
+/// Refresh `view` whenever its source may have changed, until the task is
+/// aborted.
+async fn maintain(postgres: Postgres, view: MaterializedView) {
+    let mut local_writes = postgres.notifier.subscribe_prefix(view.source_prefix.clone());
+    loop {
+        if let Err(e) = refresh(&postgres, &view).await {
+            log::warn!("Failed to refresh the view {:?}: {e}", view.name);
+        }
+        // Writes through other instances are only notified here while
+        // listening for changes, so poll again after a while regardless.
+        let _ = tokio::time::timeout(crate::SUBSCRIPTION_POLL_INTERVAL, local_writes.wait_for_change()).await;
+    }
+}
+
+/// Apply the changes to the source of `view` since its position, and
+/// return how many were applied.
+pub(crate) async fn refresh(postgres: &Postgres, view: &MaterializedView) -> PostgresResult<u64> {
+    if prefixes_overlap(&view.source_prefix, &view.target_prefix) {
+        return Err(PostgresError::InvalidConfig(format!(
+            "The source and target prefixes of view {:?} overlap", view.name
+        )));
+    }
+    let backend = &postgres.backend;
+    backend.register_view(&view.name, &view.source_prefix, &view.target_prefix).await?;
+
+    let mut applied = 0;
+    loop {
+        let (after, after_key) = backend.view_cursor(&view.name).await?;
+        let changes = match backend.poll_changes(&view.source_prefix, &after, &after_key, VIEW_BATCH_SIZE).await {
+            Ok(changes) => changes,
+            Err(PostgresError::ChangefeedTruncated) => {
+                // Deletions since the position are gone, so the view can
+                // only be rebuilt from scratch.
+                log::warn!("The view {:?} fell behind the tombstone retention and is rebuilt", view.name);
+                let mut conn = postgres.get_connection().await?;
+                if let Some(keys) = backend.clear_view(&mut conn, &view.name, false).await? {
+                    postgres.notify_keys(&keys);
+                }
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let Some(last) = changes.last() else {
+            return Ok(applied);
+        };
+        let next = (last.versionstamp, last.key.clone());
+
+        let rows: Vec<ViewRow> = changes
+            .iter()
+            .map(|change| ViewRow {
+                source_key: change.key.clone(),
+                entries: match &change.value {
+                    Some(value) => (view.transform)(&KvEntry {
+                        key: change.key.clone(),
+                        value: value.clone(),
+                        versionstamp: change.versionstamp,
+                    }),
+                    None => Vec::new(),
+                },
+            })
+            .collect();
+        let mut conn = postgres.get_connection().await?;
+        let written = backend
+            .apply_view_batch(&mut conn, &view.name, (&after, &after_key), (&next.0, &next.1), &rows)
+            .await?;
+        // Nothing is written if another maintainer applied the batch first;
+        // carry on from wherever it got to.
+        if let Some(keys) = written {
+            postgres.notify_keys(&keys);
+            applied += changes.len() as u64;
+        }
+    }
+}
+
+/// Whether a key can start with both prefixes, so that writing the view
+/// could change its own source.
+fn prefixes_overlap(a: &[u8], b: &[u8]) -> bool {
+    a.starts_with(b) || b.starts_with(a)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlapping_prefixes() {
+        assert!(prefixes_overlap(b"\x02users\x00", b"\x02users\x00"));
+        assert!(prefixes_overlap(b"\x02users\x00", b"\x02users\x00\x02by_email\x00"));
+        assert!(prefixes_overlap(b"", b"\x02by_email\x00"));
+        assert!(!prefixes_overlap(b"\x02users\x00", b"\x02users_by_email\x00"));
+    }
+}
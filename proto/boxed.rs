@@ -0,0 +1,145 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! A [`Database`] whose implementation is chosen at run time.
+//!
+//! [`Database`] is not object safe, since every implementation has a queue
+//! message handle type of its own. Every one is also a [`DynDatabase`],
+//! which boxes the handles, so backends of different types can be kept
+//! behind `Box<dyn DynDatabase>`, such as by factories and wrappers that
+//! pick or combine them. [`BoxedDatabase`] turns such an object back into a
+//! [`Database`].
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use deno_error::JsErrorBox;
+
+use crate::AtomicWrite;
+use crate::CommitResult;
+use crate::Database;
+use crate::QueueMessageHandle;
+use crate::ReadRange;
+use crate::ReadRangeOutput;
+use crate::SnapshotReadOptions;
+use crate::WatchStream;
+
+/// Any [`Database`] behind one type, such as one picked from a URL in the
+/// configuration. Its queue messages are boxed too. Cloning it clones the
+/// handle, not the database.
+#[derive(Clone)]
+pub struct BoxedDatabase(Arc<dyn DynDatabase>);
+
+impl BoxedDatabase {
+  pub fn new<D>(database: D) -> Self
+  where
+    D: Database + Send + Sync + 'static,
+  {
+    Self(Arc::new(database))
+  }
+}
+
+impl From<Arc<dyn DynDatabase>> for BoxedDatabase {
+  fn from(database: Arc<dyn DynDatabase>) -> Self {
+    Self(database)
+  }
+}
+
+impl From<Box<dyn DynDatabase>> for BoxedDatabase {
+  fn from(database: Box<dyn DynDatabase>) -> Self {
+    Self(database.into())
+  }
+}
+
+/// The object safe part of [`Database`], implemented by every database.
+/// Its queue message handles are boxed.
+#[async_trait]
+pub trait DynDatabase: Send + Sync {
+  async fn snapshot_read(
+    &self,
+    requests: Vec<ReadRange>,
+    options: SnapshotReadOptions,
+  ) -> Result<Vec<ReadRangeOutput>, JsErrorBox>;
+
+  async fn atomic_write(
+    &self,
+    write: AtomicWrite,
+  ) -> Result<Option<CommitResult>, JsErrorBox>;
+
+  async fn dequeue_next_message(
+    &self,
+  ) -> Result<Option<Box<dyn QueueMessageHandle>>, JsErrorBox>;
+
+  fn watch(&self, keys: Vec<Vec<u8>>) -> WatchStream;
+
+  fn close(&self);
+}
+
+#[async_trait]
+impl<D> DynDatabase for D
+where
+  D: Database + Send + Sync + 'static,
+{
+  async fn snapshot_read(
+    &self,
+    requests: Vec<ReadRange>,
+    options: SnapshotReadOptions,
+  ) -> Result<Vec<ReadRangeOutput>, JsErrorBox> {
+    Database::snapshot_read(self, requests, options).await
+  }
+
+  async fn atomic_write(
+    &self,
+    write: AtomicWrite,
+  ) -> Result<Option<CommitResult>, JsErrorBox> {
+    Database::atomic_write(self, write).await
+  }
+
+  async fn dequeue_next_message(
+    &self,
+  ) -> Result<Option<Box<dyn QueueMessageHandle>>, JsErrorBox> {
+    let message = Database::dequeue_next_message(self).await?;
+    Ok(message.map(|message| Box::new(message) as Box<dyn QueueMessageHandle>))
+  }
+
+  fn watch(&self, keys: Vec<Vec<u8>>) -> WatchStream {
+    Database::watch(self, keys)
+  }
+
+  fn close(&self) {
+    Database::close(self)
+  }
+}
+
+#[async_trait]
+impl Database for BoxedDatabase {
+  type QMH = Box<dyn QueueMessageHandle>;
+
+  async fn snapshot_read(
+    &self,
+    requests: Vec<ReadRange>,
+    options: SnapshotReadOptions,
+  ) -> Result<Vec<ReadRangeOutput>, JsErrorBox> {
+    self.0.snapshot_read(requests, options).await
+  }
+
+  async fn atomic_write(
+    &self,
+    write: AtomicWrite,
+  ) -> Result<Option<CommitResult>, JsErrorBox> {
+    self.0.atomic_write(write).await
+  }
+
+  async fn dequeue_next_message(
+    &self,
+  ) -> Result<Option<Self::QMH>, JsErrorBox> {
+    self.0.dequeue_next_message().await
+  }
+
+  fn watch(&self, keys: Vec<Vec<u8>>) -> WatchStream {
+    self.0.watch(keys)
+  }
+
+  fn close(&self) {
+    self.0.close()
+  }
+}
@@ -540,4 +540,17 @@ mod tests {
       ],
     );
   }
+  #[test]
+  fn versionstamped_key() {
+    let key = encode_key(&Key(vec![KeyPart::String("log".into())])).unwrap();
+    let versionstamp = [0, 0, 0, 0, 0, 0, 0x12, 0xab, 0, 0];
+    let versionstamped = crate::versionstamped_key(&key, &versionstamp);
+    assert_eq!(
+      decode_key(&versionstamped).unwrap(),
+      Key(vec![
+        KeyPart::String("log".into()),
+        KeyPart::String("00000000000012ab0000".into()),
+      ])
+    );
+  }
 }
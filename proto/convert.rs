@@ -245,7 +245,10 @@ impl From<Vec<ReadRangeOutput>> for pb::SnapshotReadOutput {
     let mut ranges = Vec::with_capacity(result_ranges.len());
     for range in result_ranges {
       let values = range.entries.into_iter().map(Into::into).collect();
-      ranges.push(pb::ReadRangeOutput { values });
+      ranges.push(pb::ReadRangeOutput {
+        values,
+        continuation: None,
+      });
     }
 
     pb::SnapshotReadOutput {
@@ -322,6 +325,7 @@ impl From<Vec<WatchKeyOutput>> for pb::WatchOutput {
           keys.push(pb::WatchKeyOutput {
             changed: true,
             entry_if_changed: entry.map(Into::into),
+            value_delta: None,
           });
         }
       }
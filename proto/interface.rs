@@ -159,6 +159,10 @@ impl PartialOrd for KeyPart {
 /// The range is inclusive of the start and exclusive of the end. The start may
 /// not be greater than the end.
 ///
+/// An empty `end`, or an `end` starting with `0xFF`, is a sentinel meaning the
+/// range extends to the end of the keyspace. No encoded key starts with
+/// `0xFF`, so both are above every key that can be stored.
+///
 /// The range is limited to `limit` number of entries.
 #[derive(Clone, Debug)]
 pub struct ReadRange {
@@ -168,6 +172,14 @@ pub struct ReadRange {
   pub reverse: bool,
 }
 
+impl ReadRange {
+  /// Whether `end` is the end-of-keyspace sentinel, in which case backends
+  /// must not apply an upper bound.
+  pub fn is_open_ended(&self) -> bool {
+    self.end.first().is_none_or(|b| *b == 0xff)
+  }
+}
+
 /// A response to a `ReadRange` request.
 #[derive(Debug)]
 pub struct ReadRangeOutput {
@@ -186,6 +198,16 @@ pub struct KvEntry {
   pub versionstamp: Versionstamp,
 }
 
+/// A committed change to a single key, as delivered by a change feed.
+#[derive(Debug)]
+pub struct KvChange {
+  pub key: Vec<u8>,
+  /// The new value of the key, or `None` if the key was deleted.
+  pub value: Option<KvValue>,
+  pub versionstamp: Versionstamp,
+  pub expire_at: Option<DateTime<Utc>>,
+}
+
 /// A serialized value for a KV pair as stored in the database. All values
 /// **can** be serialized into the V8 representation, but not all values are.
 ///
@@ -320,6 +342,15 @@ pub struct Enqueue {
 /// the database must match the type of the value specified in the mutation. If
 /// the key does not exist in the database, then the value specified in the
 /// mutation is used as the new value of the key.
+///
+/// ## SetSuffixVersionstampedKey
+///
+/// The set suffix versionstamped key mutation sets the value of a new key,
+/// made of the specified key and the versionstamp of the commit, as
+/// [versionstamped_key] builds it. The keys are returned in
+/// [CommitResult::versionstamped_keys].
+///
+/// This operand supports all [Value] types.
 #[derive(Clone, Debug)]
 pub enum MutationKind {
   Set(KvValue),
@@ -353,6 +384,46 @@ impl MutationKind {
 pub struct CommitResult {
   /// The new versionstamp of the data that was committed.
   pub versionstamp: Versionstamp,
+  /// Whether the commit waited for the write to reach durable storage.
+  /// `false` if the backend was asked to acknowledge writes before
+  /// flushing them, in which case a crash shortly after can lose this one.
+  pub durable: bool,
+  /// The keys written by the [MutationKind::SetSuffixVersionstampedKey]
+  /// mutations of the write, in the order of the mutations.
+  pub versionstamped_keys: Vec<Vec<u8>>,
+}
+
+/// The key a [MutationKind::SetSuffixVersionstampedKey] mutation of `key`
+/// writes when committed at `versionstamp`: the key followed by a string
+/// key part holding the versionstamp in hex.
+pub fn versionstamped_key(key: &[u8], versionstamp: &Versionstamp) -> Vec<u8> {
+  const HEX: &[u8; 16] = b"0123456789abcdef";
+  let mut versionstamped = Vec::with_capacity(key.len() + 22);
+  versionstamped.extend_from_slice(key);
+  // A string key part. Hex digits never need escaping.
+  versionstamped.push(0x02);
+  for byte in versionstamp {
+    versionstamped.push(HEX[(byte >> 4) as usize]);
+    versionstamped.push(HEX[(byte & 0xf) as usize]);
+  }
+  versionstamped.push(0x00);
+  versionstamped
+}
+
+/// The keys the [MutationKind::SetSuffixVersionstampedKey] mutations of
+/// `mutations` write when committed at `versionstamp`, as reported in
+/// [CommitResult::versionstamped_keys].
+pub fn versionstamped_keys(
+  mutations: &[Mutation],
+  versionstamp: &Versionstamp,
+) -> Vec<Vec<u8>> {
+  mutations
+    .iter()
+    .filter(|mutation| {
+      matches!(mutation.kind, MutationKind::SetSuffixVersionstampedKey(_))
+    })
+    .map(|mutation| versionstamped_key(&mutation.key, versionstamp))
+    .collect()
 }
 
 #[derive(Debug)]
@@ -365,31 +436,89 @@ pub enum WatchKeyOutput {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct MetadataExchangeRequest {
   #[serde(default)]
   pub supported_versions: Vec<u64>,
+  /// Names of the protocol extensions the client understands.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub features: Vec<String>,
+}
+
+/// The header in which data path requests name the extensions they use.
+pub const FEATURES_HEADER: &str = "x-denokv-features";
+
+/// An extension to the KV Connect protocol.
+///
+/// Clients list the extensions they understand in the metadata exchange and
+/// the server answers with those it supports too. Data path requests then
+/// name the extensions they rely on in the [`FEATURES_HEADER`], so clients
+/// that do not know about an extension keep the standard behavior.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+  /// Watch outputs may carry deltas against the previously delivered value,
+  /// if the watch request also sets `accept_value_deltas`.
+  ValueDeltas,
+  /// Snapshot reads cut short by the server's response budget return a
+  /// continuation. Without it, such reads fail instead.
+  ReadContinuations,
+  /// Watches can also be opened as a WebSocket at `watch_ws`, for networks
+  /// that break long-lived HTTP responses.
+  WebsocketWatch,
+}
+
+impl Feature {
+  pub const ALL: &'static [Feature] = &[
+    Feature::ValueDeltas,
+    Feature::ReadContinuations,
+    Feature::WebsocketWatch,
+  ];
+
+  pub fn name(self) -> &'static str {
+    match self {
+      Feature::ValueDeltas => "value_deltas",
+      Feature::ReadContinuations => "read_continuations",
+      Feature::WebsocketWatch => "websocket_watch",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<Self> {
+    Self::ALL
+      .iter()
+      .copied()
+      .find(|feature| feature.name() == name)
+  }
 }
 
 /// The database metadata that is returned by the KV Connect metadata endpoint.
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct DatabaseMetadata {
   pub version: u64,
   pub database_id: Uuid,
   pub endpoints: Vec<EndpointInfo>,
+  #[cfg_attr(feature = "openapi", schema(value_type = String))]
   pub token: Cow<'static, str>,
   pub expires_at: DateTime<Utc>,
+  /// The requested extensions the server supports. Omitted if there are
+  /// none, so that the response stays the same for standard clients.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub features: Vec<String>,
 }
 
 /// An endpoint that can be used to connect to the database.
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct EndpointInfo {
+  #[cfg_attr(feature = "openapi", schema(value_type = String))]
   pub url: Cow<'static, str>,
 
   // Using `String` instead of an enum, so that parsing doesn't
   // break if more consistency levels are added.
+  #[cfg_attr(feature = "openapi", schema(value_type = String))]
   pub consistency: Cow<'static, str>,
 }
 
@@ -0,0 +1,384 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! Human-readable keys, for logs and admin tooling.
+//!
+//! An encoded key is shown as the tuple it encodes, like
+//! `["users", 42, "profile"]`. Integers are written without a decimal
+//! point and floats always with one (or as `NaN`, `Infinity` or
+//! `-Infinity`), byte arrays as `b"..."`, and booleans as `true` and
+//! `false`. Bytes that are not a valid key are shown as `0x` and their hex
+//! encoding. [`parse_key`] reads both forms back.
+
+use std::fmt;
+use std::fmt::Write;
+use std::str::FromStr;
+
+use num_bigint::BigInt;
+
+use crate::decode_key;
+use crate::encode_key;
+use crate::Key;
+use crate::KeyPart;
+
+/// The encoded key `bytes` in a human-readable form.
+pub fn format_key(bytes: &[u8]) -> String {
+  let Ok(key) = decode_key(bytes) else {
+    let mut out = String::with_capacity(2 + bytes.len() * 2);
+    out.push_str("0x");
+    for b in bytes {
+      write!(out, "{b:02x}").unwrap();
+    }
+    return out;
+  };
+
+  let mut out = String::from("[");
+  for (i, part) in key.0.iter().enumerate() {
+    if i > 0 {
+      out.push_str(", ");
+    }
+    match part {
+      KeyPart::String(s) => {
+        out.push('"');
+        for c in s.chars() {
+          match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+              write!(out, "\\u{{{:x}}}", c as u32).unwrap()
+            }
+            c => out.push(c),
+          }
+        }
+        out.push('"');
+      }
+      KeyPart::Bytes(data) => {
+        out.push_str("b\"");
+        for &b in data {
+          match b {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b' '..=b'~' => out.push(b as char),
+            b => write!(out, "\\x{b:02x}").unwrap(),
+          }
+        }
+        out.push('"');
+      }
+      KeyPart::Int(n) => write!(out, "{n}").unwrap(),
+      KeyPart::Float(n) if n.is_nan() => out.push_str("NaN"),
+      KeyPart::Float(n) if n.is_infinite() => {
+        out.push_str(if *n > 0.0 { "Infinity" } else { "-Infinity" })
+      }
+      // Debug always writes a decimal point or an exponent, which tells
+      // floats apart from integers.
+      KeyPart::Float(n) => write!(out, "{n:?}").unwrap(),
+      KeyPart::False => out.push_str("false"),
+      KeyPart::True => out.push_str("true"),
+    }
+  }
+  out.push(']');
+  out
+}
+
+/// Encode a key written as [`format_key`] writes it.
+pub fn parse_key(input: &str) -> Result<Vec<u8>, ParseKeyError> {
+  let input = input.trim();
+  if let Some(hex) = input.strip_prefix("0x") {
+    return parse_hex(hex);
+  }
+  let mut parser = Parser { input, pos: 0 };
+  let key = parser.key()?;
+  parser.skip_whitespace();
+  if parser.pos < input.len() {
+    return Err(parser.error("unexpected input after the key"));
+  }
+  encode_key(&key).map_err(|_| parser.error("the key can not be encoded"))
+}
+
+/// Why a key could not be parsed, and where.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseKeyError {
+  /// The byte offset into the input at which parsing failed.
+  pub position: usize,
+  message: &'static str,
+}
+
+impl fmt::Display for ParseKeyError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "invalid key: {} at position {}",
+      self.message, self.position
+    )
+  }
+}
+
+impl std::error::Error for ParseKeyError {}
+
+fn parse_hex(hex: &str) -> Result<Vec<u8>, ParseKeyError> {
+  let error = |position| ParseKeyError {
+    position,
+    message: "invalid hex",
+  };
+  if !hex.len().is_multiple_of(2) {
+    return Err(error(2 + hex.len()));
+  }
+  (0..hex.len())
+    .step_by(2)
+    .map(|i| {
+      hex
+        .get(i..i + 2)
+        .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        .ok_or(error(2 + i))
+    })
+    .collect()
+}
+
+struct Parser<'a> {
+  input: &'a str,
+  pos: usize,
+}
+
+impl Parser<'_> {
+  fn error(&self, message: &'static str) -> ParseKeyError {
+    ParseKeyError {
+      position: self.pos,
+      message,
+    }
+  }
+
+  fn rest(&self) -> &str {
+    &self.input[self.pos..]
+  }
+
+  fn peek(&self) -> Option<char> {
+    self.rest().chars().next()
+  }
+
+  fn next(&mut self) -> Option<char> {
+    let c = self.peek()?;
+    self.pos += c.len_utf8();
+    Some(c)
+  }
+
+  fn eat(&mut self, s: &str) -> bool {
+    if self.rest().starts_with(s) {
+      self.pos += s.len();
+      true
+    } else {
+      false
+    }
+  }
+
+  fn skip_whitespace(&mut self) {
+    while let Some(c) = self.peek().filter(|c| c.is_whitespace()) {
+      self.pos += c.len_utf8();
+    }
+  }
+
+  fn key(&mut self) -> Result<Key, ParseKeyError> {
+    if !self.eat("[") {
+      return Err(self.error("expected '['"));
+    }
+    let mut parts = Vec::new();
+    loop {
+      self.skip_whitespace();
+      if self.eat("]") {
+        return Ok(Key(parts));
+      }
+      parts.push(self.part()?);
+      self.skip_whitespace();
+      if !self.eat(",") && self.peek() != Some(']') {
+        return Err(self.error("expected ',' or ']'"));
+      }
+    }
+  }
+
+  fn part(&mut self) -> Result<KeyPart, ParseKeyError> {
+    if self.eat("\"") {
+      return Ok(KeyPart::String(self.string()?));
+    }
+    if self.eat("b\"") {
+      return Ok(KeyPart::Bytes(self.bytes()?));
+    }
+    // `-Infinity` before numbers, as it starts like one.
+    for (word, part) in [
+      ("true", KeyPart::True),
+      ("false", KeyPart::False),
+      ("NaN", KeyPart::Float(f64::NAN)),
+      ("Infinity", KeyPart::Float(f64::INFINITY)),
+      ("-Infinity", KeyPart::Float(f64::NEG_INFINITY)),
+    ] {
+      if self.eat(word) {
+        return Ok(part);
+      }
+    }
+    self.number()
+  }
+
+  fn number(&mut self) -> Result<KeyPart, ParseKeyError> {
+    let start = self.pos;
+    let len = self
+      .rest()
+      .find(|c: char| {
+        !(c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E'))
+      })
+      .unwrap_or(self.rest().len());
+    let number = &self.rest()[..len];
+    if number.is_empty() {
+      return Err(self.error("expected a key part"));
+    }
+    let part = if number.contains(['.', 'e', 'E']) {
+      f64::from_str(number).ok().map(KeyPart::Float)
+    } else {
+      BigInt::from_str(number).ok().map(KeyPart::Int)
+    };
+    self.pos += len;
+    part.ok_or(ParseKeyError {
+      position: start,
+      message: "invalid number",
+    })
+  }
+
+  /// The rest of a string whose opening quote was consumed.
+  fn string(&mut self) -> Result<String, ParseKeyError> {
+    let mut out = String::new();
+    loop {
+      match self.next() {
+        Some('"') => return Ok(out),
+        Some('\\') => out.push(self.escape()?),
+        Some(c) => out.push(c),
+        None => return Err(self.error("unterminated string")),
+      }
+    }
+  }
+
+  fn escape(&mut self) -> Result<char, ParseKeyError> {
+    match self.next() {
+      Some('"') => Ok('"'),
+      Some('\\') => Ok('\\'),
+      Some('n') => Ok('\n'),
+      Some('r') => Ok('\r'),
+      Some('t') => Ok('\t'),
+      Some('u') if self.eat("{") => {
+        let len = self.rest().find('}').unwrap_or(0);
+        let c = u32::from_str_radix(&self.rest()[..len], 16)
+          .ok()
+          .and_then(char::from_u32)
+          .ok_or(self.error("invalid unicode escape"))?;
+        self.pos += len + 1;
+        Ok(c)
+      }
+      _ => Err(self.error("invalid escape")),
+    }
+  }
+
+  /// The rest of a byte string whose opening quote was consumed.
+  fn bytes(&mut self) -> Result<Vec<u8>, ParseKeyError> {
+    let mut out = Vec::new();
+    loop {
+      match self.next() {
+        Some('"') => return Ok(out),
+        Some('\\') if self.eat("x") => {
+          let byte = self
+            .rest()
+            .get(..2)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .ok_or(self.error("invalid byte escape"))?;
+          self.pos += 2;
+          out.push(byte);
+        }
+        Some('\\') => match self.next() {
+          Some('"') => out.push(b'"'),
+          Some('\\') => out.push(b'\\'),
+          _ => return Err(self.error("invalid escape")),
+        },
+        Some(c) if c.is_ascii() => out.push(c as u8),
+        Some(_) => return Err(self.error("non-ASCII character in bytes")),
+        None => return Err(self.error("unterminated bytes")),
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn key(parts: Vec<KeyPart>) -> Vec<u8> {
+    encode_key(&Key(parts)).unwrap()
+  }
+
+  #[test]
+  fn round_trips() {
+    let keys = [
+      key(vec![
+        KeyPart::String("users".to_string()),
+        KeyPart::Int(42.into()),
+        KeyPart::String("profile".to_string()),
+      ]),
+      key(vec![]),
+      key(vec![
+        KeyPart::String("quote \" slash \\ tab \t bell \u{7} é".to_string()),
+        KeyPart::Bytes(vec![0, b'a', b'"', 0xff]),
+        KeyPart::Int((-12345678901234567890i128).into()),
+        KeyPart::Float(1.0),
+        KeyPart::Float(-0.0),
+        KeyPart::Float(1e-300),
+        KeyPart::Float(f64::NEG_INFINITY),
+        KeyPart::Float(f64::NAN),
+        KeyPart::False,
+        KeyPart::True,
+      ]),
+      vec![0xfd, 0x03],
+    ];
+    for bytes in keys {
+      let formatted = format_key(&bytes);
+      assert_eq!(parse_key(&formatted).unwrap(), bytes, "{formatted}");
+    }
+  }
+
+  #[test]
+  fn formats_tuples() {
+    let bytes = key(vec![
+      KeyPart::String("users".to_string()),
+      KeyPart::Int(42.into()),
+      KeyPart::Float(42.0),
+      KeyPart::Bytes(b"\x01id".to_vec()),
+      KeyPart::True,
+    ]);
+    assert_eq!(
+      format_key(&bytes),
+      r#"["users", 42, 42.0, b"\x01id", true]"#
+    );
+    assert_eq!(format_key(&[0xfd, 0x03]), "0xfd03");
+  }
+
+  #[test]
+  fn parses_loosely_spaced_tuples() {
+    assert_eq!(
+      parse_key(" [ \"users\",42 , ] ").unwrap(),
+      key(vec![
+        KeyPart::String("users".to_string()),
+        KeyPart::Int(42.into())
+      ])
+    );
+  }
+
+  #[test]
+  fn rejects_invalid_keys() {
+    for (input, position) in [
+      ("users", 0),
+      ("[\"users\"", 8),
+      ("[\"users\" 42]", 9),
+      ("[1.2.3]", 1),
+      ("[b\"\\x0\"]", 5),
+      ("[] extra", 3),
+      ("0xabc", 5),
+    ] {
+      assert_eq!(parse_key(input).unwrap_err().position, position, "{input}");
+    }
+  }
+}
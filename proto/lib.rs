@@ -1,14 +1,37 @@
 // Copyright 2023 the Deno authors. All rights reserved. MIT license.
 
+mod boxed;
 mod codec;
 mod convert;
 mod interface;
-mod limits;
+mod key_format;
+pub mod limits;
 mod protobuf;
+#[cfg(feature = "pubsub")]
+pub mod pubsub;
+mod rate_limit;
+mod read_budget;
 pub mod time;
+#[cfg(feature = "v8_codec")]
+pub mod v8;
+mod watch_delta;
+#[cfg(feature = "workflow")]
+pub mod workflow;
+pub use crate::boxed::BoxedDatabase;
+pub use crate::boxed::DynDatabase;
 pub use crate::codec::decode_key;
 pub use crate::codec::encode_key;
 pub use crate::convert::ConvertError;
 pub use crate::interface::*;
+pub use crate::key_format::format_key;
+pub use crate::key_format::parse_key;
+pub use crate::key_format::ParseKeyError;
 pub use crate::protobuf::backup;
 pub use crate::protobuf::datapath;
+pub use crate::rate_limit::RateLimit;
+pub use crate::rate_limit::RateLimitCheck;
+pub use crate::rate_limit::RateLimitOutcome;
+pub use crate::rate_limit::RateLimiter;
+pub use crate::read_budget::ReadBudget;
+pub use crate::watch_delta::WatchOutputDecoder;
+pub use crate::watch_delta::WatchOutputEncoder;
@@ -56,6 +56,11 @@ pub struct ReadRangeOutput {
   /// The values read in the range. The values are in key order.
   #[prost(message, repeated, tag = "1")]
   pub values: ::prost::alloc::vec::Vec<KvEntry>,
+  /// Set if the server's response budget cut the range short. Reading the
+  /// range again with this as the start key (or, for reverse reads, the end
+  /// key) returns the rest of it.
+  #[prost(bytes = "vec", optional, tag = "2")]
+  pub continuation: ::core::option::Option<::prost::alloc::vec::Vec<u8>>,
 }
 /// A request to write some data.
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -180,6 +185,10 @@ pub struct Watch {
   /// The keys to watch.
   #[prost(message, repeated, tag = "1")]
   pub keys: ::prost::alloc::vec::Vec<WatchKey>,
+  /// Whether the client can apply `value_delta` in `WatchKeyOutput`. Servers
+  /// that do not support deltas ignore this and always send full values.
+  #[prost(bool, tag = "2")]
+  pub accept_value_deltas: bool,
 }
 /// The response to a watch request.
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -208,6 +217,25 @@ pub struct WatchKeyOutput {
   /// The new value, if changed is true.
   #[prost(message, optional, tag = "2")]
   pub entry_if_changed: ::core::option::Option<KvEntry>,
+  /// If set, `entry_if_changed.value` is empty and the new value is obtained
+  /// by applying this delta to the value last delivered for this key on the
+  /// same stream. Only sent if the request set `accept_value_deltas`.
+  #[prost(message, optional, tag = "3")]
+  pub value_delta: ::core::option::Option<ValueDelta>,
+}
+/// A value expressed as a change to a previous value `old`:
+/// `old\[..prefix_len\] ++ middle ++ old\[old.len() - suffix_len..\]`.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ValueDelta {
+  /// Number of leading bytes kept from the previous value.
+  #[prost(uint32, tag = "1")]
+  pub prefix_len: u32,
+  /// Number of trailing bytes kept from the previous value.
+  #[prost(uint32, tag = "2")]
+  pub suffix_len: u32,
+  /// The bytes between the kept prefix and suffix.
+  #[prost(bytes = "vec", tag = "3")]
+  pub middle: ::prost::alloc::vec::Vec<u8>,
 }
 /// The status of a read request.
 #[derive(
@@ -0,0 +1,244 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! Publish/subscribe over a [`Database`], with the `pubsub` feature.
+//!
+//! Channels are encoded keys, and messages are delivered one of two ways:
+//!
+//! - [`Delivery::AtMostOnce`] sets the channel key to the message, and
+//!   [`PubSub::subscribe`] watches it. Every subscriber gets the messages
+//!   published while it is subscribed, but one published while another
+//!   is still being delivered may replace it.
+//! - [`Delivery::AtLeastOnce`] enqueues the message, naming its channel,
+//!   and rings a doorbell under the channel key (`[...channel, "doorbell"]`)
+//!   that [`PubSub::listen`] watches to dequeue right away. Each message
+//!   goes to one listener, which acknowledges it when it is done, or gets
+//!   it again.
+//!
+//! The queue of a database is shared by all its consumers: a listener hands
+//! back every message of a channel it does not listen to, to be retried
+//! after the backoff of the queue. Give one listener all the at-least-once
+//! channels of a database, and do not consume its queue otherwise.
+
+use std::time::Duration;
+
+use deno_error::JsErrorBox;
+use futures::Stream;
+use futures::StreamExt;
+
+use crate::encode_key;
+use crate::time::utc_now;
+use crate::AtomicWrite;
+use crate::Database;
+use crate::Enqueue;
+use crate::Key;
+use crate::KeyPart;
+use crate::KvValue;
+use crate::Mutation;
+use crate::MutationKind;
+use crate::QueueMessageHandle;
+use crate::Versionstamp;
+use crate::WatchKeyOutput;
+
+/// Starts the payload of every message enqueued by [`PubSub::publish`],
+/// before the length of the channel, the channel and the message.
+const ENVELOPE_MAGIC: &[u8] = b"\x00denokv-pubsub\x00";
+
+/// How often a listener checks the queue without a doorbell, for messages
+/// that come back after a backoff.
+const LISTEN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delivery {
+  AtMostOnce,
+  AtLeastOnce,
+}
+
+/// A message received by [`PubSub::subscribe`].
+#[derive(Clone, Debug)]
+pub struct Message {
+  pub payload: Vec<u8>,
+  pub versionstamp: Versionstamp,
+}
+
+/// A message received by [`PubSub::listen`]. It is delivered again unless
+/// it is acknowledged.
+pub struct QueuedMessage<H: QueueMessageHandle> {
+  pub channel: Vec<u8>,
+  pub payload: Vec<u8>,
+  handle: H,
+}
+
+impl<H: QueueMessageHandle> QueuedMessage<H> {
+  /// The message was handled, and is not delivered again.
+  pub async fn ack(self) -> Result<(), JsErrorBox> {
+    self.handle.finish(true).await
+  }
+
+  /// The message could not be handled, and is delivered again after the
+  /// backoff of the queue.
+  pub async fn nack(self) -> Result<(), JsErrorBox> {
+    self.handle.finish(false).await
+  }
+}
+
+#[derive(Clone)]
+pub struct PubSub<D: Database> {
+  db: D,
+}
+
+impl<D: Database> PubSub<D> {
+  pub fn new(db: D) -> Self {
+    Self { db }
+  }
+
+  pub async fn publish(
+    &self,
+    channel: &[u8],
+    payload: Vec<u8>,
+    delivery: Delivery,
+  ) -> Result<Versionstamp, JsErrorBox> {
+    let write = match delivery {
+      Delivery::AtMostOnce => AtomicWrite {
+        checks: vec![],
+        mutations: vec![Mutation {
+          key: channel.to_vec(),
+          kind: MutationKind::Set(KvValue::Bytes(payload)),
+          expire_at: None,
+        }],
+        enqueues: vec![],
+      },
+      Delivery::AtLeastOnce => AtomicWrite {
+        checks: vec![],
+        mutations: vec![Mutation {
+          key: doorbell(channel),
+          kind: MutationKind::Sum {
+            value: KvValue::U64(1),
+            min_v8: vec![],
+            max_v8: vec![],
+            clamp: false,
+          },
+          expire_at: None,
+        }],
+        enqueues: vec![Enqueue {
+          payload: seal(channel, &payload),
+          deadline: utc_now(),
+          keys_if_undelivered: vec![],
+          backoff_schedule: None,
+        }],
+      },
+    };
+    let result = self.db.atomic_write(write).await?;
+    Ok(result.expect("writes without checks commit").versionstamp)
+  }
+
+  /// The messages published to `channel` with [`Delivery::AtMostOnce`]
+  /// from now on.
+  pub fn subscribe(
+    &self,
+    channel: &[u8],
+  ) -> impl Stream<Item = Result<Message, JsErrorBox>> {
+    // The first output is the message published before subscribing.
+    self.db.watch(vec![channel.to_vec()]).skip(1).filter_map(
+      |outputs| async move {
+        let output = match outputs {
+          Ok(mut outputs) => outputs.pop()?,
+          Err(e) => return Some(Err(e)),
+        };
+        match output {
+          WatchKeyOutput::Changed { entry: Some(entry) } => match entry.value {
+            KvValue::Bytes(payload) => Some(Ok(Message {
+              payload,
+              versionstamp: entry.versionstamp,
+            })),
+            _ => {
+              Some(Err(JsErrorBox::type_error("Expected a pub/sub message")))
+            }
+          },
+          _ => None,
+        }
+      },
+    )
+  }
+
+  /// The messages published to `channels` with [`Delivery::AtLeastOnce`],
+  /// as they are dequeued.
+  pub fn listen(
+    &self,
+    channels: Vec<Vec<u8>>,
+  ) -> impl Stream<Item = Result<QueuedMessage<D::QMH>, JsErrorBox>> {
+    let doorbells = self
+      .db
+      .watch(channels.iter().map(|c| doorbell(c)).collect());
+    let state = (self.db.clone(), channels, doorbells);
+    futures::stream::try_unfold(
+      state,
+      |(db, channels, mut doorbells)| async move {
+        loop {
+          let Some(mut handle) = db.dequeue_next_message().await? else {
+            tokio::select! {
+              rung = doorbells.next() => {
+                if rung.transpose()?.is_none() {
+                  return Ok(None);
+                }
+              }
+              _ = tokio::time::sleep(LISTEN_POLL_INTERVAL) => {}
+            }
+            continue;
+          };
+          let payload = handle.take_payload().await?;
+          match open(&payload) {
+            Some((channel, payload))
+              if channels.iter().any(|c| c == channel) =>
+            {
+              let message = QueuedMessage {
+                channel: channel.to_vec(),
+                payload: payload.to_vec(),
+                handle,
+              };
+              return Ok(Some((message, (db, channels, doorbells))));
+            }
+            _ => handle.finish(false).await?,
+          }
+        }
+      },
+    )
+  }
+}
+
+/// The key whose changes tell listeners of `channel` to dequeue.
+fn doorbell(channel: &[u8]) -> Vec<u8> {
+  let part = encode_key(&Key(vec![KeyPart::String("doorbell".to_string())]))
+    .expect("string parts encode");
+  [channel, &part].concat()
+}
+
+fn seal(channel: &[u8], payload: &[u8]) -> Vec<u8> {
+  let len = (channel.len() as u32).to_be_bytes();
+  [ENVELOPE_MAGIC, &len, channel, payload].concat()
+}
+
+/// The channel and message of a payload made by [`seal`].
+fn open(envelope: &[u8]) -> Option<(&[u8], &[u8])> {
+  let rest = envelope.strip_prefix(ENVELOPE_MAGIC)?;
+  let (len, rest) = rest.split_first_chunk::<4>()?;
+  let len = u32::from_be_bytes(*len) as usize;
+  (len <= rest.len()).then(|| rest.split_at(len))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn envelopes_round_trip() {
+    let envelope = seal(b"\x02jobs\x00", b"payload");
+    assert_eq!(
+      open(&envelope),
+      Some((&b"\x02jobs\x00"[..], &b"payload"[..]))
+    );
+    assert_eq!(open(&seal(b"", b"")), Some((&b""[..], &b""[..])));
+    // V8 serialized values, as enqueued by Deno.
+    assert_eq!(open(b"\xff\x0f\x22\x02hi"), None);
+    assert_eq!(open(&envelope[..ENVELOPE_MAGIC.len() + 6]), None);
+  }
+}
@@ -0,0 +1,311 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! Sliding window rate limits kept in the database.
+//!
+//! Requests are counted per window of time, in a `KvU64` under the limited
+//! key followed by the number of the window. The count of the previous
+//! window is weighted by how much of it still overlaps the sliding window
+//! ending now, which smooths out bursts at window boundaries without
+//! storing every request.
+//!
+//! Counts are added with a sum mutation that checks the versionstamp the
+//! count was read at, so concurrent requests can not together exceed the
+//! limit, and expire once they no longer overlap the sliding window.
+
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+use chrono::DateTime;
+use chrono::TimeZone;
+use chrono::Utc;
+use deno_error::JsErrorBox;
+
+use crate::encode_key;
+use crate::time::utc_now;
+use crate::AtomicWrite;
+use crate::Check;
+use crate::Consistency;
+use crate::Database;
+use crate::Key;
+use crate::KeyPart;
+use crate::KvValue;
+use crate::Mutation;
+use crate::MutationKind;
+use crate::ReadRange;
+use crate::ReadRangeOutput;
+use crate::SnapshotReadOptions;
+
+/// At most `limit` units of cost per `window`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+  pub limit: u64,
+  pub window: Duration,
+}
+
+/// Whether a request was allowed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimitOutcome {
+  pub allowed: bool,
+  /// The cost still allowed right now, after this request.
+  pub remaining: u64,
+  /// For a denied request, how long until it would be allowed. `None` if
+  /// its cost is over the limit, so it never will be.
+  pub retry_after: Option<Duration>,
+}
+
+impl RateLimit {
+  /// The reads to make for a request on the encoded key `key` at `now`.
+  /// Take the outputs to [`RateLimitCheck::finish`].
+  pub fn start(&self, key: &[u8], now: DateTime<Utc>) -> RateLimitCheck {
+    let window_ms = (self.window.as_millis() as i64).max(1);
+    let now_ms = now.timestamp_millis();
+    let window = now_ms.div_euclid(window_ms);
+    let window_key = |window: i64| {
+      let part = encode_key(&Key(vec![KeyPart::Int(window.into())]))
+        .expect("integer parts encode");
+      [key, &part].concat()
+    };
+    RateLimitCheck {
+      limit: self.limit,
+      window_ms,
+      elapsed: now_ms.rem_euclid(window_ms) as f64 / window_ms as f64,
+      current: window_key(window),
+      previous: window_key(window - 1),
+      expire_at: Utc.timestamp_millis_opt((window + 2) * window_ms).single(),
+    }
+  }
+}
+
+/// A rate limit check in progress.
+#[derive(Clone, Debug)]
+pub struct RateLimitCheck {
+  limit: u64,
+  window_ms: i64,
+  /// How far into the current window the check is, from 0 to 1.
+  elapsed: f64,
+  current: Vec<u8>,
+  previous: Vec<u8>,
+  expire_at: Option<DateTime<Utc>>,
+}
+
+impl RateLimitCheck {
+  /// The counts of the current and the previous window.
+  pub fn reads(&self) -> Vec<ReadRange> {
+    [&self.current, &self.previous]
+      .into_iter()
+      .map(|key| ReadRange {
+        start: key.clone(),
+        end: [key.as_slice(), &[0]].concat(),
+        limit: NonZeroU32::MIN,
+        reverse: false,
+      })
+      .collect()
+  }
+
+  /// Decide on a request of `cost` from the outputs of [`Self::reads`].
+  /// An allowed request is only counted once the returned write commits; if
+  /// its check fails, start over.
+  pub fn finish(
+    &self,
+    outputs: &[ReadRangeOutput],
+    cost: u64,
+  ) -> Result<(RateLimitOutcome, Option<AtomicWrite>), JsErrorBox> {
+    let entry =
+      |i: usize| outputs.get(i).and_then(|output| output.entries.first());
+    let count = |i: usize| match entry(i).map(|entry| &entry.value) {
+      None => Ok(0),
+      Some(KvValue::U64(count)) => Ok(*count),
+      Some(_) => {
+        Err(JsErrorBox::type_error("Expected a KvU64 rate limit count"))
+      }
+    };
+    let (current, previous) = (count(0)?, count(1)?);
+    let weighted = previous as f64 * (1.0 - self.elapsed) + current as f64;
+    let used = weighted.ceil() as u64;
+    let allowed = used.saturating_add(cost) <= self.limit;
+    if !allowed {
+      let outcome = RateLimitOutcome {
+        allowed,
+        remaining: self.limit.saturating_sub(used),
+        retry_after: self.retry_after(current, previous, cost),
+      };
+      return Ok((outcome, None));
+    }
+
+    let outcome = RateLimitOutcome {
+      allowed,
+      remaining: self.limit - used - cost,
+      retry_after: None,
+    };
+    let write = AtomicWrite {
+      checks: vec![Check {
+        key: self.current.clone(),
+        versionstamp: entry(0).map(|entry| entry.versionstamp),
+      }],
+      mutations: vec![Mutation {
+        key: self.current.clone(),
+        kind: MutationKind::Sum {
+          value: KvValue::U64(cost),
+          min_v8: vec![],
+          max_v8: vec![],
+          clamp: false,
+        },
+        expire_at: self.expire_at,
+      }],
+      enqueues: vec![],
+    };
+    Ok((outcome, Some(write)))
+  }
+
+  /// How long until the weighted count has dropped enough for `cost`, with
+  /// no other requests in between.
+  fn retry_after(
+    &self,
+    current: u64,
+    previous: u64,
+    cost: u64,
+  ) -> Option<Duration> {
+    if cost > self.limit {
+      return None;
+    }
+    let room = (self.limit - cost) as f64;
+    // The fraction of a window from the start of the current one after
+    // which the request fits.
+    let fits_at = if current as f64 <= room {
+      // Within this window, once enough of the previous one slid out.
+      1.0 - (room - current as f64) / previous as f64
+    } else {
+      // In the next window, where this one is the previous one.
+      2.0 - room / current as f64
+    };
+    let wait = (fits_at - self.elapsed).max(0.0) * self.window_ms as f64;
+    Some(Duration::from_millis(wait.ceil() as u64))
+  }
+}
+
+/// A [`RateLimit`] on keys of a database.
+#[derive(Clone)]
+pub struct RateLimiter<D: Database> {
+  db: D,
+  limit: RateLimit,
+}
+
+impl<D: Database> RateLimiter<D> {
+  pub fn new(db: D, limit: RateLimit) -> Self {
+    Self { db, limit }
+  }
+
+  /// Count a request of `cost` against the encoded key `key`, if the limit
+  /// allows it.
+  pub async fn check(
+    &self,
+    key: &[u8],
+    cost: u64,
+  ) -> Result<RateLimitOutcome, JsErrorBox> {
+    let options = SnapshotReadOptions {
+      consistency: Consistency::Strong,
+    };
+    loop {
+      let check = self.limit.start(key, utc_now());
+      let outputs = self
+        .db
+        .snapshot_read(check.reads(), options.clone())
+        .await?;
+      let (outcome, write) = check.finish(&outputs, cost)?;
+      let Some(write) = write else {
+        return Ok(outcome);
+      };
+      if self.db.atomic_write(write).await?.is_some() {
+        return Ok(outcome);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::KvEntry;
+
+  const LIMIT: RateLimit = RateLimit {
+    limit: 10,
+    window: Duration::from_secs(60),
+  };
+
+  fn at(secs: i64) -> DateTime<Utc> {
+    Utc.timestamp_opt(secs, 0).unwrap()
+  }
+
+  fn outputs(
+    current: Option<u64>,
+    previous: Option<u64>,
+  ) -> Vec<ReadRangeOutput> {
+    [current, previous]
+      .into_iter()
+      .map(|count| ReadRangeOutput {
+        entries: count
+          .map(|count| KvEntry {
+            key: vec![],
+            value: KvValue::U64(count),
+            versionstamp: [1; 10],
+          })
+          .into_iter()
+          .collect(),
+      })
+      .collect()
+  }
+
+  #[test]
+  fn counts_in_the_current_window() {
+    let check = LIMIT.start(b"\x02ip\x00", at(600));
+    let (outcome, write) = check.finish(&outputs(None, None), 3).unwrap();
+    assert_eq!(
+      outcome,
+      RateLimitOutcome {
+        allowed: true,
+        remaining: 7,
+        retry_after: None
+      }
+    );
+    let write = write.unwrap();
+    assert_eq!(write.checks[0].key, b"\x02ip\x00\x15\x0a");
+    assert_eq!(write.checks[0].versionstamp, None);
+    assert_eq!(write.mutations[0].key, write.checks[0].key);
+    assert_eq!(write.mutations[0].expire_at, Some(at(720)));
+
+    let reads = check.reads();
+    assert_eq!(reads[1].start, b"\x02ip\x00\x15\x09");
+    let (_, write) = check.finish(&outputs(Some(1), None), 1).unwrap();
+    assert_eq!(write.unwrap().checks[0].versionstamp, Some([1; 10]));
+  }
+
+  #[test]
+  fn weighs_the_previous_window() {
+    // A quarter into the window, three quarters of the previous count.
+    let check = LIMIT.start(b"", at(615));
+    let (outcome, write) = check.finish(&outputs(Some(2), Some(8)), 3).unwrap();
+    assert_eq!(
+      outcome,
+      RateLimitOutcome {
+        allowed: false,
+        remaining: 2,
+        // Once only five eighths of the previous window are left.
+        retry_after: Some(Duration::from_millis(7500)),
+      }
+    );
+    assert!(write.is_none());
+    let (outcome, _) = check.finish(&outputs(Some(2), Some(8)), 2).unwrap();
+    assert!(outcome.allowed);
+  }
+
+  #[test]
+  fn waits_for_the_next_window_when_the_current_one_is_full() {
+    let check = LIMIT.start(b"", at(630));
+    let (outcome, _) = check.finish(&outputs(Some(10), None), 5).unwrap();
+    assert!(!outcome.allowed);
+    // Half of this window, then half of the next one.
+    assert_eq!(outcome.retry_after, Some(Duration::from_secs(60)));
+    let (outcome, _) = check.finish(&outputs(None, None), 11).unwrap();
+    assert_eq!(outcome.retry_after, None);
+  }
+}
@@ -0,0 +1,188 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+use std::num::NonZeroU32;
+
+use crate::datapath as pb;
+use crate::ReadRange;
+use crate::ReadRangeOutput;
+
+/// Server-side limits on the size of one snapshot read response, across all
+/// of its ranges.
+///
+/// A range the budget cuts short is returned with a continuation: the start
+/// key (or, for reverse reads, the end key) to read the rest of the range
+/// from. Ranges are served in request order, so once the budget runs out the
+/// remaining ranges come back empty with a continuation. A response always
+/// contains at least one entry if there is one to read, so clients following
+/// continuations make progress.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReadBudget {
+  pub max_entries: Option<usize>,
+  pub max_bytes: Option<usize>,
+}
+
+impl ReadBudget {
+  /// The ranges to actually read: `requests` with their limits lowered to
+  /// fit the entry budget, leaving out ranges that do not fit at all.
+  pub fn plan(&self, requests: &[ReadRange]) -> Vec<ReadRange> {
+    requests
+      .iter()
+      .zip(self.entry_limits(requests))
+      .map_while(|(request, limit)| {
+        Some(ReadRange {
+          limit: NonZeroU32::new(limit)?,
+          ..request.clone()
+        })
+      })
+      .collect()
+  }
+
+  /// Build the response to `requests` from the outputs of reading the
+  /// ranges returned by [`Self::plan`], applying the byte budget.
+  pub fn finish(
+    &self,
+    requests: &[ReadRange],
+    outputs: Vec<ReadRangeOutput>,
+  ) -> pb::SnapshotReadOutput {
+    let limits = self.entry_limits(requests);
+    let mut outputs = outputs.into_iter();
+    let mut bytes_left = self.max_bytes.unwrap_or(usize::MAX);
+    let mut first_entry = true;
+    let mut ranges = Vec::with_capacity(requests.len());
+    for (request, limit) in requests.iter().zip(limits) {
+      let entries = outputs.next().map(|o| o.entries).unwrap_or_default();
+      let mut truncated =
+        limit < request.limit.get() && entries.len() >= limit as usize;
+      let mut values = Vec::with_capacity(entries.len());
+      for entry in entries {
+        let entry: pb::KvEntry = entry.into();
+        let size = entry.key.len() + entry.value.len();
+        if size > bytes_left && !first_entry {
+          truncated = true;
+          bytes_left = 0;
+          break;
+        }
+        bytes_left = bytes_left.saturating_sub(size);
+        first_entry = false;
+        values.push(entry);
+      }
+      let continuation = truncated.then(|| continuation(request, &values));
+      ranges.push(pb::ReadRangeOutput {
+        values,
+        continuation,
+      });
+    }
+
+    pb::SnapshotReadOutput {
+      ranges,
+      read_disabled: false,
+      read_is_strongly_consistent: true,
+      status: pb::SnapshotReadStatus::SrSuccess as i32,
+    }
+  }
+
+  /// The entry limit of each range under the entry budget, 0 for ranges
+  /// that do not fit at all.
+  fn entry_limits(&self, requests: &[ReadRange]) -> Vec<u32> {
+    let mut left = self.max_entries.unwrap_or(usize::MAX);
+    requests
+      .iter()
+      .map(|request| {
+        let limit = (request.limit.get() as usize).min(left);
+        left -= limit;
+        limit as u32
+      })
+      .collect()
+  }
+}
+
+/// Where to continue reading `request` after `values`.
+fn continuation(request: &ReadRange, values: &[pb::KvEntry]) -> Vec<u8> {
+  match values.last() {
+    Some(last) if request.reverse => last.key.clone(),
+    Some(last) => {
+      let mut next = last.key.clone();
+      next.push(0);
+      next
+    }
+    None if request.reverse => request.end.clone(),
+    None => request.start.clone(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::KvEntry;
+  use crate::KvValue;
+
+  fn range(start: &[u8], end: &[u8], limit: u32, reverse: bool) -> ReadRange {
+    ReadRange {
+      start: start.to_vec(),
+      end: end.to_vec(),
+      limit: NonZeroU32::new(limit).unwrap(),
+      reverse,
+    }
+  }
+
+  fn output(keys: &[&[u8]], value_size: usize) -> ReadRangeOutput {
+    ReadRangeOutput {
+      entries: keys
+        .iter()
+        .map(|key| KvEntry {
+          key: key.to_vec(),
+          value: KvValue::Bytes(vec![0; value_size]),
+          versionstamp: [0; 10],
+        })
+        .collect(),
+    }
+  }
+
+  #[test]
+  fn entry_budget_spans_ranges() {
+    let budget = ReadBudget {
+      max_entries: Some(3),
+      max_bytes: None,
+    };
+    let requests = [
+      range(b"a", b"b", 2, false),
+      range(b"b", b"c", 2, true),
+      range(b"c", b"d", 2, false),
+    ];
+    let planned = budget.plan(&requests);
+    assert_eq!(planned.len(), 2);
+    assert_eq!(planned[1].limit.get(), 1);
+
+    let out = budget.finish(
+      &requests,
+      vec![output(&[b"a1", b"a2"], 1), output(&[b"b9"], 1)],
+    );
+    assert_eq!(out.ranges[0].continuation, None);
+    assert_eq!(out.ranges[1].continuation.as_deref(), Some(&b"b9"[..]));
+    assert_eq!(out.ranges[2].continuation.as_deref(), Some(&b"c"[..]));
+    assert!(out.ranges[2].values.is_empty());
+  }
+
+  #[test]
+  fn short_range_is_not_truncated() {
+    let budget = ReadBudget {
+      max_entries: Some(1),
+      max_bytes: None,
+    };
+    let requests = [range(b"a", b"b", 5, false)];
+    let out = budget.finish(&requests, vec![output(&[], 0)]);
+    assert_eq!(out.ranges[0].continuation, None);
+  }
+
+  #[test]
+  fn byte_budget_always_returns_one_entry() {
+    let budget = ReadBudget {
+      max_entries: None,
+      max_bytes: Some(10),
+    };
+    let requests = [range(b"a", b"b", 10, false)];
+    let out = budget.finish(&requests, vec![output(&[b"a1", b"a2"], 100)]);
+    assert_eq!(out.ranges[0].values.len(), 1);
+    assert_eq!(out.ranges[0].continuation.as_deref(), Some(&b"a1\0"[..]));
+  }
+}
@@ -7,9 +7,7 @@
 /// which links to macOS's "CoreFoundation" framework which increases
 /// startup time for the CLI.
 pub fn utc_now() -> chrono::DateTime<chrono::Utc> {
-  let now = std::time::SystemTime::now()
-    .duration_since(std::time::UNIX_EPOCH)
-    .expect("system time before Unix epoch");
+  let now = since_epoch();
   let naive = chrono::NaiveDateTime::from_timestamp_opt(
     now.as_secs() as i64,
     now.subsec_nanos(),
@@ -17,3 +15,16 @@ pub fn utc_now() -> chrono::DateTime<chrono::Utc> {
   .unwrap();
   chrono::DateTime::from_naive_utc_and_offset(naive, chrono::Utc)
 }
+
+#[cfg(not(target_arch = "wasm32"))]
+fn since_epoch() -> std::time::Duration {
+  std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .expect("system time before Unix epoch")
+}
+
+/// `SystemTime::now()` panics on wasm32, so ask the JavaScript host.
+#[cfg(target_arch = "wasm32")]
+fn since_epoch() -> std::time::Duration {
+  std::time::Duration::from_secs_f64(js_sys::Date::now() / 1000.0)
+}
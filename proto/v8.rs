@@ -0,0 +1,1571 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! Serde support for [`KvValue::V8`](crate::KvValue::V8) values, so Rust
+//! code can read and write the values that `Deno.openKv` clients store.
+//!
+//! Values are encoded in the wire format of V8's `ValueSerializer`. Only
+//! the part of it that plain data needs is supported:
+//!
+//! | JavaScript          | Rust                                      |
+//! |---------------------|-------------------------------------------|
+//! | `undefined`, `null` | `()`, `None`                              |
+//! | boolean             | `bool`                                    |
+//! | number              | integers and floats                       |
+//! | bigint              | `i128`, `u128`                            |
+//! | string              | `String`, `char`, unit enum variants      |
+//! | `Uint8Array`        | [`Bytes`]                                 |
+//! | `Date`              | [`Date`]                                  |
+//! | array               | sequences and tuples                      |
+//! | object              | structs, other enum variants, [`Object`]  |
+//! | `Map`               | maps                                      |
+//!
+//! Decoding is more lenient than encoding: objects and `Map`s both decode
+//! into structs and maps, `Set`s decode into sequences, and bigints decode
+//! into any integer type that holds them. Integers that a JavaScript number
+//! can not hold exactly fail to encode, so that they are not silently
+//! rounded; use `i128` to store them as bigints instead.
+//!
+//! The `v8_valueserializer` crate, which the SQLite backend reads sum
+//! operands with, models values as a heap of JavaScript objects and can not
+//! build array buffers, so it does not fit serde; the tests check this
+//! codec against it instead.
+
+use std::fmt;
+
+use chrono::DateTime;
+use chrono::TimeZone;
+use chrono::Utc;
+use serde::de;
+use serde::de::DeserializeOwned;
+use serde::de::IntoDeserializer;
+use serde::ser;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// The format version written, which is the one current V8 writes.
+const VERSION: u32 = 15;
+/// The oldest format version read. Older ones differ in more than details.
+const MIN_VERSION: u32 = 13;
+
+/// The largest integer a JavaScript number holds exactly.
+const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+/// How deeply arrays and objects may nest.
+const MAX_DEPTH: usize = 128;
+/// The longest sparse array decoded, as each hole takes up memory here.
+const MAX_SPARSE_ARRAY_LENGTH: u64 = 1 << 20;
+
+/// The name under which [`Date`] passes through serde.
+const DATE_TOKEN: &str = "$denokv::v8::Date";
+/// The name under which [`Object`] passes through serde.
+const OBJECT_TOKEN: &str = "$denokv::v8::Object";
+
+mod tag {
+  pub const VERSION: u8 = 0xff;
+  pub const PADDING: u8 = b'\0';
+  pub const UNDEFINED: u8 = b'_';
+  pub const NULL: u8 = b'0';
+  pub const TRUE: u8 = b'T';
+  pub const FALSE: u8 = b'F';
+  pub const INT32: u8 = b'I';
+  pub const UINT32: u8 = b'U';
+  pub const DOUBLE: u8 = b'N';
+  pub const BIGINT: u8 = b'Z';
+  pub const UTF8_STRING: u8 = b'S';
+  pub const ONE_BYTE_STRING: u8 = b'"';
+  pub const TWO_BYTE_STRING: u8 = b'c';
+  pub const OBJECT_REFERENCE: u8 = b'^';
+  pub const BEGIN_OBJECT: u8 = b'o';
+  pub const END_OBJECT: u8 = b'{';
+  pub const BEGIN_SPARSE_ARRAY: u8 = b'a';
+  pub const END_SPARSE_ARRAY: u8 = b'@';
+  pub const BEGIN_DENSE_ARRAY: u8 = b'A';
+  pub const END_DENSE_ARRAY: u8 = b'$';
+  pub const THE_HOLE: u8 = b'-';
+  pub const DATE: u8 = b'D';
+  pub const BEGIN_MAP: u8 = b';';
+  pub const END_MAP: u8 = b':';
+  pub const BEGIN_SET: u8 = b'\'';
+  pub const END_SET: u8 = b',';
+  pub const ARRAY_BUFFER: u8 = b'B';
+  pub const ARRAY_BUFFER_VIEW: u8 = b'V';
+  pub const UINT8_ARRAY: u8 = b'B';
+}
+
+/// Encode `value` as a V8 value.
+pub fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, Error> {
+  let value = value.serialize(ValueSerializer)?;
+  let mut writer = Writer {
+    out: vec![tag::VERSION, VERSION as u8],
+  };
+  writer.write_value(&value);
+  Ok(writer.out)
+}
+
+/// Decode a V8 value.
+pub fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Error> {
+  let mut reader = Reader {
+    input: bytes,
+    version: 0,
+    objects: Vec::new(),
+    depth: 0,
+  };
+  reader.read_header()?;
+  let value = reader.read_value()?;
+  T::deserialize(value)
+}
+
+/// What [`edit_fields`] does with a property.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldEdit {
+  /// Keep the property, editing the properties of its value in turn.
+  Keep,
+  /// Remove the property.
+  Remove,
+  /// Replace the value of the property with a string.
+  Replace(String),
+}
+
+/// Re-encode the V8 value `bytes` after passing every string-named
+/// property of the objects and `Map`s in it, at any depth, through `edit`
+/// with its name and, if it is a string, its value. Everything else is
+/// written back as it was decoded, so fields can be removed or masked in
+/// values that have no Rust type.
+pub fn edit_fields(
+  bytes: &[u8],
+  mut edit: impl FnMut(&str, Option<&str>) -> FieldEdit,
+) -> Result<Vec<u8>, Error> {
+  fn edit_value(
+    value: &mut Value,
+    edit: &mut dyn FnMut(&str, Option<&str>) -> FieldEdit,
+  ) {
+    match value {
+      Value::Array(items) => {
+        for item in items {
+          edit_value(item, edit);
+        }
+      }
+      Value::Object(entries) | Value::Map(entries) => {
+        entries.retain_mut(|(key, value)| {
+          let Value::String(name) = key else {
+            edit_value(value, edit);
+            return true;
+          };
+          let string = match value {
+            Value::String(string) => Some(string.as_str()),
+            _ => None,
+          };
+          match edit(name, string) {
+            FieldEdit::Keep => {
+              edit_value(value, edit);
+              true
+            }
+            FieldEdit::Remove => false,
+            FieldEdit::Replace(string) => {
+              *value = Value::String(string);
+              true
+            }
+          }
+        });
+      }
+      _ => {}
+    }
+  }
+
+  let mut reader = Reader {
+    input: bytes,
+    version: 0,
+    objects: Vec::new(),
+    depth: 0,
+  };
+  reader.read_header()?;
+  let mut value = reader.read_value()?;
+  edit_value(&mut value, &mut edit);
+  let mut writer = Writer {
+    out: vec![tag::VERSION, VERSION as u8],
+  };
+  writer.write_value(&value);
+  Ok(writer.out)
+}
+
+/// Why a value could not be encoded or decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.0)
+  }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+  fn custom<T: fmt::Display>(msg: T) -> Self {
+    Error(msg.to_string())
+  }
+}
+
+impl de::Error for Error {
+  fn custom<T: fmt::Display>(msg: T) -> Self {
+    Error(msg.to_string())
+  }
+}
+
+fn error(msg: impl Into<String>) -> Error {
+  Error(msg.into())
+}
+
+/// A JavaScript `Date`, as milliseconds since the Unix epoch. An invalid
+/// date is NaN.
+///
+/// With serializers other than this module's, it is just the number.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct Date(pub f64);
+
+impl Date {
+  /// The date as a [`DateTime`], or `None` if it is invalid or out of
+  /// range.
+  pub fn to_datetime(self) -> Option<DateTime<Utc>> {
+    if !self.0.is_finite() {
+      return None;
+    }
+    Utc.timestamp_millis_opt(self.0 as i64).single()
+  }
+}
+
+impl From<DateTime<Utc>> for Date {
+  fn from(datetime: DateTime<Utc>) -> Self {
+    Date(datetime.timestamp_millis() as f64)
+  }
+}
+
+impl Serialize for Date {
+  fn serialize<S: ser::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_newtype_struct(DATE_TOKEN, &self.0)
+  }
+}
+
+impl<'de> Deserialize<'de> for Date {
+  fn deserialize<D: de::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+    struct DateVisitor;
+
+    impl<'de> de::Visitor<'de> for DateVisitor {
+      type Value = Date;
+
+      fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a date")
+      }
+
+      fn visit_newtype_struct<D: de::Deserializer<'de>>(
+        self,
+        d: D,
+      ) -> Result<Date, D::Error> {
+        f64::deserialize(d).map(Date)
+      }
+
+      fn visit_f64<E: de::Error>(self, ms: f64) -> Result<Date, E> {
+        Ok(Date(ms))
+      }
+
+      fn visit_i64<E: de::Error>(self, ms: i64) -> Result<Date, E> {
+        Ok(Date(ms as f64))
+      }
+
+      fn visit_u64<E: de::Error>(self, ms: u64) -> Result<Date, E> {
+        Ok(Date(ms as f64))
+      }
+    }
+
+    d.deserialize_newtype_struct(DATE_TOKEN, DateVisitor)
+  }
+}
+
+/// A JavaScript object with the entries of a map, for properties that are
+/// only known at runtime. A map on its own is encoded as a `Map`.
+///
+/// With serializers other than this module's, it is just the map.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Object<T>(pub T);
+
+impl<T: Serialize> Serialize for Object<T> {
+  fn serialize<S: ser::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_newtype_struct(OBJECT_TOKEN, &self.0)
+  }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Object<T> {
+  fn deserialize<D: de::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+    T::deserialize(d).map(Object)
+  }
+}
+
+/// A JavaScript `Uint8Array`. A plain `Vec<u8>` is an array of numbers.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Bytes(pub Vec<u8>);
+
+impl Serialize for Bytes {
+  fn serialize<S: ser::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_bytes(&self.0)
+  }
+}
+
+impl<'de> Deserialize<'de> for Bytes {
+  fn deserialize<D: de::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+    struct BytesVisitor;
+
+    impl<'de> de::Visitor<'de> for BytesVisitor {
+      type Value = Bytes;
+
+      fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("bytes")
+      }
+
+      fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Bytes, E> {
+        Ok(Bytes(bytes.to_vec()))
+      }
+
+      fn visit_byte_buf<E: de::Error>(
+        self,
+        bytes: Vec<u8>,
+      ) -> Result<Bytes, E> {
+        Ok(Bytes(bytes))
+      }
+
+      fn visit_seq<A: de::SeqAccess<'de>>(
+        self,
+        mut seq: A,
+      ) -> Result<Bytes, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+          bytes.push(byte);
+        }
+        Ok(Bytes(bytes))
+      }
+    }
+
+    d.deserialize_byte_buf(BytesVisitor)
+  }
+}
+
+/// A decoded value, between the wire format and serde.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+  Undefined,
+  Null,
+  Bool(bool),
+  Number(f64),
+  BigInt(i128),
+  String(String),
+  Bytes(Vec<u8>),
+  Date(f64),
+  Array(Vec<Value>),
+  Object(Vec<(Value, Value)>),
+  Map(Vec<(Value, Value)>),
+}
+
+struct Writer {
+  out: Vec<u8>,
+}
+
+impl Writer {
+  fn write_varint(&mut self, mut n: u64) {
+    loop {
+      let byte = (n & 0x7f) as u8;
+      n >>= 7;
+      if n == 0 {
+        self.out.push(byte);
+        return;
+      }
+      self.out.push(byte | 0x80);
+    }
+  }
+
+  fn write_value(&mut self, value: &Value) {
+    match value {
+      Value::Undefined => self.out.push(tag::UNDEFINED),
+      Value::Null => self.out.push(tag::NULL),
+      Value::Bool(true) => self.out.push(tag::TRUE),
+      Value::Bool(false) => self.out.push(tag::FALSE),
+      Value::Number(n) => {
+        let small = *n as i32;
+        if small as f64 == *n && !(small == 0 && n.is_sign_negative()) {
+          self.out.push(tag::INT32);
+          self.write_varint(((small << 1) ^ (small >> 31)) as u32 as u64);
+        } else {
+          self.out.push(tag::DOUBLE);
+          self.out.extend_from_slice(&n.to_le_bytes());
+        }
+      }
+      Value::BigInt(n) => {
+        let magnitude = n.unsigned_abs();
+        let words = match magnitude {
+          0 => 0,
+          m if m >> 64 == 0 => 1,
+          _ => 2,
+        };
+        self.out.push(tag::BIGINT);
+        self.write_varint(((words * 8) << 1) | (*n < 0) as u64);
+        let bytes = magnitude.to_le_bytes();
+        self.out.extend_from_slice(&bytes[..words as usize * 8]);
+      }
+      Value::String(s) => self.write_string(s),
+      Value::Bytes(bytes) => {
+        self.out.push(tag::ARRAY_BUFFER);
+        self.write_varint(bytes.len() as u64);
+        self.out.extend_from_slice(bytes);
+        self.out.push(tag::ARRAY_BUFFER_VIEW);
+        self.out.push(tag::UINT8_ARRAY);
+        self.write_varint(0);
+        self.write_varint(bytes.len() as u64);
+        // Flags: the view is neither length-tracking nor backed by a
+        // resizable buffer.
+        self.write_varint(0);
+      }
+      Value::Date(ms) => {
+        self.out.push(tag::DATE);
+        self.out.extend_from_slice(&ms.to_le_bytes());
+      }
+      Value::Array(items) => {
+        self.out.push(tag::BEGIN_DENSE_ARRAY);
+        self.write_varint(items.len() as u64);
+        for item in items {
+          self.write_value(item);
+        }
+        self.out.push(tag::END_DENSE_ARRAY);
+        self.write_varint(0);
+        self.write_varint(items.len() as u64);
+      }
+      Value::Object(entries) => {
+        self.out.push(tag::BEGIN_OBJECT);
+        self.write_entries(entries);
+        self.out.push(tag::END_OBJECT);
+        self.write_varint(entries.len() as u64);
+      }
+      Value::Map(entries) => {
+        self.out.push(tag::BEGIN_MAP);
+        self.write_entries(entries);
+        self.out.push(tag::END_MAP);
+        self.write_varint(entries.len() as u64 * 2);
+      }
+    }
+  }
+
+  fn write_entries(&mut self, entries: &[(Value, Value)]) {
+    for (key, value) in entries {
+      self.write_value(key);
+      self.write_value(value);
+    }
+  }
+
+  fn write_string(&mut self, s: &str) {
+    if s.chars().all(|c| (c as u32) < 0x100) {
+      self.out.push(tag::ONE_BYTE_STRING);
+      self.write_varint(s.chars().count() as u64);
+      self.out.extend(s.chars().map(|c| c as u8));
+      return;
+    }
+    let units: Vec<u16> = s.encode_utf16().collect();
+    let byte_length = units.len() as u64 * 2;
+    // V8 aligns two-byte strings to two bytes, counting from the header.
+    let mut varint_len = 1;
+    while byte_length >> (7 * varint_len) != 0 {
+      varint_len += 1;
+    }
+    if (self.out.len() + 1 + varint_len) % 2 == 1 {
+      self.out.push(tag::PADDING);
+    }
+    self.out.push(tag::TWO_BYTE_STRING);
+    self.write_varint(byte_length);
+    for unit in units {
+      self.out.extend_from_slice(&unit.to_le_bytes());
+    }
+  }
+}
+
+struct Reader<'a> {
+  input: &'a [u8],
+  version: u32,
+  /// Decoded objects by id, for back references. `None` while an object
+  /// is still being decoded, as references to it would be cyclic.
+  objects: Vec<Option<Value>>,
+  depth: usize,
+}
+
+impl Reader<'_> {
+  fn read_byte(&mut self) -> Result<u8, Error> {
+    let (&byte, rest) = self
+      .input
+      .split_first()
+      .ok_or_else(|| error("unexpected end of the value"))?;
+    self.input = rest;
+    Ok(byte)
+  }
+
+  fn read_bytes(&mut self, len: u64) -> Result<&[u8], Error> {
+    if len > self.input.len() as u64 {
+      return Err(error("unexpected end of the value"));
+    }
+    let (bytes, rest) = self.input.split_at(len as usize);
+    self.input = rest;
+    Ok(bytes)
+  }
+
+  fn read_varint(&mut self) -> Result<u64, Error> {
+    let mut n = 0u64;
+    for shift in (0..64).step_by(7) {
+      let byte = self.read_byte()?;
+      n |= ((byte & 0x7f) as u64) << shift;
+      if byte & 0x80 == 0 {
+        return Ok(n);
+      }
+    }
+    Err(error("invalid varint"))
+  }
+
+  fn read_double(&mut self) -> Result<f64, Error> {
+    let bytes = self.read_bytes(8)?;
+    Ok(f64::from_le_bytes(bytes.try_into().unwrap()))
+  }
+
+  fn read_tag(&mut self) -> Result<u8, Error> {
+    loop {
+      let tag = self.read_byte()?;
+      if tag != tag::PADDING {
+        return Ok(tag);
+      }
+    }
+  }
+
+  fn peek_tag(&self) -> Option<u8> {
+    self.input.iter().copied().find(|&tag| tag != tag::PADDING)
+  }
+
+  fn read_header(&mut self) -> Result<(), Error> {
+    if self.read_byte()? != tag::VERSION {
+      return Err(error("not a V8 value"));
+    }
+    self.version = self.read_varint()? as u32;
+    if !(MIN_VERSION..=VERSION).contains(&self.version) {
+      return Err(error(format!(
+        "unsupported V8 format version {}",
+        self.version
+      )));
+    }
+    Ok(())
+  }
+
+  fn read_value(&mut self) -> Result<Value, Error> {
+    self.depth += 1;
+    if self.depth > MAX_DEPTH {
+      return Err(error("the value is nested too deeply"));
+    }
+    let value = self.read_value_inner();
+    self.depth -= 1;
+    value
+  }
+
+  fn read_value_inner(&mut self) -> Result<Value, Error> {
+    let value = match self.read_tag()? {
+      tag::UNDEFINED => Value::Undefined,
+      tag::NULL => Value::Null,
+      tag::TRUE => Value::Bool(true),
+      tag::FALSE => Value::Bool(false),
+      tag::INT32 => {
+        let n = self.read_varint()? as u32;
+        Value::Number(((n >> 1) as i32 ^ -((n & 1) as i32)) as f64)
+      }
+      tag::UINT32 => Value::Number(self.read_varint()? as u32 as f64),
+      tag::DOUBLE => Value::Number(self.read_double()?),
+      tag::BIGINT => self.read_bigint()?,
+      tag::UTF8_STRING => {
+        let len = self.read_varint()?;
+        let bytes = self.read_bytes(len)?.to_vec();
+        Value::String(
+          String::from_utf8(bytes).map_err(|_| error("invalid UTF-8"))?,
+        )
+      }
+      tag::ONE_BYTE_STRING => {
+        let len = self.read_varint()?;
+        let bytes = self.read_bytes(len)?;
+        Value::String(bytes.iter().map(|&b| b as char).collect())
+      }
+      tag::TWO_BYTE_STRING => {
+        let len = self.read_varint()?;
+        if len % 2 != 0 {
+          return Err(error("invalid two-byte string"));
+        }
+        let units: Vec<u16> = self
+          .read_bytes(len)?
+          .chunks_exact(2)
+          .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+          .collect();
+        Value::String(
+          String::from_utf16(&units).map_err(|_| error("invalid UTF-16"))?,
+        )
+      }
+      tag::OBJECT_REFERENCE => {
+        let id = self.read_varint()?;
+        match self.objects.get(id as usize) {
+          Some(Some(value)) => value.clone(),
+          Some(None) => return Err(error("cyclic values are not supported")),
+          None => return Err(error("invalid object reference")),
+        }
+      }
+      tag::BEGIN_OBJECT => {
+        let id = self.begin_object();
+        let entries = self.read_entries(tag::END_OBJECT)?;
+        self.read_varint()?;
+        self.end_object(id, Value::Object(entries))
+      }
+      tag::BEGIN_DENSE_ARRAY => {
+        let id = self.begin_object();
+        let len = self.read_varint()?;
+        let mut items = Vec::new();
+        for _ in 0..len {
+          if self.peek_tag() == Some(tag::THE_HOLE) {
+            self.read_tag()?;
+            items.push(Value::Undefined);
+          } else {
+            items.push(self.read_value()?);
+          }
+        }
+        // Properties other than the elements are dropped.
+        self.read_entries(tag::END_DENSE_ARRAY)?;
+        self.read_varint()?;
+        self.read_varint()?;
+        self.end_object(id, Value::Array(items))
+      }
+      tag::BEGIN_SPARSE_ARRAY => {
+        let id = self.begin_object();
+        let len = self.read_varint()?;
+        if len > MAX_SPARSE_ARRAY_LENGTH {
+          return Err(error("the sparse array is too long"));
+        }
+        let mut items = vec![Value::Undefined; len as usize];
+        for (key, value) in self.read_entries(tag::END_SPARSE_ARRAY)? {
+          if let Value::Number(i) = key {
+            if i >= 0.0 && i < len as f64 && i.fract() == 0.0 {
+              items[i as usize] = value;
+            }
+          }
+        }
+        self.read_varint()?;
+        self.read_varint()?;
+        self.end_object(id, Value::Array(items))
+      }
+      tag::DATE => {
+        let id = self.begin_object();
+        let ms = self.read_double()?;
+        self.end_object(id, Value::Date(ms))
+      }
+      tag::BEGIN_MAP => {
+        let id = self.begin_object();
+        let entries = self.read_entries(tag::END_MAP)?;
+        self.read_varint()?;
+        self.end_object(id, Value::Map(entries))
+      }
+      tag::BEGIN_SET => {
+        let id = self.begin_object();
+        let mut items = Vec::new();
+        while self.peek_tag() != Some(tag::END_SET) {
+          items.push(self.read_value()?);
+        }
+        self.read_tag()?;
+        self.read_varint()?;
+        self.end_object(id, Value::Array(items))
+      }
+      tag::ARRAY_BUFFER => self.read_array_buffer()?,
+      tag => {
+        return Err(error(format!(
+          "unsupported V8 value tag {:?}",
+          tag as char
+        )))
+      }
+    };
+    Ok(value)
+  }
+
+  fn begin_object(&mut self) -> usize {
+    self.objects.push(None);
+    self.objects.len() - 1
+  }
+
+  fn end_object(&mut self, id: usize, value: Value) -> Value {
+    self.objects[id] = Some(value.clone());
+    value
+  }
+
+  fn read_entries(
+    &mut self,
+    end_tag: u8,
+  ) -> Result<Vec<(Value, Value)>, Error> {
+    let mut entries = Vec::new();
+    while self.peek_tag() != Some(end_tag) {
+      let key = self.read_value()?;
+      let value = self.read_value()?;
+      entries.push((key, value));
+    }
+    self.read_tag()?;
+    Ok(entries)
+  }
+
+  fn read_bigint(&mut self) -> Result<Value, Error> {
+    let bitfield = self.read_varint()?;
+    let len = bitfield >> 1;
+    let bytes = self.read_bytes(len)?;
+    let (low, high) = bytes.split_at(bytes.len().min(16));
+    if high.iter().any(|&b| b != 0) {
+      return Err(error("bigints beyond 128 bits are not supported"));
+    }
+    let mut magnitude = [0u8; 16];
+    magnitude[..low.len()].copy_from_slice(low);
+    let magnitude = u128::from_le_bytes(magnitude);
+    let n = if bitfield & 1 == 1 {
+      0i128.checked_sub_unsigned(magnitude)
+    } else {
+      i128::try_from(magnitude).ok()
+    };
+    n.map(Value::BigInt)
+      .ok_or_else(|| error("bigints beyond 128 bits are not supported"))
+  }
+
+  fn read_array_buffer(&mut self) -> Result<Value, Error> {
+    let id = self.begin_object();
+    let len = self.read_varint()?;
+    let buffer = self.read_bytes(len)?.to_vec();
+    let buffer = self.end_object(id, Value::Bytes(buffer));
+    if self.peek_tag() != Some(tag::ARRAY_BUFFER_VIEW) {
+      return Ok(buffer);
+    }
+    self.read_tag()?;
+    let id = self.begin_object();
+    let subtag = self.read_byte()?;
+    let offset = self.read_varint()?;
+    let len = self.read_varint()?;
+    if self.version >= 14 {
+      self.read_varint()?;
+    }
+    if subtag != tag::UINT8_ARRAY {
+      return Err(error("only Uint8Array views are supported"));
+    }
+    let Value::Bytes(buffer) = buffer else {
+      unreachable!()
+    };
+    let view = offset
+      .checked_add(len)
+      .and_then(|end| buffer.get(offset as usize..end as usize))
+      .ok_or_else(|| error("the Uint8Array is out of bounds"))?;
+    Ok(self.end_object(id, Value::Bytes(view.to_vec())))
+  }
+}
+
+fn safe_integer(n: i128) -> Result<Value, Error> {
+  if n.unsigned_abs() > MAX_SAFE_INTEGER as u128 {
+    return Err(error(format!(
+      "{n} can not be stored exactly as a JavaScript number"
+    )));
+  }
+  Ok(Value::Number(n as f64))
+}
+
+struct ValueSerializer;
+
+impl ser::Serializer for ValueSerializer {
+  type Ok = Value;
+  type Error = Error;
+  type SerializeSeq = SerializeArray;
+  type SerializeTuple = SerializeArray;
+  type SerializeTupleStruct = SerializeArray;
+  type SerializeTupleVariant = SerializeVariant<SerializeArray>;
+  type SerializeMap = SerializeMap;
+  type SerializeStruct = SerializeObject;
+  type SerializeStructVariant = SerializeVariant<SerializeObject>;
+
+  fn serialize_bool(self, v: bool) -> Result<Value, Error> {
+    Ok(Value::Bool(v))
+  }
+
+  fn serialize_i8(self, v: i8) -> Result<Value, Error> {
+    Ok(Value::Number(v as f64))
+  }
+
+  fn serialize_i16(self, v: i16) -> Result<Value, Error> {
+    Ok(Value::Number(v as f64))
+  }
+
+  fn serialize_i32(self, v: i32) -> Result<Value, Error> {
+    Ok(Value::Number(v as f64))
+  }
+
+  fn serialize_i64(self, v: i64) -> Result<Value, Error> {
+    safe_integer(v as i128)
+  }
+
+  fn serialize_i128(self, v: i128) -> Result<Value, Error> {
+    Ok(Value::BigInt(v))
+  }
+
+  fn serialize_u8(self, v: u8) -> Result<Value, Error> {
+    Ok(Value::Number(v as f64))
+  }
+
+  fn serialize_u16(self, v: u16) -> Result<Value, Error> {
+    Ok(Value::Number(v as f64))
+  }
+
+  fn serialize_u32(self, v: u32) -> Result<Value, Error> {
+    Ok(Value::Number(v as f64))
+  }
+
+  fn serialize_u64(self, v: u64) -> Result<Value, Error> {
+    safe_integer(v as i128)
+  }
+
+  fn serialize_u128(self, v: u128) -> Result<Value, Error> {
+    i128::try_from(v)
+      .map(Value::BigInt)
+      .map_err(|_| error("bigints beyond 128 bits are not supported"))
+  }
+
+  fn serialize_f32(self, v: f32) -> Result<Value, Error> {
+    Ok(Value::Number(v as f64))
+  }
+
+  fn serialize_f64(self, v: f64) -> Result<Value, Error> {
+    Ok(Value::Number(v))
+  }
+
+  fn serialize_char(self, v: char) -> Result<Value, Error> {
+    Ok(Value::String(v.to_string()))
+  }
+
+  fn serialize_str(self, v: &str) -> Result<Value, Error> {
+    Ok(Value::String(v.to_string()))
+  }
+
+  fn serialize_bytes(self, v: &[u8]) -> Result<Value, Error> {
+    Ok(Value::Bytes(v.to_vec()))
+  }
+
+  fn serialize_none(self) -> Result<Value, Error> {
+    Ok(Value::Null)
+  }
+
+  fn serialize_some<T: Serialize + ?Sized>(
+    self,
+    value: &T,
+  ) -> Result<Value, Error> {
+    value.serialize(self)
+  }
+
+  fn serialize_unit(self) -> Result<Value, Error> {
+    Ok(Value::Null)
+  }
+
+  fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, Error> {
+    Ok(Value::Null)
+  }
+
+  fn serialize_unit_variant(
+    self,
+    _name: &'static str,
+    _index: u32,
+    variant: &'static str,
+  ) -> Result<Value, Error> {
+    Ok(Value::String(variant.to_string()))
+  }
+
+  fn serialize_newtype_struct<T: Serialize + ?Sized>(
+    self,
+    name: &'static str,
+    value: &T,
+  ) -> Result<Value, Error> {
+    let value = value.serialize(self)?;
+    match value {
+      Value::Number(ms) if name == DATE_TOKEN => Ok(Value::Date(ms)),
+      Value::Map(entries) if name == OBJECT_TOKEN => Ok(Value::Object(entries)),
+      value => Ok(value),
+    }
+  }
+
+  fn serialize_newtype_variant<T: Serialize + ?Sized>(
+    self,
+    _name: &'static str,
+    _index: u32,
+    variant: &'static str,
+    value: &T,
+  ) -> Result<Value, Error> {
+    let value = value.serialize(self)?;
+    Ok(Value::Object(vec![(
+      Value::String(variant.to_string()),
+      value,
+    )]))
+  }
+
+  fn serialize_seq(self, len: Option<usize>) -> Result<SerializeArray, Error> {
+    Ok(SerializeArray(Vec::with_capacity(len.unwrap_or(0))))
+  }
+
+  fn serialize_tuple(self, len: usize) -> Result<SerializeArray, Error> {
+    self.serialize_seq(Some(len))
+  }
+
+  fn serialize_tuple_struct(
+    self,
+    _name: &'static str,
+    len: usize,
+  ) -> Result<SerializeArray, Error> {
+    self.serialize_seq(Some(len))
+  }
+
+  fn serialize_tuple_variant(
+    self,
+    _name: &'static str,
+    _index: u32,
+    variant: &'static str,
+    len: usize,
+  ) -> Result<SerializeVariant<SerializeArray>, Error> {
+    Ok(SerializeVariant {
+      variant,
+      inner: self.serialize_seq(Some(len))?,
+    })
+  }
+
+  fn serialize_map(self, len: Option<usize>) -> Result<SerializeMap, Error> {
+    Ok(SerializeMap {
+      entries: Vec::with_capacity(len.unwrap_or(0)),
+      key: None,
+    })
+  }
+
+  fn serialize_struct(
+    self,
+    _name: &'static str,
+    len: usize,
+  ) -> Result<SerializeObject, Error> {
+    Ok(SerializeObject(Vec::with_capacity(len)))
+  }
+
+  fn serialize_struct_variant(
+    self,
+    _name: &'static str,
+    _index: u32,
+    variant: &'static str,
+    len: usize,
+  ) -> Result<SerializeVariant<SerializeObject>, Error> {
+    Ok(SerializeVariant {
+      variant,
+      inner: SerializeObject(Vec::with_capacity(len)),
+    })
+  }
+}
+
+struct SerializeArray(Vec<Value>);
+
+impl ser::SerializeSeq for SerializeArray {
+  type Ok = Value;
+  type Error = Error;
+
+  fn serialize_element<T: Serialize + ?Sized>(
+    &mut self,
+    value: &T,
+  ) -> Result<(), Error> {
+    self.0.push(value.serialize(ValueSerializer)?);
+    Ok(())
+  }
+
+  fn end(self) -> Result<Value, Error> {
+    Ok(Value::Array(self.0))
+  }
+}
+
+impl ser::SerializeTuple for SerializeArray {
+  type Ok = Value;
+  type Error = Error;
+
+  fn serialize_element<T: Serialize + ?Sized>(
+    &mut self,
+    value: &T,
+  ) -> Result<(), Error> {
+    ser::SerializeSeq::serialize_element(self, value)
+  }
+
+  fn end(self) -> Result<Value, Error> {
+    ser::SerializeSeq::end(self)
+  }
+}
+
+impl ser::SerializeTupleStruct for SerializeArray {
+  type Ok = Value;
+  type Error = Error;
+
+  fn serialize_field<T: Serialize + ?Sized>(
+    &mut self,
+    value: &T,
+  ) -> Result<(), Error> {
+    ser::SerializeSeq::serialize_element(self, value)
+  }
+
+  fn end(self) -> Result<Value, Error> {
+    ser::SerializeSeq::end(self)
+  }
+}
+
+struct SerializeMap {
+  entries: Vec<(Value, Value)>,
+  key: Option<Value>,
+}
+
+impl ser::SerializeMap for SerializeMap {
+  type Ok = Value;
+  type Error = Error;
+
+  fn serialize_key<T: Serialize + ?Sized>(
+    &mut self,
+    key: &T,
+  ) -> Result<(), Error> {
+    self.key = Some(key.serialize(ValueSerializer)?);
+    Ok(())
+  }
+
+  fn serialize_value<T: Serialize + ?Sized>(
+    &mut self,
+    value: &T,
+  ) -> Result<(), Error> {
+    let key = self
+      .key
+      .take()
+      .ok_or_else(|| error("map value without a key"))?;
+    self.entries.push((key, value.serialize(ValueSerializer)?));
+    Ok(())
+  }
+
+  fn end(self) -> Result<Value, Error> {
+    Ok(Value::Map(self.entries))
+  }
+}
+
+struct SerializeObject(Vec<(Value, Value)>);
+
+impl ser::SerializeStruct for SerializeObject {
+  type Ok = Value;
+  type Error = Error;
+
+  fn serialize_field<T: Serialize + ?Sized>(
+    &mut self,
+    key: &'static str,
+    value: &T,
+  ) -> Result<(), Error> {
+    let value = value.serialize(ValueSerializer)?;
+    self.0.push((Value::String(key.to_string()), value));
+    Ok(())
+  }
+
+  fn end(self) -> Result<Value, Error> {
+    Ok(Value::Object(self.0))
+  }
+}
+
+/// An enum variant with fields, as an object with the variant as its only
+/// property, the way serde_json writes them.
+struct SerializeVariant<T> {
+  variant: &'static str,
+  inner: T,
+}
+
+impl<T> SerializeVariant<T> {
+  fn wrap(variant: &'static str, value: Value) -> Value {
+    Value::Object(vec![(Value::String(variant.to_string()), value)])
+  }
+}
+
+impl ser::SerializeTupleVariant for SerializeVariant<SerializeArray> {
+  type Ok = Value;
+  type Error = Error;
+
+  fn serialize_field<T: Serialize + ?Sized>(
+    &mut self,
+    value: &T,
+  ) -> Result<(), Error> {
+    ser::SerializeSeq::serialize_element(&mut self.inner, value)
+  }
+
+  fn end(self) -> Result<Value, Error> {
+    let value = ser::SerializeSeq::end(self.inner)?;
+    Ok(Self::wrap(self.variant, value))
+  }
+}
+
+impl ser::SerializeStructVariant for SerializeVariant<SerializeObject> {
+  type Ok = Value;
+  type Error = Error;
+
+  fn serialize_field<T: Serialize + ?Sized>(
+    &mut self,
+    key: &'static str,
+    value: &T,
+  ) -> Result<(), Error> {
+    ser::SerializeStruct::serialize_field(&mut self.inner, key, value)
+  }
+
+  fn end(self) -> Result<Value, Error> {
+    let value = ser::SerializeStruct::end(self.inner)?;
+    Ok(Self::wrap(self.variant, value))
+  }
+}
+
+fn visit_number<'de, V: de::Visitor<'de>>(
+  n: f64,
+  visitor: V,
+) -> Result<V::Value, Error> {
+  let integral = n.fract() == 0.0 && n.abs() <= MAX_SAFE_INTEGER as f64;
+  if integral && !(n == 0.0 && n.is_sign_negative()) {
+    visitor.visit_i64(n as i64)
+  } else {
+    visitor.visit_f64(n)
+  }
+}
+
+impl<'de> de::Deserializer<'de> for Value {
+  type Error = Error;
+
+  fn deserialize_any<V: de::Visitor<'de>>(
+    self,
+    visitor: V,
+  ) -> Result<V::Value, Error> {
+    match self {
+      Value::Undefined | Value::Null => visitor.visit_unit(),
+      Value::Bool(b) => visitor.visit_bool(b),
+      Value::Number(n) => visit_number(n, visitor),
+      Value::BigInt(n) => match i64::try_from(n) {
+        Ok(n) => visitor.visit_i64(n),
+        Err(_) => visitor.visit_i128(n),
+      },
+      Value::String(s) => visitor.visit_string(s),
+      Value::Bytes(bytes) => visitor.visit_byte_buf(bytes),
+      Value::Date(ms) => visitor.visit_f64(ms),
+      Value::Array(items) => {
+        visitor.visit_seq(de::value::SeqDeserializer::new(items.into_iter()))
+      }
+      Value::Object(entries) | Value::Map(entries) => {
+        visitor.visit_map(de::value::MapDeserializer::new(entries.into_iter()))
+      }
+    }
+  }
+
+  fn deserialize_option<V: de::Visitor<'de>>(
+    self,
+    visitor: V,
+  ) -> Result<V::Value, Error> {
+    match self {
+      Value::Undefined | Value::Null => visitor.visit_none(),
+      value => visitor.visit_some(value),
+    }
+  }
+
+  fn deserialize_newtype_struct<V: de::Visitor<'de>>(
+    self,
+    name: &'static str,
+    visitor: V,
+  ) -> Result<V::Value, Error> {
+    match self {
+      Value::Date(ms) if name == DATE_TOKEN => {
+        visitor.visit_newtype_struct(Value::Number(ms))
+      }
+      value => visitor.visit_newtype_struct(value),
+    }
+  }
+
+  fn deserialize_seq<V: de::Visitor<'de>>(
+    self,
+    visitor: V,
+  ) -> Result<V::Value, Error> {
+    match self {
+      // So that a `Uint8Array` also decodes into a `Vec<u8>`.
+      Value::Bytes(bytes) => {
+        visitor.visit_seq(de::value::SeqDeserializer::new(bytes.into_iter()))
+      }
+      value => value.deserialize_any(visitor),
+    }
+  }
+
+  fn deserialize_tuple<V: de::Visitor<'de>>(
+    self,
+    _len: usize,
+    visitor: V,
+  ) -> Result<V::Value, Error> {
+    self.deserialize_seq(visitor)
+  }
+
+  fn deserialize_tuple_struct<V: de::Visitor<'de>>(
+    self,
+    _name: &'static str,
+    _len: usize,
+    visitor: V,
+  ) -> Result<V::Value, Error> {
+    self.deserialize_seq(visitor)
+  }
+
+  fn deserialize_string<V: de::Visitor<'de>>(
+    self,
+    visitor: V,
+  ) -> Result<V::Value, Error> {
+    match self {
+      // V8 writes integer object keys as numbers.
+      Value::Number(n) if n.fract() == 0.0 => {
+        visitor.visit_string((n as i64).to_string())
+      }
+      value => value.deserialize_any(visitor),
+    }
+  }
+
+  fn deserialize_str<V: de::Visitor<'de>>(
+    self,
+    visitor: V,
+  ) -> Result<V::Value, Error> {
+    self.deserialize_string(visitor)
+  }
+
+  fn deserialize_identifier<V: de::Visitor<'de>>(
+    self,
+    visitor: V,
+  ) -> Result<V::Value, Error> {
+    self.deserialize_string(visitor)
+  }
+
+  fn deserialize_enum<V: de::Visitor<'de>>(
+    self,
+    _name: &'static str,
+    _variants: &'static [&'static str],
+    visitor: V,
+  ) -> Result<V::Value, Error> {
+    match self {
+      Value::String(variant) => visitor.visit_enum(variant.into_deserializer()),
+      Value::Object(mut entries) | Value::Map(mut entries)
+        if entries.len() == 1 =>
+      {
+        let (variant, value) = entries.pop().unwrap();
+        visitor.visit_enum(EnumDeserializer { variant, value })
+      }
+      _ => Err(error("expected a string or an object with one property")),
+    }
+  }
+
+  serde::forward_to_deserialize_any! {
+    bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char bytes byte_buf
+    unit unit_struct map struct ignored_any
+  }
+}
+
+impl IntoDeserializer<'_, Error> for Value {
+  type Deserializer = Self;
+
+  fn into_deserializer(self) -> Self {
+    self
+  }
+}
+
+struct EnumDeserializer {
+  variant: Value,
+  value: Value,
+}
+
+impl<'de> de::EnumAccess<'de> for EnumDeserializer {
+  type Error = Error;
+  type Variant = Value;
+
+  fn variant_seed<V: de::DeserializeSeed<'de>>(
+    self,
+    seed: V,
+  ) -> Result<(V::Value, Value), Error> {
+    Ok((seed.deserialize(self.variant)?, self.value))
+  }
+}
+
+impl<'de> de::VariantAccess<'de> for Value {
+  type Error = Error;
+
+  fn unit_variant(self) -> Result<(), Error> {
+    de::Deserialize::deserialize(self)
+  }
+
+  fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(
+    self,
+    seed: T,
+  ) -> Result<T::Value, Error> {
+    seed.deserialize(self)
+  }
+
+  fn tuple_variant<V: de::Visitor<'de>>(
+    self,
+    _len: usize,
+    visitor: V,
+  ) -> Result<V::Value, Error> {
+    de::Deserializer::deserialize_seq(self, visitor)
+  }
+
+  fn struct_variant<V: de::Visitor<'de>>(
+    self,
+    _fields: &'static [&'static str],
+    visitor: V,
+  ) -> Result<V::Value, Error> {
+    de::Deserializer::deserialize_map(self, visitor)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::BTreeMap;
+
+  use super::*;
+
+  #[derive(Debug, PartialEq, Serialize, Deserialize)]
+  struct User {
+    name: String,
+    age: u32,
+    score: f64,
+    tags: Vec<String>,
+    avatar: Bytes,
+    joined: Date,
+    friends: BTreeMap<String, u32>,
+    nickname: Option<String>,
+    balance: i128,
+    role: Role,
+  }
+
+  #[derive(Debug, PartialEq, Serialize, Deserialize)]
+  enum Role {
+    Admin,
+    Member { since: u32 },
+  }
+
+  #[test]
+  fn edits_fields() {
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Account {
+      email: String,
+      ssn: Option<String>,
+      contacts: Vec<BTreeMap<String, String>>,
+    }
+
+    let account = Account {
+      email: "zoe@example.com".to_string(),
+      ssn: Some("123".to_string()),
+      contacts: vec![BTreeMap::from([
+        ("email".to_string(), "bob@example.com".to_string()),
+        ("name".to_string(), "Bob".to_string()),
+      ])],
+    };
+    let edited =
+      edit_fields(&to_vec(&account).unwrap(), |name, value| {
+        match (name, value) {
+          ("ssn", _) => FieldEdit::Remove,
+          ("email", Some(email)) => FieldEdit::Replace(email.len().to_string()),
+          _ => FieldEdit::Keep,
+        }
+      })
+      .unwrap();
+
+    // The object is still an object and the nested map still a map.
+    assert_eq!(edited[2], tag::BEGIN_OBJECT);
+    assert!(edited.contains(&tag::BEGIN_MAP));
+    assert_eq!(
+      from_slice::<Account>(&edited).unwrap(),
+      Account {
+        email: "15".to_string(),
+        ssn: None,
+        contacts: vec![BTreeMap::from([
+          ("email".to_string(), "15".to_string()),
+          ("name".to_string(), "Bob".to_string()),
+        ])],
+      }
+    );
+  }
+
+  #[test]
+  fn round_trips() {
+    let user = User {
+      name: "Zoë 🦕".to_string(),
+      age: 42,
+      score: -0.5,
+      tags: vec!["a".to_string(), "€".to_string()],
+      avatar: Bytes(vec![0, 1, 255]),
+      joined: Date(1_700_000_000_000.0),
+      friends: BTreeMap::from([("bob".to_string(), 1)]),
+      nickname: None,
+      balance: -(1 << 100),
+      role: Role::Member { since: 2020 },
+    };
+    let bytes = to_vec(&user).unwrap();
+    assert_eq!(from_slice::<User>(&bytes).unwrap(), user);
+    assert_eq!(
+      from_slice::<Role>(&to_vec(&Role::Admin).unwrap()),
+      Ok(Role::Admin)
+    );
+  }
+
+  /// Values as written by `Deno.core.serialize`.
+  #[test]
+  fn decodes_values_written_by_v8() {
+    // "hello"
+    let bytes = b"\xff\x0f\x22\x05hello";
+    assert_eq!(from_slice::<String>(bytes).unwrap(), "hello");
+    // [true, "€"], with the string aligned by padding
+    let bytes = b"\xff\x0f\x41\x02\x54\x00\x63\x02\xac\x20\x24\x00\x02";
+    assert_eq!(
+      from_slice::<(bool, String)>(bytes).unwrap(),
+      (true, "€".to_string())
+    );
+    // { a: 1, b: "x" }
+    let bytes = b"\xff\x0f\x6f\x22\x01a\x49\x02\x22\x01b\x22\x01x\x7b\x02";
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Ab {
+      a: u8,
+      b: String,
+    }
+    assert_eq!(
+      from_slice::<Ab>(bytes).unwrap(),
+      Ab {
+        a: 1,
+        b: "x".to_string()
+      }
+    );
+    // [1, 2]
+    let bytes = b"\xff\x0f\x41\x02\x49\x02\x49\x04\x24\x00\x02";
+    assert_eq!(from_slice::<Vec<i32>>(bytes).unwrap(), [1, 2]);
+    // new Uint8Array([1, 2, 3])
+    let bytes = b"\xff\x0f\x42\x03\x01\x02\x03\x56\x42\x00\x03\x00";
+    assert_eq!(from_slice::<Bytes>(bytes).unwrap(), Bytes(vec![1, 2, 3]));
+    assert_eq!(from_slice::<Vec<u8>>(bytes).unwrap(), [1, 2, 3]);
+    // new Map([["k", 1]])
+    let bytes = b"\xff\x0f\x3b\x22\x01k\x49\x02\x3a\x02";
+    assert_eq!(
+      from_slice::<BTreeMap<String, u8>>(bytes).unwrap(),
+      BTreeMap::from([("k".to_string(), 1)])
+    );
+    // new Date(0)
+    let bytes = b"\xff\x0f\x44\x00\x00\x00\x00\x00\x00\x00\x00";
+    assert_eq!(from_slice::<Date>(bytes).unwrap(), Date(0.0));
+    // 10n
+    let bytes = b"\xff\x0f\x5a\x10\x0a\x00\x00\x00\x00\x00\x00\x00";
+    assert_eq!(from_slice::<u64>(bytes).unwrap(), 10);
+    // const o = {}; [o, o]
+    let bytes = b"\xff\x0f\x41\x02\x6f\x7b\x00\x5e\x01\x24\x00\x02";
+    assert_eq!(
+      from_slice::<Vec<BTreeMap<String, u8>>>(bytes)
+        .unwrap()
+        .len(),
+      2
+    );
+  }
+
+  #[test]
+  fn encodes_like_v8() {
+    assert_eq!(to_vec("hello").unwrap(), b"\xff\x0f\x22\x05hello");
+    assert_eq!(
+      to_vec(&[1, 2]).unwrap(),
+      b"\xff\x0f\x41\x02\x49\x02\x49\x04\x24\x00\x02"
+    );
+    assert_eq!(
+      to_vec(&Bytes(vec![1, 2, 3])).unwrap(),
+      b"\xff\x0f\x42\x03\x01\x02\x03\x56\x42\x00\x03\x00"
+    );
+    assert_eq!(to_vec("€").unwrap(), b"\xff\x0f\x63\x02\xac\x20");
+    assert_eq!(
+      to_vec(&(true, "€")).unwrap(),
+      b"\xff\x0f\x41\x02\x54\x00\x63\x02\xac\x20\x24\x00\x02"
+    );
+  }
+
+  #[test]
+  fn agrees_with_v8_valueserializer() {
+    use v8_valueserializer::HeapBuilder;
+    use v8_valueserializer::HeapValue;
+    use v8_valueserializer::Object;
+    use v8_valueserializer::PropertyKey;
+    use v8_valueserializer::StringValue;
+    use v8_valueserializer::ValueDeserializer;
+    use v8_valueserializer::ValueSerializer;
+
+    let user = User {
+      name: "Zoë".to_string(),
+      age: 7,
+      score: 0.25,
+      tags: vec![],
+      avatar: Bytes(vec![9; 3]),
+      joined: Date(0.0),
+      friends: BTreeMap::new(),
+      nickname: Some("z".to_string()),
+      balance: 1 << 70,
+      role: Role::Admin,
+    };
+    ValueDeserializer::default()
+      .read(&to_vec(&user).unwrap())
+      .unwrap();
+
+    let mut heap = HeapBuilder::default();
+    let string = |s: &str| StringValue::new(s.to_string());
+    let object = heap.insert(HeapValue::Object(Object {
+      properties: vec![
+        (
+          PropertyKey::String(string("a")),
+          v8_valueserializer::Value::I32(1),
+        ),
+        (
+          PropertyKey::String(string("b")),
+          v8_valueserializer::Value::String(string("€")),
+        ),
+      ],
+    }));
+    let bytes = ValueSerializer::default()
+      .finish(
+        &heap.build().unwrap(),
+        &v8_valueserializer::Value::HeapReference(object),
+      )
+      .unwrap();
+    assert_eq!(
+      from_slice::<BTreeMap<String, serde_json::Value>>(&bytes).unwrap(),
+      BTreeMap::from([
+        ("a".to_string(), serde_json::json!(1)),
+        ("b".to_string(), serde_json::json!("€")),
+      ])
+    );
+  }
+
+  #[test]
+  fn encodes_objects_of_maps() {
+    let entries = BTreeMap::from([("a", 1)]);
+    // `{ a: 1 }` rather than `new Map([["a", 1]])`.
+    assert_eq!(
+      to_vec(&Object(&entries)).unwrap(),
+      b"\xff\x0f\x6f\x22\x01a\x49\x02\x7b\x01"
+    );
+    assert_eq!(
+      to_vec(&entries).unwrap(),
+      b"\xff\x0f\x3b\x22\x01a\x49\x02\x3a\x02"
+    );
+    let Object(decoded): Object<BTreeMap<String, u32>> =
+      from_slice(&to_vec(&Object(&entries)).unwrap()).unwrap();
+    assert_eq!(decoded, BTreeMap::from([("a".to_string(), 1)]));
+  }
+
+  #[test]
+  fn rejects_unsafe_integers() {
+    assert!(to_vec(&(1u64 << 53)).is_err());
+    assert!(to_vec(&((1u64 << 53) - 1)).is_ok());
+  }
+
+  #[test]
+  fn rejects_invalid_values() {
+    // Truncated, unknown tag, and a self-referencing array.
+    for bytes in [
+      &b"\xff\x0f\x22\x05hel"[..],
+      b"\xff\x0f\x72",
+      b"\xff\x0f\x41\x01\x5e\x00\x24\x00\x01",
+    ] {
+      assert!(from_slice::<()>(bytes).is_err(), "{bytes:?}");
+    }
+    let nested = [&b"\xff\x0f"[..], &[b'A', 1].repeat(1000)].concat();
+    assert!(from_slice::<()>(&nested).is_err());
+  }
+}
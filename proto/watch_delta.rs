@@ -0,0 +1,206 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+use crate::datapath as pb;
+use crate::WatchKeyOutput;
+
+/// Deltas that don't save at least this many bytes over the full value are
+/// not worth the extra work on both ends.
+const MIN_DELTA_SAVING: usize = 16;
+
+/// Encodes the outputs of one watch stream. If the client accepts value
+/// deltas, a changed value is sent as a [`pb::ValueDelta`] against the value
+/// last delivered for the same key whenever that is meaningfully smaller.
+pub struct WatchOutputEncoder {
+  accept_deltas: bool,
+  /// The last delivered value and encoding for each watched key.
+  previous: Vec<Option<(Vec<u8>, i32)>>,
+}
+
+impl WatchOutputEncoder {
+  pub fn new(accept_deltas: bool) -> Self {
+    Self {
+      accept_deltas,
+      previous: Vec::new(),
+    }
+  }
+
+  pub fn encode(&mut self, outputs: Vec<WatchKeyOutput>) -> pb::WatchOutput {
+    if !self.accept_deltas {
+      return outputs.into();
+    }
+    self.previous.resize(outputs.len(), None);
+
+    let mut output: pb::WatchOutput = outputs.into();
+    for (key, previous) in output.keys.iter_mut().zip(&mut self.previous) {
+      if !key.changed {
+        continue;
+      }
+      let Some(entry) = &mut key.entry_if_changed else {
+        *previous = None;
+        continue;
+      };
+      let delta = match previous {
+        Some((old, encoding)) if *encoding == entry.encoding => {
+          diff(old, &entry.value)
+        }
+        _ => None,
+      };
+      let value = if let Some(delta) = delta {
+        key.value_delta = Some(delta);
+        std::mem::take(&mut entry.value)
+      } else {
+        entry.value.clone()
+      };
+      *previous = Some((value, entry.encoding));
+    }
+    output
+  }
+}
+
+/// Reassembles values sent as deltas on one watch stream.
+#[derive(Default)]
+pub struct WatchOutputDecoder {
+  /// The last received value for each watched key.
+  previous: Vec<Option<Vec<u8>>>,
+}
+
+impl WatchOutputDecoder {
+  /// Replace delta-encoded values in `output` with the full values. Returns
+  /// `false` if a delta does not fit the previous value of its key.
+  pub fn decode(&mut self, output: &mut pb::WatchOutput) -> bool {
+    self.previous.resize(output.keys.len(), None);
+    for (key, previous) in output.keys.iter_mut().zip(&mut self.previous) {
+      if !key.changed {
+        continue;
+      }
+      let Some(entry) = &mut key.entry_if_changed else {
+        *previous = None;
+        continue;
+      };
+      if let Some(delta) = key.value_delta.take() {
+        let Some(value) =
+          previous.as_deref().and_then(|old| apply(old, &delta))
+        else {
+          return false;
+        };
+        entry.value = value;
+      }
+      *previous = Some(entry.value.clone());
+    }
+    true
+  }
+}
+
+/// Express `new` as the common prefix and suffix with `old` plus the bytes
+/// in between, if that is meaningfully smaller than `new`.
+fn diff(old: &[u8], new: &[u8]) -> Option<pb::ValueDelta> {
+  let prefix_len = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+  let max_suffix = old.len().min(new.len()) - prefix_len;
+  let suffix_len = old
+    .iter()
+    .rev()
+    .zip(new.iter().rev())
+    .take(max_suffix)
+    .take_while(|(a, b)| a == b)
+    .count();
+  let middle = &new[prefix_len..new.len() - suffix_len];
+  if middle.len() + MIN_DELTA_SAVING > new.len() {
+    return None;
+  }
+  Some(pb::ValueDelta {
+    prefix_len: prefix_len as u32,
+    suffix_len: suffix_len as u32,
+    middle: middle.to_vec(),
+  })
+}
+
+fn apply(old: &[u8], delta: &pb::ValueDelta) -> Option<Vec<u8>> {
+  let prefix = old.get(..delta.prefix_len as usize)?;
+  let suffix_start = old.len().checked_sub(delta.suffix_len as usize)?;
+  if suffix_start < prefix.len() {
+    return None;
+  }
+  Some([prefix, &delta.middle, &old[suffix_start..]].concat())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::KvEntry;
+  use crate::KvValue;
+
+  fn changed(value: &[u8]) -> WatchKeyOutput {
+    WatchKeyOutput::Changed {
+      entry: Some(KvEntry {
+        key: b"doc".to_vec(),
+        value: KvValue::Bytes(value.to_vec()),
+        versionstamp: [0; 10],
+      }),
+    }
+  }
+
+  #[test]
+  fn delta_round_trip() {
+    let first = vec![b'a'; 1000];
+    let mut second = first.clone();
+    second[500..504].copy_from_slice(b"edit");
+
+    let mut encoder = WatchOutputEncoder::new(true);
+    let mut decoder = WatchOutputDecoder::default();
+
+    let mut out = encoder.encode(vec![changed(&first)]);
+    assert!(out.keys[0].value_delta.is_none());
+    assert!(decoder.decode(&mut out));
+
+    let mut out = encoder.encode(vec![changed(&second)]);
+    let delta = out.keys[0].value_delta.as_ref().unwrap();
+    assert_eq!(delta.middle, b"edit");
+    assert!(out.keys[0]
+      .entry_if_changed
+      .as_ref()
+      .unwrap()
+      .value
+      .is_empty());
+    assert!(decoder.decode(&mut out));
+    assert_eq!(out.keys[0].entry_if_changed.as_ref().unwrap().value, second);
+  }
+
+  #[test]
+  fn full_values_without_negotiation_or_saving() {
+    let mut encoder = WatchOutputEncoder::new(false);
+    encoder.encode(vec![changed(&[1; 100])]);
+    let out = encoder.encode(vec![changed(&[1; 101])]);
+    assert!(out.keys[0].value_delta.is_none());
+
+    let mut encoder = WatchOutputEncoder::new(true);
+    encoder.encode(vec![changed(b"short")]);
+    let out = encoder.encode(vec![changed(b"shorter")]);
+    assert!(out.keys[0].value_delta.is_none());
+  }
+
+  #[test]
+  fn delta_without_base_is_rejected() {
+    let mut out = pb::WatchOutput {
+      keys: vec![pb::WatchKeyOutput {
+        changed: true,
+        entry_if_changed: Some(pb::KvEntry::default()),
+        value_delta: Some(pb::ValueDelta::default()),
+      }],
+      ..Default::default()
+    };
+    assert!(!WatchOutputDecoder::default().decode(&mut out));
+  }
+
+  #[test]
+  fn overlapping_prefix_and_suffix() {
+    // "aa" -> "aaa": prefix and suffix may not overlap in the old value.
+    let delta = diff(b"aa", b"aaa").map(|d| (d.prefix_len, d.suffix_len));
+    assert_eq!(delta, None);
+    let delta = pb::ValueDelta {
+      prefix_len: 2,
+      suffix_len: 0,
+      middle: b"a".to_vec(),
+    };
+    assert_eq!(apply(b"aa", &delta).unwrap(), b"aaa");
+  }
+}
@@ -0,0 +1,536 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! Multi-step workflows driven by the queue of a [`Database`], with the
+//! `workflow` feature.
+//!
+//! A [`Workflow`] is a list of [`Step`]s that run in order over a state.
+//! Each run of a workflow is stored at `[...prefix, id]` as JSON: the step
+//! it is at, its state and how it went. Every step runs from a queue
+//! message, and finishing a step stores the new state and enqueues the
+//! message for the next step in one atomic write, which checks that the
+//! run was not moved on by someone else in the meantime.
+//!
+//! A step that fails is retried after a backoff that doubles with every
+//! attempt. Once its attempts are used up the run is compensated: the
+//! compensation hooks of the steps that completed run in reverse order,
+//! and the run ends as [`RunStatus::Failed`].
+//!
+//! Steps run at least once. A worker that stops during a step leaves its
+//! message in the queue, to be delivered again after the backoff of the
+//! queue, so steps should be idempotent. Like the listeners of `pubsub`, a
+//! [`WorkflowEngine`] hands back every queue message that is not its own,
+//! so give it the queue of a database to itself.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use deno_error::JsErrorBox;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::encode_key;
+use crate::time::utc_now;
+use crate::AtomicWrite;
+use crate::Check;
+use crate::Consistency;
+use crate::Database;
+use crate::Enqueue;
+use crate::Key;
+use crate::KeyPart;
+use crate::KvValue;
+use crate::Mutation;
+use crate::MutationKind;
+use crate::QueueMessageHandle;
+use crate::ReadRange;
+use crate::SnapshotReadOptions;
+use crate::Versionstamp;
+
+/// Starts the payload of every message enqueued by a [`WorkflowEngine`],
+/// before the JSON of the task.
+const TASK_MAGIC: &[u8] = b"\x00denokv-workflow\x00";
+
+/// How long [`WorkflowEngine::run`] waits when the queue is empty.
+const RUN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A step of a [`Workflow`].
+#[async_trait]
+pub trait Step<S>: Send + Sync {
+  fn name(&self) -> &str;
+
+  /// Do the work of the step, updating `state`. Changes to `state` are
+  /// only kept if this succeeds.
+  async fn run(&self, state: &mut S) -> Result<(), JsErrorBox>;
+
+  /// Undo the work of the step after a later step failed for good.
+  async fn compensate(&self, state: &mut S) -> Result<(), JsErrorBox> {
+    let _ = state;
+    Ok(())
+  }
+}
+
+/// The steps of a workflow over a state of type `S`, and how they are
+/// retried.
+pub struct Workflow<S> {
+  name: String,
+  steps: Vec<Box<dyn Step<S>>>,
+  max_attempts: u32,
+  retry_backoff: Duration,
+}
+
+impl<S> Workflow<S> {
+  /// An empty workflow. Queue messages carry the name, so that engines of
+  /// different workflows tell theirs apart.
+  pub fn new(name: impl Into<String>) -> Self {
+    Self {
+      name: name.into(),
+      steps: vec![],
+      max_attempts: 3,
+      retry_backoff: Duration::from_secs(1),
+    }
+  }
+
+  pub fn step(mut self, step: impl Step<S> + 'static) -> Self {
+    self.steps.push(Box::new(step));
+    self
+  }
+
+  /// How many times a step or a compensation is tried before giving up.
+  /// Defaults to 3.
+  pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+    self.max_attempts = max_attempts.max(1);
+    self
+  }
+
+  /// How long to wait before the first retry of a step. Every further
+  /// retry waits twice as long. Defaults to a second.
+  pub fn retry_backoff(mut self, retry_backoff: Duration) -> Self {
+    self.retry_backoff = retry_backoff;
+    self
+  }
+
+  /// Where `run` goes after its current step finished with `result` and
+  /// left `state`, and how long until that runs, if anything is left to
+  /// run.
+  fn advance(
+    &self,
+    mut run: WorkflowRun<S>,
+    state: S,
+    result: Result<(), JsErrorBox>,
+  ) -> (WorkflowRun<S>, Option<Duration>) {
+    let compensating = run.status == RunStatus::Compensating;
+    let name = self.steps[run.step].name();
+    let error = match result {
+      Ok(()) => {
+        run.state = state;
+        run.attempts = 0;
+        if compensating {
+          let previous = run.step.checked_sub(1);
+          return self.compensate_from(run, previous);
+        }
+        run.step += 1;
+        if run.step == self.steps.len() {
+          run.status = RunStatus::Completed;
+          return (run, None);
+        }
+        return (run, Some(Duration::ZERO));
+      }
+      Err(e) => e,
+    };
+
+    run.attempts += 1;
+    if run.attempts < self.max_attempts {
+      let backoff = self.retry_backoff * 2u32.saturating_pow(run.attempts - 1);
+      return (run, Some(backoff));
+    }
+    run.attempts = 0;
+    if compensating {
+      let message = format!("compensating step {name:?} failed: {error}");
+      run.error = Some(match run.error.take() {
+        Some(cause) => format!("{cause}; {message}"),
+        None => message,
+      });
+      run.status = RunStatus::Failed;
+      return (run, None);
+    }
+    run.error = Some(format!("step {name:?} failed: {error}"));
+    let previous = run.step.checked_sub(1);
+    self.compensate_from(run, previous)
+  }
+
+  /// Compensate `run` from `step` backwards, or end it if no step is left.
+  fn compensate_from(
+    &self,
+    mut run: WorkflowRun<S>,
+    step: Option<usize>,
+  ) -> (WorkflowRun<S>, Option<Duration>) {
+    match step {
+      Some(step) => {
+        run.status = RunStatus::Compensating;
+        run.step = step;
+        (run, Some(Duration::ZERO))
+      }
+      None => {
+        run.status = RunStatus::Failed;
+        (run, None)
+      }
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+  Running,
+  /// A step failed for good, and the steps before it are being undone.
+  Compensating,
+  Completed,
+  Failed,
+}
+
+/// A run of a workflow, as stored in the database.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WorkflowRun<S> {
+  pub status: RunStatus,
+  /// The index of the step running or being compensated.
+  pub step: usize,
+  /// Failed attempts at the current step.
+  pub attempts: u32,
+  pub state: S,
+  /// Why the run is compensating or failed.
+  pub error: Option<String>,
+}
+
+/// The queue message that runs a step of a run.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Task {
+  workflow: String,
+  id: String,
+  status: RunStatus,
+  step: usize,
+  attempts: u32,
+}
+
+impl Task {
+  fn seal(&self) -> Vec<u8> {
+    let json = serde_json::to_vec(self).expect("tasks serialize");
+    [TASK_MAGIC, &json].concat()
+  }
+
+  fn open(payload: &[u8]) -> Option<Self> {
+    serde_json::from_slice(payload.strip_prefix(TASK_MAGIC)?).ok()
+  }
+
+  /// Whether `run` is still where the task was enqueued for. Anything else
+  /// is a task delivered again after the run moved on.
+  fn is_due<S>(&self, run: &WorkflowRun<S>) -> bool {
+    (self.status, self.step, self.attempts)
+      == (run.status, run.step, run.attempts)
+  }
+}
+
+/// Runs of a [`Workflow`], stored under an encoded key prefix of a
+/// database.
+pub struct WorkflowEngine<D: Database, S> {
+  db: D,
+  prefix: Vec<u8>,
+  workflow: Arc<Workflow<S>>,
+}
+
+impl<D: Database, S> Clone for WorkflowEngine<D, S> {
+  fn clone(&self) -> Self {
+    Self {
+      db: self.db.clone(),
+      prefix: self.prefix.clone(),
+      workflow: self.workflow.clone(),
+    }
+  }
+}
+
+impl<D, S> WorkflowEngine<D, S>
+where
+  D: Database,
+  S: Serialize + DeserializeOwned + Clone + Send + Sync + 'static,
+{
+  pub fn new(db: D, prefix: Vec<u8>, workflow: Workflow<S>) -> Self {
+    Self {
+      db,
+      prefix,
+      workflow: Arc::new(workflow),
+    }
+  }
+
+  /// Start the run `id` with `state`. Returns `false`, and starts nothing,
+  /// if a run with that id exists.
+  pub async fn start(&self, id: &str, state: S) -> Result<bool, JsErrorBox> {
+    let run = WorkflowRun {
+      status: if self.workflow.steps.is_empty() {
+        RunStatus::Completed
+      } else {
+        RunStatus::Running
+      },
+      step: 0,
+      attempts: 0,
+      state,
+      error: None,
+    };
+    let next = (run.status == RunStatus::Running).then_some(Duration::ZERO);
+    self.write(id, &run, None, next).await
+  }
+
+  /// The run `id`, if it was started.
+  pub async fn get(
+    &self,
+    id: &str,
+  ) -> Result<Option<WorkflowRun<S>>, JsErrorBox> {
+    Ok(self.read(id).await?.map(|(run, _)| run))
+  }
+
+  /// Dequeue a message and run the step it is for. Returns `false` if the
+  /// queue was empty. Messages of other workflows and other users of the
+  /// queue are handed back.
+  pub async fn process_next(&self) -> Result<bool, JsErrorBox> {
+    let Some(mut handle) = self.db.dequeue_next_message().await? else {
+      return Ok(false);
+    };
+    let payload = handle.take_payload().await?;
+    let task =
+      Task::open(&payload).filter(|task| task.workflow == self.workflow.name);
+    let Some(task) = task else {
+      handle.finish(false).await?;
+      return Ok(true);
+    };
+    match self.handle(task).await {
+      Ok(()) => handle.finish(true).await?,
+      Err(e) => {
+        handle.finish(false).await?;
+        return Err(e);
+      }
+    }
+    Ok(true)
+  }
+
+  /// Process messages until one fails to be processed.
+  pub async fn run(&self) -> Result<(), JsErrorBox> {
+    loop {
+      if !self.process_next().await? {
+        tokio::time::sleep(RUN_POLL_INTERVAL).await;
+      }
+    }
+  }
+
+  async fn handle(&self, task: Task) -> Result<(), JsErrorBox> {
+    let Some((run, versionstamp)) = self.read(&task.id).await? else {
+      return Ok(());
+    };
+    if !task.is_due(&run) || run.step >= self.workflow.steps.len() {
+      return Ok(());
+    }
+    let step = &self.workflow.steps[run.step];
+    let mut state = run.state.clone();
+    let result = match run.status {
+      RunStatus::Running => step.run(&mut state).await,
+      _ => step.compensate(&mut state).await,
+    };
+    let (run, next) = self.workflow.advance(run, state, result);
+    // If the check fails the run was moved on by a task delivered twice,
+    // which already did this.
+    self.write(&task.id, &run, Some(versionstamp), next).await?;
+    Ok(())
+  }
+
+  async fn read(
+    &self,
+    id: &str,
+  ) -> Result<Option<(WorkflowRun<S>, Versionstamp)>, JsErrorBox> {
+    let key = self.run_key(id)?;
+    let read = ReadRange {
+      start: key.clone(),
+      end: [key.as_slice(), &[0]].concat(),
+      limit: std::num::NonZeroU32::MIN,
+      reverse: false,
+    };
+    let options = SnapshotReadOptions {
+      consistency: Consistency::Strong,
+    };
+    let mut outputs = self.db.snapshot_read(vec![read], options).await?;
+    let Some(entry) = outputs
+      .pop()
+      .and_then(|output| output.entries.into_iter().next())
+    else {
+      return Ok(None);
+    };
+    let KvValue::Bytes(bytes) = &entry.value else {
+      return Err(JsErrorBox::type_error("Expected a workflow run"));
+    };
+    let run = serde_json::from_slice(bytes).map_err(JsErrorBox::from_err)?;
+    Ok(Some((run, entry.versionstamp)))
+  }
+
+  /// Store `run` if its key still has `versionstamp`, and enqueue its next
+  /// task after `next`, if there is one.
+  async fn write(
+    &self,
+    id: &str,
+    run: &WorkflowRun<S>,
+    versionstamp: Option<Versionstamp>,
+    next: Option<Duration>,
+  ) -> Result<bool, JsErrorBox> {
+    let key = self.run_key(id)?;
+    let value = serde_json::to_vec(run).map_err(JsErrorBox::from_err)?;
+    let enqueues = match next {
+      Some(delay) => {
+        let task = Task {
+          workflow: self.workflow.name.clone(),
+          id: id.to_string(),
+          status: run.status,
+          step: run.step,
+          attempts: run.attempts,
+        };
+        let delay = chrono::Duration::from_std(delay)
+          .map_err(|e| JsErrorBox::type_error(e.to_string()))?;
+        vec![Enqueue {
+          payload: task.seal(),
+          deadline: utc_now() + delay,
+          keys_if_undelivered: vec![],
+          backoff_schedule: None,
+        }]
+      }
+      None => vec![],
+    };
+    let write = AtomicWrite {
+      checks: vec![Check {
+        key: key.clone(),
+        versionstamp,
+      }],
+      mutations: vec![Mutation {
+        key,
+        kind: MutationKind::Set(KvValue::Bytes(value)),
+        expire_at: None,
+      }],
+      enqueues,
+    };
+    Ok(self.db.atomic_write(write).await?.is_some())
+  }
+
+  fn run_key(&self, id: &str) -> Result<Vec<u8>, JsErrorBox> {
+    let part = encode_key(&Key(vec![KeyPart::String(id.to_string())]))
+      .map_err(JsErrorBox::from_err)?;
+    Ok([self.prefix.as_slice(), &part].concat())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  struct Noop(&'static str);
+
+  #[async_trait]
+  impl Step<()> for Noop {
+    fn name(&self) -> &str {
+      self.0
+    }
+
+    async fn run(&self, _: &mut ()) -> Result<(), JsErrorBox> {
+      Ok(())
+    }
+  }
+
+  fn workflow() -> Workflow<()> {
+    Workflow::new("orders")
+      .step(Noop("reserve"))
+      .step(Noop("charge"))
+      .step(Noop("ship"))
+      .max_attempts(2)
+      .retry_backoff(Duration::from_secs(1))
+  }
+
+  fn run(status: RunStatus, step: usize, attempts: u32) -> WorkflowRun<()> {
+    WorkflowRun {
+      status,
+      step,
+      attempts,
+      state: (),
+      error: None,
+    }
+  }
+
+  fn failed() -> Result<(), JsErrorBox> {
+    Err(JsErrorBox::generic("declined"))
+  }
+
+  #[test]
+  fn runs_steps_in_order() {
+    let workflow = workflow();
+    let (next, delay) =
+      workflow.advance(run(RunStatus::Running, 1, 1), (), Ok(()));
+    assert_eq!(
+      (next.status, next.step, next.attempts),
+      (RunStatus::Running, 2, 0)
+    );
+    assert_eq!(delay, Some(Duration::ZERO));
+    let (next, delay) = workflow.advance(next, (), Ok(()));
+    assert_eq!(next.status, RunStatus::Completed);
+    assert_eq!(delay, None);
+  }
+
+  #[test]
+  fn retries_then_compensates_completed_steps() {
+    let workflow = workflow();
+    let (next, delay) =
+      workflow.advance(run(RunStatus::Running, 2, 0), (), failed());
+    assert_eq!(
+      (next.status, next.step, next.attempts),
+      (RunStatus::Running, 2, 1)
+    );
+    assert_eq!(delay, Some(Duration::from_secs(1)));
+
+    let (next, delay) = workflow.advance(next, (), failed());
+    assert_eq!(
+      (next.status, next.step, next.attempts),
+      (RunStatus::Compensating, 1, 0)
+    );
+    assert_eq!(delay, Some(Duration::ZERO));
+    assert_eq!(
+      next.error.as_deref(),
+      Some("step \"ship\" failed: declined")
+    );
+
+    let (next, _) = workflow.advance(next, (), Ok(()));
+    assert_eq!((next.status, next.step), (RunStatus::Compensating, 0));
+    let (next, delay) = workflow.advance(next, (), Ok(()));
+    assert_eq!(next.status, RunStatus::Failed);
+    assert_eq!(delay, None);
+  }
+
+  #[test]
+  fn a_failing_compensation_ends_the_run() {
+    let workflow = workflow();
+    let mut compensating = run(RunStatus::Compensating, 1, 1);
+    compensating.error = Some("step \"ship\" failed: declined".to_string());
+    let (next, delay) = workflow.advance(compensating, (), failed());
+    assert_eq!(next.status, RunStatus::Failed);
+    assert_eq!(delay, None);
+    assert_eq!(
+      next.error.as_deref(),
+      Some(
+        "step \"ship\" failed: declined; compensating step \"charge\" failed: declined"
+      )
+    );
+  }
+
+  #[test]
+  fn tasks_round_trip() {
+    let task = Task {
+      workflow: "orders".to_string(),
+      id: "42".to_string(),
+      status: RunStatus::Compensating,
+      step: 1,
+      attempts: 0,
+    };
+    assert_eq!(Task::open(&task.seal()), Some(task));
+    assert_eq!(Task::open(b"{\"workflow\":\"orders\"}"), None);
+  }
+}
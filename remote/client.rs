@@ -0,0 +1,331 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! A typed client for Rust services that talk to a remote denokv server.
+//!
+//! [`KvClient`] wraps a [`Remote`] and works with [`Key`]s instead of
+//! encoded keys. The [`Remote`] underneath retries failed requests, keeps the
+//! access token fresh and follows read continuations; connections are pooled
+//! by the transport. With the `reqwest` feature, [`KvClient::connect`] sets
+//! all of that up from a URL and an access token.
+
+use std::fmt;
+use std::num::NonZeroU32;
+use std::str::FromStr;
+
+use async_stream::try_stream;
+use deno_error::JsError;
+use deno_error::JsErrorBox;
+use denokv_proto::decode_key;
+use denokv_proto::encode_key;
+use denokv_proto::AtomicWrite;
+use denokv_proto::Consistency;
+use denokv_proto::Database;
+use denokv_proto::Key;
+use denokv_proto::KvEntry;
+use denokv_proto::KvValue;
+use denokv_proto::Mutation;
+use denokv_proto::MutationKind;
+use denokv_proto::ReadRange;
+use denokv_proto::SnapshotReadOptions;
+use denokv_proto::Versionstamp;
+use denokv_proto::WatchKeyOutput;
+use futures::Stream;
+use futures::StreamExt;
+use thiserror::Error;
+
+use crate::Remote;
+use crate::RemotePermissions;
+use crate::RemoteTransport;
+
+/// Page size [`KvClient::count`] reads keys with.
+const COUNT_PAGE_SIZE: u32 = 1000;
+
+#[derive(Debug, Error, JsError)]
+pub enum ClientError {
+  #[class(inherit)]
+  #[error("{0}")]
+  Remote(JsErrorBox),
+  #[class(generic)]
+  #[error("Invalid key: {0}")]
+  InvalidKey(#[source] std::io::Error),
+  #[class(generic)]
+  #[error("Invalid list cursor")]
+  InvalidCursor,
+  #[class(generic)]
+  #[error("Invalid value: {0}")]
+  InvalidValue(String),
+  #[class(generic)]
+  #[error("Computing a value failed: {0}")]
+  Compute(String),
+}
+
+impl From<JsErrorBox> for ClientError {
+  fn from(err: JsErrorBox) -> Self {
+    ClientError::Remote(err)
+  }
+}
+
+/// A stored entry with its key decoded.
+#[derive(Clone, Debug)]
+pub struct Entry {
+  pub key: Key,
+  pub value: KvValue,
+  pub versionstamp: Versionstamp,
+}
+
+impl TryFrom<KvEntry> for Entry {
+  type Error = ClientError;
+
+  fn try_from(entry: KvEntry) -> Result<Self, ClientError> {
+    Ok(Entry {
+      key: decode_key(&entry.key).map_err(ClientError::InvalidKey)?,
+      value: entry.value,
+      versionstamp: entry.versionstamp,
+    })
+  }
+}
+
+/// Where a listing continues: the encoded key of the last entry returned.
+/// Its string form is hex, so it can be handed to other clients.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cursor(pub(crate) Vec<u8>);
+
+impl fmt::Display for Cursor {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&hex::encode(&self.0))
+  }
+}
+
+impl FromStr for Cursor {
+  type Err = ClientError;
+
+  fn from_str(s: &str) -> Result<Self, ClientError> {
+    hex::decode(s)
+      .map(Cursor)
+      .map_err(|_| ClientError::InvalidCursor)
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct ListOptions {
+  /// Entries per page.
+  pub limit: NonZeroU32,
+  pub reverse: bool,
+  pub consistency: Consistency,
+}
+
+impl Default for ListOptions {
+  fn default() -> Self {
+    Self {
+      limit: NonZeroU32::new(100).unwrap(),
+      reverse: false,
+      consistency: Consistency::Strong,
+    }
+  }
+}
+
+pub struct ListPage {
+  pub entries: Vec<Entry>,
+  /// Set if the page is full, in which case there may be more entries.
+  pub cursor: Option<Cursor>,
+}
+
+#[derive(Clone)]
+pub struct KvClient<P: RemotePermissions, T: RemoteTransport> {
+  remote: Remote<P, T>,
+}
+
+impl<P: RemotePermissions, T: RemoteTransport> KvClient<P, T> {
+  pub fn new(remote: Remote<P, T>) -> Self {
+    Self { remote }
+  }
+
+  /// The untyped database underneath, for atomic writes with checks and
+  /// enqueues.
+  pub fn remote(&self) -> &Remote<P, T> {
+    &self.remote
+  }
+
+  pub async fn get(&self, key: &Key) -> Result<Option<Entry>, ClientError> {
+    let mut entries = self.get_many(std::slice::from_ref(key)).await?;
+    Ok(entries.pop().flatten())
+  }
+
+  /// Read several keys from one snapshot.
+  pub async fn get_many(
+    &self,
+    keys: &[Key],
+  ) -> Result<Vec<Option<Entry>>, ClientError> {
+    let requests = keys
+      .iter()
+      .map(|key| {
+        let start = encode_key(key).map_err(ClientError::InvalidKey)?;
+        let mut end = start.clone();
+        end.push(0);
+        Ok(ReadRange {
+          start,
+          end,
+          limit: NonZeroU32::MIN,
+          reverse: false,
+        })
+      })
+      .collect::<Result<_, ClientError>>()?;
+    let outputs = self.remote.snapshot_read(requests, strong_read()).await?;
+    outputs
+      .into_iter()
+      .map(|output| output.entries.into_iter().next().map(Entry::try_from))
+      .map(Option::transpose)
+      .collect()
+  }
+
+  pub async fn set(
+    &self,
+    key: &Key,
+    value: KvValue,
+  ) -> Result<Versionstamp, ClientError> {
+    self.mutate(key, MutationKind::Set(value)).await
+  }
+
+  pub async fn delete(&self, key: &Key) -> Result<Versionstamp, ClientError> {
+    self.mutate(key, MutationKind::Delete).await
+  }
+
+  pub(crate) async fn mutate(
+    &self,
+    key: &Key,
+    kind: MutationKind,
+  ) -> Result<Versionstamp, ClientError> {
+    let write = AtomicWrite {
+      checks: vec![],
+      mutations: vec![Mutation {
+        key: encode_key(key).map_err(ClientError::InvalidKey)?,
+        kind,
+        expire_at: None,
+      }],
+      enqueues: vec![],
+    };
+    let result = self.remote.atomic_write(write).await?;
+    Ok(result.expect("writes without checks commit").versionstamp)
+  }
+
+  /// One page of the entries whose keys start with `prefix`, after
+  /// `cursor` if given.
+  pub async fn list(
+    &self,
+    prefix: &Key,
+    cursor: Option<&Cursor>,
+    options: &ListOptions,
+  ) -> Result<ListPage, ClientError> {
+    let prefix = encode_key(prefix).map_err(ClientError::InvalidKey)?;
+    let mut start = [prefix.as_slice(), &[0]].concat();
+    let mut end = [prefix.as_slice(), &[0xff]].concat();
+    if let Some(Cursor(last)) = cursor {
+      if !last.starts_with(&prefix) {
+        return Err(ClientError::InvalidCursor);
+      }
+      if options.reverse {
+        end.clone_from(last);
+      } else {
+        start = [last.as_slice(), &[0]].concat();
+      }
+    }
+    let request = ReadRange {
+      start,
+      end,
+      limit: options.limit,
+      reverse: options.reverse,
+    };
+    let read_options = SnapshotReadOptions {
+      consistency: options.consistency,
+    };
+    let output = self
+      .remote
+      .snapshot_read(vec![request], read_options)
+      .await?
+      .pop()
+      .expect("one output per range");
+    let cursor = match output.entries.last() {
+      Some(last) if output.entries.len() == options.limit.get() as usize => {
+        Some(Cursor(last.key.clone()))
+      }
+      _ => None,
+    };
+    let entries = output
+      .entries
+      .into_iter()
+      .map(Entry::try_from)
+      .collect::<Result<_, _>>()?;
+    Ok(ListPage { entries, cursor })
+  }
+
+  /// Every entry whose key starts with `prefix`, read page by page. Pages
+  /// are separate snapshots.
+  pub fn list_all(
+    &self,
+    prefix: Key,
+    options: ListOptions,
+  ) -> impl Stream<Item = Result<Entry, ClientError>> + '_ {
+    try_stream! {
+      let mut cursor = None;
+      loop {
+        let page = self.list(&prefix, cursor.as_ref(), &options).await?;
+        for entry in page.entries {
+          yield entry;
+        }
+        match page.cursor {
+          Some(next) => cursor = Some(next),
+          None => break,
+        }
+      }
+    }
+  }
+
+  /// The number of keys starting with `prefix`. The server has no count
+  /// operation, so this reads every entry under the prefix.
+  pub async fn count(&self, prefix: &Key) -> Result<u64, ClientError> {
+    let options = ListOptions {
+      limit: NonZeroU32::new(COUNT_PAGE_SIZE).unwrap(),
+      ..Default::default()
+    };
+    let mut count = 0;
+    let entries = self.list_all(prefix.clone(), options);
+    futures::pin_mut!(entries);
+    while let Some(entry) = entries.next().await {
+      entry?;
+      count += 1;
+    }
+    Ok(count)
+  }
+
+  /// The current entries at `keys`, and again every time one of them
+  /// changes.
+  pub fn watch(
+    &self,
+    keys: &[Key],
+  ) -> Result<
+    impl Stream<Item = Result<Vec<Option<Entry>>, ClientError>>,
+    ClientError,
+  > {
+    let encoded = keys
+      .iter()
+      .map(encode_key)
+      .collect::<Result<_, _>>()
+      .map_err(ClientError::InvalidKey)?;
+    let mut current: Vec<Option<Entry>> = vec![None; keys.len()];
+    let stream = self.remote.watch(encoded).map(move |outputs| {
+      for (slot, output) in current.iter_mut().zip(outputs?) {
+        if let WatchKeyOutput::Changed { entry } = output {
+          *slot = entry.map(Entry::try_from).transpose()?;
+        }
+      }
+      Ok(current.clone())
+    });
+    Ok(stream)
+  }
+}
+
+fn strong_read() -> SnapshotReadOptions {
+  SnapshotReadOptions {
+    consistency: Consistency::Strong,
+  }
+}
@@ -0,0 +1,278 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! Values computed on a miss and cached in the database, with single-flight
+//! deduplication.
+//!
+//! Callers of one [`ComputeCache`] in a process that miss the same key at
+//! the same time share one computation: the first computes, and the others
+//! wait for its result. Results are written with a check that the key is
+//! still missing, so when processes race on a key the value stored first
+//! wins and is what all of them return.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use denokv_proto::encode_key;
+use denokv_proto::AtomicWrite;
+use denokv_proto::Check;
+use denokv_proto::Database;
+use denokv_proto::Key;
+use denokv_proto::KvValue;
+use denokv_proto::Mutation;
+use denokv_proto::MutationKind;
+use futures::future::BoxFuture;
+use futures::future::Shared;
+use futures::FutureExt;
+use tokio::sync::oneshot;
+
+use crate::time::utc_now;
+use crate::ClientError;
+use crate::KvClient;
+use crate::RemotePermissions;
+use crate::RemoteTransport;
+
+/// The result of a computation in flight, shared by every caller waiting
+/// for it: the value, why computing it failed, or `None` if the computing
+/// caller gave up, in which case the others try again themselves.
+type Flight = Shared<BoxFuture<'static, Option<Result<KvValue, String>>>>;
+
+type InFlight = Arc<Mutex<HashMap<Vec<u8>, Flight>>>;
+
+/// Made by [`KvClient::compute_cache`]. Clones share their computations in
+/// flight.
+#[derive(Clone)]
+pub struct ComputeCache<P: RemotePermissions, T: RemoteTransport> {
+  client: KvClient<P, T>,
+  ttl: Option<Duration>,
+  in_flight: InFlight,
+}
+
+impl<P: RemotePermissions, T: RemoteTransport> KvClient<P, T> {
+  /// A cache of computed values, which expire `ttl` after they were
+  /// computed if given.
+  pub fn compute_cache(&self, ttl: Option<Duration>) -> ComputeCache<P, T> {
+    ComputeCache {
+      client: self.clone(),
+      ttl,
+      in_flight: Default::default(),
+    }
+  }
+}
+
+impl<P: RemotePermissions, T: RemoteTransport> ComputeCache<P, T> {
+  /// The value of `key`, computed by `compute` and stored if it is missing.
+  pub async fn get_or_compute<F, Fut, E>(
+    &self,
+    key: &Key,
+    compute: F,
+  ) -> Result<KvValue, ClientError>
+  where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<KvValue, E>>,
+    E: Display,
+  {
+    let mut compute = Some(compute);
+    let mut values = self
+      .get_or_compute_many(std::slice::from_ref(key), |_| {
+        let compute = compute.take().expect("one key is computed once");
+        compute().map(|value| value.map(|value| vec![value]))
+      })
+      .await?;
+    Ok(values.pop().expect("one value per key"))
+  }
+
+  /// The values of `keys`, read from one snapshot. The missing ones that
+  /// are not being computed already are computed by one call of `compute`,
+  /// which gets them in order and returns their values in the same order.
+  /// `compute` is called again if a computation this call waited for was
+  /// given up.
+  pub async fn get_or_compute_many<F, Fut, E>(
+    &self,
+    keys: &[Key],
+    mut compute: F,
+  ) -> Result<Vec<KvValue>, ClientError>
+  where
+    F: FnMut(Vec<Key>) -> Fut,
+    Fut: Future<Output = Result<Vec<KvValue>, E>>,
+    E: Display,
+  {
+    let encoded = keys
+      .iter()
+      .map(|key| encode_key(key).map_err(ClientError::InvalidKey))
+      .collect::<Result<Vec<_>, _>>()?;
+    let mut values: Vec<Option<KvValue>> = vec![None; keys.len()];
+    loop {
+      let pending: Vec<usize> =
+        (0..keys.len()).filter(|&i| values[i].is_none()).collect();
+      if pending.is_empty() {
+        return Ok(values.into_iter().flatten().collect());
+      }
+      let pending_keys: Vec<Key> =
+        pending.iter().map(|&i| keys[i].clone()).collect();
+      let stored = self.client.get_many(&pending_keys).await?;
+      for (&i, entry) in pending.iter().zip(stored) {
+        values[i] = entry.map(|entry| entry.value);
+      }
+
+      // Lead the computation of missing keys nobody else is computing, and
+      // wait for the others.
+      let mut leading = Leading {
+        in_flight: &self.in_flight,
+        keys: vec![],
+        senders: vec![],
+        indices: vec![],
+      };
+      let mut waiting = vec![];
+      {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        for i in (0..keys.len()).filter(|&i| values[i].is_none()) {
+          match leading.keys.iter().position(|key| *key == encoded[i]) {
+            Some(led) => leading.indices[led].push(i),
+            None => match in_flight.get(&encoded[i]) {
+              Some(flight) => waiting.push((i, flight.clone())),
+              None => {
+                let (sender, receiver) = oneshot::channel();
+                let flight = receiver.map(Result::ok).map(Option::flatten);
+                in_flight.insert(encoded[i].clone(), flight.boxed().shared());
+                leading.keys.push(encoded[i].clone());
+                leading.senders.push(sender);
+                leading.indices.push(vec![i]);
+              }
+            },
+          }
+        }
+      }
+
+      if !leading.keys.is_empty() {
+        let led_keys: Vec<Key> = leading
+          .indices
+          .iter()
+          .map(|indices| keys[indices[0]].clone())
+          .collect();
+        let computed = match compute(led_keys.clone()).await {
+          Ok(computed) => computed,
+          Err(e) => {
+            let message = e.to_string();
+            leading.finish(|_| Err(message.clone()));
+            return Err(ClientError::Compute(message));
+          }
+        };
+        if computed.len() != led_keys.len() {
+          return Err(ClientError::InvalidValue(format!(
+            "computed {} values for {} keys",
+            computed.len(),
+            led_keys.len()
+          )));
+        }
+        let computed = self.store(&led_keys, computed).await?;
+        for (indices, value) in leading.indices.iter().zip(&computed) {
+          for &i in indices {
+            values[i] = Some(value.clone());
+          }
+        }
+        leading.finish(|led| Ok(computed[led].clone()));
+      }
+      drop(leading);
+
+      for (i, flight) in waiting {
+        match flight.await {
+          Some(Ok(value)) => values[i] = Some(value),
+          Some(Err(message)) => return Err(ClientError::Compute(message)),
+          // Read and compute again.
+          None => {}
+        }
+      }
+    }
+  }
+
+  /// Store `values` at the keys that are still missing, and return what
+  /// ends up stored at each key.
+  async fn store(
+    &self,
+    keys: &[Key],
+    mut values: Vec<KvValue>,
+  ) -> Result<Vec<KvValue>, ClientError> {
+    let expire_at = match self.ttl {
+      Some(ttl) => Some(
+        utc_now()
+          + chrono::Duration::from_std(ttl)
+            .map_err(|e| ClientError::InvalidValue(e.to_string()))?,
+      ),
+      None => None,
+    };
+    let mut unwritten: Vec<usize> = (0..keys.len()).collect();
+    loop {
+      let mut checks = vec![];
+      let mut mutations = vec![];
+      for &i in &unwritten {
+        let key = encode_key(&keys[i]).map_err(ClientError::InvalidKey)?;
+        checks.push(Check {
+          key: key.clone(),
+          versionstamp: None,
+        });
+        mutations.push(Mutation {
+          key,
+          kind: MutationKind::Set(values[i].clone()),
+          expire_at,
+        });
+      }
+      let write = AtomicWrite {
+        checks,
+        mutations,
+        enqueues: vec![],
+      };
+      if self.client.remote().atomic_write(write).await?.is_some() {
+        return Ok(values);
+      }
+
+      // Another process stored some of the keys first; its values win.
+      let unwritten_keys: Vec<Key> =
+        unwritten.iter().map(|&i| keys[i].clone()).collect();
+      let stored = self.client.get_many(&unwritten_keys).await?;
+      let mut still_missing = vec![];
+      for (i, entry) in unwritten.into_iter().zip(stored) {
+        match entry {
+          Some(entry) => values[i] = entry.value,
+          None => still_missing.push(i),
+        }
+      }
+      if still_missing.is_empty() {
+        return Ok(values);
+      }
+      unwritten = still_missing;
+    }
+  }
+}
+
+/// The computations led by one call. Dropping it, including when the call
+/// is cancelled, takes them out of flight, and callers waiting for any that
+/// did not finish try again.
+struct Leading<'a> {
+  in_flight: &'a Mutex<HashMap<Vec<u8>, Flight>>,
+  keys: Vec<Vec<u8>>,
+  senders: Vec<oneshot::Sender<Option<Result<KvValue, String>>>>,
+  /// For each led key, where it is in the keys of the call.
+  indices: Vec<Vec<usize>>,
+}
+
+impl Leading<'_> {
+  /// Hand the result of each led key, by position, to whoever waits for it.
+  fn finish(&mut self, result: impl Fn(usize) -> Result<KvValue, String>) {
+    for (led, sender) in self.senders.drain(..).enumerate() {
+      let _ = sender.send(Some(result(led)));
+    }
+  }
+}
+
+impl Drop for Leading<'_> {
+  fn drop(&mut self) {
+    let mut in_flight = self.in_flight.lock().unwrap();
+    for key in &self.keys {
+      in_flight.remove(key);
+    }
+  }
+}
@@ -0,0 +1,122 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! Counters kept with sum mutations.
+//!
+//! A [`Counter`] is a `KvU64` at one key, so `Deno.openKv` clients can read
+//! and add to it too. Every increment of a counter writes the same row,
+//! which serializes them on the database. A sharded counter spreads
+//! increments over several child keys of its key instead, picked at random,
+//! and adds them up on read.
+
+use std::num::NonZeroU32;
+
+use denokv_proto::Key;
+use denokv_proto::KeyPart;
+use denokv_proto::KvValue;
+use denokv_proto::MutationKind;
+use denokv_proto::Versionstamp;
+use rand::Rng;
+
+use crate::ClientError;
+use crate::Entry;
+use crate::KvClient;
+use crate::ListOptions;
+use crate::RemotePermissions;
+use crate::RemoteTransport;
+
+/// A counter stored at a key. Made by [`KvClient::counter`] and
+/// [`KvClient::sharded_counter`].
+///
+/// Counts are unsigned 64-bit integers that wrap around, like sums of
+/// `KvU64` values: decrementing a counter below zero wraps it to the top of
+/// the range.
+#[derive(Clone)]
+pub struct Counter<P: RemotePermissions, T: RemoteTransport> {
+  client: KvClient<P, T>,
+  key: Key,
+  shards: Option<NonZeroU32>,
+}
+
+impl<P: RemotePermissions, T: RemoteTransport> KvClient<P, T> {
+  /// The counter at `key`.
+  pub fn counter(&self, key: Key) -> Counter<P, T> {
+    Counter {
+      client: self.clone(),
+      key,
+      shards: None,
+    }
+  }
+
+  /// The counter at `key`, spread over `shards` child keys `[...key, 0]` to
+  /// `[...key, shards - 1]`. The number of shards can grow later, but not
+  /// shrink, as the count in the dropped shards would be lost.
+  pub fn sharded_counter(&self, key: Key, shards: NonZeroU32) -> Counter<P, T> {
+    Counter {
+      client: self.clone(),
+      key,
+      shards: Some(shards),
+    }
+  }
+}
+
+impl<P: RemotePermissions, T: RemoteTransport> Counter<P, T> {
+  pub fn key(&self) -> &Key {
+    &self.key
+  }
+
+  pub async fn increment(&self, by: u64) -> Result<Versionstamp, ClientError> {
+    self.add(by).await
+  }
+
+  pub async fn decrement(&self, by: u64) -> Result<Versionstamp, ClientError> {
+    self.add(by.wrapping_neg()).await
+  }
+
+  /// The current count, which is zero if the counter was never written.
+  pub async fn read(&self) -> Result<u64, ClientError> {
+    let Some(shards) = self.shards else {
+      return match self.client.get(&self.key).await? {
+        Some(entry) => count_of(&entry),
+        None => Ok(0),
+      };
+    };
+    // The shards are the only children of the key, so one page of them is
+    // read from one snapshot.
+    let options = ListOptions {
+      limit: shards,
+      ..Default::default()
+    };
+    let page = self.client.list(&self.key, None, &options).await?;
+    page.entries.iter().try_fold(0u64, |count, entry| {
+      Ok(count.wrapping_add(count_of(entry)?))
+    })
+  }
+
+  async fn add(&self, operand: u64) -> Result<Versionstamp, ClientError> {
+    let key = match self.shards {
+      None => self.key.clone(),
+      Some(shards) => {
+        let shard = rand::thread_rng().gen_range(0..shards.get());
+        let mut key = self.key.clone();
+        key.0.push(KeyPart::Int(shard.into()));
+        key
+      }
+    };
+    let kind = MutationKind::Sum {
+      value: KvValue::U64(operand),
+      min_v8: vec![],
+      max_v8: vec![],
+      clamp: false,
+    };
+    self.client.mutate(&key, kind).await
+  }
+}
+
+fn count_of(entry: &Entry) -> Result<u64, ClientError> {
+  match entry.value {
+    KvValue::U64(count) => Ok(count),
+    _ => Err(ClientError::InvalidValue(
+      "expected a KvU64 counter".to_string(),
+    )),
+  }
+}
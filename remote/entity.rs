@@ -0,0 +1,222 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! Typed entities on top of [`KvClient`], with the `entity` feature.
+//!
+//! A struct that derives [`KvEntity`](macro@crate::KvEntity) is stored
+//! under a key built from its fields, with its value encoded by serde.
+//! [`KvClient::load`] returns it together with the versionstamp it was read
+//! at, and [`KvClient::update_if_unchanged`] writes it back only if nobody
+//! changed it in between, with a check on that versionstamp.
+
+use denokv_proto::encode_key;
+use denokv_proto::v8;
+use denokv_proto::AtomicWrite;
+use denokv_proto::Check;
+use denokv_proto::Database;
+use denokv_proto::Key;
+use denokv_proto::KeyPart;
+use denokv_proto::KvValue;
+use denokv_proto::Mutation;
+use denokv_proto::MutationKind;
+use denokv_proto::Versionstamp;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::ClientError;
+use crate::KvClient;
+use crate::RemotePermissions;
+use crate::RemoteTransport;
+
+/// A struct stored under a key of its own. Derive it with
+/// `#[derive(KvEntity)]` rather than implementing it.
+pub trait KvEntity: Serialize + DeserializeOwned {
+  /// How values are encoded.
+  const CODEC: Codec = Codec::V8;
+
+  /// The constant start of the key pattern, which all entities of the type
+  /// share, to list them by.
+  fn prefix() -> Key;
+
+  /// The key the entity is stored under.
+  fn key(&self) -> Key;
+}
+
+/// The encoding of entity values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+  /// The V8 serialization format, so that `Deno.openKv` clients can read
+  /// and write the entities too.
+  V8,
+  /// JSON, stored as bytes.
+  Json,
+}
+
+impl Codec {
+  pub fn encode<E: Serialize>(self, value: &E) -> Result<KvValue, ClientError> {
+    match self {
+      Codec::V8 => v8::to_vec(value)
+        .map(KvValue::V8)
+        .map_err(|e| ClientError::InvalidValue(e.to_string())),
+      Codec::Json => serde_json::to_vec(value)
+        .map(KvValue::Bytes)
+        .map_err(|e| ClientError::InvalidValue(e.to_string())),
+    }
+  }
+
+  pub fn decode<E: DeserializeOwned>(
+    self,
+    value: &KvValue,
+  ) -> Result<E, ClientError> {
+    let result = match (self, value) {
+      (Codec::V8, KvValue::V8(bytes)) => {
+        v8::from_slice(bytes).map_err(|e| e.to_string())
+      }
+      (Codec::Json, KvValue::Bytes(bytes)) => {
+        serde_json::from_slice(bytes).map_err(|e| e.to_string())
+      }
+      _ => Err(format!("expected a value encoded as {self:?}")),
+    };
+    result.map_err(ClientError::InvalidValue)
+  }
+}
+
+/// A value that can be a field of an entity key.
+pub trait ToKeyPart {
+  fn to_key_part(&self) -> KeyPart;
+}
+
+impl<T: ToKeyPart + ?Sized> ToKeyPart for &T {
+  fn to_key_part(&self) -> KeyPart {
+    (**self).to_key_part()
+  }
+}
+
+impl ToKeyPart for str {
+  fn to_key_part(&self) -> KeyPart {
+    KeyPart::String(self.to_string())
+  }
+}
+
+impl ToKeyPart for String {
+  fn to_key_part(&self) -> KeyPart {
+    KeyPart::String(self.clone())
+  }
+}
+
+impl ToKeyPart for uuid::Uuid {
+  fn to_key_part(&self) -> KeyPart {
+    KeyPart::String(self.to_string())
+  }
+}
+
+impl ToKeyPart for Vec<u8> {
+  fn to_key_part(&self) -> KeyPart {
+    KeyPart::Bytes(self.clone())
+  }
+}
+
+impl ToKeyPart for bool {
+  fn to_key_part(&self) -> KeyPart {
+    if *self {
+      KeyPart::True
+    } else {
+      KeyPart::False
+    }
+  }
+}
+
+impl ToKeyPart for f64 {
+  fn to_key_part(&self) -> KeyPart {
+    KeyPart::Float(*self)
+  }
+}
+
+macro_rules! int_key_part {
+  ($($ty:ty),*) => {
+    $(
+      impl ToKeyPart for $ty {
+        fn to_key_part(&self) -> KeyPart {
+          KeyPart::Int((*self).into())
+        }
+      }
+    )*
+  };
+}
+
+int_key_part!(i8, i16, i32, i64, i128, u8, u16, u32, u64, u128, usize);
+
+/// An entity with the versionstamp it was read or written at.
+#[derive(Clone, Debug)]
+pub struct Versioned<E> {
+  pub value: E,
+  pub versionstamp: Versionstamp,
+}
+
+impl<P: RemotePermissions, T: RemoteTransport> KvClient<P, T> {
+  /// The entity stored at `key`, usually made with the `key_of` function
+  /// the derive adds.
+  pub async fn load<E: KvEntity>(
+    &self,
+    key: &Key,
+  ) -> Result<Option<Versioned<E>>, ClientError> {
+    let Some(entry) = self.get(key).await? else {
+      return Ok(None);
+    };
+    Ok(Some(Versioned {
+      value: E::CODEC.decode(&entry.value)?,
+      versionstamp: entry.versionstamp,
+    }))
+  }
+
+  /// Store `entity`, replacing whatever is stored at its key.
+  pub async fn save<E: KvEntity>(
+    &self,
+    entity: &E,
+  ) -> Result<Versionstamp, ClientError> {
+    self.set(&entity.key(), E::CODEC.encode(entity)?).await
+  }
+
+  /// Store `entity` if nothing is stored at its key yet. Returns `None`
+  /// if something is.
+  pub async fn create<E: KvEntity>(
+    &self,
+    entity: &E,
+  ) -> Result<Option<Versionstamp>, ClientError> {
+    self.write_checked(entity, None).await
+  }
+
+  /// Store `entity.value` if its key still has `entity.versionstamp`, that
+  /// is, if nobody changed it since it was loaded. Returns `None` if
+  /// somebody did, in which case the entity should be loaded again. The
+  /// key fields must not have changed.
+  pub async fn update_if_unchanged<E: KvEntity>(
+    &self,
+    entity: &Versioned<E>,
+  ) -> Result<Option<Versionstamp>, ClientError> {
+    self
+      .write_checked(&entity.value, Some(entity.versionstamp))
+      .await
+  }
+
+  async fn write_checked<E: KvEntity>(
+    &self,
+    entity: &E,
+    versionstamp: Option<Versionstamp>,
+  ) -> Result<Option<Versionstamp>, ClientError> {
+    let key = encode_key(&entity.key()).map_err(ClientError::InvalidKey)?;
+    let write = AtomicWrite {
+      checks: vec![Check {
+        key: key.clone(),
+        versionstamp,
+      }],
+      mutations: vec![Mutation {
+        key,
+        kind: MutationKind::Set(E::CODEC.encode(entity)?),
+        expire_at: None,
+      }],
+      enqueues: vec![],
+    };
+    let result = self.remote().atomic_write(write).await?;
+    Ok(result.map(|result| result.versionstamp))
+  }
+}
@@ -0,0 +1,297 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! Feature flags kept in the database and cached in the process.
+//!
+//! Flag definitions are stored as JSON at `["__flags", "defs", name]`.
+//! Every change made through [`Flags`] also adds to the counter at
+//! `["__flags", "version"]` in the same atomic write, and each [`Flags`]
+//! watches that key to reload all definitions when it changes, so flags
+//! changed in one process take effect in the others within a watch round
+//! trip. Evaluating a flag only reads the cache.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use denokv_proto::encode_key;
+use denokv_proto::AtomicWrite;
+use denokv_proto::Database;
+use denokv_proto::Key;
+use denokv_proto::KeyPart;
+use denokv_proto::KvValue;
+use denokv_proto::Mutation;
+use denokv_proto::MutationKind;
+use futures::StreamExt;
+use futures::TryStreamExt;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::runtime;
+use crate::runtime::JoinHandle;
+use crate::ClientError;
+use crate::KvClient;
+use crate::ListOptions;
+use crate::RemotePermissions;
+use crate::RemoteTransport;
+
+/// The first part of every key of the flags.
+const FLAGS_PREFIX: &str = "__flags";
+
+/// How long the refresh task waits before watching again after its watch
+/// failed.
+const WATCH_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// The definition of a feature flag.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Flag {
+  /// Whether the flag is on for anyone.
+  pub enabled: bool,
+  /// Subjects the flag is always on for while it is enabled.
+  #[serde(default)]
+  pub subjects: Vec<String>,
+  /// The percentage of other subjects the flag is on for, from 0 to 100.
+  /// Which subjects those are depends only on the names of the flag and
+  /// the subject, so it is the same in every process.
+  #[serde(default = "full_rollout")]
+  pub rollout: u8,
+  /// What [`Flags::value`] returns for subjects the flag is on for.
+  #[serde(default)]
+  pub value: Option<serde_json::Value>,
+}
+
+fn full_rollout() -> u8 {
+  100
+}
+
+impl Flag {
+  /// A flag that is on for everyone.
+  pub fn on() -> Self {
+    Self {
+      enabled: true,
+      subjects: vec![],
+      rollout: 100,
+      value: None,
+    }
+  }
+
+  /// Whether the flag `name`, defined by `self`, is on for `subject`.
+  /// Without a subject only a full rollout counts.
+  fn is_on(&self, name: &str, subject: Option<&str>) -> bool {
+    if !self.enabled {
+      return false;
+    }
+    match subject {
+      Some(subject) if self.subjects.iter().any(|s| s == subject) => true,
+      Some(subject) => bucket(name, subject) < u64::from(self.rollout),
+      None => self.rollout >= 100,
+    }
+  }
+}
+
+/// Which of 100 buckets `subject` falls into for the flag `name`, by the
+/// 64-bit FNV-1a hash of both, which is stable across processes and
+/// versions.
+fn bucket(name: &str, subject: &str) -> u64 {
+  let mut hash: u64 = 0xcbf29ce484222325;
+  for byte in name.bytes().chain([0]).chain(subject.bytes()) {
+    hash ^= u64::from(byte);
+    hash = hash.wrapping_mul(0x100000001b3);
+  }
+  hash % 100
+}
+
+/// The feature flags of a database, cached and kept up to date by a
+/// background task. Made by [`KvClient::flags`]; the task stops once every
+/// clone is dropped.
+#[derive(Clone)]
+pub struct Flags<P: RemotePermissions, T: RemoteTransport> {
+  client: KvClient<P, T>,
+  cache: Arc<RwLock<HashMap<String, Flag>>>,
+  _refresh: Arc<RefreshTask>,
+}
+
+struct RefreshTask(JoinHandle<()>);
+
+impl Drop for RefreshTask {
+  fn drop(&mut self) {
+    self.0.abort();
+  }
+}
+
+impl<P: RemotePermissions, T: RemoteTransport> KvClient<P, T> {
+  /// Load the feature flags, and keep them up to date until the returned
+  /// [`Flags`] is dropped.
+  pub async fn flags(&self) -> Result<Flags<P, T>, ClientError> {
+    let cache = Arc::new(RwLock::new(load(self).await?));
+    let task = runtime::spawn(refresh(self.clone(), cache.clone()));
+    Ok(Flags {
+      client: self.clone(),
+      cache,
+      _refresh: Arc::new(RefreshTask(task)),
+    })
+  }
+}
+
+impl<P: RemotePermissions, T: RemoteTransport> Flags<P, T> {
+  /// Whether the flag `name` is on for `subject`, such as a user id. Flags
+  /// that are not defined are off.
+  pub fn is_enabled(&self, name: &str, subject: Option<&str>) -> bool {
+    let cache = self.cache.read().unwrap();
+    cache
+      .get(name)
+      .is_some_and(|flag| flag.is_on(name, subject))
+  }
+
+  /// The value of the flag `name` for `subject`: [`Flag::value`] if the flag
+  /// is on for it, and `None` if it is off or has no value.
+  pub fn value<V: DeserializeOwned>(
+    &self,
+    name: &str,
+    subject: Option<&str>,
+  ) -> Result<Option<V>, ClientError> {
+    let cache = self.cache.read().unwrap();
+    let Some(flag) = cache.get(name) else {
+      return Ok(None);
+    };
+    match &flag.value {
+      Some(value) if flag.is_on(name, subject) => {
+        serde_json::from_value(value.clone())
+          .map(Some)
+          .map_err(|e| ClientError::InvalidValue(e.to_string()))
+      }
+      _ => Ok(None),
+    }
+  }
+
+  /// The definition of the flag `name`, as cached.
+  pub fn get(&self, name: &str) -> Option<Flag> {
+    self.cache.read().unwrap().get(name).cloned()
+  }
+
+  /// Define the flag `name`, replacing any previous definition.
+  pub async fn set(&self, name: &str, flag: Flag) -> Result<(), ClientError> {
+    let value = serde_json::to_vec(&flag)
+      .map_err(|e| ClientError::InvalidValue(e.to_string()))?;
+    self
+      .write(name, MutationKind::Set(KvValue::Bytes(value)))
+      .await?;
+    self.cache.write().unwrap().insert(name.to_string(), flag);
+    Ok(())
+  }
+
+  /// Remove the flag `name`, which turns it off.
+  pub async fn delete(&self, name: &str) -> Result<(), ClientError> {
+    self.write(name, MutationKind::Delete).await?;
+    self.cache.write().unwrap().remove(name);
+    Ok(())
+  }
+
+  /// Read all definitions again now, rather than on the next change.
+  pub async fn reload(&self) -> Result<(), ClientError> {
+    let flags = load(&self.client).await?;
+    *self.cache.write().unwrap() = flags;
+    Ok(())
+  }
+
+  async fn write(
+    &self,
+    name: &str,
+    kind: MutationKind,
+  ) -> Result<(), ClientError> {
+    let mut key = definitions_prefix();
+    key.0.push(KeyPart::String(name.to_string()));
+    let write = AtomicWrite {
+      checks: vec![],
+      mutations: vec![
+        Mutation {
+          key: encode_key(&key).map_err(ClientError::InvalidKey)?,
+          kind,
+          expire_at: None,
+        },
+        Mutation {
+          key: encode_key(&version_key()).map_err(ClientError::InvalidKey)?,
+          kind: MutationKind::Sum {
+            value: KvValue::U64(1),
+            min_v8: vec![],
+            max_v8: vec![],
+            clamp: false,
+          },
+          expire_at: None,
+        },
+      ],
+      enqueues: vec![],
+    };
+    self.client.remote().atomic_write(write).await?;
+    Ok(())
+  }
+}
+
+fn definitions_prefix() -> Key {
+  Key(vec![
+    KeyPart::String(FLAGS_PREFIX.to_string()),
+    KeyPart::String("defs".to_string()),
+  ])
+}
+
+fn version_key() -> Key {
+  Key(vec![
+    KeyPart::String(FLAGS_PREFIX.to_string()),
+    KeyPart::String("version".to_string()),
+  ])
+}
+
+/// Read every flag definition.
+async fn load<P: RemotePermissions, T: RemoteTransport>(
+  client: &KvClient<P, T>,
+) -> Result<HashMap<String, Flag>, ClientError> {
+  let entries = client.list_all(definitions_prefix(), ListOptions::default());
+  entries
+    .map(|entry| {
+      let entry = entry?;
+      let (Some(KeyPart::String(name)), KvValue::Bytes(bytes)) =
+        (entry.key.0.last(), &entry.value)
+      else {
+        return Err(ClientError::InvalidValue(
+          "expected a flag definition".to_string(),
+        ));
+      };
+      let flag = serde_json::from_slice(bytes)
+        .map_err(|e| ClientError::InvalidValue(e.to_string()))?;
+      Ok((name.clone(), flag))
+    })
+    .try_collect()
+    .await
+}
+
+/// Reload `cache` every time the flags change, until the task is aborted.
+async fn refresh<P: RemotePermissions, T: RemoteTransport>(
+  client: KvClient<P, T>,
+  cache: Arc<RwLock<HashMap<String, Flag>>>,
+) {
+  loop {
+    let changes = match client.watch(&[version_key()]) {
+      Ok(changes) => changes,
+      Err(e) => {
+        log::warn!("Failed to watch the feature flags: {e}");
+        return;
+      }
+    };
+    futures::pin_mut!(changes);
+    while let Some(change) = changes.next().await {
+      let result = match change {
+        Ok(_) => load(&client).await,
+        Err(e) => Err(e),
+      };
+      match result {
+        Ok(flags) => *cache.write().unwrap() = flags,
+        Err(e) => {
+          log::warn!("Failed to reload the feature flags: {e}");
+          break;
+        }
+      }
+    }
+    runtime::sleep(WATCH_RETRY_DELAY).await;
+  }
+}
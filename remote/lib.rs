@@ -1,10 +1,27 @@
 // Copyright 2023 the Deno authors. All rights reserved. MIT license.
 
+mod client;
+mod compute_cache;
+mod counter;
+#[cfg(feature = "entity")]
+mod entity;
+mod flags;
+#[cfg(feature = "reqwest")]
+mod reqwest_transport;
+mod runtime;
+mod session;
+#[cfg(feature = "tower")]
+mod session_layer;
+mod sorted_set;
 mod time;
+#[cfg(feature = "websocket")]
+mod websocket;
+
+#[cfg(all(feature = "websocket", target_arch = "wasm32"))]
+compile_error!("the `websocket` feature is not available on wasm32");
 
 use std::io;
 use std::ops::Sub;
-use std::pin::pin;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::time::Duration;
@@ -22,6 +39,7 @@ use denokv_proto::CommitResult;
 use denokv_proto::Consistency;
 use denokv_proto::Database;
 use denokv_proto::DatabaseMetadata;
+use denokv_proto::Feature;
 use denokv_proto::KvEntry;
 use denokv_proto::KvValue;
 use denokv_proto::MetadataExchangeRequest;
@@ -30,6 +48,8 @@ use denokv_proto::ReadRange;
 use denokv_proto::ReadRangeOutput;
 use denokv_proto::SnapshotReadOptions;
 use denokv_proto::WatchKeyOutput;
+use denokv_proto::WatchOutputDecoder;
+use denokv_proto::FEATURES_HEADER;
 use futures::Future;
 use futures::Stream;
 use futures::StreamExt;
@@ -42,11 +62,11 @@ use log::error;
 use log::warn;
 use prost::Message;
 use rand::Rng;
+use runtime::JoinHandle;
 use serde::Deserialize;
 use thiserror::Error;
 use time::utc_now;
 use tokio::sync::watch;
-use tokio::task::JoinHandle;
 use tokio_util::codec::LengthDelimitedCodec;
 use tokio_util::io::StreamReader;
 use url::Url;
@@ -54,6 +74,47 @@ use uuid::Uuid;
 
 use denokv_proto::datapath as pb;
 
+pub use crate::client::ClientError;
+pub use crate::client::Cursor;
+pub use crate::client::Entry;
+pub use crate::client::KvClient;
+pub use crate::client::ListOptions;
+pub use crate::client::ListPage;
+pub use crate::compute_cache::ComputeCache;
+pub use crate::counter::Counter;
+#[cfg(feature = "entity")]
+pub use crate::entity::Codec;
+#[cfg(feature = "entity")]
+pub use crate::entity::KvEntity;
+#[cfg(feature = "entity")]
+pub use crate::entity::ToKeyPart;
+#[cfg(feature = "entity")]
+pub use crate::entity::Versioned;
+pub use crate::flags::Flag;
+pub use crate::flags::Flags;
+#[cfg(feature = "reqwest")]
+pub use crate::reqwest_transport::AllowAllPermissions;
+#[cfg(feature = "reqwest")]
+pub use crate::reqwest_transport::ReqwestTransport;
+pub use crate::session::expired_session_cookie;
+pub use crate::session::Session;
+pub use crate::session::SessionStore;
+#[cfg(feature = "tower")]
+pub use crate::session_layer::SessionLayer;
+#[cfg(feature = "tower")]
+pub use crate::session_layer::SessionService;
+pub use crate::sorted_set::SortedSet;
+#[cfg(feature = "entity")]
+pub use denokv_derive::KvEntity;
+
+/// Paths used by `#[derive(KvEntity)]`.
+#[cfg(feature = "entity")]
+#[doc(hidden)]
+pub mod __private {
+  pub use denokv_proto::Key;
+  pub use denokv_proto::KeyPart;
+}
+
 const DATAPATH_BACKOFF_BASE: Duration = Duration::from_millis(200);
 const METADATA_BACKOFF_BASE: Duration = Duration::from_secs(5);
 
@@ -98,6 +159,8 @@ struct Metadata {
   endpoints: Vec<DataPathEndpoint>,
   token: String,
   expires_at: DateTime<Utc>,
+  /// Protocol extensions the server agreed to.
+  features: Vec<Feature>,
 }
 
 impl Metadata {
@@ -127,6 +190,12 @@ impl Metadata {
           self.database_id.to_string().try_into().unwrap(),
         );
         headers.insert("x-denokv-version", HeaderValue::from_static("3"));
+        if !self.features.is_empty() {
+          let features: Vec<_> =
+            self.features.iter().map(|feature| feature.name()).collect();
+          headers
+            .insert(FEATURES_HEADER, features.join(",").try_into().unwrap());
+        }
       }
     };
     headers
@@ -222,12 +291,17 @@ pub enum CallDataError {
   Decode(#[source] prost::DecodeError),
 }
 
+/// Watch output frames, with empty frames for pings.
+type WatchFrames = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
 #[derive(Clone)]
 pub struct Remote<P: RemotePermissions, T: RemoteTransport> {
   permissions: P,
   client: T,
-  metadata_refresher: Arc<JoinHandle<()>>,
+  _metadata_refresher: Arc<MetadataRefresher>,
   metadata: watch::Receiver<MetadataState>,
+  #[cfg(feature = "websocket")]
+  websocket_watch: bool,
 }
 
 impl<P: RemotePermissions, T: RemoteTransport> Remote<P, T> {
@@ -237,7 +311,7 @@ impl<P: RemotePermissions, T: RemoteTransport> Remote<P, T> {
     metadata_endpoint: MetadataEndpoint,
   ) -> Self {
     let (tx, rx) = watch::channel(MetadataState::Pending);
-    let metadata_refresher = tokio::spawn(metadata_refresh_task(
+    let metadata_refresher = runtime::spawn(metadata_refresh_task(
       client.clone(),
       metadata_endpoint,
       tx,
@@ -245,9 +319,89 @@ impl<P: RemotePermissions, T: RemoteTransport> Remote<P, T> {
     Self {
       client,
       permissions,
-      metadata_refresher: Arc::new(metadata_refresher),
+      _metadata_refresher: Arc::new(MetadataRefresher(metadata_refresher)),
       metadata: rx,
+      #[cfg(feature = "websocket")]
+      websocket_watch: false,
+    }
+  }
+
+  /// Open watches as WebSockets if the server supports it, for networks
+  /// that break long-lived HTTP responses. Watches fall back to streamed
+  /// responses if the WebSocket can not be opened.
+  #[cfg(feature = "websocket")]
+  pub fn with_websocket_watch(mut self) -> Self {
+    self.websocket_watch = true;
+    self
+  }
+
+  async fn current_metadata(&self) -> Result<Arc<Metadata>, CallRawError> {
+    loop {
+      let mut metadata_rx = self.metadata.clone();
+      match &*metadata_rx.borrow() {
+        MetadataState::Pending => {}
+        MetadataState::Ok(metadata) => return Ok(metadata.clone()),
+        MetadataState::Error(e) => {
+          return Err(CallRawError::Other(e.to_string()));
+        }
+      };
+      if metadata_rx.changed().await.is_err() {
+        return Err(CallRawError::DatabaseClosed);
+      }
+    }
+  }
+
+  /// The strong consistency data path URL.
+  fn strong_endpoint(metadata: &Metadata) -> Result<&Url, CallRawError> {
+    metadata
+      .endpoints
+      .iter()
+      .find(|endpoint| endpoint.consistency == DataPathConsistency::Strong)
+      .map(|endpoint| &endpoint.url)
+      .ok_or(CallRawError::MissingStrongConsistencyEndpoints)
+  }
+
+  /// The frames of a watch, from a WebSocket if enabled and granted,
+  /// otherwise from a streamed response.
+  async fn watch_frames(
+    &self,
+    req: pb::Watch,
+  ) -> Result<(WatchFrames, Option<Duration>), JsErrorBox> {
+    #[cfg(feature = "websocket")]
+    if self.websocket_watch {
+      let metadata = self
+        .current_metadata()
+        .await
+        .map_err(JsErrorBox::from_err)?;
+      if metadata.features.contains(&Feature::WebsocketWatch) {
+        let endpoint =
+          Self::strong_endpoint(&metadata).map_err(JsErrorBox::from_err)?;
+        self
+          .permissions
+          .check_net_url(endpoint)
+          .map_err(|e| JsErrorBox::from_err(CallRawError::Permission(e)))?;
+        match websocket::open_watch(endpoint, &metadata.headers(), &req).await {
+          Ok(frames) => {
+            let idle_timeout = Some(websocket::WEBSOCKET_IDLE_TIMEOUT);
+            return Ok((Box::pin(frames), idle_timeout));
+          }
+          Err(e) => {
+            debug!("KV Connect watch WebSocket failed, streaming instead: {e}")
+          }
+        }
+      }
     }
+
+    let (stream, _) = self.call_stream("watch", req).await?;
+    let reader = StreamReader::new(Box::pin(stream));
+    let codec = LengthDelimitedCodec::builder()
+      .little_endian()
+      .length_field_length(4)
+      .max_frame_length(16 * 1048576)
+      .new_codec();
+    let frames = tokio_util::codec::FramedRead::new(reader, codec)
+      .map_ok(|frame| frame.freeze());
+    Ok((Box::pin(frames), None))
   }
 
   async fn call_raw<Req: prost::Message>(
@@ -258,32 +412,10 @@ impl<P: RemotePermissions, T: RemoteTransport> Remote<P, T> {
     let attempt = 0;
     let req_body = Bytes::from(req.encode_to_vec());
     loop {
-      let metadata = loop {
-        let mut metadata_rx = self.metadata.clone();
-        match &*metadata_rx.borrow() {
-          MetadataState::Pending => {}
-          MetadataState::Ok(metadata) => break metadata.clone(),
-          MetadataState::Error(e) => {
-            return Err(CallRawError::Other(e.to_string()));
-          }
-        };
-        if metadata_rx.changed().await.is_err() {
-          return Err(CallRawError::DatabaseClosed);
-        }
-      };
-
-      let endpoint = match metadata
-        .endpoints
-        .iter()
-        .find(|endpoint| endpoint.consistency == DataPathConsistency::Strong)
-      {
-        Some(endpoint) => endpoint,
-        None => {
-          return Err(CallRawError::MissingStrongConsistencyEndpoints);
-        }
-      };
+      let metadata = self.current_metadata().await?;
+      let endpoint = Self::strong_endpoint(&metadata)?;
 
-      let url = Url::parse(&format!("{}/{}", endpoint.url, method))?;
+      let url = Url::parse(&format!("{}/{}", endpoint, method))?;
       self
         .permissions
         .check_net_url(&url)
@@ -365,11 +497,61 @@ impl<P: RemotePermissions, T: RemoteTransport> Remote<P, T> {
 
     Ok((resp, version))
   }
+
+  /// Send one snapshot read and check its status.
+  async fn snapshot_read_once(
+    &self,
+    ranges: Vec<pb::ReadRange>,
+    options: &SnapshotReadOptions,
+  ) -> Result<pb::SnapshotReadOutput, JsErrorBox> {
+    let req = pb::SnapshotRead { ranges };
+
+    let (res, version): (pb::SnapshotReadOutput, _) = self
+      .call_data("snapshot_read", req)
+      .await
+      .map_err(|e| JsErrorBox::from_err(SnapshotReadError::CallData(e)))?;
+
+    match version {
+      ProtocolVersion::V1 | ProtocolVersion::V2 => {
+        if res.read_disabled {
+          // TODO: this should result in a retry after a forced metadata refresh.
+          return Err(JsErrorBox::from_err(SnapshotReadError::ReadsDisabled));
+        }
+      }
+      ProtocolVersion::V3 => match res.status() {
+        pb::SnapshotReadStatus::SrSuccess => {}
+        pb::SnapshotReadStatus::SrReadDisabled => {
+          // TODO: this should result in a retry after a forced metadata refresh.
+          return Err(JsErrorBox::from_err(SnapshotReadError::ReadsDisabled));
+        }
+        pb::SnapshotReadStatus::SrUnspecified => {
+          return Err(JsErrorBox::from_err(
+            SnapshotReadError::UnspecifiedRead(res.status),
+          ));
+        }
+      },
+    }
+
+    if !res.read_is_strongly_consistent
+      && options.consistency == Consistency::Strong
+    {
+      // TODO: this should result in a retry after a forced metadata refresh.
+      return Err(JsErrorBox::from_err(
+        SnapshotReadError::StrongConsistencyReadsUnavailable,
+      ));
+    }
+
+    Ok(res)
+  }
 }
 
-impl<P: RemotePermissions, T: RemoteTransport> Drop for Remote<P, T> {
+/// The metadata refresh task of a [`Remote`], shared by its clones and
+/// stopped when the last one is dropped.
+struct MetadataRefresher(JoinHandle<()>);
+
+impl Drop for MetadataRefresher {
   fn drop(&mut self) {
-    self.metadata_refresher.abort();
+    self.0.abort();
   }
 }
 
@@ -377,7 +559,7 @@ async fn randomized_exponential_backoff(base: Duration, attempt: u64) {
   let attempt = attempt.min(12);
   let delay = base.as_millis() as u64 + (2 << attempt);
   let delay = delay + rand::thread_rng().gen_range(0..(delay / 2) + 1);
-  tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+  runtime::sleep(std::time::Duration::from_millis(delay)).await;
 }
 
 async fn metadata_refresh_task<T: RemoteTransport>(
@@ -406,7 +588,7 @@ async fn metadata_refresh_task<T: RemoteTransport>(
           .unwrap_or_default()
           .min(Duration::from_secs(60));
 
-        tokio::time::sleep(sleep_time).await;
+        runtime::sleep(sleep_time).await;
       }
       RetryableResult::Retry => {
         attempts += 1;
@@ -434,6 +616,13 @@ async fn fetch_metadata<T: RemoteTransport>(
 ) -> RetryableResult<Metadata, String> {
   let body = serde_json::to_vec(&MetadataExchangeRequest {
     supported_versions: vec![1, 2, 3],
+    features: Feature::ALL
+      .iter()
+      .filter(|feature| {
+        cfg!(feature = "websocket") || **feature != Feature::WebsocketWatch
+      })
+      .map(|feature| feature.name().to_string())
+      .collect(),
   })
   .unwrap();
   let res = match client
@@ -557,6 +746,11 @@ fn parse_metadata(base_url: &Url, body: &str) -> Result<Metadata, String> {
     database_id: metadata.database_id,
     token: metadata.token.into_owned(),
     expires_at: metadata.expires_at,
+    features: metadata
+      .features
+      .iter()
+      .filter_map(|name| Feature::from_name(name))
+      .collect(),
   })
 }
 
@@ -580,6 +774,9 @@ pub enum SnapshotReadError {
   #[class(generic)]
   #[error(transparent)]
   TryFromSlice(std::array::TryFromSliceError),
+  #[class(generic)]
+  #[error("Server returned a continuation without making progress")]
+  NoProgress,
 }
 
 #[derive(Debug, Error, JsError)]
@@ -616,6 +813,12 @@ pub enum WatchError {
   #[class(generic)]
   #[error(transparent)]
   TryFromSlice(std::array::TryFromSliceError),
+  #[class(generic)]
+  #[error("Watch value delta does not match the previous value")]
+  InvalidValueDelta,
+  #[class(generic)]
+  #[error("The server rejected the watch: {0}")]
+  Rejected(String),
 }
 
 #[async_trait]
@@ -627,7 +830,15 @@ impl<P: RemotePermissions, T: RemoteTransport> Database for Remote<P, T> {
     requests: Vec<ReadRange>,
     options: SnapshotReadOptions,
   ) -> Result<Vec<ReadRangeOutput>, JsErrorBox> {
-    let ranges = requests
+    let mut outputs: Vec<ReadRangeOutput> = requests
+      .iter()
+      .map(|_| ReadRangeOutput { entries: vec![] })
+      .collect();
+    // Ranges still to read, with their index in `requests`. A server with a
+    // response budget may cut ranges short; the rest of each such range is
+    // read with follow-up requests. Those are separate snapshots, so a range
+    // split this way is not read at a single point in time.
+    let mut pending: Vec<(usize, pb::ReadRange)> = requests
       .into_iter()
       .map(|r| pb::ReadRange {
         start: r.start,
@@ -635,69 +846,53 @@ impl<P: RemotePermissions, T: RemoteTransport> Database for Remote<P, T> {
         limit: r.limit.get() as _,
         reverse: r.reverse,
       })
+      .enumerate()
       .collect();
-    let req = pb::SnapshotRead { ranges };
-
-    let (res, version): (pb::SnapshotReadOutput, _) = self
-      .call_data("snapshot_read", req)
-      .await
-      .map_err(|e| JsErrorBox::from_err(SnapshotReadError::CallData(e)))?;
 
-    match version {
-      ProtocolVersion::V1 | ProtocolVersion::V2 => {
-        if res.read_disabled {
-          // TODO: this should result in a retry after a forced metadata refresh.
-          return Err(JsErrorBox::from_err(SnapshotReadError::ReadsDisabled));
+    while !pending.is_empty() {
+      let ranges = pending.iter().map(|(_, range)| range.clone()).collect();
+      let res = self.snapshot_read_once(ranges, &options).await?;
+
+      let mut next = Vec::new();
+      let mut progressed = false;
+      for ((index, mut range), output) in pending.into_iter().zip(res.ranges) {
+        let entries = output
+          .values
+          .into_iter()
+          .map(|e| {
+            Ok(KvEntry {
+              key: e.key,
+              value: decode_value(e.value, e.encoding as i64).ok_or_else(
+                || SnapshotReadError::UnknownEncoding(e.encoding),
+              )?,
+              versionstamp: <[u8; 10]>::try_from(&e.versionstamp[..])
+                .map_err(SnapshotReadError::TryFromSlice)?,
+            })
+          })
+          .collect::<Result<Vec<_>, SnapshotReadError>>()
+          .map_err(JsErrorBox::from_err)?;
+        progressed |= !entries.is_empty();
+        range.limit -= entries.len() as i32;
+        outputs[index].entries.extend(entries);
+
+        if let Some(continuation) = output.continuation {
+          if range.reverse {
+            range.end = continuation;
+          } else {
+            range.start = continuation;
+          }
+          if range.limit > 0 {
+            next.push((index, range));
+          }
         }
       }
-      ProtocolVersion::V3 => match res.status() {
-        pb::SnapshotReadStatus::SrSuccess => {}
-        pb::SnapshotReadStatus::SrReadDisabled => {
-          // TODO: this should result in a retry after a forced metadata refresh.
-          return Err(JsErrorBox::from_err(SnapshotReadError::ReadsDisabled));
-        }
-        pb::SnapshotReadStatus::SrUnspecified => {
-          return Err(JsErrorBox::from_err(
-            SnapshotReadError::UnspecifiedRead(res.status),
-          ));
-        }
-      },
-    }
-
-    if !res.read_is_strongly_consistent
-      && options.consistency == Consistency::Strong
-    {
-      // TODO: this should result in a retry after a forced metadata refresh.
-      return Err(JsErrorBox::from_err(
-        SnapshotReadError::StrongConsistencyReadsUnavailable,
-      ));
+      if !next.is_empty() && !progressed {
+        return Err(JsErrorBox::from_err(SnapshotReadError::NoProgress));
+      }
+      pending = next;
     }
 
-    let ranges = res
-      .ranges
-      .into_iter()
-      .map(|r| {
-        Ok(ReadRangeOutput {
-          entries: r
-            .values
-            .into_iter()
-            .map(|e| {
-              Ok(KvEntry {
-                key: e.key,
-                value: decode_value(e.value, e.encoding as i64).ok_or_else(
-                  || SnapshotReadError::UnknownEncoding(e.encoding),
-                )?,
-                versionstamp: <[u8; 10]>::try_from(&e.versionstamp[..])
-                  .map_err(SnapshotReadError::TryFromSlice)?,
-              })
-            })
-            .collect::<Result<_, SnapshotReadError>>()?,
-        })
-      })
-      .collect::<Result<_, SnapshotReadError>>()
-      .map_err(JsErrorBox::from_err)?;
-
-    Ok(ranges)
+    Ok(outputs)
   }
 
   async fn atomic_write(
@@ -718,6 +913,7 @@ impl<P: RemotePermissions, T: RemoteTransport> Database for Remote<P, T> {
     }
 
     let mut mutations = Vec::new();
+    let mut versionstamped = Vec::new();
     for mutation in write.mutations {
       let expire_at_ms = mutation
         .expire_at
@@ -784,6 +980,7 @@ impl<P: RemotePermissions, T: RemoteTransport> Database for Remote<P, T> {
           });
         }
         denokv_proto::MutationKind::SetSuffixVersionstampedKey(value) => {
+          versionstamped.push(mutation.key.clone());
           mutations.push(pb::Mutation {
             key: mutation.key,
             value: Some(encode_value_to_pb(value)),
@@ -818,11 +1015,23 @@ impl<P: RemotePermissions, T: RemoteTransport> Database for Remote<P, T> {
       .map_err(|e| JsErrorBox::from_err(AtomicWriteError::CallData(e)))?;
 
     match res.status() {
-      pb::AtomicWriteStatus::AwSuccess => Ok(Some(CommitResult {
-        versionstamp: <[u8; 10]>::try_from(&res.versionstamp[..]).map_err(
-          |e| JsErrorBox::from_err(AtomicWriteError::TryFromSlice(e)),
-        )?,
-      })),
+      pb::AtomicWriteStatus::AwSuccess => {
+        let versionstamp = <[u8; 10]>::try_from(&res.versionstamp[..])
+          .map_err(|e| {
+            JsErrorBox::from_err(AtomicWriteError::TryFromSlice(e))
+          })?;
+        // The server does not send the keys back, but they follow from
+        // the versionstamp.
+        let versionstamped_keys = versionstamped
+          .iter()
+          .map(|key| denokv_proto::versionstamped_key(key, &versionstamp))
+          .collect();
+        Ok(Some(CommitResult {
+          versionstamp,
+          durable: true,
+          versionstamped_keys,
+        }))
+      }
       pb::AtomicWriteStatus::AwCheckFailure => Ok(None),
       pb::AtomicWriteStatus::AwWriteDisabled => {
         Err(JsErrorBox::from_err(AtomicWriteError::WritesDisabled))
@@ -843,41 +1052,60 @@ impl<P: RemotePermissions, T: RemoteTransport> Database for Remote<P, T> {
   fn watch(
     &self,
     keys: Vec<Vec<u8>>,
-  ) -> Pin<Box<dyn Stream<Item = Result<Vec<WatchKeyOutput>, JsErrorBox>> + Send>> {
+  ) -> Pin<Box<dyn Stream<Item = Result<Vec<WatchKeyOutput>, JsErrorBox>> + Send>>
+  {
     let this = self.clone();
     let stream = try_stream! {
       let mut attempt = 0;
+      // The versionstamp last delivered for each key, `Some(None)` if it
+      // was delivered as absent. After a reconnect the server sends every
+      // key again; keys that did not change meanwhile are reported as
+      // unchanged, so the watch resumes where it left off.
+      let mut delivered: Vec<Option<Option<[u8; 10]>>> = vec![None; keys.len()];
        loop {
         attempt += 1;
         let req = pb::Watch {
           keys: keys.iter().map(|key| pb::WatchKey { key: key.clone() }).collect(),
+          accept_value_deltas: true,
         };
 
-        let (stream, _) = this.call_stream("watch", req).await?;
-        let stream = pin!(stream);
-        let reader = StreamReader::new(stream);
-        let codec = LengthDelimitedCodec::builder()
-          .little_endian()
-          .length_field_length(4)
-          .max_frame_length(16 * 1048576)
-          .new_codec();
-
-        let mut frames = tokio_util::codec::FramedRead::new(reader, codec);
+        let (mut frames, idle_timeout) = this.watch_frames(req).await?;
+        // Value deltas refer to earlier frames of the same stream.
+        let mut decoder = WatchOutputDecoder::default();
+        // A server that is restarting ends the stream cleanly, and the watch
+        // can resume elsewhere right away.
+        let mut received = false;
+        let mut ended = false;
         'decode: loop {
-          let res = frames.next().await;
+          let res = match idle_timeout {
+            Some(idle_timeout) => match runtime::timeout(idle_timeout, frames.next()).await {
+              Some(res) => res,
+              None => {
+                debug!("KV Connect watch timed out (attempt={})", attempt);
+                break 'decode;
+              }
+            },
+            None => frames.next().await,
+          };
+          received |= matches!(res, Some(Ok(_)));
           let frame = match res {
             Some(Ok(frame)) if frame.is_empty() => continue, // ping, ignore
             Some(Ok(frame)) => frame,
+            Some(Err(err)) if err.kind() == io::ErrorKind::InvalidData => {
+              Err(JsErrorBox::from_err(WatchError::Rejected(err.to_string())))?;
+              unreachable!();
+            }
             Some(Err(err)) => {
               debug!("KV Connect watch disconnected (attempt={}): {}", attempt, err);
               break 'decode;
             }
             None => {
+              ended = true;
               break 'decode;
             }
           };
 
-          let data = pb::WatchOutput::decode(frame).map_err(|e: prost::DecodeError| JsErrorBox::from_err(WatchError::Decode(e)))?;
+          let mut data = pb::WatchOutput::decode(frame).map_err(|e: prost::DecodeError| JsErrorBox::from_err(WatchError::Decode(e)))?;
           match data.status() {
             pb::SnapshotReadStatus::SrSuccess => {}
             pb::SnapshotReadStatus::SrReadDisabled => {
@@ -891,6 +1119,10 @@ impl<P: RemotePermissions, T: RemoteTransport> Database for Remote<P, T> {
             }
           }
 
+          if !decoder.decode(&mut data) {
+            Err(JsErrorBox::from_err(WatchError::InvalidValueDelta))?;
+          }
+
           let mut outputs = Vec::new();
           for key in data.keys {
             if !key.changed {
@@ -911,9 +1143,27 @@ impl<P: RemotePermissions, T: RemoteTransport> Database for Remote<P, T> {
               outputs.push(WatchKeyOutput::Changed { entry });
             }
           }
+          for (output, delivered) in outputs.iter_mut().zip(&mut delivered) {
+            if let WatchKeyOutput::Changed { entry } = output {
+              let versionstamp = entry.as_ref().map(|entry| entry.versionstamp);
+              if *delivered == Some(versionstamp) {
+                *output = WatchKeyOutput::Unchanged;
+              } else {
+                *delivered = Some(versionstamp);
+              }
+            }
+          }
+          if outputs.iter().all(|output| matches!(output, WatchKeyOutput::Unchanged)) {
+            continue;
+          }
           yield outputs;
         }
 
+        if ended && received {
+          debug!("KV Connect watch ended by the server, resuming");
+          attempt = 0;
+          continue;
+        }
         // The stream disconnected, so retry after a short delay.
         randomized_exponential_backoff(DATAPATH_BACKOFF_BASE, attempt).await;
       }
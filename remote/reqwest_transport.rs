@@ -0,0 +1,88 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+use bytes::Bytes;
+use deno_error::JsErrorBox;
+use futures::Stream;
+use futures::TryStreamExt;
+use url::Url;
+
+use crate::runtime::AssumeSend;
+use crate::KvClient;
+use crate::MetadataEndpoint;
+use crate::Remote;
+use crate::RemotePermissions;
+use crate::RemoteResponse;
+use crate::RemoteTransport;
+
+/// A [`RemoteTransport`] over a pooled [`reqwest::Client`], or over
+/// `fetch` on wasm32.
+#[derive(Clone, Default)]
+pub struct ReqwestTransport(pub reqwest::Client);
+
+/// Responses are only `Send` natively; see [`AssumeSend`].
+pub struct ReqwestResponse(AssumeSend<reqwest::Response>);
+
+impl RemoteTransport for ReqwestTransport {
+  type Response = ReqwestResponse;
+
+  async fn post(
+    &self,
+    url: Url,
+    headers: http::HeaderMap,
+    body: Bytes,
+  ) -> Result<(Url, http::StatusCode, Self::Response), JsErrorBox> {
+    let request = self.0.post(url).headers(headers).body(body).send();
+    let res = AssumeSend(request)
+      .await
+      .map_err(|e| JsErrorBox::generic(e.to_string()))?;
+    Ok((
+      res.url().clone(),
+      res.status(),
+      ReqwestResponse(AssumeSend(res)),
+    ))
+  }
+}
+
+impl RemoteResponse for ReqwestResponse {
+  async fn bytes(self) -> Result<Bytes, JsErrorBox> {
+    AssumeSend(self.0 .0.bytes())
+      .await
+      .map_err(|e| JsErrorBox::generic(e.to_string()))
+  }
+
+  async fn text(self) -> Result<String, JsErrorBox> {
+    AssumeSend(self.0 .0.text())
+      .await
+      .map_err(|e| JsErrorBox::generic(e.to_string()))
+  }
+
+  fn stream(
+    self,
+  ) -> impl Stream<Item = Result<Bytes, JsErrorBox>> + Send + Sync {
+    AssumeSend(self.0 .0.bytes_stream())
+      .map_err(|e| JsErrorBox::generic(e.to_string()))
+  }
+}
+
+/// Permissions that allow connecting to any URL, for services that trust
+/// the server they are configured with, including its redirects.
+#[derive(Clone)]
+pub struct AllowAllPermissions;
+
+impl RemotePermissions for AllowAllPermissions {
+  fn check_net_url(&self, _url: &Url) -> Result<(), JsErrorBox> {
+    Ok(())
+  }
+}
+
+impl KvClient<AllowAllPermissions, ReqwestTransport> {
+  /// A client for the server whose metadata endpoint is `url`.
+  pub fn connect(url: Url, access_token: String) -> Self {
+    let metadata_endpoint = MetadataEndpoint { url, access_token };
+    KvClient::new(Remote::new(
+      ReqwestTransport::default(),
+      AllowAllPermissions,
+      metadata_endpoint,
+    ))
+  }
+}
@@ -0,0 +1,136 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! Background tasks and timers, on tokio natively and on the JavaScript
+//! event loop on wasm32, so that the client also runs in edge runtimes and
+//! browsers. There, `fetch` and timers hand out JavaScript values, which
+//! are not `Send`; [`AssumeSend`] lets them into the `Send` futures and
+//! responses the transport traits ask for.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+
+use futures::Stream;
+
+/// A value that is `Send` and `Sync` on wasm32, where everything runs on
+/// one thread, and elsewhere only if `T` is.
+pub(crate) struct AssumeSend<T>(pub T);
+
+// SAFETY: without the atomics target feature, wasm32 has no threads, so the
+// value is never touched from another one.
+#[cfg(all(target_arch = "wasm32", not(target_feature = "atomics")))]
+unsafe impl<T> Send for AssumeSend<T> {}
+#[cfg(all(target_arch = "wasm32", not(target_feature = "atomics")))]
+unsafe impl<T> Sync for AssumeSend<T> {}
+
+impl<T> AssumeSend<T> {
+  fn project(self: Pin<&mut Self>) -> Pin<&mut T> {
+    // SAFETY: the value is pinned structurally and never moved out of a
+    // pinned wrapper.
+    unsafe { self.map_unchecked_mut(|this| &mut this.0) }
+  }
+}
+
+impl<T: Future> Future for AssumeSend<T> {
+  type Output = T::Output;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T::Output> {
+    self.project().poll(cx)
+  }
+}
+
+impl<T: Stream> Stream for AssumeSend<T> {
+  type Item = T::Item;
+
+  fn poll_next(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Option<T::Item>> {
+    self.project().poll_next(cx)
+  }
+}
+
+/// Wait for `future` for at most `duration`, returning `None` if it did
+/// not finish in time.
+pub(crate) async fn timeout<F: Future>(
+  duration: Duration,
+  future: F,
+) -> Option<F::Output> {
+  #[cfg(not(target_arch = "wasm32"))]
+  {
+    tokio::time::timeout(duration, future).await.ok()
+  }
+  #[cfg(target_arch = "wasm32")]
+  {
+    use futures::future::Either;
+    let future = std::pin::pin!(future);
+    let timer = std::pin::pin!(sleep(duration));
+    match futures::future::select(future, timer).await {
+      Either::Left((output, _)) => Some(output),
+      Either::Right(_) => None,
+    }
+  }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use tokio::task::JoinHandle;
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use tokio::time::sleep;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn spawn(
+  future: impl Future<Output = ()> + Send + 'static,
+) -> JoinHandle<()> {
+  tokio::spawn(future)
+}
+
+/// A task on the JavaScript event loop, which stops at its next `.await`
+/// once aborted.
+#[cfg(target_arch = "wasm32")]
+pub(crate) struct JoinHandle<T>(
+  futures::future::AbortHandle,
+  std::marker::PhantomData<T>,
+);
+
+#[cfg(target_arch = "wasm32")]
+impl<T> JoinHandle<T> {
+  pub fn abort(&self) {
+    self.0.abort();
+  }
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn spawn(
+  future: impl Future<Output = ()> + Send + 'static,
+) -> JoinHandle<()> {
+  let (future, handle) = futures::future::abortable(future);
+  wasm_bindgen_futures::spawn_local(async move {
+    let _ = future.await;
+  });
+  JoinHandle(handle, std::marker::PhantomData)
+}
+
+/// Sleep with the host's `setTimeout`, which browsers, workers and edge
+/// runtimes all provide.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn sleep(duration: Duration) -> impl Future<Output = ()> + Send {
+  use wasm_bindgen::JsCast;
+  use wasm_bindgen::JsValue;
+
+  let millis = duration.as_millis().min(i32::MAX as u128) as i32;
+  let promise = js_sys::Promise::new(&mut |resolve, _reject| {
+    let set_timeout =
+      js_sys::Reflect::get(&js_sys::global(), &JsValue::from_str("setTimeout"))
+        .expect("the host has no setTimeout")
+        .unchecked_into::<js_sys::Function>();
+    set_timeout
+      .call2(&JsValue::UNDEFINED, &resolve, &JsValue::from(millis))
+      .expect("setTimeout failed");
+  });
+  let timer = AssumeSend(wasm_bindgen_futures::JsFuture::from(promise));
+  async move {
+    let _ = timer.await;
+  }
+}
@@ -0,0 +1,241 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! Web sessions kept in the database.
+//!
+//! A session is stored at `[...prefix, id]` as JSON, together with when it
+//! expires, and written with a matching `expire_at` so the database drops
+//! it once it is over. Sessions expire a fixed time after they were last
+//! refreshed, so active sessions stay alive. Ids are 32 bytes from the
+//! operating system's random number generator, in hex.
+
+use std::time::Duration;
+
+use chrono::DateTime;
+use chrono::TimeZone;
+use chrono::Utc;
+use denokv_proto::encode_key;
+use denokv_proto::AtomicWrite;
+use denokv_proto::Check;
+use denokv_proto::Database;
+use denokv_proto::Key;
+use denokv_proto::KeyPart;
+use denokv_proto::KvValue;
+use denokv_proto::Mutation;
+use denokv_proto::MutationKind;
+use denokv_proto::Versionstamp;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::time::utc_now;
+use crate::ClientError;
+use crate::KvClient;
+use crate::RemotePermissions;
+use crate::RemoteTransport;
+
+/// Length of session ids, in bytes before hex encoding.
+const SESSION_ID_BYTES: usize = 32;
+
+/// Sessions under a key prefix. Made by [`KvClient::session_store`].
+#[derive(Clone)]
+pub struct SessionStore<P: RemotePermissions, T: RemoteTransport> {
+  client: KvClient<P, T>,
+  prefix: Key,
+  ttl: Duration,
+}
+
+/// A live session with data of type `D`.
+#[derive(Clone, Debug)]
+pub struct Session<D> {
+  pub id: String,
+  pub data: D,
+  pub expires_at: DateTime<Utc>,
+  versionstamp: Versionstamp,
+}
+
+impl<D> Session<D> {
+  /// A `Set-Cookie` header value that hands the session to a browser as
+  /// the cookie `name`, for as long as the session lives.
+  pub fn cookie(&self, name: &str) -> String {
+    let max_age = (self.expires_at - utc_now()).num_seconds().max(0);
+    format!("{name}={}; {COOKIE_ATTRIBUTES}; Max-Age={max_age}", self.id)
+  }
+}
+
+/// A `Set-Cookie` header value that removes the session cookie `name`.
+pub fn expired_session_cookie(name: &str) -> String {
+  format!("{name}=; {COOKIE_ATTRIBUTES}; Max-Age=0")
+}
+
+const COOKIE_ATTRIBUTES: &str = "Path=/; HttpOnly; Secure; SameSite=Lax";
+
+#[derive(Serialize, Deserialize)]
+struct StoredSession<D> {
+  /// Milliseconds since the Unix epoch.
+  expires_at: i64,
+  data: D,
+}
+
+impl<P: RemotePermissions, T: RemoteTransport> KvClient<P, T> {
+  /// The sessions stored under `prefix`, which expire `ttl` after they
+  /// were last refreshed.
+  pub fn session_store(
+    &self,
+    prefix: Key,
+    ttl: Duration,
+  ) -> SessionStore<P, T> {
+    SessionStore {
+      client: self.clone(),
+      prefix,
+      ttl,
+    }
+  }
+}
+
+impl<P: RemotePermissions, T: RemoteTransport> SessionStore<P, T> {
+  /// Start a session holding `data`, under a new id.
+  pub async fn create<D: Serialize + DeserializeOwned>(
+    &self,
+    data: D,
+  ) -> Result<Session<D>, ClientError> {
+    let mut id = [0; SESSION_ID_BYTES];
+    OsRng.fill_bytes(&mut id);
+    let mut session = Session {
+      id: hex::encode(id),
+      data,
+      expires_at: utc_now(),
+      versionstamp: Default::default(),
+    };
+    // Random ids do not collide, but if one did, the check keeps the
+    // existing session from being handed to a second user.
+    if !self.write(&mut session, None).await? {
+      return Err(ClientError::InvalidValue(
+        "the session id is taken".to_string(),
+      ));
+    }
+    Ok(session)
+  }
+
+  /// The session with `id`, if it is live. This does not refresh it.
+  pub async fn get<D: DeserializeOwned>(
+    &self,
+    id: &str,
+  ) -> Result<Option<Session<D>>, ClientError> {
+    let Some(key) = self.key(id) else {
+      return Ok(None);
+    };
+    let Some(entry) = self.client.get(&key).await? else {
+      return Ok(None);
+    };
+    let KvValue::Bytes(bytes) = &entry.value else {
+      return Err(ClientError::InvalidValue(
+        "expected a stored session".to_string(),
+      ));
+    };
+    let stored: StoredSession<D> = serde_json::from_slice(bytes)
+      .map_err(|e| ClientError::InvalidValue(e.to_string()))?;
+    // The database removes expired entries some time after they expire.
+    let Some(expires_at) = Utc.timestamp_millis_opt(stored.expires_at).single()
+    else {
+      return Ok(None);
+    };
+    if expires_at <= utc_now() {
+      return Ok(None);
+    }
+    Ok(Some(Session {
+      id: id.to_string(),
+      data: stored.data,
+      expires_at,
+      versionstamp: entry.versionstamp,
+    }))
+  }
+
+  /// The session with `id`, if it is live, with its expiry moved to the
+  /// full time to live from now.
+  pub async fn refresh<D: Serialize + DeserializeOwned>(
+    &self,
+    id: &str,
+  ) -> Result<Option<Session<D>>, ClientError> {
+    loop {
+      let Some(mut session) = self.get(id).await? else {
+        return Ok(None);
+      };
+      let versionstamp = session.versionstamp;
+      if self.write(&mut session, Some(versionstamp)).await? {
+        return Ok(Some(session));
+      }
+    }
+  }
+
+  /// Store the data of `session`, and refresh it. Returns `false`, and
+  /// stores nothing, if the session was changed or destroyed since it was
+  /// read.
+  pub async fn save<D: Serialize>(
+    &self,
+    session: &mut Session<D>,
+  ) -> Result<bool, ClientError> {
+    let versionstamp = session.versionstamp;
+    self.write(session, Some(versionstamp)).await
+  }
+
+  /// End the session with `id`.
+  pub async fn destroy(&self, id: &str) -> Result<(), ClientError> {
+    if let Some(key) = self.key(id) {
+      self.client.delete(&key).await?;
+    }
+    Ok(())
+  }
+
+  /// Write `session` with a new expiry if its key still has `versionstamp`
+  /// (`None` meaning no entry), updating its expiry and versionstamp.
+  async fn write<D: Serialize>(
+    &self,
+    session: &mut Session<D>,
+    versionstamp: Option<Versionstamp>,
+  ) -> Result<bool, ClientError> {
+    let key = self.key(&session.id).expect("sessions have valid ids");
+    let key = encode_key(&key).map_err(ClientError::InvalidKey)?;
+    let ttl = chrono::Duration::from_std(self.ttl)
+      .map_err(|e| ClientError::InvalidValue(e.to_string()))?;
+    let expires_at = utc_now() + ttl;
+    let stored = StoredSession {
+      expires_at: expires_at.timestamp_millis(),
+      data: &session.data,
+    };
+    let value = serde_json::to_vec(&stored)
+      .map_err(|e| ClientError::InvalidValue(e.to_string()))?;
+    let write = AtomicWrite {
+      checks: vec![Check {
+        key: key.clone(),
+        versionstamp,
+      }],
+      mutations: vec![Mutation {
+        key,
+        kind: MutationKind::Set(KvValue::Bytes(value)),
+        expire_at: Some(expires_at),
+      }],
+      enqueues: vec![],
+    };
+    let Some(result) = self.client.remote().atomic_write(write).await? else {
+      return Ok(false);
+    };
+    session.expires_at = expires_at;
+    session.versionstamp = result.versionstamp;
+    Ok(true)
+  }
+
+  /// The key of the session `id`, if it is a well-formed id. Ids come from
+  /// clients, so anything else is not looked up.
+  fn key(&self, id: &str) -> Option<Key> {
+    let well_formed = id.len() == SESSION_ID_BYTES * 2
+      && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+    if !well_formed {
+      return None;
+    }
+    let mut key = self.prefix.clone();
+    key.0.push(KeyPart::String(id.to_string()));
+    Some(key)
+  }
+}
@@ -0,0 +1,185 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! Tower middleware that loads sessions of a [`SessionStore`], with the
+//! `tower` feature.
+
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+
+use http::header::COOKIE;
+use http::header::SET_COOKIE;
+use http::HeaderValue;
+use http::Request;
+use http::Response;
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::session::Session;
+use crate::RemotePermissions;
+use crate::RemoteTransport;
+use crate::SessionStore;
+
+/// Cookie sessions are read from unless [`SessionLayer::cookie_name`] says
+/// otherwise.
+const DEFAULT_COOKIE_NAME: &str = "session";
+
+/// Loads the session named by the session cookie of each request, if it is
+/// live, refreshes it, and adds it to the request extensions as a
+/// [`Session<D>`]. The response renews the cookie, unless the handler set
+/// it itself.
+///
+/// Requests without a live session pass through without one; handlers
+/// start sessions with [`SessionStore::create`] and [`Session::cookie`].
+/// A session that fails to load is treated as missing, and logged.
+pub struct SessionLayer<P: RemotePermissions, T: RemoteTransport, D> {
+  store: SessionStore<P, T>,
+  cookie_name: String,
+  data: PhantomData<fn() -> D>,
+}
+
+impl<P: RemotePermissions, T: RemoteTransport, D> SessionLayer<P, T, D> {
+  pub fn new(store: SessionStore<P, T>) -> Self {
+    Self {
+      store,
+      cookie_name: DEFAULT_COOKIE_NAME.to_string(),
+      data: PhantomData,
+    }
+  }
+
+  pub fn cookie_name(mut self, name: impl Into<String>) -> Self {
+    self.cookie_name = name.into();
+    self
+  }
+}
+
+impl<P: RemotePermissions, T: RemoteTransport, D> Clone
+  for SessionLayer<P, T, D>
+{
+  fn clone(&self) -> Self {
+    Self {
+      store: self.store.clone(),
+      cookie_name: self.cookie_name.clone(),
+      data: PhantomData,
+    }
+  }
+}
+
+impl<S, P: RemotePermissions, T: RemoteTransport, D> Layer<S>
+  for SessionLayer<P, T, D>
+{
+  type Service = SessionService<S, P, T, D>;
+
+  fn layer(&self, inner: S) -> Self::Service {
+    SessionService {
+      inner,
+      layer: self.clone(),
+    }
+  }
+}
+
+/// The service [`SessionLayer`] wraps services in.
+pub struct SessionService<S, P: RemotePermissions, T: RemoteTransport, D> {
+  inner: S,
+  layer: SessionLayer<P, T, D>,
+}
+
+impl<S: Clone, P: RemotePermissions, T: RemoteTransport, D> Clone
+  for SessionService<S, P, T, D>
+{
+  fn clone(&self) -> Self {
+    Self {
+      inner: self.inner.clone(),
+      layer: self.layer.clone(),
+    }
+  }
+}
+
+impl<S, P, T, D, ReqBody, ResBody> Service<Request<ReqBody>>
+  for SessionService<S, P, T, D>
+where
+  S: Service<Request<ReqBody>, Response = Response<ResBody>>
+    + Clone
+    + Send
+    + 'static,
+  S::Future: Send,
+  P: RemotePermissions,
+  T: RemoteTransport,
+  D: Serialize + DeserializeOwned + Clone + Send + Sync + 'static,
+  ReqBody: Send + 'static,
+{
+  type Response = S::Response;
+  type Error = S::Error;
+  type Future =
+    Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+    // The clone may not be ready, so call the instance that was polled.
+    let clone = self.inner.clone();
+    let mut inner = std::mem::replace(&mut self.inner, clone);
+    let layer = self.layer.clone();
+    Box::pin(async move {
+      let id = session_cookie(&req, &layer.cookie_name);
+      let session = match id {
+        Some(id) => match layer.store.refresh::<D>(&id).await {
+          Ok(session) => session,
+          Err(e) => {
+            warn!("Failed to load a session: {e}");
+            None
+          }
+        },
+        None => None,
+      };
+      let cookie = session
+        .as_ref()
+        .map(|session| session.cookie(&layer.cookie_name));
+      if let Some(session) = session {
+        req.extensions_mut().insert::<Session<D>>(session);
+      }
+
+      let mut res = inner.call(req).await?;
+      let set_by_handler =
+        res.headers().get_all(SET_COOKIE).iter().any(|value| {
+          value
+            .to_str()
+            .is_ok_and(|value| cookie_name(value) == layer.cookie_name)
+        });
+      if let Some(cookie) = cookie.filter(|_| !set_by_handler) {
+        if let Ok(cookie) = HeaderValue::from_str(&cookie) {
+          res.headers_mut().append(SET_COOKIE, cookie);
+        }
+      }
+      Ok(res)
+    })
+  }
+}
+
+/// The value of the cookie `name` in the `Cookie` headers of `req`.
+fn session_cookie<B>(req: &Request<B>, name: &str) -> Option<String> {
+  req
+    .headers()
+    .get_all(COOKIE)
+    .iter()
+    .filter_map(|value| value.to_str().ok())
+    .flat_map(|value| value.split(';'))
+    .find_map(|pair| {
+      let (key, value) = pair.trim().split_once('=')?;
+      (key == name).then(|| value.to_string())
+    })
+}
+
+/// The name of the cookie a `Set-Cookie` header value sets.
+fn cookie_name(set_cookie: &str) -> &str {
+  set_cookie
+    .split_once('=')
+    .map_or("", |(name, _)| name.trim())
+}
@@ -0,0 +1,264 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! Sorted sets, like leaderboards, kept under a key prefix.
+//!
+//! A member of a [`SortedSet`] is stored twice, in one atomic write:
+//!
+//! - `[...prefix, "member", member]` holds its score, to look it up by.
+//! - `[...prefix, "score", score, member]` is empty. Scores are float key
+//!   parts, which the key encoding orders numerically, so listing these
+//!   keys lists members by score, and ties by member.
+//!
+//! Writes check the versionstamp of the member entry and retry if another
+//! write got in between, so the two never disagree.
+
+use std::num::NonZeroU32;
+
+use denokv_proto::encode_key;
+use denokv_proto::AtomicWrite;
+use denokv_proto::Check;
+use denokv_proto::Database;
+use denokv_proto::Key;
+use denokv_proto::KeyPart;
+use denokv_proto::KvValue;
+use denokv_proto::Mutation;
+use denokv_proto::MutationKind;
+
+use crate::ClientError;
+use crate::Cursor;
+use crate::Entry;
+use crate::KvClient;
+use crate::ListOptions;
+use crate::RemotePermissions;
+use crate::RemoteTransport;
+
+/// Page size [`SortedSet::rank`] reads the index with.
+const RANK_PAGE_SIZE: u32 = 1000;
+
+/// A set of members ordered by score. Made by [`KvClient::sorted_set`].
+#[derive(Clone)]
+pub struct SortedSet<P: RemotePermissions, T: RemoteTransport> {
+  client: KvClient<P, T>,
+  prefix: Key,
+}
+
+impl<P: RemotePermissions, T: RemoteTransport> KvClient<P, T> {
+  /// The sorted set stored under `prefix`.
+  pub fn sorted_set(&self, prefix: Key) -> SortedSet<P, T> {
+    SortedSet {
+      client: self.clone(),
+      prefix,
+    }
+  }
+}
+
+impl<P: RemotePermissions, T: RemoteTransport> SortedSet<P, T> {
+  /// Add `member` with `score`, or move it to `score` if it is in the set
+  /// already.
+  pub async fn add(&self, member: &str, score: f64) -> Result<(), ClientError> {
+    self.update(member, |_| Some(score)).await.map(|_| ())
+  }
+
+  /// Add `delta` to the score of `member`, which is added with a score of
+  /// `delta` if it is not in the set. Returns the new score.
+  pub async fn increment(
+    &self,
+    member: &str,
+    delta: f64,
+  ) -> Result<f64, ClientError> {
+    let score = self
+      .update(member, |score| Some(score.unwrap_or(0.0) + delta))
+      .await?;
+    Ok(score.expect("incremented members stay in the set"))
+  }
+
+  /// Remove `member`. Returns whether it was in the set.
+  pub async fn remove(&self, member: &str) -> Result<bool, ClientError> {
+    let mut removed = false;
+    self
+      .update(member, |score| {
+        removed = score.is_some();
+        None
+      })
+      .await?;
+    Ok(removed)
+  }
+
+  pub async fn score(&self, member: &str) -> Result<Option<f64>, ClientError> {
+    match self.client.get(&self.member_key(member)).await? {
+      Some(entry) => score_of(&entry).map(Some),
+      None => Ok(None),
+    }
+  }
+
+  /// The `n` members with the highest scores, highest first.
+  pub async fn top(&self, n: u32) -> Result<Vec<(String, f64)>, ClientError> {
+    self.first(n, true).await
+  }
+
+  /// The `n` members with the lowest scores, lowest first.
+  pub async fn bottom(
+    &self,
+    n: u32,
+  ) -> Result<Vec<(String, f64)>, ClientError> {
+    self.first(n, false).await
+  }
+
+  /// The position of `member` in [`SortedSet::top`] order, starting at 0,
+  /// or `None` if it is not in the set. This reads every member ranked
+  /// above it, so it is slow for members far down a large set.
+  pub async fn rank(&self, member: &str) -> Result<Option<u64>, ClientError> {
+    let Some(score) = self.score(member).await? else {
+      return Ok(None);
+    };
+    let index = self.index_prefix();
+    let last = encode_key(&self.index_key(score, member))
+      .map_err(ClientError::InvalidKey)?;
+    let options = ListOptions {
+      limit: NonZeroU32::new(RANK_PAGE_SIZE).unwrap(),
+      ..Default::default()
+    };
+    let mut cursor = Cursor(last);
+    let mut rank = 0;
+    loop {
+      let page = self.client.list(&index, Some(&cursor), &options).await?;
+      rank += page.entries.len() as u64;
+      match page.cursor {
+        Some(next) => cursor = next,
+        None => return Ok(Some(rank)),
+      }
+    }
+  }
+
+  /// The number of members, which reads all of them.
+  pub async fn count(&self) -> Result<u64, ClientError> {
+    self.client.count(&self.child(&["member"])).await
+  }
+
+  async fn first(
+    &self,
+    n: u32,
+    reverse: bool,
+  ) -> Result<Vec<(String, f64)>, ClientError> {
+    let Some(limit) = NonZeroU32::new(n) else {
+      return Ok(vec![]);
+    };
+    let options = ListOptions {
+      limit,
+      reverse,
+      ..Default::default()
+    };
+    let page = self
+      .client
+      .list(&self.index_prefix(), None, &options)
+      .await?;
+    page
+      .entries
+      .into_iter()
+      .map(|entry| match entry.key.0.as_slice() {
+        [.., KeyPart::Float(score), KeyPart::String(member)] => {
+          Ok((member.clone(), *score))
+        }
+        _ => Err(ClientError::InvalidValue(
+          "unexpected key in the score index".to_string(),
+        )),
+      })
+      .collect()
+  }
+
+  /// Set the score of `member` to what `f` returns for its current score,
+  /// removing it if that is `None`. `f` runs again if the write conflicts.
+  async fn update(
+    &self,
+    member: &str,
+    mut f: impl FnMut(Option<f64>) -> Option<f64>,
+  ) -> Result<Option<f64>, ClientError> {
+    let member_key = self.member_key(member);
+    let encoded = encode_key(&member_key).map_err(ClientError::InvalidKey)?;
+    loop {
+      let entry = self.client.get(&member_key).await?;
+      let current = entry.as_ref().map(score_of).transpose()?;
+      let new = f(current);
+      if new.is_some_and(f64::is_nan) {
+        return Err(ClientError::InvalidValue(
+          "scores must not be NaN".to_string(),
+        ));
+      }
+
+      let mut mutations = vec![];
+      let mut mutate = |key: &Key, kind| {
+        mutations.push(Mutation {
+          key: encode_key(key).map_err(ClientError::InvalidKey)?,
+          kind,
+          expire_at: None,
+        });
+        Ok::<_, ClientError>(())
+      };
+      if let Some(current) = current {
+        mutate(&self.index_key(current, member), MutationKind::Delete)?;
+      }
+      match new {
+        Some(new) => {
+          let index_key = self.index_key(new, member);
+          mutate(&index_key, MutationKind::Set(KvValue::Bytes(vec![])))?;
+          let score = KvValue::Bytes(normalize(new).to_be_bytes().to_vec());
+          mutate(&member_key, MutationKind::Set(score))?;
+        }
+        None => mutate(&member_key, MutationKind::Delete)?,
+      }
+      let write = AtomicWrite {
+        checks: vec![Check {
+          key: encoded.clone(),
+          versionstamp: entry.map(|entry| entry.versionstamp),
+        }],
+        mutations,
+        enqueues: vec![],
+      };
+      if self.client.remote().atomic_write(write).await?.is_some() {
+        return Ok(new);
+      }
+    }
+  }
+
+  fn child(&self, parts: &[&str]) -> Key {
+    let mut key = self.prefix.clone();
+    key
+      .0
+      .extend(parts.iter().map(|part| KeyPart::String(part.to_string())));
+    key
+  }
+
+  fn member_key(&self, member: &str) -> Key {
+    self.child(&["member", member])
+  }
+
+  fn index_prefix(&self) -> Key {
+    self.child(&["score"])
+  }
+
+  fn index_key(&self, score: f64, member: &str) -> Key {
+    let mut key = self.index_prefix();
+    key.0.push(KeyPart::Float(normalize(score)));
+    key.0.push(KeyPart::String(member.to_string()));
+    key
+  }
+}
+
+/// `-0.0` and `0.0` are equal scores, but encode to different keys.
+fn normalize(score: f64) -> f64 {
+  if score == 0.0 {
+    0.0
+  } else {
+    score
+  }
+}
+
+fn score_of(entry: &Entry) -> Result<f64, ClientError> {
+  let score = match &entry.value {
+    KvValue::Bytes(bytes) => <[u8; 8]>::try_from(bytes.as_slice()).ok(),
+    _ => None,
+  };
+  score.map(f64::from_be_bytes).ok_or_else(|| {
+    ClientError::InvalidValue("expected a sorted set score".to_string())
+  })
+}
@@ -0,0 +1,85 @@
+// Copyright 2023 the Deno authors. All rights reserved. MIT license.
+
+//! Watches over a WebSocket, for servers that grant the `websocket_watch`
+//! extension.
+
+use std::io;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::SinkExt;
+use futures::Stream;
+use futures::StreamExt;
+use prost::Message as _;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderName;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::Message;
+use url::Url;
+
+use denokv_proto::datapath as pb;
+
+/// The server pings every few seconds; a connection that has been silent
+/// for this long is considered dead.
+pub(crate) const WEBSOCKET_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Open the watch at `endpoint` and send `req`. The returned stream yields
+/// every `WatchOutput` message as a frame and pings as empty frames, like
+/// the streamed watch response does. A close for a bad request is an
+/// `InvalidData` error, which is not worth retrying.
+pub(crate) async fn open_watch(
+  endpoint: &Url,
+  headers: &http::HeaderMap,
+  req: &pb::Watch,
+) -> io::Result<impl Stream<Item = io::Result<Bytes>>> {
+  let mut url =
+    Url::parse(&format!("{endpoint}/watch_ws")).map_err(io::Error::other)?;
+  let scheme = match url.scheme() {
+    "https" => "wss",
+    _ => "ws",
+  };
+  url
+    .set_scheme(scheme)
+    .map_err(|()| io::Error::other("invalid watch URL"))?;
+
+  let mut request = url
+    .as_str()
+    .into_client_request()
+    .map_err(io::Error::other)?;
+  for (name, value) in headers {
+    request.headers_mut().insert(
+      HeaderName::from_bytes(name.as_str().as_bytes())
+        .map_err(io::Error::other)?,
+      HeaderValue::from_bytes(value.as_bytes()).map_err(io::Error::other)?,
+    );
+  }
+  let (mut socket, _) = tokio_tungstenite::connect_async(request)
+    .await
+    .map_err(io::Error::other)?;
+  socket
+    .send(Message::Binary(req.encode_to_vec()))
+    .await
+    .map_err(io::Error::other)?;
+
+  Ok(socket.filter_map(|message| async move {
+    match message {
+      Ok(Message::Binary(data)) => Some(Ok(Bytes::from(data))),
+      Ok(Message::Ping(_) | Message::Pong(_)) => Some(Ok(Bytes::new())),
+      Ok(Message::Close(Some(frame))) if frame.code == CloseCode::Policy => {
+        Some(Err(io::Error::new(
+          io::ErrorKind::InvalidData,
+          frame.reason.into_owned(),
+        )))
+      }
+      // The server is restarting. The stream ends after the close frame,
+      // like a streamed watch does.
+      Ok(Message::Close(Some(frame))) if frame.code == CloseCode::Away => None,
+      Ok(Message::Close(_)) => {
+        Some(Err(io::Error::from(io::ErrorKind::ConnectionAborted)))
+      }
+      Ok(Message::Text(_) | Message::Frame(_)) => None,
+      Err(e) => Some(Err(io::Error::other(e))),
+    }
+  }))
+}
@@ -1,7 +1,6 @@
 // Copyright 2023 the Deno authors. All rights reserved. MIT license.
 
 use std::collections::HashSet;
-use std::mem::discriminant;
 use std::time::Duration;
 
 use chrono::DateTime;
@@ -10,8 +9,11 @@ use deno_error::JsErrorBox;
 use denokv_proto::decode_value;
 use denokv_proto::encode_value;
 use denokv_proto::encode_value_owned;
+use denokv_proto::versionstamped_key;
+use denokv_proto::versionstamped_keys;
 use denokv_proto::AtomicWrite;
 use denokv_proto::CommitResult;
+use denokv_proto::KvChange;
 use denokv_proto::KvEntry;
 use denokv_proto::KvValue;
 use denokv_proto::MutationKind;
@@ -20,7 +22,6 @@ use denokv_proto::ReadRangeOutput;
 use denokv_proto::SnapshotReadOptions;
 use denokv_proto::Versionstamp;
 use denokv_proto::VALUE_ENCODING_V8;
-use num_bigint::BigInt;
 use rand::Rng;
 use rand::RngCore;
 use rusqlite::params;
@@ -29,6 +30,7 @@ use rusqlite::Transaction;
 use thiserror::Error;
 use uuid::Uuid;
 
+use crate::sum_operand::SumError;
 use crate::sum_operand::SumOperand;
 use crate::time::utc_now;
 use crate::SqliteNotifier;
@@ -41,6 +43,10 @@ const STATEMENT_KV_RANGE_SCAN: &str =
   "select k, v, v_encoding, version from kv where k >= ? and k < ? order by k asc limit ?";
 const STATEMENT_KV_RANGE_SCAN_REVERSE: &str =
   "select k, v, v_encoding, version from kv where k >= ? and k < ? order by k desc limit ?";
+const STATEMENT_KV_RANGE_SCAN_OPEN_END: &str =
+  "select k, v, v_encoding, version from kv where k >= ? order by k asc limit ?";
+const STATEMENT_KV_RANGE_SCAN_OPEN_END_REVERSE: &str =
+  "select k, v, v_encoding, version from kv where k >= ? order by k desc limit ?";
 const STATEMENT_KV_POINT_GET_VALUE_ONLY: &str =
   "select v, v_encoding from kv where k = ?";
 const STATEMENT_KV_POINT_GET_VERSION_ONLY: &str =
@@ -48,6 +54,13 @@ const STATEMENT_KV_POINT_GET_VERSION_ONLY: &str =
 const STATEMENT_KV_POINT_SET: &str =
   "insert into kv (k, v, v_encoding, version, expiration_ms) values (:k, :v, :v_encoding, :version, :expiration_ms) on conflict(k) do update set v = :v, v_encoding = :v_encoding, version = :version, expiration_ms = :expiration_ms";
 const STATEMENT_KV_POINT_DELETE: &str = "delete from kv where k = ?";
+const STATEMENT_ADVANCE_DATA_VERSION: &str =
+  "update data_version set version = max(version + 1, ?) where k = 0 returning version";
+
+const STATEMENT_GET_REPLICATION_CURSOR: &str =
+  "select versionstamp, key from replication_cursor where k = 0";
+const STATEMENT_SET_REPLICATION_CURSOR: &str =
+  "replace into replication_cursor (k, versionstamp, key) values (0, ?, ?)";
 
 const STATEMENT_DELETE_ALL_EXPIRED: &str =
   "delete from kv where expiration_ms >= 0 and expiration_ms <= ? returning k";
@@ -75,7 +88,7 @@ create table if not exists migration_state(
 )
 ";
 
-const MIGRATIONS: [&str; 3] = [
+const MIGRATIONS: [&str; 4] = [
   "
 create table data_version (
   k integer primary key,
@@ -114,6 +127,13 @@ alter table kv add column seq integer not null default 0;
 alter table data_version add column seq integer not null default 0;
 alter table kv add column expiration_ms integer not null default -1;
 create index kv_expiration_ms_idx on kv (expiration_ms);
+",
+  "
+create table replication_cursor (
+  k integer primary key,
+  versionstamp blob not null,
+  key blob not null
+);
 ",
 ];
 
@@ -151,6 +171,18 @@ pub enum SqliteBackendError {
   SumOutOfRange,
 }
 
+impl From<SumError> for SqliteBackendError {
+  fn from(err: SumError) -> Self {
+    match err {
+      SumError::TypeMismatch(msg) => SqliteBackendError::TypeMismatch(msg),
+      SumError::OutOfRange => SqliteBackendError::SumOutOfRange,
+    }
+  }
+}
+
+/// The versionstamp and key of the last change applied to a replica.
+pub type ReplicationCursor = (Versionstamp, Vec<u8>);
+
 pub struct SqliteBackend {
   conn: rusqlite::Connection,
   rng: Box<dyn RngCore + Send>,
@@ -237,16 +269,23 @@ impl SqliteBackend {
     self.run_tx(|tx, _| {
       let mut responses = Vec::with_capacity(requests.len());
       for request in &*requests {
-        let mut stmt = tx.prepare_cached(if request.reverse {
-          STATEMENT_KV_RANGE_SCAN_REVERSE
+        let open_ended = request.is_open_ended();
+        let mut stmt =
+          tx.prepare_cached(match (open_ended, request.reverse) {
+            (false, false) => STATEMENT_KV_RANGE_SCAN,
+            (false, true) => STATEMENT_KV_RANGE_SCAN_REVERSE,
+            (true, false) => STATEMENT_KV_RANGE_SCAN_OPEN_END,
+            (true, true) => STATEMENT_KV_RANGE_SCAN_OPEN_END_REVERSE,
+          })?;
+        let mut rows = if open_ended {
+          stmt.query((request.start.as_slice(), request.limit.get()))?
         } else {
-          STATEMENT_KV_RANGE_SCAN
-        })?;
-        let mut rows = stmt.query((
-          request.start.as_slice(),
-          request.end.as_slice(),
-          request.limit.get(),
-        ))?;
+          stmt.query((
+            request.start.as_slice(),
+            request.end.as_slice(),
+            request.limit.get(),
+          ))?
+        };
         let mut entries = vec![];
         loop {
           let Some(row) = rows.next()? else { break };
@@ -337,6 +376,82 @@ impl SqliteBackend {
     commit_results
   }
 
+  /// Apply changes replicated from another database, keeping their
+  /// versionstamps, and record the last one as the replication cursor.
+  /// Returns the replication cursor, which is also how to read it without
+  /// applying anything.
+  pub fn apply_changes(
+    &mut self,
+    changes: Vec<KvChange>,
+  ) -> Result<Option<ReplicationCursor>, SqliteBackendError> {
+    if self.readonly {
+      return Err(SqliteBackendError::WriteDisabled);
+    }
+
+    let (cursor, local_versionstamp) = self.run_tx(|tx, _| {
+      let Some(last) = changes.last() else {
+        let cursor = tx
+          .prepare_cached(STATEMENT_GET_REPLICATION_CURSOR)?
+          .query_row([], |row| {
+            let versionstamp: Vec<u8> = row.get(0)?;
+            Ok((versionstamp, row.get(1)?))
+          })
+          .optional()?
+          .and_then(|(versionstamp, key)| {
+            Some((Versionstamp::try_from(versionstamp).ok()?, key))
+          });
+        return Ok((cursor, None));
+      };
+
+      let mut max_version = 0;
+      for change in &changes {
+        let version = versionstamp_to_version(&change.versionstamp);
+        max_version = max_version.max(version);
+        match &change.value {
+          Some(value) => {
+            let (value, encoding) = encode_value(value);
+            tx.prepare_cached(STATEMENT_KV_POINT_SET)?.execute(params![
+              change.key,
+              value,
+              &encoding,
+              &version,
+              change
+                .expire_at
+                .map(|time| time.timestamp_millis())
+                .unwrap_or(-1i64)
+            ])?;
+          }
+          None => {
+            tx.prepare_cached(STATEMENT_KV_POINT_DELETE)?
+              .execute(params![change.key])?;
+          }
+        }
+      }
+      tx.prepare_cached(STATEMENT_SET_REPLICATION_CURSOR)?
+        .execute(params![last.versionstamp.as_slice(), last.key])?;
+
+      // The data version only drives watches here, so it just has to move
+      // forward; rows carry the versions of the source database.
+      let local_version: i64 = tx
+        .prepare_cached(STATEMENT_ADVANCE_DATA_VERSION)?
+        .query_row([max_version], |row| row.get(0))?;
+      Ok((
+        Some((last.versionstamp, last.key.clone())),
+        Some(version_to_versionstamp(local_version)),
+      ))
+    })?;
+
+    if let Some(local_versionstamp) = local_versionstamp {
+      for change in &changes {
+        self
+          .notifier
+          .notify_key_update(&change.key, local_versionstamp);
+      }
+    }
+
+    Ok(cursor)
+  }
+
   fn atomic_write_once(
     tx: &mut rusqlite::Transaction,
     rng: &mut dyn RngCore,
@@ -415,15 +530,7 @@ impl SqliteBackend {
           })?;
         }
         MutationKind::SetSuffixVersionstampedKey(value) => {
-          let mut versionstamp_suffix = [0u8; 22];
-          versionstamp_suffix[0] = 0x02;
-          hex::encode_to_slice(
-            new_versionstamp,
-            &mut versionstamp_suffix[1..21],
-          )
-          .unwrap();
-
-          let key = [&mutation.key[..], &versionstamp_suffix[..]].concat();
+          let key = versionstamped_key(&mutation.key, &new_versionstamp);
 
           let (value, encoding) = encode_value(value);
           let changed =
@@ -472,6 +579,11 @@ impl SqliteBackend {
       has_enqueues,
       Some(CommitResult {
         versionstamp: new_versionstamp,
+        durable: true,
+        versionstamped_keys: versionstamped_keys(
+          &write.mutations,
+          &new_versionstamp,
+        ),
       }),
     ))
   }
@@ -841,26 +953,8 @@ fn sum_v8(
   clamp: bool,
   new_version: i64,
 ) -> Result<(), SqliteBackendError> {
-  let (Ok(operand), Ok(result_min), Ok(result_max)) = (
-    SumOperand::parse(operand),
-    SumOperand::parse_optional(&KvValue::V8(min_v8)),
-    SumOperand::parse_optional(&KvValue::V8(max_v8)),
-  ) else {
-    return Err(SqliteBackendError::TypeMismatch(
-      "Some of the parameters are not valid V8 values".into(),
-    ));
-  };
-
-  // min/max parameters, if any, must match the type of `operand`
-  if [&result_min, &result_max].into_iter().any(|x| {
-    x.as_ref()
-      .map(|x| discriminant(x) != discriminant(&operand))
-      .unwrap_or_default()
-  }) {
-    return Err(SqliteBackendError::TypeMismatch(
-      "Min/max parameters have different types than the operand".into(),
-    ));
-  }
+  let (operand, result_min, result_max) =
+    SumOperand::parse_sum(operand, &min_v8, &max_v8)?;
 
   let old_value = tx
     .prepare_cached(STATEMENT_KV_POINT_GET_VALUE_ONLY)?
@@ -889,71 +983,7 @@ fn sum_v8(
     }
   };
 
-  // Backward compat: sum(KvU64, bigint) -> KvU64
-  let operand = match (&old_value, operand, &result_min, &result_max, &clamp) {
-    (SumOperand::KvU64(_), SumOperand::BigInt(x), None, None, false)
-      if x >= BigInt::from(0u64) && x <= BigInt::from(u64::MAX) =>
-    {
-      SumOperand::KvU64(x.try_into().unwrap())
-    }
-    (_, x, _, _, _) => x,
-  };
-
-  let output = (|| match (&old_value, &operand) {
-    (SumOperand::BigInt(current), SumOperand::BigInt(operand)) => {
-      let mut current = current + operand;
-      if let Some(SumOperand::BigInt(result_min)) = &result_min {
-        if current < *result_min {
-          if !clamp {
-            return Err(SqliteBackendError::SumOutOfRange);
-          }
-          current.clone_from(result_min);
-        }
-      }
-      if let Some(SumOperand::BigInt(result_max)) = &result_max {
-        if current > *result_max {
-          if !clamp {
-            return Err(SqliteBackendError::SumOutOfRange);
-          }
-          current.clone_from(result_max);
-        }
-      }
-      Ok(SumOperand::BigInt(current))
-    }
-    (SumOperand::Number(current), SumOperand::Number(operand)) => {
-      let mut current = current + operand;
-      if let Some(SumOperand::Number(result_min)) = &result_min {
-        if current < *result_min {
-          if !clamp {
-            return Err(SqliteBackendError::SumOutOfRange);
-          }
-          current = *result_min;
-        }
-      }
-      if let Some(SumOperand::Number(result_max)) = &result_max {
-        if current > *result_max {
-          if !clamp {
-            return Err(SqliteBackendError::SumOutOfRange);
-          }
-          current = *result_max;
-        }
-      }
-      Ok(SumOperand::Number(current))
-    }
-    (SumOperand::KvU64(current), SumOperand::KvU64(operand)) => {
-      if result_min.is_some() || result_max.is_some() {
-        return Err(SqliteBackendError::TypeMismatch(
-          "Cannot use min/max parameters with KvU64 operands".into(),
-        ));
-      }
-      Ok(SumOperand::KvU64(current.wrapping_add(*operand)))
-    }
-    _ => Err(SqliteBackendError::TypeMismatch(format!(
-      "Cannot sum {} with {}",
-      old_value.variant_name(),
-      operand.variant_name(),
-    ))),
-  })()?;
+  let output = old_value.sum(operand, result_min, result_max, clamp)?;
 
   let (new_value, encoding) = encode_value_owned(output.encode());
   let changed = tx.prepare_cached(STATEMENT_KV_POINT_SET)?.execute(params![
@@ -972,3 +1002,7 @@ fn version_to_versionstamp(version: i64) -> Versionstamp {
   versionstamp[..8].copy_from_slice(&version.to_be_bytes());
   versionstamp
 }
+
+fn versionstamp_to_version(versionstamp: &Versionstamp) -> i64 {
+  i64::from_be_bytes(versionstamp[..8].try_into().unwrap())
+}
@@ -21,8 +21,11 @@ use std::time::Duration;
 pub use crate::backend::sqlite_retry_loop;
 use crate::backend::DequeuedMessage;
 use crate::backend::QueueMessageId;
+pub use crate::backend::ReplicationCursor;
 use crate::backend::SqliteBackend;
 pub use crate::backend::SqliteBackendError;
+pub use crate::sum_operand::SumError;
+pub use crate::sum_operand::SumOperand;
 use async_stream::try_stream;
 use chrono::DateTime;
 use chrono::Utc;
@@ -30,6 +33,7 @@ use deno_error::JsErrorBox;
 use denokv_proto::AtomicWrite;
 use denokv_proto::CommitResult;
 use denokv_proto::Database;
+use denokv_proto::KvChange;
 use denokv_proto::QueueMessageHandle;
 use denokv_proto::ReadRange;
 use denokv_proto::ReadRangeOutput;
@@ -79,6 +83,11 @@ enum SqliteRequest {
     write: AtomicWrite,
     sender: oneshot::Sender<Result<Option<CommitResult>, SqliteBackendError>>,
   },
+  ApplyChanges {
+    changes: Vec<KvChange>,
+    sender:
+      oneshot::Sender<Result<Option<ReplicationCursor>, SqliteBackendError>>,
+  },
   QueueDequeueMessage {
     sender: oneshot::Sender<DequeuedMessage>,
   },
@@ -394,6 +403,10 @@ async fn sqlite_thread(
               tx.send(result).ok(); // ignore error if receiver is gone
             }
           },
+          Some(SqliteRequest::ApplyChanges { changes, sender }) => {
+            let result = backend.apply_changes(changes);
+            sender.send(result).ok(); // ignore error if receiver is gone
+          },
           Some(SqliteRequest::QueueDequeueMessage { sender }) => {
             dequeue_channels.push_back(sender);
           },
@@ -525,6 +538,42 @@ impl Sqlite {
       .map_err(|_| SqliteBackendError::DatabaseClosed)?
   }
 
+  /// Apply changes replicated from another database, such as a change feed
+  /// of a central database, keeping their versionstamps. The key and
+  /// versionstamp of the last change are stored as the replication cursor
+  /// in the same transaction, so a replica resumes where it left off.
+  pub async fn apply_changes(
+    &self,
+    changes: Vec<KvChange>,
+  ) -> Result<(), SqliteBackendError> {
+    self.send_apply_changes(changes).await?;
+    Ok(())
+  }
+
+  /// The versionstamp and key of the last change applied with
+  /// [`Sqlite::apply_changes`], if any.
+  pub async fn replication_cursor(
+    &self,
+  ) -> Result<Option<ReplicationCursor>, SqliteBackendError> {
+    self.send_apply_changes(Vec::new()).await
+  }
+
+  async fn send_apply_changes(
+    &self,
+    changes: Vec<KvChange>,
+  ) -> Result<Option<ReplicationCursor>, SqliteBackendError> {
+    let (sender, receiver) = oneshot::channel();
+    self
+      .write_worker
+      .request_tx
+      .send(SqliteRequest::ApplyChanges { changes, sender })
+      .await
+      .map_err(|_| SqliteBackendError::DatabaseClosed)?;
+    receiver
+      .await
+      .map_err(|_| SqliteBackendError::DatabaseClosed)?
+  }
+
   pub async fn dequeue_next_message(
     &self,
   ) -> Result<Option<SqliteMessageHandle>, SqliteBackendError> {
@@ -1,5 +1,7 @@
 // Copyright 2023 the Deno authors. All rights reserved. MIT license.
 
+use std::mem::discriminant;
+
 use denokv_proto::KvValue;
 use num_bigint::BigInt;
 use thiserror::Error;
@@ -26,6 +28,15 @@ pub enum InvalidSumOperandError {
   OperandCannotBeEmpty,
 }
 
+/// Why a sum of V8 values failed.
+#[derive(Error, Debug)]
+pub enum SumError {
+  #[error("{0}")]
+  TypeMismatch(String),
+  #[error("The result of a Sum operation would exceed its range limit")]
+  OutOfRange,
+}
+
 impl SumOperand {
   pub fn variant_name(&self) -> &'static str {
     match self {
@@ -64,6 +75,113 @@ impl SumOperand {
       .ok_or(InvalidSumOperandError::OperandCannotBeEmpty)
   }
 
+  /// Parse the V8 operand of a sum and its optional bounds, which must be
+  /// of the same type as the operand.
+  pub fn parse_sum(
+    operand: &KvValue,
+    min_v8: &[u8],
+    max_v8: &[u8],
+  ) -> Result<(Self, Option<Self>, Option<Self>), SumError> {
+    let (Ok(operand), Ok(result_min), Ok(result_max)) = (
+      Self::parse(operand),
+      Self::parse_optional(&KvValue::V8(min_v8.to_vec())),
+      Self::parse_optional(&KvValue::V8(max_v8.to_vec())),
+    ) else {
+      return Err(SumError::TypeMismatch(
+        "Some of the parameters are not valid V8 values".into(),
+      ));
+    };
+
+    // min/max parameters, if any, must match the type of `operand`
+    if [&result_min, &result_max].into_iter().any(|x| {
+      x.as_ref()
+        .map(|x| discriminant(x) != discriminant(&operand))
+        .unwrap_or_default()
+    }) {
+      return Err(SumError::TypeMismatch(
+        "Min/max parameters have different types than the operand".into(),
+      ));
+    }
+    Ok((operand, result_min, result_max))
+  }
+
+  /// `self`, the current value of a key, plus `operand`. A result outside
+  /// of `result_min..=result_max` is clamped to it if `clamp` is set and
+  /// fails otherwise. KvU64 values wrap around instead and take no bounds.
+  pub fn sum(
+    self,
+    operand: Self,
+    result_min: Option<Self>,
+    result_max: Option<Self>,
+    clamp: bool,
+  ) -> Result<Self, SumError> {
+    // Backward compat: sum(KvU64, bigint) -> KvU64
+    let operand = match (&self, operand, &result_min, &result_max, &clamp) {
+      (SumOperand::KvU64(_), SumOperand::BigInt(x), None, None, false)
+        if x >= BigInt::from(0u64) && x <= BigInt::from(u64::MAX) =>
+      {
+        SumOperand::KvU64(x.try_into().unwrap())
+      }
+      (_, x, _, _, _) => x,
+    };
+
+    match (&self, &operand) {
+      (SumOperand::BigInt(current), SumOperand::BigInt(operand)) => {
+        let mut current = current + operand;
+        if let Some(SumOperand::BigInt(result_min)) = &result_min {
+          if current < *result_min {
+            if !clamp {
+              return Err(SumError::OutOfRange);
+            }
+            current.clone_from(result_min);
+          }
+        }
+        if let Some(SumOperand::BigInt(result_max)) = &result_max {
+          if current > *result_max {
+            if !clamp {
+              return Err(SumError::OutOfRange);
+            }
+            current.clone_from(result_max);
+          }
+        }
+        Ok(SumOperand::BigInt(current))
+      }
+      (SumOperand::Number(current), SumOperand::Number(operand)) => {
+        let mut current = current + operand;
+        if let Some(SumOperand::Number(result_min)) = &result_min {
+          if current < *result_min {
+            if !clamp {
+              return Err(SumError::OutOfRange);
+            }
+            current = *result_min;
+          }
+        }
+        if let Some(SumOperand::Number(result_max)) = &result_max {
+          if current > *result_max {
+            if !clamp {
+              return Err(SumError::OutOfRange);
+            }
+            current = *result_max;
+          }
+        }
+        Ok(SumOperand::Number(current))
+      }
+      (SumOperand::KvU64(current), SumOperand::KvU64(operand)) => {
+        if result_min.is_some() || result_max.is_some() {
+          return Err(SumError::TypeMismatch(
+            "Cannot use min/max parameters with KvU64 operands".into(),
+          ));
+        }
+        Ok(SumOperand::KvU64(current.wrapping_add(*operand)))
+      }
+      _ => Err(SumError::TypeMismatch(format!(
+        "Cannot sum {} with {}",
+        self.variant_name(),
+        operand.variant_name(),
+      ))),
+    }
+  }
+
   pub fn encode(self) -> KvValue {
     match self {
       Self::BigInt(x) => KvValue::V8(